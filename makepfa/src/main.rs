@@ -1,16 +1,141 @@
-use std::{io::Write, path::Path};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter},
+    path::Path,
+};
 
-use pfa::shared::DataFlags;
+use pfa::{
+    builder::PfaBuilder,
+    reader::PfaReader,
+    shared::{CatalogLayout, DataFlags},
+    writer::{
+        build_cache::BuildCache, directory_filter::DirectoryFilter,
+        streaming_builder::PfaStreamingBuilder,
+    },
+};
+
+/// Bytes spilled between checkpoints of a --resume build
+const CHECKPOINT_INTERVAL: u64 = 64 * 1024 * 1024;
+/// Largest solid block built with --solid, before compression
+const SOLID_BLOCK_SIZE: u64 = 1024 * 1024;
 
 fn usage() -> ! {
     eprintln!("USAGE:");
-    eprintln!("\tmakepfa [directory]");
+    eprintln!("\tmakepfa [--no-cache] [--resume] [--provenance] [--flags <flags>]");
+    eprintln!("\t        [--layout <layout>] [--solid <max_file_size>]");
+    eprintln!("\t        [--store-only <alignment>] [--include <glob>]... [--exclude <glob>]...");
+    eprintln!("\t        [directory]");
+    eprintln!();
+    eprintln!("<flags> is a comma separated list such as 'compress,ecc=0.2,enc=keyfile:a.key',");
+    eprintln!("where compression is one of auto (the default), compress or store.");
+    eprintln!("<layout> is fixed, for readers which only support 32 byte names, or compact.");
+    eprintln!("By default whichever layout results in the smaller catalog is used.");
+    eprintln!("--store-only stores every file uncompressed at an offset which is a multiple");
+    eprintln!("of <alignment>, such as 4096, for readers which map the archive into memory.");
+    eprintln!("--solid compresses files of up to <max_file_size> bytes together in blocks of");
+    eprintln!("up to 1 MiB, which makes archives of many small files such as scripts much");
+    eprintln!("smaller. Reading a file then decompresses its whole block.");
+    eprintln!("--include only packs the files matching one of the patterns, and --exclude skips");
+    eprintln!("files and directories matching any of them. Patterns without a '/', such as");
+    eprintln!("'*.tmp' or 'build', match file and directory names, others such as");
+    eprintln!("'/assets/**/*.png' match paths from the root of the directory. Hidden files");
+    eprintln!("and those listed in .gitignore files are always skipped.");
+    eprintln!("--provenance records the source path and hash of every file in the archive.");
+    eprintln!("Unchanged files are copied from the previous [directory].pfa using the");
+    eprintln!("[directory].pfa.cache file written next to it, unless --no-cache is given.");
+    eprintln!("Without --resume, the processed data of every file is held in memory until the");
+    eprintln!("archive is written, so use --resume for directories larger than memory.");
+    eprintln!("--resume processes files into [directory].pfa.spill as they are read, writing");
+    eprintln!("a [directory].pfa.checkpoint every 64 MiB, and continues from the checkpoint if");
+    eprintln!("an earlier --resume build was interrupted. Both are removed once the archive is");
+    eprintln!("written. It can't be used with --store-only or --solid, and doesn't use the");
+    eprintln!("build cache.");
     std::process::exit(0);
 }
 
 fn main() {
     let mut args = std::env::args().skip(1).collect::<Vec<_>>();
-    if args.len() != 1 || args[0] == "--help" || args[0] == "-h" {
+    if args.iter().any(|x| x == "--help" || x == "-h") {
+        usage()
+    }
+    let no_cache = args.iter().any(|x| x == "--no-cache");
+    let provenance = args.iter().any(|x| x == "--provenance");
+    let resume = args.iter().any(|x| x == "--resume");
+    args.retain(|x| x != "--no-cache" && x != "--provenance" && x != "--resume");
+    let flags = match args.iter().position(|x| x == "--flags") {
+        Some(i) if i + 1 < args.len() => {
+            let spec = args.drain(i..=i + 1).nth(1).unwrap();
+            spec.parse::<DataFlags>().unwrap_or_else(|e| {
+                eprintln!("{e}");
+                usage()
+            })
+        }
+        Some(_) => usage(),
+        None => DataFlags::auto(),
+    };
+    let layout = match args.iter().position(|x| x == "--layout") {
+        Some(i) if i + 1 < args.len() => {
+            let layout = args.drain(i..=i + 1).nth(1).unwrap();
+            Some(layout.parse::<CatalogLayout>().unwrap_or_else(|e| {
+                eprintln!("{e}");
+                usage()
+            }))
+        }
+        Some(_) => usage(),
+        None => None,
+    };
+    let store_only = match args.iter().position(|x| x == "--store-only") {
+        Some(i) if i + 1 < args.len() => {
+            let alignment = args.drain(i..=i + 1).nth(1).unwrap();
+            match alignment.parse::<u32>() {
+                Ok(alignment) if alignment > 0 => Some(alignment),
+                _ => {
+                    eprintln!("invalid alignment '{alignment}'");
+                    usage()
+                }
+            }
+        }
+        Some(_) => usage(),
+        None => None,
+    };
+    let solid = match args.iter().position(|x| x == "--solid") {
+        Some(i) if i + 1 < args.len() => {
+            let max_file_size = args.drain(i..=i + 1).nth(1).unwrap();
+            match max_file_size.parse::<u64>() {
+                Ok(max_file_size) if max_file_size > 0 => Some(max_file_size),
+                _ => {
+                    eprintln!("invalid file size '{max_file_size}'");
+                    usage()
+                }
+            }
+        }
+        Some(_) => usage(),
+        None => None,
+    };
+    let mut filter = DirectoryFilter::new();
+    while let Some(i) = args
+        .iter()
+        .position(|x| x == "--include" || x == "--exclude")
+    {
+        if i + 1 >= args.len() {
+            usage()
+        }
+        let mut option = args.drain(i..=i + 1);
+        let (option, pattern) = (option.next().unwrap(), option.next().unwrap());
+        filter = match &option[..] {
+            "--include" => filter.include(&pattern),
+            _ => filter.exclude(&pattern),
+        };
+    }
+    if args.len() != 1 {
+        usage()
+    }
+    if resume && store_only.is_some() {
+        eprintln!("--resume can't be used with --store-only");
+        usage()
+    }
+    if resume && solid.is_some() {
+        eprintln!("--resume can't be used with --solid");
         usage()
     }
     let directory_name = args.pop().unwrap();
@@ -22,12 +147,90 @@ fn main() {
         let path = Path::new(&directory_name);
         let canon_path = path.canonicalize().unwrap();
         let name = path.file_name().unwrap().to_string_lossy().to_string();
-        let mut pfa = pfa::builder::PfaBuilder::new(&name);
-        pfa.include_directory(canon_path.to_str().unwrap(), DataFlags::auto())
-            .unwrap();
-        let bytes = pfa.build().unwrap();
-        let mut file = std::fs::File::create(format!("{name}.pfa")).unwrap();
-        file.write_all(&bytes).unwrap();
+        let out_path = format!("{name}.pfa");
+        let cache_path = format!("{name}.pfa.cache");
+        let configure = |pfa: &mut PfaBuilder| {
+            pfa.set_sniff_content_types(true);
+            pfa.set_directory_filter(filter.clone());
+            if let Some(layout) = layout {
+                pfa.set_catalog_layout(layout);
+            }
+            if let Some(alignment) = store_only {
+                pfa.set_store_only(alignment);
+            }
+            if let Some(max_file_size) = solid {
+                pfa.set_solid_blocks(max_file_size, SOLID_BLOCK_SIZE);
+            }
+            if provenance {
+                pfa.set_record_provenance(true);
+                pfa.set_provenance_tool(concat!("makepfa ", env!("CARGO_PKG_VERSION")));
+            }
+        };
+
+        if resume {
+            let spill_path = format!("{name}.pfa.spill");
+            let checkpoint_path = format!("{name}.pfa.checkpoint");
+            let spill = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&spill_path)
+                .unwrap();
+            let mut streaming = match File::open(&checkpoint_path) {
+                Ok(checkpoint) => {
+                    println!("Resuming from {checkpoint_path}");
+                    PfaStreamingBuilder::resume(&name, spill, BufReader::new(checkpoint)).unwrap()
+                }
+                Err(_) => PfaStreamingBuilder::new(&name, spill).unwrap(),
+            };
+            configure(streaming.builder());
+            streaming.set_checkpoint(&checkpoint_path, CHECKPOINT_INTERVAL);
+            streaming
+                .include_directory(canon_path.to_str().unwrap(), flags)
+                .unwrap();
+
+            let file = File::create(out_path).unwrap();
+            streaming.build_into(BufWriter::new(file)).unwrap();
+            let _ = std::fs::remove_file(checkpoint_path);
+            let _ = std::fs::remove_file(spill_path);
+            return;
+        }
+
+        let mut pfa = PfaBuilder::new(&name);
+        configure(&mut pfa);
+
+        let new_cache = if no_cache {
+            pfa.include_directory(canon_path.to_str().unwrap(), flags)
+                .unwrap();
+            None
+        } else {
+            let mut previous = PfaReader::open(&out_path).ok();
+            let cache = BuildCache::load(&cache_path).unwrap_or_default();
+            let (new_cache, stats) = pfa
+                .include_directory_cached(
+                    canon_path.to_str().unwrap(),
+                    flags,
+                    previous.as_mut(),
+                    &cache,
+                )
+                .unwrap();
+            drop(previous);
+            if stats.reused > 0 {
+                println!(
+                    "Copied {} unchanged files from the previous archive, processed {}",
+                    stats.reused, stats.processed
+                );
+            }
+            Some(new_cache)
+        };
+
+        let file = File::create(out_path).unwrap();
+        pfa.build_into(BufWriter::new(file)).unwrap();
+        // only written once the archive it describes exists
+        if let Some(new_cache) = new_cache {
+            new_cache.save(&cache_path).unwrap();
+        }
     } else {
         eprintln!("Directory '{directory_name}' not found");
         usage()
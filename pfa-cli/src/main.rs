@@ -0,0 +1,342 @@
+use std::{fs::File, io::BufReader, path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand};
+use pfa::{
+    reader::{ArchiveIndex, IndexedFile, PfaProbe, PfaReader, ScrubTask, SourceCheck},
+    shared::{parse_hex_key, DataFlags, Tier},
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    cmd: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Copy an archive without the files above a quality tier
+    Strip {
+        /// Highest tier to keep (low, med or high)
+        #[clap(short, long)]
+        max_tier: Tier,
+        #[clap(short, long)]
+        input: PathBuf,
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+    /// Print the metadata of an entry
+    Stat {
+        #[clap(short, long)]
+        input: PathBuf,
+        /// Path of the entry, directories end with '/'
+        path: String,
+    },
+    /// Print the format version and catalog layout of an archive, read from its header alone
+    Probe {
+        #[clap(short, long)]
+        input: PathBuf,
+    },
+    /// Print the on-disk layout of the archive format implemented by this version
+    Spec,
+    /// Rewrite a version 1 archive as version 2, the oldest with header fields. Newer archives
+    /// keep their version.
+    Upgrade { input: PathBuf, output: PathBuf },
+    /// Rewrite an archive in an older format version, failing with everything it can't store
+    Downgrade {
+        #[clap(long, default_value_t = 1)]
+        to: u8,
+        /// Key of files encrypted in frames or padded, as 64 hex digits, so they can be encrypted whole
+        #[clap(long, value_parser = parse_key)]
+        key: Option<[u8; 32]>,
+        input: PathBuf,
+        output: PathBuf,
+    },
+    /// Check an archive for entries pointing outside it, data no entry refers to and codecs this
+    /// build can't decode
+    Verify { input: PathBuf },
+    /// Copy an archive without the data no entry refers to
+    Compact { input: PathBuf, output: PathBuf },
+    /// Check the entry hashes and error correction of every archive in a directory
+    Scrub {
+        dir: PathBuf,
+        /// Maximum bytes to read per second
+        #[clap(long)]
+        rate: Option<u64>,
+        /// Write a repaired copy of damaged archives next to them, as {archive}.repaired, unless
+        /// files are damaged beyond repair
+        #[clap(long)]
+        repair: bool,
+        /// Keep running, starting a pass every this many seconds
+        #[clap(long, value_name = "SECONDS")]
+        schedule: Option<u64>,
+    },
+    /// Index the files of every archive in a directory, so they can be found with `query`
+    Index {
+        dir: PathBuf,
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+    /// Find which indexed archives contain a file, printing the archive, path, stored size and
+    /// contents hash of every match
+    Query {
+        #[clap(short, long)]
+        index: PathBuf,
+        /// Path of the file, or a glob such as '/textures/**/*.png'
+        #[clap(required_unless_present = "contents")]
+        pattern: Option<String>,
+        /// Find files with the same contents as this file instead, wherever they are
+        #[clap(long, conflicts_with = "pattern")]
+        contents: Option<PathBuf>,
+    },
+}
+
+fn parse_key(hex: &str) -> Result<[u8; 32], String> {
+    parse_hex_key(hex).ok_or_else(|| "keys must be 64 hex digits".into())
+}
+
+/// Converts to `version`, or the newer of version 2 and the input's version if None
+fn convert(
+    input: PathBuf,
+    output: PathBuf,
+    version: Option<u8>,
+    key: Option<[u8; 32]>,
+) -> Result<()> {
+    let mut reader = PfaReader::open(input).context("open input file")?;
+    let version = version.unwrap_or(reader.get_version().max(2));
+    let bytes = reader
+        .convert_version(version, key)
+        .with_context(|| format!("convert archive to version {version}"))?;
+    std::fs::write(output, bytes).context("write output file")
+}
+
+/// Prints the unencrypted files which don't match the source recorded when the archive was
+/// built and weren't transformed from it, returning how many there are
+fn check_sources(reader: &mut PfaReader<BufReader<File>>) -> Result<usize> {
+    // archives with hashed names can't be listed
+    let Ok(entries) = reader.entries() else {
+        return Ok(0);
+    };
+    let paths = entries
+        .filter(|x| {
+            x.as_ref().map_or(true, |x| {
+                !x.is_directory && !DataFlags::is_encrypted(x.flags)
+            })
+        })
+        .map(|x| x.map(|x| x.path))
+        .collect::<Result<Vec<_>, _>>()
+        .context("list entries")?;
+    let mut mismatched = 0;
+    for path in paths {
+        match reader.check_source(path.clone(), None) {
+            Ok(Some(SourceCheck::Mismatch)) => {
+                println!("'{path}' doesn't match its recorded source");
+                mismatched += 1;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                println!("'{path}' couldn't be read: {e}");
+                mismatched += 1;
+            }
+        }
+    }
+    Ok(mismatched)
+}
+
+fn print_match(index: &ArchiveIndex, path: &str, file: &IndexedFile) {
+    let contents_hash = file.contents_hash.map_or("-".into(), |hash| {
+        hash.iter().map(|x| format!("{x:02x}")).collect::<String>()
+    });
+    println!(
+        "{}\t{path}\t{}\t{contents_hash}",
+        index.archives()[file.archive].path.display(),
+        file.size
+    );
+}
+
+fn run() -> Result<()> {
+    let args = Args::parse();
+    match args.cmd {
+        Commands::Strip {
+            max_tier,
+            input,
+            output,
+        } => {
+            let mut reader = PfaReader::open(input).context("open input file")?;
+            let bytes = reader
+                .strip_tiers(max_tier)
+                .context("strip archive")?
+                .build()
+                .context("build stripped archive")?;
+            std::fs::write(output, bytes).context("write output file")?;
+        }
+        Commands::Stat { input, path } => {
+            let reader = PfaReader::open(input).context("open input file")?;
+            for warning in reader.warnings() {
+                eprintln!("warning: {warning}");
+            }
+            let stat = reader
+                .stat(&path[..])
+                .context("find entry")?
+                .with_context(|| format!("'{path}' not found"))?;
+            if stat.is_directory {
+                println!("type: directory");
+                println!("entries: {}", stat.size);
+            } else {
+                println!("type: file");
+                println!("stored size: {}", stat.size);
+                println!("flags: {:#010b}", stat.flags);
+            }
+            if let Some(content_type) = stat.content_type {
+                println!("content type: {content_type}");
+            }
+            if let Some(tier) = stat.tier {
+                println!("tier: {tier:?}");
+            }
+            let provenance = reader
+                .get_provenance(&path[..])
+                .context("read provenance")?;
+            if let Some(provenance) = provenance {
+                if let Some(source_path) = provenance.source_path {
+                    println!("source path: {source_path}");
+                }
+                if let Some(hash) = provenance.source_hash {
+                    let hash = hash.iter().map(|x| format!("{x:02x}")).collect::<String>();
+                    println!("source hash: {hash}");
+                }
+                if let Some(tool) = provenance.tool {
+                    println!("tool: {tool}");
+                }
+            }
+            let transforms = reader
+                .get_transforms(&path[..])
+                .context("read transforms")?;
+            if let Some(transforms) = transforms {
+                println!("transforms: {}", transforms.id());
+                if let Some(hash) = transforms.input_hash {
+                    let hash = hash.iter().map(|x| format!("{x:02x}")).collect::<String>();
+                    println!("transform input hash: {hash}");
+                }
+                if let Some(hash) = transforms.output_hash {
+                    let hash = hash.iter().map(|x| format!("{x:02x}")).collect::<String>();
+                    println!("transform output hash: {hash}");
+                }
+            }
+        }
+        Commands::Probe { input } => {
+            let mut input = BufReader::new(File::open(input).context("open input file")?);
+            let probe = PfaProbe::read(&mut input).context("read input file header")?;
+            println!("version: {}", probe.version);
+            println!("catalog layout: {}", probe.catalog_layout);
+            println!("name encoding: {:?}", probe.name_encoding);
+            println!("entries: {}", probe.entries);
+            if let Some(alignment) = probe.data_alignment {
+                println!("store only, data aligned to {alignment} bytes");
+            }
+        }
+        Commands::Spec => print!("{}", pfa::shared::format_spec()),
+        Commands::Upgrade { input, output } => convert(input, output, None, None)?,
+        Commands::Downgrade {
+            to,
+            key,
+            input,
+            output,
+        } => convert(input, output, Some(to), key)?,
+        Commands::Verify { input } => {
+            let mut reader = PfaReader::open(input).context("open input file")?;
+            for warning in reader.warnings() {
+                println!("{warning}");
+            }
+            let missing_codecs = reader.capabilities()?.missing_codecs;
+            for codec in &missing_codecs {
+                println!("files are compressed with {codec}, which this build can't decode");
+            }
+            let mismatched = check_sources(&mut reader)?;
+            let problems = reader.warnings().len() + missing_codecs.len() + mismatched;
+            if problems > 0 {
+                return Err(anyhow!("found {problems} problems"));
+            }
+            println!("no problems found");
+        }
+        Commands::Compact { input, output } => {
+            let mut reader = PfaReader::open(input).context("open input file")?;
+            let unreferenced = reader
+                .get_unreferenced_data()
+                .context("find unreferenced data")?;
+            let bytes = reader.compact().context("compact archive")?;
+            std::fs::write(output, bytes).context("write output file")?;
+            let size = unreferenced.iter().map(|x| x.end - x.start).sum::<u64>();
+            println!(
+                "dropped {size} unreferenced bytes in {} ranges",
+                unreferenced.len()
+            );
+        }
+        Commands::Scrub {
+            dir,
+            rate,
+            repair,
+            schedule,
+        } => {
+            let task = ScrubTask::new(dir).rate_limit(rate).repair(repair);
+            if let Some(seconds) = schedule {
+                return task
+                    .run_every(Duration::from_secs(seconds), |report| {
+                        print!("{report}");
+                        true
+                    })
+                    .context("scrub archives");
+            }
+            let report = task.run().context("scrub archives")?;
+            print!("{report}");
+            if !report.is_healthy() {
+                return Err(anyhow!("found problems"));
+            }
+        }
+        Commands::Index { dir, output } => {
+            let index = ArchiveIndex::build(dir).context("index archives")?;
+            index.save(output).context("write index")?;
+            for archive in index.archives() {
+                if let Some(error) = &archive.error {
+                    eprintln!("warning: skipped {}: {error}", archive.path.display());
+                }
+            }
+            println!(
+                "indexed {} paths in {} archives",
+                index.len(),
+                index.archives().len()
+            );
+        }
+        Commands::Query {
+            index,
+            pattern,
+            contents,
+        } => {
+            let index = ArchiveIndex::load(index).context("read index")?;
+            let matches = match (pattern, contents) {
+                (_, Some(contents)) => {
+                    let contents = std::fs::read(contents).context("read contents file")?;
+                    index.find_contents(&contents)
+                }
+                (Some(pattern), None) => index.find_matching(&pattern).context("match paths")?,
+                (None, None) => unreachable!("clap requires a pattern or contents"),
+            };
+            for (path, file) in &matches {
+                print_match(&index, path, file);
+            }
+            if matches.is_empty() {
+                return Err(anyhow!("no indexed archive contains a match"));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("ERROR: {}", e);
+        e.chain()
+            .skip(1)
+            .for_each(|c| eprintln!("\tCaused by: {c}"))
+    }
+}
@@ -0,0 +1,293 @@
+//! Generates a Rust module with a constant for every asset path in an archive, so asset
+//! references are checked at compile time. Meant to be used from a build script:
+//!
+//! ```ignore
+//! // build.rs
+//! pfa_codegen::generate_module("assets.pfa", "assets.rs").unwrap();
+//!
+//! // main.rs
+//! mod assets {
+//!     include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+//! }
+//! reader.get_file(assets::textures::HERO_PNG, None)?;
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+    fs::File,
+    io::{BufReader, Read, Seek},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use pfa::reader::PfaReader;
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "box", "break", "const", "continue", "crate", "dyn", "else", "enum",
+    "extern", "false", "fn", "for", "gen", "if", "impl", "in", "let", "loop", "match", "mod",
+    "move", "mut", "pub", "ref", "return", "self", "static", "struct", "super", "trait", "true",
+    "try", "type", "unsafe", "use", "where", "while", "yield", "Self",
+];
+
+/// Lists every file in an archive, with variants listed once under their logical name
+pub fn paths_from_archive<T: Read + Seek>(reader: &mut PfaReader<T>) -> Result<Vec<String>> {
+    let mut paths = vec![];
+    let mut pending = vec!["/".to_string()];
+    while let Some(dir) = pending.pop() {
+        let contents = reader
+            .get_directory(&dir[..], None)
+            .context("read directory")?
+            .with_context(|| format!("directory '{dir}' not found"))?;
+        for path in contents.get_contents() {
+            if path.is_directory() {
+                pending.push(path.to_string());
+            } else {
+                paths.push(path.to_string());
+            }
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Reads a manifest with one path per line, empty lines and lines starting with '#' are skipped
+pub fn paths_from_manifest(manifest: &str) -> Vec<String> {
+    let mut paths = manifest
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| {
+            if x.starts_with('/') {
+                x.to_string()
+            } else {
+                format!("/{x}")
+            }
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Generates a module from an archive (a `.pfa` file) or a manifest, written to
+/// `{OUT_DIR}/{file_name}`. Cargo is told to rerun the build script when the input changes.
+pub fn generate_module(input: impl AsRef<Path>, file_name: &str) -> Result<PathBuf> {
+    let input = input.as_ref();
+    let paths = if input.extension().is_some_and(|x| x == "pfa") {
+        let file = File::open(input).context("open archive")?;
+        let mut reader = PfaReader::new(BufReader::new(file)).context("parse archive")?;
+        paths_from_archive(&mut reader)?
+    } else {
+        paths_from_manifest(&std::fs::read_to_string(input).context("read manifest")?)
+    };
+
+    let out_dir = std::env::var_os("OUT_DIR")
+        .context("OUT_DIR is not set, generate_module must be called from a build script")?;
+    let out = Path::new(&out_dir).join(file_name);
+    std::fs::write(&out, generate(&paths)).context("write generated module")?;
+    println!("cargo:rerun-if-changed={}", input.display());
+    Ok(out)
+}
+
+#[derive(Default)]
+struct Module {
+    modules: BTreeMap<String, Module>,
+    files: Vec<(String, String)>,
+}
+
+/// Generates the source of a module containing:
+/// - a nested module for every directory, with a `&str` constant for each file in it
+/// - an `Asset` enum with a variant for every file, and `Asset::path` to get its path
+pub fn generate(paths: &[String]) -> String {
+    let mut root = Module::default();
+    for path in paths {
+        let mut parts = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+        let Some(name) = parts.pop() else {
+            continue;
+        };
+        let module = parts.into_iter().fold(&mut root, |module, part| {
+            module.modules.entry(part.to_string()).or_default()
+        });
+        module.files.push((name.to_string(), path.clone()));
+    }
+
+    let mut out = String::from("// Generated by pfa-codegen, do not edit.\n\n");
+    let mut taken = HashSet::new();
+    let variants = paths
+        .iter()
+        .map(|path| (unique(&mut taken, variant_name(path), ""), path))
+        .collect::<Vec<_>>();
+
+    // downstream crates usually only use some of the assets, and variants made of one letter
+    // parts, such as `ABX` for `/a/b/x`, look like acronyms
+    out.push_str("/// An asset in the archive\n");
+    out.push_str("#[allow(dead_code, clippy::upper_case_acronyms)]\n");
+    out.push_str("#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]\n");
+    out.push_str("pub enum Asset {\n");
+    for (variant, _) in &variants {
+        let _ = writeln!(out, "    {variant},");
+    }
+    out.push_str("}\n\n#[allow(dead_code)]\nimpl Asset {\n");
+    out.push_str("    pub const ALL: &'static [Asset] = &[\n");
+    for (variant, _) in &variants {
+        let _ = writeln!(out, "        Asset::{variant},");
+    }
+    out.push_str("    ];\n\n");
+    out.push_str("    pub const fn path(self) -> &'static str {\n        match self {\n");
+    for (variant, path) in &variants {
+        let _ = writeln!(out, "            Asset::{variant} => {path:?},");
+    }
+    out.push_str("        }\n    }\n}\n\n");
+
+    write_module(&mut out, &root, 0);
+    out
+}
+
+fn write_module(out: &mut String, module: &Module, depth: usize) {
+    let indent = "    ".repeat(depth);
+    let mut taken = HashSet::new();
+    for (name, path) in &module.files {
+        let name = unique(&mut taken, identifier(name, true), "_");
+        if depth == 0 {
+            out.push_str("#[allow(dead_code)]\n");
+        }
+        let _ = writeln!(out, "{indent}pub const {name}: &str = {path:?};");
+    }
+
+    let mut taken = HashSet::new();
+    for (name, module) in &module.modules {
+        let name = unique(&mut taken, identifier(name, false), "_");
+        if depth == 0 {
+            out.push_str("\n#[allow(dead_code)]\n");
+        } else {
+            out.push('\n');
+        }
+        let _ = writeln!(out, "{indent}pub mod {name} {{");
+        write_module(out, module, depth + 1);
+        let _ = writeln!(out, "{indent}}}");
+    }
+}
+
+/// Turns a name into a valid identifier, either SCREAMING_SNAKE_CASE or snake_case
+fn identifier(name: &str, upper: bool) -> String {
+    let mut out = name
+        .chars()
+        .map(|x| match x {
+            x if x.is_ascii_alphanumeric() && upper => x.to_ascii_uppercase(),
+            x if x.is_ascii_alphanumeric() => x.to_ascii_lowercase(),
+            _ => '_',
+        })
+        .collect::<String>();
+    if out.is_empty() || out == "_" || out.starts_with(|x: char| x.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    if KEYWORDS.contains(&&out[..]) {
+        out.push('_');
+    }
+    out
+}
+
+/// CamelCase name for the `Asset` variant of a path
+fn variant_name(path: &str) -> String {
+    let mut out = path
+        .split(|x: char| !x.is_ascii_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            chars
+                .next()
+                .map(|x| x.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase())
+                .unwrap_or_default()
+        })
+        .collect::<String>();
+    if out.is_empty() || out.starts_with(|x: char| x.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    if KEYWORDS.contains(&&out[..]) {
+        out.push('_');
+    }
+    out
+}
+
+/// Appends a number to names which are already taken within the same scope
+fn unique(taken: &mut HashSet<String>, name: String, separator: &str) -> String {
+    let mut candidate = name.clone();
+    let mut n = 2;
+    while !taken.insert(candidate.clone()) {
+        candidate = format!("{name}{separator}{n}");
+        n += 1;
+    }
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use pfa::{builder::PfaBuilder, reader::PfaReader, shared::DataFlags};
+
+    use super::*;
+
+    /// Names which need escaping, in nested and clashing modules
+    const FIXTURE_MANIFEST: &str = "textures/hero.png\ntextures/hero-png\ntype/1st.txt\nself.txt\n\
+                                    self/super.rs\na-b/x\na_b/x\nreadme\n";
+
+    /// The module generated from [`FIXTURE_MANIFEST`], compiled along with the tests
+    mod fixture {
+        include!("../tests/fixtures/assets.rs");
+    }
+
+    #[test]
+    fn generate_from_manifest() {
+        let paths = paths_from_manifest(
+            "# assets\ntextures/hero.png\n/textures/hero-png\n\n/type/1st.txt\n/self.txt\n",
+        );
+        assert_eq!(paths.len(), 4);
+        let module = generate(&paths);
+
+        assert!(module.contains("pub const HERO_PNG: &str = \"/textures/hero-png\";"));
+        assert!(module.contains("pub const HERO_PNG_2: &str = \"/textures/hero.png\";"));
+        assert!(module.contains("pub mod type_ {"));
+        assert!(module.contains("pub const _1ST_TXT: &str = \"/type/1st.txt\";"));
+        assert!(module.contains("Asset::TexturesHeroPng => \"/textures/hero-png\","));
+        assert!(module.contains("Asset::TexturesHeroPng2 => \"/textures/hero.png\","));
+        assert!(module.contains("Asset::SelfTxt => \"/self.txt\","));
+    }
+
+    #[test]
+    fn generate_from_archive() {
+        let mut builder = PfaBuilder::new("assets");
+        builder
+            .add_file("/sounds/jump.ogg", vec![1], DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/readme.txt", vec![2], DataFlags::auto())
+            .unwrap();
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+
+        let paths = paths_from_archive(&mut reader).unwrap();
+        assert_eq!(paths, ["/readme.txt", "/sounds/jump.ogg"]);
+        let module = generate(&paths);
+        assert!(module.contains("pub mod sounds {\n    pub const JUMP_OGG: &str"));
+    }
+
+    #[test]
+    fn generated_module_compiles() {
+        let module = generate(&paths_from_manifest(FIXTURE_MANIFEST));
+        assert_eq!(module, include_str!("../tests/fixtures/assets.rs"));
+
+        assert_eq!(fixture::textures::HERO_PNG, "/textures/hero-png");
+        assert_eq!(fixture::textures::HERO_PNG_2, "/textures/hero.png");
+        assert_eq!(fixture::type_::_1ST_TXT, "/type/1st.txt");
+        assert_eq!(fixture::self_::SUPER_RS, "/self/super.rs");
+        assert_eq!(fixture::a_b::X, "/a-b/x");
+        assert_eq!(fixture::a_b_2::X, "/a_b/x");
+        assert_eq!(fixture::README, "/readme");
+        assert_eq!(fixture::Asset::ALL.len(), 8);
+        for asset in fixture::Asset::ALL {
+            assert!(paths_from_manifest(FIXTURE_MANIFEST).contains(&asset.path().to_string()));
+        }
+        assert_eq!(fixture::Asset::SelfTxt.path(), fixture::SELF_TXT);
+    }
+}
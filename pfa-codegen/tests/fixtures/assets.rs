@@ -0,0 +1,73 @@
+// Generated by pfa-codegen, do not edit.
+
+/// An asset in the archive
+#[allow(dead_code, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Asset {
+    ABX,
+    ABX2,
+    Readme,
+    SelfTxt,
+    SelfSuperRs,
+    TexturesHeroPng,
+    TexturesHeroPng2,
+    Type1stTxt,
+}
+
+#[allow(dead_code)]
+impl Asset {
+    pub const ALL: &'static [Asset] = &[
+        Asset::ABX,
+        Asset::ABX2,
+        Asset::Readme,
+        Asset::SelfTxt,
+        Asset::SelfSuperRs,
+        Asset::TexturesHeroPng,
+        Asset::TexturesHeroPng2,
+        Asset::Type1stTxt,
+    ];
+
+    pub const fn path(self) -> &'static str {
+        match self {
+            Asset::ABX => "/a-b/x",
+            Asset::ABX2 => "/a_b/x",
+            Asset::Readme => "/readme",
+            Asset::SelfTxt => "/self.txt",
+            Asset::SelfSuperRs => "/self/super.rs",
+            Asset::TexturesHeroPng => "/textures/hero-png",
+            Asset::TexturesHeroPng2 => "/textures/hero.png",
+            Asset::Type1stTxt => "/type/1st.txt",
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub const README: &str = "/readme";
+#[allow(dead_code)]
+pub const SELF_TXT: &str = "/self.txt";
+
+#[allow(dead_code)]
+pub mod a_b {
+    pub const X: &str = "/a-b/x";
+}
+
+#[allow(dead_code)]
+pub mod a_b_2 {
+    pub const X: &str = "/a_b/x";
+}
+
+#[allow(dead_code)]
+pub mod self_ {
+    pub const SUPER_RS: &str = "/self/super.rs";
+}
+
+#[allow(dead_code)]
+pub mod textures {
+    pub const HERO_PNG: &str = "/textures/hero-png";
+    pub const HERO_PNG_2: &str = "/textures/hero.png";
+}
+
+#[allow(dead_code)]
+pub mod type_ {
+    pub const _1ST_TXT: &str = "/type/1st.txt";
+}
@@ -0,0 +1,5 @@
+//! Python bindings, built as the `pfa_py` extension module with
+//! `maturin build --features python`.
+
+#[cfg(feature = "python")]
+mod python;
@@ -0,0 +1,258 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Cursor, Read, Seek},
+};
+
+use pfa::{builder::PfaBuilder, reader::PfaReader, shared::DataFlags};
+use pyo3::{create_exception, exceptions::PyException, prelude::*, types::PyBytes};
+
+create_exception!(pfa_py, PfaException, PyException);
+
+fn to_py_error(error: impl std::fmt::Display) -> PyErr {
+    PfaException::new_err(error.to_string())
+}
+
+fn to_key(key: Option<&[u8]>) -> PyResult<Option<[u8; 32]>> {
+    key.map(|x| {
+        x.try_into()
+            .map_err(|_| to_py_error("key must be 32 bytes"))
+    })
+    .transpose()
+}
+
+/// `compression` is one of "auto", "none" or "forced"
+fn data_flags(
+    compression: &str,
+    error_correction: Option<f32>,
+    key: Option<&[u8]>,
+) -> PyResult<DataFlags> {
+    let flags = match compression {
+        "auto" => DataFlags::auto(),
+        "none" => DataFlags::no_compression(),
+        "forced" => DataFlags::forced_compression(),
+        _ => return Err(to_py_error(format!("unknown compression '{compression}'"))),
+    };
+    Ok(flags
+        .error_correction(error_correction)
+        .encryption(to_key(key)?))
+}
+
+#[pyclass(name = "Builder", unsendable)]
+struct PyBuilder {
+    /// Taken when the archive is built
+    builder: Option<PfaBuilder>,
+}
+
+impl PyBuilder {
+    fn get(&mut self) -> PyResult<&mut PfaBuilder> {
+        self.builder
+            .as_mut()
+            .ok_or_else(|| to_py_error("archive has already been built"))
+    }
+
+    fn take(&mut self) -> PyResult<PfaBuilder> {
+        self.builder
+            .take()
+            .ok_or_else(|| to_py_error("archive has already been built"))
+    }
+}
+
+#[pymethods]
+impl PyBuilder {
+    #[new]
+    fn new(name: &str) -> Self {
+        Self {
+            builder: Some(PfaBuilder::new(name)),
+        }
+    }
+
+    #[pyo3(signature = (path, contents, compression = "auto", error_correction = None, key = None))]
+    fn add_file(
+        &mut self,
+        path: &str,
+        contents: Vec<u8>,
+        compression: &str,
+        error_correction: Option<f32>,
+        key: Option<&[u8]>,
+    ) -> PyResult<()> {
+        let flags = data_flags(compression, error_correction, key)?;
+        self.get()?
+            .add_file(path, contents, flags)
+            .map_err(to_py_error)
+    }
+
+    #[pyo3(signature = (path, compression = "auto", error_correction = None, key = None))]
+    fn include_directory(
+        &mut self,
+        path: &str,
+        compression: &str,
+        error_correction: Option<f32>,
+        key: Option<&[u8]>,
+    ) -> PyResult<()> {
+        let flags = data_flags(compression, error_correction, key)?;
+        self.get()?
+            .include_directory(path, flags)
+            .map_err(to_py_error)
+    }
+
+    fn set_entry_hashes(&mut self, entry_hashes: bool) -> PyResult<()> {
+        self.get()?.set_entry_hashes(entry_hashes);
+        Ok(())
+    }
+
+    /// Returns the archive, the builder can't be used afterwards
+    fn build<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let bytes = self.take()?.build().map_err(to_py_error)?;
+        Ok(PyBytes::new(py, &bytes))
+    }
+
+    /// Writes the archive to a file, the builder can't be used afterwards
+    fn write(&mut self, path: &str) -> PyResult<()> {
+        let bytes = self.take()?.build().map_err(to_py_error)?;
+        std::fs::write(path, bytes).map_err(to_py_error)
+    }
+}
+
+trait Source: Read + Seek {}
+impl<T: Read + Seek> Source for T {}
+
+#[pyclass(name = "Reader", unsendable)]
+struct PyReader {
+    reader: PfaReader<Box<dyn Source>>,
+}
+
+#[pymethods]
+impl PyReader {
+    #[staticmethod]
+    fn open(path: &str) -> PyResult<Self> {
+        let file = File::open(path).map_err(to_py_error)?;
+        let source: Box<dyn Source> = Box::new(BufReader::new(file));
+        let reader = PfaReader::new(source).map_err(to_py_error)?;
+        Ok(Self { reader })
+    }
+
+    #[staticmethod]
+    fn from_bytes(bytes: Vec<u8>) -> PyResult<Self> {
+        let source: Box<dyn Source> = Box::new(Cursor::new(bytes));
+        let reader = PfaReader::new(source).map_err(to_py_error)?;
+        Ok(Self { reader })
+    }
+
+    #[getter]
+    fn name(&self) -> String {
+        self.reader.get_name().to_string()
+    }
+
+    fn exists(&self, path: &str) -> PyResult<bool> {
+        self.reader.exists(path).map_err(to_py_error)
+    }
+
+    /// Paths of the entries in a directory, None if it doesn't exist
+    fn list(&mut self, path: &str) -> PyResult<Option<Vec<String>>> {
+        let dir = self.reader.get_directory(path, None).map_err(to_py_error)?;
+        Ok(dir.map(|dir| dir.get_contents().iter().map(|x| x.to_string()).collect()))
+    }
+
+    /// Paths of every file in the archive
+    fn files(&mut self) -> PyResult<Vec<String>> {
+        let mut files = vec![];
+        let mut pending = vec!["/".to_string()];
+        while let Some(dir) = pending.pop() {
+            for path in self.list(&dir)?.unwrap_or_default() {
+                if path.ends_with('/') {
+                    pending.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Contents of a file, None if it doesn't exist
+    #[pyo3(signature = (path, key = None))]
+    fn read<'py>(
+        &mut self,
+        py: Python<'py>,
+        path: &str,
+        key: Option<&[u8]>,
+    ) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        let file = self
+            .reader
+            .get_file(path, to_key(key)?)
+            .map_err(to_py_error)?;
+        Ok(file.map(|x| PyBytes::new(py, x.get_contents())))
+    }
+}
+
+fn open_archive(path: &str) -> PyResult<PfaReader<BufReader<File>>> {
+    PfaReader::open(path).map_err(to_py_error)
+}
+
+/// Writes a diff archive which turns `old` into `new`
+#[pyfunction]
+fn create_diff(old: &str, new: &str, output: &str) -> PyResult<()> {
+    let out = BufWriter::new(File::create(output).map_err(to_py_error)?);
+    pfadiff_lib::create_diff(open_archive(old)?, open_archive(new)?, out)
+        .map_err(|e| to_py_error(format!("{e:#}")))
+}
+
+/// Applies a diff archive to `old`, writing the result to `output`
+#[pyfunction]
+fn apply_diff(old: &str, diff: &str, output: &str) -> PyResult<()> {
+    let out = BufWriter::new(File::create(output).map_err(to_py_error)?);
+    pfadiff_lib::apply_diff(open_archive(old)?, open_archive(diff)?, out)
+        .map_err(|e| to_py_error(format!("{e:#}")))
+}
+
+#[pymodule]
+fn pfa_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyBuilder>()?;
+    m.add_class::<PyReader>()?;
+    m.add_function(wrap_pyfunction!(create_diff, m)?)?;
+    m.add_function(wrap_pyfunction!(apply_diff, m)?)?;
+    m.add("PfaException", m.py().get_type::<PfaException>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        Python::initialize();
+        Python::attach(|py| {
+            let pfa_py = pyo3::wrap_pymodule!(pfa_py)(py);
+            pyo3::py_run!(
+                py,
+                pfa_py,
+                r#"
+builder = pfa_py.Builder("round_trip")
+builder.add_file("/a.txt", b"hello")
+builder.add_file("/dir/b.txt", b"world" * 100, compression="forced")
+builder.add_file("/dir/secret.txt", b"secret", key=bytes(32))
+reader = pfa_py.Reader.from_bytes(builder.build())
+
+assert reader.name == "round_trip"
+assert reader.exists("/dir/b.txt")
+assert not reader.exists("/missing.txt")
+assert sorted(reader.list("/")) == ["/a.txt", "/dir/"]
+assert reader.list("/missing/") is None
+assert reader.files() == ["/a.txt", "/dir/b.txt", "/dir/secret.txt"]
+assert reader.read("/a.txt") == b"hello"
+assert reader.read("/dir/b.txt") == b"world" * 100
+assert reader.read("/dir/secret.txt", key=bytes(32)) == b"secret"
+assert reader.read("/missing.txt") is None
+
+try:
+    builder.build()
+    assert False, "built twice"
+except pfa_py.PfaException:
+    pass
+"#
+            );
+        });
+    }
+}
@@ -0,0 +1,74 @@
+//! Ships downloadable content as an encrypted archive next to the base game, and unlocks it once
+//! the player has a key. The soundtrack is encrypted in frames, so it can be streamed without
+//! decrypting it whole.
+//!
+//! cargo run -p pfa --example encrypted_dlc
+
+use std::io::Read;
+
+use pfa::{builder::PfaBuilder, reader::PfaReader, shared::DataFlags, PfaError};
+
+fn main() -> Result<(), PfaError> {
+    let mut base = PfaBuilder::new("base");
+    base.add_file("/levels/1.map", b"level one".to_vec(), DataFlags::auto())?;
+    let base = base.build()?;
+
+    // the key would normally come from a license server once the DLC is bought
+    let key = DataFlags::generate_key();
+    let mut dlc = PfaBuilder::new("dlc_winter");
+    dlc.set_encryption_key(key);
+    dlc.add_file(
+        "/levels/winter.map",
+        b"snowy level".to_vec(),
+        DataFlags::auto(),
+    )?;
+    let soundtrack = (0..=255).cycle().take(256 * 1024).collect::<Vec<u8>>();
+    dlc.add_file(
+        "/music/winter.ogg",
+        soundtrack.clone(),
+        DataFlags::no_compression().encryption_frames(Some(16 * 1024)),
+    )?;
+    let dlc = dlc.build()?;
+
+    let mut base = PfaReader::from_bytes(base)?;
+    let mut dlc = PfaReader::from_bytes(dlc)?;
+    println!(
+        "base level: {}",
+        String::from_utf8_lossy(
+            base.get_file("/levels/1.map", None)?
+                .unwrap()
+                .get_contents()
+        )
+    );
+
+    // without the key the DLC can be listed but not read
+    match dlc.get_file("/levels/winter.map", None) {
+        Err(e @ PfaError::EncryptedFileKeyNotProvided) => {
+            println!("winter level without a key: {e}")
+        }
+        _ => unreachable!("the winter level is encrypted"),
+    }
+    let wrong = dlc.get_file("/levels/winter.map", Some(DataFlags::generate_key()));
+    assert!(matches!(wrong, Err(PfaError::FileDecryptError)));
+
+    let level = dlc.get_file("/levels/winter.map", Some(key))?.unwrap();
+    println!(
+        "winter level with the key: {}",
+        String::from_utf8_lossy(level.get_contents())
+    );
+
+    let mut music = dlc.open_file("/music/winter.ogg", Some(key))?.unwrap();
+    assert!(music.is_streaming());
+    let mut chunk = vec![0; 4096];
+    let mut streamed = 0;
+    loop {
+        let read = music.read(&mut chunk).map_err(PfaError::IOError)?;
+        if read == 0 {
+            break;
+        }
+        assert_eq!(chunk[..read], soundtrack[streamed..streamed + read]);
+        streamed += read;
+    }
+    println!("streamed {streamed} bytes of music one frame at a time");
+    Ok(())
+}
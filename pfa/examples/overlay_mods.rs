@@ -0,0 +1,79 @@
+//! Layers mod archives over a base game archive, so a mod can replace or add files without
+//! repacking the base game. Lookups go through the layers from the last mod loaded down to the
+//! base game.
+//!
+//! cargo run -p pfa --example overlay_mods
+
+use std::{collections::BTreeSet, io::Cursor};
+
+use pfa::{
+    builder::PfaBuilder,
+    reader::{pfa_reader::PfaFileContents, PfaReader},
+    shared::DataFlags,
+    PfaError,
+};
+
+struct Overlay {
+    /// The base game first, then mods in load order
+    layers: Vec<PfaReader<Cursor<Vec<u8>>>>,
+}
+
+impl Overlay {
+    fn get_file(&mut self, path: &str) -> Result<Option<(String, PfaFileContents)>, PfaError> {
+        for layer in self.layers.iter_mut().rev() {
+            if let Some(file) = layer.get_file(path, None)? {
+                return Ok(Some((layer.get_name().to_string(), file)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Union of the directory's contents in every layer
+    fn list(&mut self, path: &str) -> Result<BTreeSet<String>, PfaError> {
+        let mut listing = BTreeSet::new();
+        for layer in &mut self.layers {
+            if let Some(dir) = layer.get_directory(path, None)? {
+                listing.extend(dir.get_contents().iter().map(|x| x.to_string()));
+            }
+        }
+        Ok(listing)
+    }
+}
+
+fn archive(name: &str, files: &[(&str, &str)]) -> Result<PfaReader<Cursor<Vec<u8>>>, PfaError> {
+    let mut builder = PfaBuilder::new(name);
+    for (path, contents) in files {
+        builder.add_file(path, contents.as_bytes().to_vec(), DataFlags::auto())?;
+    }
+    PfaReader::from_bytes(builder.build()?)
+}
+
+fn main() -> Result<(), PfaError> {
+    let base = archive(
+        "base",
+        &[
+            ("/units/knight.toml", "health = 100"),
+            ("/units/archer.toml", "health = 60"),
+        ],
+    )?;
+    let balance = archive("balance_mod", &[("/units/archer.toml", "health = 75")])?;
+    let dragons = archive("dragon_mod", &[("/units/dragon.toml", "health = 500")])?;
+    let mut overlay = Overlay {
+        layers: vec![base, balance, dragons],
+    };
+
+    for path in overlay.list("/units/")? {
+        let (layer, file) = overlay.get_file(&path)?.expect("listed files exist");
+        println!(
+            "{path:<20} from {layer:<12} {}",
+            String::from_utf8_lossy(file.get_contents())
+        );
+    }
+
+    let (layer, _) = overlay.get_file("/units/archer.toml")?.unwrap();
+    assert_eq!(layer, "balance_mod");
+    let (layer, _) = overlay.get_file("/units/knight.toml")?.unwrap();
+    assert_eq!(layer, "base");
+    assert!(overlay.get_file("/units/missing.toml")?.is_none());
+    Ok(())
+}
@@ -0,0 +1,91 @@
+//! Packs the same asset directory with a development, a shipping and a memory mapped profile,
+//! then checks every build reads back the same files.
+//!
+//! cargo run -p pfa --example pack_with_profiles
+
+use std::path::Path;
+
+use pfa::{
+    builder::PfaBuilder,
+    reader::PfaReader,
+    shared::{DataFlags, Ulid},
+    PfaError,
+};
+
+/// Writes a small game asset tree to `dir`
+fn write_assets(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir.join("textures/ui"))?;
+    std::fs::create_dir_all(dir.join("config"))?;
+    std::fs::write(dir.join("textures/grass.raw"), vec![0x4a; 64 * 1024])?;
+    std::fs::write(dir.join("textures/ui/button.raw"), vec![0x11; 8 * 1024])?;
+    std::fs::write(
+        dir.join("config/game.toml"),
+        "difficulty = \"normal\"\n".repeat(50),
+    )?;
+    Ok(())
+}
+
+/// Fast to build, keeps where every file came from
+fn development(dir: &str) -> Result<Vec<u8>, PfaError> {
+    let mut builder = PfaBuilder::new("assets");
+    builder.set_record_provenance(true);
+    builder.set_provenance_tool("pack_with_profiles dev");
+    builder.set_sniff_content_types(true);
+    builder.include_directory(dir, DataFlags::auto())?;
+    builder.build()
+}
+
+/// Smaller and more robust, without paths from the build machine
+fn shipping(dir: &str) -> Result<Vec<u8>, PfaError> {
+    let mut builder = PfaBuilder::new("assets");
+    builder.set_default_flags(DataFlags::forced_compression().error_correction(Some(0.05)));
+    builder.set_entry_hashes(true);
+    builder.set_strip_provenance(true);
+    builder.include_directory(dir, DataFlags::auto())?;
+    builder.build()
+}
+
+/// Every file stored as is and page aligned, so it can be borrowed from a memory mapping
+fn mapped(dir: &str) -> Result<Vec<u8>, PfaError> {
+    let mut builder = PfaBuilder::new("assets");
+    builder.set_store_only(4096);
+    builder.include_directory(dir, DataFlags::auto())?;
+    builder.build()
+}
+
+fn main() -> Result<(), PfaError> {
+    let dir = std::env::temp_dir().join(format!("pfa_profiles_{}", Ulid::new()));
+    write_assets(&dir)?;
+    let dir_str = dir.to_str().expect("temp dir is valid utf8");
+
+    let profiles = [
+        ("development", development(dir_str)?),
+        ("shipping", shipping(dir_str)?),
+        ("mapped", mapped(dir_str)?),
+    ];
+    for (name, bytes) in &profiles {
+        let mut reader = PfaReader::from_slice(bytes)?;
+        let grass = reader
+            .get_file("/textures/grass.raw", None)?
+            .ok_or(PfaError::CustomError("grass.raw is missing".into()))?;
+        assert_eq!(grass.get_contents(), vec![0x4a; 64 * 1024]);
+        println!(
+            "{name:<12} {:>7} bytes, grass.raw borrowed without copying: {}",
+            bytes.len(),
+            reader.get_file_slice("/textures/grass.raw")?.is_some()
+        );
+    }
+
+    let dev = PfaReader::from_slice(&profiles[0].1)?;
+    if let Some(source) = dev
+        .get_provenance("/config/game.toml")?
+        .and_then(|x| x.source_path)
+    {
+        println!("development build records game.toml came from {source}");
+    }
+    let shipping = PfaReader::from_slice(&profiles[1].1)?;
+    assert!(shipping.get_provenance("/config/game.toml")?.is_none());
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
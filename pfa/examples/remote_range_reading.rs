@@ -0,0 +1,91 @@
+//! Reads parts of an archive hosted remotely, fetching only the byte ranges the reader asks for.
+//! The remote is simulated by a source which counts the requests it serves, standing in for HTTP
+//! range requests.
+//!
+//! cargo run -p pfa --example remote_range_reading
+
+use std::{
+    cell::Cell,
+    io::{Read, Seek, SeekFrom},
+    rc::Rc,
+};
+
+use pfa::{
+    builder::PfaBuilder,
+    reader::{PfaReader, PfaReaderOptions, RetryPolicy},
+    shared::DataFlags,
+    PfaError,
+};
+
+/// Serves reads from a remote archive, each read being one range request
+struct RemoteArchive {
+    archive: Vec<u8>,
+    position: u64,
+    /// Shared with the caller, since the reader takes ownership of the source
+    requests: Rc<Cell<usize>>,
+    fetched: Rc<Cell<usize>>,
+}
+
+impl Read for RemoteArchive {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = (self.position as usize).min(self.archive.len());
+        let end = (start + buf.len()).min(self.archive.len());
+        buf[..end - start].copy_from_slice(&self.archive[start..end]);
+        self.position = end as u64;
+        self.requests.set(self.requests.get() + 1);
+        self.fetched.set(self.fetched.get() + end - start);
+        Ok(end - start)
+    }
+}
+
+impl Seek for RemoteArchive {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(x) => x,
+            SeekFrom::End(x) => self.archive.len() as u64 + x as u64,
+            SeekFrom::Current(x) => self.position + x as u64,
+        };
+        Ok(self.position)
+    }
+}
+
+fn main() -> Result<(), PfaError> {
+    let mut builder = PfaBuilder::new("remote");
+    for i in 0..16 {
+        let video = vec![i; 1024 * 1024];
+        builder.add_file(
+            &format!("/videos/{i}.bin"),
+            video,
+            DataFlags::no_compression(),
+        )?;
+    }
+    let archive = builder.build()?;
+    let total = archive.len();
+
+    let (requests, fetched) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+    let remote = RemoteArchive {
+        archive,
+        position: 0,
+        requests: requests.clone(),
+        fetched: fetched.clone(),
+    };
+    // remote sources can fail transiently, the retry policy hides that from the reader
+    let options = PfaReaderOptions::new().retry_policy(RetryPolicy::new());
+    let mut reader = PfaReader::new_with_options(remote, options)?;
+
+    // seek into the middle of one video without downloading the rest of the archive
+    let range = reader
+        .read_range("/videos/7.bin", 512 * 1024, 4096)?
+        .unwrap();
+    assert_eq!(range, vec![7; 4096]);
+    let info = reader.get_file_info("/videos/7.bin")?.unwrap();
+    println!("/videos/7.bin is {} bytes", info.contents_size.unwrap());
+
+    println!(
+        "fetched {} of {total} bytes in {} requests",
+        fetched.get(),
+        requests.get()
+    );
+    assert!(fetched.get() < total / 100);
+    Ok(())
+}
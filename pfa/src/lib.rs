@@ -1,5 +1,8 @@
+#[cfg(feature = "lua")]
+pub mod lua;
 pub mod reader;
 pub mod shared;
+pub mod telemetry;
 pub mod writer;
 use std::string::FromUtf8Error;
 
@@ -36,15 +39,83 @@ pub enum PfaError {
     #[error("Failed to decompress: {0}")]
     FailedDecompressionError(#[from] DecompressError),
 
+    #[error("{0}")]
+    NotFound(Box<reader::ResolutionTrace>),
+
+    #[error("Unsupported feature: {0}")]
+    UnsupportedFeature(String),
+
+    #[error("Directories can't be listed in archives with hashed names")]
+    ListingDisabledError,
+
+    #[error("Value out of range while writing {0}")]
+    WriterOverflowError(&'static str),
+
+    #[error("String '{string}' of length {} is larger than max string size of {max}", string.len())]
+    StringTooLongError { string: String, max: usize },
+
+    #[error("'{path}' is nested more than {limit} directories deep")]
+    DepthLimitError { path: String, limit: usize },
+
+    #[error("Invalid glob: {0}")]
+    InvalidGlobError(String),
+
+    #[error("Version {version} archives can't store {}", features.join(", "))]
+    UnsupportedByVersion { version: u8, features: Vec<String> },
+
+    #[error("'{entry}' is compressed with {codec}, which this build can't decode")]
+    UnsupportedCodec { entry: String, codec: String },
+
+    #[error("'{0}' was added more than once")]
+    DuplicatePath(String),
+
+    #[error("Decompressed data would be larger than the limit of {limit} bytes")]
+    DecompressionLimitError { limit: u64 },
+
+    #[error("Data of '{entry}' lies outside the data section")]
+    DataOutOfBoundsError { entry: String },
+
+    #[error("Damaged beyond repair: {}", .0.join(", "))]
+    Unrecoverable(Vec<String>),
+
     #[error("Unknown PFA error")]
     Unknown,
 }
 
+/// Embeds an archive in the executable and opens it without copying it, see
+/// [`PfaReader::from_slice`](crate::reader::PfaReader::from_slice). Archives can be packed at
+/// compile time with [`pack_directory`](crate::writer::pack::pack_directory).
+#[macro_export]
+macro_rules! include_pfa {
+    ($path:expr) => {
+        $crate::reader::PfaReader::from_slice(include_bytes!($path))
+    };
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io::{Cursor, Write};
+    use std::io::{Cursor, Read, Write};
 
-    use crate::{builder::PfaBuilder, reader::PfaReader, shared::DataFlags};
+    use crate::{
+        builder::PfaBuilder,
+        reader::{
+            pfa_reader::{PfaDirectoryContents, PfaFileContents, PfaPath},
+            BackgroundVerifyOptions, EntryFilter, EntryKind, ExtractorOptions, PfaEncryptedEntry,
+            PfaProbe, PfaReader, PfaReaderOptions, PfaResolver, PfaVisitor, ReaderWarning,
+            ScrubIssue, ScrubTask, SourceCheck, TraversalOptions, TraversalOrder, VisitControl,
+        },
+        shared::{
+            entry_hash::EntryProof, CatalogLayout, Cipher, DataCompressionType, DataFlags,
+            NameEncoding, Provenance, StoredCodec, Tier, TransformChain, Ulid, LATEST_VERSION,
+        },
+        writer::{
+            build_cache::BuildCache,
+            directory_limits::{DirectoryLimits, LimitWarning},
+            pack::pack_directory_into,
+            pfa_editor::PfaEditor,
+        },
+        PfaError,
+    };
 
     #[test]
     fn test_1() {
@@ -81,9 +152,14 @@ mod tests {
             )
             .unwrap();
 
+        let archive_id = builder.get_archive_id();
         let bytes = builder.build().unwrap();
-        let mut f = std::fs::File::create("out.pfa").unwrap();
+        let path = std::env::temp_dir().join(format!("pfa_test_1_{archive_id}.pfa"));
+        let mut f = std::fs::File::create(&path).unwrap();
         f.write_all(&bytes).unwrap();
+        drop(f);
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        std::fs::remove_file(&path).unwrap();
         let mut reader = PfaReader::new(Cursor::new(bytes)).unwrap();
         let mut files = vec![];
         reader.traverse_files("/", |file| {
@@ -91,7 +167,8 @@ mod tests {
         });
 
         assert_eq!(&reader.get_name(), &"epic_name");
-        assert_eq!(reader.get_version(), 1);
+        assert_eq!(reader.get_version(), 2);
+        assert_eq!(reader.get_archive_id(), Some(archive_id));
         assert_eq!(reader.get_extra_data().len(), 0);
 
         let f = files.pop().unwrap();
@@ -131,4 +208,4300 @@ mod tests {
 
         let _ = builder.build().unwrap();
     }
+
+    #[test]
+    fn test_extract_streaming() {
+        let mut builder = PfaBuilder::new("streaming");
+        for i in 0..50 {
+            builder
+                .add_file(
+                    &format!("dir_{}/file_{i}.txt", i % 4),
+                    vec![i as u8; 300 * i],
+                    DataFlags::auto().error_correction(Some(0.1)),
+                )
+                .unwrap();
+        }
+        builder
+            .add_file(
+                "encrypted.txt",
+                vec![1; 20],
+                DataFlags::auto().encryption(Some(DataFlags::generate_key())),
+            )
+            .unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        let mut files = vec![];
+        let mut failed = 0;
+        reader
+            .extract_streaming(
+                "/",
+                ExtractorOptions::new().read_ahead(3).decode_threads(4),
+                |file| {
+                    match file {
+                        Ok(file) => files.push(file),
+                        Err(_) => failed += 1,
+                    }
+                    Ok::<_, PfaError>(())
+                },
+            )
+            .unwrap();
+
+        assert_eq!(files.len(), 50);
+        assert_eq!(failed, 1);
+        for file in files {
+            let expected = reader
+                .get_file(&file.get_path().to_string()[..], None)
+                .unwrap()
+                .unwrap();
+            assert_eq!(file.get_contents(), expected.get_contents());
+        }
+
+        let mut seen = 0;
+        let res = reader.extract_streaming("/dir_1/", ExtractorOptions::new(), |_| {
+            seen += 1;
+            if seen == 3 {
+                return Err(PfaError::Unknown);
+            }
+            Ok(())
+        });
+        assert!(matches!(res, Err(PfaError::Unknown)));
+        assert_eq!(seen, 3);
+    }
+
+    #[test]
+    fn test_add_entries() {
+        let mut builder = PfaBuilder::new("entries");
+        let entries = (0..10).map(|i| {
+            let reader: Box<dyn std::io::Read + Send> =
+                Box::new(Cursor::new(vec![i as u8; i * 100]));
+            (format!("/generated/{i}.bin"), reader, DataFlags::auto())
+        });
+        builder.add_entries(entries).unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        for i in 0..10 {
+            let file = reader
+                .get_file(&format!("/generated/{i}.bin")[..], None)
+                .unwrap()
+                .unwrap();
+            assert_eq!(file.get_contents(), vec![i as u8; i * 100]);
+        }
+    }
+
+    #[test]
+    fn test_add_file_from_reader() {
+        let key = DataFlags::generate_key();
+        let store = DataFlags::no_compression;
+        let flag_sets = [
+            store(),
+            store().error_correction(Some(0.2)),
+            store().encryption(Some(key)).encryption_frames(Some(1000)),
+            store()
+                .encryption(Some(key))
+                .encryption_frames(Some(1000))
+                .error_correction(Some(0.1)),
+            DataFlags::auto(),
+        ];
+        let sizes = [0, 5000, 5001, 64 * 1024 * 2 + 17];
+
+        let mut streamed = PfaBuilder::new("streamed");
+        let mut buffered = PfaBuilder::new("buffered");
+        for (i, flags) in flag_sets.iter().enumerate() {
+            for size in sizes {
+                let path = format!("/{i}/{size}.bin");
+                let contents = (0..size).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+                streamed
+                    .add_file_from_reader(&path, Cursor::new(contents.clone()), flags.clone())
+                    .unwrap();
+                buffered.add_file(&path, contents, flags.clone()).unwrap();
+            }
+        }
+        let mut streamed = PfaReader::new(Cursor::new(streamed.build().unwrap())).unwrap();
+        let mut buffered = PfaReader::new(Cursor::new(buffered.build().unwrap())).unwrap();
+        for i in 0..flag_sets.len() {
+            for size in sizes {
+                let path = format!("/{i}/{size}.bin");
+                let stored = streamed.get_stored_file(&path[..]).unwrap().unwrap();
+                let key = DataFlags::is_encrypted(stored.get_flags()).then_some(key);
+                let file = streamed.get_file(&path[..], key).unwrap().unwrap();
+                let contents = (0..size).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+                assert_eq!(file.get_contents(), contents);
+
+                let expected = buffered.get_stored_file(&path[..]).unwrap().unwrap();
+                assert_eq!(stored.get_flags(), expected.get_flags());
+                match DataFlags::is_encrypted(stored.get_flags()) {
+                    true => assert_eq!(stored.get_contents().len(), expected.get_contents().len()),
+                    false => assert_eq!(stored.get_contents(), expected.get_contents()),
+                }
+            }
+        }
+
+        struct FailingReader;
+        impl std::io::Read for FailingReader {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("source failed"))
+            }
+        }
+        let mut builder = PfaBuilder::new("failing");
+        builder
+            .add_file_from_reader("/failing.bin", FailingReader, store())
+            .unwrap();
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn test_add_file_from_path() {
+        let dir = std::env::temp_dir().join(format!("pfa_path_{}", crate::shared::Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), b"old").unwrap();
+
+        let mut builder = PfaBuilder::new("paths");
+        builder
+            .add_file_from_path("/a.txt", dir.join("a.txt"), DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file_from_path("/b.txt", dir.join("b.txt"), DataFlags::no_compression())
+            .unwrap();
+        // files are only read when the archive is built
+        std::fs::write(dir.join("a.txt"), b"new").unwrap();
+        std::fs::write(dir.join("b.txt"), vec![7; 100_000]).unwrap();
+        let bytes = builder.build().unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(bytes)).unwrap();
+        let file = reader.get_file("/a.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"new");
+        let file = reader.get_file("/b.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), vec![7; 100_000]);
+
+        let mut builder = PfaBuilder::new("missing");
+        builder
+            .add_file_from_path("/c.txt", dir.join("c.txt"), DataFlags::auto())
+            .unwrap();
+        assert!(builder.build().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_streaming_builder() {
+        use crate::writer::streaming_builder::PfaStreamingBuilder;
+        struct FailingReader;
+        impl std::io::Read for FailingReader {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("source failed"))
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("pfa_stream_{}", crate::shared::Ulid::new()));
+        std::fs::create_dir_all(dir.join("maps")).unwrap();
+        std::fs::write(dir.join("maps/a.map"), vec![1; 5000]).unwrap();
+        std::fs::write(dir.join("readme.txt"), b"hello").unwrap();
+        let key = DataFlags::generate_key();
+        let framed = DataFlags::no_compression()
+            .encryption(Some(key))
+            .encryption_frames(Some(100));
+
+        let mut streaming = PfaStreamingBuilder::new("stream", Cursor::new(vec![])).unwrap();
+        streaming.builder().set_entry_hashes(true);
+        streaming.builder().set_record_provenance(true);
+        let dir_path = format!("{}/", dir.to_str().unwrap().replace('\\', "/"));
+        streaming
+            .include_directory(&dir_path, DataFlags::auto())
+            .unwrap();
+        streaming
+            .add_file_from_reader("/secret.bin", Cursor::new(vec![2; 1000]), framed)
+            .unwrap();
+        let failing = std::io::Read::chain(Cursor::new(vec![3; 10]), FailingReader);
+        assert!(streaming
+            .add_file_from_reader("/failed.bin", failing, DataFlags::no_compression())
+            .is_err());
+        streaming
+            .add_file_from_reader("/random.bin", Cursor::new(vec![4, 5, 6]), DataFlags::auto())
+            .unwrap();
+        streaming
+            .builder()
+            .add_file("/memory.txt", b"in memory".to_vec(), DataFlags::auto())
+            .unwrap();
+        let spilled = streaming.spilled();
+        let mut out = Cursor::new(vec![]);
+        streaming.build_into(&mut out).unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(out.into_inner())).unwrap();
+        assert_eq!(reader.warnings(), []);
+        assert!(spilled > 0 && reader.get_unreferenced_data().unwrap().is_empty());
+        let read = |reader: &mut PfaReader<_>, path: &str, key| {
+            reader
+                .get_file(path, key)
+                .unwrap()
+                .map(|x| x.into_contents())
+        };
+        assert_eq!(
+            read(&mut reader, "/maps/a.map", None).unwrap(),
+            vec![1; 5000]
+        );
+        assert_eq!(read(&mut reader, "/readme.txt", None).unwrap(), b"hello");
+        assert_eq!(
+            read(&mut reader, "/secret.bin", Some(key)).unwrap(),
+            vec![2; 1000]
+        );
+        assert_eq!(read(&mut reader, "/random.bin", None).unwrap(), [4, 5, 6]);
+        assert_eq!(
+            read(&mut reader, "/memory.txt", None).unwrap(),
+            b"in memory"
+        );
+        assert_eq!(read(&mut reader, "/failed.bin", None), None);
+        assert!(reader.is_compression_skipped("/random.bin").unwrap());
+        let provenance = reader.get_provenance("/readme.txt").unwrap().unwrap();
+        assert_eq!(
+            provenance.source_hash,
+            Some(*blake3::hash(b"hello").as_bytes())
+        );
+
+        // entry hashes match those of an archive built in memory
+        let mut builder = PfaBuilder::new("memory");
+        builder.set_entry_hashes(true);
+        builder
+            .include_directory(&dir_path, DataFlags::auto())
+            .unwrap();
+        let memory = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        for path in ["/maps/a.map", "/readme.txt", "/maps/"] {
+            assert_eq!(
+                reader.get_entry_hash(path).unwrap(),
+                memory.get_entry_hash(path).unwrap()
+            );
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_streaming_checkpoint() {
+        use crate::writer::streaming_builder::PfaStreamingBuilder;
+
+        let dir = std::env::temp_dir().join(format!("pfa_resume_{}", crate::shared::Ulid::new()));
+        let src = dir.join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("a.txt"), vec![1; 3000]).unwrap();
+        std::fs::write(src.join("b.txt"), vec![2; 2000]).unwrap();
+        std::fs::write(src.join("deleted.txt"), vec![6; 10]).unwrap();
+        let src_path = format!("{}/", src.to_str().unwrap().replace('\\', "/"));
+        let (spill_path, checkpoint_path) = (dir.join("spill"), dir.join("checkpoint"));
+        let open_spill = || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&spill_path)
+                .unwrap()
+        };
+
+        let mut streaming = PfaStreamingBuilder::new("resume", open_spill()).unwrap();
+        streaming.set_checkpoint(&checkpoint_path, 1);
+        streaming
+            .include_directory(&src_path, DataFlags::no_compression())
+            .unwrap();
+        streaming
+            .add_file_from_reader(
+                "/gen.bin",
+                Cursor::new(vec![3; 100]),
+                DataFlags::no_compression(),
+            )
+            .unwrap();
+        streaming
+            .add_file_from_reader(
+                "/tab\tand\nnewline.bin",
+                Cursor::new(vec![7; 10]),
+                DataFlags::no_compression(),
+            )
+            .unwrap();
+        // interrupted after spilling a file the checkpoint doesn't have yet
+        streaming.set_checkpoint(&checkpoint_path, u64::MAX);
+        streaming
+            .add_file_from_reader("/lost.bin", Cursor::new(vec![4; 100]), DataFlags::auto())
+            .unwrap();
+        drop(streaming);
+        std::fs::write(src.join("b.txt"), vec![5; 1000]).unwrap();
+        std::fs::remove_file(src.join("deleted.txt")).unwrap();
+
+        let checkpoint = std::fs::read(&checkpoint_path).unwrap();
+        let truncated = Cursor::new(vec![0; 10]);
+        assert!(PfaStreamingBuilder::resume("resume", truncated, &checkpoint[..]).is_err());
+        let mut streaming =
+            PfaStreamingBuilder::resume("resume", open_spill(), &checkpoint[..]).unwrap();
+        streaming.builder().set_record_provenance(true);
+        let spilled = streaming.spilled();
+        assert_eq!(spilled, 3000 + 2000 + 10 + 100 + 10);
+        streaming
+            .include_directory(&src_path, DataFlags::no_compression())
+            .unwrap();
+        // only the modified file is read again
+        assert_eq!(streaming.spilled(), spilled + 1000);
+        let mut out = Cursor::new(vec![]);
+        streaming.build_into(&mut out).unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(out.into_inner())).unwrap();
+        // the reused file's provenance uses the hash taken when it was spilled
+        let provenance = reader.get_provenance("/a.txt").unwrap().unwrap();
+        assert_eq!(
+            provenance.source_hash,
+            Some(*blake3::hash(&[1; 3000]).as_bytes())
+        );
+        let mut read = |path: &str| {
+            reader
+                .get_file(path, None)
+                .unwrap()
+                .map(|x| x.into_contents())
+        };
+        assert_eq!(read("/a.txt").unwrap(), vec![1; 3000]);
+        assert_eq!(read("/b.txt").unwrap(), vec![5; 1000]);
+        assert_eq!(read("/gen.bin").unwrap(), vec![3; 100]);
+        assert_eq!(read("/tab\tand\nnewline.bin").unwrap(), vec![7; 10]);
+        assert_eq!(read("/lost.bin"), None);
+        // files read from disk which weren't seen again are left out
+        assert_eq!(read("/deleted.txt"), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_policy() {
+        use crate::builder::DuplicatePolicy;
+
+        let build = |policy: Option<DuplicatePolicy>| {
+            let mut builder = PfaBuilder::new("duplicates");
+            if let Some(policy) = policy {
+                builder.set_duplicate_policy(policy);
+            }
+            builder
+                .add_file("/a/b.txt", b"first".to_vec(), DataFlags::auto())
+                .unwrap();
+            builder.set_tier("/a/b.txt", Tier::High).unwrap();
+            builder.add_directory("/a/b.txt/").unwrap();
+            let added = builder.add_file("a/b.txt", b"second".to_vec(), DataFlags::auto());
+            (added, builder)
+        };
+
+        let (added, _) = build(None);
+        match added {
+            Err(PfaError::DuplicatePath(path)) => assert_eq!(path, "/a/b.txt"),
+            x => panic!("expected a duplicate path error, got {x:?}"),
+        }
+
+        for (policy, contents, tier) in [
+            (DuplicatePolicy::Overwrite, &b"second"[..], None),
+            (DuplicatePolicy::KeepFirst, &b"first"[..], Some(Tier::High)),
+        ] {
+            let (added, builder) = build(Some(policy));
+            added.unwrap();
+            let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+            let file = reader.get_file("/a/b.txt", None).unwrap().unwrap();
+            assert_eq!(file.into_contents(), contents);
+            assert_eq!(reader.stat("/a/b.txt").unwrap().unwrap().tier, tier);
+            assert_eq!(reader.stat("/a/").unwrap().unwrap().size, 2);
+        }
+    }
+
+    #[test]
+    fn test_directory_filter() {
+        use crate::writer::directory_filter::DirectoryFilter;
+
+        let dir = std::env::temp_dir().join(format!("pfa_filter_{}", crate::shared::Ulid::new()));
+        for path in [
+            "a.txt",
+            "b.tmp",
+            "notes~",
+            "sub/c.txt",
+            "sub/d.tmp",
+            "sub/build/f.txt",
+            "build/e.txt",
+        ] {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"x").unwrap();
+        }
+        let dir_path = format!("{}/", dir.to_str().unwrap().replace('\\', "/"));
+        let files = |filter: DirectoryFilter| {
+            let mut builder = PfaBuilder::new("filtered");
+            builder.set_directory_filter(filter);
+            builder
+                .include_directory(&dir_path, DataFlags::auto())
+                .unwrap();
+            let reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+            let mut files = reader
+                .glob("/**")
+                .unwrap()
+                .into_iter()
+                .filter(|x| !x.is_directory)
+                .map(|x| x.path.to_string())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+
+        assert_eq!(files(DirectoryFilter::new()).len(), 7);
+        let filter = DirectoryFilter::new()
+            .exclude("*.tmp")
+            .exclude("*~")
+            .exclude("build");
+        assert_eq!(files(filter), ["/a.txt", "/sub/c.txt"]);
+        let filter = DirectoryFilter::new().exclude("/build/**").include("*.txt");
+        assert_eq!(files(filter), ["/a.txt", "/sub/build/f.txt", "/sub/c.txt"]);
+        let filter = DirectoryFilter::new()
+            .include("/sub/**")
+            .predicate(|path| !path.ends_with(".tmp"));
+        assert_eq!(files(filter), ["/sub/build/f.txt", "/sub/c.txt"]);
+
+        let mut builder = PfaBuilder::new("invalid");
+        builder.set_directory_filter(DirectoryFilter::new().exclude("[a"));
+        assert!(matches!(
+            builder.include_directory(&dir_path, DataFlags::auto()),
+            Err(PfaError::InvalidGlobError(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_path_separator_normalization() {
+        let mut builder = PfaBuilder::new("separators");
+        builder
+            .add_file("dir\\sub\\a.txt", b"a".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/dir//b.txt", b"b".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder.set_tier("\\dir/sub\\a.txt", Tier::Low).unwrap();
+        assert!(matches!(
+            builder.add_file("/dir/sub/a.txt", b"c".to_vec(), DataFlags::auto()),
+            Err(PfaError::DuplicatePath(_))
+        ));
+        builder.rename("dir\\b.txt", "/dir/sub/b.txt").unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        let file = reader.get_file("/dir/sub/a.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"a");
+        assert_eq!(reader.get_tier("/dir/sub/a.txt").unwrap(), Some(Tier::Low));
+        assert!(reader.exists("/dir/sub/b.txt").unwrap());
+        assert_eq!(reader.stat("/dir/").unwrap().unwrap().size, 1);
+
+        // the root doesn't need a trailing separator
+        let dir = std::env::temp_dir().join(format!("pfa_separators_{}", Ulid::new()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub").join("c.txt"), b"c").unwrap();
+        let mut builder = PfaBuilder::new("separators");
+        builder
+            .include_directory(dir.to_str().unwrap(), DataFlags::auto())
+            .unwrap();
+        let reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        assert!(reader.exists("/sub/c.txt").unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_include_directory_rejects_separators_in_names() {
+        let dir = std::env::temp_dir().join(format!("pfa_backslash_{}", Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a\\b.txt"), b"x").unwrap();
+        let mut builder = PfaBuilder::new("backslash");
+        let error = builder
+            .include_directory(dir.to_str().unwrap(), DataFlags::auto())
+            .unwrap_err();
+        assert!(error.to_string().contains("contains a path separator"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_include_directory_windows_paths() {
+        let dir = std::env::temp_dir().join(format!("pfa_windows_{}", Ulid::new()));
+        std::fs::create_dir_all(dir.join("sub").join("deeper")).unwrap();
+        std::fs::write(dir.join("sub").join("deeper").join("a.txt"), b"a").unwrap();
+        std::fs::write(dir.join("b.txt"), b"b").unwrap();
+
+        // native backslashes, forward slashes and a mix of both all give the same paths
+        let native = format!("{}\\", dir.to_str().unwrap());
+        let forward = native.replace('\\', "/");
+        let mixed = format!("{}/", dir.to_str().unwrap());
+        for root in [native, forward, mixed] {
+            let mut builder = PfaBuilder::new("windows");
+            builder.include_directory(&root, DataFlags::auto()).unwrap();
+            let reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+            let mut files = reader
+                .glob("/**")
+                .unwrap()
+                .into_iter()
+                .filter(|x| !x.is_directory)
+                .map(|x| x.path.to_string())
+                .collect::<Vec<_>>();
+            files.sort();
+            assert_eq!(files, ["/b.txt", "/sub/deeper/a.txt"], "root {root}");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_long_archive_name_is_rejected() {
+        let builder = PfaBuilder::new(&"a".repeat(300));
+        assert!(matches!(
+            builder.build(),
+            Err(PfaError::StringTooLongError { max: 255, .. })
+        ));
+    }
+
+    #[test]
+    fn test_catalog_layouts() {
+        let build = |layout: Option<CatalogLayout>| {
+            let mut builder = PfaBuilder::new("layouts");
+            builder
+                .include_directory("./src", DataFlags::no_compression())
+                .unwrap();
+            if let Some(layout) = layout {
+                builder.set_catalog_layout(layout);
+            }
+            builder.build().unwrap()
+        };
+
+        let fixed = build(Some(CatalogLayout::Fixed));
+        let compact = build(Some(CatalogLayout::Compact));
+        let auto = build(None);
+        // the contents are identical, so the difference is entirely in the catalog
+        assert!(compact.len() < fixed.len());
+        assert_eq!(auto.len(), compact.len());
+
+        for (bytes, layout) in [(fixed, "fixed"), (compact, "compact")] {
+            let probe = PfaProbe::read(&mut Cursor::new(&bytes)).unwrap();
+            assert_eq!(probe.catalog_layout, layout.parse().unwrap());
+            assert_eq!(probe.catalog_layout.to_string(), layout);
+            assert_eq!(probe.version, 2);
+
+            let mut reader = PfaReader::new(Cursor::new(bytes)).unwrap();
+            assert_eq!(probe.entries, reader.entries().unwrap().count() as u64 + 1);
+            let file = reader.get_file("/reader/pfa_reader.rs", None).unwrap();
+            assert_eq!(
+                file.unwrap().get_contents(),
+                std::fs::read("./src/reader/pfa_reader.rs").unwrap()
+            );
+        }
+        assert!(PfaProbe::read(&mut Cursor::new(b"not an archive")).is_err());
+    }
+
+    #[test]
+    fn test_long_entry_names() {
+        let name = format!("/{}/{}.txt", "d".repeat(100), "f".repeat(200));
+        let mut builder = PfaBuilder::new("long_names");
+        builder
+            .add_file(&name, vec![1, 2, 3], DataFlags::auto())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.get_catalog_layout(), CatalogLayout::Compact);
+        let file = reader.get_file(&name[..], None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), [1, 2, 3]);
+
+        let mut builder = PfaBuilder::new("long_names");
+        builder.set_catalog_layout(CatalogLayout::Fixed);
+        builder
+            .add_file(&name, vec![1, 2, 3], DataFlags::auto())
+            .unwrap();
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn test_entry_hashes() {
+        let build = |contents: &[u8], entry_hashes: bool| {
+            let mut builder = PfaBuilder::new("hashes");
+            builder.set_entry_hashes(entry_hashes);
+            builder
+                .add_file("/a/same.txt", vec![1; 100], DataFlags::auto())
+                .unwrap();
+            builder
+                .add_file("/b/changed.txt", contents.to_vec(), DataFlags::auto())
+                .unwrap();
+            PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap()
+        };
+
+        let old = build(b"old", true);
+        let new = build(b"new", true);
+        assert!(old.has_entry_hashes());
+        assert_eq!(
+            old.get_entry_hash("/a/").unwrap(),
+            new.get_entry_hash("/a/").unwrap()
+        );
+        assert_eq!(
+            old.get_entry_hash("/a/same.txt").unwrap(),
+            new.get_entry_hash("/a/same.txt").unwrap()
+        );
+        assert_ne!(
+            old.get_entry_hash("/b/").unwrap(),
+            new.get_entry_hash("/b/").unwrap()
+        );
+        assert_ne!(old.get_root_hash(), new.get_root_hash());
+        assert_eq!(old.get_root_hash(), build(b"old", true).get_root_hash());
+        assert!(old.get_entry_hash("/missing/").unwrap().is_none());
+
+        let without = build(b"old", false);
+        assert!(!without.has_entry_hashes());
+        assert!(without.get_root_hash().is_none());
+    }
+
+    #[test]
+    fn test_entry_proofs() {
+        let mut builder = PfaBuilder::new("proofs");
+        builder.set_entry_hashes(true);
+        builder
+            .add_file("/top.txt", vec![1; 10], DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/a/b/deep.txt", vec![2; 500], DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/a/other.txt", vec![3; 20], DataFlags::no_compression())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+        let reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+        let root = reader.get_root_hash().unwrap();
+
+        for path in ["/top.txt", "/a/b/deep.txt", "/a/other.txt"] {
+            let proof = reader.get_entry_proof(path).unwrap().unwrap();
+            let proof = EntryProof::from_bytes(&proof.to_bytes().unwrap()).unwrap();
+            assert_eq!(proof.path(), path);
+
+            let start = proof.slice_offset as usize;
+            let mut slice = bytes[start..start + proof.slice_size as usize].to_vec();
+            assert!(proof.verify(&root, path, &slice));
+            assert!(!proof.verify(&root, "/a/b/other.txt", &slice));
+            slice[0] ^= 1;
+            assert!(!proof.verify(&root, path, &slice));
+        }
+
+        assert!(reader.get_entry_proof("/missing.txt").unwrap().is_none());
+        assert!(reader.get_entry_proof("/a/").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_include_directory_cached() {
+        let dir = std::env::temp_dir().join(format!("pfa_cache_{}", crate::shared::Ulid::new()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.txt"), "a".repeat(1000)).unwrap();
+        std::fs::write(dir.join("sub/b.txt"), "b").unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let build = |previous: Option<&mut PfaReader<Cursor<Vec<u8>>>>, cache: &BuildCache| {
+            let mut builder = PfaBuilder::new("cached");
+            let (cache, stats) = builder
+                .include_directory_cached(dir_str, DataFlags::auto(), previous, cache)
+                .unwrap();
+            let reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+            (reader, cache, stats)
+        };
+
+        let (mut first, cache, stats) = build(None, &BuildCache::default());
+        assert_eq!((stats.reused, stats.processed), (0, 2));
+
+        let mut bytes = vec![];
+        cache.write(&mut bytes).unwrap();
+        let cache = BuildCache::read(&bytes[..]).unwrap();
+        assert_eq!(cache.len(), 2);
+
+        std::fs::write(dir.join("sub/b.txt"), "changed").unwrap();
+        let (mut second, cache, stats) = build(Some(&mut first), &cache);
+        assert_eq!((stats.reused, stats.processed), (1, 1));
+        assert_eq!(
+            second
+                .get_file("/sub/b.txt", None)
+                .unwrap()
+                .unwrap()
+                .get_contents(),
+            b"changed"
+        );
+        assert_eq!(
+            second
+                .get_file("/a.txt", None)
+                .unwrap()
+                .unwrap()
+                .get_contents(),
+            "a".repeat(1000).as_bytes()
+        );
+
+        // a cache which doesn't belong to the previous archive is ignored
+        let (_, _, stats) = build(Some(&mut first), &cache);
+        assert_eq!(stats.reused, 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_threaded_build_is_deterministic() {
+        struct FailingReader;
+        impl std::io::Read for FailingReader {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("source failed"))
+            }
+        }
+
+        let archive_id = crate::shared::Ulid::new();
+        let build = |threads: usize| {
+            let mut builder = PfaBuilder::new("threads");
+            builder.set_archive_id(archive_id);
+            builder.set_threads(threads);
+            builder.set_entry_hashes(true);
+            for i in 0..50 {
+                builder
+                    .add_file(
+                        &format!("/dir{}/file{i}.bin", i % 4),
+                        (0..i * 300).map(|x| (x % 7) as u8).collect(),
+                        DataFlags::auto().error_correction((i % 3 == 0).then_some(0.2)),
+                    )
+                    .unwrap();
+            }
+            builder
+        };
+
+        let sequential = build(1).build().unwrap();
+        assert_eq!(build(4).build().unwrap(), sequential);
+
+        let mut builder = build(4);
+        let reader: Box<dyn std::io::Read + Send> = Box::new(FailingReader);
+        builder
+            .add_entries([("/failing.bin".to_string(), reader, DataFlags::auto())])
+            .unwrap();
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn test_variants() {
+        let mut builder = PfaBuilder::new("variants");
+        builder
+            .add_file("/textures/hero.png", b"default".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file_variant(
+                "/textures/hero.png",
+                "ps5",
+                b"ps5".to_vec(),
+                DataFlags::auto(),
+            )
+            .unwrap();
+        builder
+            .add_file_variant(
+                "/textures/hero.png",
+                "mobile",
+                b"mobile".to_vec(),
+                DataFlags::auto(),
+            )
+            .unwrap();
+        builder
+            .add_file_variant(
+                "/textures/only_mobile.png",
+                "mobile",
+                b"m".to_vec(),
+                DataFlags::auto(),
+            )
+            .unwrap();
+        builder
+            .add_file(
+                "/textures/not#variant.png",
+                b"plain".to_vec(),
+                DataFlags::auto(),
+            )
+            .unwrap();
+        assert!(builder
+            .add_file_variant("/a.png", "bad/tag", vec![], DataFlags::auto())
+            .is_err());
+        let bytes = builder.build().unwrap();
+
+        let open = |preference: &[&str]| {
+            let options = PfaReaderOptions::new()
+                .variant_preference(preference.iter().map(|x| x.to_string()).collect());
+            PfaReader::new_with_options(Cursor::new(bytes.clone()), options).unwrap()
+        };
+        let read = |reader: &mut PfaReader<_>, path: &str| {
+            reader
+                .get_file(path, None)
+                .unwrap()
+                .map(|x| x.get_contents().to_vec())
+        };
+
+        let mut reader = open(&["ps5", "mobile"]);
+        assert_eq!(reader.get_variants(), ["mobile", "ps5"]);
+        assert_eq!(read(&mut reader, "/textures/hero.png").unwrap(), b"ps5");
+        assert_eq!(
+            read(&mut reader, "/textures/hero.png#mobile").unwrap(),
+            b"mobile"
+        );
+        assert_eq!(
+            read(&mut reader, "/textures/only_mobile.png").unwrap(),
+            b"m"
+        );
+        assert_eq!(
+            read(&mut reader, "/textures/not#variant.png").unwrap(),
+            b"plain"
+        );
+
+        let mut reader = open(&[]);
+        assert_eq!(read(&mut reader, "/textures/hero.png").unwrap(), b"default");
+        assert!(read(&mut reader, "/textures/only_mobile.png").is_none());
+        let mut names = reader
+            .get_directory("/textures/", None)
+            .unwrap()
+            .unwrap()
+            .get_contents()
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["/textures/hero.png", "/textures/not#variant.png"]);
+
+        // extraction picks the same variants
+        for (preference, expected) in [
+            (
+                &["mobile"][..],
+                &[("hero.png", "mobile"), ("only_mobile.png", "m")][..],
+            ),
+            (&[], &[("hero.png", "default")]),
+        ] {
+            let mut extracted = vec![];
+            open(preference)
+                .extract_streaming("/textures/", ExtractorOptions::new(), |file| {
+                    let file = file?;
+                    extracted.push((file.get_name(), file.get_contents().to_vec()));
+                    Ok::<_, PfaError>(())
+                })
+                .unwrap();
+            extracted.retain(|x| x.0 != "not#variant.png");
+            extracted.sort();
+            let expected = expected
+                .iter()
+                .map(|(name, contents)| (name.to_string(), contents.as_bytes().to_vec()))
+                .collect::<Vec<_>>();
+            assert_eq!(extracted, expected);
+        }
+    }
+
+    #[test]
+    fn test_compression_skipped() {
+        let random = (0..8)
+            .flat_map(|_| DataFlags::generate_key())
+            .collect::<Vec<_>>();
+        let mut builder = PfaBuilder::new("skips");
+        builder
+            .add_file("/random.bin", random.clone(), DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/zeros.bin", vec![0; 1000], DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/forced.bin", random.clone(), DataFlags::no_compression())
+            .unwrap();
+        builder
+            .add_file("/marked.bin", vec![0; 1000], DataFlags::auto())
+            .unwrap();
+        builder.set_compression_skipped("/marked.bin").unwrap();
+        assert!(builder.set_compression_skipped("/missing.bin").is_err());
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+
+        let skipped = |reader: &PfaReader<_>| {
+            ["/random.bin", "/zeros.bin", "/forced.bin", "/marked.bin"]
+                .map(|x| reader.is_compression_skipped(x).unwrap())
+        };
+        assert_eq!(skipped(&reader), [true, false, false, true]);
+        let marked = reader.get_stored_file("/marked.bin").unwrap().unwrap();
+        assert_eq!(marked.get_contents(), vec![0; 1000]);
+
+        let stripped = reader.strip_tiers(Tier::High).unwrap().build().unwrap();
+        let reader = PfaReader::new(Cursor::new(stripped)).unwrap();
+        assert_eq!(skipped(&reader), [true, false, false, true]);
+    }
+
+    #[test]
+    fn test_min_compression_size() {
+        let paths = ["/tiny.txt", "/large.txt", "/own.txt", "/forced.txt"];
+        let mut builder = PfaBuilder::new("thresholds");
+        builder.set_min_compression_size(2000);
+        for path in paths {
+            let flags = match path {
+                "/own.txt" => DataFlags::auto().min_compression_size(Some(100)),
+                "/forced.txt" => DataFlags::forced_compression(),
+                _ => DataFlags::auto(),
+            };
+            let size = match path {
+                "/large.txt" => 4000,
+                _ => 1000,
+            };
+            builder.add_file(path, vec![0; size], flags).unwrap();
+        }
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        let compressed = paths.map(|x| reader.get_file_info(x).unwrap().unwrap().is_compressed());
+        assert_eq!(compressed, [false, true, true, true]);
+        assert!(reader.is_compression_skipped("/tiny.txt").unwrap());
+        let tiny = reader.get_file("/tiny.txt", None).unwrap().unwrap();
+        assert_eq!(tiny.get_contents(), vec![0; 1000]);
+
+        let flags = "auto,minsize=64".parse::<DataFlags>().unwrap();
+        assert_eq!(flags.to_string(), "auto,minsize=64");
+        assert!(!flags.is_auto());
+        assert_ne!(flags.fingerprint(), DataFlags::auto().fingerprint());
+    }
+
+    #[test]
+    fn test_stats() {
+        use crate::reader::CodecStats;
+
+        let mut builder = PfaBuilder::new("stats");
+        builder.set_solid_blocks(100, 1 << 16);
+        builder.set_reserved_space(16, 1 << 16);
+        for i in 0..3 {
+            builder
+                .add_file(
+                    &format!("/small/{i}.txt"),
+                    vec![b'a'; 50],
+                    DataFlags::auto(),
+                )
+                .unwrap();
+        }
+        builder
+            .add_file("/zeros.bin", vec![0; 4000], DataFlags::forced_compression())
+            .unwrap();
+        let raw = (0..3000).map(|x| (x * 7 % 251) as u8).collect::<Vec<_>>();
+        builder
+            .add_file("/raw.bin", raw, DataFlags::no_compression())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+        let mut reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+
+        let stats = reader.stats().unwrap();
+        assert_eq!(stats.files, 5);
+        assert_eq!(stats.unknown_size, 0);
+        assert_eq!(stats.original_bytes, 3 * 50 + 4000 + 3000);
+        assert_eq!(
+            stats.codecs["none"],
+            CodecStats {
+                files: 1,
+                stored_bytes: 3000,
+                original_bytes: 3000,
+            }
+        );
+        let zeros = reader.get_file_info("/zeros.bin").unwrap().unwrap();
+        assert_eq!(zeros.contents_size, Some(4000));
+        assert!(stats.stored_bytes < 3000 + zeros.size + 150);
+        assert!(stats.ratio().unwrap() < 1.0);
+
+        let stripped = reader.strip_tiers(Tier::High).unwrap().build().unwrap();
+        let stripped = PfaReader::new(Cursor::new(stripped)).unwrap();
+        assert_eq!(
+            stripped.stats().unwrap().original_bytes,
+            stats.original_bytes
+        );
+
+        let mut editor = PfaEditor::new(Cursor::new(bytes)).unwrap();
+        editor
+            .add_file("/added.bin", vec![1; 2000], DataFlags::forced_compression())
+            .unwrap();
+        let reader = PfaReader::new(Cursor::new(editor.into_inner().into_inner())).unwrap();
+        let added = reader.get_file_info("/added.bin").unwrap().unwrap();
+        assert_eq!(added.contents_size, None);
+        let zeros = reader.get_file_info("/zeros.bin").unwrap().unwrap();
+        assert_eq!(zeros.contents_size, Some(4000));
+        let edited = reader.stats().unwrap();
+        assert_eq!(edited.original_bytes, stats.original_bytes);
+        assert_eq!((edited.files, edited.unknown_size), (6, 1));
+    }
+
+    #[test]
+    fn test_precompressed_extensions() {
+        use crate::writer::streaming_builder::PfaStreamingBuilder;
+
+        let paths = [
+            "/image.png",
+            "/music.OGG",
+            "/notes.txt",
+            "/forced.zip",
+            "/.png",
+        ];
+        let build = |mut builder: PfaBuilder| {
+            for path in paths {
+                let flags = match path {
+                    "/forced.zip" => DataFlags::forced_compression(),
+                    _ => DataFlags::auto(),
+                };
+                builder.add_file(path, vec![0; 1000], flags).unwrap();
+            }
+            PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap()
+        };
+        let compressed = |reader: &PfaReader<_>| {
+            paths.map(|x| reader.get_file_info(x).unwrap().unwrap().is_compressed())
+        };
+
+        let reader = build(PfaBuilder::new("extensions"));
+        assert_eq!(compressed(&reader), [false, false, true, true, true]);
+        assert!(reader.is_compression_skipped("/image.png").unwrap());
+        assert!(!reader.is_compression_skipped("/notes.txt").unwrap());
+
+        let mut builder = PfaBuilder::new("extensions");
+        builder.set_precompressed_extensions(&[]);
+        assert_eq!(compressed(&build(builder)), [true; 5]);
+
+        let mut streaming = PfaStreamingBuilder::new("extensions", Cursor::new(vec![])).unwrap();
+        streaming
+            .add_file_from_reader("/image.png", Cursor::new(vec![0; 1000]), DataFlags::auto())
+            .unwrap();
+        let mut out = Cursor::new(vec![]);
+        streaming.build_into(&mut out).unwrap();
+        let reader = PfaReader::new(Cursor::new(out.into_inner())).unwrap();
+        let info = reader.get_file_info("/image.png").unwrap().unwrap();
+        assert!(!info.is_compressed());
+        assert!(reader.is_compression_skipped("/image.png").unwrap());
+    }
+
+    #[test]
+    fn test_tiers() {
+        let mut builder = PfaBuilder::new("tiers");
+        builder.set_entry_hashes(true);
+        for (path, tier) in [
+            ("/models/hero_low.bin", Tier::Low),
+            ("/models/hero_med.bin", Tier::Medium),
+            ("/models/hero_high.bin", Tier::High),
+        ] {
+            builder
+                .add_file(path, path.as_bytes().to_vec(), DataFlags::auto())
+                .unwrap();
+            builder.set_tier(path, tier).unwrap();
+        }
+        builder
+            .add_file("/config.txt", b"always".to_vec(), DataFlags::auto())
+            .unwrap();
+        assert!(builder.set_tier("/missing.txt", Tier::Low).is_err());
+        let bytes = builder.build().unwrap();
+
+        let options = PfaReaderOptions::new().max_tier(Tier::Medium);
+        let mut reader = PfaReader::new_with_options(Cursor::new(bytes.clone()), options).unwrap();
+        assert_eq!(
+            reader.get_tier("/models/hero_med.bin").unwrap(),
+            Some(Tier::Medium)
+        );
+        assert_eq!(reader.get_tier("/config.txt").unwrap(), None);
+        assert!(reader
+            .get_file("/models/hero_high.bin", None)
+            .unwrap()
+            .is_none());
+        assert!(reader
+            .get_file("/models/hero_low.bin", None)
+            .unwrap()
+            .is_some());
+        let names = reader
+            .get_directory("/models/", None)
+            .unwrap()
+            .unwrap()
+            .get_contents()
+            .len();
+        assert_eq!(names, 2);
+
+        let mut reader = PfaReader::new(Cursor::new(bytes)).unwrap();
+        let stripped = reader.strip_tiers(Tier::Low).unwrap().build().unwrap();
+        let mut stripped = PfaReader::new(Cursor::new(stripped)).unwrap();
+        assert!(stripped.has_entry_hashes());
+        assert_eq!(
+            stripped.get_tier("/models/hero_low.bin").unwrap(),
+            Some(Tier::Low)
+        );
+        assert!(stripped
+            .get_file("/models/hero_med.bin", None)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            stripped
+                .get_file("/config.txt", None)
+                .unwrap()
+                .unwrap()
+                .get_contents(),
+            b"always"
+        );
+        assert_eq!(
+            stripped
+                .get_file("/models/hero_low.bin", None)
+                .unwrap()
+                .unwrap()
+                .get_contents(),
+            b"/models/hero_low.bin"
+        );
+    }
+
+    #[test]
+    fn test_reserved_space() {
+        let build = |layout: CatalogLayout, files: &[(&str, &[u8])], reserve: bool| {
+            let mut builder = PfaBuilder::new("reserved");
+            builder.set_catalog_layout(layout);
+            builder.set_entry_hashes(true);
+            if reserve {
+                builder.set_reserved_space(16, 64);
+            }
+            for (path, contents) in files {
+                builder
+                    .add_file(path, contents.to_vec(), DataFlags::no_compression())
+                    .unwrap();
+            }
+            builder
+                .set_provenance("/dir/sub/deep.txt", Provenance::new().tool("gen"))
+                .unwrap();
+            builder.build().unwrap()
+        };
+
+        let original: &[(&str, &[u8])] = &[
+            ("/dir/a.txt", b"a"),
+            ("/dir/sub/deep.txt", b"deep"),
+            ("/other.txt", b"other"),
+        ];
+        let added: &[(&str, &[u8])] = &[
+            ("/dir/b.txt", b"b"),
+            ("/dir/sub/deeper.txt", b"deeper"),
+            ("/big.bin", &[7; 100]),
+        ];
+
+        for layout in [CatalogLayout::Fixed, CatalogLayout::Compact] {
+            let bytes = build(layout, original, true);
+            let reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+            let reserved = reader.get_reserved_space().unwrap();
+            assert_eq!((reserved.free_slots, reserved.free_data), (16, 64));
+
+            let mut editor = PfaEditor::new(Cursor::new(bytes)).unwrap();
+            for (path, contents) in added {
+                editor
+                    .add_file(path, contents.to_vec(), DataFlags::no_compression())
+                    .unwrap();
+            }
+            assert!(editor
+                .add_file("/dir/a.txt", vec![], DataFlags::no_compression())
+                .is_err());
+            assert!(editor
+                .add_file("/missing/a.txt", vec![], DataFlags::no_compression())
+                .is_err());
+            let key = DataFlags::generate_key();
+            let encrypted = DataFlags::no_compression().encryption(Some(key));
+            assert!(editor.add_file("/dir/c.txt", vec![1], encrypted).is_err());
+            let reserved = editor.get_reserved_space().unwrap();
+            assert_eq!(reserved.free_data, 64 - 7);
+            let bytes = editor.into_inner().into_inner();
+
+            let mut reader = PfaReader::new(Cursor::new(bytes)).unwrap();
+            for (path, contents) in original.iter().chain(added) {
+                let file = reader.get_file(*path, None).unwrap().unwrap();
+                assert_eq!(file.get_contents(), *contents);
+            }
+            // moved entries keep what the header recorded about them
+            let provenance = reader.get_provenance("/dir/sub/deep.txt").unwrap();
+            assert_eq!(provenance.unwrap().tool.as_deref(), Some("gen"));
+            assert_eq!(reader.get_provenance("/dir/sub/deeper.txt").unwrap(), None);
+
+            let all = original.iter().chain(added).copied().collect::<Vec<_>>();
+            let fresh = PfaReader::new(Cursor::new(build(layout, &all, false))).unwrap();
+            assert_eq!(reader.get_root_hash(), fresh.get_root_hash());
+        }
+
+        let mut editor =
+            PfaEditor::new(Cursor::new(build(CatalogLayout::Fixed, original, false))).unwrap();
+        assert!(editor
+            .add_file("/new.txt", vec![], DataFlags::no_compression())
+            .is_err());
+    }
+
+    #[test]
+    fn test_embedded() {
+        let dir = std::env::temp_dir().join(format!("pfa_pack_{}", crate::shared::Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path =
+            pack_directory_into("./src", "embedded", DataFlags::no_compression(), &dir).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut reader = PfaReader::from_slice(&bytes).unwrap();
+        let slice = reader.get_file_slice("/lib.rs").unwrap().unwrap();
+        assert_eq!(slice, include_bytes!("lib.rs"));
+        assert_eq!(
+            reader
+                .get_file("/lib.rs", None)
+                .unwrap()
+                .unwrap()
+                .get_contents(),
+            slice
+        );
+        assert!(reader.get_file_slice("/missing.rs").unwrap().is_none());
+        assert!(crate::include_pfa!("lib.rs").is_err()); // not an archive
+
+        let mut builder = PfaBuilder::new("compressed");
+        builder
+            .add_file("/a.txt", vec![1; 1000], DataFlags::forced_compression())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+        let reader = PfaReader::from_slice(&bytes).unwrap();
+        assert!(reader.get_file_slice("/a.txt").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_file_raw() {
+        let key = DataFlags::generate_key();
+        let mut builder = PfaBuilder::new("raw");
+        builder
+            .add_file("/plain.txt", vec![3; 10], DataFlags::no_compression())
+            .unwrap();
+        builder
+            .add_file(
+                "/packed.txt",
+                vec![3; 1000],
+                DataFlags::forced_compression(),
+            )
+            .unwrap();
+        builder
+            .add_file(
+                "/secret.txt",
+                vec![3; 1000],
+                DataFlags::forced_compression()
+                    .encryption(Some(key))
+                    .error_correction(Some(0.25)),
+            )
+            .unwrap();
+        let bytes = builder.build().unwrap();
+        let mut reader = PfaReader::new(Cursor::new(&bytes)).unwrap();
+
+        let plain = reader.get_file_raw("/plain.txt").unwrap().unwrap();
+        let offset = plain.get_offset() as usize;
+        assert_eq!(&bytes[offset..offset + 10], plain.get_contents());
+        assert_eq!(plain.get_stage_headers().reserved_bits, 0);
+        assert!(!plain.get_stage_headers().compression);
+
+        let packed = reader.get_file_raw("/packed.txt").unwrap().unwrap();
+        assert_eq!(packed.get_stage_headers().decompressed_size, Some(1000));
+
+        let secret = reader.get_file_raw("/secret.txt").unwrap().unwrap();
+        let headers = secret.get_stage_headers();
+        assert!(headers.compression && headers.encryption && headers.error_correction);
+        assert_eq!(headers.ecc_size, Some((0.25 * 255.0) as u64));
+        assert_eq!(headers.nonce.as_ref().map(|x| x.len()), Some(12));
+        assert_eq!(headers.decompressed_size, None);
+
+        assert!(reader.get_file_raw("/missing.txt").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_build_log() {
+        #[derive(Clone, Default)]
+        struct SharedLog(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl Write for SharedLog {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        for threads in [1, 4] {
+            let log = SharedLog::default();
+            let mut builder = PfaBuilder::new("logged");
+            builder.set_threads(threads);
+            builder.set_build_log(log.clone());
+            builder
+                .add_file("/a \"quoted\".txt", vec![1; 100], DataFlags::auto())
+                .unwrap();
+            builder
+                .add_file("/dir/b.txt", vec![2; 100], DataFlags::no_compression())
+                .unwrap();
+            let size = builder.build().unwrap().len();
+
+            let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+            let events = log
+                .lines()
+                .map(|x| x.split('"').nth(3).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(events.first(), Some(&"build_started"));
+            assert_eq!(events.iter().filter(|x| **x == "file_written").count(), 2);
+            assert_eq!(
+                events[events.len() - 2..],
+                ["catalog_flushed", "build_finished"]
+            );
+            assert!(log.contains(r#""path":"/a \"quoted\".txt""#));
+            assert!(log.contains(r#""path":"/dir/b.txt","stored_size":100"#));
+            assert!(log.ends_with(&format!("\"size\":{size}}}\n")));
+        }
+    }
+
+    #[test]
+    fn test_hashed_names() {
+        let key = DataFlags::generate_key();
+        let mut builder = PfaBuilder::new("private");
+        builder.set_name_encoding(NameEncoding::Hashed);
+        builder.set_reserved_space(4, 0);
+        builder
+            .add_file(
+                "/patients/alice_smith.json",
+                b"{}".to_vec(),
+                DataFlags::auto().encryption(Some(key)),
+            )
+            .unwrap();
+        builder
+            .add_file("/readme.txt", b"hello".to_vec(), DataFlags::auto())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+        assert!(!bytes.windows(5).any(|x| x == b"alice" || x == b"patie"));
+
+        let mut reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(reader.get_name_encoding(), NameEncoding::Hashed);
+        let file = reader
+            .get_file("/patients/alice_smith.json", Some(key))
+            .unwrap()
+            .unwrap();
+        assert_eq!(file.get_contents(), b"{}");
+        assert!(reader.exists("/patients/").unwrap());
+        assert!(!reader.exists("/patients/bob.json").unwrap());
+        assert!(!reader.exists("/alice_smith.json").unwrap());
+        assert!(matches!(
+            reader.get_directory("/patients/", None),
+            Err(PfaError::ListingDisabledError)
+        ));
+
+        let mut editor = PfaEditor::new(Cursor::new(bytes)).unwrap();
+        editor
+            .add_file("/patients/bob.json", b"[]".to_vec(), DataFlags::auto())
+            .unwrap();
+        let mut reader = PfaReader::new(Cursor::new(editor.into_inner().into_inner())).unwrap();
+        let file = reader
+            .get_file("/patients/bob.json", None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(file.get_contents(), b"[]");
+        let file = reader.get_file("/readme.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"hello");
+    }
+
+    #[test]
+    fn test_directory_sharding() {
+        for encoding in [NameEncoding::Plain, NameEncoding::Hashed] {
+            let mut builder = PfaBuilder::new("wide");
+            builder.set_name_encoding(encoding);
+            builder.set_entry_hashes(true);
+            builder.set_reserved_space(32, 0);
+            builder.set_directory_limits(
+                DirectoryLimits::new()
+                    .max_entries(Some(16))
+                    .bucket_size(8)
+                    .max_depth(Some(2)),
+            );
+            for i in 0..40 {
+                builder
+                    .add_file(&format!("/big/{i}.txt"), vec![i; 10], DataFlags::auto())
+                    .unwrap();
+            }
+            builder
+                .add_file("/big/sub/x/y/deep.txt", b"deep".to_vec(), DataFlags::auto())
+                .unwrap();
+            assert_eq!(
+                builder.limit_warnings(),
+                vec![
+                    LimitWarning::Sharded {
+                        path: "/big/".into(),
+                        entries: 41,
+                        buckets: 6
+                    },
+                    LimitWarning::TooDeep {
+                        path: "/big/sub/x/".into(),
+                        depth: 3
+                    }
+                ]
+            );
+            let bytes = builder.build().unwrap();
+
+            let mut reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+            for i in 0..40 {
+                let path = format!("/big/{i}.txt");
+                let file = reader.get_file(&path[..], None).unwrap().unwrap();
+                assert_eq!(file.get_contents(), vec![i; 10]);
+            }
+            let file = reader.get_file("/big/sub/x/y/deep.txt", None).unwrap();
+            assert_eq!(file.unwrap().get_contents(), b"deep");
+            assert!(!reader.exists("/big/0/").unwrap());
+            assert!(!reader.exists("/big/40.txt").unwrap());
+
+            let root = reader.get_root_hash().unwrap();
+            let proof = reader.get_entry_proof("/big/7.txt").unwrap().unwrap();
+            let proof = EntryProof::from_bytes(&proof.to_bytes().unwrap()).unwrap();
+            let start = proof.slice_offset as usize;
+            let slice = &bytes[start..start + proof.slice_size as usize];
+            assert_eq!(proof.root_hash(slice).unwrap(), root);
+            if encoding == NameEncoding::Plain {
+                assert!(proof.verify(&root, "/big/7.txt", slice));
+                let dir = reader.get_directory("/big/", None).unwrap().unwrap();
+                let mut listing = dir
+                    .get_contents()
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>();
+                listing.sort();
+                assert_eq!(listing.len(), 41);
+                assert!(listing.contains(&"/big/39.txt".to_string()));
+                assert!(listing.contains(&"/big/sub/".to_string()));
+            }
+
+            let mut editor = PfaEditor::new(Cursor::new(bytes)).unwrap();
+            editor
+                .add_file("/big/new.txt", b"new".to_vec(), DataFlags::auto())
+                .unwrap();
+            let bytes = editor.into_inner().into_inner();
+            let mut reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+            let file = reader.get_file("/big/new.txt", None).unwrap().unwrap();
+            assert_eq!(file.get_contents(), b"new");
+            let file = reader.get_file("/big/3.txt", None).unwrap().unwrap();
+            assert_eq!(file.get_contents(), vec![3; 10]);
+            let proof = reader.get_entry_proof("/big/new.txt").unwrap().unwrap();
+            let start = proof.slice_offset as usize;
+            let slice = &bytes[start..start + proof.slice_size as usize];
+            assert_eq!(
+                proof.root_hash(slice).unwrap(),
+                reader.get_root_hash().unwrap()
+            );
+        }
+
+        // sharding is opt-in, readers which predate it would list the buckets
+        let mut builder = PfaBuilder::new("wide");
+        builder.set_directory_limits(DirectoryLimits::new().bucket_size(8));
+        for i in 0..40 {
+            builder
+                .add_file(&format!("/big/{i}.txt"), vec![i; 10], DataFlags::auto())
+                .unwrap();
+        }
+        assert!(builder.limit_warnings().is_empty());
+        let reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        assert!(reader.get_header_fields().sharded_directories.is_empty());
+    }
+
+    #[test]
+    fn test_default_flags() {
+        let key = DataFlags::generate_key();
+        let other_key = DataFlags::generate_key();
+        let mut builder = PfaBuilder::new("secure");
+        builder
+            .add_file("/early.txt", b"early".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder.set_default_flags(DataFlags::forced_compression().error_correction(Some(0.2)));
+        builder.set_encryption_key(key);
+        builder
+            .add_file("/default.txt", vec![1; 100], DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file(
+                "/own_key.txt",
+                b"own".to_vec(),
+                DataFlags::no_compression().encryption(Some(other_key)),
+            )
+            .unwrap();
+        let bytes = builder.build().unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(bytes)).unwrap();
+        for path in ["/early.txt", "/default.txt", "/own_key.txt"] {
+            assert!(reader.get_file(path, None).is_err());
+        }
+        let raw = reader.get_file_raw("/default.txt").unwrap().unwrap();
+        let headers = raw.get_stage_headers();
+        assert!(headers.compression && headers.encryption && headers.error_correction);
+        let file = reader.get_file("/early.txt", Some(key)).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"early");
+        let file = reader.get_file("/default.txt", Some(key)).unwrap().unwrap();
+        assert_eq!(file.get_contents(), vec![1; 100]);
+        let file = reader.get_file("/own_key.txt", Some(other_key)).unwrap();
+        assert_eq!(file.unwrap().get_contents(), b"own");
+
+        let mut builder = PfaBuilder::new("secure");
+        builder.set_encryption_key(key);
+        builder
+            .add_stored_file("/plain.txt", b"plain".to_vec(), 0)
+            .unwrap();
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn test_directory_flags() {
+        let key = DataFlags::generate_key();
+        let mut builder = PfaBuilder::new("subtrees");
+        builder.set_default_flags(DataFlags::auto().error_correction(Some(0.2)));
+        builder.set_directory_flags("/secret/", DataFlags::auto().encryption(Some(key)));
+        builder.set_directory_flags("secret\\public", DataFlags::no_compression());
+        let files = [
+            ("/a.txt", DataFlags::auto()),
+            ("/secret/a.txt", DataFlags::auto()),
+            ("/secret/maps/a.txt", DataFlags::auto()),
+            ("/secret/public/a.txt", DataFlags::auto()),
+            ("/secret/own.txt", DataFlags::no_compression()),
+            ("/secretive/a.txt", DataFlags::auto()),
+        ];
+        for (path, flags) in files {
+            builder.add_file(path, vec![1; 100], flags).unwrap();
+        }
+        let bytes = builder.build().unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(bytes)).unwrap();
+        let mut stages = |path: &str| {
+            let raw = reader.get_file_raw(path).unwrap().unwrap();
+            let headers = raw.get_stage_headers();
+            (headers.encryption, headers.error_correction)
+        };
+        assert_eq!(stages("/a.txt"), (false, true));
+        assert_eq!(stages("/secret/a.txt"), (true, false));
+        assert_eq!(stages("/secret/maps/a.txt"), (true, false));
+        assert_eq!(stages("/secret/public/a.txt"), (false, false));
+        assert_eq!(stages("/secret/own.txt"), (false, false));
+        assert_eq!(stages("/secretive/a.txt"), (false, true));
+        let file = reader.get_file("/secret/maps/a.txt", Some(key)).unwrap();
+        assert_eq!(file.unwrap().get_contents(), vec![1; 100]);
+    }
+
+    #[test]
+    fn test_strict_flags() {
+        let mut builder = PfaBuilder::new("flags");
+        builder.set_catalog_layout(CatalogLayout::Fixed);
+        builder
+            .add_file("/a.txt", b"hello".to_vec(), DataFlags::no_compression())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+        let reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(reader.get_unknown_flag_bits(), 0);
+        // {num_entries}{root entry}{file name}{file flags}
+        let flags_at = reader.catalog_idx + 8 + 49 + 32;
+        assert_eq!(bytes[flags_at], 0);
+
+        let strict = PfaReaderOptions::new().strict(true);
+        let mut legacy = bytes.clone();
+        legacy[flags_at] = 0b11111000;
+        let mut reader = PfaReader::new_with_options(Cursor::new(legacy), strict.clone()).unwrap();
+        assert_eq!(reader.get_unknown_flag_bits(), 0);
+        let file = reader.get_file("/a.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"hello");
+        // the legacy bits don't stop the file being read as it's stored
+        let info = reader.get_file_info("/a.txt").unwrap().unwrap();
+        assert_eq!(info.contents_size, Some(5));
+        assert_eq!(reader.read_range("/a.txt", 1, 3).unwrap().unwrap(), b"ell");
+        let mut contents = vec![];
+        let mut file = reader.open_file("/a.txt", None).unwrap().unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"hello");
+
+        let mut future = bytes;
+        future[flags_at] = 0b10000000;
+        assert!(matches!(
+            PfaReader::new_with_options(Cursor::new(future.clone()), strict),
+            Err(PfaError::UnsupportedFeature(_))
+        ));
+        let reader = PfaReader::new(Cursor::new(future)).unwrap();
+        assert_eq!(reader.get_unknown_flag_bits(), 0b10000000);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let mut builder = PfaBuilder::new("capabilities");
+        builder.set_catalog_layout(CatalogLayout::Fixed);
+        builder
+            .add_file("/a.txt", vec![b'a'; 100], DataFlags::forced_compression())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+        let mut reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+        let capabilities = reader.capabilities().unwrap();
+        assert_eq!(capabilities, crate::reader::Capabilities::supported());
+        assert_eq!(capabilities.max_version, LATEST_VERSION);
+        assert!(capabilities.codecs.contains(&"lz4".to_string()));
+        assert!(!capabilities.codecs.contains(&"custom".to_string()));
+        assert!(capabilities.can_read_all());
+
+        let flags_at = reader.catalog_idx + 8 + 49 + 32;
+        assert_eq!(DataFlags::codec(bytes[flags_at]), Some(StoredCodec::Lz4));
+        let mut zstd = bytes.clone();
+        zstd[flags_at] |= 0b00100000;
+        assert_eq!(DataFlags::codec(zstd[flags_at]), Some(StoredCodec::Zstd));
+        let capabilities = PfaReader::new(Cursor::new(zstd))
+            .unwrap()
+            .capabilities()
+            .unwrap();
+        assert_eq!(capabilities.unknown_flag_bits, 0);
+        assert_eq!(
+            capabilities.missing_codecs.is_empty(),
+            cfg!(feature = "zstd")
+        );
+        let mut future = bytes;
+        future[flags_at] |= 0b10000000;
+        let capabilities = PfaReader::new(Cursor::new(future))
+            .unwrap()
+            .capabilities()
+            .unwrap();
+        assert_eq!(capabilities.unknown_flag_bits, 0b10000000);
+        assert!(!capabilities.can_read_all());
+        assert_eq!(DataFlags::codec(0), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize_reports() {
+        let mut builder = PfaBuilder::new("serde");
+        builder
+            .add_file("/dir/a.txt", b"hello".to_vec(), DataFlags::no_compression())
+            .unwrap();
+        builder.set_tier("/dir/a.txt", Tier::High).unwrap();
+        builder
+            .set_provenance("/dir/a.txt", Provenance::new().source_hash([0xab; 32]))
+            .unwrap();
+        let reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+
+        let stat = reader.stat("/dir/a.txt").unwrap().unwrap();
+        assert_eq!(
+            serde_json::to_string(&stat).unwrap(),
+            r#"{"path":"/dir/a.txt","is_directory":false,"size":5,"contents_size":5,"flags":0,"content_type":null,"tier":"high"}"#
+        );
+        let info = reader.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(
+            serde_json::to_string(&info).unwrap(),
+            r#"{"path":"/dir/","is_directory":true,"size":1,"contents_size":null,"flags":0,"content_type":null,"tier":null}"#
+        );
+        let provenance = reader.get_provenance("/dir/a.txt").unwrap().unwrap();
+        let provenance = serde_json::to_value(provenance).unwrap();
+        assert_eq!(provenance["source_hash"], "ab".repeat(32));
+
+        let warning = ReaderWarning::UnreferencedData { offset: 4, size: 2 };
+        assert_eq!(
+            serde_json::to_string(&warning).unwrap(),
+            r#"{"kind":"unreferenced_data","offset":4,"size":2}"#
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_compression() {
+        use crate::shared::CompressionCodec;
+
+        let text = b"the quick brown fox jumps over the lazy dog\n".repeat(200);
+        let zstd = DataFlags::forced_compression().compression_codec(CompressionCodec::Zstd);
+        let mut builder = PfaBuilder::new("zstd");
+        builder
+            .add_file("/lz4.txt", text.clone(), DataFlags::auto())
+            .unwrap();
+        builder.add_file("/zstd.txt", text.clone(), zstd).unwrap();
+        builder
+            .add_file(
+                "/auto.txt",
+                text.clone(),
+                "auto,codec=zstd".parse::<DataFlags>().unwrap(),
+            )
+            .unwrap();
+        let bytes = builder.build().unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert!(reader.capabilities().unwrap().can_read_all());
+        for (path, codec) in [
+            ("/lz4.txt", StoredCodec::Lz4),
+            ("/zstd.txt", StoredCodec::Zstd),
+            ("/auto.txt", StoredCodec::Zstd),
+        ] {
+            let raw = reader.get_file_raw(path).unwrap().unwrap();
+            assert_eq!(DataFlags::codec(raw.get_flags()), Some(codec));
+            let file = reader.get_file(path, None).unwrap().unwrap();
+            assert_eq!(file.get_contents(), text);
+        }
+        let zstd = reader.get_file_raw("/zstd.txt").unwrap().unwrap();
+        assert_eq!(zstd.get_stage_headers().decompressed_size, None);
+        // readers of version 2 would decode zstd data as LZ4
+        assert_eq!(reader.get_version(), 3);
+
+        // zstd data is decompressed as it's read, LZ4 blocks only whole
+        let mut file = reader.open_file("/zstd.txt", None).unwrap().unwrap();
+        assert!(file.is_streaming());
+        let mut contents = vec![];
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, text);
+        let file = reader.open_file("/lz4.txt", None).unwrap().unwrap();
+        assert!(!file.is_streaming());
+        let preview = reader.get_file_preview("/zstd.txt", 9, None).unwrap();
+        assert_eq!(preview.unwrap(), b"the quick");
+        let options = PfaReaderOptions::new().max_decompressed_size(100);
+        let mut limited = PfaReader::new_with_options(Cursor::new(bytes), options).unwrap();
+        let mut file = limited.open_file("/zstd.txt", None).unwrap().unwrap();
+        assert!(file.read_to_end(&mut vec![]).is_err());
+        assert!(matches!(
+            limited.get_file_preview("/zstd.txt", 10_000, None),
+            Err(PfaError::DecompressionLimitError { limit: 100 })
+        ));
+
+        for version in [1, 2] {
+            let mut builder = PfaBuilder::new("zstd");
+            builder.set_version(version).unwrap();
+            builder
+                .add_file(
+                    "/a.txt",
+                    text.clone(),
+                    DataFlags::forced_compression().compression_codec(CompressionCodec::Zstd),
+                )
+                .unwrap();
+            let Err(PfaError::UnsupportedByVersion {
+                version: unsupported,
+                features,
+            }) = builder.build()
+            else {
+                panic!("version {version} can't store zstd data");
+            };
+            assert_eq!(unsupported, version);
+            assert_eq!(features, ["zstd compression, used by 'a.txt'"]);
+        }
+
+        let mut builder = PfaBuilder::new("lz4");
+        builder.set_reserved_space(1, 0);
+        builder
+            .add_file("/a.txt", text.clone(), DataFlags::auto())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+        assert_eq!(
+            PfaReader::new(Cursor::new(&bytes)).unwrap().get_version(),
+            2
+        );
+        let mut editor = PfaEditor::new(Cursor::new(bytes)).unwrap();
+        assert!(matches!(
+            editor.add_file(
+                "/b.txt",
+                text,
+                DataFlags::forced_compression().compression_codec(CompressionCodec::Zstd)
+            ),
+            Err(PfaError::UnsupportedByVersion { version: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_custom_codec() {
+        use std::sync::Arc;
+
+        use crate::shared::{Codec, CodecRegistry, CompressionCodec};
+
+        /// Stores runs of a byte as {count:u8}{byte}
+        struct RunLength(u8);
+        impl Codec for RunLength {
+            fn id(&self) -> u8 {
+                self.0
+            }
+            fn name(&self) -> &str {
+                "rle"
+            }
+            fn compress(&self, contents: &[u8]) -> Result<Vec<u8>, PfaError> {
+                let mut out = vec![];
+                for run in contents.chunk_by(|a, b| a == b) {
+                    for part in run.chunks(255) {
+                        out.extend([part.len() as u8, part[0]]);
+                    }
+                }
+                Ok(out)
+            }
+            fn decompress(&self, compressed: &[u8]) -> Result<Vec<u8>, PfaError> {
+                Ok(compressed
+                    .chunks(2)
+                    .flat_map(|x| std::iter::repeat_n(x[1], x[0] as usize))
+                    .collect())
+            }
+        }
+
+        let mut codecs = CodecRegistry::new();
+        codecs.register(Arc::new(RunLength(200))).unwrap();
+        assert!(codecs.register(Arc::new(RunLength(200))).is_err());
+        let contents = [vec![1; 1000], vec![2; 10]].concat();
+        let mut builder = PfaBuilder::new("codecs");
+        builder
+            .add_file(
+                "/a.bin",
+                contents.clone(),
+                DataFlags::parse_with_codecs("compress,codec=rle", &codecs).unwrap(),
+            )
+            .unwrap();
+        // the id is stored with the data, so a codec which wasn't registered can't be read back
+        let unregistered = CompressionCodec::Custom(Arc::new(RunLength(201)));
+        builder
+            .add_file(
+                "/b.bin",
+                contents.clone(),
+                DataFlags::forced_compression().compression_codec(unregistered),
+            )
+            .unwrap();
+        let bytes = builder.build().unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+        let raw = reader.get_file_raw("/a.bin").unwrap().unwrap();
+        assert_eq!(DataFlags::codec(raw.get_flags()), Some(StoredCodec::Custom));
+        assert_eq!(raw.get_stage_headers().codec_id, Some(200));
+        assert_eq!(raw.get_contents().len(), 1 + 2 * 5);
+        // readers only have the codecs they're given
+        assert!(reader.get_file("/a.bin", None).is_err());
+        let capabilities = reader.capabilities().unwrap();
+        assert_eq!(
+            capabilities.missing_codecs,
+            ["custom codec 200", "custom codec 201"]
+        );
+        assert!(!capabilities.can_read_all());
+
+        let options = PfaReaderOptions::new().codecs(codecs.clone());
+        let mut reader = PfaReader::new_with_options(Cursor::new(bytes), options).unwrap();
+        let file = reader.get_file("/a.bin", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), contents);
+        assert!(reader.get_file("/b.bin", None).is_err());
+        let capabilities = reader.capabilities().unwrap();
+        assert!(capabilities.codecs.contains(&"rle".to_string()));
+        assert_eq!(capabilities.missing_codecs, ["custom codec 201"]);
+        assert!("compress,codec=rle".parse::<DataFlags>().is_err());
+        assert!(DataFlags::parse_with_codecs("codec=missing", &codecs).is_err());
+
+        // files of solid blocks are compressed with the block's codec again when copied
+        let mut builder = PfaBuilder::new("solid");
+        builder.set_solid_blocks(4096, 1 << 20);
+        let rle = DataFlags::parse_with_codecs("compress,codec=rle", &codecs).unwrap();
+        for name in ["/c.bin", "/d.bin"] {
+            builder
+                .add_file(name, contents.clone(), rle.clone())
+                .unwrap();
+        }
+        let options = PfaReaderOptions::new().codecs(codecs);
+        let bytes = builder.build().unwrap();
+        let mut reader = PfaReader::new_with_options(Cursor::new(bytes), options).unwrap();
+        assert!(reader.capabilities().unwrap().can_read_all());
+        let copy = reader.strip_tiers(Tier::High).unwrap();
+        assert!(copy.codecs().find_by_id(200).is_some());
+        let copy = PfaReader::new(Cursor::new(copy.build().unwrap())).unwrap();
+        let block = copy.get_header_fields().solid_blocks[0];
+        assert_eq!(DataFlags::codec(block.flags), Some(StoredCodec::Custom));
+    }
+
+    #[test]
+    fn test_reader_warnings() {
+        let mut builder = PfaBuilder::new("game");
+        builder.set_catalog_layout(CatalogLayout::Fixed);
+        builder
+            .add_file("/a.txt", b"hello".to_vec(), DataFlags::no_compression())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+        let reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(reader.warnings(), []);
+        // {num_entries}{root entry}{file name}{file flags}
+        let name_at = reader.catalog_idx + 8 + 49;
+        let flags_at = name_at + 32;
+
+        let mut broken = bytes.clone();
+        broken[name_at] = 0xff;
+        broken[flags_at] = 0b10000000;
+        broken.truncate(broken.len() - 1);
+        let reader = PfaReader::new(Cursor::new(broken.clone())).unwrap();
+        let name = "\u{fffd}.txt".to_string();
+        assert_eq!(
+            reader.warnings(),
+            [
+                ReaderWarning::LossyName {
+                    index: 1,
+                    name: name.clone()
+                },
+                ReaderWarning::DataOutOfBounds {
+                    index: 1,
+                    name: name.clone(),
+                    offset: 0,
+                    size: 5
+                },
+                ReaderWarning::UnknownFlagBits {
+                    index: 1,
+                    name,
+                    bits: 0b10000000
+                },
+            ]
+        );
+        let strict = PfaReaderOptions::new().strict(true);
+        assert!(PfaReader::new_with_options(Cursor::new(broken), strict).is_err());
+
+        // an entry claiming more data than the archive holds fails before it's allocated
+        let mut huge = bytes.clone();
+        huge[flags_at + 1..flags_at + 9].copy_from_slice(&(1u64 << 50).to_le_bytes());
+        let mut reader = PfaReader::new(Cursor::new(huge)).unwrap();
+        assert!(matches!(
+            reader.get_file("/a.txt", None),
+            Err(PfaError::DataOutOfBoundsError { entry }) if entry == "/a.txt"
+        ));
+        let mut extracted = vec![];
+        reader
+            .extract_streaming("/", ExtractorOptions::new(), |file| {
+                extracted.push(file.map(|x| x.get_path().to_string()));
+                Ok::<_, PfaError>(())
+            })
+            .unwrap();
+        assert!(matches!(
+            &extracted[..],
+            [Err(PfaError::DataOutOfBoundsError { .. })]
+        ));
+    }
+
+    #[test]
+    fn test_build_into() {
+        let make = || {
+            let mut builder = PfaBuilder::new("stream");
+            builder.set_archive_id(crate::shared::Ulid::from_parts(1, 2));
+            builder.set_entry_hashes(true);
+            for i in 0..16 {
+                builder
+                    .add_file(&format!("/data/{i}.bin"), vec![i; 300], DataFlags::auto())
+                    .unwrap();
+            }
+            builder
+        };
+        let expected = make().build().unwrap();
+
+        let mut out = Cursor::new(b"prefix".to_vec());
+        out.set_position(6);
+        let size = make().build_into(&mut out).unwrap();
+        assert_eq!(size, expected.len() as u64);
+        assert_eq!(&out.get_ref()[6..], expected);
+    }
+
+    #[test]
+    fn test_unreferenced_data() {
+        let mut builder = PfaBuilder::new("game");
+        builder.set_catalog_layout(CatalogLayout::Fixed);
+        builder.set_reserved_space(2, 64);
+        for (path, contents) in [("/a.txt", b"hello"), ("/b.txt", b"world")] {
+            builder
+                .add_file(path, contents.to_vec(), DataFlags::no_compression())
+                .unwrap();
+        }
+        let bytes = builder.build().unwrap();
+        let mut reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(reader.warnings(), []);
+        assert_eq!(reader.get_unreferenced_data().unwrap(), []);
+
+        // shrink '/a.txt' to 2 bytes and leave junk at the end
+        let size_at = reader.catalog_idx + 8 + 49 + 32 + 1;
+        let mut broken = bytes;
+        broken[size_at] = 2;
+        broken.extend_from_slice(b"junk");
+        let mut reader = PfaReader::new(Cursor::new(broken.clone())).unwrap();
+        let unreferenced = reader.get_unreferenced_data().unwrap();
+        assert_eq!(unreferenced.len(), 2);
+        assert_eq!(unreferenced[0].end - unreferenced[0].start, 3);
+        assert_eq!(
+            unreferenced[1].end,
+            broken.len() as u64 - reader.data_idx as u64
+        );
+        assert_eq!(unreferenced[1].end - unreferenced[1].start, 4);
+        assert_eq!(
+            reader.warnings()[0],
+            ReaderWarning::UnreferencedData {
+                offset: unreferenced[0].start,
+                size: 3
+            }
+        );
+
+        let compacted = reader.compact().unwrap();
+        assert_eq!(compacted.len(), broken.len() - 7);
+        let mut reader = PfaReader::new(Cursor::new(compacted)).unwrap();
+        assert_eq!(reader.warnings(), []);
+        assert_eq!(reader.get_catalog_layout(), CatalogLayout::Fixed);
+        assert_eq!(reader.get_reserved_space().unwrap().free_data, 64);
+        let file = reader.get_file("/a.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"he");
+        let file = reader.get_file("/b.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"world");
+
+        // padding between aligned files isn't unreferenced
+        let mut builder = PfaBuilder::new("mapped");
+        builder.set_store_only(64);
+        builder
+            .add_file("/a", vec![1; 3], DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/b", vec![2; 3], DataFlags::auto())
+            .unwrap();
+        let reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        assert_eq!(reader.warnings(), []);
+    }
+
+    #[test]
+    fn test_content_types() {
+        let mut builder = PfaBuilder::new("site");
+        builder.set_sniff_content_types(true);
+        builder
+            .add_file("/index.html", b"<html>".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file(
+                "/img/logo.bin",
+                b"\x89PNG\r\n\x1a\nrest".to_vec(),
+                DataFlags::auto(),
+            )
+            .unwrap();
+        builder
+            .add_file("/data.dat", vec![0; 100], DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/img/icon.svg", b"<svg/>".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder
+            .set_content_type("/data.dat", "application/x-custom")
+            .unwrap();
+        builder.set_tier("/data.dat", Tier::High).unwrap();
+        let bytes = builder.build().unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(bytes)).unwrap();
+        let content_type =
+            |reader: &PfaReader<_>, path: &str| reader.get_content_type(path).unwrap();
+        assert_eq!(
+            content_type(&reader, "/index.html").as_deref(),
+            Some("text/html")
+        );
+        assert_eq!(
+            content_type(&reader, "/img/logo.bin").as_deref(),
+            Some("image/png")
+        );
+        assert_eq!(
+            content_type(&reader, "/data.dat").as_deref(),
+            Some("application/x-custom")
+        );
+        let stat = reader.stat("/data.dat").unwrap().unwrap();
+        assert!(!stat.is_directory);
+        assert_eq!(stat.tier, Some(Tier::High));
+        assert_eq!(stat.content_type.as_deref(), Some("application/x-custom"));
+        let stat = reader.stat("/img/").unwrap().unwrap();
+        assert!(stat.is_directory && stat.size == 2 && stat.content_type.is_none());
+        assert!(reader.stat("/missing").unwrap().is_none());
+
+        let mut images = reader
+            .find_files_by_content_type("/", "image/*")
+            .unwrap()
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        images.sort();
+        assert_eq!(images, ["/img/icon.svg", "/img/logo.bin"]);
+
+        let stripped = reader.strip_tiers(Tier::High).unwrap().build().unwrap();
+        let mut resolver = PfaResolver::new();
+        resolver.mount(PfaReader::new(Cursor::new(stripped)).unwrap());
+        let file = resolver
+            .resolve_file_with_content_type("pfa://site/index.html")
+            .unwrap()
+            .unwrap();
+        assert_eq!(file.contents, b"<html>");
+        assert_eq!(file.content_type.as_deref(), Some("text/html"));
+    }
+
+    #[test]
+    fn test_resolution_trace() {
+        let mut builder = PfaBuilder::new("game");
+        for path in ["/textures/hero.png", "/textures/heroine.png", "/config.txt"] {
+            builder
+                .add_file(path, b"x".to_vec(), DataFlags::auto())
+                .unwrap();
+        }
+        let bytes = builder.build().unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert!(reader
+            .get_file("/texture/hero.png", None)
+            .unwrap()
+            .is_none());
+        let trace = reader.trace_path("/texture/hero.png").unwrap();
+        assert_eq!(trace.stopped_in, "/");
+        assert_eq!(trace.missing.as_deref(), Some("texture"));
+        assert_eq!(trace.nearest[0], "textures/");
+        assert_eq!(trace.suggestion.as_deref(), Some("/textures/hero.png"));
+        assert!(reader.trace_path("/textures/hero.png").unwrap().is_found());
+
+        let trace = reader.trace_path("/textures/Hero.PNG").unwrap();
+        assert_eq!(trace.matched, ["textures"]);
+        assert_eq!(trace.stopped_in, "/textures/");
+        assert_eq!(trace.suggestion.as_deref(), Some("/textures/hero.png"));
+        let trace = reader.trace_path("/textures").unwrap();
+        assert!(trace.wrong_kind);
+        let trace = reader.trace_path("/nothing/like/this.bin").unwrap();
+        assert_eq!(trace.suggestion, None);
+
+        let options = PfaReaderOptions::new().trace_not_found(true);
+        let mut reader = PfaReader::new_with_options(Cursor::new(bytes), options).unwrap();
+        match reader.get_file("/confg.txt", None) {
+            Err(PfaError::NotFound(trace)) => {
+                assert_eq!(trace.suggestion.as_deref(), Some("/config.txt"));
+                assert_eq!(
+                    trace.to_string(),
+                    "'/confg.txt' not found, no entry 'confg.txt' in '/', did you mean '/config.txt'?"
+                );
+            }
+            _ => panic!("expected a not found error"),
+        }
+        assert!(reader.get_file("/config.txt", None).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_format_spec() {
+        let spec = crate::shared::format_spec();
+        assert!(spec.contains(&format!("versions 1 to {}", crate::shared::LATEST_VERSION)));
+        // the hand written spec documents the same header fields
+        let design_spec = include_str!("../../design_spec.md");
+        for (tag, name, _) in crate::shared::header_fields::HeaderFields::LAYOUTS {
+            assert!(spec.contains(&format!("{tag:<3}  {name:<19}")), "{name}");
+            assert!(
+                design_spec.contains(&format!("| {tag} | {name} |")),
+                "{name}"
+            );
+        }
+        for (mask, name, _) in DataFlags::BITS {
+            assert!(spec.contains(&format!("{mask:#010b}  {name}")), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_format_spec_layout() {
+        let files = [("a.txt", &b"hello"[..]), ("b.txt", &b"world!"[..])];
+        let mut builder = PfaBuilder::new("layout");
+        builder.set_catalog_layout(CatalogLayout::Fixed);
+        for (name, contents) in files {
+            builder
+                .add_file(&format!("/{name}"), contents.to_vec(), DataFlags::auto())
+                .unwrap();
+        }
+        let bytes = builder.build().unwrap();
+
+        // walk the archive as the spec describes it
+        let take = |position: &mut usize, len: usize| {
+            *position += len;
+            &bytes[*position - len..*position]
+        };
+        let u64_at = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+        let mut position = 0;
+        assert_eq!(take(&mut position, 3), crate::shared::WATERMARK);
+        assert_eq!(take(&mut position, 1), [2]);
+        let name_size = take(&mut position, 1)[0].into();
+        assert_eq!(take(&mut position, name_size), b"layout");
+        let extra_data_len = u64_at(take(&mut position, 8)) as usize;
+        take(&mut position, extra_data_len);
+        let num_fields = u16::from_le_bytes(take(&mut position, 2).try_into().unwrap());
+        for _ in 0..num_fields {
+            take(&mut position, 1);
+            let len = u32::from_le_bytes(take(&mut position, 4).try_into().unwrap());
+            take(&mut position, len as usize);
+        }
+        let num_entries = u64_at(take(&mut position, 8));
+        let entries = (0..num_entries)
+            .map(|_| {
+                let name = take(&mut position, CatalogLayout::FIXED_NAME_SIZE);
+                let name = String::from_utf8(name.to_vec()).unwrap();
+                let flags = take(&mut position, 1)[0];
+                let size = u64_at(take(&mut position, 8)) as usize;
+                let offset = u64_at(take(&mut position, 8)) as usize;
+                (name.trim_end_matches('\0').to_string(), flags, size, offset)
+            })
+            .collect::<Vec<_>>();
+
+        let data_start = position;
+        let mut data_end = data_start;
+        for (name, contents) in files {
+            let (_, flags, size, offset) = entries.iter().find(|x| x.0 == name).unwrap();
+            assert_eq!(*flags, 0);
+            let start = data_start + offset;
+            assert_eq!(&bytes[start..start + size], contents);
+            data_end = data_end.max(start + size);
+        }
+        assert_eq!(data_end, bytes.len());
+    }
+
+    #[test]
+    fn test_encryption_frames() {
+        let key = DataFlags::generate_key();
+        let contents = (0..10_000u32)
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<_>>();
+        let framed = DataFlags::no_compression()
+            .encryption(Some(key))
+            .encryption_frames(Some(4096));
+        let mut builder = PfaBuilder::new("frames");
+        builder
+            .add_file("/framed.bin", contents.clone(), framed.clone())
+            .unwrap();
+        builder
+            .add_file(
+                "/compressed.bin",
+                contents.clone(),
+                framed.compression_type(DataCompressionType::Forced(true)),
+            )
+            .unwrap();
+        builder
+            .add_file(
+                "/whole.bin",
+                contents.clone(),
+                DataFlags::no_compression().encryption(Some(key)),
+            )
+            .unwrap();
+        let bytes = builder.build().unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(bytes)).unwrap();
+        for path in ["/framed.bin", "/compressed.bin", "/whole.bin"] {
+            let file = reader.get_file(path, Some(key)).unwrap().unwrap();
+            assert_eq!(file.get_contents(), contents, "{path}");
+            let range = reader
+                .read_encrypted_range(path, 5000..13000, key)
+                .unwrap()
+                .unwrap();
+            assert_eq!(range, contents[5000..13000], "{path}");
+        }
+        let raw = reader.get_file_raw("/framed.bin").unwrap().unwrap();
+        let frames = raw.get_stage_headers().encryption_frames.clone().unwrap();
+        assert_eq!(frames.frame_size(), 4096);
+        assert_eq!(frames.len(), contents.len() as u64);
+        assert_eq!(frames.num_frames(), 10);
+        assert_eq!(reader.get_unknown_flag_bits(), 0);
+        assert!(reader
+            .read_encrypted_range("/framed.bin", 0..10, [0; 32])
+            .is_err());
+    }
+
+    #[test]
+    fn test_ciphers() {
+        let key = DataFlags::generate_key();
+        let contents = (0..10_000u32)
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<_>>();
+        let xchacha = DataFlags::no_compression()
+            .encryption(Some(key))
+            .cipher(Cipher::XChaCha20Poly1305);
+        let mut builder = PfaBuilder::new("ciphers");
+        builder.set_reserved_space(4, 1 << 16);
+        builder
+            .add_file("/whole.bin", contents.clone(), xchacha.clone())
+            .unwrap();
+        builder
+            .add_file(
+                "/framed.bin",
+                contents.clone(),
+                xchacha.clone().encryption_frames(Some(4096)),
+            )
+            .unwrap();
+        builder
+            .add_file(
+                "/aes.bin",
+                contents.clone(),
+                DataFlags::no_compression().encryption(Some(key)),
+            )
+            .unwrap();
+        let bytes = builder.build().unwrap();
+
+        let check = |bytes: Vec<u8>, whole_cipher: Cipher| {
+            let mut reader = PfaReader::new(Cursor::new(bytes)).unwrap();
+            let ciphers = ["/whole.bin", "/framed.bin", "/aes.bin"]
+                .map(|x| reader.get_cipher(x).unwrap().unwrap());
+            assert_eq!(
+                ciphers,
+                [whole_cipher, whole_cipher, Cipher::Aes256Gcm],
+                "{ciphers:?}"
+            );
+            for path in ["/whole.bin", "/framed.bin", "/aes.bin"] {
+                let file = reader.get_file(path, Some(key)).unwrap().unwrap();
+                assert_eq!(file.get_contents(), contents, "{path}");
+                let range = reader
+                    .read_encrypted_range(path, 5000..13000, key)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(range, contents[5000..13000], "{path}");
+            }
+            let mut streamed = vec![];
+            let mut file = reader.open_file("/framed.bin", Some(key)).unwrap().unwrap();
+            file.read_to_end(&mut streamed).unwrap();
+            assert_eq!(streamed, contents);
+
+            let mut extracted = 0;
+            reader
+                .extract_streaming("/", ExtractorOptions::new().key(Some(key)), |file| {
+                    assert_eq!(file?.get_contents(), contents);
+                    extracted += 1;
+                    Ok::<_, PfaError>(())
+                })
+                .unwrap();
+            assert_eq!(extracted, 3);
+            reader
+        };
+        let mut reader = check(bytes.clone(), Cipher::XChaCha20Poly1305);
+        assert_eq!(reader.get_cipher("/missing.bin").unwrap(), None);
+
+        let stripped = reader.strip_tiers(Tier::High).unwrap().build().unwrap();
+        check(stripped, Cipher::XChaCha20Poly1305);
+        let downgraded = reader.convert_version(1, Some(key)).unwrap();
+        check(downgraded, Cipher::Aes256Gcm);
+
+        let mut editor = PfaEditor::new(Cursor::new(bytes)).unwrap();
+        assert!(editor
+            .add_file("/added.bin", vec![1; 100], xchacha)
+            .is_err());
+        let reader = check(editor.into_inner().into_inner(), Cipher::XChaCha20Poly1305);
+        assert!(!reader.exists("/added.bin").unwrap());
+    }
+
+    #[test]
+    fn test_exists_all() {
+        let mut builder = PfaBuilder::new("game");
+        for path in [
+            "/levels/1/music.ogg",
+            "/levels/1/sky.png",
+            "/levels/2/sky.png",
+        ] {
+            builder
+                .add_file(path, b"x".to_vec(), DataFlags::auto())
+                .unwrap();
+        }
+        builder
+            .add_file_variant(
+                "/levels/2/music.ogg",
+                "ps5",
+                b"y".to_vec(),
+                DataFlags::auto(),
+            )
+            .unwrap();
+        let bytes = builder.build().unwrap();
+
+        let paths = [
+            "/levels/1/music.ogg",
+            "/levels/1/sky.png",
+            "/levels/1/override.png",
+            "/levels/2/music.ogg",
+            "/levels/3/sky.png",
+            "/levels/1/",
+            "/levels/1",
+            "/",
+        ];
+        let reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+        let found = reader.exists_all(&paths).unwrap();
+        assert_eq!(found, [true, true, false, false, false, true, false, true]);
+        for (path, found) in paths.iter().zip(found) {
+            assert_eq!(reader.exists(*path).unwrap(), found, "{path}");
+        }
+
+        let options = PfaReaderOptions::new().variant_preference(vec!["ps5".into()]);
+        let reader = PfaReader::new_with_options(Cursor::new(bytes), options).unwrap();
+        assert_eq!(reader.exists_all(&["/levels/2/music.ogg"]).unwrap(), [true]);
+    }
+
+    #[test]
+    fn test_traversal_order() {
+        let mut builder = PfaBuilder::new("game");
+        for path in ["/a/deep/1.txt", "/a/2.txt", "/b/3.txt", "/4.txt"] {
+            builder
+                .add_file(path, b"x".to_vec(), DataFlags::auto())
+                .unwrap();
+        }
+        let key = DataFlags::generate_key();
+        builder
+            .add_file(
+                "/b/secret.txt",
+                b"x".to_vec(),
+                DataFlags::auto().encryption(Some(key)),
+            )
+            .unwrap();
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+
+        let mut traverse = |options: TraversalOptions| {
+            let mut paths = vec![];
+            reader
+                .traverse_files_with("/", &options, |file| {
+                    paths.push(file.get_path().to_string());
+                    Ok::<_, ()>(())
+                })
+                .unwrap();
+            paths
+        };
+        let depth_first = traverse(TraversalOptions::new());
+        let breadth_first = traverse(TraversalOptions::new().order(TraversalOrder::BreadthFirst));
+        assert_eq!(traverse(TraversalOptions::new()), depth_first);
+        assert_eq!(depth_first.len(), 4);
+        assert_eq!(breadth_first.len(), 4);
+
+        let depth = |path: &String| path.matches('/').count();
+        assert!(breadth_first
+            .windows(2)
+            .all(|x| depth(&x[0]) <= depth(&x[1])));
+        let deep = depth_first.iter().position(|x| x == "/a/deep/1.txt");
+        let other = depth_first.iter().position(|x| x == "/a/2.txt");
+        let outside = depth_first.iter().position(|x| x == "/b/3.txt");
+        // everything in /a/ is visited together
+        assert_eq!(deep.unwrap().abs_diff(other.unwrap()), 1);
+        assert!(deep.unwrap().max(other.unwrap()) < outside.unwrap());
+
+        let with_key = traverse(TraversalOptions::new().key(Some(key)));
+        assert!(with_key.contains(&"/b/secret.txt".to_string()));
+
+        let mut legacy = vec![];
+        reader.traverse_files("/", |file| legacy.push(file.get_path().to_string()));
+        assert_eq!(legacy, depth_first);
+
+        let mut visited = 0;
+        let result = reader.traverse_files_cancelable("/", |_| {
+            visited += 1;
+            match visited {
+                2 => Err("cancelled"),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(result, Err("cancelled"));
+        assert_eq!(visited, 2);
+    }
+
+    #[test]
+    fn test_visitor() {
+        // Rolls up file sizes per directory, skipping /cache/ and stopping at /stop.txt
+        #[derive(Default)]
+        struct Sizes {
+            stack: Vec<usize>,
+            sizes: Vec<(String, usize)>,
+            events: usize,
+        }
+        impl PfaVisitor for Sizes {
+            fn on_dir_enter(&mut self, dir: &PfaDirectoryContents) -> VisitControl {
+                self.events += 1;
+                self.stack.push(0);
+                match dir.get_path().to_string().as_str() {
+                    "/cache/" => VisitControl::SkipSubtree,
+                    _ => VisitControl::Continue,
+                }
+            }
+
+            fn on_file(&mut self, file: PfaFileContents) -> VisitControl {
+                self.events += 1;
+                if file.get_name() == "stop.txt" {
+                    return VisitControl::Stop;
+                }
+                *self.stack.last_mut().unwrap() += file.get_contents().len();
+                VisitControl::Continue
+            }
+
+            fn on_dir_leave(&mut self, path: &PfaPath) -> VisitControl {
+                self.events += 1;
+                let size = self.stack.pop().unwrap();
+                if let Some(parent) = self.stack.last_mut() {
+                    *parent += size;
+                }
+                self.sizes.push((path.to_string(), size));
+                VisitControl::Continue
+            }
+        }
+
+        let mut builder = PfaBuilder::new("game");
+        for (path, size) in [
+            ("/data/a.bin", 3),
+            ("/data/nested/b.bin", 4),
+            ("/cache/c.bin", 100),
+            ("/d.bin", 1),
+        ] {
+            builder
+                .add_file(path, vec![0; size], DataFlags::auto())
+                .unwrap();
+        }
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+
+        let mut sizes = Sizes::default();
+        reader.visit("/", &TraversalOptions::new(), &mut sizes);
+        assert!(sizes.stack.is_empty());
+        sizes.sizes.sort();
+        assert_eq!(
+            sizes.sizes,
+            [
+                ("/".to_string(), 8),
+                ("/cache/".to_string(), 0),
+                ("/data/".to_string(), 7),
+                ("/data/nested/".to_string(), 4),
+            ]
+        );
+
+        let mut builder = PfaBuilder::new("game");
+        for path in ["/stop.txt", "/other.txt"] {
+            builder
+                .add_file(path, b"x".to_vec(), DataFlags::auto())
+                .unwrap();
+        }
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        let mut sizes = Sizes::default();
+        reader.visit("/", &TraversalOptions::new(), &mut sizes);
+        // entering / and reading stop.txt, wherever it is in the catalog
+        assert!(sizes.events <= 3);
+        assert!(sizes.sizes.is_empty());
+    }
+
+    #[test]
+    fn test_provenance() {
+        let dir = std::env::temp_dir().join(format!("pfa_provenance_{}", Ulid::new()));
+        std::fs::create_dir_all(dir.join("textures")).unwrap();
+        std::fs::write(dir.join("textures/wall.png"), b"png").unwrap();
+
+        let build = |strip: bool| {
+            let mut builder = PfaBuilder::new("game");
+            builder.set_record_provenance(true);
+            builder.set_provenance_tool("packer 1.0");
+            builder
+                .include_directory(dir.to_str().unwrap(), DataFlags::auto())
+                .unwrap();
+            builder
+                .add_file("/generated.txt", b"x".to_vec(), DataFlags::auto())
+                .unwrap();
+            builder
+                .set_provenance("/generated.txt", Provenance::new().tool("generator"))
+                .unwrap();
+            builder.set_strip_provenance(strip);
+            PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap()
+        };
+
+        let reader = build(false);
+        let wall = reader
+            .get_provenance("/textures/wall.png")
+            .unwrap()
+            .unwrap();
+        assert!(wall.source_path.unwrap().ends_with("wall.png"));
+        assert_eq!(wall.source_hash, Some(*blake3::hash(b"png").as_bytes()));
+        assert_eq!(wall.tool.as_deref(), Some("packer 1.0"));
+        assert_eq!(
+            reader.get_provenance("/generated.txt").unwrap(),
+            Some(Provenance::new().tool("generator"))
+        );
+        assert_eq!(reader.get_provenance("/textures/").unwrap(), None);
+
+        let reader = build(true);
+        assert_eq!(reader.get_provenance("/textures/wall.png").unwrap(), None);
+        assert_eq!(reader.get_provenance("/generated.txt").unwrap(), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_transforms() {
+        let source = b"{ \"speed\": 1 }";
+        let source_hash = *blake3::hash(source).as_bytes();
+        let minify = TransformChain::new()
+            .step("minify-json@1")
+            .input_hash(source_hash);
+        let mut builder = PfaBuilder::new("game");
+        let mut add = |path: &str, contents: &[u8], hash, transforms: Option<TransformChain>| {
+            builder
+                .add_file(path, contents.to_vec(), DataFlags::auto())
+                .unwrap();
+            builder
+                .set_provenance(path, Provenance::new().source_hash(hash))
+                .unwrap();
+            if let Some(transforms) = transforms {
+                builder.set_transforms(path, transforms).unwrap();
+            }
+        };
+        add(
+            "/config.json",
+            b"{\"speed\":1}",
+            source_hash,
+            Some(minify.clone()),
+        );
+        add("/raw.json", source, source_hash, None);
+        add("/damaged.json", b"{ \"speed\": 2 }", source_hash, None);
+        add(
+            "/stale.json",
+            b"{\"speed\":1}",
+            [0; 32],
+            Some(minify.clone()),
+        );
+        add(
+            "/corrupted.json",
+            b"{\"speed\":10}",
+            source_hash,
+            Some(minify.clone()),
+        );
+        let generated = TransformChain::new().step("generate");
+        add("/untracked.json", b"{}", [0; 32], Some(generated));
+        builder
+            .add_file("/plain.txt", b"plain".to_vec(), DataFlags::auto())
+            .unwrap();
+        assert!(builder
+            .set_transforms("/plain.txt", TransformChain::new().step("a|b"))
+            .is_err());
+        let mut bytes = builder.build().unwrap();
+        let position = bytes
+            .windows(12)
+            .position(|x| x == b"{\"speed\":10}")
+            .unwrap();
+        bytes[position + 10] = b'9';
+
+        let mut reader = PfaReader::new(Cursor::new(bytes)).unwrap();
+        let transforms = reader.get_transforms("/config.json").unwrap().unwrap();
+        let minify = minify.output_hash(*blake3::hash(b"{\"speed\":1}").as_bytes());
+        assert_eq!(transforms, minify);
+        assert_eq!(transforms.id(), "minify-json@1");
+        assert_eq!(reader.get_transforms("/raw.json").unwrap(), None);
+
+        let mut check = |path: &str| reader.check_source(path, None).unwrap();
+        assert_eq!(
+            check("/config.json"),
+            Some(SourceCheck::Transformed(minify))
+        );
+        assert_eq!(check("/raw.json"), Some(SourceCheck::Unchanged));
+        assert_eq!(check("/damaged.json"), Some(SourceCheck::Mismatch));
+        assert_eq!(check("/stale.json"), Some(SourceCheck::Mismatch));
+        assert_eq!(check("/corrupted.json"), Some(SourceCheck::Mismatch));
+        assert!(matches!(
+            check("/untracked.json"),
+            Some(SourceCheck::Transformed(_))
+        ));
+        assert_eq!(check("/plain.txt"), None);
+        assert_eq!(check("/missing.txt"), None);
+
+        let compacted = reader.compact().unwrap();
+        let compacted = PfaReader::new(Cursor::new(compacted)).unwrap();
+        assert_eq!(
+            compacted.get_transforms("/config.json").unwrap(),
+            reader.get_transforms("/config.json").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_entries() {
+        let mut builder = PfaBuilder::new("game");
+        builder
+            .add_file("/a/big.bin", vec![0; 4000], DataFlags::no_compression())
+            .unwrap();
+        builder
+            .add_file("/a/b/small.txt", b"hi".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/hd.png", b"x".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder.set_tier("/hd.png", Tier::High).unwrap();
+        let bytes = builder.build().unwrap();
+        let reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+
+        let entries = reader
+            .entries()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let mut paths = entries
+            .iter()
+            .map(|x| x.path.to_string())
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            ["/a/", "/a/b/", "/a/b/small.txt", "/a/big.bin", "/hd.png"]
+        );
+        let largest = entries
+            .iter()
+            .filter(|x| !x.is_directory)
+            .max_by_key(|x| x.size)
+            .unwrap();
+        assert_eq!(largest.path.to_string(), "/a/big.bin");
+        assert_eq!(largest.flags, 0);
+        let a = entries
+            .iter()
+            .find(|x| x.path.to_string() == "/a/")
+            .unwrap();
+        assert!(a.is_directory);
+        assert_eq!(a.size, 2);
+
+        let options = PfaReaderOptions::new().max_tier(Tier::Low);
+        let reader = PfaReader::new_with_options(Cursor::new(bytes), options).unwrap();
+        assert_eq!(reader.entries().unwrap().count(), 4);
+    }
+
+    #[test]
+    fn test_shared_contents() {
+        let mut builder = PfaBuilder::new("game");
+        builder
+            .add_file("/a.txt", b"hello world".to_vec(), DataFlags::auto())
+            .unwrap();
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        let file = reader.get_file("/a.txt", None).unwrap().unwrap();
+
+        let word = file.get_bytes().slice(6..);
+        assert_eq!(word.as_ptr(), file.get_contents()[6..].as_ptr());
+        let handle = std::thread::spawn(move || word.to_vec());
+        assert_eq!(handle.join().unwrap(), b"world");
+
+        let copy = file.clone();
+        assert_eq!(copy.get_contents().as_ptr(), file.get_contents().as_ptr());
+        assert_eq!(copy.into_contents(), b"hello world");
+        assert_eq!(file.into_bytes(), &b"hello world"[..]);
+    }
+
+    #[test]
+    fn test_read_range() {
+        let mut builder = PfaBuilder::new("game");
+        let contents = (0..=255).cycle().take(4000).collect::<Vec<u8>>();
+        builder
+            .add_file("/raw.bin", contents.clone(), DataFlags::no_compression())
+            .unwrap();
+        builder
+            .add_file(
+                "/packed.bin",
+                contents.clone(),
+                DataFlags::forced_compression(),
+            )
+            .unwrap();
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+
+        for path in ["/raw.bin", "/packed.bin"] {
+            let range = reader.read_range(path, 1000, 10).unwrap().unwrap();
+            assert_eq!(range, contents[1000..1010]);
+            let tail = reader.read_range(path, 3995, 100).unwrap().unwrap();
+            assert_eq!(tail, contents[3995..]);
+            assert!(reader
+                .read_range(path, 5000, 10)
+                .unwrap()
+                .unwrap()
+                .is_empty());
+        }
+        assert!(reader.read_range("/missing.bin", 0, 10).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_file_preview() {
+        let key = DataFlags::generate_key();
+        let contents = (0..=255).cycle().take(4000).collect::<Vec<u8>>();
+        let mut builder = PfaBuilder::new("preview");
+        let files = [
+            ("/raw.bin", DataFlags::no_compression()),
+            ("/packed.bin", DataFlags::forced_compression()),
+            (
+                "/frames.bin",
+                DataFlags::no_compression()
+                    .encryption(Some(key))
+                    .encryption_frames(Some(256)),
+            ),
+            ("/whole.bin", DataFlags::auto().encryption(Some(key))),
+        ];
+        for (path, flags) in files.clone() {
+            builder.add_file(path, contents.clone(), flags).unwrap();
+        }
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+
+        for (path, _) in files {
+            let preview = reader.get_file_preview(path, 300, Some(key)).unwrap();
+            assert_eq!(preview.as_deref(), Some(&contents[..300]), "{path}");
+            let whole = reader.get_file_preview(path, 10_000, Some(key)).unwrap();
+            assert_eq!(whole, Some(contents.clone()), "{path}");
+        }
+        assert!(reader.get_file_preview("/frames.bin", 10, None).is_err());
+        assert!(reader
+            .get_file_preview("/missing.bin", 10, None)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_open_file() {
+        let key = DataFlags::generate_key();
+        let contents = (0..=255).cycle().take(10000).collect::<Vec<u8>>();
+        let mut builder = PfaBuilder::new("game");
+        builder
+            .add_file("/raw.bin", contents.clone(), DataFlags::no_compression())
+            .unwrap();
+        let framed = DataFlags::no_compression()
+            .encryption(Some(key))
+            .encryption_frames(Some(1000));
+        builder
+            .add_file("/framed.bin", contents.clone(), framed)
+            .unwrap();
+        builder
+            .add_file(
+                "/packed.bin",
+                contents.clone(),
+                DataFlags::forced_compression(),
+            )
+            .unwrap();
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+
+        for (path, key, streaming) in [
+            ("/raw.bin", None, true),
+            // keys are ignored for files which aren't encrypted
+            ("/raw.bin", Some(key), true),
+            ("/framed.bin", Some(key), true),
+            ("/packed.bin", None, false),
+        ] {
+            let mut file = reader.open_file(path, key).unwrap().unwrap();
+            assert_eq!(file.is_streaming(), streaming);
+            let mut chunk = [0; 777];
+            file.read_exact(&mut chunk).unwrap();
+            assert_eq!(chunk, contents[..777]);
+            let mut rest = vec![];
+            file.read_to_end(&mut rest).unwrap();
+            assert_eq!(rest, contents[777..]);
+        }
+
+        assert!(reader.open_file("/framed.bin", None).is_err());
+        assert!(reader.open_file("/missing.bin", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_store_only() {
+        let contents = vec![7; 5000];
+        let mut builder = PfaBuilder::new("mapped");
+        builder.set_store_only(4096);
+        builder.set_reserved_space(4, 6000);
+        builder
+            .add_file("/a.txt", contents.clone(), DataFlags::forced_compression())
+            .unwrap();
+        builder
+            .add_file("/dir/b.bin", vec![1, 2, 3], DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/dir/empty", vec![], DataFlags::auto())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+
+        let probe = PfaProbe::read(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(probe.data_alignment, Some(4096));
+        let check = |bytes: &[u8], files: &[(&str, &[u8])]| {
+            let mut reader = PfaReader::from_slice(bytes).unwrap();
+            assert_eq!(reader.get_data_alignment(), Some(4096));
+            assert!(reader.has_entry_hashes());
+            for (path, expected) in files {
+                let slice = reader.get_file_slice(*path).unwrap().unwrap();
+                assert_eq!(slice, *expected);
+                if !slice.is_empty() {
+                    assert_eq!(
+                        (slice.as_ptr() as usize - bytes.as_ptr() as usize) % 4096,
+                        0
+                    );
+                }
+                let cow = reader.get_file_cow(*path, None).unwrap().unwrap();
+                assert!(matches!(cow, std::borrow::Cow::Borrowed(_)));
+            }
+        };
+        check(
+            &bytes,
+            &[
+                ("/a.txt", &contents),
+                ("/dir/b.bin", &[1, 2, 3]),
+                ("/dir/empty", &[]),
+            ],
+        );
+
+        // the first file fits in the reserved data after padding, the second is appended
+        let mut editor = PfaEditor::new(Cursor::new(bytes)).unwrap();
+        editor
+            .add_file("/dir/c.txt", vec![4; 100], DataFlags::forced_compression())
+            .unwrap();
+        editor
+            .add_file("/dir/d.txt", vec![5; 3000], DataFlags::auto())
+            .unwrap();
+        let key = DataFlags::generate_key();
+        let encrypted = DataFlags::auto().encryption(Some(key));
+        assert!(editor.add_file("/e.txt", vec![6], encrypted).is_err());
+        let bytes = editor.into_inner().into_inner();
+        check(
+            &bytes,
+            &[
+                ("/a.txt", &contents),
+                ("/dir/c.txt", &[4; 100]),
+                ("/dir/d.txt", &[5; 3000]),
+            ],
+        );
+
+        let mut builder = PfaBuilder::new("mapped");
+        builder.set_store_only(4096);
+        builder
+            .add_file("/a.txt", vec![1], DataFlags::auto().encryption(Some(key)))
+            .unwrap();
+        assert!(builder.build().is_err());
+        let mut builder = PfaBuilder::new("mapped");
+        builder.set_store_only(4096);
+        builder.add_stored_file("/a.txt", vec![1], 1).unwrap();
+        assert!(builder.build().is_err());
+        let mut builder = PfaBuilder::new("mapped");
+        builder.set_store_only(0);
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn test_glob() {
+        let mut builder = PfaBuilder::new("assets");
+        for path in [
+            "/textures/a.png",
+            "/textures/ui/b.png",
+            "/textures/ui/c.txt",
+            "/textures/d.jpg",
+            "/other/e.png",
+            "/maps/m1/x",
+            "/maps/m2/y",
+        ] {
+            builder
+                .add_file(path, path.as_bytes().to_vec(), DataFlags::auto())
+                .unwrap();
+        }
+        let reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        let glob = |pattern: &str| {
+            let mut paths = reader
+                .glob(pattern)
+                .unwrap()
+                .into_iter()
+                .map(|x| x.path.to_string())
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+
+        assert_eq!(
+            glob("/textures/**/*.png"),
+            ["/textures/a.png", "/textures/ui/b.png"]
+        );
+        assert_eq!(glob("/textures/*.png"), ["/textures/a.png"]);
+        assert_eq!(glob("/**/*.png").len(), 3);
+        assert_eq!(glob("/maps/*/"), ["/maps/m1/", "/maps/m2/"]);
+        assert_eq!(glob("/textures/d.jpg"), ["/textures/d.jpg"]);
+        assert!(glob("/missing/*").is_empty());
+        assert!(glob("/textures/a.png/*").is_empty());
+        assert!(reader.glob("/maps/*/").unwrap()[0].is_directory);
+        assert!(reader.glob("textures/*").is_err());
+        assert!(reader.glob("/textures/[").is_err());
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let mut builder = PfaBuilder::new("windows");
+        builder
+            .add_file("/data/config.txt", b"lower".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/data/Exact.txt", b"upper".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/data/exact.txt", b"lower".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder.set_directory_limits(DirectoryLimits::new().max_entries(Some(4)).bucket_size(2));
+        for i in 0..10 {
+            builder
+                .add_file(&format!("/big/File{i}.bin"), vec![i], DataFlags::auto())
+                .unwrap();
+        }
+        let bytes = builder.build().unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert!(reader.get_file("/Data/Config.TXT", None).unwrap().is_none());
+
+        let options = PfaReaderOptions::new().case_insensitive(true);
+        let mut reader = PfaReader::new_with_options(Cursor::new(bytes), options).unwrap();
+        let file = reader.get_file("/Data/Config.TXT", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"lower");
+        let file = reader.get_file("/data/Exact.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"upper");
+        let file = reader.get_file("/BIG/file7.BIN", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), [7]);
+        assert!(reader.exists("/DATA/").unwrap());
+        assert!(!reader.exists("/data/config.txt/").unwrap());
+        assert_eq!(reader.glob("/DATA/CONFIG.*").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_indexed_lookup() {
+        let mut builder = PfaBuilder::new("many");
+        for i in 0..5000 {
+            builder
+                .add_file(
+                    &format!("/levels/{}/{i}.map", i % 50),
+                    i.to_string().into_bytes(),
+                    DataFlags::no_compression(),
+                )
+                .unwrap();
+        }
+        // a file and a directory may share a name
+        builder
+            .add_file("/levels/0", b"file".to_vec(), DataFlags::auto())
+            .unwrap();
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+
+        for i in (0..5000).step_by(7) {
+            let file = reader
+                .get_file(&format!("/levels/{}/{i}.map", i % 50)[..], None)
+                .unwrap()
+                .unwrap();
+            assert_eq!(file.get_contents(), i.to_string().as_bytes());
+        }
+        assert!(!reader.exists("/levels/1/0.map").unwrap());
+        assert!(!reader.exists("/levels/0/0.map/").unwrap());
+        let file = reader.get_file("/levels/0", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"file");
+        let dir = reader.get_directory("/levels/0/", None).unwrap().unwrap();
+        assert_eq!(dir.get_contents().len(), 100);
+    }
+
+    #[test]
+    fn test_lazy_catalog() {
+        for layout in [CatalogLayout::Fixed, CatalogLayout::Compact] {
+            let mut builder = PfaBuilder::new("lazy");
+            builder.set_catalog_layout(layout);
+            builder.set_entry_hashes(true);
+            builder
+                .set_directory_limits(DirectoryLimits::new().max_entries(Some(8)).bucket_size(4));
+            for i in 0..40 {
+                builder
+                    .add_file(
+                        &format!("/maps/{}/{i}.map", i % 4),
+                        vec![i],
+                        DataFlags::auto(),
+                    )
+                    .unwrap();
+            }
+            let bytes = builder.build().unwrap();
+
+            let eager = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+            let options = PfaReaderOptions::new().lazy_catalog(true);
+            let mut lazy = PfaReader::new_with_options(Cursor::new(bytes), options).unwrap();
+            let file = lazy.get_file("/maps/3/39.map", None).unwrap().unwrap();
+            assert_eq!(file.get_contents(), [39]);
+            assert!(!lazy.exists("/maps/3/38.map").unwrap());
+            assert!(lazy.exists("/maps/0/").unwrap());
+            assert_eq!(
+                lazy.get_entry_proof("/maps/1/21.map").unwrap(),
+                eager.get_entry_proof("/maps/1/21.map").unwrap()
+            );
+            let list = |reader: &PfaReader<_>| {
+                reader
+                    .entries()
+                    .unwrap()
+                    .map(|x| x.unwrap().path.to_string())
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(list(&lazy), list(&eager));
+
+            lazy.preload_catalog().unwrap();
+            assert_eq!(list(&lazy), list(&eager));
+            assert!(lazy.exists("/maps/2/10.map").unwrap());
+        }
+
+        // catalog warnings are only made once the catalog is preloaded
+        let mut builder = PfaBuilder::new("game");
+        builder.set_catalog_layout(CatalogLayout::Fixed);
+        builder
+            .add_file("/a.txt", b"hello".to_vec(), DataFlags::no_compression())
+            .unwrap();
+        let mut bytes = builder.build().unwrap();
+        let name_at = PfaReader::new(Cursor::new(bytes.clone()))
+            .unwrap()
+            .catalog_idx
+            + 8
+            + 49;
+        bytes[name_at + 32] = 0b10000000;
+        let options = PfaReaderOptions::new().lazy_catalog(true);
+        let mut lazy = PfaReader::new_with_options(Cursor::new(bytes.clone()), options).unwrap();
+        assert_eq!(lazy.warnings(), []);
+        lazy.preload_catalog().unwrap();
+        assert_eq!(lazy.warnings().len(), 1);
+
+        let options = PfaReaderOptions::new().lazy_catalog(true).strict(true);
+        let mut strict = PfaReader::new_with_options(Cursor::new(bytes), options).unwrap();
+        assert!(strict.get_file("/a.txt", None).is_err());
+        assert!(strict.preload_catalog().is_err());
+    }
+
+    #[test]
+    fn test_catalog_cache() {
+        let dir = std::env::temp_dir().join(format!("pfa_catalog_cache_{}", Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let archive = dir.join("game.pfa");
+        let cache = dir.join("game.pfa.catalog");
+        let build = |files: &[&str]| {
+            let mut builder = PfaBuilder::new("game");
+            builder.set_catalog_layout(CatalogLayout::Compact);
+            for path in files {
+                builder
+                    .add_file(path, path.as_bytes().to_vec(), DataFlags::auto())
+                    .unwrap();
+            }
+            builder.build().unwrap()
+        };
+        let open = |path: &std::path::Path| {
+            let options = PfaReaderOptions::new().catalog_cache(&cache);
+            PfaReader::open_with_options(path, options).unwrap()
+        };
+
+        std::fs::write(&archive, build(&["/maps/a.map", "/maps/b.map", "/c.txt"])).unwrap();
+        let reader = open(&archive);
+        assert!(!reader.is_catalog_cached());
+        assert!(cache.exists());
+        let mut reader = open(&archive);
+        assert!(reader.is_catalog_cached());
+        let file = reader.get_file("/maps/b.map", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"/maps/b.map");
+        assert!(!reader.exists("/maps/c.txt").unwrap());
+        let list = |reader: &PfaReader<_>| {
+            reader
+                .entries()
+                .unwrap()
+                .map(|x| x.unwrap().path.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(list(&reader), list(&PfaReader::open(&archive).unwrap()));
+
+        // changing the catalog replaces the cache
+        std::fs::write(&archive, build(&["/maps/a.map", "/d.txt"])).unwrap();
+        let reader = open(&archive);
+        assert!(!reader.is_catalog_cached());
+        assert!(reader.exists("/d.txt").unwrap());
+        assert!(!reader.exists("/maps/b.map").unwrap());
+        assert!(open(&archive).is_catalog_cached());
+
+        // strict readers decode names differently, so they don't share the cache
+        let strict = PfaReaderOptions::new().catalog_cache(&cache).strict(true);
+        let reader = PfaReader::open_with_options(&archive, strict).unwrap();
+        assert!(!reader.is_catalog_cached());
+
+        // damaged caches are decoded again and rewritten
+        let len = std::fs::metadata(&cache).unwrap().len();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&cache)
+            .unwrap();
+        file.set_len(len - 4).unwrap();
+        drop(file);
+        assert!(!open(&archive).is_catalog_cached());
+        assert!(open(&archive).is_catalog_cached());
+
+        // warnings are the same as without the cache
+        let mut bytes = build(&["/a.txt"]);
+        // {num_entries}{root entry}{file name length}{file name}
+        let name_at = PfaReader::new(Cursor::new(bytes.clone()))
+            .unwrap()
+            .catalog_idx
+            + 8
+            + 2
+            + CatalogLayout::SLICE_SIZE
+            + 1;
+        bytes[name_at] = 0xff;
+        std::fs::write(&archive, &bytes).unwrap();
+        let expected = PfaReader::open(&archive).unwrap().warnings().to_vec();
+        assert!(matches!(
+            expected[0],
+            ReaderWarning::LossyName { index: 1, .. }
+        ));
+        assert_eq!(open(&archive).warnings(), expected);
+        let reader = open(&archive);
+        assert!(reader.is_catalog_cached());
+        assert_eq!(reader.warnings(), expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_convert_version() {
+        let key = DataFlags::generate_key();
+        let frames = DataFlags::no_compression()
+            .encryption(Some(key))
+            .encryption_frames(Some(64));
+        let mut builder = PfaBuilder::new("convert");
+        builder.set_entry_hashes(true);
+        builder.set_extra_data(b"manifest ".repeat(20));
+        builder.set_compress_extra_data(true);
+        builder
+            .add_file("/text/a.txt", b"aaaa".repeat(100), DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/secret.bin", vec![7; 300], frames.clone())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+        let Err(PfaError::UnsupportedByVersion { version, features }) =
+            reader.convert_version(1, None)
+        else {
+            panic!("frames can't be stored without the key");
+        };
+        assert_eq!(version, 1);
+        assert_eq!(features, ["encryption frames, used by 'secret.bin'"]);
+
+        let v1 = reader.convert_version(1, Some(key)).unwrap();
+        let mut v1 = PfaReader::new(Cursor::new(v1)).unwrap();
+        assert_eq!(v1.get_version(), 1);
+        assert_eq!(v1.get_archive_id(), None);
+        assert!(!v1.has_entry_hashes());
+        assert_eq!(v1.get_extra_data(), b"manifest ".repeat(20));
+        let secret = v1.get_file("/secret.bin", Some(key)).unwrap().unwrap();
+        assert_eq!(secret.get_contents(), [7; 300]);
+        let stored = v1.get_stored_file("/secret.bin").unwrap().unwrap();
+        assert!(!DataFlags::has_encryption_frames(stored.get_flags()));
+
+        let upgraded = v1.convert_version(LATEST_VERSION, None).unwrap();
+        let mut upgraded = PfaReader::new(Cursor::new(upgraded)).unwrap();
+        assert_eq!(upgraded.get_version(), LATEST_VERSION);
+        assert!(upgraded.has_entry_hashes());
+        assert!(upgraded.get_archive_id().is_some());
+        // data is copied as it is stored
+        for path in ["/text/a.txt", "/secret.bin"] {
+            assert_eq!(
+                upgraded
+                    .get_stored_file(path)
+                    .unwrap()
+                    .unwrap()
+                    .get_contents(),
+                v1.get_stored_file(path).unwrap().unwrap().get_contents()
+            );
+        }
+
+        let mut builder = PfaBuilder::new("features");
+        builder.set_extra_data(vec![0; 300]);
+        builder
+            .add_file(&format!("/{}", "n".repeat(40)), vec![1], DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/tiered", vec![2], DataFlags::auto())
+            .unwrap();
+        builder.set_tier("/tiered", Tier::High).unwrap();
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        let Err(PfaError::UnsupportedByVersion { features, .. }) = reader.convert_version(1, None)
+        else {
+            panic!("long names and tiers can't be stored in version 1");
+        };
+        assert_eq!(features.len(), 3, "{features:?}");
+        assert!(matches!(
+            reader.convert_version(LATEST_VERSION + 1, None),
+            Err(PfaError::UnsupportedFeature(_))
+        ));
+    }
+
+    #[test]
+    fn test_builder_version() {
+        let mut builder = PfaBuilder::new("versioned");
+        assert!(matches!(
+            builder.set_version(0),
+            Err(PfaError::UnsupportedFeature(_))
+        ));
+        assert!(builder.set_version(LATEST_VERSION + 1).is_err());
+        builder.set_version(1).unwrap();
+        builder.set_extra_data(b"game=1.4".to_vec());
+        builder
+            .add_file("/a.txt", b"hello".to_vec(), DataFlags::auto())
+            .unwrap();
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        assert_eq!(reader.get_version(), 1);
+        assert_eq!(reader.get_extra_data(), b"game=1.4");
+        let file = reader.get_file("/a.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"hello");
+
+        let mut builder = PfaBuilder::new("versioned");
+        builder.set_version(1).unwrap();
+        builder.set_extra_data(vec![0; 300]);
+        assert!(matches!(
+            builder.build(),
+            Err(PfaError::UnsupportedByVersion { version: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_follow_generations() {
+        let mut builder = PfaBuilder::new("live");
+        builder.set_generation(0x1122334455667788);
+        builder.set_reserved_space(4, 1024);
+        builder
+            .add_file("/a.txt", b"first".to_vec(), DataFlags::auto())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+        let path =
+            std::env::temp_dir().join(format!("pfa_live_{}.pfa", crate::shared::Ulid::new()));
+        std::fs::write(&path, &bytes).unwrap();
+
+        let options = PfaReaderOptions::new().follow_generations(true);
+        let mut reader = PfaReader::open_with_options(&path, options.clone()).unwrap();
+        let generation = reader.get_generation().unwrap();
+        assert_eq!(generation.number, 0x1122334455667788);
+        assert!(!generation.is_in_progress());
+        let mut stale = PfaReader::open(&path).unwrap();
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut editor = PfaEditor::new(file).unwrap();
+        editor
+            .add_file("/b.txt", b"second".to_vec(), DataFlags::auto())
+            .unwrap();
+        drop(editor);
+
+        assert!(!reader.exists("/b.txt").unwrap());
+        let file = reader.get_file("/b.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"second");
+        assert!(reader.exists("/b.txt").unwrap());
+        assert_eq!(reader.get_generation().unwrap().number, 0x1122334455667789);
+        assert!(stale.get_file("/b.txt", None).unwrap().is_none());
+
+        // a file read as it's needed while the archive is updated
+        let mut opened = reader.open_file("/a.txt", None).unwrap().unwrap();
+        assert!(opened.is_streaming());
+        let mut start = [0; 2];
+        opened.read_exact(&mut start).unwrap();
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut editor = PfaEditor::new(file).unwrap();
+        editor
+            .add_file("/c.txt", b"third".to_vec(), DataFlags::auto())
+            .unwrap();
+        drop(editor);
+        assert!(opened.read_to_end(&mut vec![]).is_err());
+
+        // an update which replaces the file
+        let mut builder = PfaBuilder::new("live");
+        builder.set_generation(1);
+        builder
+            .add_file("/d.txt", b"fourth".to_vec(), DataFlags::auto())
+            .unwrap();
+        let replacement = path.with_extension("new");
+        std::fs::write(&replacement, builder.build().unwrap()).unwrap();
+        std::fs::rename(&replacement, &path).unwrap();
+        let file = reader.get_file("/d.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"fourth");
+        assert!(!reader.exists("/a.txt").unwrap());
+        assert!(stale.get_file("/d.txt", None).unwrap().is_none());
+
+        // an update which never finishes
+        let at = bytes
+            .windows(8)
+            .position(|x| x == 0x1122334455667788u64.to_le_bytes())
+            .unwrap();
+        let mut in_progress = bytes;
+        in_progress[at + 8..at + 16].fill(0);
+        std::fs::write(&path, &in_progress).unwrap();
+        let mut reader = PfaReader::open_with_options(&path, options).unwrap();
+        assert!(reader.get_generation().unwrap().is_in_progress());
+        assert!(reader.get_file("/a.txt", None).is_err());
+        let mut reader = PfaReader::open(&path).unwrap();
+        assert!(reader.get_file("/a.txt", None).unwrap().is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_thread_pool() {
+        let id = Ulid::new();
+        let build = |pool: Option<std::sync::Arc<rayon::ThreadPool>>| {
+            let mut builder = PfaBuilder::new("pool");
+            builder.set_archive_id(id);
+            builder.set_thread_pool(pool);
+            builder.set_threads(1);
+            for i in 0..20 {
+                let contents = format!("file {i} ").repeat(i * 50).into_bytes();
+                let flags = DataFlags::auto().error_correction(Some(0.1));
+                builder
+                    .add_file(&format!("/dir{}/{i}.txt", i % 3), contents, flags)
+                    .unwrap();
+            }
+            builder.build().unwrap()
+        };
+
+        let serial = build(None);
+        let pool = std::sync::Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(2)
+                .build()
+                .unwrap(),
+        );
+        assert_eq!(build(Some(pool.clone())), serial);
+        // building from a task running on the same pool
+        assert_eq!(pool.install(|| build(Some(pool.clone()))), serial);
+    }
+
+    #[test]
+    fn test_archive_index() {
+        use crate::reader::ArchiveIndex;
+
+        let dir = std::env::temp_dir().join(format!("pfa_index_{}", crate::shared::Ulid::new()));
+        std::fs::create_dir_all(dir.join("old")).unwrap();
+        let key = DataFlags::generate_key();
+        for (release, version) in [("old/1.0.pfa", "1.0"), ("1.1.pfa", "1.1")] {
+            let mut builder = PfaBuilder::new(release);
+            builder.set_entry_hashes(release == "1.1.pfa");
+            builder
+                .add_file("/shared.txt", b"same".to_vec(), DataFlags::auto())
+                .unwrap();
+            builder
+                .add_file("/maps/version.txt", version.into(), DataFlags::auto())
+                .unwrap();
+            builder
+                .add_file(
+                    "/secret.bin",
+                    vec![1; 10],
+                    DataFlags::auto().encryption(Some(key)),
+                )
+                .unwrap();
+            builder
+                .add_file("/tab\tname.txt", b"tab".to_vec(), DataFlags::auto())
+                .unwrap();
+            // recorded contents hashes are used instead of decoding the file
+            builder
+                .add_file("/recorded.txt", b"recorded".to_vec(), DataFlags::auto())
+                .unwrap();
+            builder
+                .set_provenance(
+                    "/recorded.txt",
+                    Provenance::new().source_hash(*blake3::hash(b"source").as_bytes()),
+                )
+                .unwrap();
+            std::fs::write(dir.join(release), builder.build().unwrap()).unwrap();
+        }
+        std::fs::write(dir.join("broken.pfa"), b"not an archive").unwrap();
+
+        let index = ArchiveIndex::build(&dir).unwrap();
+        let archives = index.archives();
+        assert_eq!(archives.len(), 3);
+        assert_eq!(archives[2].path, dir.join("old/1.0.pfa"));
+        assert!(archives[1].error.is_some() && archives[2].error.is_none());
+        assert_eq!(index.len(), 5);
+
+        let shared = index.find("/shared.txt");
+        assert_eq!(shared.len(), 2);
+        assert_eq!(shared[0].entry_hash, shared[1].entry_hash);
+        assert!(index.find("/missing.txt").is_empty());
+        assert!(index.find("/secret.bin")[0].contents_hash.is_none());
+        let versions = index.find_matching("/maps/*.txt").unwrap();
+        assert_eq!(versions.len(), 2);
+        let found = index.find_contents(b"1.0");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, "/maps/version.txt");
+        assert_eq!(archives[found[0].1.archive].name, "old/1.0.pfa");
+        assert_eq!(index.find_contents(b"source").len(), 2);
+        assert!(index.find_contents(b"recorded").is_empty());
+
+        let mut saved = vec![];
+        index.write(&mut saved).unwrap();
+        let loaded = ArchiveIndex::read(&saved[..]).unwrap();
+        assert_eq!(loaded, index);
+        assert_eq!(loaded.find("/tab\tname.txt").len(), 2);
+        assert!(ArchiveIndex::read(&saved[..saved.len() - 1]).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_background_verify() {
+        let protected = b"protected contents ".repeat(40);
+        let plain = b"plain contents ".repeat(40);
+        let mut builder = PfaBuilder::new("verify");
+        builder.set_entry_hashes(true);
+        builder
+            .add_file(
+                "/protected.txt",
+                protected.clone(),
+                DataFlags::no_compression().error_correction(Some(0.2)),
+            )
+            .unwrap();
+        builder
+            .add_file("/plain.txt", plain.clone(), DataFlags::no_compression())
+            .unwrap();
+        let mut damaged = builder.build().unwrap();
+        let at = damaged.windows(14).position(|x| x == &plain[..14]).unwrap();
+        damaged[at] ^= 0xff;
+
+        let reader = PfaReader::from_bytes(damaged.clone()).unwrap();
+        let verify = reader
+            .start_background_verify(Cursor::new(damaged.clone()), BackgroundVerifyOptions::new())
+            .unwrap();
+        let issues = verify.issues().iter().collect::<Vec<_>>();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].path(), "/plain.txt");
+        let health = verify.join().unwrap();
+        assert_eq!(health.files, 2);
+        assert_eq!(health.issues, issues);
+
+        // stopping cuts the wait for the rate limit short
+        let options = BackgroundVerifyOptions::new().rate_limit(Some(1));
+        let verify = reader
+            .start_background_verify(Cursor::new(damaged), options)
+            .unwrap();
+        verify.stop();
+        assert!(verify.join().unwrap().files <= 1);
+
+        let verify = reader
+            .start_background_verify(Cursor::new(vec![0; 16]), BackgroundVerifyOptions::new())
+            .unwrap();
+        assert!(verify.join().is_err());
+    }
+
+    #[test]
+    fn test_partial_read() {
+        let contents = (0..2000).map(|x| (x * 7 % 251) as u8).collect::<Vec<_>>();
+        let mut builder = PfaBuilder::new("partial");
+        builder
+            .add_file(
+                "/media.bin",
+                contents.clone(),
+                DataFlags::no_compression().error_correction(Some(0.1)),
+            )
+            .unwrap();
+        builder
+            .add_file(
+                "/compressed.bin",
+                vec![0; 2000],
+                DataFlags::forced_compression().error_correction(Some(0.1)),
+            )
+            .unwrap();
+        let bytes = builder.build().unwrap();
+
+        let mut reader = PfaReader::from_bytes(bytes.clone()).unwrap();
+        let file = reader
+            .get_file_partial("/media.bin", None)
+            .unwrap()
+            .unwrap();
+        assert!(file.is_intact());
+        assert_eq!(file.get_contents(), contents);
+        let raw = reader.get_file_raw("/media.bin").unwrap().unwrap();
+        let block_data = 255 - raw.get_stage_headers().ecc_size.unwrap();
+        assert!(reader
+            .get_file_partial("/missing.bin", None)
+            .unwrap()
+            .is_none());
+
+        // wreck the second block, far beyond what its parity bytes repair
+        let mut damaged = bytes;
+        let at = damaged
+            .windows(32)
+            .position(|x| x == &contents[..32])
+            .unwrap();
+        damaged[at + 255..at + 255 + 100].fill(0xaa);
+        let mut reader = PfaReader::from_bytes(damaged).unwrap();
+        assert!(matches!(
+            reader.get_file("/media.bin", None),
+            Err(PfaError::ErrorCorrectionError(_))
+        ));
+        let file = reader
+            .get_file_partial("/media.bin", None)
+            .unwrap()
+            .unwrap();
+        assert!(!file.is_intact());
+        let damaged = block_data..block_data * 2;
+        assert_eq!(file.damaged_ranges(), std::slice::from_ref(&damaged));
+        assert_eq!(
+            file.recovered_ranges(),
+            [0..block_data, block_data * 2..2000]
+        );
+        assert_eq!(file.get_contents().len(), 2000);
+        for range in file.recovered_ranges() {
+            let range = range.start as usize..range.end as usize;
+            assert_eq!(file.get_contents()[range.clone()], contents[range]);
+        }
+        let file = reader
+            .get_file_partial("/compressed.bin", None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(file.into_contents(), vec![0; 2000]);
+    }
+
+    #[test]
+    fn test_scrub() {
+        let protected = b"protected contents ".repeat(40);
+        let plain = b"plain contents ".repeat(40);
+        let mut builder = PfaBuilder::new("scrub");
+        builder.set_entry_hashes(true);
+        builder
+            .add_file(
+                "/dir/protected.txt",
+                protected.clone(),
+                DataFlags::no_compression().error_correction(Some(0.2)),
+            )
+            .unwrap();
+        builder
+            .add_file("/plain.txt", plain.clone(), DataFlags::no_compression())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+        let health = PfaReader::from_bytes(bytes.clone())
+            .unwrap()
+            .scrub()
+            .unwrap();
+        assert_eq!(health.files, 2);
+        assert!(health.is_healthy());
+
+        let mut damaged = bytes.clone();
+        let at = damaged
+            .windows(18)
+            .position(|x| x == &protected[..18])
+            .unwrap();
+        damaged[at..at + 4].fill(0);
+        let at = damaged.windows(14).position(|x| x == &plain[..14]).unwrap();
+        damaged[at] ^= 0xff;
+        let mut reader = PfaReader::from_bytes(damaged.clone()).unwrap();
+        let health = reader.scrub().unwrap();
+        assert_eq!(
+            health.issues[0],
+            ScrubIssue::Corrected {
+                path: "/dir/protected.txt".into(),
+                bytes: 4
+            }
+        );
+        assert!(
+            matches!(&health.issues[1], ScrubIssue::Damaged { path, .. } if path == "/plain.txt")
+        );
+
+        let Err(PfaError::Unrecoverable(unrecoverable)) = reader.repair() else {
+            panic!("/plain.txt has no error correction");
+        };
+        assert_eq!(unrecoverable, ["/plain.txt"]);
+
+        // only the damage error correction repairs
+        let mut fixable = damaged.clone();
+        fixable[at] ^= 0xff;
+        let mut reader = PfaReader::from_bytes(fixable.clone()).unwrap();
+        let mut repaired = PfaReader::from_bytes(reader.repair().unwrap()).unwrap();
+        assert!(repaired.scrub().unwrap().is_healthy());
+        let file = repaired
+            .get_file("/dir/protected.txt", None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(file.get_contents(), protected);
+        let file = repaired.get_file("/plain.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), plain);
+
+        // solid blocks have no error correction, so their files are as unrecoverable
+        let mut builder = PfaBuilder::new("solid");
+        builder.set_entry_hashes(true);
+        builder.set_solid_blocks(1024, 64 * 1024);
+        for name in ["a", "b"] {
+            builder
+                .add_file(&format!("/{name}.txt"), plain.clone(), DataFlags::auto())
+                .unwrap();
+        }
+        let mut solid = builder.build().unwrap();
+        let reader = PfaReader::from_bytes(solid.clone()).unwrap();
+        let block = reader.get_header_fields().solid_blocks[0];
+        let at = reader.data_idx + block.offset as usize + block.size as usize / 2;
+        solid[at] ^= 0xff;
+        let mut reader = PfaReader::from_bytes(solid).unwrap();
+        let Err(PfaError::Unrecoverable(unrecoverable)) = reader.repair() else {
+            panic!("the solid block is damaged");
+        };
+        assert_eq!(unrecoverable, ["/a.txt", "/b.txt"]);
+
+        let dir = std::env::temp_dir().join(format!("pfa_scrub_{}", crate::shared::Ulid::new()));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("good.pfa"), &bytes).unwrap();
+        std::fs::write(dir.join("nested/bad.pfa"), &damaged).unwrap();
+        std::fs::write(dir.join("nested/fixable.pfa"), &fixable).unwrap();
+        std::fs::write(dir.join("broken.pfa"), b"not an archive").unwrap();
+        let task = ScrubTask::new(&dir).repair(true).rate_limit(Some(u64::MAX));
+        let report = task.run().unwrap();
+        assert!(!report.is_healthy());
+        let names = report
+            .archives
+            .iter()
+            .map(|x| x.path.strip_prefix(&dir).unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "broken.pfa",
+                "good.pfa",
+                "nested/bad.pfa",
+                "nested/fixable.pfa"
+            ]
+        );
+        assert!(report.archives[0].error.is_some());
+        assert!(report.archives[1].repaired.is_none());
+        assert!(report.archives[2].repaired.is_none());
+        assert_eq!(report.archives[2].unrecoverable, ["/plain.txt"]);
+        assert!(!dir.join("nested/bad.pfa.repaired").exists());
+        let repaired_path = report.archives[3].repaired.clone().unwrap();
+        assert_eq!(repaired_path, dir.join("nested/fixable.pfa.repaired"));
+        assert!(report.archives[3].unrecoverable.is_empty());
+        let mut runs = 0;
+        task.run_every(std::time::Duration::ZERO, |report| {
+            runs += 1;
+            assert_eq!(report.archives.len(), 4);
+            runs < 2
+        })
+        .unwrap();
+        assert_eq!(runs, 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_extra_data_compression() {
+        let manifest = b"asset manifest line\n".repeat(500);
+        let build = |extra_data: &[u8], compress: bool| {
+            let mut builder = PfaBuilder::new("extra");
+            builder.set_extra_data(extra_data.to_vec());
+            builder.set_compress_extra_data(compress);
+            builder.set_reserved_space(1, 0);
+            builder
+                .add_file("/a.txt", b"a".to_vec(), DataFlags::auto())
+                .unwrap();
+            builder.build().unwrap()
+        };
+
+        let plain = build(&manifest, false);
+        let compressed = build(&manifest, true);
+        assert!(compressed.len() + manifest.len() / 2 < plain.len());
+        for bytes in [plain, compressed.clone()] {
+            let reader = PfaReader::new(Cursor::new(bytes)).unwrap();
+            assert_eq!(reader.get_extra_data(), manifest);
+        }
+        // data which doesn't shrink is stored as is
+        let reader = PfaReader::new(Cursor::new(build(b"tiny", true))).unwrap();
+        assert_eq!(reader.get_extra_data(), b"tiny");
+
+        let mut editor = PfaEditor::new(Cursor::new(compressed)).unwrap();
+        editor
+            .add_file("/b.txt", b"b".to_vec(), DataFlags::auto())
+            .unwrap();
+        let reader = PfaReader::new(Cursor::new(editor.into_inner().into_inner())).unwrap();
+        assert_eq!(reader.get_extra_data(), manifest);
+        assert!(reader.exists("/b.txt").unwrap());
+    }
+
+    #[test]
+    fn test_open_constructors() {
+        let mut builder = PfaBuilder::new("disk");
+        builder
+            .add_file("/a.txt", b"on disk".to_vec(), DataFlags::auto())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+        let path = std::env::temp_dir().join(format!("pfa_open_{}.pfa", Ulid::new()));
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut reader = PfaReader::open(&path).unwrap();
+        let file = reader.get_file("/a.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"on disk");
+        let options = PfaReaderOptions::new().case_insensitive(true);
+        let reader = PfaReader::open_with_options(&path, options).unwrap();
+        assert!(reader.exists("/A.TXT").unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(PfaReader::open(&path), Err(PfaError::IOError(_))));
+
+        let mut reader = PfaReader::from_bytes(bytes).unwrap();
+        let file = reader.get_file("/a.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"on disk");
+        assert!(PfaReader::from_bytes(b"not an archive".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_file_info() {
+        let key = DataFlags::generate_key();
+        let mut builder = PfaBuilder::new("game");
+        builder
+            .add_file("/raw.bin", vec![1; 100], DataFlags::no_compression())
+            .unwrap();
+        builder
+            .add_file(
+                "/packed.txt",
+                vec![b'a'; 4000],
+                DataFlags::forced_compression(),
+            )
+            .unwrap();
+        builder
+            .add_file(
+                "/secret.txt",
+                b"secret".to_vec(),
+                DataFlags::no_compression().encryption(Some(key)),
+            )
+            .unwrap();
+        let reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+
+        let raw = reader.get_file_info("/raw.bin").unwrap().unwrap();
+        assert_eq!(
+            (raw.contents_size, raw.size, raw.flags),
+            (Some(100), 100, 0)
+        );
+        let packed = reader.get_file_info("/packed.txt").unwrap().unwrap();
+        assert!(packed.is_compressed() && !packed.is_encrypted());
+        assert_eq!(packed.contents_size, Some(4000));
+        assert!(packed.size < 4000);
+        let secret = reader.get_file_info("/secret.txt").unwrap().unwrap();
+        assert!(secret.is_encrypted() && !secret.has_error_correction());
+        assert!(secret.size > 6);
+
+        assert_eq!(reader.get_file_info("/missing.txt").unwrap(), None);
+        assert_eq!(reader.get_file_info("/").unwrap(), None);
+    }
+
+    #[test]
+    fn test_max_decompressed_size() {
+        let mut builder = PfaBuilder::new("bombs");
+        builder
+            .add_file(
+                "/bomb.bin",
+                vec![0; 1 << 20],
+                DataFlags::forced_compression(),
+            )
+            .unwrap();
+        builder
+            .add_file("/small.txt", vec![1; 100], DataFlags::forced_compression())
+            .unwrap();
+        builder
+            .add_file("/stored.bin", vec![2; 8000], DataFlags::no_compression())
+            .unwrap();
+        builder
+            .add_file("/large.bin", vec![3; 12_000], DataFlags::no_compression())
+            .unwrap();
+        builder.set_extra_data(vec![0; 8000]);
+        builder.set_compress_extra_data(true);
+        let bytes = builder.build().unwrap();
+
+        let options = PfaReaderOptions::new().max_decompressed_size(10_000);
+        let mut reader = PfaReader::new_with_options(Cursor::new(bytes.clone()), options).unwrap();
+        assert!(matches!(
+            reader.get_file("/bomb.bin", None),
+            Err(PfaError::DecompressionLimitError { limit: 10_000 })
+        ));
+        let small = reader.get_file("/small.txt", None).unwrap().unwrap();
+        assert_eq!(small.get_contents(), vec![1; 100]);
+        let stored = reader.get_file("/stored.bin", None).unwrap().unwrap();
+        assert_eq!(stored.get_contents().len(), 8000);
+        // stored data is checked before it's read, even when it isn't compressed
+        assert!(matches!(
+            reader.get_file("/large.bin", None),
+            Err(PfaError::DecompressionLimitError { limit: 10_000 })
+        ));
+        assert!(matches!(
+            reader.get_stored_file("/large.bin"),
+            Err(PfaError::DecompressionLimitError { limit: 10_000 })
+        ));
+
+        let options = PfaReaderOptions::new().max_decompressed_size(1000);
+        assert!(matches!(
+            PfaReader::new_with_options(Cursor::new(bytes.clone()), options),
+            Err(PfaError::DecompressionLimitError { limit: 1000 })
+        ));
+
+        let mut builder = PfaBuilder::new("solid bombs");
+        builder.set_solid_blocks(1 << 20, 1 << 22);
+        for i in 0..4 {
+            builder
+                .add_file(&format!("/{i}.txt"), vec![0; 4000], DataFlags::auto())
+                .unwrap();
+        }
+        let options = PfaReaderOptions::new().max_decompressed_size(10_000);
+        let mut reader =
+            PfaReader::new_with_options(Cursor::new(builder.build().unwrap()), options).unwrap();
+        assert!(matches!(
+            reader.get_file("/0.txt", None),
+            Err(PfaError::DecompressionLimitError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let path = format!("{}deep.txt", "/d".repeat(2000) + "/");
+        let mut builder = PfaBuilder::new("deep");
+        let err = builder.add_file(&path, b"deep".to_vec(), DataFlags::auto());
+        assert!(matches!(
+            err,
+            Err(PfaError::DepthLimitError { limit: 1024, .. })
+        ));
+
+        builder.set_depth_limit(2000);
+        builder
+            .add_file(&path, b"deep".to_vec(), DataFlags::auto())
+            .unwrap();
+        let bytes = builder.build().unwrap();
+
+        let mut reader = PfaReader::new(Cursor::new(bytes.clone())).unwrap();
+        let file = reader.get_file(&path[..], None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"deep");
+        let entries = reader.entries().unwrap().collect::<Result<Vec<_>, _>>();
+        assert!(matches!(
+            entries,
+            Err(PfaError::DepthLimitError { limit: 1024, .. })
+        ));
+        let mut files = 0;
+        reader.traverse_files("/", |_| files += 1);
+        assert_eq!(files, 0);
+
+        let options = PfaReaderOptions::new().depth_limit(2000);
+        let mut reader = PfaReader::new_with_options(Cursor::new(bytes), options).unwrap();
+        assert_eq!(reader.entries().unwrap().count(), 2001);
+        reader.traverse_files("/", |file| {
+            assert_eq!(file.get_path().to_string(), path);
+            files += 1;
+        });
+        assert_eq!(files, 1);
+    }
+
+    #[test]
+    fn test_resolver() {
+        let key = DataFlags::generate_key();
+        let mut builder = PfaBuilder::new("ui");
+        builder
+            .add_file("/index.html", b"<html>".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/img/my icon.png", b"icon".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file(
+                "/secret.txt",
+                b"secret".to_vec(),
+                DataFlags::auto().encryption(Some(key)),
+            )
+            .unwrap();
+        let reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+
+        let mut resolver = PfaResolver::new();
+        assert!(resolver.mount(reader).is_none());
+        assert_eq!(
+            resolver
+                .resolve_file("pfa://ui/index.html?v=2#top")
+                .unwrap(),
+            Some(b"<html>".to_vec())
+        );
+        assert_eq!(
+            resolver.resolve_file("pfa://ui/img/my%20icon.png").unwrap(),
+            Some(b"icon".to_vec())
+        );
+        assert!(resolver
+            .resolve_file("pfa://ui/missing.txt")
+            .unwrap()
+            .is_none());
+        assert!(resolver.resolve("pfa://ui/img/").unwrap().is_some());
+        assert!(resolver.resolve("pfa://other/index.html").is_err());
+        assert!(resolver.resolve("https://ui/index.html").is_err());
+        assert!(resolver.resolve_file("pfa://ui/secret.txt").is_err());
+
+        let reader = resolver.unmount("ui").unwrap();
+        resolver.mount_as("assets", reader, Some(key));
+        assert_eq!(
+            resolver.resolve_file("pfa://assets/secret.txt").unwrap(),
+            Some(b"secret".to_vec())
+        );
+        assert_eq!(
+            PfaResolver::<Cursor<Vec<u8>>>::parse_url("pfa://ui").unwrap(),
+            ("ui".to_string(), "/".to_string())
+        );
+    }
+
+    #[test]
+    fn test_remove_and_rename() {
+        let mut builder = PfaBuilder::new("tree");
+        for path in ["/a.txt", "/b.txt", "/dir/c.txt", "/dir/sub/d.txt"] {
+            builder
+                .add_file(path, path.as_bytes().to_vec(), DataFlags::auto())
+                .unwrap();
+        }
+        builder.set_tier("/dir/c.txt", Tier::High).unwrap();
+        builder.remove("/b.txt").unwrap();
+        builder.rename("/a.txt", "/moved/a2.txt").unwrap();
+        builder.rename("/dir/", "/other/").unwrap();
+        builder.rename("/other/sub/", "/sub/").unwrap();
+        builder.remove("/sub/").unwrap();
+
+        assert!(builder.remove("/missing.txt").is_err());
+        assert!(
+            builder.remove("/other").is_err(),
+            "directories end with '/'"
+        );
+        assert!(builder.remove("/").is_err());
+        assert!(builder.rename("/other/", "/other/inner/").is_err());
+        assert!(builder.rename("/other/c.txt", "/other/").is_err());
+        builder
+            .add_file("/taken.txt", vec![], DataFlags::auto())
+            .unwrap();
+        assert!(matches!(
+            builder.rename("/other/c.txt", "/taken.txt"),
+            Err(PfaError::DuplicatePath(_))
+        ));
+
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        let files = reader
+            .entries()
+            .unwrap()
+            .map(|x| x.unwrap())
+            .filter(|x| !x.is_directory)
+            .map(|x| x.path.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(files, ["/moved/a2.txt", "/other/c.txt", "/taken.txt"]);
+        let file = reader.get_file("/moved/a2.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"/a.txt");
+        assert_eq!(
+            reader.stat("/other/c.txt").unwrap().unwrap().tier,
+            Some(Tier::High)
+        );
+    }
+
+    #[test]
+    fn test_builder_from_reader() {
+        use crate::builder::DuplicatePolicy;
+
+        let key = DataFlags::generate_key();
+        let frames = DataFlags::no_compression()
+            .encryption(Some(key))
+            .encryption_frames(Some(64));
+        let mut builder = PfaBuilder::new("seed");
+        builder.set_entry_hashes(true);
+        builder.set_extra_data(b"extra".to_vec());
+        builder.set_generation(3);
+        builder
+            .add_file("/a.txt", b"aaaa".repeat(100), DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file("/dir/b.txt", b"b".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder.set_tier("/dir/b.txt", Tier::High).unwrap();
+        builder
+            .add_file("/secret.bin", vec![7; 300], frames)
+            .unwrap();
+        let mut old = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+
+        let mut builder = PfaBuilder::from_reader(&mut old, None).unwrap();
+        builder.set_duplicate_policy(DuplicatePolicy::Overwrite);
+        builder
+            .add_file("/a.txt", b"new".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder.remove("/dir/").unwrap();
+        let mut new = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        assert_eq!(new.get_archive_id(), old.get_archive_id());
+        assert_eq!(new.get_name(), "seed");
+        assert_eq!(old.get_generation().unwrap().number, 3);
+        assert_eq!(new.get_generation(), None);
+        assert!(new.has_entry_hashes());
+        assert_eq!(new.get_extra_data(), b"extra");
+        let contents = |reader: &mut PfaReader<_>, path: &str| {
+            let key = Some(key).filter(|_| path == "/secret.bin");
+            reader
+                .get_file(path, key)
+                .unwrap()
+                .map(|x| x.into_contents())
+        };
+        assert_eq!(contents(&mut new, "/a.txt"), Some(b"new".to_vec()));
+        assert_eq!(contents(&mut new, "/dir/b.txt"), None);
+        assert_eq!(contents(&mut new, "/secret.bin"), Some(vec![7; 300]));
+        assert_eq!(
+            new.get_stored_file("/secret.bin")
+                .unwrap()
+                .unwrap()
+                .get_contents(),
+            old.get_stored_file("/secret.bin")
+                .unwrap()
+                .unwrap()
+                .get_contents()
+        );
+
+        // files the key provider has a key for are encrypted again when built
+        let keys = |entry: &PfaEncryptedEntry| {
+            (entry.key_id == Some(DataFlags::key_id(&key))).then_some(key)
+        };
+        let builder = PfaBuilder::from_reader(&mut old, Some(&keys)).unwrap();
+        let mut new = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        assert_eq!(
+            new.stat("/dir/b.txt").unwrap().unwrap().tier,
+            Some(Tier::High)
+        );
+        assert_eq!(contents(&mut new, "/secret.bin"), Some(vec![7; 300]));
+        let stored = new.get_stored_file("/secret.bin").unwrap().unwrap();
+        assert!(!DataFlags::has_encryption_frames(stored.get_flags()));
+    }
+
+    #[test]
+    fn test_entry_filter() {
+        let key = DataFlags::generate_key();
+        let mut builder = PfaBuilder::new("filter");
+        for path in ["/img/a.PNG", "/img/b.jpg", "/img/deep/c.png", "/readme.txt"] {
+            builder
+                .add_file(path, path.as_bytes().to_vec(), DataFlags::auto())
+                .unwrap();
+        }
+        builder
+            .add_file(
+                "/secret.png",
+                vec![1; 10],
+                DataFlags::auto().encryption(Some(key)),
+            )
+            .unwrap();
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        let list = |reader: &PfaReader<_>, path: &str, filter: EntryFilter| {
+            reader
+                .entries_matching(path, &filter)
+                .unwrap()
+                .map(|x| x.unwrap().path.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            list(
+                &reader,
+                "/",
+                EntryFilter::new().kind(EntryKind::Directories)
+            ),
+            ["/img/", "/img/deep/"]
+        );
+        assert_eq!(
+            list(&reader, "/img/", EntryFilter::new().kind(EntryKind::Files)),
+            ["/img/a.PNG", "/img/b.jpg", "/img/deep/c.png"]
+        );
+        assert_eq!(
+            list(&reader, "/", EntryFilter::new().extension(".png")),
+            ["/img/a.PNG", "/img/deep/c.png", "/secret.png"]
+        );
+        assert_eq!(
+            list(&reader, "/", EntryFilter::new().encrypted(Some(true))),
+            ["/secret.png"]
+        );
+        assert!(list(&reader, "/missing/", EntryFilter::new()).is_empty());
+
+        let mut read = vec![];
+        let filter = EntryFilter::new().extension("png").encrypted(Some(false));
+        let options = TraversalOptions::new().filter(Some(filter));
+        reader
+            .traverse_files_with("/", &options, |file| {
+                read.push(file.get_path().to_string());
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        assert_eq!(read, ["/img/a.PNG", "/img/deep/c.png"]);
+    }
+
+    #[test]
+    fn test_encrypted_entries() {
+        let key = DataFlags::generate_key();
+        let other_key = DataFlags::generate_key();
+        let mut builder = PfaBuilder::new("keys");
+        builder
+            .add_file("/plain.txt", b"plain".to_vec(), DataFlags::auto())
+            .unwrap();
+        builder
+            .add_file(
+                "/a.bin",
+                vec![1; 100],
+                DataFlags::auto().encryption(Some(key)),
+            )
+            .unwrap();
+        builder
+            .add_file(
+                "/dir/b.bin",
+                vec![2; 100],
+                DataFlags::no_compression().encryption(Some(key)),
+            )
+            .unwrap();
+        builder
+            .add_file(
+                "/dir/c.bin",
+                vec![3; 100],
+                DataFlags::auto().encryption(Some(other_key)),
+            )
+            .unwrap();
+        let mut reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        let stored = reader.get_stored_file("/a.bin").unwrap().unwrap();
+
+        let entries = reader.encrypted_entries().unwrap();
+        let paths = entries
+            .iter()
+            .map(|x| x.path.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/a.bin", "/dir/b.bin", "/dir/c.bin"]);
+        assert_eq!(entries[0].key_id, Some(DataFlags::key_id(&key)));
+        assert_eq!(entries[1].key_id, Some(DataFlags::key_id(&key)));
+        assert_eq!(entries[2].key_id, Some(DataFlags::key_id(&other_key)));
+        assert_ne!(DataFlags::key_id(&key), DataFlags::key_id(&other_key));
+        let keys = [DataFlags::key_id(&key), DataFlags::key_id(&other_key)];
+        assert_eq!(reader.required_keys().unwrap(), (keys.to_vec(), false));
+
+        // key ids are kept when the data is copied
+        let compacted = reader.compact().unwrap();
+        let compacted = PfaReader::new(Cursor::new(compacted)).unwrap();
+        assert_eq!(compacted.encrypted_entries().unwrap(), entries);
+
+        // data encrypted elsewhere only has a key id if one is set
+        let mut builder = PfaBuilder::new("copied");
+        let flags = stored.get_flags();
+        builder
+            .add_stored_file("/a.bin", stored.get_contents().to_vec(), flags)
+            .unwrap();
+        builder
+            .add_stored_file("/b.bin", stored.into_contents(), flags)
+            .unwrap();
+        builder
+            .set_key_id("/b.bin", DataFlags::key_id(&key))
+            .unwrap();
+        let reader = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        let entries = reader.encrypted_entries().unwrap();
+        assert_eq!(entries[0].key_id, None);
+        assert_eq!(entries[1].key_id, Some(DataFlags::key_id(&key)));
+        assert_eq!(reader.required_keys().unwrap(), (keys[..1].to_vec(), true));
+    }
+
+    #[test]
+    fn test_solid_blocks() {
+        let item = |path: &str| {
+            let i = path.trim_start_matches("/items/").trim_end_matches(".json");
+            format!("{{\"id\": {i}, \"name\": \"item {i}\", \"stack\": 64}}")
+        };
+        let build = |solid: bool, version: u8| {
+            let mut builder = PfaBuilder::new("solid");
+            builder.set_entry_hashes(version > 1);
+            builder.set_version(version).unwrap();
+            if solid {
+                builder.set_solid_blocks(1024, 16 * 1024);
+            }
+            for i in 0..100 {
+                let path = format!("/items/{i}.json");
+                builder
+                    .add_file(&path, item(&path).into_bytes(), DataFlags::auto())
+                    .unwrap();
+            }
+            builder
+                .add_file("/large.bin", vec![3; 4096], DataFlags::auto())
+                .unwrap();
+            builder.build()
+        };
+        let plain = build(false, LATEST_VERSION).unwrap();
+        let bytes = build(true, LATEST_VERSION).unwrap();
+        assert!(bytes.len() < plain.len());
+
+        let check = |reader: &mut PfaReader<Cursor<Vec<u8>>>| {
+            for i in [0, 42, 99] {
+                let path = format!("/items/{i}.json");
+                let file = reader.get_file(&path[..], None).unwrap().unwrap();
+                assert_eq!(file.get_contents(), item(&path).as_bytes());
+                assert!(reader.is_in_solid_block(&path[..]).unwrap());
+            }
+            assert!(!reader.is_in_solid_block("/large.bin").unwrap());
+            assert_eq!(
+                reader
+                    .get_file("/large.bin", None)
+                    .unwrap()
+                    .unwrap()
+                    .get_contents(),
+                vec![3; 4096]
+            );
+        };
+        let mut reader = PfaReader::new(Cursor::new(bytes)).unwrap();
+        check(&mut reader);
+        let info = reader.get_file_info("/items/7.json").unwrap().unwrap();
+        assert_eq!(info.contents_size, Some(item("/items/7.json").len() as u64));
+        // members are slices of the cached block rather than copies
+        let first = reader.get_file("/items/0.json", None).unwrap().unwrap();
+        let second = reader.get_file("/items/1.json", None).unwrap().unwrap();
+        assert_eq!(
+            second.get_contents().as_ptr(),
+            first.get_contents()[first.get_contents().len()..].as_ptr()
+        );
+        assert_eq!(reader.get_unreferenced_data().unwrap(), []);
+        let health = reader.scrub().unwrap();
+        assert_eq!(health.files, 101);
+        assert!(health.is_healthy());
+
+        let mut files = 0;
+        reader
+            .extract_streaming("/items/", ExtractorOptions::new(), |file| {
+                let file = file?;
+                assert_eq!(
+                    file.get_contents(),
+                    item(&file.get_path().to_string()).as_bytes()
+                );
+                files += 1;
+                Ok::<_, PfaError>(())
+            })
+            .unwrap();
+        assert_eq!(files, 100);
+
+        // copies group the files into solid blocks again
+        let mut compacted = PfaReader::new(Cursor::new(reader.compact().unwrap())).unwrap();
+        check(&mut compacted);
+        assert!(compacted.scrub().unwrap().is_healthy());
+        let builder = PfaBuilder::from_reader(&mut reader, None).unwrap();
+        check(&mut PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap());
+
+        // version 1 archives can't store them
+        let Err(PfaError::UnsupportedByVersion { features, .. }) = build(true, 1) else {
+            panic!("solid blocks need version 2");
+        };
+        assert_eq!(features, ["solid blocks"]);
+        let mut v1 = PfaReader::new(Cursor::new(reader.convert_version(1, None).unwrap())).unwrap();
+        assert!(!v1.is_in_solid_block("/items/7.json").unwrap());
+        assert_eq!(
+            v1.get_file("/items/7.json", None)
+                .unwrap()
+                .unwrap()
+                .get_contents(),
+            item("/items/7.json").as_bytes()
+        );
+    }
 }
@@ -0,0 +1,137 @@
+//! Lua bindings, enabled with the `lua` feature. [`register`] installs a global `pfa` table:
+//!
+//! ```lua
+//! local archive = pfa.open("assets.pfa")
+//! print(archive:name())
+//! for _, path in ipairs(archive:list("/textures/")) do print(path) end
+//! if archive:exists("/config.txt") then
+//!     local contents = archive:read("/config.txt") -- nil if it doesn't exist
+//! end
+//! local secret = archive:read("/secret.txt", key) -- key is a 32 byte string
+//! ```
+
+use std::{fs::File, io::BufReader};
+
+use mlua::{Lua, LuaString, Table, UserData, UserDataMethods};
+
+use crate::{reader::PfaReader, PfaError};
+
+struct LuaArchive {
+    reader: PfaReader<BufReader<File>>,
+}
+
+fn to_lua_error(error: PfaError) -> mlua::Error {
+    mlua::Error::external(error)
+}
+
+fn to_key(key: Option<LuaString>) -> mlua::Result<Option<[u8; 32]>> {
+    key.map(|x| {
+        x.as_bytes().as_ref().try_into().map_err(|_| {
+            mlua::Error::external(PfaError::CustomError("key must be 32 bytes".into()))
+        })
+    })
+    .transpose()
+}
+
+impl UserData for LuaArchive {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("name", |_, this, ()| Ok(this.reader.get_name().to_string()));
+
+        methods.add_method("exists", |_, this, path: String| {
+            this.reader.exists(&path[..]).map_err(to_lua_error)
+        });
+
+        methods.add_method_mut("list", |lua, this, path: String| {
+            let Some(dir) = this
+                .reader
+                .get_directory(&path[..], None)
+                .map_err(to_lua_error)?
+            else {
+                return Ok(None);
+            };
+            let paths = dir.get_contents().iter().map(|x| x.to_string());
+            lua.create_sequence_from(paths).map(Some)
+        });
+
+        methods.add_method_mut(
+            "read",
+            |lua, this, (path, key): (String, Option<LuaString>)| {
+                let Some(file) = this
+                    .reader
+                    .get_file(&path[..], to_key(key)?)
+                    .map_err(to_lua_error)?
+                else {
+                    return Ok(None);
+                };
+                lua.create_string(file.get_contents()).map(Some)
+            },
+        );
+    }
+}
+
+/// Creates the `pfa` table without installing it, for example to return it from a
+/// `package.preload` loader
+pub fn module(lua: &Lua) -> mlua::Result<Table> {
+    let module = lua.create_table()?;
+    module.set(
+        "open",
+        lua.create_function(|_, path: String| {
+            let reader = PfaReader::open(&path).map_err(to_lua_error)?;
+            Ok(LuaArchive { reader })
+        })?,
+    )?;
+    Ok(module)
+}
+
+/// Installs the `pfa` table as a global
+pub fn register(lua: &Lua) -> mlua::Result<()> {
+    lua.globals().set("pfa", module(lua)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::Lua;
+
+    use crate::{builder::PfaBuilder, shared::DataFlags};
+
+    #[test]
+    fn lua_bindings() {
+        let mut builder = PfaBuilder::new("scripts");
+        builder
+            .add_file("/mods/init.lua", b"return 1".to_vec(), DataFlags::auto())
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("pfa_lua_{}.pfa", crate::shared::Ulid::new()));
+        std::fs::write(&path, builder.build().unwrap()).unwrap();
+
+        let lua = Lua::new();
+        super::register(&lua).unwrap();
+        lua.globals()
+            .set("archive_path", path.to_str().unwrap())
+            .unwrap();
+        let result: (String, String, bool, bool, bool, bool) = lua
+            .load(
+                r#"
+                local archive = pfa.open(archive_path)
+                local listing = archive:list("/mods/")
+                return archive:name(), archive:read("/mods/init.lua"), listing[1] == "/mods/init.lua",
+                    archive:exists("/mods/init.lua"), archive:exists("/missing.lua"),
+                    archive:read("/missing.lua") == nil
+                "#,
+            )
+            .eval()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            result,
+            (
+                "scripts".to_string(),
+                "return 1".to_string(),
+                true,
+                true,
+                false,
+                true
+            )
+        );
+    }
+}
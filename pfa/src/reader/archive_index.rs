@@ -0,0 +1,372 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufReader, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    reader::{scrub::find_archives, PfaReader},
+    shared::{entry_hash, header_fields::HeaderFields, Cipher, DataFlags, EntryHash, Ulid},
+    PfaError,
+};
+
+const MAGIC: &[u8; 8] = b"pfaindx\0";
+const VERSION: u8 = 1;
+
+/// An archive covered by an [`ArchiveIndex`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct IndexedArchive {
+    pub path: PathBuf,
+    pub name: String,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::shared::serde_fields::option_display")
+    )]
+    pub archive_id: Option<Ulid>,
+    /// Why the archive couldn't be indexed, none of its files are then in the index
+    pub error: Option<String>,
+}
+
+/// A file of an indexed archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct IndexedFile {
+    /// Index of the archive in [`ArchiveIndex::archives`]
+    pub archive: usize,
+    /// Size of the stored data
+    pub size: u64,
+    pub flags: u8,
+    /// Hash of the entry, see [`entry_hash`]
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::shared::serde_fields::hex")
+    )]
+    pub entry_hash: EntryHash,
+    /// BLAKE3 hash of the contents, as recorded by the archive where it was (see
+    /// [`ArchiveIndex::add_archive`]), otherwise from decoding the file. None for files which
+    /// can't be decoded without a key.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::shared::serde_fields::option_hex")
+    )]
+    pub contents_hash: Option<[u8; 32]>,
+}
+
+/// Which archives contain which files, across many archives such as every shipped release of a
+/// game. Built from a directory of archives with [`Self::build`] and stored as:
+///
+/// ```text
+/// {magic:u8[8]="pfaindx\0"}{version:u8=1}
+/// {num_archives:u64}{archives:{has_archive_id:u8}{archive_id:u8[16]?}{has_error:u8}
+///     {error:string?}{name:string}{path:string}[num_archives]}
+/// {num_files:u64}{files:{path:string}{archive:u64}{size:u64}{flags:u8}{entry_hash:u8[32]}
+///     {has_contents_hash:u8}{contents_hash:u8[32]?}[num_files]}
+/// ```
+///
+/// where strings are stored as {len:u32}{utf8:u8\[len\]}, and integers are little endian.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveIndex {
+    archives: Vec<IndexedArchive>,
+    files: BTreeMap<String, Vec<IndexedFile>>,
+}
+
+impl ArchiveIndex {
+    /// Indexes every `.pfa` archive in a directory and its subdirectories. Archives which can't
+    /// be read are recorded with their error instead of failing the whole index.
+    pub fn build(dir: impl AsRef<Path>) -> Result<Self, PfaError> {
+        let mut index = Self::default();
+        for path in find_archives(dir.as_ref())? {
+            let added = PfaReader::open(&path).and_then(|mut x| index.add_archive(&path, &mut x));
+            if let Err(e) = added {
+                index.archives.push(IndexedArchive {
+                    path,
+                    name: String::new(),
+                    archive_id: None,
+                    error: Some(e.to_string()),
+                });
+            }
+        }
+        Ok(index)
+    }
+
+    /// Adds every file of the archive read by `reader`, found at `path`. The entry hashes and
+    /// contents hashes recorded by the archive are used where there are any, a contents hash
+    /// being the output hash of a file's [transformations](PfaReader::get_transforms), or the
+    /// source hash of its [provenance](PfaReader::get_provenance) when it has none. Other files
+    /// are read to hash them, and decoded to hash their contents, except for encrypted ones.
+    pub fn add_archive<T: Read + Seek>(
+        &mut self,
+        path: impl Into<PathBuf>,
+        reader: &mut PfaReader<T>,
+    ) -> Result<(), PfaError> {
+        let archive = self.archives.len();
+        let hashes = reader.get_header_fields().entry_hashes.clone();
+        let mut files = vec![];
+        for slice in reader.collect_file_slices("/")? {
+            let name = slice.path.get_name().map_or("", |x| &x[..]);
+            let hash = hashes.as_ref().and_then(|x| x.get(slice.index)).copied();
+            let recorded = Self::recorded_contents_hash(reader.get_header_fields(), slice.index);
+            let (entry_hash, contents_hash) = match reader.solid_member(&slice) {
+                Some((member, block)) => {
+                    let entry_hash = match hash {
+                        Some(hash) => hash,
+                        None => {
+                            let stored =
+                                reader.read_solid_block(&slice.path.to_string(), &block)?;
+                            let digest = member.digest(blake3::hash(&stored).as_bytes());
+                            entry_hash::file_hash_of_digest(name, slice.flags, &digest)
+                        }
+                    };
+                    let contents_hash = match recorded {
+                        Some(hash) => Some(hash),
+                        None if DataFlags::is_encrypted(block.flags) => None,
+                        None => reader
+                            .read_solid_member(&slice.path.to_string(), member, block)
+                            .ok()
+                            .map(|x| *blake3::hash(&x).as_bytes()),
+                    };
+                    (entry_hash, contents_hash)
+                }
+                None => {
+                    let decodable = !DataFlags::is_encrypted(slice.flags)
+                        && DataFlags::check_codec(slice.flags, &slice.path.to_string()).is_ok();
+                    match (hash, recorded) {
+                        (Some(hash), recorded) if recorded.is_some() || !decodable => {
+                            (hash, recorded)
+                        }
+                        _ => {
+                            let mut contents = reader.read_slice(&slice)?;
+                            let entry_hash = hash.unwrap_or_else(|| {
+                                entry_hash::file_hash(name, slice.flags, &contents)
+                            });
+                            let decoded = recorded.is_none()
+                                && decodable
+                                && DataFlags::unprocess_contents_from_flags(
+                                    slice.flags,
+                                    &mut contents,
+                                    None,
+                                    Cipher::default(),
+                                    reader.max_decompressed_size(),
+                                    reader.codecs(),
+                                )
+                                .is_ok();
+                            let contents_hash =
+                                recorded.or(decoded.then(|| *blake3::hash(&contents).as_bytes()));
+                            (entry_hash, contents_hash)
+                        }
+                    }
+                }
+            };
+            let file = IndexedFile {
+                archive,
+                size: slice.size,
+                flags: slice.flags,
+                entry_hash,
+                contents_hash,
+            };
+            files.push((slice.path.to_string(), file));
+        }
+
+        // nothing is added if reading any of the files failed
+        self.archives.push(IndexedArchive {
+            path: path.into(),
+            name: reader.get_name().to_string(),
+            archive_id: reader.get_archive_id(),
+            error: None,
+        });
+        for (path, file) in files {
+            self.files.entry(path).or_default().push(file);
+        }
+        Ok(())
+    }
+
+    fn recorded_contents_hash(fields: &HeaderFields, index: usize) -> Option<[u8; 32]> {
+        match fields.entry_transforms(index) {
+            Some(transforms) => transforms.output_hash,
+            None => fields.entry_provenance(index).and_then(|x| x.source_hash),
+        }
+    }
+
+    pub fn archives(&self) -> &[IndexedArchive] {
+        &self.archives
+    }
+
+    /// Number of distinct file paths
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Every archive containing a file at `path`, in the order they were indexed
+    pub fn find(&self, path: &str) -> &[IndexedFile] {
+        self.files.get(path).map_or(&[], |x| &x[..])
+    }
+
+    /// Every indexed file whose path matches `pattern`, a glob like those of
+    /// [`PfaReader::glob`], sorted by path
+    pub fn find_matching(&self, pattern: &str) -> Result<Vec<(&str, &IndexedFile)>, PfaError> {
+        let matcher = globset::GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| PfaError::InvalidGlobError(format!("'{pattern}': {}", e.kind())))?
+            .compile_matcher();
+        Ok(self
+            .iter()
+            .filter(|(path, _)| matcher.is_match(path))
+            .collect())
+    }
+
+    /// Every indexed file with the same contents as `contents`, wherever it is
+    pub fn find_contents(&self, contents: &[u8]) -> Vec<(&str, &IndexedFile)> {
+        let hash = *blake3::hash(contents).as_bytes();
+        self.iter()
+            .filter(|(_, file)| file.contents_hash == Some(hash))
+            .collect()
+    }
+
+    /// Every indexed file, sorted by path
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &IndexedFile)> {
+        self.files
+            .iter()
+            .flat_map(|(path, files)| files.iter().map(move |x| (&path[..], x)))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PfaError> {
+        Self::read(std::fs::File::open(path)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PfaError> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    pub fn read(input: impl Read) -> Result<Self, PfaError> {
+        let mut input = BufReader::new(input);
+        Self::read_from(&mut input)
+            .map_err(|_| PfaError::CustomError("invalid archive index".into()))
+    }
+
+    fn read_from(input: &mut impl Read) -> Result<Self, PfaError> {
+        let invalid = || PfaError::CustomError("invalid archive index".into());
+        let mut magic = [0; MAGIC.len()];
+        input.read_exact(&mut magic)?;
+        if magic != *MAGIC || input.read_u8()? != VERSION {
+            return Err(invalid());
+        }
+
+        let mut index = Self::default();
+        let num_archives = input.read_u64::<LittleEndian>()?;
+        for _ in 0..num_archives {
+            let archive_id = match input.read_u8()? {
+                0 => None,
+                _ => Some(Ulid::from(input.read_u128::<LittleEndian>()?)),
+            };
+            let error = match input.read_u8()? {
+                0 => None,
+                _ => Some(read_string(input)?),
+            };
+            let name = read_string(input)?;
+            let path = PathBuf::from(read_string(input)?);
+            index.archives.push(IndexedArchive {
+                path,
+                name,
+                archive_id,
+                error,
+            });
+        }
+        let num_files = input.read_u64::<LittleEndian>()?;
+        for _ in 0..num_files {
+            let path = read_string(input)?;
+            let archive = usize::try_from(input.read_u64::<LittleEndian>()?)
+                .ok()
+                .filter(|x| *x < index.archives.len())
+                .ok_or_else(invalid)?;
+            let size = input.read_u64::<LittleEndian>()?;
+            let flags = input.read_u8()?;
+            let mut entry_hash = EntryHash::default();
+            input.read_exact(&mut entry_hash)?;
+            let contents_hash = match input.read_u8()? {
+                0 => None,
+                _ => {
+                    let mut hash = [0; 32];
+                    input.read_exact(&mut hash)?;
+                    Some(hash)
+                }
+            };
+            index.files.entry(path).or_default().push(IndexedFile {
+                archive,
+                size,
+                flags,
+                entry_hash,
+                contents_hash,
+            });
+        }
+        Ok(index)
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> Result<(), PfaError> {
+        out.write_all(MAGIC)?;
+        out.write_u8(VERSION)?;
+        out.write_u64::<LittleEndian>(self.archives.len() as u64)?;
+        for archive in &self.archives {
+            match archive.archive_id {
+                Some(id) => {
+                    out.write_u8(1)?;
+                    out.write_u128::<LittleEndian>(id.into())?;
+                }
+                None => out.write_u8(0)?,
+            }
+            match &archive.error {
+                Some(error) => {
+                    out.write_u8(1)?;
+                    write_string(out, error)?;
+                }
+                None => out.write_u8(0)?,
+            }
+            write_string(out, &archive.name)?;
+            write_string(out, &archive.path.to_string_lossy())?;
+        }
+        out.write_u64::<LittleEndian>(self.iter().count() as u64)?;
+        for (path, file) in self.iter() {
+            write_string(out, path)?;
+            out.write_u64::<LittleEndian>(file.archive as u64)?;
+            out.write_u64::<LittleEndian>(file.size)?;
+            out.write_u8(file.flags)?;
+            out.write_all(&file.entry_hash)?;
+            match file.contents_hash {
+                Some(hash) => {
+                    out.write_u8(1)?;
+                    out.write_all(&hash)?;
+                }
+                None => out.write_u8(0)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_string(out: &mut impl Write, value: &str) -> Result<(), PfaError> {
+    let len =
+        u32::try_from(value.len()).map_err(|_| PfaError::WriterOverflowError("index string"))?;
+    out.write_u32::<LittleEndian>(len)?;
+    out.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+fn read_string(input: &mut impl Read) -> Result<String, PfaError> {
+    let len = input.read_u32::<LittleEndian>()?;
+    let mut value = vec![];
+    input.take(len.into()).read_to_end(&mut value)?;
+    if value.len() != len as usize {
+        return Err(PfaError::CustomError("invalid archive index".into()));
+    }
+    String::from_utf8(value).map_err(|_| PfaError::CustomError("invalid archive index".into()))
+}
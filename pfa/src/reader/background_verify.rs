@@ -0,0 +1,134 @@
+use std::{
+    io::{Read, Seek},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+use crate::{
+    reader::{
+        scrub::{ArchiveHealth, ScrubIssue, Throttle},
+        PfaReader,
+    },
+    PfaError,
+};
+
+/// How [`PfaReader::start_background_verify`] runs
+#[derive(Debug, Clone)]
+pub struct BackgroundVerifyOptions {
+    bytes_per_second: Option<u64>,
+    thread_name: String,
+}
+
+impl Default for BackgroundVerifyOptions {
+    fn default() -> Self {
+        Self {
+            bytes_per_second: Some(Self::DEFAULT_RATE_LIMIT),
+            thread_name: "pfa-verify".into(),
+        }
+    }
+}
+
+impl BackgroundVerifyOptions {
+    /// Rate data is read at unless set, low enough to leave most of the IO of a slow disc or
+    /// hard drive to the application
+    pub const DEFAULT_RATE_LIMIT: u64 = 8 * 1024 * 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maximum rate to read the archive at, None to read as fast as possible
+    pub fn rate_limit(mut self, bytes_per_second: Option<u64>) -> Self {
+        self.bytes_per_second = bytes_per_second;
+        self
+    }
+
+    /// Name of the verifying thread, as shown by debuggers and profilers
+    pub fn thread_name(mut self, name: &str) -> Self {
+        self.thread_name = name.to_string();
+        self
+    }
+}
+
+/// Verification running on its own thread, started with
+/// [`PfaReader::start_background_verify`]. Dropping it stops the verification after the file
+/// being checked, without waiting for it.
+pub struct BackgroundVerify {
+    issues: Receiver<ScrubIssue>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<ArchiveHealth, PfaError>>>,
+}
+
+impl BackgroundVerify {
+    /// Issues as they're found, the channel closes once verification ends
+    pub fn issues(&self) -> &Receiver<ScrubIssue> {
+        &self.issues
+    }
+
+    /// Asks the verification to stop after the file being checked
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(|x| x.is_finished())
+    }
+
+    /// Waits for the verification to end, returning everything it found, which only covers the
+    /// files checked before it was [stopped](Self::stop). Fails if the archive couldn't be opened
+    /// or read.
+    pub fn join(mut self) -> Result<ArchiveHealth, PfaError> {
+        self.thread
+            .take()
+            .expect("thread is only taken when joining")
+            .join()
+            .map_err(|_| PfaError::CustomError("background verification panicked".into()))?
+    }
+}
+
+impl Drop for BackgroundVerify {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl<T: Read + Seek> PfaReader<T> {
+    /// Starts checking the stored data of every file against its entry hash and error
+    /// correction blocks on another thread, like [`scrub`](Self::scrub), reporting issues through
+    /// [`BackgroundVerify::issues`] as they're found. Lets applications notice damaged storage
+    /// early without delaying startup. `input` is a second handle to the same archive, such as
+    /// the file opened again, which is opened with this reader's options, so the application
+    /// keeps using this reader meanwhile. The thread reads at a limited rate, see
+    /// [`BackgroundVerifyOptions::rate_limit`].
+    pub fn start_background_verify<R: Read + Seek + Send + 'static>(
+        &self,
+        input: R,
+        options: BackgroundVerifyOptions,
+    ) -> Result<BackgroundVerify, PfaError> {
+        let reader_options = self.get_options().clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let (issues_tx, issues) = mpsc::channel();
+        let thread = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name(options.thread_name)
+                .spawn(move || {
+                    let mut reader = PfaReader::new_with_options(input, reader_options)?;
+                    let mut throttle = Throttle::new(options.bytes_per_second).until(stop.clone());
+                    reader.scrub_throttled(&mut throttle, &stop, |issue| {
+                        // the issues are still returned when joining if nobody listens
+                        let _ = issues_tx.send(issue.clone());
+                    })
+                })?
+        };
+        Ok(BackgroundVerify {
+            issues,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
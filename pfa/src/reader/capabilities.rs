@@ -0,0 +1,44 @@
+use crate::shared::{DataFlags, StoredCodec, LATEST_VERSION};
+
+/// What this build can read, and what an archive needs which it can't, see
+/// [`PfaReader::capabilities`](crate::reader::PfaReader::capabilities)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Capabilities {
+    /// Newest format version which can be read
+    pub max_version: u8,
+    /// Flag bits which are understood, see [`DataFlags::KNOWN_BITS`]
+    pub flag_bits: u8,
+    /// Compression codecs which can be decoded, built in ones followed by the names of the
+    /// reader's custom codecs
+    pub codecs: Vec<String>,
+    /// Codecs used by the archive which can't be decoded, such as `zstd` or `custom codec 200`.
+    /// Files using them fail to read.
+    pub missing_codecs: Vec<String>,
+    /// Flag bits used by the archive which aren't understood, see
+    /// [`PfaReader::get_unknown_flag_bits`](crate::reader::PfaReader::get_unknown_flag_bits)
+    pub unknown_flag_bits: u8,
+}
+
+impl Capabilities {
+    /// Capabilities of this build without any custom codecs, and without anything missing
+    pub fn supported() -> Self {
+        Self {
+            max_version: LATEST_VERSION,
+            flag_bits: DataFlags::KNOWN_BITS,
+            codecs: DataFlags::CODECS
+                .iter()
+                .map(|(_, codec)| *codec)
+                .filter(|x| *x != StoredCodec::Custom && x.is_supported())
+                .map(|x| x.to_string())
+                .collect(),
+            missing_codecs: vec![],
+            unknown_flag_bits: 0,
+        }
+    }
+
+    /// Whether every file of the archive can be decoded
+    pub fn can_read_all(&self) -> bool {
+        self.missing_codecs.is_empty() && self.unknown_flag_bits == 0
+    }
+}
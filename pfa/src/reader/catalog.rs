@@ -0,0 +1,556 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::HashMap,
+    io::{Cursor, Read, Write},
+    path::Path,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    reader::{
+        catalog_cache,
+        name_table::{NameId, NameInterner, NameTable},
+        pfa_reader::{child_range, PfaPath},
+        ReaderWarning,
+    },
+    shared::{CatalogLayout, DataFlags},
+    PfaError,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum PfaSlice {
+    Data { flags: u8, offset: u64, size: u64 },
+    Catalog { flags: u8, offset: u64, size: u64 },
+}
+
+impl PfaSlice {
+    /// Flag bits this version doesn't understand, catalog slices don't support any flags
+    pub(crate) fn unknown_bits(&self) -> u8 {
+        match *self {
+            PfaSlice::Data { flags, .. } => DataFlags::unknown_bits(flags),
+            PfaSlice::Catalog { flags, .. } => flags,
+        }
+    }
+
+    /// Whether the slice holds a file's data when `is_file` is set, or a directory's entries
+    /// otherwise
+    pub(crate) fn is_kind(&self, is_file: bool) -> bool {
+        matches!(
+            (self, is_file),
+            (PfaSlice::Data { .. }, true) | (PfaSlice::Catalog { .. }, false)
+        )
+    }
+}
+
+#[derive(Debug)]
+struct PfaEntry {
+    name: NameId,
+    slice: PfaSlice,
+}
+
+/// Catalog with every entry decoded
+#[derive(Debug)]
+pub(crate) struct DecodedCatalog {
+    names: NameTable,
+    entries: Vec<PfaEntry>,
+    /// Entry index by the catalog entry (directory or bucket) it's in, its name, and whether it's
+    /// a file, so lookups don't scan directories
+    children: HashMap<(usize, NameId, bool), usize>,
+}
+
+/// Children of a directory by name and whether the entry is a file
+type DirectoryIndex = HashMap<(String, bool), usize>;
+
+/// Catalog kept as it is stored, entries are decoded when they are used
+#[derive(Debug)]
+pub(crate) struct LazyCatalog {
+    layout: CatalogLayout,
+    strict: bool,
+    bytes: Vec<u8>,
+    /// Start of every entry in `bytes`, only kept for the compact layout since its entries vary
+    /// in size
+    offsets: Vec<usize>,
+    /// Contents of the directories and buckets looked into so far
+    children: RefCell<HashMap<usize, DirectoryIndex>>,
+}
+
+/// Entries of an archive's catalog, either decoded when the archive is opened or as they are
+/// needed, see [`PfaReaderOptions::lazy_catalog`](crate::reader::PfaReaderOptions::lazy_catalog)
+#[derive(Debug)]
+pub(crate) enum PfaCatalog {
+    Decoded(DecodedCatalog),
+    Lazy(LazyCatalog),
+}
+
+impl PfaCatalog {
+    /// Reads and decodes every entry, adding a warning for every name which needed lossy decoding
+    pub(crate) fn read(
+        buf: &mut impl Read,
+        layout: CatalogLayout,
+        strict: bool,
+        warnings: &mut Vec<ReaderWarning>,
+    ) -> Result<Self, PfaError> {
+        let num_entries = buf.read_u64::<LittleEndian>()?;
+        Ok(Self::Decoded(DecodedCatalog::read(
+            buf,
+            num_entries,
+            layout,
+            strict,
+            warnings,
+        )?))
+    }
+
+    /// Reads the entries without decoding them
+    pub(crate) fn read_lazy(
+        buf: &mut impl Read,
+        layout: CatalogLayout,
+        strict: bool,
+    ) -> Result<Self, PfaError> {
+        Ok(Self::Lazy(LazyCatalog::read(buf, layout, strict)?))
+    }
+
+    /// Like [`read`](Self::read), but loads the decoded catalog from the cache at `cache` when
+    /// it was written for the same catalog, and otherwise writes it there once decoded. Returns
+    /// whether the cache was used. Failing to write the cache isn't an error, the archive is just
+    /// decoded again the next time it is opened.
+    pub(crate) fn read_cached(
+        buf: &mut impl Read,
+        layout: CatalogLayout,
+        strict: bool,
+        cache: &Path,
+        warnings: &mut Vec<ReaderWarning>,
+    ) -> Result<(Self, bool), PfaError> {
+        let lazy = LazyCatalog::read(buf, layout, strict)?;
+        let digest = lazy.digest();
+        if let Some((catalog, lossy)) = catalog_cache::load(cache, &digest) {
+            warnings.extend(lossy.into_iter().map(|index| ReaderWarning::LossyName {
+                index,
+                name: catalog.names.get(catalog.entries[index].name).to_string(),
+            }));
+            return Ok((Self::Decoded(catalog), true));
+        }
+
+        let start = warnings.len();
+        let catalog = DecodedCatalog::read(
+            &mut Cursor::new(&lazy.bytes),
+            lazy.len() as u64,
+            layout,
+            strict,
+            warnings,
+        )?;
+        let lossy = warnings[start..]
+            .iter()
+            .filter_map(|x| match x {
+                ReaderWarning::LossyName { index, .. } => Some(*index),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let _ = catalog_cache::store(cache, &digest, &catalog, &lossy);
+        Ok((Self::Decoded(catalog), false))
+    }
+
+    /// Decodes every entry of a lazy catalog
+    pub(crate) fn preload(&mut self, warnings: &mut Vec<ReaderWarning>) -> Result<(), PfaError> {
+        let Self::Lazy(lazy) = self else {
+            return Ok(());
+        };
+        let num_entries = lazy.len() as u64;
+        let decoded = DecodedCatalog::read(
+            &mut Cursor::new(&lazy.bytes),
+            num_entries,
+            lazy.layout,
+            lazy.strict,
+            warnings,
+        )?;
+        *self = Self::Decoded(decoded);
+        Ok(())
+    }
+
+    pub(crate) fn is_lazy(&self) -> bool {
+        matches!(self, Self::Lazy(_))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Self::Decoded(catalog) => catalog.entries.len(),
+            Self::Lazy(catalog) => catalog.len(),
+        }
+    }
+
+    pub(crate) fn slice(&self, index: usize) -> Result<PfaSlice, PfaError> {
+        match self {
+            Self::Decoded(catalog) => catalog
+                .entries
+                .get(index)
+                .map(|x| x.slice)
+                .ok_or_else(out_of_bounds),
+            Self::Lazy(catalog) => Ok(catalog.entry(index)?.1),
+        }
+    }
+
+    /// Name of the entry as it is stored, without the `/` ending directory names
+    pub(crate) fn name(&self, index: usize) -> Result<Cow<'_, str>, PfaError> {
+        match self {
+            Self::Decoded(catalog) => catalog
+                .entries
+                .get(index)
+                .map(|x| Cow::Borrowed(catalog.names.get(x.name)))
+                .ok_or_else(out_of_bounds),
+            Self::Lazy(catalog) => Ok(catalog.entry(index)?.0),
+        }
+    }
+
+    pub(crate) fn child_path(
+        &self,
+        parent: &PfaPath,
+        index: usize,
+    ) -> Result<Option<PfaPath>, PfaError> {
+        let name = self.name(index)?;
+        Ok(match self.slice(index)? {
+            PfaSlice::Data { .. } => parent.append(&name[..]),
+            PfaSlice::Catalog { .. } => parent.append(&format!("{name}/")[..]),
+        })
+    }
+
+    /// Finds the entry stored as `name` among the entries of the directory or bucket at `dir`,
+    /// which must be within the catalog
+    pub(crate) fn find_child(
+        &self,
+        dir: usize,
+        name: &str,
+        is_file: bool,
+    ) -> Result<Option<usize>, PfaError> {
+        match self {
+            Self::Decoded(catalog) => Ok(catalog
+                .names
+                .find(name)
+                .and_then(|id| catalog.children.get(&(dir, id, is_file)))
+                .copied()),
+            Self::Lazy(catalog) => catalog.find_child(dir, name, is_file),
+        }
+    }
+
+    /// Fails on the first entry using flag bits this version doesn't understand
+    pub(crate) fn check_unknown_bits(&self) -> Result<(), PfaError> {
+        for index in 0..self.len() {
+            let bits = self.slice(index)?.unknown_bits();
+            if bits != 0 {
+                return Err(unknown_bits_error(&self.name(index)?, bits));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DecodedCatalog {
+    fn read(
+        buf: &mut impl Read,
+        num_entries: u64,
+        layout: CatalogLayout,
+        strict: bool,
+        warnings: &mut Vec<ReaderWarning>,
+    ) -> Result<Self, PfaError> {
+        let mut entries = Vec::with_capacity(num_entries as usize);
+        let mut names = NameInterner::default();
+        let mut name_buf = vec![];
+        let mut slice_buf = [0; CatalogLayout::SLICE_SIZE];
+        for index in 0..num_entries as usize {
+            let name_len = match layout {
+                CatalogLayout::Fixed => CatalogLayout::FIXED_NAME_SIZE,
+                CatalogLayout::Compact => buf.read_u8()?.into(),
+            };
+            name_buf.resize(name_len, 0);
+            buf.read_exact(&mut name_buf)?;
+            buf.read_exact(&mut slice_buf)?;
+            let (name, slice) = decode_entry(&name_buf, &slice_buf, strict)?;
+            if let Cow::Owned(name) = &name {
+                warnings.push(ReaderWarning::LossyName {
+                    index,
+                    name: name.clone(),
+                });
+            }
+            entries.push(PfaEntry {
+                name: names.intern(&name)?,
+                slice,
+            });
+        }
+
+        let mut catalog = Self {
+            names: names.finish(),
+            entries,
+            children: HashMap::new(),
+        };
+        catalog.index_children();
+        Ok(catalog)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Writes the entries and index so [`read_from`](Self::read_from) can load them without
+    /// decoding the catalog again
+    pub(crate) fn write_to(&self, out: &mut impl Write) -> Result<(), PfaError> {
+        self.names.write_to(out)?;
+        out.write_u64::<LittleEndian>(self.entries.len() as u64)?;
+        for entry in &self.entries {
+            let (kind, flags, offset, size) = match entry.slice {
+                PfaSlice::Data {
+                    flags,
+                    offset,
+                    size,
+                } => (0, flags, offset, size),
+                PfaSlice::Catalog {
+                    flags,
+                    offset,
+                    size,
+                } => (1, flags, offset, size),
+            };
+            out.write_u32::<LittleEndian>(entry.name.to_u32())?;
+            out.write_u8(kind)?;
+            out.write_u8(flags)?;
+            out.write_u64::<LittleEndian>(offset)?;
+            out.write_u64::<LittleEndian>(size)?;
+        }
+        out.write_u64::<LittleEndian>(self.children.len() as u64)?;
+        for ((dir, name, is_file), child) in &self.children {
+            out.write_u64::<LittleEndian>(*dir as u64)?;
+            out.write_u32::<LittleEndian>(name.to_u32())?;
+            out.write_u8(u8::from(*is_file))?;
+            out.write_u64::<LittleEndian>(*child as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Reads entries written by [`write_to`](Self::write_to), failing if any name or index is
+    /// out of bounds
+    pub(crate) fn read_from(buf: &mut impl Read) -> Result<Self, PfaError> {
+        let invalid = || PfaError::CustomError("invalid cached catalog".into());
+        let names = NameTable::read_from(buf)?;
+        let num_entries = buf.read_u64::<LittleEndian>()?;
+        let mut entries = vec![];
+        for _ in 0..num_entries {
+            let name = names
+                .id(buf.read_u32::<LittleEndian>()?)
+                .ok_or_else(invalid)?;
+            let kind = buf.read_u8()?;
+            let flags = buf.read_u8()?;
+            let offset = buf.read_u64::<LittleEndian>()?;
+            let size = buf.read_u64::<LittleEndian>()?;
+            let slice = match kind {
+                0 => PfaSlice::Data {
+                    flags,
+                    offset,
+                    size,
+                },
+                1 => PfaSlice::Catalog {
+                    flags,
+                    offset,
+                    size,
+                },
+                _ => return Err(invalid()),
+            };
+            entries.push(PfaEntry { name, slice });
+        }
+
+        let num_children = buf.read_u64::<LittleEndian>()?;
+        let mut children = HashMap::new();
+        let in_bounds = |index: u64| (index < entries.len() as u64).then_some(index as usize);
+        for _ in 0..num_children {
+            let dir = in_bounds(buf.read_u64::<LittleEndian>()?).ok_or_else(invalid)?;
+            let name = names
+                .id(buf.read_u32::<LittleEndian>()?)
+                .ok_or_else(invalid)?;
+            let is_file = buf.read_u8()? != 0;
+            let child = in_bounds(buf.read_u64::<LittleEndian>()?).ok_or_else(invalid)?;
+            children.insert((dir, name, is_file), child);
+        }
+        Ok(Self {
+            names,
+            entries,
+            children,
+        })
+    }
+
+    /// Indexes the contents of every directory and bucket. Only the first of duplicate entries is
+    /// kept, and slices out of bounds are left out, since looking into them fails anyway.
+    fn index_children(&mut self) {
+        for (index, entry) in self.entries.iter().enumerate() {
+            let PfaSlice::Catalog { offset, size, .. } = entry.slice else {
+                continue;
+            };
+            let Some(children) = child_range(index, offset, size)
+                .ok()
+                .filter(|x| x.end <= self.entries.len())
+            else {
+                continue;
+            };
+            for child in children {
+                let is_file = matches!(self.entries[child].slice, PfaSlice::Data { .. });
+                self.children
+                    .entry((index, self.entries[child].name, is_file))
+                    .or_insert(child);
+            }
+        }
+    }
+}
+
+impl LazyCatalog {
+    fn read(buf: &mut impl Read, layout: CatalogLayout, strict: bool) -> Result<Self, PfaError> {
+        let num_entries = buf.read_u64::<LittleEndian>()?;
+        let mut bytes = vec![];
+        let mut offsets = vec![];
+        match layout {
+            CatalogLayout::Fixed => {
+                let size = num_entries
+                    .checked_mul(layout.entry_size("") as u64)
+                    .ok_or(PfaError::CustomError("catalog is too large".into()))?;
+                buf.take(size).read_to_end(&mut bytes)?;
+                if bytes.len() as u64 != size {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+            CatalogLayout::Compact => {
+                for _ in 0..num_entries {
+                    offsets.push(bytes.len());
+                    let name_len = buf.read_u8()?;
+                    bytes.push(name_len);
+                    let start = bytes.len();
+                    bytes.resize(start + usize::from(name_len) + CatalogLayout::SLICE_SIZE, 0);
+                    buf.read_exact(&mut bytes[start..])?;
+                }
+            }
+        }
+        Ok(Self {
+            layout,
+            strict,
+            bytes,
+            offsets,
+            children: RefCell::new(HashMap::new()),
+        })
+    }
+
+    /// Identifies the stored catalog together with how it is decoded, since strict readers
+    /// decode names differently
+    fn digest(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(self.layout.to_string().as_bytes());
+        hasher.update(&[u8::from(self.strict)]);
+        hasher.update(&(self.len() as u64).to_le_bytes());
+        hasher.update(&self.bytes);
+        *hasher.finalize().as_bytes()
+    }
+
+    fn len(&self) -> usize {
+        match self.layout {
+            CatalogLayout::Fixed => self.bytes.len() / self.layout.entry_size(""),
+            CatalogLayout::Compact => self.offsets.len(),
+        }
+    }
+
+    /// Decodes the name (without the `/` ending directory names) and slice of an entry. Strict
+    /// catalogs fail on names which aren't valid UTF-8 and unknown flag bits, like they do when
+    /// the whole catalog is decoded.
+    fn entry(&self, index: usize) -> Result<(Cow<'_, str>, PfaSlice), PfaError> {
+        let (name, slice) = match self.layout {
+            CatalogLayout::Fixed => {
+                let size = self.layout.entry_size("");
+                let start = index.checked_mul(size).ok_or_else(out_of_bounds)?;
+                self.bytes
+                    .get(start..start + size)
+                    .ok_or_else(out_of_bounds)?
+                    .split_at(CatalogLayout::FIXED_NAME_SIZE)
+            }
+            CatalogLayout::Compact => {
+                let start = *self.offsets.get(index).ok_or_else(out_of_bounds)?;
+                let name_len = usize::from(self.bytes[start]);
+                self.bytes[start + 1..start + 1 + name_len + CatalogLayout::SLICE_SIZE]
+                    .split_at(name_len)
+            }
+        };
+        let (name, slice) = decode_entry(name, slice, self.strict)?;
+        if self.strict && slice.unknown_bits() != 0 {
+            return Err(unknown_bits_error(&name, slice.unknown_bits()));
+        }
+        Ok((name, slice))
+    }
+
+    fn find_child(&self, dir: usize, name: &str, is_file: bool) -> Result<Option<usize>, PfaError> {
+        let key = (name.to_string(), is_file);
+        if let Some(children) = self.children.borrow().get(&dir) {
+            return Ok(children.get(&key).copied());
+        }
+
+        let PfaSlice::Catalog { offset, size, .. } = self.entry(dir)?.1 else {
+            return Ok(None);
+        };
+        let mut children = HashMap::new();
+        for child in child_range(dir, offset, size)? {
+            let (name, slice) = self.entry(child)?;
+            let is_file = matches!(slice, PfaSlice::Data { .. });
+            children
+                .entry((name.into_owned(), is_file))
+                .or_insert(child);
+        }
+        let found = children.get(&key).copied();
+        self.children.borrow_mut().insert(dir, children);
+        Ok(found)
+    }
+}
+
+/// Decodes an entry's name, without the `/` ending directory names, and slice. Names which
+/// aren't valid UTF-8 are decoded lossily, making them owned, unless `strict` is set.
+fn decode_entry<'a>(
+    name: &'a [u8],
+    mut slice: &[u8],
+    strict: bool,
+) -> Result<(Cow<'a, str>, PfaSlice), PfaError> {
+    let name = &name[..name.iter().position(|x| *x == 0).unwrap_or(name.len())];
+    let mut name = match std::str::from_utf8(name) {
+        Ok(name) => Cow::Borrowed(name),
+        Err(e) if strict => {
+            return Err(PfaError::CustomError(format!(
+                "invalid utf8 entry name: {e}"
+            )))
+        }
+        Err(_) => String::from_utf8_lossy(name),
+    };
+
+    let flags = slice.read_u8()?;
+    let size = slice.read_u64::<LittleEndian>()?;
+    let offset = slice.read_u64::<LittleEndian>()?;
+    let is_directory = name.ends_with('/');
+    if is_directory {
+        match &mut name {
+            Cow::Borrowed(x) => *x = &x[..x.len() - 1],
+            Cow::Owned(x) => {
+                x.pop();
+            }
+        }
+    }
+    let slice = match is_directory {
+        true => PfaSlice::Catalog {
+            flags,
+            offset,
+            size,
+        },
+        false => PfaSlice::Data {
+            flags,
+            offset,
+            size,
+        },
+    };
+    Ok((name, slice))
+}
+
+fn unknown_bits_error(name: &str, bits: u8) -> PfaError {
+    PfaError::UnsupportedFeature(format!(
+        "entry '{name}' uses unknown flag bits {bits:#010b}"
+    ))
+}
+
+fn out_of_bounds() -> PfaError {
+    PfaError::CustomError("catalog slice out of bounds".into())
+}
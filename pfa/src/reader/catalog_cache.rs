@@ -0,0 +1,66 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{reader::catalog::DecodedCatalog, PfaError};
+
+const MAGIC: &[u8; 8] = b"pfacatc\0";
+const VERSION: u8 = 1;
+
+/// Loads the catalog cached at `path`, and the indices of entries whose names were decoded
+/// lossily, if it was written for the catalog with `digest`. Missing, outdated and damaged caches
+/// are all ignored, since the catalog can be decoded from the archive instead.
+pub(crate) fn load(path: &Path, digest: &[u8; 32]) -> Option<(DecodedCatalog, Vec<usize>)> {
+    let mut buf = BufReader::new(File::open(path).ok()?);
+    let mut header = [0; MAGIC.len() + 1 + 32];
+    buf.read_exact(&mut header).ok()?;
+    if header[..MAGIC.len()] != MAGIC[..]
+        || header[MAGIC.len()] != VERSION
+        || header[MAGIC.len() + 1..] != digest[..]
+    {
+        return None;
+    }
+    read(&mut buf).ok()
+}
+
+fn read(buf: &mut impl Read) -> Result<(DecodedCatalog, Vec<usize>), PfaError> {
+    let catalog = DecodedCatalog::read_from(buf)?;
+    let num_lossy = buf.read_u64::<LittleEndian>()?;
+    let mut lossy = vec![];
+    for _ in 0..num_lossy {
+        let index = buf.read_u64::<LittleEndian>()?;
+        if index >= catalog.len() as u64 {
+            return Err(PfaError::CustomError("invalid cached catalog".into()));
+        }
+        lossy.push(index as usize);
+    }
+    Ok((catalog, lossy))
+}
+
+/// Writes the cache for the catalog with `digest`, replacing the one at `path` only once the new
+/// one is complete, so readers opening the archive meanwhile never load half a cache
+pub(crate) fn store(
+    path: &Path,
+    digest: &[u8; 32],
+    catalog: &DecodedCatalog,
+    lossy: &[usize],
+) -> Result<(), PfaError> {
+    let mut partial = path.as_os_str().to_os_string();
+    partial.push(".partial");
+    let mut out = BufWriter::new(File::create(&partial)?);
+    out.write_all(MAGIC)?;
+    out.write_u8(VERSION)?;
+    out.write_all(digest)?;
+    catalog.write_to(&mut out)?;
+    out.write_u64::<LittleEndian>(lossy.len() as u64)?;
+    for index in lossy {
+        out.write_u64::<LittleEndian>(*index as u64)?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use std::io::{Read, Seek};
+
+use globset::GlobBuilder;
+
+use crate::{
+    reader::{pfa_reader::PfaPath, PfaEntryInfo, PfaReader},
+    PfaError,
+};
+
+impl<T: Read + Seek> PfaReader<T> {
+    /// Every entry whose path matches `pattern`, such as `/textures/**/*.png`, depth first in
+    /// catalog order without reading any data. Patterns start at the root, `*` doesn't match '/'
+    /// and `**` matches across directories. Directory paths end with '/', so `/maps/*/` matches
+    /// the directories in `/maps/`. Case is ignored when the reader is
+    /// [case insensitive](crate::reader::PfaReaderOptions::case_insensitive).
+    ///
+    /// Only directories which can contain a match are listed: the leading components without
+    /// wildcards are looked up directly, and without `**` nothing deeper than the pattern is
+    /// visited.
+    pub fn glob(&self, pattern: &str) -> Result<Vec<PfaEntryInfo>, PfaError> {
+        let invalid = |reason: &str| PfaError::InvalidGlobError(format!("'{pattern}': {reason}"));
+        let Some(relative) = pattern.strip_prefix('/') else {
+            return Err(invalid("patterns must start with '/'"));
+        };
+        let matcher = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .case_insensitive(self.get_options().case_insensitive)
+            .build()
+            .map_err(|e| invalid(&e.kind().to_string()))?
+            .compile_matcher();
+
+        let parts = relative.split('/').collect::<Vec<_>>();
+        let literal = parts
+            .iter()
+            .take_while(|x| !x.contains(['*', '?', '[', '{', '\\']))
+            .count()
+            .min(parts.len() - 1);
+        let start = match literal {
+            0 => PfaPath::from("/"),
+            _ => PfaPath::from(&format!("/{}/", parts[..literal].join("/"))[..]),
+        };
+        let max_depth = match parts.iter().any(|x| x.contains("**")) {
+            true => usize::MAX,
+            false => parts.iter().filter(|x| !x.is_empty()).count(),
+        };
+
+        let Some(index) = self.find_entry(&start)? else {
+            return Ok(vec![]);
+        };
+        let mut matches = vec![];
+        for entry in self.entries_within(index, start, max_depth)? {
+            let entry = entry?;
+            if matcher.is_match(entry.path.to_string()) {
+                matches.push(entry);
+            }
+        }
+        Ok(matches)
+    }
+}
@@ -1,2 +1,42 @@
+pub mod archive_index;
+pub mod background_verify;
+pub mod capabilities;
+mod catalog;
+mod catalog_cache;
+mod glob;
+mod name_table;
+pub mod partial_read;
+mod pfa_convert;
+mod pfa_embedded;
+pub mod pfa_extractor;
+pub mod pfa_file_reader;
 pub mod pfa_reader;
-pub use pfa_reader::PfaReader;
+mod pfa_strip;
+pub mod reader_options;
+pub mod reader_warning;
+pub mod resolution_trace;
+pub mod resolver;
+pub mod retry_reader;
+pub mod scrub;
+mod solid_blocks;
+pub mod source_check;
+pub mod stats;
+pub mod traversal;
+pub use archive_index::{ArchiveIndex, IndexedArchive, IndexedFile};
+pub use background_verify::{BackgroundVerify, BackgroundVerifyOptions};
+pub use capabilities::Capabilities;
+pub use partial_read::PartialFile;
+pub use pfa_extractor::ExtractorOptions;
+pub use pfa_file_reader::PfaFileReader;
+pub use pfa_reader::{PfaEncryptedEntry, PfaEntries, PfaEntryInfo, PfaProbe, PfaReader};
+pub use reader_options::PfaReaderOptions;
+pub use reader_warning::ReaderWarning;
+pub use resolution_trace::ResolutionTrace;
+pub use resolver::PfaResolver;
+pub use retry_reader::{RetryPolicy, RetryReader};
+pub use scrub::{ArchiveHealth, ArchiveScrub, ScrubIssue, ScrubReport, ScrubTask};
+pub use source_check::SourceCheck;
+pub use stats::{ArchiveStats, CodecStats};
+pub use traversal::{
+    EntryFilter, EntryKind, PfaVisitor, TraversalOptions, TraversalOrder, VisitControl,
+};
@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::PfaError;
+
+/// Index of an interned entry name, equal names always share the same id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct NameId(u32);
+
+impl NameId {
+    pub(crate) fn to_u32(self) -> u32 {
+        self.0
+    }
+}
+
+/// Entry names stored back to back in a single buffer. Archives tend to repeat the same names in
+/// many directories, so each distinct name is only stored once.
+#[derive(Debug, Default)]
+pub(crate) struct NameTable {
+    arena: String,
+    spans: Vec<(u32, u32)>,
+    /// Ids ordered by name, for binary search
+    sorted: Vec<NameId>,
+}
+
+impl NameTable {
+    pub(crate) fn get(&self, id: NameId) -> &str {
+        let (start, end) = self.spans[id.0 as usize];
+        &self.arena[start as usize..end as usize]
+    }
+
+    pub(crate) fn find(&self, name: &str) -> Option<NameId> {
+        self.sorted
+            .binary_search_by(|id| self.get(*id).cmp(name))
+            .ok()
+            .map(|idx| self.sorted[idx])
+    }
+
+    /// The id with the given value, if the table has a name with it
+    pub(crate) fn id(&self, value: u32) -> Option<NameId> {
+        ((value as usize) < self.spans.len()).then_some(NameId(value))
+    }
+
+    /// Writes the table so [`read_from`](Self::read_from) can load it without interning or
+    /// sorting the names again
+    pub(crate) fn write_to(&self, out: &mut impl Write) -> Result<(), PfaError> {
+        out.write_u64::<LittleEndian>(self.arena.len() as u64)?;
+        out.write_all(self.arena.as_bytes())?;
+        out.write_u64::<LittleEndian>(self.spans.len() as u64)?;
+        for (start, end) in &self.spans {
+            out.write_u32::<LittleEndian>(*start)?;
+            out.write_u32::<LittleEndian>(*end)?;
+        }
+        for id in &self.sorted {
+            out.write_u32::<LittleEndian>(id.0)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a table written by [`write_to`](Self::write_to), failing if any span or id is out of
+    /// bounds
+    pub(crate) fn read_from(buf: &mut impl Read) -> Result<Self, PfaError> {
+        let invalid = || PfaError::CustomError("invalid name table".into());
+        let arena_len = buf.read_u64::<LittleEndian>()?;
+        let mut arena = vec![];
+        buf.take(arena_len).read_to_end(&mut arena)?;
+        if arena.len() as u64 != arena_len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let arena = String::from_utf8(arena).map_err(|_| invalid())?;
+
+        let num_names = buf.read_u64::<LittleEndian>()?;
+        let mut spans = vec![];
+        for _ in 0..num_names {
+            let start = buf.read_u32::<LittleEndian>()?;
+            let end = buf.read_u32::<LittleEndian>()?;
+            if start > end || arena.get(start as usize..end as usize).is_none() {
+                return Err(invalid());
+            }
+            spans.push((start, end));
+        }
+        let mut sorted = Vec::with_capacity(spans.len());
+        for _ in 0..spans.len() {
+            let id = buf.read_u32::<LittleEndian>()?;
+            if id as usize >= spans.len() {
+                return Err(invalid());
+            }
+            sorted.push(NameId(id));
+        }
+        Ok(Self {
+            arena,
+            spans,
+            sorted,
+        })
+    }
+}
+
+/// Builds a [`NameTable`] while the catalog is read
+#[derive(Debug, Default)]
+pub(crate) struct NameInterner {
+    table: NameTable,
+    lookup: HashMap<String, NameId>,
+}
+
+impl NameInterner {
+    pub(crate) fn intern(&mut self, name: &str) -> Result<NameId, PfaError> {
+        if let Some(id) = self.lookup.get(name) {
+            return Ok(*id);
+        }
+
+        let too_large = || PfaError::CustomError("catalog names are too large".into());
+        let id = NameId(self.table.spans.len().try_into().map_err(|_| too_large())?);
+        let start: u32 = self.table.arena.len().try_into().map_err(|_| too_large())?;
+        self.table.arena.push_str(name);
+        let end: u32 = self.table.arena.len().try_into().map_err(|_| too_large())?;
+        self.table.spans.push((start, end));
+        self.lookup.insert(name.to_string(), id);
+
+        Ok(id)
+    }
+
+    pub(crate) fn finish(self) -> NameTable {
+        let mut table = self.table;
+        let mut sorted = (0..table.spans.len() as u32)
+            .map(NameId)
+            .collect::<Vec<_>>();
+        sorted.sort_unstable_by(|a, b| table.get(*a).cmp(table.get(*b)));
+        table.sorted = sorted;
+        table.arena.shrink_to_fit();
+        table.spans.shrink_to_fit();
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NameInterner;
+
+    #[test]
+    fn interned_names_are_shared() {
+        let mut interner = NameInterner::default();
+        let a = interner.intern("textures").unwrap();
+        let b = interner.intern("a.png").unwrap();
+        let c = interner.intern("textures").unwrap();
+        let empty = interner.intern("").unwrap();
+        assert_eq!(a, c);
+        assert_ne!(a, b);
+
+        let table = interner.finish();
+        assert_eq!(table.spans.len(), 3);
+        assert_eq!(table.get(a), "textures");
+        assert_eq!(table.get(b), "a.png");
+        assert_eq!(table.find("textures"), Some(a));
+        assert_eq!(table.find(""), Some(empty));
+        assert_eq!(table.find("b.png"), None);
+    }
+}
@@ -0,0 +1,100 @@
+use std::{
+    io::{Read, Seek},
+    ops::Range,
+};
+
+use crate::{
+    reader::{
+        pfa_reader::{PfaPath, PfaStoredFile},
+        PfaReader,
+    },
+    shared::DataFlags,
+    PfaError,
+};
+
+/// Contents of a file read with [`PfaReader::get_file_partial`], which may hold damaged ranges
+/// error correction couldn't repair
+pub struct PartialFile {
+    path: PfaPath,
+    contents: Vec<u8>,
+    damaged: Vec<Range<u64>>,
+}
+
+impl PartialFile {
+    pub fn get_path(&self) -> &PfaPath {
+        &self.path
+    }
+
+    /// The contents, with damaged ranges holding their bytes as stored, errors included
+    pub fn get_contents(&self) -> &[u8] {
+        &self.contents
+    }
+
+    pub fn into_contents(self) -> Vec<u8> {
+        self.contents
+    }
+
+    /// Ranges of the contents error correction couldn't repair, in order and never adjacent
+    pub fn damaged_ranges(&self) -> &[Range<u64>] {
+        &self.damaged
+    }
+
+    /// Ranges of the contents which were read intact or repaired, in order
+    pub fn recovered_ranges(&self) -> Vec<Range<u64>> {
+        let mut recovered = vec![];
+        let mut start = 0;
+        for range in &self.damaged {
+            if range.start > start {
+                recovered.push(start..range.start);
+            }
+            start = range.end;
+        }
+        if (start as usize) < self.contents.len() {
+            recovered.push(start..self.contents.len() as u64);
+        }
+        recovered
+    }
+
+    /// Whether every byte was recovered
+    pub fn is_intact(&self) -> bool {
+        self.damaged.is_empty()
+    }
+}
+
+impl<T: Read + Seek> PfaReader<T> {
+    /// Reads a file like [`get_file`](Self::get_file), but when error correction can only repair
+    /// part of it, returns what it could recover along with the damaged ranges instead of
+    /// failing, which is still useful for media and other formats that tolerate damage. Only
+    /// files which are error corrected but neither compressed nor encrypted can be recovered in
+    /// part, reading any other damaged file fails as usual.
+    pub fn get_file_partial(
+        &mut self,
+        path: impl Into<PfaPath>,
+        key: Option<[u8; 32]>,
+    ) -> Result<Option<PartialFile>, PfaError> {
+        let Some(PfaStoredFile {
+            path,
+            flags,
+            mut contents,
+        }) = self.get_stored_file(path)?
+        else {
+            return Ok(None);
+        };
+        DataFlags::check_codec(flags, &path.to_string())?;
+        let limit = self.max_decompressed_size();
+        let cipher = self.path_cipher(&path)?;
+        let damaged = DataFlags::unprocess_contents_partially(
+            flags,
+            &mut contents,
+            key,
+            cipher,
+            limit,
+            self.codecs(),
+        )?;
+        Ok(Some(PartialFile {
+            path,
+            contents,
+            damaged,
+        }))
+    }
+}
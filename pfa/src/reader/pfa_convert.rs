@@ -0,0 +1,168 @@
+use std::io::{Read, Seek};
+
+use crate::{
+    builder::PfaBuilder,
+    reader::{pfa_reader::PfaFileSlice, PfaReader},
+    shared::{Cipher, DataCompressionType, DataFlags},
+    PfaError,
+};
+
+impl<T: Read + Seek> PfaReader<T> {
+    /// Rewrites the archive as format `version`, copying data as it is stored wherever possible.
+    /// Upgraded version 1 archives get entry hashes. When downgrading, layout only features are
+    /// re-encoded: the extra data is stored uncompressed, directories aren't sharded, entry
+    /// hashes, reserved space and data alignment are dropped, and files encrypted in frames, with
+    /// size padding or with XChaCha20-Poly1305 are encrypted whole again with AES256-GCM and
+    /// `key`. Fails with
+    /// [`PfaError::UnsupportedByVersion`] listing everything else the version can't store.
+    pub fn convert_version(
+        &mut self,
+        version: u8,
+        key: Option<[u8; 32]>,
+    ) -> Result<Vec<u8>, PfaError> {
+        // version 1 can't store frames, padding or other ciphers, so those files are encrypted
+        // whole again
+        let ciphered = self.get_header_fields().ciphers.clone();
+        let decode = |slice: &PfaFileSlice| {
+            key.filter(|_| {
+                version == 1
+                    && (DataFlags::has_encryption_frames(slice.flags)
+                        || DataFlags::has_size_padding(slice.flags)
+                        || ciphered
+                            .binary_search_by_key(&(slice.index as u32), |x| x.0)
+                            .is_ok())
+            })
+        };
+        self.rebuild(version, decode, |_, contents| Ok(Some(contents)))?
+            .build()
+    }
+
+    /// Rewrites the archive without the data no entry refers to (see
+    /// [`get_unreferenced_data`](Self::get_unreferenced_data)), copying files as they are stored.
+    /// The format version, catalog layout, data alignment and free reserved space are kept.
+    pub fn compact(&mut self) -> Result<Vec<u8>, PfaError> {
+        self.compact_with(|_, contents| Ok(Some(contents)))
+    }
+
+    /// Like [`compact`](Self::compact), passing the stored data of each file through
+    /// `transform`, which leaves the file out by returning None
+    pub(crate) fn compact_with(
+        &mut self,
+        transform: impl FnMut(&PfaFileSlice, Vec<u8>) -> Result<Option<Vec<u8>>, PfaError>,
+    ) -> Result<Vec<u8>, PfaError> {
+        let mut builder = self.rebuild(self.get_version(), |_| None, transform)?;
+        self.copy_layout(&mut builder)?;
+        builder.build()
+    }
+
+    /// Sets the catalog layout, data alignment and free reserved space of the archive on a
+    /// builder of the same version
+    pub(crate) fn copy_layout(&self, builder: &mut PfaBuilder) -> Result<(), PfaError> {
+        if self.get_version() > 1 {
+            builder.set_catalog_layout(self.get_catalog_layout());
+        }
+        if let Some(alignment) = self.get_data_alignment() {
+            builder.set_store_only(alignment);
+        }
+        if let Some(reserved) = self.get_reserved_space() {
+            let slots = usize::try_from(reserved.free_slots)
+                .map_err(|_| PfaError::CustomError("invalid reserved space".into()))?;
+            builder.set_reserved_space(slots, reserved.free_data);
+        }
+        Ok(())
+    }
+
+    /// Builder holding every file of the archive, converted to format `version`, with the stored
+    /// data passed through `transform`. Files `decode` returns a key for are decoded and added
+    /// encrypted whole with it, the others are copied as they are stored. Files in solid blocks
+    /// are decoded and grouped into solid blocks again, or compressed on their own for version 1,
+    /// without going through `transform`.
+    pub(crate) fn rebuild(
+        &mut self,
+        version: u8,
+        mut decode: impl FnMut(&PfaFileSlice) -> Option<[u8; 32]>,
+        mut transform: impl FnMut(&PfaFileSlice, Vec<u8>) -> Result<Option<Vec<u8>>, PfaError>,
+    ) -> Result<PfaBuilder, PfaError> {
+        let mut builder = PfaBuilder::new(self.get_name());
+        builder.set_codecs(self.codecs().clone());
+        builder.set_version(version)?;
+        builder.set_extra_data(self.get_extra_data().to_vec());
+        builder.add_variant_names(self.get_variants().iter().cloned());
+        if version > 1 {
+            if let Some(id) = self.get_archive_id() {
+                builder.set_archive_id(id);
+            }
+            builder.set_compress_extra_data(self.get_header_fields().extra_data_compressed);
+            if let Some(generation) = self.get_generation() {
+                builder.set_generation(generation.number);
+            }
+            builder.set_entry_hashes(self.has_entry_hashes() || self.get_version() == 1);
+            if let Some((max_file_size, block_size)) = self.solid_block_sizes() {
+                builder.set_solid_blocks(max_file_size, block_size);
+            }
+        }
+
+        for slice in self.collect_file_slices("/")? {
+            if let Some((member, block)) = self.solid_member(&slice) {
+                self.copy_solid_member(&slice, member, block, &mut builder)?;
+                continue;
+            }
+            let contents = self.read_slice(&slice)?;
+            let Some(mut contents) = transform(&slice, contents)? else {
+                continue;
+            };
+
+            let path = slice.path.to_string();
+            match decode(&slice) {
+                Some(key) => {
+                    DataFlags::check_codec(slice.flags, &path)?;
+                    let cipher = self.get_header_fields().entry_cipher(slice.index);
+                    let encrypt_with = match version {
+                        1 => Cipher::Aes256Gcm,
+                        _ => cipher,
+                    };
+                    let flags = Self::whole_file_flags(slice.flags, &contents, key, encrypt_with);
+                    DataFlags::unprocess_contents_from_flags(
+                        slice.flags,
+                        &mut contents,
+                        Some(key),
+                        cipher,
+                        self.max_decompressed_size(),
+                        self.codecs(),
+                    )?;
+                    builder.add_file(&path, contents, flags)?;
+                }
+                None => builder.add_stored_file(&path, contents, slice.flags)?,
+            }
+            self.copy_entry_metadata(&slice, &mut builder)?;
+        }
+
+        Ok(builder)
+    }
+
+    /// Stored data of a file, as it is in the archive
+    pub(crate) fn read_slice(&mut self, slice: &PfaFileSlice) -> Result<Vec<u8>, PfaError> {
+        self.data.seek(std::io::SeekFrom::Start(
+            self.data_idx as u64 + slice.offset,
+        ))?;
+        let mut contents = vec![0; slice.size as usize];
+        self.data.read_exact(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Flags which process a file like `bitfield` does, but encrypt it whole
+    fn whole_file_flags(bitfield: u8, contents: &[u8], key: [u8; 32], cipher: Cipher) -> DataFlags {
+        let headers = DataFlags::parse_stage_headers(bitfield, contents);
+        // DataFlags rounds the percentage down to whole bytes
+        let error_correction = headers
+            .ecc_size
+            .map(|x| (x as f32 + 0.5) / 255.0)
+            .filter(|_| headers.error_correction);
+        DataFlags::new(
+            error_correction,
+            Some(key),
+            DataCompressionType::Forced(headers.compression),
+        )
+        .cipher(cipher)
+    }
+}
@@ -0,0 +1,59 @@
+use std::{borrow::Cow, io::Cursor};
+
+use crate::{
+    reader::{pfa_reader::PfaPath, PfaReader, PfaReaderOptions},
+    shared::DataFlags,
+    PfaError,
+};
+
+/// Archives held in memory, such as ones embedded with [`include_pfa!`](crate::include_pfa)
+impl<'a> PfaReader<Cursor<&'a [u8]>> {
+    pub fn from_slice(bytes: &'a [u8]) -> Result<Self, PfaError> {
+        Self::new(Cursor::new(bytes))
+    }
+
+    pub fn from_slice_with_options(
+        bytes: &'a [u8],
+        options: PfaReaderOptions,
+    ) -> Result<Self, PfaError> {
+        Self::new_with_options(Cursor::new(bytes), options)
+    }
+
+    /// Borrows a file's contents from the archive without copying them. Returns None if the file
+    /// doesn't exist, or if it is stored compressed, encrypted or with error correction, in which
+    /// case [`PfaReader::get_file`] has to be used.
+    pub fn get_file_slice(&self, path: impl Into<PfaPath>) -> Result<Option<&'a [u8]>, PfaError> {
+        let Some((flags, offset, size)) = self.find_data_slice(&path.into())? else {
+            return Ok(None);
+        };
+        if !DataFlags::is_unprocessed(flags) {
+            return Ok(None);
+        }
+
+        let bytes: &'a [u8] = self.data.get_ref().get_ref();
+        let start = usize::try_from(offset)
+            .ok()
+            .and_then(|x| x.checked_add(self.data_idx));
+        let end = start.and_then(|x| x.checked_add(usize::try_from(size).ok()?));
+        match (start, end) {
+            (Some(start), Some(end)) if end <= bytes.len() => Ok(Some(&bytes[start..end])),
+            _ => Err(PfaError::CustomError("data slice out of bounds".into())),
+        }
+    }
+
+    /// Borrows a file's contents when they are stored as is, which every file in a store only
+    /// archive is, and decodes them otherwise
+    pub fn get_file_cow(
+        &mut self,
+        path: impl Into<PfaPath>,
+        key: Option<[u8; 32]>,
+    ) -> Result<Option<Cow<'a, [u8]>>, PfaError> {
+        let path = path.into();
+        if let Some(slice) = self.get_file_slice(path.clone())? {
+            return Ok(Some(Cow::Borrowed(slice)));
+        }
+        Ok(self
+            .get_file(path, key)?
+            .map(|x| Cow::Owned(x.into_contents())))
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::{Read, Seek};
+
+use bytes::Bytes;
+
+use crate::{
+    reader::{
+        pfa_reader::{read_stored_data, PfaFileContents, PfaFileSlice, PfaPath, PfaReader},
+        solid_blocks::decode_solid_block,
+    },
+    shared::{
+        ordered_pipeline::{self, Produced},
+        Cipher, DataFlags,
+    },
+    PfaError,
+};
+
+#[derive(Debug, Clone)]
+pub struct ExtractorOptions {
+    read_ahead: usize,
+    decode_threads: usize,
+    key: Option<[u8; 32]>,
+}
+
+impl ExtractorOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of raw slices which may be buffered ahead of the consumer. The default of 2 keeps
+    /// one slice being decoded while the next one is read.
+    pub fn read_ahead(mut self, read_ahead: usize) -> Self {
+        self.read_ahead = read_ahead.max(1);
+        self
+    }
+
+    pub fn decode_threads(mut self, decode_threads: usize) -> Self {
+        self.decode_threads = decode_threads.max(1);
+        self
+    }
+
+    pub fn key(mut self, key: Option<[u8; 32]>) -> Self {
+        self.key = key;
+        self
+    }
+}
+
+impl Default for ExtractorOptions {
+    fn default() -> Self {
+        Self {
+            read_ahead: 2,
+            decode_threads: std::thread::available_parallelism()
+                .map(|x| x.get())
+                .unwrap_or(1),
+            key: None,
+        }
+    }
+}
+
+struct RawSlice {
+    path: PfaPath,
+    flags: u8,
+    cipher: Cipher,
+    contents: Vec<u8>,
+}
+
+impl<T: Read + Seek + Send> PfaReader<T> {
+    /// Extracts every file under `path`, reading the data section sequentially on one thread while
+    /// a pool of threads decodes previously read slices.
+    ///
+    /// Files are delivered to the callback in data section order. Files which fail to decode (for
+    /// example, encrypted files without a key) are passed as `Err`, while IO errors end the
+    /// extraction. The callback should return Err to cancel the extraction.
+    ///
+    /// Files with variants are extracted once under their logical name, with the contents of the
+    /// variant [`get_file`](Self::get_file) reads, as directories list them.
+    pub fn extract_streaming<E: From<PfaError>>(
+        &mut self,
+        path: impl Into<PfaPath>,
+        options: ExtractorOptions,
+        mut callback: impl FnMut(Result<PfaFileContents, PfaError>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut slices = self.collect_file_slices(path)?;
+        if !self.get_variants().is_empty() {
+            let picked = self.pick_variants(slices.iter().map(|x| &x.path));
+            let mut listed = slices.into_iter().map(Some).collect::<Vec<_>>();
+            slices = picked
+                .into_iter()
+                .filter_map(|(i, logical)| {
+                    let mut slice = listed[i].take()?;
+                    slice.path = logical;
+                    Some(slice)
+                })
+                .collect();
+        }
+        // files in the same solid block share its offset, so they're next to each other
+        slices.sort_by_key(|x| x.offset);
+        let members = slices
+            .iter()
+            .map(|x| self.solid_member(x))
+            .collect::<Vec<_>>();
+        let ciphers = slices
+            .iter()
+            .map(|x| self.get_header_fields().entry_cipher(x.index))
+            .collect::<Vec<_>>();
+
+        let ExtractorOptions {
+            read_ahead,
+            decode_threads,
+            key,
+        } = options;
+        let data_idx = self.data_idx as u64;
+        let limit = self.max_decompressed_size();
+        let codecs = self.codecs().clone();
+        let codecs = &codecs;
+        let data = &mut self.data;
+
+        // solid blocks are decoded while reading, once for all of their files
+        let mut solid: Option<(u32, Bytes)> = None;
+        let produced =
+            slices
+                .into_iter()
+                .zip(members)
+                .zip(ciphers)
+                .map(move |((slice, member), cipher)| {
+                    let PfaFileSlice {
+                        path,
+                        flags,
+                        offset,
+                        size,
+                        ..
+                    } = slice;
+                    if let Some((member, block)) = member {
+                        if solid.as_ref().is_none_or(|x| x.0 != member.block) {
+                            let entry = path.to_string();
+                            match decode_solid_block(data, data_idx, &block, &entry, limit, codecs)
+                            {
+                                Ok(contents) => solid = Some((member.block, Bytes::from(contents))),
+                                Err(e @ PfaError::IOError(_)) => return Produced::Last(Err(e)),
+                                Err(e) => return Produced::Done(Err(e)),
+                            }
+                        }
+                        let contents = match &solid {
+                            Some((_, block)) => member.contents(block).map(|x| block.slice_ref(x)),
+                            None => Ok(Bytes::new()),
+                        }
+                        .map(|x| PfaFileContents::new(path, x));
+                        return Produced::Done(contents);
+                    }
+
+                    let entry = path.to_string();
+                    match read_stored_data(data, data_idx, offset, size, &entry, limit) {
+                        Ok(contents) => Produced::Work(RawSlice {
+                            path,
+                            flags,
+                            cipher,
+                            contents,
+                        }),
+                        Err(e @ PfaError::IOError(_)) => Produced::Last(Err(e)),
+                        Err(e) => Produced::Done(Err(e)),
+                    }
+                });
+
+        let decode = |raw: RawSlice| {
+            let RawSlice {
+                path,
+                flags,
+                cipher,
+                mut contents,
+            } = raw;
+            DataFlags::check_codec(flags, &path.to_string())
+                .and_then(|_| {
+                    DataFlags::unprocess_contents_from_flags(
+                        flags,
+                        &mut contents,
+                        key,
+                        cipher,
+                        limit,
+                        codecs,
+                    )
+                })
+                .map(|_| PfaFileContents::new(path, contents))
+        };
+
+        ordered_pipeline::run(
+            produced,
+            read_ahead,
+            decode_threads,
+            decode,
+            |decoded: Result<PfaFileContents, PfaError>| {
+                if let Err(PfaError::IOError(e)) = decoded {
+                    // reading stops after an io error
+                    return Err(PfaError::IOError(e).into());
+                }
+                callback(decoded)
+            },
+        )
+    }
+}
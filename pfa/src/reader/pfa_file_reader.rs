@@ -0,0 +1,217 @@
+use std::io::{Cursor, Read, Seek, SeekFrom, Take};
+
+use bytes::Bytes;
+
+use crate::{
+    reader::{pfa_reader::PfaPath, PfaReader, RetryReader},
+    shared::{DataFlags, EncryptionFrames, Generation},
+    PfaError,
+};
+
+/// Reads the contents of a file opened with [`PfaReader::open_file`]
+pub struct PfaFileReader<'a, T: Read + Seek> {
+    inner: Inner<'a, T>,
+    /// Generation the file was opened in and where it's stored, when following generations
+    generation: Option<(u64, Generation)>,
+}
+
+enum Inner<'a, T: Read + Seek> {
+    /// Data stored as is, read straight from the archive
+    Stored(Take<&'a mut RetryReader<T>>),
+    /// Data encrypted in frames, decrypted one frame at a time
+    Frames {
+        data: Take<&'a mut RetryReader<T>>,
+        frames: EncryptionFrames,
+        key: [u8; 32],
+        next: u64,
+        frame: Cursor<Vec<u8>>,
+    },
+    /// Data compressed with zstd, decompressed as it's read
+    #[cfg(feature = "zstd")]
+    Zstd {
+        decoder: ZstdDecoder<'a, T>,
+        decompressed: u64,
+        limit: Option<u64>,
+    },
+    /// Data which has to be decoded whole
+    Decoded(Cursor<Bytes>),
+}
+
+#[cfg(feature = "zstd")]
+type ZstdDecoder<'a, T> =
+    zstd::stream::read::Decoder<'static, std::io::BufReader<Take<&'a mut RetryReader<T>>>>;
+
+impl<T: Read + Seek> PfaFileReader<'_, T> {
+    /// Whether the contents are read as they are needed, rather than decoded up front
+    pub fn is_streaming(&self) -> bool {
+        !matches!(self.inner, Inner::Decoded(_))
+    }
+
+    fn read_frame(&mut self) -> Result<bool, PfaError> {
+        let Inner::Frames {
+            data,
+            frames,
+            key,
+            next,
+            frame,
+        } = &mut self.inner
+        else {
+            return Ok(false);
+        };
+        if *next >= frames.num_frames() {
+            return Ok(false);
+        }
+        let location = frames.frame_location(*next);
+        let mut stored = vec![0; (location.end - location.start) as usize];
+        data.read_exact(&mut stored)?;
+        check_generation(data, self.generation)?;
+        *frame = Cursor::new(frames.decrypt_frame(key, *next, &stored)?);
+        *next += 1;
+        Ok(true)
+    }
+}
+
+impl<T: Read + Seek> Read for PfaFileReader<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = match &mut self.inner {
+                Inner::Stored(data) => {
+                    let read = data.read(buf)?;
+                    check_generation(data, self.generation)?;
+                    return Ok(read);
+                }
+                #[cfg(feature = "zstd")]
+                Inner::Zstd {
+                    decoder,
+                    decompressed,
+                    limit,
+                } => {
+                    let read = decoder.read(buf)?;
+                    check_generation(decoder.get_mut().get_mut(), self.generation)?;
+                    *decompressed += read as u64;
+                    if let Some(limit) = limit.filter(|x| *decompressed > *x) {
+                        return Err(std::io::Error::other(PfaError::DecompressionLimitError {
+                            limit,
+                        }));
+                    }
+                    return Ok(read);
+                }
+                Inner::Decoded(contents) => return contents.read(buf),
+                Inner::Frames { frame, .. } => frame.read(buf)?,
+            };
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            if !self.read_frame().map_err(std::io::Error::other)? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+impl<T: Read + Seek> PfaReader<T> {
+    /// Opens a file for reading without loading it whole. Files stored as is, files only
+    /// compressed with zstd, and files encrypted in frames without compression, error correction
+    /// or size padding, are read as they are needed. Other files, including those compressed
+    /// with LZ4 whose blocks can only be decompressed whole, are decoded when they are opened.
+    /// `key` is only used for encrypted files.
+    ///
+    /// When [following generations](crate::reader::PfaReaderOptions::follow_generations), files
+    /// read as they are needed fail with an IO error once the archive is updated, and have to be
+    /// opened again.
+    pub fn open_file(
+        &mut self,
+        path: impl Into<PfaPath>,
+        key: Option<[u8; 32]>,
+    ) -> Result<Option<PfaFileReader<'_, T>>, PfaError> {
+        let path: PfaPath = path.into();
+        self.sync_generation()?;
+        let Some((flags, offset, size)) = self.find_data_slice(&path)? else {
+            return Ok(None);
+        };
+        let generation = self.followed_generation();
+        let key = key.filter(|_| DataFlags::is_encrypted(flags));
+        let framed = DataFlags::has_encryption_frames(flags)
+            && !DataFlags::is_compressed(flags)
+            && !DataFlags::has_error_correction(flags)
+            && !DataFlags::has_size_padding(flags);
+        #[cfg(feature = "zstd")]
+        let zstd = DataFlags::codec(flags) == Some(crate::shared::StoredCodec::Zstd)
+            && !DataFlags::is_encrypted(flags)
+            && !DataFlags::has_error_correction(flags)
+            // solid members are read through their block
+            && size > 0;
+        let inner = match key {
+            None if DataFlags::is_unprocessed(flags) => {
+                self.data
+                    .seek(std::io::SeekFrom::Start(self.data_idx as u64 + offset))?;
+                Inner::Stored(self.data.by_ref().take(size))
+            }
+            #[cfg(feature = "zstd")]
+            None if zstd => {
+                self.data
+                    .seek(std::io::SeekFrom::Start(self.data_idx as u64 + offset))?;
+                let limit = self.max_decompressed_size();
+                Inner::Zstd {
+                    decoder: zstd::stream::read::Decoder::new(self.data.by_ref().take(size))?,
+                    decompressed: 0,
+                    limit,
+                }
+            }
+            Some(key) if framed => {
+                self.data
+                    .seek(std::io::SeekFrom::Start(self.data_idx as u64 + offset))?;
+                let mut header = [0; EncryptionFrames::HEADER_SIZE];
+                self.data.read_exact(&mut header)?;
+                let frames =
+                    EncryptionFrames::parse(&header, size)?.with_cipher(self.path_cipher(&path)?);
+                Inner::Frames {
+                    data: self.data.by_ref().take(size - header.len() as u64),
+                    frames,
+                    key,
+                    next: 0,
+                    frame: Cursor::new(vec![]),
+                }
+            }
+            _ => {
+                // decoded in a single generation, which may not be the one it was found in
+                let contents = self.consistent_read(|reader| {
+                    let Some((flags, offset, size)) = reader.find_data_slice(&path)? else {
+                        return Ok(None);
+                    };
+                    reader
+                        .read_data_slice_contents(&path, flags, offset, size, key)
+                        .map(Some)
+                })?;
+                let Some(contents) = contents else {
+                    return Ok(None);
+                };
+                Inner::Decoded(Cursor::new(contents))
+            }
+        };
+        Ok(Some(PfaFileReader { inner, generation }))
+    }
+}
+
+/// Fails once the generation stored in the archive differs from the one the file was opened in,
+/// since the data read may then be from another generation
+fn check_generation<T: Read + Seek>(
+    data: &mut Take<&mut RetryReader<T>>,
+    generation: Option<(u64, Generation)>,
+) -> std::io::Result<()> {
+    let Some((idx, generation)) = generation else {
+        return Ok(());
+    };
+    let data = data.get_mut();
+    let position = data.stream_position()?;
+    let mut bytes = [0; Generation::SIZE];
+    data.seek(SeekFrom::Start(idx))?;
+    data.read_exact(&mut bytes)?;
+    data.seek(SeekFrom::Start(position))?;
+    match Generation::from_bytes(&bytes) == Some(generation) {
+        true => Ok(()),
+        false => Err(std::io::Error::other(
+            "archive changed while the file was read",
+        )),
+    }
+}
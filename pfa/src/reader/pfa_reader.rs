@@ -1,54 +1,113 @@
 use std::{
-    collections::VecDeque,
+    collections::{hash_map::Entry, HashMap, VecDeque},
     fmt::Display,
-    io::{Read, Seek},
+    fs::File,
+    io::{BufReader, Cursor, Read, Seek},
+    ops::Range,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
 use byteorder::{LittleEndian, ReadBytesExt};
+use bytes::Bytes;
+
+use crate::{
+    reader::{
+        catalog::{PfaCatalog, PfaSlice},
+        solid_blocks::SolidBlockCache,
+        Capabilities, PfaReaderOptions, ReaderWarning, ResolutionTrace, RetryReader,
+        TraversalOptions,
+    },
+    shared::{
+        content_type_matches,
+        data_flags::{DataFlags, StageHeaders},
+        entry_hash::{EntryProof, ProofLevel},
+        header_fields::HeaderFields,
+        sharding, CatalogLayout, Cipher, CodecRegistry, EncryptionFrames, EntryHash, Generation,
+        KeyId, NameEncoding, Provenance, ReservedSpace, StoredCodec, Tier, TransformChain, Ulid,
+        DEFAULT_DEPTH_LIMIT, LATEST_VERSION, WATERMARK,
+    },
+    telemetry, PfaError,
+};
+
+/// Times a read waits for an update in progress, or is retried after the archive changed, before
+/// giving up
+const MAX_REOPEN_ATTEMPTS: u32 = 8;
 
-use crate::{shared::data_flags::DataFlags, PfaError};
+/// Everything read when an archive is opened
+struct LoadedArchive {
+    header: PfaHeader,
+    catalog: PfaCatalog,
+    catalog_cached: bool,
+    warnings: Vec<ReaderWarning>,
+    catalog_idx: usize,
+    data_idx: usize,
+}
 
 #[derive(Debug)]
 struct PfaHeader {
     version: u8,
     name: String,
     extra_data: Vec<u8>,
+    fields: HeaderFields,
+    /// Position of the generation's data, relative to the start of the archive
+    generation_idx: Option<u64>,
 }
 
 #[derive(Debug)]
-enum PfaSlice {
-    Data {
-        flags: u8,
-        offset: u64,
-        size: u64,
-    },
-    Catalog {
-        #[allow(dead_code)]
-        flags: u8,
-        offset: u64,
-        size: u64,
-    },
+pub struct PfaReader<T: Read + Seek> {
+    header: PfaHeader,
+    catalog: PfaCatalog,
+    catalog_cached: bool,
+    options: PfaReaderOptions,
+    warnings: Vec<ReaderWarning>,
+    pub(crate) catalog_idx: usize,
+    pub(crate) data_idx: usize,
+    pub(crate) data: RetryReader<T>,
+    pub(crate) solid_cache: SolidBlockCache,
+    /// Set for archives opened by path, see [`Self::sync_generation`]
+    source: Option<SourcePath<T>>,
 }
 
+/// Where an archive opened by path is, so an updater which replaces the file, rather than
+/// changing it in place, is noticed
 #[derive(Debug)]
-struct PfaEntry {
-    path: String,
-    slice: PfaSlice,
+struct SourcePath<T> {
+    path: PathBuf,
+    identity: FileIdentity,
+    open: fn(&Path) -> std::io::Result<T>,
 }
 
-#[derive(Debug)]
-struct PfaCatalog {
-    entries: Vec<PfaEntry>,
+/// Tells files apart, the device and inode on unix, and the creation time elsewhere, which
+/// changes when a file is replaced but not when it's written to
+type FileIdentity = (u64, u64);
+
+#[cfg(unix)]
+fn file_identity(metadata: &std::fs::Metadata) -> Option<FileIdentity> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_identity(metadata: &std::fs::Metadata) -> Option<FileIdentity> {
+    let created = metadata
+        .created()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?;
+    Some((created.as_secs(), created.subsec_nanos().into()))
 }
 
 #[derive(Debug)]
-pub struct PfaReader<T: Read + Seek> {
-    header: PfaHeader,
-    catalog: PfaCatalog,
-    data_idx: usize,
-    data: T,
+pub(crate) struct PfaFileSlice {
+    pub(crate) path: PfaPath,
+    pub(crate) index: usize,
+    pub(crate) flags: u8,
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
 }
 
+#[derive(Clone, PartialEq, Eq)]
 pub struct PfaPath {
     parts: VecDeque<String>,
 }
@@ -82,6 +141,14 @@ impl PfaPath {
         Some(Self { parts })
     }
 
+    /// Replaces the last part of the path
+    pub(crate) fn with_name(&self, name: &str) -> Self {
+        let mut parts = self.parts.clone();
+        parts.pop_back();
+        parts.push_back(name.to_string());
+        Self { parts }
+    }
+
     pub fn get_parent(&self) -> Option<Self> {
         let mut parts = self.parts.clone();
         parts.pop_back()?;
@@ -108,6 +175,16 @@ impl PfaPath {
     pub fn is_file(&self) -> bool {
         !self.is_directory()
     }
+
+    /// Number of directories the entry is nested in, or for directories the number of
+    /// directories down to and including it. The root is at depth 0.
+    pub fn depth(&self) -> usize {
+        let parts = self.parts.iter().filter(|x| !x.is_empty()).count();
+        match self.is_directory() {
+            true => parts,
+            false => parts.saturating_sub(1),
+        }
+    }
 }
 
 impl From<&str> for PfaPath {
@@ -138,12 +215,30 @@ impl std::fmt::Debug for PfaPath {
     }
 }
 
+/// Paths are written as strings, directories ending with '/'
+#[cfg(feature = "serde")]
+impl serde::Serialize for PfaPath {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A file read from an archive. The contents are reference counted, so cloning the file or
+/// slicing its contents doesn't copy them.
+#[derive(Clone)]
 pub struct PfaFileContents {
-    path: PfaPath,
-    contents: Vec<u8>,
+    pub(crate) path: PfaPath,
+    pub(crate) contents: Bytes,
 }
 
 impl PfaFileContents {
+    pub(crate) fn new(path: PfaPath, contents: impl Into<Bytes>) -> Self {
+        Self {
+            path,
+            contents: contents.into(),
+        }
+    }
+
     pub fn get_path(&self) -> &PfaPath {
         &self.path
     }
@@ -152,6 +247,21 @@ impl PfaFileContents {
         &self.contents
     }
 
+    /// The contents as a buffer sharing this file's allocation, which can be sliced with
+    /// [`Bytes::slice`] and sent to other threads
+    pub fn get_bytes(&self) -> Bytes {
+        self.contents.clone()
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.contents
+    }
+
+    /// Only copies the contents when they are shared with a clone or a slice
+    pub fn into_contents(self) -> Vec<u8> {
+        self.contents.into()
+    }
+
     pub fn get_name(&self) -> String {
         self.get_path()
             .get_name()
@@ -160,9 +270,206 @@ impl PfaFileContents {
     }
 }
 
+/// A file's data exactly as it is stored in the archive, before decompression, decryption or
+/// error correction
+pub struct PfaStoredFile {
+    pub(crate) path: PfaPath,
+    pub(crate) flags: u8,
+    pub(crate) contents: Vec<u8>,
+}
+
+impl PfaStoredFile {
+    pub fn get_path(&self) -> &PfaPath {
+        &self.path
+    }
+
+    pub fn get_flags(&self) -> u8 {
+        self.flags
+    }
+
+    pub fn get_contents(&self) -> &[u8] {
+        &self.contents
+    }
+
+    pub fn into_contents(self) -> Vec<u8> {
+        self.contents
+    }
+}
+
+/// A file's stored data along with where it is in the archive and the headers of its processing
+/// stages, for inspecting damaged archives
+pub struct PfaRawFile {
+    pub(crate) stored: PfaStoredFile,
+    pub(crate) offset: u64,
+    pub(crate) headers: StageHeaders,
+}
+
+impl PfaRawFile {
+    pub fn get_path(&self) -> &PfaPath {
+        self.stored.get_path()
+    }
+
+    pub fn get_flags(&self) -> u8 {
+        self.stored.get_flags()
+    }
+
+    /// Offset of the data from the start of the archive
+    pub fn get_offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn get_contents(&self) -> &[u8] {
+        self.stored.get_contents()
+    }
+
+    pub fn get_stage_headers(&self) -> &StageHeaders {
+        &self.headers
+    }
+
+    pub fn into_stored(self) -> PfaStoredFile {
+        self.stored
+    }
+}
+
+/// What an archive needs from a reader, read by [`PfaProbe::read`] from the header alone
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PfaProbe {
+    pub version: u8,
+    pub catalog_layout: CatalogLayout,
+    pub name_encoding: NameEncoding,
+    /// Number of entries in the catalog, including reserved slots
+    pub entries: u64,
+    /// Set for store only archives, whose files can be borrowed from a memory mapping
+    pub data_alignment: Option<u32>,
+}
+
+impl PfaProbe {
+    /// Reads the header of the archive at the current position of `input`, leaving it at the
+    /// start of the catalog's entries. Fails for archives this version can't read.
+    pub fn read<R: Read + Seek>(input: &mut R) -> Result<Self, PfaError> {
+        let header = PfaReader::<R>::read_header(input, None)?;
+        Ok(Self {
+            version: header.version,
+            catalog_layout: header.fields.catalog_layout,
+            name_encoding: header.fields.name_encoding,
+            entries: input.read_u64::<LittleEndian>()?,
+            data_alignment: header.fields.data_alignment,
+        })
+    }
+}
+
+/// Metadata of a file or directory, taken from the catalog without reading any data, returned
+/// by [`PfaReader::stat`], [`PfaReader::get_file_info`] and [`PfaReader::entries`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PfaEntryInfo {
+    pub path: PfaPath,
+    pub is_directory: bool,
+    /// Size of the data stored in the archive for files, after compression, encryption and error
+    /// correction, zero for files in a solid block, whose data is part of the block's. Number of
+    /// entries for directories.
+    pub size: u64,
+    /// Size of a file's contents, only known without reading the data when the file is stored as
+    /// is, in a solid block, or the archive recorded it. None for directories.
+    pub contents_size: Option<u64>,
+    /// Flags of the stored data, zero for directories
+    pub flags: u8,
+    pub content_type: Option<String>,
+    pub tier: Option<Tier>,
+}
+
+impl PfaEntryInfo {
+    pub fn is_compressed(&self) -> bool {
+        DataFlags::is_compressed(self.flags)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        DataFlags::is_encrypted(self.flags)
+    }
+
+    pub fn has_error_correction(&self) -> bool {
+        DataFlags::has_error_correction(self.flags)
+    }
+}
+
+/// An encrypted file listed by [`PfaReader::encrypted_entries`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PfaEncryptedEntry {
+    pub path: PfaPath,
+    /// Id of the key the file is encrypted with, see [`DataFlags::key_id`]. None if the archive
+    /// didn't record it.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::shared::serde_fields::option_hex")
+    )]
+    pub key_id: Option<KeyId>,
+}
+
+/// Iterator returned by [`PfaReader::entries`]
+pub struct PfaEntries<'a, T: Read + Seek> {
+    reader: &'a PfaReader<T>,
+    stack: Vec<(usize, PfaPath, usize)>,
+    /// Directories at this depth are listed without their contents
+    max_depth: usize,
+}
+
+impl<T: Read + Seek> Iterator for PfaEntries<'_, T> {
+    type Item = Result<PfaEntryInfo, PfaError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let catalog = &self.reader.catalog;
+        let sharded = &self.reader.header.fields.sharded_directories;
+        loop {
+            let (index, path, depth) = self.stack.pop()?;
+            if self.reader.is_excluded(index) {
+                continue;
+            }
+            let slice = match catalog.slice(index) {
+                Ok(slice) => slice,
+                Err(e) => return Some(Err(e)),
+            };
+            let (is_directory, size, flags) = match slice {
+                PfaSlice::Data { flags, size, .. } => (false, size, flags),
+                PfaSlice::Catalog { .. } => {
+                    if let Err(e) = self.reader.check_depth(&path, depth) {
+                        self.stack.clear();
+                        return Some(Err(e));
+                    }
+                    let children = match directory_children(catalog, sharded, index) {
+                        Ok(children) => children,
+                        Err(e) => return Some(Err(e)),
+                    };
+                    let listed = match depth < self.max_depth {
+                        true => &children[..],
+                        false => &[],
+                    };
+                    for &child in listed.iter().rev() {
+                        let child_path = match catalog.child_path(&path, child) {
+                            Ok(Some(child_path)) => child_path,
+                            Ok(None) => return Some(Err(PfaError::MalformedPathError)),
+                            Err(e) => return Some(Err(e)),
+                        };
+                        self.stack.push((child, child_path, depth + 1));
+                    }
+                    (true, children.len() as u64, 0)
+                }
+            };
+            return Some(Ok(self.reader.entry_info(
+                index,
+                path,
+                is_directory,
+                size,
+                flags,
+            )));
+        }
+    }
+}
+
 pub struct PfaDirectoryContents {
-    path: PfaPath,
-    contents: Vec<PfaPath>,
+    pub(crate) path: PfaPath,
+    pub(crate) contents: Vec<PfaPath>,
 }
 
 impl PfaDirectoryContents {
@@ -187,299 +494,1848 @@ pub enum PfaPathContents {
     Directory(PfaDirectoryContents),
 }
 
-impl<T: Read + Seek> PfaReader<T> {
-    pub fn new(mut input: T) -> Result<Self, PfaError> {
-        let header = Self::read_header(&mut input)?;
-        let catalog = Self::read_catalog(&mut input)?;
+pub(crate) fn child_range(
+    index: usize,
+    offset: u64,
+    size: u64,
+) -> Result<std::ops::Range<usize>, PfaError> {
+    let start = index
+        .checked_add(offset as usize)
+        .ok_or(PfaError::CustomError("catalog slice out of bounds".into()))?;
+    let end = start
+        .checked_add(size as usize)
+        .ok_or(PfaError::CustomError("catalog slice out of bounds".into()))?;
+    Ok(start..end)
+}
 
-        let data_idx = input.stream_position()? as usize;
+/// Reads `size` bytes of data at `offset` in the data section starting at `data_idx`, `entry` is
+/// the file it's read for, named in errors. The size is checked against the length of the data
+/// section and `limit` before anything is allocated, so an entry claiming a huge size fails
+/// instead of exhausting memory.
+pub(crate) fn read_stored_data(
+    data: &mut (impl Read + Seek),
+    data_idx: u64,
+    offset: u64,
+    size: u64,
+    entry: &str,
+    limit: Option<u64>,
+) -> Result<Vec<u8>, PfaError> {
+    let data_len = data
+        .seek(std::io::SeekFrom::End(0))?
+        .saturating_sub(data_idx);
+    if offset.checked_add(size).is_none_or(|end| end > data_len) {
+        return Err(PfaError::DataOutOfBoundsError {
+            entry: entry.to_string(),
+        });
+    }
+    if let Some(limit) = limit.filter(|&limit| size > limit) {
+        return Err(PfaError::DecompressionLimitError { limit });
+    }
+    data.seek(std::io::SeekFrom::Start(data_idx + offset))?;
+    let mut contents = vec![0; size as usize];
+    data.read_exact(&mut contents)?;
+    Ok(contents)
+}
 
-        Ok(Self {
-            header,
-            catalog,
-            data_idx,
-            data: input,
-        })
+/// Indices of the entries in the directory at `index`, which are the contents of its buckets
+/// when it is sharded
+fn directory_children(
+    catalog: &PfaCatalog,
+    sharded: &[u32],
+    index: usize,
+) -> Result<Vec<usize>, PfaError> {
+    let out_of_bounds = || PfaError::CustomError("catalog slice out of bounds".into());
+    let PfaSlice::Catalog { offset, size, .. } = catalog.slice(index)? else {
+        return Ok(vec![]);
+    };
+    let children = child_range(index, offset, size)?;
+    if children.end > catalog.len() {
+        return Err(out_of_bounds());
+    }
+    if !u32::try_from(index).is_ok_and(|x| sharded.binary_search(&x).is_ok()) {
+        return Ok(children.collect());
     }
 
-    pub fn get_name(&self) -> &str {
-        &self.header.name
+    let mut out = vec![];
+    for bucket in children {
+        let PfaSlice::Catalog { offset, size, .. } = catalog.slice(bucket)? else {
+            return Err(PfaError::CustomError("bucket is not a directory".into()));
+        };
+        let contents = child_range(bucket, offset, size)?;
+        if contents.end > catalog.len() {
+            return Err(out_of_bounds());
+        }
+        out.extend(contents);
     }
+    Ok(out)
+}
 
-    pub fn get_version(&self) -> u8 {
-        self.header.version
+/// Archives read from a file on disk
+impl PfaReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, PfaError> {
+        Self::open_with_options(path, PfaReaderOptions::default())
     }
 
-    pub fn get_extra_data(&self) -> &[u8] {
-        &self.header.extra_data
+    pub fn open_with_options(
+        path: impl AsRef<Path>,
+        options: PfaReaderOptions,
+    ) -> Result<Self, PfaError> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let identity = file.metadata().ok().as_ref().and_then(file_identity);
+        let mut reader = Self::new_with_options(BufReader::new(file), options)?;
+        reader.source = identity.map(|identity| SourcePath {
+            path: path.to_path_buf(),
+            identity,
+            open: |path| File::open(path).map(BufReader::new),
+        });
+        Ok(reader)
     }
+}
 
-    pub fn get_path(
-        &mut self,
-        path: impl Into<PfaPath>,
-        key: Option<[u8; 32]>,
-    ) -> Result<Option<PfaPathContents>, PfaError> {
-        let path: PfaPath = path.into();
-        let is_directory = path.is_directory();
+/// Archives which have been read into memory
+impl PfaReader<Cursor<Vec<u8>>> {
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, PfaError> {
+        Self::new(Cursor::new(bytes))
+    }
 
-        let mut parts = path.get_parts().clone();
+    pub fn from_bytes_with_options(
+        bytes: Vec<u8>,
+        options: PfaReaderOptions,
+    ) -> Result<Self, PfaError> {
+        Self::new_with_options(Cursor::new(bytes), options)
+    }
+}
 
-        if is_directory {
-            let _ = parts.pop_back(); // remove last empty part
+impl<T: Read + Seek> PfaReader<T> {
+    pub fn new(input: T) -> Result<Self, PfaError> {
+        Self::new_with_options(input, PfaReaderOptions::default())
+    }
+
+    pub fn new_with_options(input: T, options: PfaReaderOptions) -> Result<Self, PfaError> {
+        let mut input = RetryReader::new(input, options.retry.clone());
+        let archive = Self::load(&mut input, &options)?;
+        Ok(Self {
+            header: archive.header,
+            catalog: archive.catalog,
+            catalog_cached: archive.catalog_cached,
+            options,
+            warnings: archive.warnings,
+            catalog_idx: archive.catalog_idx,
+            data_idx: archive.data_idx,
+            data: input,
+            solid_cache: SolidBlockCache::default(),
+            source: None,
+        })
+    }
+
+    /// Reads the header and catalog, `input` must be at the start of the archive
+    fn load(
+        input: &mut RetryReader<T>,
+        options: &PfaReaderOptions,
+    ) -> Result<LoadedArchive, PfaError> {
+        let header = Self::read_header(input, options.max_decompressed_size)?;
+        let catalog_idx = input.stream_position()? as usize;
+        let mut warnings = vec![];
+        let layout = header.fields.catalog_layout;
+        let (catalog, catalog_cached) = match (&options.catalog_cache, options.lazy_catalog) {
+            (_, true) => (PfaCatalog::read_lazy(input, layout, options.strict)?, false),
+            (Some(cache), false) => {
+                let (catalog, cached) =
+                    PfaCatalog::read_cached(input, layout, options.strict, cache, &mut warnings)?;
+                match cached {
+                    true => telemetry::increment(telemetry::CATALOG_CACHE_HITS, 1),
+                    false => telemetry::increment(telemetry::CATALOG_CACHE_MISSES, 1),
+                }
+                (catalog, cached)
+            }
+            (None, false) => (
+                PfaCatalog::read(input, layout, options.strict, &mut warnings)?,
+                false,
+            ),
+        };
+        if let Some(hashes) = &header.fields.entry_hashes {
+            if hashes.len() != catalog.len() {
+                return Err(PfaError::CustomError(
+                    "entry hash count does not match the catalog".into(),
+                ));
+            }
         }
 
-        if parts.is_empty() {
-            return Err(PfaError::MalformedPathError);
+        let data_idx = input.stream_position()? as usize;
+        let data_len = input.seek(std::io::SeekFrom::End(0))? - data_idx as u64;
+        input.seek(std::io::SeekFrom::Start(data_idx as u64))?;
+        if !catalog.is_lazy() {
+            let data = (data_idx as u64, data_len);
+            Self::check_decoded_catalog(
+                &catalog,
+                &header.fields,
+                options.strict,
+                data,
+                &mut warnings,
+            )?;
         }
-        let mut index = 0;
-        let mut remaining_size = None;
-        let mut part = parts.pop_front().ok_or(PfaError::MalformedPathError)?;
-        loop {
-            if index == self.catalog.entries.len() {
-                return Ok(None);
-            }
 
-            let is_last = parts.is_empty();
-            let needs_data_slice = is_last && !is_directory; // the last component of the path would be the
-                                                             // file, which would be the only data slice
-            let entry = &self.catalog.entries[index];
-            remaining_size = remaining_size.map(|x| x - 1);
+        Ok(LoadedArchive {
+            header,
+            catalog,
+            catalog_cached,
+            warnings,
+            catalog_idx,
+            data_idx,
+        })
+    }
 
-            if entry.path == part {
-                match (&entry.slice, needs_data_slice) {
-                    (
-                        PfaSlice::Data {
-                            offset,
-                            size,
-                            flags,
-                        },
-                        true,
-                    ) => {
-                        self.data
-                            .seek(std::io::SeekFrom::Start(self.data_idx as u64 + offset))?;
-                        let mut buf = vec![0; *size as usize];
-                        self.data.read_exact(&mut buf)?;
-
-                        DataFlags::unprocess_contents_from_flags(*flags, &mut buf, key)?;
-
-                        return Ok(Some(PfaPathContents::File(PfaFileContents {
-                            path,
-                            contents: buf,
-                        })));
-                    }
-                    (PfaSlice::Catalog { offset, size, .. }, false) => {
-                        if is_last {
-                            let index = index + *offset as usize;
-                            let catalog_contents =
-                                &self.catalog.entries[index..index + *size as usize];
-
-                            let contents = catalog_contents
-                                .iter()
-                                .map(|x| match &x.slice {
-                                    PfaSlice::Data { .. } => {
-                                        path.append(PfaPath::from(&x.path[..]))
-                                    }
-                                    PfaSlice::Catalog { .. } => {
-                                        path.append(PfaPath::from(&(format!("{}/", x.path))[..]))
-                                    }
-                                })
-                                .collect::<Option<Vec<_>>>()
-                                .ok_or(PfaError::MalformedPathError)?;
-
-                            return Ok(Some(PfaPathContents::Directory(PfaDirectoryContents {
-                                path,
-                                contents,
-                            })));
-                        }
+    /// Reads the archive again, replacing everything read when it was opened
+    fn reopen(&mut self) -> Result<(), PfaError> {
+        self.data.seek(std::io::SeekFrom::Start(0))?;
+        let archive = Self::load(&mut self.data, &self.options)?;
+        self.header = archive.header;
+        self.catalog = archive.catalog;
+        self.catalog_cached = archive.catalog_cached;
+        self.warnings = archive.warnings;
+        self.catalog_idx = archive.catalog_idx;
+        self.data_idx = archive.data_idx;
+        self.solid_cache.clear();
+        Ok(())
+    }
 
-                        index += *offset as usize;
-                        remaining_size = Some(*size);
-                        part = parts.pop_front().ok_or(PfaError::MalformedPathError)?;
-                    }
-                    _ => {}
+    /// Generation stored in the header when the archive was last read, see
+    /// [`PfaBuilder::set_generation`](crate::builder::PfaBuilder::set_generation)
+    pub fn get_generation(&self) -> Option<Generation> {
+        self.header.fields.generation
+    }
+
+    /// Reads the generation currently stored in the header
+    fn read_generation(&mut self) -> Result<Option<Generation>, PfaError> {
+        let Some(idx) = self.header.generation_idx else {
+            return Ok(None);
+        };
+        self.data.seek(std::io::SeekFrom::Start(idx))?;
+        let mut bytes = [0; Generation::SIZE];
+        self.data.read_exact(&mut bytes)?;
+        Ok(Generation::from_bytes(&bytes))
+    }
+
+    /// When [following generations](PfaReaderOptions::follow_generations), reopens the archive
+    /// once the stored generation differs from the one it was read with, or the file it was
+    /// opened from by path was replaced, waiting for updates in progress to finish. Returns
+    /// whether it was reopened.
+    pub(crate) fn sync_generation(&mut self) -> Result<bool, PfaError> {
+        if !self.options.follow_generations {
+            return Ok(false);
+        }
+        let mut reopened = false;
+        for attempt in 0..MAX_REOPEN_ATTEMPTS {
+            if let Some(identity) = self.replaced_identity() {
+                // the new file may not be complete yet
+                if self.reopen_replaced(identity).is_ok() {
+                    reopened = true;
+                    continue;
                 }
             } else {
-                index += 1;
-            }
-
-            if let Some(0) = remaining_size {
-                return Ok(None);
+                let Some(read) = self.header.fields.generation else {
+                    return Ok(reopened);
+                };
+                // the header may be unreadable while the archive is being rewritten
+                let current = self.read_generation().ok().flatten();
+                if current == Some(read) && !read.is_in_progress() {
+                    return Ok(reopened);
+                }
+                if current.is_some_and(|x| !x.is_in_progress()) && self.reopen().is_ok() {
+                    // checked again, in case it changed while it was reopened
+                    reopened = true;
+                    continue;
+                }
             }
+            std::thread::sleep(Duration::from_millis(1 << attempt));
         }
+        Err(PfaError::CustomError(
+            "archive kept changing while it was reopened".into(),
+        ))
     }
 
-    pub fn get_file(
-        &mut self,
-        path: impl Into<PfaPath>,
-        key: Option<[u8; 32]>,
-    ) -> Result<Option<PfaFileContents>, PfaError> {
-        match self.get_path(path, key) {
-            Ok(Some(PfaPathContents::File(f))) => Ok(Some(f)),
-            Err(e) => Err(e),
-            _ => Ok(None),
-        }
+    /// Identity of the file now at the path the archive was opened from, when it isn't the file
+    /// which is read
+    fn replaced_identity(&self) -> Option<FileIdentity> {
+        let source = self.source.as_ref()?;
+        // a missing file is being replaced, the old one is still read
+        let identity = file_identity(&std::fs::metadata(&source.path).ok()?)?;
+        (identity != source.identity).then_some(identity)
     }
 
-    pub fn get_directory(
-        &mut self,
-        path: impl Into<PfaPath>,
-        key: Option<[u8; 32]>,
-    ) -> Result<Option<PfaDirectoryContents>, PfaError> {
-        let mut path: PfaPath = path.into();
-        if !path.is_directory() {
-            path = path.append("").ok_or(PfaError::MalformedPathError)?; // append empty part to make it a directory
+    /// Reads the archive from the file which replaced the one it was opened from, keeping the
+    /// old file if the new one can't be read
+    fn reopen_replaced(&mut self, identity: FileIdentity) -> Result<(), PfaError> {
+        let Some(source) = &self.source else {
+            return Ok(());
+        };
+        let file = (source.open)(&source.path)?;
+        let old = std::mem::replace(self.data.get_mut(), file);
+        if let Err(e) = self.reopen() {
+            *self.data.get_mut() = old;
+            return Err(e);
         }
-
-        match self.get_path(path, key) {
-            Ok(Some(PfaPathContents::Directory(f))) => Ok(Some(f)),
-            Err(e) => Err(e),
-            _ => Ok(None),
+        if let Some(source) = &mut self.source {
+            source.identity = identity;
         }
+        Ok(())
     }
 
-    /// Warning: this function will only successfully traverse non-encrypted files
-    pub fn traverse_files(
-        &mut self,
-        path: impl Into<PfaPath>,
-        mut callback: impl FnMut(PfaFileContents),
-    ) {
-        fn inner<T: Read + Seek>(
-            s: &mut PfaReader<T>,
-            path: PfaPath,
-            callback: &mut impl FnMut(PfaFileContents),
-        ) {
-            let contents = s.get_path(path, None);
-            match contents {
-                Ok(Some(PfaPathContents::File(f))) => (callback)(f),
-                Ok(Some(PfaPathContents::Directory(d))) => {
-                    for path in d.contents {
-                        inner(s, path, callback);
-                    }
-                }
-                _ => {}
-            }
+    /// Generation the archive was read in and where it's stored, for readers which check it
+    /// themselves, see [`PfaFileReader`](crate::reader::PfaFileReader)
+    pub(crate) fn followed_generation(&self) -> Option<(u64, Generation)> {
+        if !self.options.follow_generations {
+            return None;
         }
-        inner(self, path.into(), &mut callback);
+        Some((self.header.generation_idx?, self.header.fields.generation?))
     }
 
-    /// Warning: this function will only successfully traverse non-encrypted files
-    /// Callback should return Err to cancel
-    /// Returns first propagated error, or () if there wassn't any
-    pub fn traverse_files_cancelable<E>(
+    /// Runs `read` in a single generation of the archive, running it again after reopening the
+    /// archive if the generation changed by the time it finished
+    pub(crate) fn consistent_read<R>(
         &mut self,
-        path: impl Into<PfaPath>,
-        mut callback: impl FnMut(PfaFileContents) -> Result<(), E>,
-    ) -> Result<(), E> {
-        fn inner<T: Read + Seek, E>(
-            s: &mut PfaReader<T>,
-            path: PfaPath,
-            callback: &mut impl FnMut(PfaFileContents) -> Result<(), E>,
-        ) -> Result<(), E> {
-            let contents = s.get_path(path, None);
-            match contents {
-                Ok(Some(PfaPathContents::File(f))) => (callback)(f),
-                Ok(Some(PfaPathContents::Directory(d))) => {
-                    for path in d.contents {
-                        inner(s, path, callback)?;
-                    }
-                    Ok(())
-                }
-                _ => Ok(()),
+        mut read: impl FnMut(&mut Self) -> Result<R, PfaError>,
+    ) -> Result<R, PfaError> {
+        self.sync_generation()?;
+        for _ in 0..MAX_REOPEN_ATTEMPTS {
+            let out = read(self);
+            if !self.sync_generation()? {
+                return out;
             }
         }
-        inner(self, path.into(), &mut callback)
+        Err(PfaError::CustomError(
+            "archive kept changing while it was read".into(),
+        ))
     }
 
-    fn read_sized_buffer(buf: &mut T) -> Result<Vec<u8>, PfaError> {
-        let size = buf.read_u8()?;
-        let mut str_buf = vec![0; size.into()];
-        let _ = buf.read(&mut str_buf);
-        Ok(str_buf)
+    pub fn get_options(&self) -> &PfaReaderOptions {
+        &self.options
     }
 
-    fn read_sized_string(buf: &mut T) -> Result<String, PfaError> {
-        let str_buf = Self::read_sized_buffer(buf)?;
-        Ok(String::from_utf8(str_buf)?)
+    /// Problems found while opening the archive which didn't stop it from being opened, so
+    /// tools can report them
+    pub fn warnings(&self) -> &[ReaderWarning] {
+        &self.warnings
     }
 
-    fn read_fixed_sized_string(buf: &mut T, length: usize) -> Result<String, PfaError> {
-        let mut string_buf = vec![0; length];
-        let _ = buf.read(&mut string_buf)?;
-
-        let string_length = string_buf
-            .iter()
-            .enumerate()
-            .find(|x| *x.1 == 0)
-            .map(|(i, _)| i)
-            .unwrap_or(length);
-
-        let string_slice = string_buf[0..string_length].to_vec();
-
-        Ok(String::from_utf8(string_slice)?)
+    /// Whether the catalog was loaded from the [catalog cache](PfaReaderOptions::catalog_cache)
+    /// when the archive was last read, instead of being decoded
+    pub fn is_catalog_cached(&self) -> bool {
+        self.catalog_cached
     }
 
-    fn read_catalog(buf: &mut T) -> Result<PfaCatalog, PfaError> {
-        let num_entries = buf.read_u64::<LittleEndian>()?;
-        let mut entries = Vec::with_capacity(num_entries as usize);
-        for _ in 0..num_entries {
-            entries.push(Self::read_catalog_entry(buf)?);
+    /// Decodes every entry of a [lazy catalog](PfaReaderOptions::lazy_catalog), which then
+    /// makes the same checks, and reports the same warnings, as opening the archive does when the
+    /// catalog isn't lazy. Nothing happens if the catalog is already decoded.
+    pub fn preload_catalog(&mut self) -> Result<(), PfaError> {
+        if !self.catalog.is_lazy() {
+            return Ok(());
         }
-
-        let catalog = PfaCatalog { entries };
-
-        Ok(catalog)
+        let mut warnings = vec![];
+        self.catalog.preload(&mut warnings)?;
+        let data_len = self.data.seek(std::io::SeekFrom::End(0))? - self.data_idx as u64;
+        Self::check_decoded_catalog(
+            &self.catalog,
+            &self.header.fields,
+            self.options.strict,
+            (self.data_idx as u64, data_len),
+            &mut warnings,
+        )?;
+        self.warnings.extend(warnings);
+        Ok(())
     }
 
-    fn read_catalog_entry(buf: &mut T) -> Result<PfaEntry, PfaError> {
-        let mut path = Self::read_fixed_sized_string(buf, 32)?; // TODO: don't hardcode this
-        let is_directory = path.ends_with('/');
-        let slice = if is_directory {
-            path = path[0..path.len() - 1].to_string();
-            Self::read_catalog_slice(buf)?
-        } else {
-            Self::read_data_slice(buf)?
-        };
-
-        Ok(PfaEntry { path, slice })
+    /// Fails on unknown flag bits if `strict` is set, otherwise adds warnings about them. Warns
+    /// about entries which point outside the archive, and data no entry refers to. `data` is the
+    /// start and length of the data section.
+    fn check_decoded_catalog(
+        catalog: &PfaCatalog,
+        fields: &HeaderFields,
+        strict: bool,
+        data: (u64, u64),
+        warnings: &mut Vec<ReaderWarning>,
+    ) -> Result<(), PfaError> {
+        if strict {
+            catalog.check_unknown_bits()?;
+        }
+        let (data_idx, data_len) = data;
+        warnings.extend(Self::check_catalog(catalog, data_len)?);
+        let unreferenced = Self::find_unreferenced_data(catalog, fields, data_idx, data_len)?;
+        warnings.extend(
+            unreferenced
+                .into_iter()
+                .map(|x| ReaderWarning::UnreferencedData {
+                    offset: x.start,
+                    size: x.end - x.start,
+                }),
+        );
+        Ok(())
     }
-    fn read_catalog_slice(buf: &mut T) -> Result<PfaSlice, PfaError> {
-        let flags = buf.read_u8()?;
-        let size = buf.read_u64::<LittleEndian>()?;
-        let offset = buf.read_u64::<LittleEndian>()?;
 
-        Ok(PfaSlice::Catalog {
-            flags,
-            offset,
-            size,
-        })
+    /// Ranges of the data section, relative to its start, which aren't covered by the data of any
+    /// file or solid block. Padding which aligns file data, and free reserved data, are expected
+    /// and not counted.
+    fn find_unreferenced_data(
+        catalog: &PfaCatalog,
+        fields: &HeaderFields,
+        data_idx: u64,
+        data_len: u64,
+    ) -> Result<Vec<Range<u64>>, PfaError> {
+        let mut used = vec![];
+        for index in 0..catalog.len() {
+            if let PfaSlice::Data { offset, size, .. } = catalog.slice(index)? {
+                used.push(offset..offset.saturating_add(size).min(data_len));
+            }
+        }
+        for block in &fields.solid_blocks {
+            used.push(block.offset..block.offset.saturating_add(block.size).min(data_len));
+        }
+        if let Some(reserved) = fields.reserved_space {
+            let end = reserved.data_offset.saturating_add(reserved.free_data);
+            used.push(reserved.data_offset..end.min(data_len));
+        }
+        used.retain(|x| !x.is_empty());
+        used.sort_by_key(|x| x.start);
+
+        let is_padding = |gap: &Range<u64>| {
+            fields.data_alignment.is_some_and(|alignment| {
+                let alignment = u64::from(alignment);
+                gap.end - gap.start < alignment && (data_idx + gap.end).is_multiple_of(alignment)
+            })
+        };
+        let mut unreferenced = vec![];
+        let mut covered = 0;
+        for range in used {
+            let gap = covered..range.start;
+            if !gap.is_empty() && !is_padding(&gap) {
+                unreferenced.push(gap);
+            }
+            covered = covered.max(range.end);
+        }
+        if covered < data_len {
+            unreferenced.push(covered..data_len);
+        }
+        Ok(unreferenced)
     }
 
-    fn read_data_slice(buf: &mut T) -> Result<PfaSlice, PfaError> {
-        let flags = buf.read_u8()?;
-        let size = buf.read_u64::<LittleEndian>()?;
-        let offset = buf.read_u64::<LittleEndian>()?;
-
-        Ok(PfaSlice::Data {
-            flags,
-            offset,
-            size,
-        })
+    /// Ranges of the data section, relative to its start, which no entry refers to. They are left
+    /// by editing an archive in place or tampering with it, and are dropped by
+    /// [`compact`](Self::compact). Also reported as
+    /// [`ReaderWarning::UnreferencedData`] when the archive is opened.
+    pub fn get_unreferenced_data(&mut self) -> Result<Vec<Range<u64>>, PfaError> {
+        let data_len = self.data.seek(std::io::SeekFrom::End(0))? - self.data_idx as u64;
+        Self::find_unreferenced_data(
+            &self.catalog,
+            &self.header.fields,
+            self.data_idx as u64,
+            data_len,
+        )
     }
 
-    fn read_header(buf: &mut T) -> Result<PfaHeader, PfaError> {
-        let mut watermark = [0; 3];
-        let _ = buf.read(&mut watermark);
-        if &watermark != b"pfa" {
+    /// Finds entries which point outside the archive, or use unknown flag bits
+    fn check_catalog(catalog: &PfaCatalog, data_len: u64) -> Result<Vec<ReaderWarning>, PfaError> {
+        let mut warnings = vec![];
+        for index in 0..catalog.len() {
+            let slice = catalog.slice(index)?;
+            let name = || catalog.name(index).map(|x| x.into_owned());
+            match slice {
+                PfaSlice::Data { offset, size, .. } => {
+                    if offset.checked_add(size).is_none_or(|end| end > data_len) {
+                        warnings.push(ReaderWarning::DataOutOfBounds {
+                            index,
+                            name: name()?,
+                            offset,
+                            size,
+                        });
+                    }
+                }
+                PfaSlice::Catalog { offset, size, .. } => {
+                    if child_range(index, offset, size).map_or(true, |x| x.end > catalog.len()) {
+                        warnings.push(ReaderWarning::CatalogOutOfBounds {
+                            index,
+                            name: name()?,
+                        });
+                    }
+                }
+            }
+            if slice.unknown_bits() != 0 {
+                warnings.push(ReaderWarning::UnknownFlagBits {
+                    index,
+                    name: name()?,
+                    bits: slice.unknown_bits(),
+                });
+            }
+        }
+        Ok(warnings)
+    }
+
+    /// Every flag bit used by the archive's entries which this version doesn't understand, zero
+    /// if every entry can be read. Files using unknown bits may be decoded incorrectly unless the
+    /// reader is [strict](PfaReaderOptions::strict).
+    pub fn get_unknown_flag_bits(&self) -> u8 {
+        (0..self.catalog.len())
+            .filter_map(|index| self.catalog.slice(index).ok())
+            .fold(0, |bits, slice| bits | slice.unknown_bits())
+    }
+
+    /// What this reader can read, including the custom codecs of its
+    /// [options](PfaReaderOptions::codecs), along with the codecs and flag bits used by the
+    /// archive which it can't, so mismatches can be found before reading any file. The id of the
+    /// custom codec of each file compressed with one is read from its data, except for encrypted
+    /// files, where it can't be known without the key. Those only count as missing when the reader
+    /// has no custom codecs at all.
+    pub fn capabilities(&mut self) -> Result<Capabilities, PfaError> {
+        let mut capabilities = Capabilities::supported();
+        let codecs = self.codecs().clone();
+        capabilities
+            .codecs
+            .extend(codecs.codecs().iter().map(|x| x.name().to_string()));
+
+        // solid members have empty data slices, their blocks are checked instead
+        let mut stored = (0..self.catalog.len())
+            .filter_map(|index| match self.catalog.slice(index) {
+                Ok(PfaSlice::Data {
+                    flags,
+                    offset,
+                    size,
+                }) if size > 0 => Some((flags, offset, size)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        stored.extend(
+            self.header
+                .fields
+                .solid_blocks
+                .iter()
+                .map(|x| (x.flags, x.offset, x.size)),
+        );
+        for (flags, offset, size) in stored {
+            let missing = match DataFlags::codec(flags) {
+                None => continue,
+                Some(StoredCodec::Custom) => match self.custom_codec_id(flags, offset, size)? {
+                    Some(id) if codecs.find_by_id(id).is_some() => continue,
+                    Some(id) => format!("custom codec {id}"),
+                    None if !codecs.codecs().is_empty() => continue,
+                    None => StoredCodec::Custom.to_string(),
+                },
+                Some(codec) if codec.is_supported() => continue,
+                Some(codec) => codec.to_string(),
+            };
+            if !capabilities.missing_codecs.contains(&missing) {
+                capabilities.missing_codecs.push(missing);
+            }
+        }
+        capabilities.unknown_flag_bits = self.get_unknown_flag_bits();
+        Ok(capabilities)
+    }
+
+    /// Id of the custom codec data stored with `flags` was compressed with, None when it's
+    /// encrypted or its header can't be read
+    fn custom_codec_id(
+        &mut self,
+        flags: u8,
+        offset: u64,
+        size: u64,
+    ) -> Result<Option<u8>, PfaError> {
+        if DataFlags::is_encrypted(flags) {
+            return Ok(None);
+        }
+        // the id is the first byte, unless it's behind the error correction header
+        let len = match DataFlags::has_error_correction(flags) {
+            true => size,
+            false => size.min(1),
+        };
+        self.data
+            .seek(std::io::SeekFrom::Start(self.data_idx as u64 + offset))?;
+        let mut stored = vec![0; len as usize];
+        self.data.read_exact(&mut stored)?;
+        Ok(DataFlags::parse_stage_headers(flags, &stored).codec_id)
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.header.name
+    }
+
+    pub fn get_version(&self) -> u8 {
+        self.header.version
+    }
+
+    /// Archive level data stored in the header, decompressed if it was stored compressed
+    pub fn get_extra_data(&self) -> &[u8] {
+        &self.header.extra_data
+    }
+
+    /// Unique id assigned to the archive when it was built, archives older than version 2 don't
+    /// have one
+    pub fn get_archive_id(&self) -> Option<Ulid> {
+        self.header.fields.archive_id
+    }
+
+    pub fn get_catalog_layout(&self) -> CatalogLayout {
+        self.header.fields.catalog_layout
+    }
+
+    pub fn get_name_encoding(&self) -> NameEncoding {
+        self.header.fields.name_encoding
+    }
+
+    pub(crate) fn depth_limit(&self) -> usize {
+        self.options.depth_limit.unwrap_or(DEFAULT_DEPTH_LIMIT)
+    }
+
+    pub(crate) fn max_decompressed_size(&self) -> Option<u64> {
+        self.options.max_decompressed_size
+    }
+
+    pub(crate) fn codecs(&self) -> &CodecRegistry {
+        &self.options.codecs
+    }
+
+    /// Fails for directories nested deeper than the depth limit
+    fn check_depth(&self, path: &PfaPath, depth: usize) -> Result<(), PfaError> {
+        let limit = self.depth_limit();
+        match depth > limit {
+            true => Err(PfaError::DepthLimitError {
+                path: path.to_string(),
+                limit,
+            }),
+            false => Ok(()),
+        }
+    }
+
+    fn ensure_listing_allowed(&self) -> Result<(), PfaError> {
+        match self.header.fields.name_encoding {
+            NameEncoding::Plain => Ok(()),
+            NameEncoding::Hashed => Err(PfaError::ListingDisabledError),
+        }
+    }
+
+    /// Space which is still available for adding files in place
+    pub fn get_reserved_space(&self) -> Option<ReservedSpace> {
+        self.header.fields.reserved_space
+    }
+
+    /// Alignment of the data of every file in store only archives (see
+    /// [`PfaBuilder::set_store_only`](crate::builder::PfaBuilder::set_store_only)), whose files
+    /// can all be borrowed without copying them
+    pub fn get_data_alignment(&self) -> Option<u32> {
+        self.header.fields.data_alignment
+    }
+
+    pub(crate) fn get_header_fields(&self) -> &HeaderFields {
+        &self.header.fields
+    }
+
+    /// Every catalog entry as (name, flags, size, offset), directory names end with '/'
+    pub(crate) fn get_raw_entries(
+        &self,
+    ) -> impl Iterator<Item = Result<(String, u8, u64, u64), PfaError>> + '_ {
+        (0..self.catalog.len()).map(|index| {
+            let name = self.catalog.name(index)?;
+            Ok(match self.catalog.slice(index)? {
+                PfaSlice::Data {
+                    flags,
+                    offset,
+                    size,
+                } => (name.to_string(), flags, size, offset),
+                PfaSlice::Catalog {
+                    flags,
+                    offset,
+                    size,
+                } => (format!("{name}/"), flags, size, offset),
+            })
+        })
+    }
+
+    pub fn has_entry_hashes(&self) -> bool {
+        self.header.fields.entry_hashes.is_some()
+    }
+
+    /// Hash of the root directory, which covers the entire archive
+    pub fn get_root_hash(&self) -> Option<EntryHash> {
+        self.header.fields.entry_hashes.as_ref()?.first().copied()
+    }
+
+    /// Returns None if the path doesn't exist or the archive was built without entry hashes. Equal
+    /// hashes mean the entries (and for directories, everything inside them) are identical.
+    pub fn get_entry_hash(&self, path: impl Into<PfaPath>) -> Result<Option<EntryHash>, PfaError> {
+        let Some(hashes) = &self.header.fields.entry_hashes else {
+            return Ok(None);
+        };
+        Ok(self.find_entry(&path.into())?.map(|index| hashes[index]))
+    }
+
+    /// Creates an inclusion proof for a file, which can be checked against the root hash with
+    /// [`EntryProof::verify`]. Returns None if the file doesn't exist or the archive was built
+    /// without entry hashes.
+    pub fn get_entry_proof(
+        &self,
+        path: impl Into<PfaPath>,
+    ) -> Result<Option<EntryProof>, PfaError> {
+        let Some(hashes) = &self.header.fields.entry_hashes else {
+            return Ok(None);
+        };
+        let path: PfaPath = path.into();
+        if path.is_directory() {
+            return Ok(None);
+        }
+        let Some(index) = self.find_entry(&path)? else {
+            return Ok(None);
+        };
+        let PfaSlice::Data {
+            flags,
+            offset,
+            size,
+        } = self.catalog.slice(index)?
+        else {
+            return Ok(None);
+        };
+
+        // every directory containing the file, starting at the root
+        let mut parts = path
+            .get_parts()
+            .iter()
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>();
+        let _ = parts.pop(); // file name
+        let mut directories = vec![];
+        for i in 0..=parts.len() {
+            let mut dir = String::from("/");
+            for part in &parts[..i] {
+                dir.push_str(part);
+                dir.push('/');
+            }
+            let dir_index = self
+                .find_entry(&PfaPath::from(&dir[..]))?
+                .ok_or(PfaError::CustomError("parent directory missing".into()))?;
+            directories.push((dir_index, false));
+        }
+        // buckets of sharded directories are part of the tree being hashed
+        for i in (0..directories.len()).rev() {
+            let dir_index = directories[i].0;
+            if self.is_sharded(dir_index) {
+                let child = directories.get(i + 1).map(|x| x.0).unwrap_or(index);
+                let name = self.catalog.name(child)?;
+                let bucket = self.bucket_for(dir_index, &name)?;
+                directories.insert(i + 1, (bucket, true));
+            }
+        }
+
+        let mut levels = vec![];
+        let mut child = index;
+        for (dir_index, bucket) in directories.into_iter().rev() {
+            let PfaSlice::Catalog { offset, size, .. } = self.catalog.slice(dir_index)? else {
+                return Err(PfaError::CustomError("parent is not a directory".into()));
+            };
+            let children = child_range(dir_index, offset, size)?;
+            if !children.contains(&child) || children.end > hashes.len() {
+                return Err(PfaError::CustomError("catalog slice out of bounds".into()));
+            }
+            let position = child - children.start;
+            let siblings = children
+                .filter(|x| *x != child)
+                .map(|x| hashes[x])
+                .collect();
+            levels.push(ProofLevel {
+                name: self.catalog.name(dir_index)?.into_owned(),
+                index: position
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("directory is too large".into()))?,
+                siblings,
+                bucket,
+            });
+            child = dir_index;
+        }
+
+        Ok(Some(EntryProof {
+            name: self.catalog.name(index)?.into_owned(),
+            flags,
+            slice_offset: self.data_idx as u64 + offset,
+            slice_size: size,
+            levels,
+        }))
+    }
+
+    /// Splits a file name into its logical name and variant tag, if the tag is one of the
+    /// archive's variants
+    fn split_variant<'a>(&self, name: &'a str) -> Option<(&'a str, &'a str)> {
+        let (logical, tag) = name.rsplit_once('#')?;
+        self.header
+            .fields
+            .variants
+            .iter()
+            .any(|x| x == tag)
+            .then_some((logical, tag))
+    }
+
+    pub fn get_variants(&self) -> &[String] {
+        &self.header.fields.variants
+    }
+
+    /// Finds an entry, picking the preferred variant of a file if it has any
+    pub(crate) fn find_entry(&self, path: &PfaPath) -> Result<Option<usize>, PfaError> {
+        let (directories, last) = Self::split_path(path)?;
+        let mut prefix = String::from("/");
+        let Some(dir) = self.find_directory(0, &mut prefix, &directories)? else {
+            return Ok(None);
+        };
+        match last {
+            Some(last) => self.find_in_directory(dir, &prefix, &last, path.is_directory()),
+            None => Ok(Some(dir).filter(|x| !self.is_excluded(*x))),
+        }
+    }
+
+    /// Names of the directories leading to an entry, and the entry's own name (None for the root)
+    fn split_path(path: &PfaPath) -> Result<(Vec<String>, Option<String>), PfaError> {
+        let mut parts = path.get_parts().clone();
+        if path.is_directory() {
+            let _ = parts.pop_back(); // remove last empty part
+        }
+        if parts.is_empty() {
+            return Err(PfaError::MalformedPathError);
+        }
+        if parts.front().is_some_and(|x| x.is_empty()) {
+            let _ = parts.pop_front(); // root, relative paths start there as well
+        }
+
+        let last = parts.pop_back();
+        Ok((parts.into(), last))
+    }
+
+    /// Walks from the directory at `index` through the given sub-directories. `prefix` is the
+    /// path of the starting directory, and is extended as the walk goes when names are encoded.
+    fn find_directory(
+        &self,
+        mut index: usize,
+        prefix: &mut String,
+        directories: &[String],
+    ) -> Result<Option<usize>, PfaError> {
+        if index >= self.catalog.len()
+            || !matches!(self.catalog.slice(index)?, PfaSlice::Catalog { .. })
+        {
+            return Ok(None);
+        }
+        for part in directories {
+            let Some(child) = self.find_child_entry(index, prefix, part, false)? else {
+                return Ok(None);
+            };
+            if self.header.fields.name_encoding != NameEncoding::Plain {
+                prefix.push_str(part);
+                prefix.push('/');
+            }
+            index = child;
+        }
+        Ok(Some(index))
+    }
+
+    /// Finds the entry called `name` in a directory, picking the preferred variant of a file if
+    /// it has any
+    fn find_in_directory(
+        &self,
+        dir: usize,
+        prefix: &str,
+        name: &str,
+        is_directory: bool,
+    ) -> Result<Option<usize>, PfaError> {
+        let find = |name: &str| -> Result<Option<usize>, PfaError> {
+            Ok(self
+                .find_child_entry(dir, prefix, name, !is_directory)?
+                .filter(|x| !self.is_excluded(*x)))
+        };
+        // files without a variant tag are looked up by the preferred variants first, unless a
+        // specific variant was asked for
+        if !is_directory && self.split_variant(name).is_none() {
+            for tag in &self.options.variant_preference {
+                if !self.header.fields.variants.contains(tag) {
+                    continue;
+                }
+                if let Some(index) = find(&format!("{name}#{tag}"))? {
+                    return Ok(Some(index));
+                }
+            }
+        }
+        find(name)
+    }
+
+    /// Lists files with variants once under their logical name, leaving out files which have no
+    /// variant that can be read with the current preferences
+    fn collapse_variants(&self, contents: Vec<PfaPath>) -> Vec<PfaPath> {
+        self.pick_variants(&contents)
+            .into_iter()
+            .map(|(_, logical)| logical)
+            .collect()
+    }
+
+    /// Picks the file which is read under each logical name from `paths` with the current
+    /// preferences, like [`Self::find_in_directory`]: its first preferred variant, otherwise the
+    /// file without a variant tag. Returns the index of each picked path in `paths` along with
+    /// its logical path, in the order the logical paths are first listed.
+    pub(crate) fn pick_variants<'a>(
+        &self,
+        paths: impl IntoIterator<Item = &'a PfaPath>,
+    ) -> Vec<(usize, PfaPath)> {
+        let preference = &self.options.variant_preference;
+        // lower ranks are preferred, files without a variant tag rank last
+        let mut picked = HashMap::<String, (usize, PfaPath, usize)>::new();
+        let mut order = vec![];
+        for (i, path) in paths.into_iter().enumerate() {
+            let (logical, rank) = match path.get_name().and_then(|x| self.split_variant(x)) {
+                Some((name, tag)) if path.is_file() => {
+                    let Some(rank) = preference.iter().position(|x| x == tag) else {
+                        continue;
+                    };
+                    (path.with_name(name), rank)
+                }
+                _ => (path.clone(), preference.len()),
+            };
+            match picked.entry(logical.to_string()) {
+                Entry::Occupied(mut x) => {
+                    if rank < x.get().2 {
+                        x.insert((i, logical, rank));
+                    }
+                }
+                Entry::Vacant(x) => {
+                    order.push(x.key().clone());
+                    x.insert((i, logical, rank));
+                }
+            }
+        }
+        order
+            .into_iter()
+            .filter_map(|x| picked.remove(&x))
+            .map(|(i, logical, _)| (i, logical))
+            .collect()
+    }
+
+    /// Finds the entry called `part` in the directory at `dir`, which is a file when
+    /// `needs_data_slice` is set. `prefix` is the directory's path, only used when names are
+    /// encoded.
+    fn find_child_entry(
+        &self,
+        dir: usize,
+        prefix: &str,
+        part: &str,
+        needs_data_slice: bool,
+    ) -> Result<Option<usize>, PfaError> {
+        if !matches!(self.catalog.slice(dir)?, PfaSlice::Catalog { .. }) {
+            return Ok(None);
+        }
+        let encoding = self.header.fields.name_encoding;
+        let encoded;
+        let stored_name = if encoding == NameEncoding::Plain {
+            part
+        } else {
+            let path = if needs_data_slice {
+                format!("{prefix}{part}")
+            } else {
+                format!("{prefix}{part}/")
+            };
+            encoded = encoding.encode(self.header.fields.archive_id, &path, part);
+            &encoded
+        };
+        let found = self.find_named_child(dir, stored_name, needs_data_slice)?;
+        if found.is_none() && self.options.case_insensitive && encoding == NameEncoding::Plain {
+            return self.find_child_ignoring_case(dir, part, needs_data_slice);
+        }
+        Ok(found)
+    }
+
+    /// Finds the child of the directory at `dir` which is stored as `stored_name`
+    fn find_named_child(
+        &self,
+        dir: usize,
+        stored_name: &str,
+        needs_data_slice: bool,
+    ) -> Result<Option<usize>, PfaError> {
+        let dir = self.bucket_for(dir, stored_name)?;
+        let PfaSlice::Catalog { offset, size, .. } = self.catalog.slice(dir)? else {
+            return Err(PfaError::CustomError("bucket is not a directory".into()));
+        };
+        if child_range(dir, offset, size)?.end > self.catalog.len() {
+            return Err(PfaError::CustomError("catalog slice out of bounds".into()));
+        }
+        self.catalog.find_child(dir, stored_name, needs_data_slice)
+    }
+
+    /// Finds the first child of the directory at `dir` whose name matches `part` ignoring case.
+    /// Buckets depend on the exact name, so every bucket of a sharded directory is searched.
+    fn find_child_ignoring_case(
+        &self,
+        dir: usize,
+        part: &str,
+        needs_data_slice: bool,
+    ) -> Result<Option<usize>, PfaError> {
+        let part = part.to_lowercase();
+        let children =
+            directory_children(&self.catalog, &self.header.fields.sharded_directories, dir)?;
+        for index in children {
+            if self.catalog.slice(index)?.is_kind(needs_data_slice)
+                && self.catalog.name(index)?.to_lowercase() == part
+            {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+
+    fn is_sharded(&self, index: usize) -> bool {
+        u32::try_from(index).is_ok_and(|index| {
+            self.header
+                .fields
+                .sharded_directories
+                .binary_search(&index)
+                .is_ok()
+        })
+    }
+
+    /// The bucket of the directory at `index` which holds the entry stored as `name`, or the
+    /// directory itself when it isn't sharded
+    fn bucket_for(&self, index: usize, name: &str) -> Result<usize, PfaError> {
+        let PfaSlice::Catalog { offset, size, .. } = self.catalog.slice(index)? else {
+            return Ok(index);
+        };
+        if !self.is_sharded(index) {
+            return Ok(index);
+        }
+        let bucket =
+            child_range(index, offset, size)?.start as u64 + sharding::shard_bucket(name, size);
+        usize::try_from(bucket)
+            .ok()
+            .filter(|x| *x < self.catalog.len())
+            .ok_or(PfaError::CustomError("catalog slice out of bounds".into()))
+    }
+
+    pub(crate) fn entry_tier(&self, index: usize) -> Option<Tier> {
+        let tiers = &self.header.fields.tiers;
+        let index = u32::try_from(index).ok()?;
+        tiers
+            .binary_search_by_key(&index, |x| x.0)
+            .ok()
+            .map(|x| tiers[x].1)
+    }
+
+    pub(crate) fn entry_compression_skipped(&self, index: usize) -> bool {
+        u32::try_from(index).is_ok_and(|index| {
+            self.header
+                .fields
+                .compression_skipped
+                .binary_search(&index)
+                .is_ok()
+        })
+    }
+
+    /// Whether an entry is hidden by the reader's maximum tier
+    fn is_excluded(&self, index: usize) -> bool {
+        self.options
+            .max_tier
+            .is_some_and(|max| self.entry_tier(index).is_some_and(|tier| tier > max))
+    }
+
+    /// Whether a file or directory exists, without reading it
+    pub fn exists(&self, path: impl Into<PfaPath>) -> Result<bool, PfaError> {
+        Ok(self.find_entry(&path.into())?.is_some())
+    }
+
+    /// Checks many paths at once, such as optional override files. Each directory is only looked
+    /// up once, no matter how many of the paths are in it.
+    pub fn exists_all(&self, paths: &[&str]) -> Result<Vec<bool>, PfaError> {
+        // directory path (with its encoding prefix) to its index
+        let mut directories: HashMap<String, Option<(usize, String)>> = HashMap::new();
+        let mut out = Vec::with_capacity(paths.len());
+        for path in paths {
+            let path = PfaPath::from(*path);
+            let (parts, last) = Self::split_path(&path)?;
+            let mut dir = Some((0, String::from("/")));
+            let mut key = String::from("/");
+            for part in &parts {
+                key.push_str(part);
+                key.push('/');
+                dir = match directories.get(&key) {
+                    Some(cached) => cached.clone(),
+                    None => {
+                        let resolved = match dir {
+                            Some((index, mut prefix)) => self
+                                .find_directory(index, &mut prefix, std::slice::from_ref(part))?
+                                .map(|index| (index, prefix)),
+                            None => None,
+                        };
+                        directories.insert(key.clone(), resolved.clone());
+                        resolved
+                    }
+                };
+            }
+
+            let found = match (dir, last) {
+                (None, _) => None,
+                (Some((index, prefix)), Some(last)) => {
+                    self.find_in_directory(index, &prefix, &last, path.is_directory())?
+                }
+                (Some((index, _)), None) => self
+                    .find_directory(index, &mut String::new(), &[])?
+                    .filter(|x| !self.is_excluded(*x)),
+            };
+            out.push(found.is_some());
+        }
+        Ok(out)
+    }
+
+    /// Tier of a file, None if it doesn't have one
+    pub fn get_tier(&self, path: impl Into<PfaPath>) -> Result<Option<Tier>, PfaError> {
+        Ok(self
+            .find_entry(&path.into())?
+            .and_then(|index| self.entry_tier(index)))
+    }
+
+    /// Walks a path one segment at a time to find where its lookup fails, along with the entries
+    /// next to the missing one which have similar names. Lists directories, so it is meant for
+    /// diagnosing missing files rather than for every lookup.
+    pub fn trace_path(&self, path: impl Into<PfaPath>) -> Result<ResolutionTrace, PfaError> {
+        let path: PfaPath = path.into();
+        let parts = path
+            .get_parts()
+            .iter()
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>();
+        let mut trace = ResolutionTrace {
+            path: path.to_string(),
+            stopped_in: "/".to_string(),
+            matched: vec![],
+            missing: None,
+            wrong_kind: false,
+            nearest: vec![],
+            suggestion: None,
+        };
+        let mut dir = 0;
+        for (i, part) in parts.iter().enumerate() {
+            let wants_directory = i + 1 < parts.len() || path.is_directory();
+            let prefix = trace.stopped_in.clone();
+            let (candidate, other) = match wants_directory {
+                true => (format!("{prefix}{part}/"), format!("{prefix}{part}")),
+                false => (format!("{prefix}{part}"), format!("{prefix}{part}/")),
+            };
+            if let Some(index) = self.find_entry(&PfaPath::from(&candidate[..]))? {
+                trace.matched.push(part.to_string());
+                if wants_directory {
+                    trace.stopped_in = candidate;
+                    dir = index;
+                }
+                continue;
+            }
+
+            trace.missing = Some(part.to_string());
+            trace.wrong_kind = self.find_entry(&PfaPath::from(&other[..]))?.is_some();
+            if self.ensure_listing_allowed().is_err() {
+                break;
+            }
+            let sharded = &self.header.fields.sharded_directories;
+            let names = directory_children(&self.catalog, sharded, dir)?
+                .into_iter()
+                .filter(|x| !self.is_excluded(*x))
+                .map(|x| {
+                    let name = self.catalog.name(x)?;
+                    let name = match name.split_once('#') {
+                        Some((base, tag))
+                            if self.header.fields.variants.iter().any(|x| x == tag) =>
+                        {
+                            base
+                        }
+                        _ => &name,
+                    };
+                    Ok(match self.catalog.slice(x)? {
+                        PfaSlice::Catalog { .. } => format!("{name}/"),
+                        PfaSlice::Data { .. } => name.to_string(),
+                    })
+                })
+                .collect::<Result<Vec<_>, PfaError>>()?;
+            trace.rank_nearest(names);
+
+            let rest = parts[i + 1..]
+                .iter()
+                .map(|x| format!("/{x}"))
+                .collect::<String>();
+            let suffix = if path.is_directory() && !rest.is_empty() {
+                "/"
+            } else {
+                ""
+            };
+            for name in trace.nearest.clone() {
+                if !trace.is_likely_typo(&name) {
+                    continue;
+                }
+                let suggestion = match rest.is_empty() {
+                    true => format!("{prefix}{name}"),
+                    false => format!("{prefix}{}{rest}{suffix}", name.trim_end_matches('/')),
+                };
+                if suggestion != trace.path && self.exists(&suggestion[..])? {
+                    trace.suggestion = Some(suggestion);
+                    break;
+                }
+            }
+            break;
+        }
+        Ok(trace)
+    }
+
+    /// MIME type recorded for a file, None if the file doesn't exist or has none
+    pub fn get_content_type(&self, path: impl Into<PfaPath>) -> Result<Option<String>, PfaError> {
+        Ok(self
+            .find_entry(&path.into())?
+            .and_then(|index| self.header.fields.entry_content_type(index))
+            .map(|x| x.to_string()))
+    }
+
+    /// Where a file came from when the archive was built, None if it wasn't recorded
+    pub fn get_provenance(&self, path: impl Into<PfaPath>) -> Result<Option<Provenance>, PfaError> {
+        Ok(self
+            .find_entry(&path.into())?
+            .and_then(|index| self.header.fields.entry_provenance(index))
+            .cloned())
+    }
+
+    /// Transformations a file's contents went through before it was added, None if they weren't
+    /// recorded
+    pub fn get_transforms(
+        &self,
+        path: impl Into<PfaPath>,
+    ) -> Result<Option<TransformChain>, PfaError> {
+        Ok(self
+            .find_entry(&path.into())?
+            .and_then(|index| self.header.fields.entry_transforms(index))
+            .cloned())
+    }
+
+    /// Cipher a file is encrypted with, see [`DataFlags::cipher`]. None if the file doesn't exist
+    /// or isn't encrypted.
+    pub fn get_cipher(&self, path: impl Into<PfaPath>) -> Result<Option<Cipher>, PfaError> {
+        let path = path.into();
+        match self.find_data_slice(&path)? {
+            Some((flags, ..)) if DataFlags::is_encrypted(flags) => {
+                self.path_cipher(&path).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Cipher recorded for the entry at `path`, AES256-GCM if none was
+    pub(crate) fn path_cipher(&self, path: &PfaPath) -> Result<Cipher, PfaError> {
+        // most archives don't record any, so most reads never need the lookup
+        if self.header.fields.ciphers.is_empty() {
+            return Ok(Cipher::default());
+        }
+        Ok(self
+            .find_entry(path)?
+            .map_or(Cipher::default(), |x| self.header.fields.entry_cipher(x)))
+    }
+
+    /// Every encrypted file, with the id of its key where the archive recorded one. Lets callers
+    /// ask for every key they need before extracting anything.
+    pub fn encrypted_entries(&self) -> Result<Vec<PfaEncryptedEntry>, PfaError> {
+        Ok(self
+            .collect_file_slices("/")?
+            .into_iter()
+            .filter(|x| DataFlags::is_encrypted(x.flags))
+            .map(|x| PfaEncryptedEntry {
+                key_id: self.header.fields.entry_key_id(x.index),
+                path: x.path,
+            })
+            .collect())
+    }
+
+    /// Distinct ids of the keys needed to read every encrypted file, and whether there are
+    /// encrypted files whose key wasn't recorded
+    pub fn required_keys(&self) -> Result<(Vec<KeyId>, bool), PfaError> {
+        let mut keys = vec![];
+        let mut unknown = false;
+        for entry in self.encrypted_entries()? {
+            match entry.key_id {
+                Some(key_id) if !keys.contains(&key_id) => keys.push(key_id),
+                Some(_) => {}
+                None => unknown = true,
+            }
+        }
+        Ok((keys, unknown))
+    }
+
+    /// Metadata of a file or directory, None if it doesn't exist
+    pub fn stat(&self, path: impl Into<PfaPath>) -> Result<Option<PfaEntryInfo>, PfaError> {
+        let path = path.into();
+        let Some(index) = self.find_entry(&path)? else {
+            return Ok(None);
+        };
+        Ok(Some(match self.catalog.slice(index)? {
+            PfaSlice::Data { flags, size, .. } => self.entry_info(index, path, false, size, flags),
+            PfaSlice::Catalog { .. } => {
+                let sharded = &self.header.fields.sharded_directories;
+                let children = directory_children(&self.catalog, sharded, index)?;
+                self.entry_info(index, path, true, children.len() as u64, 0)
+            }
+        }))
+    }
+
+    /// Metadata of a file, see [`Self::stat`]. None if the file doesn't exist, or is a
+    /// directory.
+    pub fn get_file_info(
+        &self,
+        path: impl Into<PfaPath>,
+    ) -> Result<Option<PfaEntryInfo>, PfaError> {
+        Ok(self.stat(path)?.filter(|x| !x.is_directory))
+    }
+
+    fn entry_info(
+        &self,
+        index: usize,
+        path: PfaPath,
+        is_directory: bool,
+        size: u64,
+        flags: u8,
+    ) -> PfaEntryInfo {
+        let fields = &self.header.fields;
+        PfaEntryInfo {
+            path,
+            is_directory,
+            size,
+            contents_size: match is_directory {
+                true => None,
+                false => self.contents_size(index, flags, size),
+            },
+            flags,
+            content_type: fields.entry_content_type(index).map(|x| x.to_string()),
+            tier: self.entry_tier(index),
+        }
+    }
+
+    /// Size of the contents of the file at `index` if it's known without reading its data
+    pub(crate) fn contents_size(&self, index: usize, flags: u8, stored_size: u64) -> Option<u64> {
+        if let Some((member, _)) = self.header.fields.solid_member(index) {
+            return Some(member.size);
+        }
+        if DataFlags::is_unprocessed(flags) {
+            return Some(stored_size);
+        }
+        self.header.fields.entry_original_size(index)
+    }
+
+    /// Paths of every file under `path` whose content type matches `pattern`, such as
+    /// `text/html` or `image/*`. Files without a content type never match.
+    pub fn find_files_by_content_type(
+        &self,
+        path: impl Into<PfaPath>,
+        pattern: &str,
+    ) -> Result<Vec<PfaPath>, PfaError> {
+        Ok(self
+            .collect_file_slices(path)?
+            .into_iter()
+            .filter(|x| {
+                self.header
+                    .fields
+                    .entry_content_type(x.index)
+                    .is_some_and(|x| content_type_matches(pattern, x))
+            })
+            .map(|x| x.path)
+            .collect())
+    }
+
+    /// Whether a file was stored uncompressed because compressing it didn't reduce its size, in
+    /// which case repacking it doesn't need to try again
+    pub fn is_compression_skipped(&self, path: impl Into<PfaPath>) -> Result<bool, PfaError> {
+        Ok(self
+            .find_entry(&path.into())?
+            .is_some_and(|index| self.entry_compression_skipped(index)))
+    }
+
+    /// Whether a file's contents are stored in a solid block along with other small files, see
+    /// [`PfaBuilder::set_solid_blocks`](crate::builder::PfaBuilder::set_solid_blocks)
+    pub fn is_in_solid_block(&self, path: impl Into<PfaPath>) -> Result<bool, PfaError> {
+        Ok(self
+            .find_entry(&path.into())?
+            .is_some_and(|index| self.header.fields.solid_member(index).is_some()))
+    }
+
+    /// Returns the (flags, offset, size) of a file's data slice
+    pub(crate) fn find_data_slice(
+        &self,
+        path: &PfaPath,
+    ) -> Result<Option<(u8, u64, u64)>, PfaError> {
+        let Some(index) = self.find_entry(path)? else {
+            return Ok(None);
+        };
+        match self.catalog.slice(index)? {
+            PfaSlice::Data {
+                flags,
+                offset,
+                size,
+            } => Ok(Some((flags, offset, size))),
+            PfaSlice::Catalog { .. } => Ok(None),
+        }
+    }
+
+    /// Lists every file and directory within the root, depth first in catalog order, without
+    /// reading any data. Variants are listed as separate files named `{name}#{variant}`.
+    pub fn entries(&self) -> Result<PfaEntries<'_, T>, PfaError> {
+        if self.catalog.len() == 0 {
+            self.ensure_listing_allowed()?;
+            return Ok(PfaEntries {
+                reader: self,
+                stack: vec![],
+                max_depth: usize::MAX,
+            });
+        }
+        self.entries_within(0, PfaPath::from("/"), usize::MAX)
+    }
+
+    /// Lists the entries within the directory at catalog index `index`, leaving out the contents
+    /// of directories at `max_depth`
+    pub(crate) fn entries_within(
+        &self,
+        index: usize,
+        path: PfaPath,
+        max_depth: usize,
+    ) -> Result<PfaEntries<'_, T>, PfaError> {
+        self.ensure_listing_allowed()?;
+        let depth = path.depth();
+        let mut entries = PfaEntries {
+            reader: self,
+            stack: vec![(index, path, depth)],
+            max_depth,
+        };
+        // the directory itself isn't listed
+        entries.next().transpose()?;
+        Ok(entries)
+    }
+
+    pub(crate) fn collect_file_slices(
+        &self,
+        path: impl Into<PfaPath>,
+    ) -> Result<Vec<PfaFileSlice>, PfaError> {
+        let path: PfaPath = path.into();
+        let mut out = vec![];
+        let Some(index) = self.find_entry(&path)? else {
+            return Ok(out);
+        };
+        if let PfaSlice::Catalog { .. } = self.catalog.slice(index)? {
+            self.ensure_listing_allowed()?;
+        }
+
+        let sharded = &self.header.fields.sharded_directories;
+        let depth = path.depth();
+        let mut stack = vec![(index, path, depth)];
+        while let Some((index, path, depth)) = stack.pop() {
+            match self.catalog.slice(index)? {
+                PfaSlice::Data {
+                    flags,
+                    offset,
+                    size,
+                } => out.push(PfaFileSlice {
+                    path,
+                    index,
+                    flags,
+                    offset,
+                    size,
+                }),
+                PfaSlice::Catalog { .. } => {
+                    self.check_depth(&path, depth)?;
+                    for child in directory_children(&self.catalog, sharded, index)?
+                        .into_iter()
+                        .rev()
+                    {
+                        let child_path = self
+                            .catalog
+                            .child_path(&path, child)?
+                            .ok_or(PfaError::MalformedPathError)?;
+                        stack.push((child, child_path, depth + 1));
+                    }
+                }
+            }
+        }
+        out.retain(|x| !self.is_excluded(x.index));
+        Ok(out)
+    }
+
+    /// Reads and decodes the data of the file at `path`, which may be stored in a solid block
+    pub(crate) fn read_data_slice_contents(
+        &mut self,
+        path: &PfaPath,
+        flags: u8,
+        offset: u64,
+        size: u64,
+        key: Option<[u8; 32]>,
+    ) -> Result<Bytes, PfaError> {
+        // solid members have empty data slices, so other files never need the lookup
+        if size == 0 && !self.header.fields.solid_members.is_empty() {
+            let member = self
+                .find_entry(path)?
+                .and_then(|x| self.header.fields.solid_member(x));
+            if let Some((member, block)) = member {
+                return self.read_solid_member(&path.to_string(), member, block);
+            }
+        }
+        let limit = self.max_decompressed_size();
+        let entry = path.to_string();
+        let mut buf = read_stored_data(
+            &mut self.data,
+            self.data_idx as u64,
+            offset,
+            size,
+            &entry,
+            limit,
+        )?;
+
+        DataFlags::check_codec(flags, &entry)?;
+        let cipher = match key {
+            Some(_) => self.path_cipher(path)?,
+            None => Cipher::default(),
+        };
+        DataFlags::unprocess_contents_from_flags(
+            flags,
+            &mut buf,
+            key,
+            cipher,
+            limit,
+            self.codecs(),
+        )?;
+        Ok(buf.into())
+    }
+
+    pub fn get_path(
+        &mut self,
+        path: impl Into<PfaPath>,
+        key: Option<[u8; 32]>,
+    ) -> Result<Option<PfaPathContents>, PfaError> {
+        let path: PfaPath = path.into();
+        self.consistent_read(|reader| reader.read_path(path.clone(), key))
+    }
+
+    fn read_path(
+        &mut self,
+        path: PfaPath,
+        key: Option<[u8; 32]>,
+    ) -> Result<Option<PfaPathContents>, PfaError> {
+        let Some(index) = self.find_entry(&path)? else {
+            if self.options.trace_not_found {
+                return Err(PfaError::NotFound(Box::new(self.trace_path(path)?)));
+            }
+            return Ok(None);
+        };
+
+        match self.catalog.slice(index)? {
+            PfaSlice::Data {
+                flags,
+                offset,
+                size,
+            } => {
+                let contents = self.read_data_slice_contents(&path, flags, offset, size, key)?;
+                Ok(Some(PfaPathContents::File(PfaFileContents::new(
+                    path, contents,
+                ))))
+            }
+            PfaSlice::Catalog { .. } => {
+                self.ensure_listing_allowed()?;
+                let children = directory_children(
+                    &self.catalog,
+                    &self.header.fields.sharded_directories,
+                    index,
+                )?;
+
+                let mut contents = children
+                    .into_iter()
+                    .filter(|index| !self.is_excluded(*index))
+                    .map(|x| {
+                        self.catalog
+                            .child_path(&path, x)?
+                            .ok_or(PfaError::MalformedPathError)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                if !self.header.fields.variants.is_empty() {
+                    contents = self.collapse_variants(contents);
+                }
+
+                Ok(Some(PfaPathContents::Directory(PfaDirectoryContents {
+                    path,
+                    contents,
+                })))
+            }
+        }
+    }
+
+    pub fn get_file(
+        &mut self,
+        path: impl Into<PfaPath>,
+        key: Option<[u8; 32]>,
+    ) -> Result<Option<PfaFileContents>, PfaError> {
+        match self.get_path(path, key) {
+            Ok(Some(PfaPathContents::File(f))) => Ok(Some(f)),
+            Err(e) => Err(e),
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads a file's data without processing it. Files in a solid block have no data of their
+    /// own, so their contents are returned, as if stored as is with no flags.
+    pub fn get_stored_file(
+        &mut self,
+        path: impl Into<PfaPath>,
+    ) -> Result<Option<PfaStoredFile>, PfaError> {
+        let path: PfaPath = path.into();
+        self.consistent_read(|reader| reader.read_stored_file(path.clone()))
+    }
+
+    fn read_stored_file(&mut self, path: PfaPath) -> Result<Option<PfaStoredFile>, PfaError> {
+        let Some((flags, offset, size)) = self.find_data_slice(&path)? else {
+            return Ok(None);
+        };
+        let member = self
+            .find_entry(&path)?
+            .and_then(|x| self.header.fields.solid_member(x));
+        if let Some((member, block)) = member {
+            let contents = self.read_solid_member(&path.to_string(), member, block)?;
+            return Ok(Some(PfaStoredFile {
+                path,
+                flags: 0,
+                contents: contents.into(),
+            }));
+        }
+
+        let limit = self.max_decompressed_size();
+        let contents = read_stored_data(
+            &mut self.data,
+            self.data_idx as u64,
+            offset,
+            size,
+            &path.to_string(),
+            limit,
+        )?;
+        Ok(Some(PfaStoredFile {
+            path,
+            flags,
+            contents,
+        }))
+    }
+
+    /// Like [`Self::get_stored_file`], but also parses the headers left by each processing stage
+    /// without applying them
+    pub fn get_file_raw(
+        &mut self,
+        path: impl Into<PfaPath>,
+    ) -> Result<Option<PfaRawFile>, PfaError> {
+        let path: PfaPath = path.into();
+        let Some((_, offset, _)) = self.find_data_slice(&path)? else {
+            return Ok(None);
+        };
+        let Some(stored) = self.get_stored_file(path)? else {
+            return Ok(None);
+        };
+        let headers = DataFlags::parse_stage_headers(stored.flags, &stored.contents);
+        Ok(Some(PfaRawFile {
+            stored,
+            offset: self.data_idx as u64 + offset,
+            headers,
+        }))
+    }
+
+    /// Reads `len` bytes of a file's contents starting at `offset`, fewer when the file ends
+    /// before that. Only the range is read when the file is stored as is, other files are read
+    /// and decoded whole. Encrypted files can be read with [`Self::read_encrypted_range`].
+    pub fn read_range(
+        &mut self,
+        path: impl Into<PfaPath>,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>, PfaError> {
+        let path: PfaPath = path.into();
+        self.consistent_read(|reader| reader.read_range_once(&path, offset, len))
+    }
+
+    fn read_range_once(
+        &mut self,
+        path: &PfaPath,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>, PfaError> {
+        let Some((flags, data_offset, size)) = self.find_data_slice(path)? else {
+            return Ok(None);
+        };
+        if !DataFlags::is_unprocessed(flags) {
+            let contents = self.read_data_slice_contents(path, flags, data_offset, size, None)?;
+            let start = (offset as usize).min(contents.len());
+            let end = start.saturating_add(len as usize).min(contents.len());
+            return Ok(Some(contents[start..end].to_vec()));
+        }
+
+        let start = offset.min(size);
+        let end = start.saturating_add(len).min(size);
+        self.data.seek(std::io::SeekFrom::Start(
+            self.data_idx as u64 + data_offset + start,
+        ))?;
+        let mut contents = vec![0; (end - start) as usize];
+        self.data.read_exact(&mut contents)?;
+        Ok(Some(contents))
+    }
+
+    /// The first `max_bytes` of a file's contents, for previews and sniffing content types.
+    /// Only as much as those bytes need is read from files which [`Self::open_file`] reads as
+    /// they are needed, other files are decoded whole and cut short. `key` is only used for
+    /// encrypted files.
+    pub fn get_file_preview(
+        &mut self,
+        path: impl Into<PfaPath>,
+        max_bytes: u64,
+        key: Option<[u8; 32]>,
+    ) -> Result<Option<Vec<u8>>, PfaError> {
+        let path: PfaPath = path.into();
+        self.consistent_read(|reader| {
+            let Some(file) = reader.open_file(path.clone(), key)? else {
+                return Ok(None);
+            };
+            let mut preview = vec![];
+            file.take(max_bytes)
+                .read_to_end(&mut preview)
+                .map_err(|e| e.downcast::<PfaError>().unwrap_or_else(PfaError::IOError))?;
+            Ok(Some(preview))
+        })
+    }
+
+    /// Decrypts a byte range of an encrypted file. Only the frames holding the range are read
+    /// and decrypted when the file was encrypted in frames without compression, error correction
+    /// or size padding, other files are decrypted whole.
+    pub fn read_encrypted_range(
+        &mut self,
+        path: impl Into<PfaPath>,
+        range: Range<u64>,
+        key: [u8; 32],
+    ) -> Result<Option<Vec<u8>>, PfaError> {
+        let path: PfaPath = path.into();
+        self.consistent_read(|reader| reader.read_encrypted_range_once(&path, range.clone(), key))
+    }
+
+    fn read_encrypted_range_once(
+        &mut self,
+        path: &PfaPath,
+        range: Range<u64>,
+        key: [u8; 32],
+    ) -> Result<Option<Vec<u8>>, PfaError> {
+        let Some((flags, offset, size)) = self.find_data_slice(path)? else {
+            return Ok(None);
+        };
+        if !DataFlags::has_encryption_frames(flags)
+            || DataFlags::is_compressed(flags)
+            || DataFlags::has_error_correction(flags)
+            || DataFlags::has_size_padding(flags)
+        {
+            let contents = self.read_data_slice_contents(path, flags, offset, size, Some(key))?;
+            let end = (range.end as usize).min(contents.len());
+            let start = (range.start as usize).min(end);
+            return Ok(Some(contents[start..end].to_vec()));
+        }
+
+        let start = self.data_idx as u64 + offset;
+        let mut header = [0; EncryptionFrames::HEADER_SIZE];
+        self.data.seek(std::io::SeekFrom::Start(start))?;
+        self.data.read_exact(&mut header)?;
+        let frames = EncryptionFrames::parse(&header, size)?.with_cipher(self.path_cipher(path)?);
+        let stored_range = frames.stored_range(range.clone());
+        let mut stored = vec![0; (stored_range.end - stored_range.start) as usize];
+        self.data
+            .seek(std::io::SeekFrom::Start(start + stored_range.start))?;
+        self.data.read_exact(&mut stored)?;
+        frames.decrypt_range(&key, &stored, range).map(Some)
+    }
+
+    pub fn get_directory(
+        &mut self,
+        path: impl Into<PfaPath>,
+        key: Option<[u8; 32]>,
+    ) -> Result<Option<PfaDirectoryContents>, PfaError> {
+        let mut path: PfaPath = path.into();
+        if !path.is_directory() {
+            path = path.append("").ok_or(PfaError::MalformedPathError)?; // append empty part to make it a directory
+        }
+
+        match self.get_path(path, key) {
+            Ok(Some(PfaPathContents::Directory(f))) => Ok(Some(f)),
+            Err(e) => Err(e),
+            _ => Ok(None),
+        }
+    }
+
+    /// Warning: this function will only successfully traverse non-encrypted files
+    /// Files are visited depth first, see [`TraversalOrder`](crate::reader::TraversalOrder)
+    /// The callback may capture and mutate local state, such as a counter or a progress bar
+    pub fn traverse_files(
+        &mut self,
+        path: impl Into<PfaPath>,
+        mut callback: impl FnMut(PfaFileContents),
+    ) {
+        let _ = self.traverse_files_with(path, &TraversalOptions::new(), |file| {
+            callback(file);
+            Ok::<_, ()>(())
+        });
+    }
+
+    /// Warning: this function will only successfully traverse non-encrypted files
+    /// Files are visited depth first, see [`TraversalOrder`](crate::reader::TraversalOrder)
+    /// Callback should return Err to cancel
+    /// Returns first propagated error, or () if there wasn't any
+    pub fn traverse_files_cancelable<E>(
+        &mut self,
+        path: impl Into<PfaPath>,
+        callback: impl FnMut(PfaFileContents) -> Result<(), E>,
+    ) -> Result<(), E> {
+        self.traverse_files_with(path, &TraversalOptions::new(), callback)
+    }
+
+    fn read_sized_buffer(buf: &mut impl Read) -> Result<Vec<u8>, PfaError> {
+        let size = buf.read_u8()?;
+        let mut str_buf = vec![0; size.into()];
+        buf.read_exact(&mut str_buf)?;
+        Ok(str_buf)
+    }
+
+    fn read_sized_string(buf: &mut impl Read) -> Result<String, PfaError> {
+        let str_buf = Self::read_sized_buffer(buf)?;
+        Ok(String::from_utf8(str_buf)?)
+    }
+
+    /// Reads the header, refusing to decompress the extra data to more than `limit` bytes
+    fn read_header(buf: &mut impl Read, limit: Option<u64>) -> Result<PfaHeader, PfaError> {
+        let mut watermark = [0; 3];
+        buf.read_exact(&mut watermark)?;
+        if &watermark != WATERMARK {
             return Err(PfaError::CustomError("invalid watermark".into()));
         }
         let version = buf.read_u8()?;
+        if version == 0 || version > LATEST_VERSION {
+            return Err(PfaError::CustomError(format!(
+                "unsupported archive version: {version}"
+            )));
+        }
         let name = Self::read_sized_string(buf)?;
-        let extra_data = Self::read_sized_buffer(buf)?;
+
+        let (extra_data, fields, generation_idx) = if version == 1 {
+            (Self::read_sized_buffer(buf)?, HeaderFields::default(), None)
+        } else {
+            let extra_data_len = buf.read_u64::<LittleEndian>()?;
+            let mut extra_data = vec![];
+            buf.take(extra_data_len).read_to_end(&mut extra_data)?;
+            if extra_data.len() as u64 != extra_data_len {
+                return Err(PfaError::CustomError("extra data is truncated".into()));
+            }
+            let fields = HeaderFields::read(buf)?;
+            // {watermark}{version}{name_len}{name}{extra_data_len}{extra_data}
+            let fields_idx = 3 + 1 + 1 + name.len() as u64 + 8 + extra_data_len;
+            let generation_idx = fields.generation_offset.map(|x| fields_idx + x);
+            if fields.extra_data_compressed {
+                extra_data = DataFlags::decompress_lz4(&extra_data, limit)?;
+            }
+            (extra_data, fields, generation_idx)
+        };
 
         let header = PfaHeader {
             version,
             name,
             extra_data,
+            fields,
+            generation_idx,
         };
 
         Ok(header)
@@ -0,0 +1,104 @@
+use std::io::{Read, Seek};
+
+use crate::{
+    builder::PfaBuilder,
+    reader::{
+        pfa_reader::{PfaFileSlice, PfaPath},
+        PfaReader,
+    },
+    shared::{DataFlags, Tier},
+    PfaError,
+};
+
+impl<T: Read + Seek> PfaReader<T> {
+    /// Creates a builder holding every file up to `max_tier`, along with files without a tier.
+    /// Data is copied as it is stored, so nothing is decrypted. Only files in solid blocks are
+    /// decompressed, to be grouped into new blocks.
+    pub fn strip_tiers(&mut self, max_tier: Tier) -> Result<PfaBuilder, PfaError> {
+        let mut builder = PfaBuilder::new(self.get_name());
+        builder.set_codecs(self.codecs().clone());
+        builder.set_entry_hashes(self.has_entry_hashes());
+        builder.add_variant_names(self.get_variants().iter().cloned());
+        if let Some((max_file_size, block_size)) = self.solid_block_sizes() {
+            builder.set_solid_blocks(max_file_size, block_size);
+        }
+
+        for slice in self.collect_file_slices("/")? {
+            let tier = self.entry_tier(slice.index);
+            if tier.is_some_and(|x| x > max_tier) {
+                continue;
+            }
+            if let Some((member, block)) = self.solid_member(&slice) {
+                self.copy_solid_member(&slice, member, block, &mut builder)?;
+                continue;
+            }
+
+            self.data.seek(std::io::SeekFrom::Start(
+                self.data_idx as u64 + slice.offset,
+            ))?;
+            let mut contents = vec![0; slice.size as usize];
+            self.data.read_exact(&mut contents)?;
+
+            builder.add_stored_file(&slice.path.to_string(), contents, slice.flags)?;
+            self.copy_entry_metadata(&slice, &mut builder)?;
+        }
+
+        Ok(builder)
+    }
+
+    /// Copies the metadata of the file at `path`, such as its tier, content type, key id, cipher
+    /// and contents size, to the file at the same path in `builder`, which files copied with
+    /// [`PfaBuilder::add_stored_file`] need to be read the same way. Returns false if the archive
+    /// has no file at `path`.
+    pub fn copy_file_metadata(
+        &self,
+        path: impl Into<PfaPath>,
+        builder: &mut PfaBuilder,
+    ) -> Result<bool, PfaError> {
+        let path: PfaPath = path.into();
+        if !path.is_file() {
+            return Ok(false);
+        }
+        let Some(slice) = self.collect_file_slices(path)?.pop() else {
+            return Ok(false);
+        };
+        self.copy_entry_metadata(&slice, builder)?;
+        Ok(true)
+    }
+
+    /// Copies the tier, content type, provenance, transforms, compression skipped marker, key
+    /// id, cipher and contents size of a file to the file at the same path in `builder`
+    pub(crate) fn copy_entry_metadata(
+        &self,
+        slice: &PfaFileSlice,
+        builder: &mut PfaBuilder,
+    ) -> Result<(), PfaError> {
+        let path = slice.path.to_string();
+        let fields = self.get_header_fields();
+        if let Some(tier) = self.entry_tier(slice.index) {
+            builder.set_tier(&path, tier)?;
+        }
+        if self.entry_compression_skipped(slice.index) {
+            builder.set_compression_skipped(&path)?;
+        }
+        if let Some(content_type) = fields.entry_content_type(slice.index) {
+            builder.set_content_type(&path, content_type)?;
+        }
+        if let Some(provenance) = fields.entry_provenance(slice.index) {
+            builder.set_provenance(&path, provenance.clone())?;
+        }
+        if let Some(transforms) = fields.entry_transforms(slice.index) {
+            builder.set_transforms(&path, transforms.clone())?;
+        }
+        if let Some(key_id) = fields.entry_key_id(slice.index) {
+            builder.set_key_id(&path, key_id)?;
+        }
+        if DataFlags::is_encrypted(slice.flags) {
+            builder.set_cipher(&path, fields.entry_cipher(slice.index))?;
+        }
+        if let Some(size) = fields.entry_original_size(slice.index) {
+            builder.set_original_size(&path, size)?;
+        }
+        Ok(())
+    }
+}
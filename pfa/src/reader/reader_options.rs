@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+
+use crate::{
+    reader::retry_reader::RetryPolicy,
+    shared::{CodecRegistry, Tier},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct PfaReaderOptions {
+    pub(crate) retry: RetryPolicy,
+    pub(crate) variant_preference: Vec<String>,
+    pub(crate) max_tier: Option<Tier>,
+    pub(crate) strict: bool,
+    pub(crate) trace_not_found: bool,
+    /// None for [`DEFAULT_DEPTH_LIMIT`](crate::shared::DEFAULT_DEPTH_LIMIT)
+    pub(crate) depth_limit: Option<usize>,
+    pub(crate) case_insensitive: bool,
+    pub(crate) lazy_catalog: bool,
+    pub(crate) follow_generations: bool,
+    pub(crate) catalog_cache: Option<PathBuf>,
+    pub(crate) max_decompressed_size: Option<u64>,
+    pub(crate) codecs: CodecRegistry,
+}
+
+impl PfaReaderOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Variant tags to look for, in order of preference, when reading a file which has variants.
+    /// The file without a tag is used when none of them exist.
+    pub fn variant_preference(mut self, variants: Vec<String>) -> Self {
+        self.variant_preference = variants;
+        self
+    }
+
+    /// Hides files with a higher tier. Files without a tier are always visible.
+    pub fn max_tier(mut self, max_tier: Tier) -> Self {
+        self.max_tier = Some(max_tier);
+        self
+    }
+
+    /// Refuses to open archives with entries using flag bits this version doesn't understand,
+    /// instead of reading them as if the bits weren't set
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Makes reading a missing path fail with [`PfaError::NotFound`](crate::PfaError::NotFound),
+    /// holding a [`ResolutionTrace`](crate::reader::ResolutionTrace) of how far the lookup got,
+    /// instead of returning None. Meant for development builds, since tracing lists directories.
+    pub fn trace_not_found(mut self, trace_not_found: bool) -> Self {
+        self.trace_not_found = trace_not_found;
+        self
+    }
+
+    /// Deepest directory nesting walked when listing or traversing, defaults to
+    /// [`DEFAULT_DEPTH_LIMIT`](crate::shared::DEFAULT_DEPTH_LIMIT). Listing deeper directories fails with
+    /// [`PfaError::DepthLimitError`](crate::PfaError::DepthLimitError), and traversals skip them.
+    pub fn depth_limit(mut self, depth_limit: usize) -> Self {
+        self.depth_limit = Some(depth_limit);
+        self
+    }
+
+    /// Resolves paths whose case doesn't match the archive's, so `/Data/Config.TXT` finds
+    /// `/data/config.txt`. Names which match exactly are still preferred, otherwise the first
+    /// entry in catalog order whose name matches ignoring case is used. Has no effect on archives
+    /// with hashed names.
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Keeps the catalog as it is stored when the archive is opened, and only decodes the
+    /// entries of directories as paths are resolved through them, which makes opening archives
+    /// with very many entries faster and uses less memory. The checks and warnings made about the
+    /// whole catalog when the archive is opened are only made once
+    /// [`PfaReader::preload_catalog`](crate::reader::PfaReader::preload_catalog) is called.
+    /// Strict readers still fail on any entry they decode which they can't read correctly.
+    pub fn lazy_catalog(mut self, lazy_catalog: bool) -> Self {
+        self.lazy_catalog = lazy_catalog;
+        self
+    }
+
+    /// For archives which are updated in place while they're read, such as by
+    /// [`PfaEditor`](crate::writer::pfa_editor::PfaEditor), and have a generation (see
+    /// [`PfaBuilder::set_generation`](crate::builder::PfaBuilder::set_generation)). Reading a
+    /// file or directory checks the generation before and after the read, and when it changed,
+    /// reopens the archive and reads it again, so contents never mix data from two generations.
+    /// Reads wait while an update is in progress. Archives opened by path are also reopened
+    /// when an updater replaces the file rather than changing it in place. Methods which only
+    /// look at the catalog use the generation read last, and files read as they are needed by
+    /// [`PfaReader::open_file`](crate::reader::PfaReader::open_file) fail once the archive is
+    /// updated.
+    pub fn follow_generations(mut self, follow_generations: bool) -> Self {
+        self.follow_generations = follow_generations;
+        self
+    }
+
+    /// Keeps the decoded catalog and its lookup index in a sidecar file at `path`, so archives
+    /// opened often, such as when a game starts, skip decoding the catalog. The cache is keyed by
+    /// a digest of the stored catalog, and is written again whenever the catalog no longer
+    /// matches it, so it never has to be removed by hand. The archive is still checked, and
+    /// reports the same warnings, as without a cache. Has no effect with a
+    /// [lazy catalog](Self::lazy_catalog).
+    pub fn catalog_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.catalog_cache = Some(path.into());
+        self
+    }
+
+    /// Refuses to decompress file contents, solid blocks or extra data to more than `bytes`,
+    /// failing with [`PfaError::DecompressionLimitError`](crate::PfaError::DecompressionLimitError)
+    /// instead, so an archive holding a small file which expands to gigabytes can't exhaust
+    /// memory. LZ4 and zstd data is checked before it's decompressed, data compressed with a
+    /// [custom codec](crate::shared::Codec) only once the codec returns it. Stored data larger than
+    /// `bytes`, such as that of a large file stored uncompressed, is refused before it's read.
+    pub fn max_decompressed_size(mut self, bytes: u64) -> Self {
+        self.max_decompressed_size = Some(bytes);
+        self
+    }
+
+    /// Custom codecs files compressed with one can be decoded with. Reading a file compressed
+    /// with a custom codec whose id isn't in `codecs` fails.
+    pub fn codecs(mut self, codecs: CodecRegistry) -> Self {
+        self.codecs = codecs;
+        self
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt::Display;
+
+/// A problem found while opening an archive which doesn't stop it from being read, see
+/// [`PfaReader::warnings`](crate::reader::PfaReader::warnings). Entries are identified by their
+/// index in the catalog and their name, since their paths aren't known until the tree is walked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(tag = "kind", rename_all = "snake_case")
+)]
+pub enum ReaderWarning {
+    /// The entry's name isn't valid UTF-8, invalid bytes were replaced with U+FFFD. Strict
+    /// readers fail instead.
+    LossyName { index: usize, name: String },
+    /// The file's data extends past the end of the archive, so reading it will fail
+    DataOutOfBounds {
+        index: usize,
+        name: String,
+        offset: u64,
+        size: u64,
+    },
+    /// The directory's entries extend past the end of the catalog, so listing it will fail
+    CatalogOutOfBounds { index: usize, name: String },
+    /// The entry uses flag bits this version doesn't understand, so it may be decoded
+    /// incorrectly. Strict readers fail instead.
+    UnknownFlagBits {
+        index: usize,
+        name: String,
+        bits: u8,
+    },
+    /// Bytes of the data section which no entry refers to, left by editing the archive in place
+    /// or by tampering. The offset is relative to the start of the data section.
+    UnreferencedData { offset: u64, size: u64 },
+}
+
+impl Display for ReaderWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReaderWarning::LossyName { index, name } => {
+                write!(f, "entry {index} has an invalid utf8 name, read as '{name}'")
+            }
+            ReaderWarning::DataOutOfBounds {
+                index,
+                name,
+                offset,
+                size,
+            } => write!(
+                f,
+                "data of '{name}' (entry {index}) at {offset} with size {size} extends past the end of the archive"
+            ),
+            ReaderWarning::CatalogOutOfBounds { index, name } => write!(
+                f,
+                "entries of '{name}/' (entry {index}) extend past the end of the catalog"
+            ),
+            ReaderWarning::UnknownFlagBits { index, name, bits } => write!(
+                f,
+                "'{name}' (entry {index}) uses unknown flag bits {bits:#010b}"
+            ),
+            ReaderWarning::UnreferencedData { offset, size } => write!(
+                f,
+                "{size} bytes of data at {offset} aren't referenced by any entry"
+            ),
+        }
+    }
+}
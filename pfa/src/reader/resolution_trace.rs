@@ -0,0 +1,98 @@
+use std::fmt::Display;
+
+/// How far the lookup of a path got, see
+/// [`PfaReader::trace_path`](crate::reader::PfaReader::trace_path)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolutionTrace {
+    pub path: String,
+    /// Path of the directory the walk stopped in, ending with '/'
+    pub stopped_in: String,
+    /// Segments which matched, starting at the root
+    pub matched: Vec<String>,
+    /// First segment which didn't match, None if the whole path resolved
+    pub missing: Option<String>,
+    /// An entry named like the missing segment exists, but it is a directory where a file was
+    /// requested or the other way around
+    pub wrong_kind: bool,
+    /// Entries of the directory the walk stopped in with the closest names, closest first.
+    /// Directory names end with '/'.
+    pub nearest: Vec<String>,
+    /// An existing path which differs from the requested one by a likely typo
+    pub suggestion: Option<String>,
+}
+
+impl ResolutionTrace {
+    /// Number of nearest entries kept
+    const NEAREST: usize = 3;
+
+    pub fn is_found(&self) -> bool {
+        self.missing.is_none()
+    }
+
+    /// Ranks `names` by how close they are to the missing segment, ignoring case
+    pub(crate) fn rank_nearest(&mut self, names: impl IntoIterator<Item = String>) {
+        let Some(missing) = &self.missing else {
+            return;
+        };
+        let missing = missing.to_lowercase();
+        let mut ranked = names
+            .into_iter()
+            .map(|name| {
+                let distance = edit_distance(&missing, name.trim_end_matches('/'));
+                (distance, name)
+            })
+            .collect::<Vec<_>>();
+        ranked.sort();
+        ranked.dedup();
+        self.nearest = ranked
+            .into_iter()
+            .take(Self::NEAREST)
+            .map(|(_, name)| name)
+            .collect();
+    }
+
+    /// Whether a name is close enough to the missing segment to be suggested
+    pub(crate) fn is_likely_typo(&self, name: &str) -> bool {
+        let Some(missing) = &self.missing else {
+            return false;
+        };
+        let distance = edit_distance(&missing.to_lowercase(), name.trim_end_matches('/'));
+        distance <= missing.chars().count() / 3 + 1
+    }
+}
+
+impl Display for ResolutionTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some(missing) = &self.missing else {
+            return write!(f, "'{}' exists", self.path);
+        };
+        write!(f, "'{}' not found, ", self.path)?;
+        if self.wrong_kind {
+            write!(f, "'{missing}' in '{}' has the wrong type", self.stopped_in)?;
+        } else {
+            write!(f, "no entry '{missing}' in '{}'", self.stopped_in)?;
+        }
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, ", did you mean '{suggestion}'?")?;
+        } else if !self.nearest.is_empty() {
+            write!(f, ", nearest entries: {}", self.nearest.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Levenshtein distance, where `a` is already lowercase and `b` is compared lowercased
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let mut previous = (0..=a.len()).collect::<Vec<_>>();
+    let mut current = vec![0; a.len() + 1];
+    for (i, cb) in b.chars().flat_map(char::to_lowercase).enumerate() {
+        current[0] = i + 1;
+        for (j, ca) in a.iter().enumerate() {
+            let substitution = previous[j] + usize::from(*ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[a.len()]
+}
@@ -0,0 +1,137 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Seek},
+};
+
+use crate::{
+    reader::{pfa_reader::PfaPathContents, PfaReader},
+    PfaError,
+};
+
+/// A file read with [`PfaResolver::resolve_file_with_content_type`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedFile {
+    pub contents: Vec<u8>,
+    pub content_type: Option<String>,
+}
+
+struct Mount<T: Read + Seek> {
+    reader: PfaReader<T>,
+    key: Option<[u8; 32]>,
+}
+
+/// Resolves `pfa://{archive}/{path}` URLs to entries of mounted archives, for tools which address
+/// assets by URL such as custom scheme handlers of web views
+pub struct PfaResolver<T: Read + Seek> {
+    mounts: HashMap<String, Mount<T>>,
+}
+
+impl<T: Read + Seek> Default for PfaResolver<T> {
+    fn default() -> Self {
+        Self {
+            mounts: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Read + Seek> PfaResolver<T> {
+    pub const SCHEME: &'static str = "pfa://";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mounts an archive under its own name, replacing any archive mounted under the same name
+    pub fn mount(&mut self, reader: PfaReader<T>) -> Option<PfaReader<T>> {
+        let name = reader.get_name().to_string();
+        self.mount_as(&name, reader, None)
+    }
+
+    /// Mounts an archive under the given name, encrypted files are read with `key`
+    pub fn mount_as(
+        &mut self,
+        name: &str,
+        reader: PfaReader<T>,
+        key: Option<[u8; 32]>,
+    ) -> Option<PfaReader<T>> {
+        self.mounts
+            .insert(name.to_string(), Mount { reader, key })
+            .map(|x| x.reader)
+    }
+
+    pub fn unmount(&mut self, name: &str) -> Option<PfaReader<T>> {
+        self.mounts.remove(name).map(|x| x.reader)
+    }
+
+    pub fn get_reader(&mut self, name: &str) -> Option<&mut PfaReader<T>> {
+        self.mounts.get_mut(name).map(|x| &mut x.reader)
+    }
+
+    /// Splits a URL into the archive name and the path within it. Percent encoded characters
+    /// are decoded, and any query or fragment is ignored.
+    pub fn parse_url(url: &str) -> Result<(String, String), PfaError> {
+        let invalid = || PfaError::CustomError(format!("invalid pfa url '{url}'"));
+        let rest = url.strip_prefix(Self::SCHEME).ok_or_else(invalid)?;
+        let rest = rest.split(['?', '#']).next().unwrap_or_default();
+        let (name, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let name = percent_decode(name).ok_or_else(invalid)?;
+        if name.is_empty() {
+            return Err(invalid());
+        }
+        let path = percent_decode(path).ok_or_else(invalid)?;
+        Ok((name, format!("/{path}")))
+    }
+
+    /// Reads the file or lists the directory a URL points to. Returns None if the archive is
+    /// mounted but doesn't contain the path.
+    pub fn resolve(&mut self, url: &str) -> Result<Option<PfaPathContents>, PfaError> {
+        let (name, path) = Self::parse_url(url)?;
+        let mount = self
+            .mounts
+            .get_mut(&name)
+            .ok_or_else(|| PfaError::CustomError(format!("archive '{name}' is not mounted")))?;
+        mount.reader.get_path(&path[..], mount.key)
+    }
+
+    /// Like [`Self::resolve`], but only for files
+    pub fn resolve_file(&mut self, url: &str) -> Result<Option<Vec<u8>>, PfaError> {
+        match self.resolve(url)? {
+            Some(PfaPathContents::File(file)) => Ok(Some(file.into_contents())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Like [`Self::resolve_file`], along with the file's recorded content type, for responses
+    /// which need a `Content-Type` header
+    pub fn resolve_file_with_content_type(
+        &mut self,
+        url: &str,
+    ) -> Result<Option<ResolvedFile>, PfaError> {
+        let (name, path) = Self::parse_url(url)?;
+        let Some(contents) = self.resolve_file(url)? else {
+            return Ok(None);
+        };
+        let content_type = match self.mounts.get(&name) {
+            Some(mount) => mount.reader.get_content_type(&path[..])?,
+            None => None,
+        };
+        Ok(Some(ResolvedFile {
+            contents,
+            content_type,
+        }))
+    }
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            out.push(byte);
+        }
+    }
+    String::from_utf8(out).ok()
+}
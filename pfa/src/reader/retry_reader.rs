@@ -0,0 +1,195 @@
+use std::{
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    retry_would_block: bool,
+    timeout: Option<Duration>,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Only retries reads which were interrupted, this is what the reader uses by default
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also retry reads which fail with WouldBlock or TimedOut, which is useful for non-blocking
+    /// or network backed sources
+    pub fn retry_would_block(mut self, retry: bool) -> Self {
+        self.retry_would_block = retry;
+        self
+    }
+
+    /// Maximum time to keep retrying a single operation before giving up with TimedOut
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retry_would_block: false,
+            timeout: None,
+            backoff: Duration::from_millis(1),
+        }
+    }
+}
+
+/// Wraps the storage backend of a reader, retrying operations according to a [RetryPolicy]
+#[derive(Debug)]
+pub struct RetryReader<T> {
+    inner: T,
+    policy: RetryPolicy,
+}
+
+impl<T> RetryReader<T> {
+    pub fn new(inner: T, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn retry<R>(&mut self, mut op: impl FnMut(&mut T) -> std::io::Result<R>) -> std::io::Result<R> {
+        let mut started = None;
+        loop {
+            match op(&mut self.inner) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e)
+                    if self.policy.retry_would_block
+                        && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    let started = *started.get_or_insert_with(Instant::now);
+                    if let Some(timeout) = self.policy.timeout {
+                        if started.elapsed() >= timeout {
+                            return Err(std::io::Error::new(
+                                ErrorKind::TimedOut,
+                                format!("storage backend did not become ready in {timeout:?}"),
+                            ));
+                        }
+                    }
+                    std::thread::sleep(self.policy.backoff);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<T: Read> Read for RetryReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.retry(|inner| inner.read(buf))
+    }
+}
+
+impl<T: Seek> Seek for RetryReader<T> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.retry(|inner| inner.seek(pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom};
+
+    use crate::{
+        builder::PfaBuilder,
+        reader::{PfaReader, PfaReaderOptions, RetryPolicy},
+        shared::DataFlags,
+    };
+
+    /// Fails every other operation and only ever returns a single byte per read
+    struct FlakyReader {
+        inner: Cursor<Vec<u8>>,
+        kind: ErrorKind,
+        fail: bool,
+    }
+
+    impl FlakyReader {
+        fn flake(&mut self) -> std::io::Result<()> {
+            self.fail = !self.fail;
+            if self.fail {
+                return Err(self.kind.into());
+            }
+            Ok(())
+        }
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.flake()?;
+            let len = buf.len().min(1);
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    impl Seek for FlakyReader {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.flake()?;
+            self.inner.seek(pos)
+        }
+    }
+
+    fn archive() -> Vec<u8> {
+        let mut builder = PfaBuilder::new("flaky");
+        builder
+            .add_file("file.txt", vec![7; 500], DataFlags::auto())
+            .unwrap();
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn interrupted_reads_are_retried() {
+        let flaky = FlakyReader {
+            inner: Cursor::new(archive()),
+            kind: ErrorKind::Interrupted,
+            fail: false,
+        };
+        let mut reader = PfaReader::new(flaky).unwrap();
+        let file = reader.get_file("/file.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), [7; 500]);
+    }
+
+    #[test]
+    fn would_block_is_retried_when_configured() {
+        let flaky = FlakyReader {
+            inner: Cursor::new(archive()),
+            kind: ErrorKind::WouldBlock,
+            fail: false,
+        };
+        assert!(PfaReader::new(flaky).is_err());
+
+        let flaky = FlakyReader {
+            inner: Cursor::new(archive()),
+            kind: ErrorKind::WouldBlock,
+            fail: false,
+        };
+        let options = PfaReaderOptions::new().retry_policy(
+            RetryPolicy::new()
+                .retry_would_block(true)
+                .backoff(std::time::Duration::ZERO),
+        );
+        let mut reader = PfaReader::new_with_options(flaky, options).unwrap();
+        let file = reader.get_file("/file.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), [7; 500]);
+    }
+}
@@ -0,0 +1,480 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::{Read, Seek},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    reader::{pfa_reader::PfaFileSlice, PfaReader, ReaderWarning},
+    shared::{entry_hash, DataFlags},
+    PfaError,
+};
+
+/// What scrubbing found wrong with a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(tag = "kind", rename_all = "snake_case")
+)]
+pub enum ScrubIssue {
+    /// The stored data is damaged, but error correction repairs the given number of bytes
+    Corrected { path: String, bytes: u64 },
+    /// The stored data is damaged beyond what error correction (if any) can repair
+    Damaged { path: String, reason: String },
+}
+
+impl ScrubIssue {
+    pub fn path(&self) -> &str {
+        match self {
+            ScrubIssue::Corrected { path, .. } | ScrubIssue::Damaged { path, .. } => path,
+        }
+    }
+}
+
+impl Display for ScrubIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScrubIssue::Corrected { path, bytes } => {
+                write!(
+                    f,
+                    "'{path}' has {bytes} damaged bytes, error correction repairs them"
+                )
+            }
+            ScrubIssue::Damaged { path, reason } => write!(f, "'{path}' is damaged: {reason}"),
+        }
+    }
+}
+
+/// The result of scrubbing one archive, see [`PfaReader::scrub`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ArchiveHealth {
+    pub files: usize,
+    pub bytes_read: u64,
+    /// Files without an entry hash or error correction, whose damage can't be detected
+    pub unverified: usize,
+    pub issues: Vec<ScrubIssue>,
+    /// Problems found while opening the archive
+    pub warnings: Vec<ReaderWarning>,
+}
+
+impl ArchiveHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.issues.is_empty() && self.warnings.is_empty()
+    }
+}
+
+/// Limits the rate data is read at by sleeping once it's ahead
+pub(crate) struct Throttle {
+    bytes_per_second: Option<u64>,
+    start: Instant,
+    bytes: u64,
+    stop: Option<Arc<AtomicBool>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(bytes_per_second: Option<u64>) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.filter(|x| *x > 0),
+            start: Instant::now(),
+            bytes: 0,
+            stop: None,
+        }
+    }
+
+    /// Cuts waits short once `stop` is set
+    pub(crate) fn until(mut self, stop: Arc<AtomicBool>) -> Self {
+        self.stop = Some(stop);
+        self
+    }
+
+    fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        let Some(rate) = self.bytes_per_second else {
+            return;
+        };
+        let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+        let Some(stop) = &self.stop else {
+            if let Some(wait) = due.checked_sub(self.start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+            return;
+        };
+        while let Some(wait) = due.checked_sub(self.start.elapsed()) {
+            if stop.load(Ordering::Relaxed) {
+                return;
+            }
+            std::thread::sleep(wait.min(Duration::from_millis(50)));
+        }
+    }
+}
+
+impl<T: Read + Seek> PfaReader<T> {
+    /// Reads the stored data of every file, checking it against its entry hash and error
+    /// correction blocks. Keys aren't needed, since both cover the data as it is stored. Solid
+    /// blocks are read once for all of their files.
+    pub fn scrub(&mut self) -> Result<ArchiveHealth, PfaError> {
+        self.scrub_throttled(&mut Throttle::new(None), &AtomicBool::new(false), |_| {})
+    }
+
+    /// Scrubs like [`Self::scrub`], passing each issue to `on_issue` as it's found. Once `stop`
+    /// is set, returns what was found so far after the file being checked.
+    pub(crate) fn scrub_throttled(
+        &mut self,
+        throttle: &mut Throttle,
+        stop: &AtomicBool,
+        mut on_issue: impl FnMut(&ScrubIssue),
+    ) -> Result<ArchiveHealth, PfaError> {
+        let mut health = ArchiveHealth {
+            warnings: self.warnings().to_vec(),
+            ..Default::default()
+        };
+        let mut report = |health: &mut ArchiveHealth, issue: ScrubIssue| {
+            on_issue(&issue);
+            health.issues.push(issue);
+        };
+        let mut block_hashes = HashMap::new();
+        for slice in self.collect_file_slices("/")? {
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+            if let Some((member, block)) = self.solid_member(&slice) {
+                let block_hash = match block_hashes.get(&member.block) {
+                    Some(hash) => *hash,
+                    None => {
+                        let stored = self.read_solid_block(&slice.path.to_string(), &block)?;
+                        throttle.consume(block.size);
+                        health.bytes_read += block.size;
+                        let hash = *blake3::hash(&stored).as_bytes();
+                        block_hashes.insert(member.block, hash);
+                        hash
+                    }
+                };
+                health.files += 1;
+                let hashes = self.get_header_fields().entry_hashes.as_deref();
+                match hashes {
+                    None => health.unverified += 1,
+                    Some(hashes) => {
+                        let digest = member.digest(&block_hash);
+                        if let Err(e) = Self::check_digest(&slice, &digest, Some(hashes)) {
+                            let issue = ScrubIssue::Damaged {
+                                path: slice.path.to_string(),
+                                reason: e.to_string(),
+                            };
+                            report(&mut health, issue);
+                        }
+                    }
+                }
+                continue;
+            }
+            let contents = self.read_slice(&slice)?;
+            throttle.consume(slice.size);
+            health.files += 1;
+            health.bytes_read += slice.size;
+            let issue = match self.check_slice(&slice, &contents) {
+                Ok(Some(0)) => continue,
+                Ok(Some(bytes)) => ScrubIssue::Corrected {
+                    path: slice.path.to_string(),
+                    bytes,
+                },
+                Ok(None) => {
+                    health.unverified += 1;
+                    continue;
+                }
+                Err(e) => ScrubIssue::Damaged {
+                    path: slice.path.to_string(),
+                    reason: e.to_string(),
+                },
+            };
+            report(&mut health, issue);
+        }
+        Ok(health)
+    }
+
+    /// Rewrites the archive with the damage error correction can repair fixed. Like
+    /// [`compact`](Self::compact), unreferenced data isn't copied. Fails with
+    /// [`PfaError::Unrecoverable`] listing every file damaged beyond what error correction
+    /// repairs, including the files of a damaged solid block, which have no error correction, so
+    /// a repaired copy never leaves out files.
+    pub fn repair(&mut self) -> Result<Vec<u8>, PfaError> {
+        let health = self.scrub()?;
+        self.repair_throttled(&mut Throttle::new(None), &health)
+    }
+
+    /// Repairs the archive, given what scrubbing it found
+    fn repair_throttled(
+        &mut self,
+        throttle: &mut Throttle,
+        health: &ArchiveHealth,
+    ) -> Result<Vec<u8>, PfaError> {
+        let unrecoverable = health
+            .issues
+            .iter()
+            .filter(|x| matches!(x, ScrubIssue::Damaged { .. }))
+            .map(|x| x.path().to_string())
+            .collect::<Vec<_>>();
+        if !unrecoverable.is_empty() {
+            return Err(PfaError::Unrecoverable(unrecoverable));
+        }
+        let hashes = self.get_header_fields().entry_hashes.clone();
+        self.compact_with(|slice, contents| {
+            throttle.consume(slice.size);
+            Self::repair_slice(slice, &contents, hashes.as_deref()).map(Some)
+        })
+    }
+
+    /// Returns the number of bytes error correction repairs, or None if the file has neither an
+    /// entry hash nor error correction. Fails if the data is damaged beyond repair.
+    fn check_slice(&self, slice: &PfaFileSlice, contents: &[u8]) -> Result<Option<u64>, PfaError> {
+        let hashes = self.get_header_fields().entry_hashes.as_deref();
+        let ecc = DataFlags::has_error_correction(slice.flags);
+        if hashes.is_none() && !ecc {
+            return Ok(None);
+        }
+        let corrections = DataFlags::check_error_correction(slice.flags, contents)?;
+        if corrections > 0 {
+            Self::repair_slice(slice, contents, hashes)?;
+        } else {
+            Self::check_hash(slice, contents, hashes)?;
+        }
+        Ok(Some(corrections))
+    }
+
+    /// Stored data with error correction applied, checked against the entry hash
+    fn repair_slice(
+        slice: &PfaFileSlice,
+        contents: &[u8],
+        hashes: Option<&[entry_hash::EntryHash]>,
+    ) -> Result<Vec<u8>, PfaError> {
+        let repaired = DataFlags::repair_error_correction(slice.flags, contents)?;
+        Self::check_hash(slice, &repaired, hashes)?;
+        Ok(repaired)
+    }
+
+    fn check_hash(
+        slice: &PfaFileSlice,
+        contents: &[u8],
+        hashes: Option<&[entry_hash::EntryHash]>,
+    ) -> Result<(), PfaError> {
+        Self::check_digest(slice, blake3::hash(contents).as_bytes(), hashes)
+    }
+
+    /// Like [`Self::check_hash`], given the BLAKE3 hash of the stored data
+    fn check_digest(
+        slice: &PfaFileSlice,
+        contents_hash: &[u8; 32],
+        hashes: Option<&[entry_hash::EntryHash]>,
+    ) -> Result<(), PfaError> {
+        let Some(expected) = hashes.and_then(|x| x.get(slice.index)) else {
+            return Ok(());
+        };
+        let name = slice.path.get_name().map_or("", |x| &x[..]);
+        if &entry_hash::file_hash_of_digest(name, slice.flags, contents_hash) != expected {
+            return Err(PfaError::CustomError(
+                "stored data doesn't match its entry hash".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The result of scrubbing one archive of a [`ScrubTask`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ArchiveScrub {
+    pub path: PathBuf,
+    /// The archive couldn't be opened or read
+    pub error: Option<String>,
+    pub health: Option<ArchiveHealth>,
+    /// Where the repaired copy was written
+    pub repaired: Option<PathBuf>,
+    /// Files damaged beyond repair, which stopped a repaired copy from being written
+    pub unrecoverable: Vec<String>,
+}
+
+/// Everything one pass of a [`ScrubTask`] found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ScrubReport {
+    pub archives: Vec<ArchiveScrub>,
+}
+
+impl ScrubReport {
+    pub fn is_healthy(&self) -> bool {
+        self.archives
+            .iter()
+            .all(|x| x.error.is_none() && x.health.as_ref().is_some_and(|x| x.is_healthy()))
+    }
+}
+
+impl Display for ScrubReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for archive in &self.archives {
+            let path = archive.path.display();
+            if let Some(error) = &archive.error {
+                writeln!(f, "{path}: {error}")?;
+            }
+            let Some(health) = &archive.health else {
+                continue;
+            };
+            write!(
+                f,
+                "{path}: {} files, {} bytes",
+                health.files, health.bytes_read
+            )?;
+            if health.unverified > 0 {
+                write!(f, ", {} unverified", health.unverified)?;
+            }
+            writeln!(
+                f,
+                ", {} issues",
+                health.issues.len() + health.warnings.len()
+            )?;
+            for warning in &health.warnings {
+                writeln!(f, "  {warning}")?;
+            }
+            for issue in &health.issues {
+                writeln!(f, "  {issue}")?;
+            }
+            if let Some(repaired) = &archive.repaired {
+                writeln!(f, "  repaired copy written to {}", repaired.display())?;
+            }
+            if !archive.unrecoverable.is_empty() {
+                writeln!(
+                    f,
+                    "  not repaired, damaged beyond repair: {}",
+                    archive.unrecoverable.join(", ")
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Periodically scrubs every `.pfa` archive in a directory and its subdirectories, for archives
+/// kept in long-term storage. Each pass reads all of the stored data, so it can be rate limited
+/// to leave IO for other work.
+#[derive(Debug, Clone)]
+pub struct ScrubTask {
+    dir: PathBuf,
+    bytes_per_second: Option<u64>,
+    repair: bool,
+}
+
+impl ScrubTask {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            bytes_per_second: None,
+            repair: false,
+        }
+    }
+
+    /// Maximum rate to read archives at, including reads for repairs
+    pub fn rate_limit(mut self, bytes_per_second: Option<u64>) -> Self {
+        self.bytes_per_second = bytes_per_second;
+        self
+    }
+
+    /// Write a repaired copy of every archive with issues next to it, see [`repaired_path`].
+    /// Archives with files damaged beyond repair get no copy, their files are listed in
+    /// [`ArchiveScrub::unrecoverable`] instead. The archives themselves are never modified.
+    pub fn repair(mut self, repair: bool) -> Self {
+        self.repair = repair;
+        self
+    }
+
+    /// Scrubs every archive once
+    pub fn run(&self) -> Result<ScrubReport, PfaError> {
+        let mut throttle = Throttle::new(self.bytes_per_second);
+        let mut report = ScrubReport::default();
+        for path in find_archives(&self.dir)? {
+            let mut archive = ArchiveScrub {
+                path,
+                error: None,
+                health: None,
+                repaired: None,
+                unrecoverable: vec![],
+            };
+            if let Err(e) = self.scrub_archive(&mut archive, &mut throttle) {
+                archive.error = Some(e.to_string());
+            }
+            report.archives.push(archive);
+        }
+        Ok(report)
+    }
+
+    /// Runs a pass every `interval`, passing each report to `on_report` until it returns false
+    pub fn run_every(
+        &self,
+        interval: Duration,
+        mut on_report: impl FnMut(&ScrubReport) -> bool,
+    ) -> Result<(), PfaError> {
+        loop {
+            let start = Instant::now();
+            if !on_report(&self.run()?) {
+                return Ok(());
+            }
+            std::thread::sleep(interval.saturating_sub(start.elapsed()));
+        }
+    }
+
+    fn scrub_archive(
+        &self,
+        archive: &mut ArchiveScrub,
+        throttle: &mut Throttle,
+    ) -> Result<(), PfaError> {
+        let mut reader = PfaReader::open(&archive.path)?;
+        let health = reader.scrub_throttled(throttle, &AtomicBool::new(false), |_| {})?;
+        let needs_repair = !health.issues.is_empty();
+        let repaired = match self.repair && needs_repair {
+            true => Some(reader.repair_throttled(throttle, &health)),
+            false => None,
+        };
+        archive.health = Some(health);
+        match repaired {
+            Some(Ok(bytes)) => {
+                let path = repaired_path(&archive.path);
+                std::fs::write(&path, bytes)?;
+                archive.repaired = Some(path);
+            }
+            Some(Err(PfaError::Unrecoverable(paths))) => archive.unrecoverable = paths,
+            Some(Err(e)) => return Err(e),
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// Every `.pfa` archive in a directory and its subdirectories, sorted by path
+pub(crate) fn find_archives(dir: &Path) -> Result<Vec<PathBuf>, PfaError> {
+    let mut archives = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|x| x == "pfa") {
+                archives.push(path);
+            }
+        }
+    }
+    archives.sort();
+    Ok(archives)
+}
+
+/// Path a [`ScrubTask`] writes the repaired copy of an archive to, `{archive}.repaired`, which
+/// isn't scrubbed itself
+pub fn repaired_path(archive: &Path) -> PathBuf {
+    let mut name = archive.file_name().unwrap_or_default().to_os_string();
+    name.push(".repaired");
+    archive.with_file_name(name)
+}
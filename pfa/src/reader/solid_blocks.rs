@@ -0,0 +1,173 @@
+use std::{
+    collections::VecDeque,
+    io::{Read, Seek},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    builder::PfaBuilder,
+    reader::{
+        pfa_reader::{read_stored_data, PfaFileSlice},
+        PfaReader,
+    },
+    shared::{
+        solid_block::{SolidBlock, SolidMember},
+        Cipher, CodecRegistry, CompressionCodec, DataFlags, StoredCodec,
+    },
+    PfaError,
+};
+
+/// Number of decoded solid blocks a reader keeps
+const CACHED_BLOCKS: usize = 4;
+
+/// The solid blocks a reader decoded last, so reading the other files of a block doesn't
+/// decompress it again. Files read from a cached block share its allocation.
+#[derive(Default)]
+pub(crate) struct SolidBlockCache {
+    blocks: VecDeque<(u32, Bytes)>,
+}
+
+impl SolidBlockCache {
+    pub(crate) fn clear(&mut self) {
+        self.blocks.clear();
+    }
+}
+
+impl std::fmt::Debug for SolidBlockCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.blocks.iter().map(|x| x.0))
+            .finish()
+    }
+}
+
+/// Reads and decodes a solid block, `entry` is the file it's read for, named in errors. The
+/// whole block must decompress to at most `limit` bytes.
+pub(crate) fn decode_solid_block(
+    data: &mut (impl Read + Seek),
+    data_idx: u64,
+    block: &SolidBlock,
+    entry: &str,
+    limit: Option<u64>,
+    codecs: &CodecRegistry,
+) -> Result<Vec<u8>, PfaError> {
+    let mut contents = read_stored_data(data, data_idx, block.offset, block.size, entry, limit)?;
+    DataFlags::check_codec(block.flags, entry)?;
+    DataFlags::unprocess_contents_from_flags(
+        block.flags,
+        &mut contents,
+        None,
+        Cipher::default(),
+        limit,
+        codecs,
+    )?;
+    Ok(contents)
+}
+
+impl<T: Read + Seek> PfaReader<T> {
+    /// The solid block holding the contents of the file of `slice`, and where they are within it
+    pub(crate) fn solid_member(&self, slice: &PfaFileSlice) -> Option<(SolidMember, SolidBlock)> {
+        self.get_header_fields().solid_member(slice.index)
+    }
+
+    /// Contents of a file stored in a solid block, decompressing the block unless it's cached.
+    /// The contents are a slice of the cached block, so they aren't copied.
+    pub(crate) fn read_solid_member(
+        &mut self,
+        entry: &str,
+        member: SolidMember,
+        block: SolidBlock,
+    ) -> Result<Bytes, PfaError> {
+        let position = match self
+            .solid_cache
+            .blocks
+            .iter()
+            .position(|x| x.0 == member.block)
+        {
+            Some(position) => position,
+            None => {
+                let limit = self.max_decompressed_size();
+                let codecs = self.codecs().clone();
+                let contents = decode_solid_block(
+                    &mut self.data,
+                    self.data_idx as u64,
+                    &block,
+                    entry,
+                    limit,
+                    &codecs,
+                )?;
+                let cache = &mut self.solid_cache.blocks;
+                if cache.len() == CACHED_BLOCKS {
+                    cache.pop_front();
+                }
+                cache.push_back((member.block, contents.into()));
+                cache.len() - 1
+            }
+        };
+        let block = &self.solid_cache.blocks[position].1;
+        Ok(block.slice_ref(member.contents(block)?))
+    }
+
+    /// Stored data of a solid block, as it is in the archive, `entry` is a file in it
+    pub(crate) fn read_solid_block(
+        &mut self,
+        entry: &str,
+        block: &SolidBlock,
+    ) -> Result<Vec<u8>, PfaError> {
+        let limit = self.max_decompressed_size();
+        read_stored_data(
+            &mut self.data,
+            self.data_idx as u64,
+            block.offset,
+            block.size,
+            entry,
+            limit,
+        )
+    }
+
+    /// Largest file stored in a solid block, and the largest block before compression, which
+    /// builders copying the archive group its files with. None if it has no solid blocks.
+    pub(crate) fn solid_block_sizes(&self) -> Option<(u64, u64)> {
+        let members = &self.get_header_fields().solid_members;
+        let max_file_size = members.iter().map(|x| x.1.size).max()?;
+        let mut block_sizes = vec![0u64; self.get_header_fields().solid_blocks.len()];
+        for (_, member) in members {
+            let size = &mut block_sizes[member.block as usize];
+            *size = size.saturating_add(member.size);
+        }
+        Some((max_file_size, block_sizes.into_iter().max()?))
+    }
+
+    /// Adds a file stored in a solid block to `builder` decoded, so it's grouped into the
+    /// builder's solid blocks again, compressed with the block's codec. Files compressed with a
+    /// custom codec the builder doesn't [have](PfaBuilder::set_codecs) are added with LZ4.
+    pub(crate) fn copy_solid_member(
+        &mut self,
+        slice: &PfaFileSlice,
+        member: SolidMember,
+        block: SolidBlock,
+        builder: &mut PfaBuilder,
+    ) -> Result<(), PfaError> {
+        let path = slice.path.to_string();
+        let contents = self.read_solid_member(&path, member, block)?;
+        let flags = DataFlags::auto();
+        let flags = match DataFlags::codec(block.flags) {
+            #[cfg(feature = "zstd")]
+            Some(StoredCodec::Zstd) => flags.compression_codec(CompressionCodec::Zstd),
+            Some(StoredCodec::Custom) => {
+                let stored = self.read_solid_block(&path, &block)?;
+                let codec = DataFlags::parse_stage_headers(block.flags, &stored)
+                    .codec_id
+                    .and_then(|id| builder.codecs().find_by_id(id));
+                match codec {
+                    Some(codec) => flags.compression_codec(CompressionCodec::Custom(codec.clone())),
+                    None => flags,
+                }
+            }
+            _ => flags,
+        };
+        builder.add_file(&path, contents.into(), flags)?;
+        self.copy_entry_metadata(slice, builder)
+    }
+}
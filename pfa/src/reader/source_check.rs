@@ -0,0 +1,69 @@
+use std::io::{Read, Seek};
+
+use crate::{
+    reader::{pfa_reader::PfaPath, PfaReader},
+    shared::TransformChain,
+    PfaError,
+};
+
+/// How a file's contents compare to the source recorded when the archive was built, see
+/// [`PfaReader::check_source`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(tag = "kind", rename_all = "snake_case")
+)]
+pub enum SourceCheck {
+    /// The contents are the source's
+    Unchanged,
+    /// The contents differ from the source because they went through the recorded
+    /// transformations
+    Transformed(TransformChain),
+    /// The contents differ from the source and no recorded transformation explains it, so the
+    /// file was damaged or replaced
+    Mismatch,
+}
+
+impl<T: Read + Seek> PfaReader<T> {
+    /// Compares the contents of a file with the source hash in its
+    /// [provenance](Self::get_provenance), and the input and output hashes of its
+    /// [transformations](Self::get_transforms). Files which differ from their source are only
+    /// reported as transformed when their transformations started from that source and produced
+    /// their contents. A chain without an input hash is trusted to start from the source, and
+    /// one without an output hash, such as those of archives written before output hashes were
+    /// recorded, to produce the contents. None if the file doesn't exist or has neither a source
+    /// nor an input hash recorded.
+    pub fn check_source(
+        &mut self,
+        path: impl Into<PfaPath>,
+        key: Option<[u8; 32]>,
+    ) -> Result<Option<SourceCheck>, PfaError> {
+        let path = path.into();
+        let source_hash = self
+            .get_provenance(path.clone())?
+            .and_then(|x| x.source_hash);
+        let transforms = self.get_transforms(path.clone())?;
+        let input_hash = transforms.as_ref().and_then(|x| x.input_hash);
+        if source_hash.is_none() && input_hash.is_none() {
+            return Ok(None);
+        }
+        let Some(file) = self.get_file(path, key)? else {
+            return Ok(None);
+        };
+
+        let hash = *blake3::hash(file.get_contents()).as_bytes();
+        Ok(Some(match (source_hash, transforms) {
+            (Some(source), _) if source == hash => SourceCheck::Unchanged,
+            (source, Some(transforms))
+                if (source.is_none()
+                    || transforms.input_hash.is_none()
+                    || source == input_hash)
+                    && transforms.output_hash.is_none_or(|x| x == hash) =>
+            {
+                SourceCheck::Transformed(transforms)
+            }
+            _ => SourceCheck::Mismatch,
+        }))
+    }
+}
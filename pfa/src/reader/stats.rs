@@ -0,0 +1,87 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::{Read, Seek},
+};
+
+use crate::{reader::PfaReader, shared::DataFlags, PfaError};
+
+/// Sizes of the files stored with one codec, see [`ArchiveStats::codecs`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CodecStats {
+    pub files: usize,
+    pub stored_bytes: u64,
+    /// Total size of the contents of the files whose size is known
+    pub original_bytes: u64,
+}
+
+/// Sizes of every file in an archive, taken from the catalog without reading any data, see
+/// [`PfaReader::stats`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ArchiveStats {
+    pub files: usize,
+    /// Size of the data stored in the archive, after compression, encryption and error
+    /// correction. Solid blocks are counted once.
+    pub stored_bytes: u64,
+    /// Total size of the contents of the files whose size is known
+    pub original_bytes: u64,
+    /// Processed files whose contents size wasn't recorded, such as those of archives written
+    /// before sizes were. Their stored size is still counted.
+    pub unknown_size: usize,
+    /// Breakdown by the codec the data was compressed with, `none` for data stored uncompressed
+    pub codecs: BTreeMap<String, CodecStats>,
+}
+
+impl ArchiveStats {
+    /// Stored size relative to the contents size, lower is better. None if no contents size is
+    /// known.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.original_bytes > 0).then(|| self.stored_bytes as f64 / self.original_bytes as f64)
+    }
+}
+
+impl<T: Read + Seek> PfaReader<T> {
+    /// Counts the files in the archive along with their stored and contents sizes, overall and
+    /// per codec, without reading or decoding any data. The contents size of a compressed,
+    /// encrypted or error corrected file is only known when the archive recorded it, which
+    /// builders do for every such file, but not [editors](crate::writer::pfa_editor::PfaEditor).
+    pub fn stats(&self) -> Result<ArchiveStats, PfaError> {
+        let mut stats = ArchiveStats::default();
+        let mut counted_blocks = HashSet::new();
+        for slice in self.collect_file_slices("/")? {
+            let (flags, stored) = match self.get_header_fields().solid_member(slice.index) {
+                Some((member, block)) => {
+                    // a block's data is counted along with its first member
+                    let stored = if counted_blocks.insert(member.block) {
+                        block.size
+                    } else {
+                        0
+                    };
+                    (block.flags, stored)
+                }
+                None => (slice.flags, slice.size),
+            };
+            let codec = stats
+                .codecs
+                .entry(
+                    DataFlags::codec(flags)
+                        .map_or("none", |x| x.name())
+                        .to_string(),
+                )
+                .or_default();
+            codec.files += 1;
+            codec.stored_bytes += stored;
+            stats.files += 1;
+            stats.stored_bytes += stored;
+            match self.contents_size(slice.index, slice.flags, slice.size) {
+                Some(size) => {
+                    codec.original_bytes += size;
+                    stats.original_bytes += size;
+                }
+                None => stats.unknown_size += 1,
+            }
+        }
+        Ok(stats)
+    }
+}
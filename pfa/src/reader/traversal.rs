@@ -0,0 +1,278 @@
+use std::{
+    collections::VecDeque,
+    io::{Read, Seek},
+};
+
+use crate::{
+    reader::pfa_reader::{
+        PfaDirectoryContents, PfaEntryInfo, PfaFileContents, PfaPath, PfaPathContents, PfaReader,
+    },
+    shared::DataFlags,
+    PfaError,
+};
+
+/// Order files are visited in by [`PfaReader::traverse_files_with`]. In both orders the entries
+/// of a directory are visited in catalog order, so traversing the same archive always visits
+/// files in the same order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TraversalOrder {
+    /// Visits everything within a directory before moving on to its next sibling
+    #[default]
+    DepthFirst,
+    /// Visits every file at one depth before any file deeper than it
+    BreadthFirst,
+}
+
+/// Kind of entry an [`EntryFilter`] matches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EntryKind {
+    #[default]
+    Any,
+    Files,
+    Directories,
+}
+
+/// Matches entries by what their catalog entries record, so filtering doesn't read any data.
+/// The extension and flag filters only match files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryFilter {
+    kind: EntryKind,
+    extensions: Vec<String>,
+    encrypted: Option<bool>,
+    compressed: Option<bool>,
+}
+
+impl EntryFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn kind(mut self, kind: EntryKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// Matches files with this extension, ignoring case and any variant tag. Can be called more
+    /// than once to match any of several extensions.
+    pub fn extension(mut self, extension: &str) -> Self {
+        self.extensions
+            .push(extension.trim_start_matches('.').to_ascii_lowercase());
+        self
+    }
+
+    /// Matches only encrypted files when true, or only unencrypted ones when false
+    pub fn encrypted(mut self, encrypted: Option<bool>) -> Self {
+        self.encrypted = encrypted;
+        self
+    }
+
+    /// Matches only compressed files when true, or only uncompressed ones when false
+    pub fn compressed(mut self, compressed: Option<bool>) -> Self {
+        self.compressed = compressed;
+        self
+    }
+
+    pub fn matches(&self, entry: &PfaEntryInfo) -> bool {
+        match entry.is_directory {
+            true => self.matches_directory(),
+            false => self.matches_file(&entry.path, entry.flags),
+        }
+    }
+
+    fn has_file_filters(&self) -> bool {
+        !self.extensions.is_empty() || self.encrypted.is_some() || self.compressed.is_some()
+    }
+
+    fn matches_directory(&self) -> bool {
+        self.kind != EntryKind::Files && !self.has_file_filters()
+    }
+
+    fn matches_file(&self, path: &PfaPath, flags: u8) -> bool {
+        if self.kind == EntryKind::Directories {
+            return false;
+        }
+        if self
+            .encrypted
+            .is_some_and(|x| x != DataFlags::is_encrypted(flags))
+            || self
+                .compressed
+                .is_some_and(|x| x != DataFlags::is_compressed(flags))
+        {
+            return false;
+        }
+        if self.extensions.is_empty() {
+            return true;
+        }
+        let name = path.get_name().map_or("", |x| &x[..]);
+        let name = name.split('#').next().unwrap_or_default();
+        name.rsplit_once('.').is_some_and(|(_, extension)| {
+            self.extensions
+                .iter()
+                .any(|x| x.eq_ignore_ascii_case(extension))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TraversalOptions {
+    pub(crate) order: TraversalOrder,
+    pub(crate) key: Option<[u8; 32]>,
+    pub(crate) filter: Option<EntryFilter>,
+}
+
+impl TraversalOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn order(mut self, order: TraversalOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Key used to read encrypted files, files which it doesn't decrypt are skipped
+    pub fn key(mut self, key: Option<[u8; 32]>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Only files which match are read and visited, the others are skipped using their catalog
+    /// entries alone. Directories are still walked, whatever the filter's kind.
+    pub fn filter(mut self, filter: Option<EntryFilter>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Whether a file is filtered out, without reading it. Directories never are.
+    fn skips<T: Read + Seek>(&self, reader: &PfaReader<T>, path: &PfaPath) -> bool {
+        let Some(filter) = &self.filter else {
+            return false;
+        };
+        match reader.find_data_slice(path) {
+            Ok(Some((flags, ..))) => !filter.matches_file(path, flags),
+            _ => false,
+        }
+    }
+}
+
+/// What [`PfaReader::visit`] does after an event
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VisitControl {
+    #[default]
+    Continue,
+    /// Returned from [`PfaVisitor::on_dir_enter`], doesn't visit the directory's contents. The
+    /// directory is still left. Anywhere else it's the same as `Continue`.
+    SkipSubtree,
+    /// Ends the traversal without any further events
+    Stop,
+}
+
+/// Receives the entries of an archive from [`PfaReader::visit`]. Every directory which is
+/// entered is left after its contents have been visited.
+pub trait PfaVisitor {
+    fn on_dir_enter(&mut self, _dir: &PfaDirectoryContents) -> VisitControl {
+        VisitControl::Continue
+    }
+
+    fn on_file(&mut self, _file: PfaFileContents) -> VisitControl {
+        VisitControl::Continue
+    }
+
+    fn on_dir_leave(&mut self, _path: &PfaPath) -> VisitControl {
+        VisitControl::Continue
+    }
+}
+
+impl<T: Read + Seek> PfaReader<T> {
+    /// Walks the entries within `path` depth first, whatever order the options ask for, calling
+    /// the visitor as directories are entered and left and files are read. Entries which can't be
+    /// read, or are nested deeper than the reader's depth limit, are skipped.
+    pub fn visit(
+        &mut self,
+        path: impl Into<PfaPath>,
+        options: &TraversalOptions,
+        visitor: &mut impl PfaVisitor,
+    ) {
+        enum Step {
+            Visit(PfaPath),
+            Leave(PfaPath),
+        }
+
+        let limit = self.depth_limit();
+        let mut stack = vec![Step::Visit(path.into())];
+        while let Some(step) = stack.pop() {
+            let control = match step {
+                Step::Leave(path) => visitor.on_dir_leave(&path),
+                Step::Visit(path) if path.depth() > limit => VisitControl::Continue,
+                Step::Visit(path) if options.skips(self, &path) => VisitControl::Continue,
+                Step::Visit(path) => match self.get_path(path, options.key) {
+                    Ok(Some(PfaPathContents::File(f))) => visitor.on_file(f),
+                    Ok(Some(PfaPathContents::Directory(d))) => {
+                        let control = visitor.on_dir_enter(&d);
+                        stack.push(Step::Leave(d.path));
+                        if control == VisitControl::Continue {
+                            stack.extend(d.contents.into_iter().rev().map(Step::Visit));
+                        }
+                        control
+                    }
+                    _ => VisitControl::Continue,
+                },
+            };
+            if control == VisitControl::Stop {
+                return;
+            }
+        }
+    }
+
+    /// Calls `callback` with every file within `path` in the order given by the options. Entries
+    /// which can't be read, or are nested deeper than the reader's
+    /// [depth limit](crate::reader::PfaReaderOptions::depth_limit), are skipped.
+    /// Callback should return Err to cancel, which is returned
+    pub fn traverse_files_with<E>(
+        &mut self,
+        path: impl Into<PfaPath>,
+        options: &TraversalOptions,
+        mut callback: impl FnMut(PfaFileContents) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let limit = self.depth_limit();
+        let mut queue = VecDeque::from([path.into()]);
+        while let Some(path) = queue.pop_front() {
+            if path.depth() > limit || options.skips(self, &path) {
+                continue;
+            }
+            match self.get_path(path, options.key) {
+                Ok(Some(PfaPathContents::File(f))) => callback(f)?,
+                Ok(Some(PfaPathContents::Directory(d))) => match options.order {
+                    TraversalOrder::DepthFirst => {
+                        for child in d.contents.into_iter().rev() {
+                            queue.push_front(child);
+                        }
+                    }
+                    TraversalOrder::BreadthFirst => queue.extend(d.contents),
+                },
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`entries`](Self::entries), but lists only the entries within the directory at
+    /// `path` which match `filter`. Directories which don't match are still listed within.
+    pub fn entries_matching<'a>(
+        &'a self,
+        path: impl Into<PfaPath>,
+        filter: &'a EntryFilter,
+    ) -> Result<impl Iterator<Item = Result<PfaEntryInfo, PfaError>> + 'a, PfaError> {
+        let path = path.into();
+        let entries = match self.find_entry(&path)? {
+            Some(index) if path.is_directory() => {
+                Some(self.entries_within(index, path, usize::MAX)?)
+            }
+            _ => None,
+        };
+        Ok(entries
+            .into_iter()
+            .flatten()
+            .filter(|x| x.as_ref().map_or(true, |x| filter.matches(x))))
+    }
+}
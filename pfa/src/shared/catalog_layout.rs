@@ -0,0 +1,70 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::PfaError;
+
+/// How entry names are encoded in the catalog
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum CatalogLayout {
+    /// Every name takes up a 32 byte null terminated field
+    #[default]
+    Fixed,
+    /// Names are prefixed with their length, and may be up to 255 bytes long
+    Compact,
+}
+
+impl CatalogLayout {
+    pub(crate) const FIXED_NAME_SIZE: usize = 32;
+    pub(crate) const SLICE_SIZE: usize = 17;
+
+    pub(crate) fn max_name_size(&self) -> usize {
+        match self {
+            CatalogLayout::Fixed => Self::FIXED_NAME_SIZE,
+            CatalogLayout::Compact => u8::MAX as usize,
+        }
+    }
+
+    /// Number of bytes an entry with the given name takes up in the catalog
+    pub(crate) fn entry_size(&self, name: &str) -> usize {
+        match self {
+            CatalogLayout::Fixed => Self::FIXED_NAME_SIZE + Self::SLICE_SIZE,
+            CatalogLayout::Compact => 1 + name.len() + Self::SLICE_SIZE,
+        }
+    }
+
+    /// Reserved slots always have a 32 byte name field, so an entry with a name up to that size
+    /// can be written over one without moving anything after it
+    pub(crate) fn reserved_slot_size(&self) -> usize {
+        match self {
+            CatalogLayout::Fixed => Self::FIXED_NAME_SIZE + Self::SLICE_SIZE,
+            CatalogLayout::Compact => 1 + Self::FIXED_NAME_SIZE + Self::SLICE_SIZE,
+        }
+    }
+}
+
+impl FromStr for CatalogLayout {
+    type Err = PfaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_ascii_lowercase()[..] {
+            "fixed" => Ok(CatalogLayout::Fixed),
+            "compact" => Ok(CatalogLayout::Compact),
+            _ => Err(PfaError::CustomError(format!(
+                "unknown catalog layout '{s}'"
+            ))),
+        }
+    }
+}
+
+impl Display for CatalogLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CatalogLayout::Fixed => write!(f, "fixed"),
+            CatalogLayout::Compact => write!(f, "compact"),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::{fmt::Display, str::FromStr};
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit};
+use chacha20poly1305::XChaCha20Poly1305;
+use rand::RngCore;
+
+use crate::PfaError;
+
+/// AEAD encrypted files are encrypted with, see
+/// [`DataFlags::cipher`](crate::shared::DataFlags::cipher). Recorded per entry, entries without one
+/// recorded use AES256-GCM.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum Cipher {
+    /// Fastest on CPUs with AES instructions, and readable by every version of the format
+    #[default]
+    Aes256Gcm,
+    /// Faster than AES256-GCM on CPUs without AES instructions, such as older ARM devices
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 0,
+            Cipher::XChaCha20Poly1305 => 1,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Cipher::Aes256Gcm),
+            1 => Some(Cipher::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    /// Size of a nonce in bytes
+    pub fn nonce_size(self) -> usize {
+        match self {
+            Cipher::Aes256Gcm => 12,
+            Cipher::XChaCha20Poly1305 => 24,
+        }
+    }
+
+    pub(crate) fn generate_nonce(self) -> Vec<u8> {
+        let mut nonce = vec![0; self.nonce_size()];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        nonce
+    }
+
+    /// Encrypts `plaintext`, `nonce` must be [`Self::nonce_size`] bytes long
+    pub(crate) fn encrypt(self, key: &[u8; 32], nonce: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        match self {
+            Cipher::Aes256Gcm => {
+                Aes256Gcm::new(key.into()).encrypt(aes_gcm::Nonce::from_slice(nonce), plaintext)
+            }
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(key.into())
+                .encrypt(chacha20poly1305::XNonce::from_slice(nonce), plaintext),
+        }
+        .expect("failed to encrypt")
+    }
+
+    /// Decrypts and authenticates `ciphertext`, failing with [`PfaError::FileDecryptError`] if
+    /// the key is wrong, the data was tampered with or `nonce` isn't [`Self::nonce_size`] bytes
+    /// long
+    pub(crate) fn decrypt(
+        self,
+        key: &[u8; 32],
+        nonce: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, PfaError> {
+        if nonce.len() != self.nonce_size() {
+            return Err(PfaError::FileDecryptError);
+        }
+        match self {
+            Cipher::Aes256Gcm => {
+                Aes256Gcm::new(key.into()).decrypt(aes_gcm::Nonce::from_slice(nonce), ciphertext)
+            }
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(key.into())
+                .decrypt(chacha20poly1305::XNonce::from_slice(nonce), ciphertext),
+        }
+        .map_err(|_| PfaError::FileDecryptError)
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = PfaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_ascii_lowercase()[..] {
+            "aes256-gcm" | "aes" => Ok(Cipher::Aes256Gcm),
+            "xchacha20-poly1305" | "xchacha" => Ok(Cipher::XChaCha20Poly1305),
+            _ => Err(PfaError::CustomError(format!("unknown cipher '{s}'"))),
+        }
+    }
+}
+
+impl Display for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cipher::Aes256Gcm => write!(f, "aes256-gcm"),
+            Cipher::XChaCha20Poly1305 => write!(f, "xchacha20-poly1305"),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::{fmt::Debug, sync::Arc};
+
+use crate::PfaError;
+
+/// A compression codec applications can plug in, used with
+/// [`CompressionCodec::Custom`](crate::shared::CompressionCodec::Custom). Compressed data is
+/// stored as `{codec_id:u8}` followed by the output of [`Codec::compress`], so readers find the
+/// codec in their [registry](CodecRegistry) by its id.
+pub trait Codec: Send + Sync {
+    /// Id stored with the compressed data, which must never change once archives use it
+    fn id(&self) -> u8;
+    /// Name used in flag specifications and errors
+    fn name(&self) -> &str;
+    fn compress(&self, contents: &[u8]) -> Result<Vec<u8>, PfaError>;
+    fn decompress(&self, compressed: &[u8]) -> Result<Vec<u8>, PfaError>;
+}
+
+impl Debug for dyn Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.name(), self.id())
+    }
+}
+
+/// Codec data was compressed with, as recorded by its flags, see
+/// [`DataFlags::codec`](crate::shared::DataFlags::codec)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StoredCodec {
+    Lz4,
+    Zstd,
+    /// One of the application's codecs, whose id is stored with the data
+    Custom,
+}
+
+impl StoredCodec {
+    pub fn name(self) -> &'static str {
+        match self {
+            StoredCodec::Lz4 => "lz4",
+            StoredCodec::Zstd => "zstd",
+            StoredCodec::Custom => "custom",
+        }
+    }
+
+    /// Whether this build can decode the codec. Data compressed with a custom codec also needs
+    /// its codec to be registered.
+    pub fn is_supported(self) -> bool {
+        match self {
+            StoredCodec::Zstd => cfg!(feature = "zstd"),
+            StoredCodec::Lz4 | StoredCodec::Custom => true,
+        }
+    }
+}
+
+impl std::fmt::Display for StoredCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The custom codecs a reader can decode (see
+/// [`PfaReaderOptions::codecs`](crate::reader::PfaReaderOptions::codecs)), or a builder can
+/// name (see [`PfaBuilder::set_codecs`](crate::builder::PfaBuilder::set_codecs))
+#[derive(Clone, Default)]
+pub struct CodecRegistry {
+    codecs: Vec<Arc<dyn Codec>>,
+}
+
+impl CodecRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `codec`, failing if its id or name is already taken
+    pub fn register(&mut self, codec: Arc<dyn Codec>) -> Result<(), PfaError> {
+        let built_in = [StoredCodec::Lz4, StoredCodec::Zstd, StoredCodec::Custom];
+        if built_in.iter().any(|x| x.name() == codec.name()) {
+            return Err(PfaError::CustomError(format!(
+                "codec name '{}' is built in",
+                codec.name()
+            )));
+        }
+        if let Some(taken) = self
+            .codecs
+            .iter()
+            .find(|x| x.id() == codec.id() || x.name() == codec.name())
+        {
+            return Err(PfaError::CustomError(format!(
+                "codec {codec:?} clashes with registered codec {taken:?}"
+            )));
+        }
+        self.codecs.push(codec);
+        Ok(())
+    }
+
+    /// Every registered codec, in the order they were registered
+    pub fn codecs(&self) -> &[Arc<dyn Codec>] {
+        &self.codecs
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<&Arc<dyn Codec>> {
+        self.codecs.iter().find(|x| x.name() == name)
+    }
+
+    pub fn find_by_id(&self, id: u8) -> Option<&Arc<dyn Codec>> {
+        self.codecs.iter().find(|x| x.id() == id)
+    }
+
+    /// Decompresses data written by [`compress`] with the registered codec of its id
+    pub(crate) fn decompress(&self, compressed: &[u8]) -> Result<Vec<u8>, PfaError> {
+        let (&id, data) = compressed
+            .split_first()
+            .ok_or(PfaError::CustomError("compressed data is empty".into()))?;
+        let codec = self.find_by_id(id).ok_or_else(|| {
+            PfaError::CustomError(format!(
+                "data is compressed with codec {id}, which isn't registered"
+            ))
+        })?;
+        codec.decompress(data)
+    }
+}
+
+impl Debug for CodecRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(&self.codecs).finish()
+    }
+}
+
+/// Compresses with `codec`, prepending its id
+pub(crate) fn compress(codec: &dyn Codec, contents: &[u8]) -> Result<Vec<u8>, PfaError> {
+    let mut compressed = vec![codec.id()];
+    compressed.extend(codec.compress(contents)?);
+    Ok(compressed)
+}
@@ -0,0 +1,18 @@
+/// Extensions of formats which are compressed already, so compressing them again rarely saves
+/// anything. Files with automatic compression and one of these extensions are stored without
+/// trial compression by default, see
+/// [`PfaBuilder::set_precompressed_extensions`](crate::builder::PfaBuilder::set_precompressed_extensions).
+pub const PRECOMPRESSED_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "webp", "avif", "heic", "ktx2", "basis", "ogg", "oga", "opus",
+    "mp3", "m4a", "aac", "flac", "wma", "mp4", "m4v", "mkv", "webm", "mov", "avi", "wmv", "bk2",
+    "usm", "zip", "gz", "tgz", "bz2", "xz", "zst", "lz4", "7z", "rar", "jar", "apk", "pfa", "woff",
+    "woff2",
+];
+
+/// Whether the extension of the file `name` is one of `extensions`, ignoring case
+pub(crate) fn has_extension(name: &str, extensions: &[String]) -> bool {
+    let Some((stem, extension)) = name.rsplit_once('.') else {
+        return false;
+    };
+    !stem.is_empty() && extensions.iter().any(|x| x.eq_ignore_ascii_case(extension))
+}
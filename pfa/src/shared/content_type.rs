@@ -0,0 +1,73 @@
+/// Guesses the MIME type of a file from its leading bytes, falling back to the extension of its
+/// name. The variant tag of a name (`{name}#{variant}`) is ignored.
+pub fn sniff_content_type(name: &str, contents: &[u8]) -> Option<&'static str> {
+    sniff_magic(contents).or_else(|| content_type_from_name(name))
+}
+
+fn sniff_magic(contents: &[u8]) -> Option<&'static str> {
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\0asm", "application/wasm"),
+        (b"OggS", "audio/ogg"),
+        (b"ID3", "audio/mpeg"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+    ];
+    if let Some((_, content_type)) = MAGIC.iter().find(|(magic, _)| contents.starts_with(magic)) {
+        return Some(content_type);
+    }
+    match contents.get(8..12) {
+        Some(b"WEBP") if contents.starts_with(b"RIFF") => Some("image/webp"),
+        Some(b"WAVE") if contents.starts_with(b"RIFF") => Some("audio/wav"),
+        _ => None,
+    }
+}
+
+fn content_type_from_name(name: &str) -> Option<&'static str> {
+    let name = name.split('#').next().unwrap_or_default();
+    let (_, extension) = name.rsplit_once('.')?;
+    Some(match &extension.to_ascii_lowercase()[..] {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "md" => "text/markdown",
+        "csv" => "text/csv",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => return None,
+    })
+}
+
+/// Whether a content type matches a pattern, which is either a full type or a wildcard such as
+/// `image/*`
+pub fn content_type_matches(pattern: &str, content_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(kind) => content_type
+            .split_once('/')
+            .is_some_and(|(x, _)| x.eq_ignore_ascii_case(kind)),
+        None => pattern.eq_ignore_ascii_case(content_type),
+    }
+}
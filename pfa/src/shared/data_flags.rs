@@ -1,29 +1,188 @@
-use std::io::{Cursor, Read, Write};
+use std::{
+    fmt::Display,
+    io::{Cursor, Read, Write},
+    ops::Range,
+    str::FromStr,
+    sync::Arc,
+};
 
-use aes_gcm::{aead::Aead, AeadCore, KeyInit};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use rand::{RngCore, SeedableRng};
 
-use crate::PfaError;
+use crate::{
+    shared::{
+        cipher::Cipher,
+        codec::{self, Codec, CodecRegistry, StoredCodec},
+        encryption_frames::{self, EncryptionFrames},
+        entropy,
+    },
+    telemetry, PfaError,
+};
+
+/// Headers of the processing stages of a stored file, see
+/// [`PfaReader::get_file_raw`](crate::reader::PfaReader::get_file_raw). Values are None when a
+/// stage wasn't applied, or its header couldn't be read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StageHeaders {
+    pub compression: bool,
+    pub encryption: bool,
+    pub error_correction: bool,
+    /// Bits of the flag bitfield which have no meaning yet
+    pub reserved_bits: u8,
+    /// Error correction bytes per block, from the first (uncorrected) block
+    pub ecc_size: Option<u64>,
+    /// Number of error correction blocks after the first one
+    pub ecc_blocks: Option<usize>,
+    pub nonce: Option<Vec<u8>>,
+    /// Layout of the frames of data encrypted in frames
+    pub encryption_frames: Option<EncryptionFrames>,
+    /// Size prepended to LZ4 compressed data, unavailable when the file is also encrypted
+    pub decompressed_size: Option<u32>,
+    /// Id of the [custom codec](crate::shared::Codec) compressed data was compressed with,
+    /// unavailable when the file is also encrypted
+    pub codec_id: Option<u8>,
+    /// Whether the encrypted data was padded, see [`DataFlags::size_padding`]
+    pub size_padding: bool,
+}
 
 #[derive(Debug, Clone)]
 pub enum DataCompressionType {
+    /// Compressed when it makes the file smaller. Files whose extension is one of a compressed
+    /// format (see [`PRECOMPRESSED_EXTENSIONS`](crate::shared::PRECOMPRESSED_EXTENSIONS)), whose
+    /// contents look incompressible, or which are smaller than
+    /// [`DataFlags::min_compression_size`] are stored without trying.
     Automatic,
     Forced(bool),
 }
 
+/// Codec files are compressed with, see [`DataFlags::compression_codec`]
+#[derive(Debug, Clone, Default)]
+pub enum CompressionCodec {
+    /// Fast, and readable by every version of the format
+    #[default]
+    Lz4,
+    /// Slower, but with much better ratios for text. Readers need the `zstd` feature to decode
+    /// it.
+    #[cfg(feature = "zstd")]
+    Zstd,
+    /// A codec supplied by the application, which readers must have in their
+    /// [registry](CodecRegistry)
+    Custom(Arc<dyn Codec>),
+}
+
+impl CompressionCodec {
+    pub fn name(&self) -> &str {
+        match self {
+            CompressionCodec::Lz4 => "lz4",
+            #[cfg(feature = "zstd")]
+            CompressionCodec::Zstd => "zstd",
+            CompressionCodec::Custom(codec) => codec.name(),
+        }
+    }
+
+    fn compress(&self, contents: &[u8]) -> Result<Vec<u8>, PfaError> {
+        match self {
+            CompressionCodec::Lz4 => Ok(lz4_flex::compress_prepend_size(contents)),
+            #[cfg(feature = "zstd")]
+            CompressionCodec::Zstd => {
+                zstd::bulk::compress(contents, zstd::DEFAULT_COMPRESSION_LEVEL).map_err(|e| {
+                    PfaError::CustomError(format!("failed to compress with zstd: {e}"))
+                })
+            }
+            CompressionCodec::Custom(codec) => codec::compress(codec.as_ref(), contents),
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        match self {
+            CompressionCodec::Lz4 => DataFlags::COMPRESSION,
+            #[cfg(feature = "zstd")]
+            CompressionCodec::Zstd => DataFlags::COMPRESSION | DataFlags::ZSTD,
+            CompressionCodec::Custom(_) => DataFlags::COMPRESSION | DataFlags::CUSTOM_CODEC,
+        }
+    }
+}
+
+/// Identifies an encryption key without revealing it, see [`DataFlags::key_id`]
+pub type KeyId = [u8; 8];
+
 #[derive(Debug, Clone)]
 pub struct DataFlags {
     compression: DataCompressionType,
+    codec: CompressionCodec,
     encryption_key: Option<[u8; 32]>,
+    cipher: Cipher,
+    encryption_frame_size: Option<u32>,
+    size_padding: Option<u32>,
     error_correction: Option<f32>,
+    min_compression_size: Option<u32>,
 }
 
 impl DataFlags {
     const COMPRESSION: u8 = 0b00000001;
     const ENCRYPTION: u8 = 0b00000010;
     const ERROR_CORRECTION: u8 = 0b00000100;
-    const RESERVED: u8 = 0b11111000;
+    const ENCRYPTION_FRAMES: u8 = 0b00001000;
+    const SIZE_PADDING: u8 = 0b00010000;
+    const ZSTD: u8 = 0b00100000;
+    const CUSTOM_CODEC: u8 = 0b01000000;
+    const RESERVED: u8 = 0b10000000;
+    /// Every bit which isn't COMPRESSION, ENCRYPTION or ERROR_CORRECTION, all of which older
+    /// writers set
+    const LEGACY_RESERVED: u8 = 0b11111000;
+    /// Bits of the flag bitfield which readers of this version understand
+    pub const KNOWN_BITS: u8 = Self::COMPRESSION
+        | Self::ENCRYPTION
+        | Self::ERROR_CORRECTION
+        | Self::ENCRYPTION_FRAMES
+        | Self::SIZE_PADDING
+        | Self::ZSTD
+        | Self::CUSTOM_CODEC;
+    /// Mask, name and description of every known bit, used to generate the format description
+    pub(crate) const BITS: &'static [(u8, &'static str, &'static str)] = &[
+        (
+            Self::COMPRESSION,
+            "use_compression",
+            "LZ4 with the uncompressed size prepended as a u32",
+        ),
+        (
+            Self::ENCRYPTION,
+            "password_encrypted",
+            "AES256-GCM unless the entry's cipher is recorded, {nonce_len:u64}{nonce:u8[nonce_len]}{ciphertext_and_tag}",
+        ),
+        (
+            Self::ERROR_CORRECTION,
+            "error_correction",
+            "Reed Solomon, {ecc_size:u64} with 4 parity bytes, then chunks of up to 255 bytes ending in ecc_size parity bytes",
+        ),
+        (
+            Self::ENCRYPTION_FRAMES,
+            "encryption_frames",
+            "with password_encrypted, {frame_size:u32}{nonce_prefix:u8[7]} then frames of frame_size bytes and a 16 byte tag, nonce {nonce_prefix}{frame_index:u32 big endian}{is_last_frame:u8}",
+        ),
+        (
+            Self::SIZE_PADDING,
+            "size_padding",
+            "with password_encrypted, the plaintext is {size:u64}{data:u8[size]} followed by zeros up to a multiple of the bucket size",
+        ),
+        (
+            Self::ZSTD,
+            "zstd",
+            "with use_compression, a zstd frame instead of LZ4",
+        ),
+        (
+            Self::CUSTOM_CODEC,
+            "custom_codec",
+            "with use_compression, {codec_id:u8} followed by data compressed with the application's codec of that id",
+        ),
+    ];
+    /// Flag bits of every compression codec. Compressed data uses the first codec whose bits
+    /// are all set.
+    pub(crate) const CODECS: &'static [(u8, StoredCodec)] = &[
+        (Self::COMPRESSION | Self::CUSTOM_CODEC, StoredCodec::Custom),
+        (Self::COMPRESSION | Self::ZSTD, StoredCodec::Zstd),
+        (Self::COMPRESSION, StoredCodec::Lz4),
+    ];
     pub fn new(
         error_correction: Option<f32>,
         encryption_key: Option<[u8; 32]>,
@@ -33,6 +192,7 @@ impl DataFlags {
             encryption_key,
             compression,
             error_correction,
+            ..Default::default()
         }
     }
 
@@ -62,6 +222,12 @@ impl DataFlags {
         self
     }
 
+    /// Codec used when the file is compressed, LZ4 unless set
+    pub fn compression_codec(mut self, codec: CompressionCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
     pub fn error_correction(mut self, error_correction_percentage: Option<f32>) -> Self {
         self.error_correction = error_correction_percentage;
         self
@@ -72,16 +238,236 @@ impl DataFlags {
         self
     }
 
+    /// Cipher used when the file is encrypted, AES256-GCM unless set. XChaCha20-Poly1305 is
+    /// much faster to decrypt on CPUs without AES instructions.
+    pub fn cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Encrypts in frames of `frame_size` bytes which are authenticated on their own, so the
+    /// file can be decrypted as a stream or in ranges, see [`EncryptionFrames`]. Only used along
+    /// with [`Self::encryption`].
+    pub fn encryption_frames(mut self, frame_size: Option<u32>) -> Self {
+        self.encryption_frame_size = frame_size;
+        self
+    }
+
+    /// Pads encrypted data up to a multiple of `bucket` bytes, so the stored size of a file
+    /// doesn't reveal which known file it is. The true size is stored encrypted along with the
+    /// data. Only used along with [`Self::encryption`].
+    pub fn size_padding(mut self, bucket: Option<u32>) -> Self {
+        self.size_padding = bucket;
+        self
+    }
+
+    /// Stores files with automatic compression which are smaller than `bytes` without trying to
+    /// compress them. Compressing tiny files rarely saves anything, and costs a decompression,
+    /// and for LZ4 a size prefix, on every read.
+    pub fn min_compression_size(mut self, bytes: Option<u32>) -> Self {
+        self.min_compression_size = bytes;
+        self
+    }
+
+    /// Identifies the processing options, so data processed with one set of options is never
+    /// reused for another
+    pub(crate) fn fingerprint(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(match self.compression {
+            DataCompressionType::Automatic => &[0],
+            DataCompressionType::Forced(false) => &[1],
+            DataCompressionType::Forced(true) => &[2],
+        });
+        // left out for LZ4, so fingerprints from before codecs could be chosen stay the same
+        if !matches!(self.codec, CompressionCodec::Lz4) {
+            hasher.update(self.codec.name().as_bytes());
+        }
+        if let CompressionCodec::Custom(codec) = &self.codec {
+            hasher.update(&[codec.id()]);
+        }
+        // fingerprints are written to build caches and checkpoints, so they only include the
+        // key's id, which archives already store, never the key
+        match self.encryption_key {
+            Some(key) => hasher.update(&[1]).update(&Self::key_id(&key)),
+            None => hasher.update(&[0]),
+        };
+        // left out for AES256-GCM, so fingerprints from before ciphers could be chosen stay the
+        // same
+        if self.cipher != Cipher::Aes256Gcm {
+            hasher.update(&[self.cipher.to_u8()]);
+        }
+        match self.encryption_frame_size {
+            Some(size) => hasher.update(&[1]).update(&size.to_le_bytes()),
+            None => hasher.update(&[0]),
+        };
+        match self.size_padding {
+            Some(bucket) => hasher.update(&[1]).update(&bucket.to_le_bytes()),
+            None => hasher.update(&[0]),
+        };
+        match self.error_correction {
+            Some(percentage) => hasher
+                .update(&[1])
+                .update(&percentage.to_bits().to_le_bytes()),
+            None => hasher.update(&[0]),
+        };
+        // left out when unset, so fingerprints from before the threshold existed stay the same
+        if let Some(bytes) = self.min_compression_size {
+            hasher.update(&bytes.to_le_bytes());
+        }
+        *hasher.finalize().as_bytes()
+    }
+
     const MAX_CHUNK_SIZE: usize = 255;
 
-    pub(crate) fn process_content_and_generate_flags(mut self, file_data: &[u8]) -> (Vec<u8>, u8) {
+    pub(crate) fn is_automatic_compression(&self) -> bool {
+        matches!(self.compression, DataCompressionType::Automatic)
+    }
+
+    /// Whether these are the flags of [`Self::auto`], which callers pass when they don't need
+    /// anything specific
+    pub(crate) fn is_auto(&self) -> bool {
+        self.is_automatic_compression()
+            && self.encryption_key.is_none()
+            && self.cipher == Cipher::Aes256Gcm
+            && self.encryption_frame_size.is_none()
+            && self.size_padding.is_none()
+            && self.error_correction.is_none()
+            && self.min_compression_size.is_none()
+    }
+
+    /// Threshold set with [`Self::min_compression_size`]
+    pub(crate) fn has_min_compression_size(&self) -> bool {
+        self.min_compression_size.is_some()
+    }
+
+    pub(crate) fn has_encryption_key(&self) -> bool {
+        self.encryption_key.is_some()
+    }
+
+    /// Id of the encryption key, if one is set
+    pub(crate) fn encryption_key_id(&self) -> Option<KeyId> {
+        self.encryption_key.as_ref().map(Self::key_id)
+    }
+
+    /// Cipher files are encrypted with, if a key is set
+    pub(crate) fn encryption_cipher(&self) -> Option<Cipher> {
+        self.encryption_key.map(|_| self.cipher)
+    }
+
+    pub(crate) fn has_error_correction_percentage(&self) -> bool {
+        self.error_correction.is_some()
+    }
+
+    /// Whether files processed with these flags can be stored in a solid block, which is only
+    /// ever compressed
+    pub(crate) fn allows_solid(&self) -> bool {
+        !matches!(self.compression, DataCompressionType::Forced(false))
+            && self.encryption_key.is_none()
+            && self.error_correction.is_none()
+    }
+
+    pub fn is_encrypted(bitfield: u8) -> bool {
+        bitfield & DataFlags::ENCRYPTION != 0
+    }
+
+    /// Whether encrypted data is stored in [`EncryptionFrames`]
+    pub fn has_encryption_frames(bitfield: u8) -> bool {
+        let bitfield = Self::without_legacy_bits(bitfield);
+        bitfield & (DataFlags::ENCRYPTION | DataFlags::ENCRYPTION_FRAMES)
+            == DataFlags::ENCRYPTION | DataFlags::ENCRYPTION_FRAMES
+    }
+
+    /// Reserved bits which are set in a data slice's flags. Reserved bits must be zero, except
+    /// that writers which predate this rule set all of them, which means the same as none.
+    pub fn unknown_bits(bitfield: u8) -> u8 {
+        Self::without_legacy_bits(bitfield) & Self::RESERVED
+    }
+
+    /// Clears the reserved bits set by older writers, which also cover ENCRYPTION_FRAMES
+    fn without_legacy_bits(bitfield: u8) -> u8 {
+        match bitfield & Self::LEGACY_RESERVED {
+            Self::LEGACY_RESERVED => bitfield & !Self::LEGACY_RESERVED,
+            _ => bitfield,
+        }
+    }
+
+    pub fn is_compressed(bitfield: u8) -> bool {
+        bitfield & DataFlags::COMPRESSION != 0
+    }
+
+    pub fn has_error_correction(bitfield: u8) -> bool {
+        bitfield & DataFlags::ERROR_CORRECTION != 0
+    }
+
+    /// Whether encrypted data is padded, see [`Self::size_padding`]
+    pub fn has_size_padding(bitfield: u8) -> bool {
+        let bitfield = Self::without_legacy_bits(bitfield);
+        bitfield & (DataFlags::ENCRYPTION | DataFlags::SIZE_PADDING)
+            == DataFlags::ENCRYPTION | DataFlags::SIZE_PADDING
+    }
+
+    /// Codec data was compressed with, None if it isn't compressed
+    pub fn codec(bitfield: u8) -> Option<StoredCodec> {
+        let bitfield = Self::without_legacy_bits(bitfield);
+        Self::CODECS
+            .iter()
+            .find(|(mask, _)| bitfield & mask == *mask)
+            .map(|(_, codec)| *codec)
+    }
+
+    /// Fails with [`PfaError::UnsupportedCodec`] if the data of `entry` was compressed with a
+    /// codec this build can't decode
+    pub(crate) fn check_codec(bitfield: u8, entry: &str) -> Result<(), PfaError> {
+        match Self::codec(bitfield) {
+            Some(codec) if !codec.is_supported() => Err(PfaError::UnsupportedCodec {
+                entry: entry.to_string(),
+                codec: codec.to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether data stored with the given flags is compressed with a codec other than LZ4.
+    /// Readers of version 2 archives which predate codecs would decode it as LZ4, so it's only
+    /// stored in version 3 archives.
+    pub(crate) fn needs_version_3(bitfield: u8) -> bool {
+        matches!(
+            Self::codec(bitfield),
+            Some(StoredCodec::Zstd | StoredCodec::Custom)
+        )
+    }
+
+    /// Whether data stored with the given flags is identical to the original contents
+    pub(crate) fn is_unprocessed(bitfield: u8) -> bool {
+        bitfield & (DataFlags::COMPRESSION | DataFlags::ENCRYPTION | DataFlags::ERROR_CORRECTION)
+            == 0
+    }
+
+    pub(crate) fn process_content_and_generate_flags(
+        mut self,
+        file_data: &[u8],
+    ) -> Result<(Vec<u8>, u8), PfaError> {
         let mut contents = file_data.to_vec(); // TODO: maybe use Cow, or take contents via mut ref
 
         let mut already_compressed = false;
         if let DataCompressionType::Automatic = self.compression {
-            let compressed_bytes = lz4_flex::compress_prepend_size(&contents);
+            let too_small = self
+                .min_compression_size
+                .is_some_and(|x| contents.len() < x as usize);
+            // compressing media or encrypted input is wasted work, and only makes it larger
+            let compressed_bytes = match too_small {
+                true => {
+                    telemetry::increment(telemetry::SIZE_SKIPS, 1);
+                    None
+                }
+                false if entropy::looks_incompressible(&contents) => {
+                    telemetry::increment(telemetry::ENTROPY_SKIPS, 1);
+                    None
+                }
+                false => Some(self.codec.compress(&contents)?),
+            };
 
-            if compressed_bytes.len() < contents.len() {
+            if let Some(compressed_bytes) = compressed_bytes.filter(|x| x.len() < contents.len()) {
                 contents = compressed_bytes;
                 already_compressed = true;
                 self.compression = DataCompressionType::Forced(true);
@@ -93,27 +479,27 @@ impl DataFlags {
         let mut bits: u8 = 0;
         match self.compression {
             DataCompressionType::Forced(true) => {
-                bits |= DataFlags::COMPRESSION;
+                bits |= self.codec.bits();
                 if !already_compressed {
-                    contents = lz4_flex::compress_prepend_size(&contents);
+                    contents = self.codec.compress(&contents)?;
                 }
             }
             DataCompressionType::Forced(false) => bits &= !DataFlags::COMPRESSION,
             _ => unreachable!(),
         }
 
-        if let Some(key) = self.encryption_key {
-            bits |= DataFlags::ENCRYPTION;
-            let key = aes_gcm::Key::<aes_gcm::Aes256Gcm>::from_slice(&key);
-            let cipher = aes_gcm::Aes256Gcm::new(key);
-            let mut seed = [0; 32];
-            rand::rngs::OsRng.fill_bytes(&mut seed);
-            let nonce =
-                aes_gcm::Aes256Gcm::generate_nonce(&mut rand_chacha::ChaChaRng::from_seed(seed));
+        if let (Some(_), Some(bucket)) = (self.encryption_key, self.size_padding) {
+            bits |= DataFlags::SIZE_PADDING;
+            contents = Self::pad(contents, bucket);
+        }
 
-            let mut encrypted = cipher
-                .encrypt(&nonce, &contents[..])
-                .expect("failed to encrypt");
+        if let (Some(key), Some(frame_size)) = (self.encryption_key, self.encryption_frame_size) {
+            bits |= DataFlags::ENCRYPTION | DataFlags::ENCRYPTION_FRAMES;
+            contents = encryption_frames::encrypt(&key, self.cipher, frame_size, &contents);
+        } else if let Some(key) = self.encryption_key {
+            bits |= DataFlags::ENCRYPTION;
+            let nonce = self.cipher.generate_nonce();
+            let mut encrypted = self.cipher.encrypt(&key, &nonce, &contents);
 
             let mut header = vec![];
             header
@@ -126,103 +512,426 @@ impl DataFlags {
         }
 
         if let Some(percentage) = self.error_correction {
-            let ecc_size = (percentage * Self::MAX_CHUNK_SIZE as f32) as usize;
-            let block_size = Self::MAX_CHUNK_SIZE - ecc_size;
+            let mut ecc = EccEncoder::new(percentage);
+            let mut encoded = ecc.header();
+            ecc.push(&contents, &mut encoded);
+            ecc.finish(&mut encoded);
+            contents = encoded;
 
-            // The first block has hard coded values and stores the ecc size of the following
-            // blocks
-
-            let mut header = vec![];
-            {
-                let mut first_buf = vec![];
-                first_buf
-                    .write_u64::<LittleEndian>(ecc_size as u64)
-                    .unwrap();
-                let first_enc = reed_solomon::Encoder::new(4);
-                let first_ecc = first_enc.encode(&first_buf);
-                header.extend_from_slice(&first_ecc[..]);
-            }
+            bits |= DataFlags::ERROR_CORRECTION;
+        }
 
-            let enc = reed_solomon::Encoder::new(ecc_size);
+        Ok((contents, bits))
+    }
 
-            for chunk in contents.chunks(block_size) {
-                let encoded = enc.encode(chunk);
-                header.extend_from_slice(&encoded);
+    /// Whether [`Self::process_reader`] can be used, which needs every stage to work on part of
+    /// the contents at a time. Compression, size padding, and encryption which isn't in frames
+    /// all need the whole contents.
+    pub(crate) fn is_streamable(&self) -> bool {
+        matches!(self.compression, DataCompressionType::Forced(false))
+            && match self.encryption_key {
+                Some(_) => self.encryption_frame_size.is_some() && self.size_padding.is_none(),
+                None => true,
             }
+    }
 
-            contents = header;
-
+    /// Processes contents read from `reader` a chunk at a time, writing the same data as
+    /// [`Self::process_content_and_generate_flags`] to `sink` and returning its flags and the
+    /// size of the contents. Must only be used if [`Self::is_streamable`].
+    pub(crate) fn process_reader(
+        &self,
+        reader: &mut dyn Read,
+        sink: &mut dyn Write,
+    ) -> Result<(u8, u64), PfaError> {
+        debug_assert!(self.is_streamable());
+        const CHUNK_SIZE: usize = 64 * 1024;
+
+        let mut bits = 0;
+        let mut out = vec![];
+        let mut ecc = self.error_correction.map(EccEncoder::new);
+        if let Some(ecc) = &ecc {
             bits |= DataFlags::ERROR_CORRECTION;
+            out.extend(ecc.header());
+        }
+        let mut emit = |data: &[u8], out: &mut Vec<u8>| match &mut ecc {
+            Some(ecc) => ecc.push(data, out),
+            None => out.extend_from_slice(data),
+        };
+
+        let mut frames = match (self.encryption_key, self.encryption_frame_size) {
+            (Some(key), Some(frame_size)) => {
+                bits |= DataFlags::ENCRYPTION | DataFlags::ENCRYPTION_FRAMES;
+                let frames = encryption_frames::FrameEncryptor::new(&key, self.cipher, frame_size);
+                emit(&frames.header(), &mut out);
+                Some(frames)
+            }
+            _ => None,
+        };
+        let chunk_size = frames
+            .as_ref()
+            .map_or(CHUNK_SIZE, |x| x.frame_size() as usize);
+        let mut read_chunk = || {
+            let mut chunk = Vec::with_capacity(chunk_size);
+            reader.take(chunk_size as u64).read_to_end(&mut chunk)?;
+            Ok::<_, PfaError>(chunk)
+        };
+
+        // frames are encrypted differently when they're the last one, so one is read ahead
+        let mut chunk = read_chunk()?;
+        let mut size = 0;
+        loop {
+            size += chunk.len() as u64;
+            let next = match chunk.len() == chunk_size {
+                true => read_chunk()?,
+                false => vec![],
+            };
+            let last = next.is_empty();
+            match &mut frames {
+                Some(frames) => emit(&frames.encrypt_frame(&chunk, last)?, &mut out),
+                None => emit(&chunk, &mut out),
+            }
+            sink.write_all(&out)?;
+            out.clear();
+            if last {
+                break;
+            }
+            chunk = next;
         }
 
-        bits |= DataFlags::RESERVED;
+        if let Some(ecc) = ecc {
+            ecc.finish(&mut out);
+        }
+        sink.write_all(&out)?;
+        Ok((bits, size))
+    }
 
-        (contents, bits)
+    /// Prepends the size of `contents` and pads it with zeros up to a multiple of `bucket`
+    fn pad(contents: Vec<u8>, bucket: u32) -> Vec<u8> {
+        let padded_len = (contents.len() + 8).next_multiple_of(bucket.max(1) as usize);
+        let mut padded = Vec::with_capacity(padded_len);
+        padded
+            .write_u64::<LittleEndian>(contents.len() as u64)
+            .unwrap();
+        padded.extend_from_slice(&contents);
+        padded.resize(padded_len, 0);
+        padded
     }
 
+    /// Decodes stored data in place, recording the result in the [`telemetry`] counters. Fails
+    /// with [`PfaError::DecompressionLimitError`] instead of decompressing to more than `limit`
+    /// bytes. Data compressed with a custom codec is decompressed with one of `codecs`.
     pub(crate) fn unprocess_contents_from_flags(
         bitfield: u8,
-        mut contents: &mut Vec<u8>,
+        contents: &mut Vec<u8>,
         key: Option<[u8; 32]>,
+        cipher: Cipher,
+        limit: Option<u64>,
+        codecs: &CodecRegistry,
     ) -> Result<(), PfaError> {
+        match Self::unprocess_contents(bitfield, contents, key, cipher, limit, codecs) {
+            Ok(corrections) => {
+                telemetry::increment(telemetry::FILES_READ, 1);
+                telemetry::increment(telemetry::BYTES_DECODED, contents.len() as u64);
+                telemetry::increment(telemetry::ECC_CORRECTIONS, corrections);
+                Ok(())
+            }
+            Err(e) => {
+                telemetry::increment(telemetry::DECODE_FAILURES, 1);
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns the number of bytes repaired by error correction
+    fn unprocess_contents(
+        bitfield: u8,
+        mut contents: &mut Vec<u8>,
+        key: Option<[u8; 32]>,
+        cipher: Cipher,
+        limit: Option<u64>,
+        codecs: &CodecRegistry,
+    ) -> Result<u64, PfaError> {
+        let mut corrections = 0;
         if (bitfield & DataFlags::ERROR_CORRECTION) != 0 {
-            let mut c = Cursor::new(&contents);
+            let corrected = Self::correct_errors(contents)?;
+            corrections = corrected.corrections;
+            *contents = corrected.data;
+        }
 
-            let all_chunks_len = contents.len() - 12; // first chunk header size
-            let num_chunks = all_chunks_len / Self::MAX_CHUNK_SIZE;
-            let mut chunk_sizes = vec![Self::MAX_CHUNK_SIZE; num_chunks];
-            if all_chunks_len % Self::MAX_CHUNK_SIZE != 0 {
-                chunk_sizes.push(all_chunks_len % Self::MAX_CHUNK_SIZE);
+        if let Some(key) = key {
+            if (bitfield & DataFlags::ENCRYPTION) == 0 {
+                return Err(PfaError::DecryptUnencryptedFileError);
             }
+            if Self::has_encryption_frames(bitfield) {
+                *contents = encryption_frames::decrypt(&key, cipher, contents)?;
+            } else {
+                let mut c = Cursor::new(contents);
+                let nonce_length = c.read_u64::<LittleEndian>()?;
+                let mut nonce = vec![0; nonce_length as usize];
+                c.read_exact(&mut nonce)?;
+                let data_start = c.position() as usize;
 
-            let ecc_size = {
-                // Read first header
-                let mut first_header = vec![0; 12];
-                c.read_exact(&mut first_header).unwrap();
-                let dec = reed_solomon::Decoder::new(4);
+                contents = c.into_inner();
+
+                *contents = cipher.decrypt(&key, &nonce, &contents[data_start..])?;
+            }
+            if Self::has_size_padding(bitfield) {
+                let size = contents
+                    .get(..8)
+                    .map(|mut x| x.read_u64::<LittleEndian>().unwrap())
+                    .filter(|x| x.checked_add(8).is_some_and(|x| x <= contents.len() as u64))
+                    .ok_or(PfaError::CustomError("invalid size padding".into()))?;
+                contents.truncate(size as usize + 8);
+                contents.drain(..8);
+            }
+        } else if (bitfield & DataFlags::ENCRYPTION) != 0 {
+            return Err(PfaError::EncryptedFileKeyNotProvided);
+        }
 
-                let dec_first_header = dec.correct(&first_header, None).unwrap();
-                dec_first_header.data().read_u64::<LittleEndian>().unwrap()
+        if (bitfield & DataFlags::COMPRESSION) != 0 {
+            *contents = match Self::codec(bitfield) {
+                #[cfg(feature = "zstd")]
+                Some(StoredCodec::Zstd) => {
+                    // read one byte past the limit, so exceeding it is noticed without
+                    // decompressing the rest
+                    let mut decompressed = vec![];
+                    zstd::stream::read::Decoder::new(&contents[..])?
+                        .take(limit.map_or(u64::MAX, |x| x.saturating_add(1)))
+                        .read_to_end(&mut decompressed)?;
+                    Self::check_decompressed_size(decompressed.len() as u64, limit)?;
+                    decompressed
+                }
+                #[cfg(not(feature = "zstd"))]
+                Some(StoredCodec::Zstd) => {
+                    return Err(PfaError::CustomError(
+                        "data is compressed with zstd, which this build can't decode".into(),
+                    ))
+                }
+                Some(StoredCodec::Custom) => {
+                    // custom codecs can't be stopped early, only their output is checked
+                    let decompressed = codecs.decompress(contents)?;
+                    Self::check_decompressed_size(decompressed.len() as u64, limit)?;
+                    decompressed
+                }
+                Some(StoredCodec::Lz4) | None => Self::decompress_lz4(contents, limit)?,
             };
+        }
+
+        Ok(corrections)
+    }
+
+    /// Decompresses LZ4 data with its size prepended, checking the size against `limit` before
+    /// anything is allocated
+    pub(crate) fn decompress_lz4(contents: &[u8], limit: Option<u64>) -> Result<Vec<u8>, PfaError> {
+        if let Some(size) = contents.get(..4) {
+            Self::check_decompressed_size(
+                u32::from_le_bytes(size.try_into().unwrap()) as u64,
+                limit,
+            )?;
+        }
+        Ok(lz4_flex::decompress_size_prepended(contents)?)
+    }
 
-            let mut buf = vec![];
-            for chunk_size in chunk_sizes {
-                let decoder = reed_solomon::Decoder::new(ecc_size as usize);
-                let mut chunk_data = vec![0; chunk_size];
-                c.read_exact(&mut chunk_data).unwrap();
-                let dec_chunk_data = decoder.correct(&chunk_data, None).unwrap();
-                buf.extend_from_slice(dec_chunk_data.data());
+    fn check_decompressed_size(size: u64, limit: Option<u64>) -> Result<(), PfaError> {
+        match limit {
+            Some(limit) if size > limit => Err(PfaError::DecompressionLimitError { limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Decodes stored data in place like [`Self::unprocess_contents_from_flags`], except that
+    /// blocks of data which is only error corrected are kept as they are stored when they have
+    /// more errors than their parity bytes can repair, instead of failing. Returns the ranges of
+    /// the decoded data those blocks hold. Data which is also compressed or encrypted can't be
+    /// decoded with damaged blocks, so it fails as usual.
+    pub(crate) fn unprocess_contents_partially(
+        bitfield: u8,
+        contents: &mut Vec<u8>,
+        key: Option<[u8; 32]>,
+        cipher: Cipher,
+        limit: Option<u64>,
+        codecs: &CodecRegistry,
+    ) -> Result<Vec<Range<u64>>, PfaError> {
+        if !Self::has_error_correction(bitfield)
+            || bitfield & (DataFlags::COMPRESSION | DataFlags::ENCRYPTION) != 0
+            || key.is_some()
+        {
+            return Self::unprocess_contents_from_flags(
+                bitfield, contents, key, cipher, limit, codecs,
+            )
+            .map(|_| vec![]);
+        }
+        match Self::correct_blocks(contents, true) {
+            Ok((corrected, damaged)) => {
+                *contents = corrected.data;
+                telemetry::increment(telemetry::FILES_READ, 1);
+                telemetry::increment(telemetry::BYTES_DECODED, contents.len() as u64);
+                telemetry::increment(telemetry::ECC_CORRECTIONS, corrected.corrections);
+                Ok(damaged)
+            }
+            Err(e) => {
+                telemetry::increment(telemetry::DECODE_FAILURES, 1);
+                Err(e)
             }
-            *contents = buf;
         }
+    }
 
-        if let Some(key) = key {
-            if (bitfield & DataFlags::ENCRYPTION) == 0 {
-                return Err(PfaError::DecryptUnencryptedFileError);
+    /// Undoes the error correction stage of stored data, failing if a block has more errors
+    /// than its parity bytes can repair
+    fn correct_errors(contents: &[u8]) -> Result<CorrectedData, PfaError> {
+        Self::correct_blocks(contents, false).map(|(corrected, _)| corrected)
+    }
+
+    /// Undoes the error correction stage of stored data. Blocks with more errors than their
+    /// parity bytes can repair fail, unless `keep_damaged` is set, in which case their data is
+    /// kept uncorrected and its range is returned, merged with adjacent damaged ranges.
+    fn correct_blocks(
+        contents: &[u8],
+        keep_damaged: bool,
+    ) -> Result<(CorrectedData, Vec<Range<u64>>), PfaError> {
+        let header_size = 12;
+        let (first_header, chunks) = contents
+            .split_at_checked(header_size)
+            .ok_or(PfaError::ErrorCorrectionError("data too short".into()))?;
+        let (first_header, mut corrections) = reed_solomon::Decoder::new(4)
+            .correct_err_count(first_header, None)
+            .map_err(|e| PfaError::ErrorCorrectionError(format!("header: {e:?}")))?;
+        let ecc_size = first_header.data().read_u64::<LittleEndian>()? as usize;
+
+        let mut data = Vec::with_capacity(chunks.len());
+        let mut damaged: Vec<Range<u64>> = vec![];
+        let decoder = reed_solomon::Decoder::new(ecc_size.min(Self::MAX_CHUNK_SIZE));
+        for (i, chunk) in chunks.chunks(Self::MAX_CHUNK_SIZE).enumerate() {
+            if chunk.len() <= ecc_size {
+                return Err(PfaError::ErrorCorrectionError(format!(
+                    "block {i} is shorter than its {ecc_size} parity bytes"
+                )));
+            }
+            match decoder.correct_err_count(chunk, None) {
+                Ok((chunk, fixed)) => {
+                    corrections += fixed;
+                    data.extend_from_slice(chunk.data());
+                }
+                Err(_) if keep_damaged => {
+                    let start = data.len() as u64;
+                    data.extend_from_slice(&chunk[..chunk.len() - ecc_size]);
+                    let end = data.len() as u64;
+                    match damaged.last_mut() {
+                        Some(last) if last.end == start => last.end = end,
+                        _ => damaged.push(start..end),
+                    }
+                }
+                Err(e) => return Err(PfaError::ErrorCorrectionError(format!("block {i}: {e:?}"))),
             }
+        }
+        let corrected = CorrectedData {
+            data,
+            corrections: corrections as u64,
+            ecc_size,
+        };
+        Ok((corrected, damaged))
+    }
 
-            let key = aes_gcm::Key::<aes_gcm::Aes256Gcm>::from_slice(&key);
-            let cipher = aes_gcm::Aes256Gcm::new(key);
-            let mut c = Cursor::new(contents);
-            let nonce_length = c.read_u64::<LittleEndian>()?;
-            let mut nonce = vec![0; nonce_length as usize];
-            c.read_exact(&mut nonce)?;
-            let data_start = c.position() as usize;
+    /// Checks the error correction blocks of stored data, returning the number of damaged bytes
+    /// they repair. Data without error correction has nothing to check.
+    pub(crate) fn check_error_correction(bitfield: u8, contents: &[u8]) -> Result<u64, PfaError> {
+        if !Self::has_error_correction(bitfield) {
+            return Ok(0);
+        }
+        Ok(Self::correct_errors(contents)?.corrections)
+    }
 
-            contents = c.into_inner();
+    /// Stored data with the damage error correction can repair fixed, by correcting it and
+    /// encoding it again with the same number of parity bytes
+    pub(crate) fn repair_error_correction(
+        bitfield: u8,
+        contents: &[u8],
+    ) -> Result<Vec<u8>, PfaError> {
+        if !Self::has_error_correction(bitfield) {
+            return Ok(contents.to_vec());
+        }
+        let corrected = Self::correct_errors(contents)?;
+        let mut ecc = EccEncoder::with_ecc_size(corrected.ecc_size);
+        let mut repaired = ecc.header();
+        ecc.push(&corrected.data, &mut repaired);
+        ecc.finish(&mut repaired);
+        Ok(repaired)
+    }
 
-            *contents = cipher
-                .decrypt(aes_gcm::Nonce::from_slice(&nonce), &contents[data_start..])
-                .map_err(|_| PfaError::FileDecryptError)?;
-        } else if (bitfield & DataFlags::ENCRYPTION) != 0 {
-            return Err(PfaError::EncryptedFileKeyNotProvided);
+    /// Reads the headers each processing stage left in stored data, without applying any of the
+    /// stages. Error correction blocks are not corrected, so values can be garbage if the data is
+    /// damaged.
+    pub(crate) fn parse_stage_headers(bitfield: u8, contents: &[u8]) -> StageHeaders {
+        let mut headers = StageHeaders {
+            compression: bitfield & DataFlags::COMPRESSION != 0,
+            encryption: bitfield & DataFlags::ENCRYPTION != 0,
+            error_correction: bitfield & DataFlags::ERROR_CORRECTION != 0,
+            reserved_bits: bitfield & DataFlags::RESERVED,
+            size_padding: Self::has_size_padding(bitfield),
+            ..Default::default()
+        };
+
+        let mut payload = contents.to_vec();
+        if headers.error_correction {
+            let Some(ecc_size) = contents
+                .get(..8)
+                .map(|mut x| x.read_u64::<LittleEndian>().unwrap())
+            else {
+                return headers;
+            };
+            headers.ecc_size = Some(ecc_size);
+            let blocks = contents.get(12..).unwrap_or_default();
+            headers.ecc_blocks = Some(blocks.len().div_ceil(Self::MAX_CHUNK_SIZE));
+            let Some(ecc_size) = usize::try_from(ecc_size)
+                .ok()
+                .filter(|x| *x < Self::MAX_CHUNK_SIZE)
+            else {
+                return headers;
+            };
+            payload = blocks
+                .chunks(Self::MAX_CHUNK_SIZE)
+                .flat_map(|x| &x[..x.len().saturating_sub(ecc_size)])
+                .copied()
+                .collect();
         }
 
-        if (bitfield & DataFlags::COMPRESSION) != 0 {
-            *contents = lz4_flex::decompress_size_prepended(contents)?;
+        if headers.encryption && Self::has_encryption_frames(bitfield) {
+            headers.encryption_frames =
+                EncryptionFrames::parse(&payload, payload.len() as u64).ok();
+            return headers;
+        }
+        if headers.encryption {
+            let mut c = Cursor::new(&payload);
+            let Ok(nonce_length) = c.read_u64::<LittleEndian>() else {
+                return headers;
+            };
+            let mut nonce = vec![0; (nonce_length as usize).min(payload.len())];
+            if c.read_exact(&mut nonce).is_ok() {
+                headers.nonce = Some(nonce);
+            }
+            // the compression header is encrypted
+            return headers;
         }
 
-        Ok(())
+        match Self::codec(bitfield) {
+            Some(StoredCodec::Lz4) => {
+                headers.decompressed_size = payload
+                    .get(..4)
+                    .map(|mut x| x.read_u32::<LittleEndian>().unwrap());
+            }
+            Some(StoredCodec::Custom) => headers.codec_id = payload.first().copied(),
+            _ => {}
+        }
+
+        headers
+    }
+
+    /// The id archives store for files encrypted with `key`
+    pub fn key_id(key: &[u8; 32]) -> KeyId {
+        let hash = blake3::derive_key("pfa 2024 encryption key id", key);
+        let mut id = KeyId::default();
+        id.copy_from_slice(&hash[..8]);
+        id
     }
 
     pub fn generate_key() -> [u8; 32] {
@@ -237,31 +946,272 @@ impl DataFlags {
     }
 }
 
+/// Data decoded by [`DataFlags::correct_errors`]
+struct CorrectedData {
+    data: Vec<u8>,
+    /// Number of damaged bytes which were repaired
+    corrections: u64,
+    /// Parity bytes per block
+    ecc_size: usize,
+}
+
+/// Reed Solomon encodes data in blocks of [`DataFlags::MAX_CHUNK_SIZE`] bytes, including the
+/// parity bytes, after a header holding the number of parity bytes per block
+struct EccEncoder {
+    ecc_size: usize,
+    encoder: reed_solomon::Encoder,
+    /// Data which doesn't fill a whole block yet
+    pending: Vec<u8>,
+}
+
+impl EccEncoder {
+    fn new(percentage: f32) -> Self {
+        Self::with_ecc_size((percentage * DataFlags::MAX_CHUNK_SIZE as f32) as usize)
+    }
+
+    fn with_ecc_size(ecc_size: usize) -> Self {
+        Self {
+            ecc_size,
+            encoder: reed_solomon::Encoder::new(ecc_size),
+            pending: vec![],
+        }
+    }
+
+    fn block_size(&self) -> usize {
+        DataFlags::MAX_CHUNK_SIZE - self.ecc_size
+    }
+
+    /// The first block has hard coded values and stores the ecc size of the following blocks
+    fn header(&self) -> Vec<u8> {
+        let mut first_buf = vec![];
+        first_buf
+            .write_u64::<LittleEndian>(self.ecc_size as u64)
+            .unwrap();
+        reed_solomon::Encoder::new(4).encode(&first_buf).to_vec()
+    }
+
+    fn push(&mut self, mut data: &[u8], out: &mut Vec<u8>) {
+        let block_size = self.block_size();
+        if !self.pending.is_empty() {
+            let take = (block_size - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < block_size {
+                return;
+            }
+            out.extend_from_slice(&self.encoder.encode(&self.pending));
+            self.pending.clear();
+        }
+        let mut blocks = data.chunks_exact(block_size);
+        for block in &mut blocks {
+            out.extend_from_slice(&self.encoder.encode(block));
+        }
+        self.pending.extend_from_slice(blocks.remainder());
+    }
+
+    fn finish(self, out: &mut Vec<u8>) {
+        if !self.pending.is_empty() {
+            out.extend_from_slice(&self.encoder.encode(&self.pending));
+        }
+    }
+}
+
 impl Default for DataFlags {
     fn default() -> Self {
         Self {
             compression: DataCompressionType::Forced(false),
+            codec: CompressionCodec::Lz4,
             encryption_key: None,
+            cipher: Cipher::Aes256Gcm,
+            encryption_frame_size: None,
+            size_padding: None,
             error_correction: None,
+            min_compression_size: None,
+        }
+    }
+}
+
+/// Parses a flag specification, the comma separated syntax every tool accepts for flags:
+/// - `auto`, `compress` or `store` for automatic, forced or no compression, `auto` if none is
+///   given
+/// - `ecc=<fraction>`, error correction using the given fraction of each block, e.g. `ecc=0.2`
+/// - `enc=keyfile:<path>` or `enc=hex:<key>`, encryption with a key read from a file, holding
+///   either the 32 key bytes or them in hex, or given in hex
+/// - `cipher=<name>`, the cipher used for encryption, `aes256-gcm` or `xchacha20-poly1305`
+/// - `frames=<bytes>`, encryption in frames of the given size
+/// - `pad=<bytes>`, padding encrypted data to a multiple of the given size
+/// - `minsize=<bytes>`, storing smaller files with automatic compression without trying to
+///   compress them
+/// - `codec=<name>`, the codec used for compression, `lz4`, `zstd` or the name of a custom codec
+///   when parsed with [`DataFlags::parse_with_codecs`]
+impl FromStr for DataFlags {
+    type Err = PfaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with_codecs(s, &CodecRegistry::default())
+    }
+}
+
+impl DataFlags {
+    /// Parses a flag specification like [`DataFlags::from_str`], where `codec=<name>` can also
+    /// name one of `codecs`
+    pub fn parse_with_codecs(s: &str, codecs: &CodecRegistry) -> Result<Self, PfaError> {
+        let invalid = |message: String| PfaError::CustomError(format!("flags '{s}': {message}"));
+        let mut flags = DataFlags::auto();
+        for item in s.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let (name, value) = match item.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (item, None),
+            };
+            match (name, value) {
+                ("auto", None) => flags.compression = DataCompressionType::Automatic,
+                ("compress", None) => flags.compression = DataCompressionType::Forced(true),
+                ("store", None) => flags.compression = DataCompressionType::Forced(false),
+                ("ecc", Some(value)) => {
+                    let fraction = value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|x| (0.0..1.0).contains(x))
+                        .ok_or_else(|| invalid(format!("invalid ecc fraction '{value}'")))?;
+                    flags.error_correction = Some(fraction);
+                }
+                ("enc", Some(value)) => {
+                    let key = match value.split_once(':') {
+                        Some(("keyfile", path)) => {
+                            let key = std::fs::read(path)
+                                .map_err(|e| invalid(format!("read key file '{path}': {e}")))?;
+                            match <[u8; 32]>::try_from(&key[..]) {
+                                Ok(key) => Some(key),
+                                Err(_) => std::str::from_utf8(&key)
+                                    .ok()
+                                    .and_then(|x| parse_hex_key(x.trim())),
+                            }
+                        }
+                        Some(("hex", hex)) => parse_hex_key(hex),
+                        _ => return Err(invalid(format!("unknown key source '{value}'"))),
+                    };
+                    flags.encryption_key =
+                        Some(key.ok_or_else(|| invalid("keys must be 32 bytes".into()))?);
+                }
+                ("cipher", Some(value)) => {
+                    flags.cipher = value
+                        .parse()
+                        .map_err(|_| invalid(format!("unknown cipher '{value}'")))?;
+                }
+                ("frames", Some(value)) => {
+                    let size = value
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|x| *x > 0)
+                        .ok_or_else(|| invalid(format!("invalid frame size '{value}'")))?;
+                    flags.encryption_frame_size = Some(size);
+                }
+                ("pad", Some(value)) => {
+                    let bucket = value
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|x| *x > 0)
+                        .ok_or_else(|| invalid(format!("invalid padding size '{value}'")))?;
+                    flags.size_padding = Some(bucket);
+                }
+                ("minsize", Some(value)) => {
+                    let bytes = value
+                        .parse::<u32>()
+                        .map_err(|_| invalid(format!("invalid minimum size '{value}'")))?;
+                    flags.min_compression_size = Some(bytes);
+                }
+                ("codec", Some(value)) => {
+                    flags.codec = match value {
+                        "lz4" => CompressionCodec::Lz4,
+                        #[cfg(feature = "zstd")]
+                        "zstd" => CompressionCodec::Zstd,
+                        #[cfg(not(feature = "zstd"))]
+                        "zstd" => return Err(invalid("zstd isn't supported by this build".into())),
+                        _ => CompressionCodec::Custom(
+                            codecs
+                                .find_by_name(value)
+                                .ok_or_else(|| invalid(format!("unknown codec '{value}'")))?
+                                .clone(),
+                        ),
+                    };
+                }
+                _ => return Err(invalid(format!("unknown flag '{item}'"))),
+            }
         }
+        Ok(flags)
+    }
+}
+
+/// Parses a key written as 64 hex digits, as in `enc=hex:{key}`. Also used for the hashes of
+/// [build caches](crate::writer::build_cache::BuildCache).
+pub fn parse_hex_key(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// Writes the flags as a flag specification, see [`DataFlags::from_str`]. Keys are never
+/// written, encryption is shown as `enc=hidden`, which doesn't parse.
+impl Display for DataFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.compression {
+            DataCompressionType::Automatic => write!(f, "auto")?,
+            DataCompressionType::Forced(true) => write!(f, "compress")?,
+            DataCompressionType::Forced(false) => write!(f, "store")?,
+        }
+        if let Some(fraction) = self.error_correction {
+            write!(f, ",ecc={fraction}")?;
+        }
+        if self.encryption_key.is_some() {
+            write!(f, ",enc=hidden")?;
+        }
+        if self.cipher != Cipher::Aes256Gcm {
+            write!(f, ",cipher={}", self.cipher)?;
+        }
+        if let Some(size) = self.encryption_frame_size {
+            write!(f, ",frames={size}")?;
+        }
+        if let Some(bucket) = self.size_padding {
+            write!(f, ",pad={bucket}")?;
+        }
+        if let Some(bytes) = self.min_compression_size {
+            write!(f, ",minsize={bytes}")?;
+        }
+        if !matches!(self.codec, CompressionCodec::Lz4) {
+            write!(f, ",codec={}", self.codec.name())?;
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{DataCompressionType, DataFlags};
+    use super::{Cipher, CodecRegistry, DataCompressionType, DataFlags, EncryptionFrames};
 
     #[test]
     fn no_compression_test() {
         let data = vec![5; 2000];
         let flags = DataFlags::new(None, None, DataCompressionType::Forced(false));
-        let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data);
+        let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data).unwrap();
 
         assert_eq!(data.len(), new_data.len());
-        assert_eq!(bitfield, 0b11111000);
+        assert_eq!(bitfield, 0b00000000);
 
         let original_data = data;
-        DataFlags::unprocess_contents_from_flags(bitfield, &mut new_data, None).unwrap();
+        DataFlags::unprocess_contents_from_flags(
+            bitfield,
+            &mut new_data,
+            None,
+            Cipher::default(),
+            None,
+            &CodecRegistry::default(),
+        )
+        .unwrap();
         assert_eq!(original_data, new_data);
     }
 
@@ -269,22 +1219,91 @@ mod tests {
     fn forced_compression_test() {
         let data = vec![5; 2000];
         let flags = DataFlags::new(None, None, DataCompressionType::Forced(true));
-        let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data);
+        let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data).unwrap();
 
         assert_ne!(data.len(), new_data.len());
-        assert_eq!(bitfield, 0b11111001);
+        assert_eq!(bitfield, 0b00000001);
 
         let original_data = data;
-        DataFlags::unprocess_contents_from_flags(bitfield, &mut new_data, None).unwrap();
+        DataFlags::unprocess_contents_from_flags(
+            bitfield,
+            &mut new_data,
+            None,
+            Cipher::default(),
+            None,
+            &CodecRegistry::default(),
+        )
+        .unwrap();
         assert_eq!(original_data, new_data);
     }
 
+    #[test]
+    fn decompression_limit_test() {
+        let data = vec![5; 2000];
+        let (stored, bitfield) = DataFlags::forced_compression()
+            .process_content_and_generate_flags(&data)
+            .unwrap();
+
+        let mut new_data = stored.clone();
+        let result = DataFlags::unprocess_contents_from_flags(
+            bitfield,
+            &mut new_data,
+            None,
+            Cipher::default(),
+            Some(1999),
+            &CodecRegistry::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(crate::PfaError::DecompressionLimitError { limit: 1999 })
+        ));
+        let mut new_data = stored;
+        DataFlags::unprocess_contents_from_flags(
+            bitfield,
+            &mut new_data,
+            None,
+            Cipher::default(),
+            Some(2000),
+            &CodecRegistry::default(),
+        )
+        .unwrap();
+        assert_eq!(data, new_data);
+
+        #[cfg(feature = "zstd")]
+        {
+            let flags =
+                DataFlags::forced_compression().compression_codec(super::CompressionCodec::Zstd);
+            let (stored, bitfield) = flags.process_content_and_generate_flags(&data).unwrap();
+            let mut new_data = stored.clone();
+            assert!(DataFlags::unprocess_contents_from_flags(
+                bitfield,
+                &mut new_data,
+                None,
+                Cipher::default(),
+                Some(100),
+                &CodecRegistry::default()
+            )
+            .is_err());
+            let mut new_data = stored;
+            DataFlags::unprocess_contents_from_flags(
+                bitfield,
+                &mut new_data,
+                None,
+                Cipher::default(),
+                Some(2000),
+                &CodecRegistry::default(),
+            )
+            .unwrap();
+            assert_eq!(data, new_data);
+        }
+    }
+
     #[test]
     fn auto_compression_test() {
         for size in 0..5000 {
             let data = vec![5; size];
             let flags = DataFlags::new(None, None, DataCompressionType::Automatic);
-            let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data);
+            let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data).unwrap();
 
             assert!(
                 data.len() >= new_data.len(),
@@ -292,7 +1311,15 @@ mod tests {
             );
 
             let original_data = data;
-            DataFlags::unprocess_contents_from_flags(bitfield, &mut new_data, None).unwrap();
+            DataFlags::unprocess_contents_from_flags(
+                bitfield,
+                &mut new_data,
+                None,
+                Cipher::default(),
+                None,
+                &CodecRegistry::default(),
+            )
+            .unwrap();
             assert_eq!(original_data, new_data);
         }
     }
@@ -302,22 +1329,94 @@ mod tests {
         let data = vec![5; 2000];
         let key = DataFlags::generate_key();
         let flags = DataFlags::new(None, Some(key), DataCompressionType::Forced(false));
-        let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data);
+        let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data).unwrap();
 
         let original_data = data;
-        DataFlags::unprocess_contents_from_flags(bitfield, &mut new_data, Some(key)).unwrap();
+        DataFlags::unprocess_contents_from_flags(
+            bitfield,
+            &mut new_data,
+            Some(key),
+            Cipher::default(),
+            None,
+            &CodecRegistry::default(),
+        )
+        .unwrap();
         assert_eq!(original_data, new_data);
     }
 
+    #[test]
+    fn xchacha_encryption_test() {
+        let data = (0..2000).map(|x| x as u8).collect::<Vec<_>>();
+        let key = DataFlags::generate_key();
+        for frames in [None, Some(300)] {
+            let flags = DataFlags::no_compression()
+                .encryption(Some(key))
+                .encryption_frames(frames)
+                .cipher(Cipher::XChaCha20Poly1305);
+            let (new_data, bitfield) = flags.process_content_and_generate_flags(&data).unwrap();
+            if frames.is_none() {
+                assert_eq!(new_data[..8], 24u64.to_le_bytes());
+            }
+
+            let mut wrong_cipher = new_data.clone();
+            assert!(DataFlags::unprocess_contents_from_flags(
+                bitfield,
+                &mut wrong_cipher,
+                Some(key),
+                Cipher::Aes256Gcm,
+                None,
+                &CodecRegistry::default()
+            )
+            .is_err());
+            let mut decoded = new_data;
+            DataFlags::unprocess_contents_from_flags(
+                bitfield,
+                &mut decoded,
+                Some(key),
+                Cipher::XChaCha20Poly1305,
+                None,
+                &CodecRegistry::default(),
+            )
+            .unwrap();
+            assert_eq!(data, decoded);
+        }
+
+        let flags = "store,cipher=xchacha20-poly1305"
+            .parse::<DataFlags>()
+            .unwrap();
+        assert_eq!(flags.to_string(), "store,cipher=xchacha20-poly1305");
+        assert_ne!(
+            flags.fingerprint(),
+            DataFlags::no_compression().fingerprint()
+        );
+
+        // keys are told apart by their ids
+        let encrypted = |key| DataFlags::no_compression().encryption(Some(key));
+        let key = DataFlags::generate_key();
+        assert_eq!(encrypted(key).fingerprint(), encrypted(key).fingerprint());
+        assert_ne!(
+            encrypted(key).fingerprint(),
+            encrypted(DataFlags::generate_key()).fingerprint()
+        );
+    }
+
     #[test]
     fn encryption_with_compression_test() {
         let data = vec![5; 2000];
         let key = DataFlags::generate_key();
         let flags = DataFlags::new(None, Some(key), DataCompressionType::Forced(true));
-        let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data);
+        let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data).unwrap();
 
         let original_data = data;
-        DataFlags::unprocess_contents_from_flags(bitfield, &mut new_data, Some(key)).unwrap();
+        DataFlags::unprocess_contents_from_flags(
+            bitfield,
+            &mut new_data,
+            Some(key),
+            Cipher::default(),
+            None,
+            &CodecRegistry::default(),
+        )
+        .unwrap();
         assert_eq!(original_data, new_data);
     }
 
@@ -325,7 +1424,7 @@ mod tests {
     fn error_correction_test() {
         let data = vec![5; 2000];
         let flags = DataFlags::auto().error_correction(Some(0.5));
-        let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data);
+        let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data).unwrap();
 
         for (i, val) in new_data.iter_mut().enumerate() {
             if i % 3 == 0 {
@@ -335,7 +1434,15 @@ mod tests {
         }
 
         let original_data = data;
-        DataFlags::unprocess_contents_from_flags(bitfield, &mut new_data, None).unwrap();
+        DataFlags::unprocess_contents_from_flags(
+            bitfield,
+            &mut new_data,
+            None,
+            Cipher::default(),
+            None,
+            &CodecRegistry::default(),
+        )
+        .unwrap();
         assert_eq!(original_data, new_data);
     }
 
@@ -346,7 +1453,7 @@ mod tests {
         let flags = DataFlags::auto()
             .error_correction(Some(0.5))
             .encryption(Some(key));
-        let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data);
+        let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data).unwrap();
 
         for (i, val) in new_data.iter_mut().enumerate() {
             if i % 3 == 0 {
@@ -356,7 +1463,157 @@ mod tests {
         }
 
         let original_data = data;
-        DataFlags::unprocess_contents_from_flags(bitfield, &mut new_data, Some(key)).unwrap();
+        DataFlags::unprocess_contents_from_flags(
+            bitfield,
+            &mut new_data,
+            Some(key),
+            Cipher::default(),
+            None,
+            &CodecRegistry::default(),
+        )
+        .unwrap();
         assert_eq!(original_data, new_data);
     }
+
+    #[test]
+    fn size_padding_test() {
+        let key = DataFlags::generate_key();
+        let flags = |size| {
+            DataFlags::no_compression()
+                .encryption(Some(key))
+                .size_padding(size)
+        };
+        for frames in [None, Some(64)] {
+            let (short, bitfield) = flags(Some(256))
+                .encryption_frames(frames)
+                .process_content_and_generate_flags(&[1; 10])
+                .unwrap();
+            let (long, _) = flags(Some(256))
+                .encryption_frames(frames)
+                .process_content_and_generate_flags(&[2; 200])
+                .unwrap();
+            let (unpadded, _) = flags(None)
+                .encryption_frames(frames)
+                .process_content_and_generate_flags(&[1; 10])
+                .unwrap();
+            assert_eq!(short.len(), long.len());
+            assert!(unpadded.len() < short.len());
+            assert!(DataFlags::has_size_padding(bitfield));
+            assert!(DataFlags::parse_stage_headers(bitfield, &short).size_padding);
+
+            let mut contents = short;
+            DataFlags::unprocess_contents_from_flags(
+                bitfield,
+                &mut contents,
+                Some(key),
+                Cipher::default(),
+                None,
+                &CodecRegistry::default(),
+            )
+            .unwrap();
+            assert_eq!(contents, [1; 10]);
+        }
+
+        // padding needs encryption
+        let (_, bitfield) = DataFlags::no_compression()
+            .size_padding(Some(256))
+            .process_content_and_generate_flags(&[1; 10])
+            .unwrap();
+        assert!(!DataFlags::has_size_padding(bitfield));
+    }
+
+    #[test]
+    fn flag_spec_test() {
+        let flags = "compress, ecc=0.25,frames=4096,pad=512"
+            .parse::<DataFlags>()
+            .unwrap();
+        assert_eq!(flags.to_string(), "compress,ecc=0.25,frames=4096,pad=512");
+        assert_eq!(
+            flags
+                .to_string()
+                .parse::<DataFlags>()
+                .unwrap()
+                .fingerprint(),
+            flags.fingerprint()
+        );
+        assert!("".parse::<DataFlags>().unwrap().is_auto());
+        assert_eq!(DataFlags::no_compression().to_string(), "store");
+
+        let key = DataFlags::generate_key();
+        let hex = key.iter().map(|x| format!("{x:02x}")).collect::<String>();
+        let path = std::env::temp_dir().join(format!("pfa_key_{hex}.key"));
+        std::fs::write(&path, format!("{hex}\n")).unwrap();
+        let from_file = format!("enc=keyfile:{}", path.display()).parse::<DataFlags>();
+        std::fs::remove_file(&path).unwrap();
+        let from_hex = format!("auto,enc=hex:{hex}").parse::<DataFlags>().unwrap();
+        assert_eq!(from_file.unwrap().encryption_key, Some(key));
+        assert_eq!(from_hex.encryption_key, Some(key));
+        assert_eq!(from_hex.to_string(), "auto,enc=hidden");
+
+        for invalid in [
+            "ecc=2",
+            "enc=hex:00",
+            "frames=0",
+            "fast",
+            "store=1",
+            "enc=hidden",
+        ] {
+            assert!(invalid.parse::<DataFlags>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn encryption_frames_test() {
+        let key = DataFlags::generate_key();
+        for len in [0, 1, 99, 100, 101, 1000] {
+            let data = (0..len).map(|x| x as u8).collect::<Vec<_>>();
+            let flags = DataFlags::no_compression()
+                .encryption(Some(key))
+                .encryption_frames(Some(100));
+            let (mut new_data, bitfield) = flags.process_content_and_generate_flags(&data).unwrap();
+            assert!(DataFlags::has_encryption_frames(bitfield));
+            assert_eq!(DataFlags::unknown_bits(bitfield), 0);
+
+            let frames = EncryptionFrames::parse(&new_data, new_data.len() as u64).unwrap();
+            assert_eq!(frames.len(), len as u64);
+            let stored = frames.stored_range(50..250);
+            let range = frames
+                .decrypt_range(
+                    &key,
+                    &new_data[stored.start as usize..stored.end as usize],
+                    50..250,
+                )
+                .unwrap();
+            assert_eq!(range, data[50.min(data.len())..250.min(data.len())]);
+
+            let mut truncated = new_data.clone();
+            truncated.truncate(
+                new_data
+                    .len()
+                    .saturating_sub(116)
+                    .max(EncryptionFrames::HEADER_SIZE + 16),
+            );
+            if truncated.len() != new_data.len() {
+                assert!(DataFlags::unprocess_contents_from_flags(
+                    bitfield,
+                    &mut truncated,
+                    Some(key),
+                    Cipher::default(),
+                    None,
+                    &CodecRegistry::default()
+                )
+                .is_err());
+            }
+            DataFlags::unprocess_contents_from_flags(
+                bitfield,
+                &mut new_data,
+                Some(key),
+                Cipher::default(),
+                None,
+                &CodecRegistry::default(),
+            )
+            .unwrap();
+            assert_eq!(data, new_data);
+        }
+    }
 }
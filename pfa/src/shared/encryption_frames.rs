@@ -0,0 +1,269 @@
+//! Chunked AES256-GCM or XChaCha20-Poly1305, where the plaintext is split into frames which are
+//! encrypted and authenticated on their own, so part of a file can be decrypted without the rest
+//! of it.
+//!
+//! Stored as {frame_size:u32}{nonce_prefix:u8\[7\]}{frames}, where every frame is the ciphertext
+//! of `frame_size` plaintext bytes (fewer for the last one) followed by a 16 byte tag. The nonce
+//! of a frame is {nonce_prefix}{frame_index:u32 big endian}{is_last_frame:u8}, so frames can't be
+//! reordered and the file can't be truncated at a frame boundary. XChaCha20-Poly1305 nonces are
+//! the same 12 bytes preceded by 12 zero bytes.
+
+use std::ops::Range;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use rand::RngCore;
+
+use crate::{shared::Cipher, PfaError};
+
+const NONCE_PREFIX_SIZE: usize = 7;
+const TAG_SIZE: u64 = 16;
+
+/// Position of the frames of a file stored with chunked encryption, see
+/// [`DataFlags::encryption_frames`](crate::shared::DataFlags::encryption_frames)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionFrames {
+    frame_size: u32,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    num_frames: u64,
+    len: u64,
+    cipher: Cipher,
+}
+
+impl EncryptionFrames {
+    /// Size of the header at the start of the stored data
+    pub const HEADER_SIZE: usize = 4 + NONCE_PREFIX_SIZE;
+
+    /// Reads the layout of a file's frames from the start of its stored data, `stored_size` is
+    /// the size of all of its stored data
+    pub fn parse(header: &[u8], stored_size: u64) -> Result<Self, PfaError> {
+        let invalid = || PfaError::CustomError("invalid encryption frame header".into());
+        let mut header = header.get(..Self::HEADER_SIZE).ok_or_else(invalid)?;
+        let frame_size = header.read_u32::<LittleEndian>()?;
+        let nonce_prefix = header.try_into().map_err(|_| invalid())?;
+
+        let frames_size = stored_size
+            .checked_sub(Self::HEADER_SIZE as u64)
+            .ok_or_else(invalid)?;
+        let stored_frame_size = u64::from(frame_size) + TAG_SIZE;
+        let num_frames = frames_size.div_ceil(stored_frame_size).max(1);
+        let len = frames_size
+            .checked_sub(num_frames * TAG_SIZE)
+            .filter(|_| frame_size > 0)
+            .ok_or_else(invalid)?;
+        Ok(Self {
+            frame_size,
+            nonce_prefix,
+            num_frames,
+            len,
+            cipher: Cipher::default(),
+        })
+    }
+
+    /// Decrypts the frames with `cipher` rather than AES256-GCM, see
+    /// [`PfaReader::get_cipher`](crate::reader::PfaReader::get_cipher)
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    pub fn frame_size(&self) -> u32 {
+        self.frame_size
+    }
+
+    pub fn num_frames(&self) -> u64 {
+        self.num_frames
+    }
+
+    /// Size of the plaintext
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Indices of the frames holding a range of the plaintext
+    pub fn frames_for(&self, range: Range<u64>) -> Range<u64> {
+        let end = range.end.min(self.len);
+        if range.start >= end {
+            return 0..0;
+        }
+        let frame_size = u64::from(self.frame_size);
+        range.start / frame_size..end.div_ceil(frame_size)
+    }
+
+    /// Range of the stored data holding a frame
+    pub fn frame_location(&self, index: u64) -> Range<u64> {
+        let stored_frame_size = u64::from(self.frame_size) + TAG_SIZE;
+        let start = Self::HEADER_SIZE as u64 + index * stored_frame_size;
+        let end = if index + 1 == self.num_frames {
+            Self::HEADER_SIZE as u64 + self.len + self.num_frames * TAG_SIZE
+        } else {
+            start + stored_frame_size
+        };
+        start..end
+    }
+
+    /// Decrypts the frame at `index`, `frame` being the stored data at
+    /// [`Self::frame_location`]
+    pub fn decrypt_frame(
+        &self,
+        key: &[u8; 32],
+        index: u64,
+        frame: &[u8],
+    ) -> Result<Vec<u8>, PfaError> {
+        let index_bytes = u32::try_from(index)
+            .map_err(|_| PfaError::FileDecryptError)?
+            .to_be_bytes();
+        let nonce = frame_nonce(
+            self.cipher,
+            &self.nonce_prefix,
+            index_bytes,
+            index + 1 == self.num_frames,
+        );
+        self.cipher.decrypt(key, &nonce, frame)
+    }
+
+    /// Range of the stored data holding the frames for a range of the plaintext
+    pub fn stored_range(&self, range: Range<u64>) -> Range<u64> {
+        let frames = self.frames_for(range);
+        if frames.is_empty() {
+            return 0..0;
+        }
+        self.frame_location(frames.start).start..self.frame_location(frames.end - 1).end
+    }
+
+    /// Decrypts a range of the plaintext, `stored` being the stored data at
+    /// [`Self::stored_range`] for the same range
+    pub fn decrypt_range(
+        &self,
+        key: &[u8; 32],
+        stored: &[u8],
+        range: Range<u64>,
+    ) -> Result<Vec<u8>, PfaError> {
+        let frames = self.frames_for(range.clone());
+        if frames.is_empty() {
+            return Ok(vec![]);
+        }
+        let stored_start = self.frame_location(frames.start).start;
+        let mut plaintext = vec![];
+        for index in frames.clone() {
+            let location = self.frame_location(index);
+            let frame = stored
+                .get(
+                    (location.start - stored_start) as usize
+                        ..(location.end - stored_start) as usize,
+                )
+                .ok_or(PfaError::FileDecryptError)?;
+            plaintext.extend(self.decrypt_frame(key, index, frame)?);
+        }
+        let start = (range.start - frames.start * u64::from(self.frame_size)) as usize;
+        let end = start + (range.end.min(self.len) - range.start) as usize;
+        plaintext.truncate(end);
+        plaintext.drain(..start);
+        Ok(plaintext)
+    }
+}
+
+fn frame_nonce(
+    cipher: Cipher,
+    prefix: &[u8; NONCE_PREFIX_SIZE],
+    index: [u8; 4],
+    last: bool,
+) -> Vec<u8> {
+    let mut nonce = vec![0; cipher.nonce_size()];
+    let start = nonce.len() - 12;
+    nonce[start..start + NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[start + NONCE_PREFIX_SIZE..start + 11].copy_from_slice(&index);
+    nonce[start + 11] = u8::from(last);
+    nonce
+}
+
+/// Encrypts `contents` in frames of `frame_size` bytes. The frame size is raised when the frame
+/// indices wouldn't fit in a u32.
+pub(crate) fn encrypt(key: &[u8; 32], cipher: Cipher, frame_size: u32, contents: &[u8]) -> Vec<u8> {
+    let min_frame_size = (contents.len() as u64).div_ceil(u64::from(u32::MAX));
+    let frame_size = u64::from(frame_size).max(min_frame_size).max(1);
+    let frame_size = u32::try_from(frame_size).unwrap_or(u32::MAX);
+
+    let mut encryptor = FrameEncryptor::new(key, cipher, frame_size);
+    let mut out = Vec::with_capacity(
+        EncryptionFrames::HEADER_SIZE + contents.len() + contents.len() / frame_size as usize * 16,
+    );
+    out.extend(encryptor.header());
+    let mut frames = contents.chunks(frame_size as usize).peekable();
+    if frames.peek().is_none() {
+        out.extend(
+            encryptor
+                .encrypt_frame(&[], true)
+                .expect("first frame index"),
+        );
+    }
+    while let Some(frame) = frames.next() {
+        let last = frames.peek().is_none();
+        out.extend(
+            encryptor
+                .encrypt_frame(frame, last)
+                .expect("frame size fits every index"),
+        );
+    }
+    out
+}
+
+/// Encrypts frames one at a time, for contents which aren't available all at once
+pub(crate) struct FrameEncryptor {
+    key: [u8; 32],
+    cipher: Cipher,
+    frame_size: u32,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    index: u64,
+}
+
+impl FrameEncryptor {
+    pub(crate) fn new(key: &[u8; 32], cipher: Cipher, frame_size: u32) -> Self {
+        let mut nonce_prefix = [0; NONCE_PREFIX_SIZE];
+        rand::rngs::OsRng.fill_bytes(&mut nonce_prefix);
+        Self {
+            key: *key,
+            cipher,
+            frame_size: frame_size.max(1),
+            nonce_prefix,
+            index: 0,
+        }
+    }
+
+    pub(crate) fn frame_size(&self) -> u32 {
+        self.frame_size
+    }
+
+    /// Stored before the first frame
+    pub(crate) fn header(&self) -> [u8; EncryptionFrames::HEADER_SIZE] {
+        let mut header = [0; EncryptionFrames::HEADER_SIZE];
+        header[..4].copy_from_slice(&self.frame_size.to_le_bytes());
+        header[4..].copy_from_slice(&self.nonce_prefix);
+        header
+    }
+
+    /// Encrypts the next frame, which must be `frame_size` bytes long unless it's the last one
+    pub(crate) fn encrypt_frame(&mut self, frame: &[u8], last: bool) -> Result<Vec<u8>, PfaError> {
+        let index = u32::try_from(self.index).map_err(|_| {
+            PfaError::CustomError("too many frames for the encryption frame size".into())
+        })?;
+        self.index += 1;
+        let nonce = frame_nonce(self.cipher, &self.nonce_prefix, index.to_be_bytes(), last);
+        Ok(self.cipher.encrypt(&self.key, &nonce, frame))
+    }
+}
+
+/// Decrypts every frame of `stored`
+pub(crate) fn decrypt(key: &[u8; 32], cipher: Cipher, stored: &[u8]) -> Result<Vec<u8>, PfaError> {
+    let frames = EncryptionFrames::parse(stored, stored.len() as u64)?.with_cipher(cipher);
+    let mut plaintext = Vec::with_capacity(frames.len() as usize);
+    for index in 0..frames.num_frames() {
+        let location = frames.frame_location(index);
+        let frame = &stored[location.start as usize..location.end as usize];
+        plaintext.extend(frames.decrypt_frame(key, index, frame)?);
+    }
+    Ok(plaintext)
+}
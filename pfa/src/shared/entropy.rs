@@ -0,0 +1,71 @@
+/// Files smaller than this are always trial compressed, since a sample of them says little and
+/// compressing them is cheap anyway
+const MIN_SIZE: usize = 4096;
+/// Size of each sampled chunk
+const CHUNK_SIZE: usize = 1024;
+/// Number of chunks sampled, spread evenly across the contents
+const CHUNKS: usize = 8;
+/// Bits per byte above which data is treated as incompressible. Compressed media and ciphertext
+/// sit just below 8, while text and most binaries are well under 7.
+const INCOMPRESSIBLE_ENTROPY: f64 = 7.5;
+
+/// Estimated Shannon entropy of `contents` in bits per byte, from 0 to 8. Large contents are
+/// estimated from evenly spaced chunks rather than read whole.
+pub fn sample_entropy(contents: &[u8]) -> f64 {
+    let mut counts = [0u64; 256];
+    let mut total = 0;
+    let mut count = |chunk: &[u8]| {
+        for &byte in chunk {
+            counts[usize::from(byte)] += 1;
+        }
+        total += chunk.len();
+    };
+    if contents.len() <= CHUNK_SIZE * CHUNKS {
+        count(contents);
+    } else {
+        let stride = (contents.len() - CHUNK_SIZE) / (CHUNKS - 1);
+        for i in 0..CHUNKS {
+            count(&contents[i * stride..][..CHUNK_SIZE]);
+        }
+    }
+    if total == 0 {
+        return 0.0;
+    }
+
+    let total = total as f64;
+    counts
+        .iter()
+        .filter(|x| **x > 0)
+        .map(|&x| {
+            let p = x as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Whether compressing `contents` is unlikely to help, such as for compressed media or
+/// encrypted input, so trial compression can be skipped
+pub(crate) fn looks_incompressible(contents: &[u8]) -> bool {
+    contents.len() >= MIN_SIZE && sample_entropy(contents) > INCOMPRESSIBLE_ENTROPY
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::RngCore;
+
+    use super::*;
+
+    #[test]
+    fn test_sample_entropy() {
+        let mut random = vec![0; 100_000];
+        rand::thread_rng().fill_bytes(&mut random);
+        assert!(sample_entropy(&random) > 7.9);
+        assert!(looks_incompressible(&random));
+        assert!(!looks_incompressible(&random[..MIN_SIZE - 1]));
+
+        assert_eq!(sample_entropy(&[]), 0.0);
+        assert_eq!(sample_entropy(&[7; 100_000]), 0.0);
+        let text = b"the quick brown fox jumps over the lazy dog ".repeat(1000);
+        assert!(!looks_incompressible(&text));
+    }
+}
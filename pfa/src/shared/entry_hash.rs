@@ -0,0 +1,219 @@
+//! Merkle style hashes of catalog entries. A file is hashed from its name, flags and stored
+//! (processed) contents, and a directory from its name and the hashes of its children in catalog
+//! order, so two subtrees with the same hash are identical.
+
+use std::io::{Cursor, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::PfaError;
+
+pub type EntryHash = [u8; 32];
+
+pub(crate) const FILE_TAG: u8 = 0;
+pub(crate) const DIRECTORY_TAG: u8 = 1;
+
+/// Hash of a file entry, `contents` is the data slice exactly as it is stored in the archive
+pub fn file_hash(name: &str, flags: u8, contents: &[u8]) -> EntryHash {
+    file_hash_of_digest(name, flags, blake3::hash(contents).as_bytes())
+}
+
+/// Like [`file_hash`], given the BLAKE3 hash of the stored contents
+pub(crate) fn file_hash_of_digest(name: &str, flags: u8, contents_hash: &[u8; 32]) -> EntryHash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[FILE_TAG]);
+    hash_name(&mut hasher, name);
+    hasher.update(&[flags]);
+    hasher.update(contents_hash);
+    *hasher.finalize().as_bytes()
+}
+
+/// Hash of a directory entry, `name` doesn't include the trailing '/'
+pub fn directory_hash<'a>(
+    name: &str,
+    children: impl ExactSizeIterator<Item = &'a EntryHash>,
+) -> EntryHash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[DIRECTORY_TAG]);
+    hash_name(&mut hasher, name);
+    hasher.update(&(children.len() as u64).to_le_bytes());
+    for child in children {
+        hasher.update(child);
+    }
+    *hasher.finalize().as_bytes()
+}
+
+fn hash_name(hasher: &mut blake3::Hasher, name: &str) {
+    hasher.update(&(name.len() as u64).to_le_bytes());
+    hasher.update(name.as_bytes());
+}
+
+/// One directory on the path from a file up to the root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProofLevel {
+    /// Directory name without the trailing '/', empty for the root
+    pub name: String,
+    /// Position of the entry below this level within the directory
+    pub index: u32,
+    /// Hashes of every other child of the directory, in catalog order
+    pub siblings: Vec<EntryHash>,
+    /// Buckets of sharded directories are hashed like directories but aren't part of the path
+    pub bucket: bool,
+}
+
+/// Proves that a file with the given contents is part of the archive with a known root hash,
+/// without needing the rest of the archive. The slice location isn't covered by the proof, it only
+/// tells the holder where to find the data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryProof {
+    pub name: String,
+    pub flags: u8,
+    /// Absolute offset of the stored data within the archive
+    pub slice_offset: u64,
+    pub slice_size: u64,
+    /// Ordered from the file's parent directory up to the root
+    pub levels: Vec<ProofLevel>,
+}
+
+impl EntryProof {
+    /// Written before the name of bucket levels, directory names are at most 254 bytes since the
+    /// catalog stores them with a trailing '/'
+    pub(crate) const BUCKET_MARKER: u8 = u8::MAX;
+
+    /// The full path of the file this proof is for
+    pub fn path(&self) -> String {
+        let mut parts = self
+            .levels
+            .iter()
+            .rev()
+            .skip(1)
+            .filter(|x| !x.bucket)
+            .map(|x| &x.name[..])
+            .collect::<Vec<_>>();
+        parts.push(&self.name);
+        format!("/{}", parts.join("/"))
+    }
+
+    /// Computes the root hash implied by the proof for the given stored contents
+    pub fn root_hash(&self, stored_contents: &[u8]) -> Result<EntryHash, PfaError> {
+        let mut hash = file_hash(&self.name, self.flags, stored_contents);
+        for level in &self.levels {
+            let index = level.index as usize;
+            if index > level.siblings.len() {
+                return Err(PfaError::CustomError("proof index out of bounds".into()));
+            }
+            let children = level.siblings[..index]
+                .iter()
+                .chain(std::iter::once(&hash))
+                .chain(level.siblings[index..].iter())
+                .copied()
+                .collect::<Vec<_>>();
+            hash = directory_hash(&level.name, children.iter());
+        }
+        Ok(hash)
+    }
+
+    /// Checks that `stored_contents` is the data stored at `path` in the archive with the given
+    /// root hash. The root hash must come from a trusted source, such as a signed release
+    /// manifest.
+    pub fn verify(&self, root_hash: &EntryHash, path: &str, stored_contents: &[u8]) -> bool {
+        let path = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("/{path}")
+        };
+        self.path() == path
+            && stored_contents.len() as u64 == self.slice_size
+            && self
+                .root_hash(stored_contents)
+                .is_ok_and(|hash| &hash == root_hash)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PfaError> {
+        let mut buf = vec![];
+        write_name(&mut buf, &self.name)?;
+        buf.write_u8(self.flags)?;
+        buf.write_u64::<LittleEndian>(self.slice_offset)?;
+        buf.write_u64::<LittleEndian>(self.slice_size)?;
+        buf.write_u16::<LittleEndian>(
+            self.levels
+                .len()
+                .try_into()
+                .map_err(|_| PfaError::CustomError("proof is too deep".into()))?,
+        )?;
+        for level in &self.levels {
+            if level.bucket {
+                buf.write_u8(Self::BUCKET_MARKER)?;
+            }
+            write_name(&mut buf, &level.name)?;
+            buf.write_u32::<LittleEndian>(level.index)?;
+            buf.write_u32::<LittleEndian>(
+                level
+                    .siblings
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("directory is too large".into()))?,
+            )?;
+            buf.extend(level.siblings.iter().flatten());
+        }
+        Ok(buf)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PfaError> {
+        let mut buf = Cursor::new(bytes);
+        let name = read_name(&mut buf)?;
+        let flags = buf.read_u8()?;
+        let slice_offset = buf.read_u64::<LittleEndian>()?;
+        let slice_size = buf.read_u64::<LittleEndian>()?;
+        let num_levels = buf.read_u16::<LittleEndian>()?;
+        let mut levels = vec![];
+        for _ in 0..num_levels {
+            let mut name_len = buf.read_u8()?;
+            let bucket = name_len == Self::BUCKET_MARKER;
+            if bucket {
+                name_len = buf.read_u8()?;
+            }
+            let mut name = vec![0; name_len.into()];
+            buf.read_exact(&mut name)?;
+            let name = String::from_utf8(name)?;
+            let index = buf.read_u32::<LittleEndian>()?;
+            let num_siblings = buf.read_u32::<LittleEndian>()?;
+            let mut siblings = vec![];
+            for _ in 0..num_siblings {
+                let mut hash = [0; 32];
+                buf.read_exact(&mut hash)?;
+                siblings.push(hash);
+            }
+            levels.push(ProofLevel {
+                name,
+                index,
+                siblings,
+                bucket,
+            });
+        }
+
+        Ok(Self {
+            name,
+            flags,
+            slice_offset,
+            slice_size,
+            levels,
+        })
+    }
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) -> Result<(), PfaError> {
+    buf.write_u8(
+        name.len()
+            .try_into()
+            .map_err(|_| PfaError::CustomError("name is too long".into()))?,
+    )?;
+    buf.write_all(name.as_bytes())?;
+    Ok(())
+}
+
+fn read_name(buf: &mut impl Read) -> Result<String, PfaError> {
+    let mut name = vec![0; buf.read_u8()?.into()];
+    buf.read_exact(&mut name)?;
+    Ok(String::from_utf8(name)?)
+}
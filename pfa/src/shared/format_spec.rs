@@ -0,0 +1,171 @@
+//! Description of the on-disk layout generated from the definitions the reader and writer use,
+//! so it can't drift from the implementation like hand written documentation can.
+
+use std::fmt::Write;
+
+use super::{
+    entry_hash::{EntryProof, DIRECTORY_TAG, FILE_TAG},
+    header_fields::HeaderFields,
+    CatalogLayout, DataFlags, NameEncoding, LATEST_VERSION, WATERMARK,
+};
+
+/// Layout of the header after the archive name, for every supported version
+fn version_layout(version: u8) -> Option<&'static str> {
+    match version {
+        1 => Some("{extra_data_len:u8}{extra_data:u8[extra_data_len]}"),
+        2 => Some(
+            "{extra_data_len:u64}{extra_data:u8[extra_data_len]}{num_fields:u16}{fields:header_field[num_fields]}",
+        ),
+        3 => Some("as version 2, data may be compressed with codecs other than LZ4"),
+        _ => None,
+    }
+}
+
+/// Plain text description of the archive format as implemented by this version of the crate,
+/// with field offsets and sizes in bytes. All numbers are little endian.
+pub fn format_spec() -> String {
+    let mut out = String::new();
+    write_spec(&mut out).expect("writing to a string can't fail");
+    out
+}
+
+fn write_spec(out: &mut String) -> std::fmt::Result {
+    let watermark = String::from_utf8_lossy(WATERMARK);
+    writeln!(out, "pfa format, versions 1 to {LATEST_VERSION}")?;
+    writeln!(out, "all numbers are little endian")?;
+    writeln!(out)?;
+    writeln!(out, "archive: {{header}}{{catalog}}{{data}}")?;
+    writeln!(out)?;
+
+    let name_offset = WATERMARK.len() + 2;
+    writeln!(out, "header")?;
+    writeln!(out, "  offset  size  field")?;
+    writeln!(
+        out,
+        "  {:<6}  {:<4}  watermark \"{watermark}\"",
+        0,
+        WATERMARK.len()
+    )?;
+    writeln!(out, "  {:<6}  {:<4}  version:u8", WATERMARK.len(), 1)?;
+    writeln!(
+        out,
+        "  {:<6}  {:<4}  archive_name_size:u8",
+        name_offset - 1,
+        1
+    )?;
+    writeln!(out, "  {name_offset:<6}  {:<4}  archive_name", "n")?;
+    for version in 1..=LATEST_VERSION {
+        let layout = version_layout(version).expect("every version has a header layout");
+        writeln!(out, "  version {version}, at {name_offset} + n: {layout}")?;
+    }
+    writeln!(out)?;
+
+    writeln!(out, "header_field: {{tag:u8}}{{len:u32}}{{data:u8[len]}}")?;
+    writeln!(out, "  unknown tags are skipped")?;
+    writeln!(out, "  tag  field                data")?;
+    for (tag, name, layout) in HeaderFields::LAYOUTS {
+        writeln!(out, "  {tag:<3}  {name:<19}  {layout}")?;
+    }
+    writeln!(out)?;
+
+    let fixed = CatalogLayout::Fixed;
+    let slice = CatalogLayout::SLICE_SIZE;
+    writeln!(
+        out,
+        "catalog: {{num_entries:u64}}{{entries:entry[num_entries]}}"
+    )?;
+    writeln!(out, "  fixed layout entry, {} bytes", fixed.entry_size(""))?;
+    writeln!(out, "    offset  size  field")?;
+    writeln!(
+        out,
+        "    {:<6}  {:<4}  name, null padded",
+        0,
+        CatalogLayout::FIXED_NAME_SIZE
+    )?;
+    writeln!(
+        out,
+        "    {:<6}  {slice:<4}  slice",
+        CatalogLayout::FIXED_NAME_SIZE
+    )?;
+    writeln!(out, "  compact layout entry, {} + n bytes", 1 + slice)?;
+    writeln!(out, "    offset  size  field")?;
+    writeln!(out, "    {:<6}  {:<4}  name_size:u8 (n)", 0, 1)?;
+    writeln!(out, "    {:<6}  {:<4}  name", 1, "n")?;
+    writeln!(out, "    {:<6}  {slice:<4}  slice", "1 + n")?;
+    writeln!(
+        out,
+        "  reserved slots take {} bytes in the fixed and {} bytes in the compact layout",
+        fixed.reserved_slot_size(),
+        CatalogLayout::Compact.reserved_slot_size()
+    )?;
+    writeln!(
+        out,
+        "  directory names end with '/' and have a catalog slice"
+    )?;
+    writeln!(out)?;
+
+    writeln!(out, "slice, {slice} bytes")?;
+    writeln!(out, "  offset  size  field")?;
+    writeln!(out, "  {:<6}  {:<4}  flags:u8", 0, 1)?;
+    writeln!(out, "  {:<6}  {:<4}  size:u64", 1, 8)?;
+    writeln!(out, "  {:<6}  {:<4}  offset:u64", 9, 8)?;
+    writeln!(
+        out,
+        "  catalog slice: size entries starting offset entries after the directory"
+    )?;
+    writeln!(
+        out,
+        "  data slice: size bytes starting offset bytes into the data section"
+    )?;
+    writeln!(out)?;
+
+    writeln!(out, "slice flags, applied in this order when writing")?;
+    writeln!(out, "  bit  mask        field                description")?;
+    for (mask, name, description) in DataFlags::BITS {
+        writeln!(
+            out,
+            "  {:<3}  {mask:#010b}  {name:<19}  {description}",
+            mask.trailing_zeros()
+        )?;
+    }
+    writeln!(
+        out,
+        "  reserved bits ({:#010b}) must be zero, all of them set is read as none",
+        !DataFlags::KNOWN_BITS
+    )?;
+    writeln!(out, "  catalog slices don't use any flags")?;
+    writeln!(out)?;
+
+    writeln!(
+        out,
+        "data: the rest of the archive, directly after the catalog, without a size"
+    )?;
+    writeln!(out)?;
+
+    writeln!(
+        out,
+        "entry hashes, lengths are u64 and names don't end with '/'"
+    )?;
+    writeln!(
+        out,
+        "  file: blake3({{{FILE_TAG}:u8}}{{name_len}}{{name}}{{flags:u8}}{{blake3(stored data)}})"
+    )?;
+    writeln!(
+        out,
+        "  directory: blake3({{{DIRECTORY_TAG}:u8}}{{name_len}}{{name}}{{num_children}}{{child hashes}})"
+    )?;
+    writeln!(
+        out,
+        "  proof levels of hash buckets start with a name_len of {}",
+        EntryProof::BUCKET_MARKER
+    )?;
+    writeln!(out)?;
+
+    writeln!(
+        out,
+        "hashed names: the first {} hex digits of blake3_keyed(blake3_derive_key(\"{}\", archive_id), path)",
+        NameEncoding::HASHED_NAME_LEN,
+        NameEncoding::KEY_CONTEXT
+    )?;
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Counts the updates of an archive which is read while it's updated in place, see
+/// [`PfaReaderOptions::follow_generations`](crate::reader::PfaReaderOptions::follow_generations)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Generation {
+    pub number: u64,
+    /// Milliseconds since the unix epoch when the generation was written, zero while the archive
+    /// is being updated to this generation
+    pub timestamp: u64,
+}
+
+impl Generation {
+    /// Size of the generation when stored in the header
+    pub(crate) const SIZE: usize = 16;
+
+    /// Generation `number`, finished now
+    pub fn now(number: u64) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis() as u64)
+            .unwrap_or_default();
+        Self {
+            number,
+            timestamp: timestamp.max(1),
+        }
+    }
+
+    /// Whether the archive is in the middle of being updated to this generation
+    pub fn is_in_progress(&self) -> bool {
+        self.timestamp == 0
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..8].copy_from_slice(&self.number.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; Self::SIZE] = bytes.try_into().ok()?;
+        Some(Self {
+            number: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            timestamp: u64::from_le_bytes(bytes[8..].try_into().unwrap()),
+        })
+    }
+}
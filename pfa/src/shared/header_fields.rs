@@ -0,0 +1,885 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ulid::Ulid;
+
+use crate::{
+    shared::{
+        solid_block::{SolidBlock, SolidMember},
+        CatalogLayout, Cipher, EntryHash, Generation, KeyId, NameEncoding, Provenance,
+        ReservedSpace, Tier, TransformChain,
+    },
+    PfaError,
+};
+
+/// Tagged fields stored at the end of a version 2 or 3 header. Each field is stored as
+/// {tag:u8}{len:u32}{data:u8\[len\]}, and fields with unknown tags are preserved so newer
+/// archives can still be opened.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HeaderFields {
+    pub(crate) archive_id: Option<Ulid>,
+    /// Only written when it isn't the fixed layout, so those archives stay readable by readers
+    /// which predate the field
+    pub(crate) catalog_layout: CatalogLayout,
+    /// Hash of every catalog entry, in catalog order
+    pub(crate) entry_hashes: Option<Vec<EntryHash>>,
+    /// Variant tags used by files in the archive
+    pub(crate) variants: Vec<String>,
+    /// Tiers of catalog entries which have one, sorted by entry index
+    pub(crate) tiers: Vec<(u32, Tier)>,
+    pub(crate) reserved_space: Option<ReservedSpace>,
+    /// Only written for hashed names, like the catalog layout
+    pub(crate) name_encoding: NameEncoding,
+    /// Indices of file entries stored uncompressed because compressing them didn't reduce their
+    /// size, sorted
+    pub(crate) compression_skipped: Vec<u32>,
+    /// Indices of directory entries whose children are hash buckets rather than their actual
+    /// entries, sorted
+    pub(crate) sharded_directories: Vec<u32>,
+    /// Distinct content types, referenced by `entry_content_types`
+    pub(crate) content_types: Vec<String>,
+    /// Index into `content_types` of file entries which have one, sorted by entry index
+    pub(crate) entry_content_types: Vec<(u32, u16)>,
+    /// Distinct ids of the keys files are encrypted with, referenced by `entry_key_ids`
+    pub(crate) key_ids: Vec<KeyId>,
+    /// Index into `key_ids` of encrypted file entries whose key is known, sorted by entry index
+    pub(crate) entry_key_ids: Vec<(u32, u16)>,
+    /// Provenance of file entries which have one, sorted by entry index
+    pub(crate) provenance: Vec<(u32, Provenance)>,
+    /// Set for store only archives, whose files are all stored as is at archive offsets which are
+    /// multiples of this
+    pub(crate) data_alignment: Option<u32>,
+    /// Set when extra_data is stored compressed with LZ4, with its size prepended
+    pub(crate) extra_data_compressed: bool,
+    /// Written first, so its data is at a fixed position and can be checked and rewritten on its
+    /// own
+    pub(crate) generation: Option<Generation>,
+    /// Position of the generation's data relative to the start of the fields, when it was read
+    pub(crate) generation_offset: Option<u64>,
+    /// Blocks of small files compressed together, referenced by `solid_members`
+    pub(crate) solid_blocks: Vec<SolidBlock>,
+    /// Where the contents of file entries stored in a solid block are, sorted by entry index
+    pub(crate) solid_members: Vec<(u32, SolidMember)>,
+    /// Transformations of file entries which recorded them, sorted by entry index
+    pub(crate) transforms: Vec<(u32, TransformChain)>,
+    /// Sizes of the contents of file entries whose stored data was compressed, encrypted or
+    /// error corrected, sorted by entry index
+    pub(crate) original_sizes: Vec<(u32, u64)>,
+    /// Ciphers of encrypted file entries which don't use AES256-GCM, sorted by entry index
+    pub(crate) ciphers: Vec<(u32, Cipher)>,
+    pub(crate) unknown: Vec<(u8, Vec<u8>)>,
+}
+
+impl HeaderFields {
+    const ARCHIVE_ID: u8 = 1;
+    const CATALOG_LAYOUT: u8 = 2;
+    const ENTRY_HASHES: u8 = 3;
+    const VARIANTS: u8 = 4;
+    const TIERS: u8 = 5;
+    const RESERVED_SPACE: u8 = 6;
+    const NAME_ENCODING: u8 = 7;
+    const COMPRESSION_SKIPPED: u8 = 8;
+    const SHARDED_DIRECTORIES: u8 = 9;
+    const CONTENT_TYPES: u8 = 10;
+    const PROVENANCE: u8 = 11;
+    const STORE_ONLY: u8 = 12;
+    const EXTRA_DATA_COMPRESSION: u8 = 13;
+    const GENERATION: u8 = 14;
+    const KEY_IDS: u8 = 15;
+    const SOLID_BLOCKS: u8 = 16;
+    const TRANSFORMS: u8 = 17;
+    const ORIGINAL_SIZES: u8 = 18;
+    const CIPHERS: u8 = 19;
+    const TRANSFORM_OUTPUTS: u8 = 20;
+    const LZ4: u8 = 1;
+    const BLAKE3: u8 = 1;
+
+    /// Tag, name and data layout of every field, used to generate the format description
+    pub(crate) const LAYOUTS: &'static [(u8, &'static str, &'static str)] = &[
+        (Self::ARCHIVE_ID, "archive_id", "{ulid:u8[16]} (big endian)"),
+        (
+            Self::CATALOG_LAYOUT,
+            "catalog_layout",
+            "{layout:u8} (0 = fixed, 1 = compact, fixed when absent)",
+        ),
+        (
+            Self::ENTRY_HASHES,
+            "entry_hashes",
+            "{algorithm:u8} (1 = BLAKE3){hashes:u8[32][num_entries]}",
+        ),
+        (
+            Self::VARIANTS,
+            "variants",
+            "{num_variants:u16}{variants:{len:u8}{name:u8[len]}[num_variants]}",
+        ),
+        (
+            Self::TIERS,
+            "tiers",
+            "{num_tiers:u32}{tiers:{entry_index:u32}{tier:u8}[num_tiers]} (0 = low, 1 = med, 2 = high)",
+        ),
+        (
+            Self::RESERVED_SPACE,
+            "reserved_space",
+            "{slots_start:u64}{first_free_slot:u64}{free_slots:u64}{data_offset:u64}{free_data:u64}",
+        ),
+        (
+            Self::NAME_ENCODING,
+            "name_encoding",
+            "{encoding:u8} (0 = plain, 1 = hashed, plain when absent)",
+        ),
+        (
+            Self::COMPRESSION_SKIPPED,
+            "compression_skipped",
+            "{num_entries:u32}{entry_index:u32[num_entries]}",
+        ),
+        (
+            Self::SHARDED_DIRECTORIES,
+            "sharded_directories",
+            "{num_entries:u32}{entry_index:u32[num_entries]}",
+        ),
+        (
+            Self::CONTENT_TYPES,
+            "content_types",
+            "{num_types:u16}{types:{len:u8}{type:u8[len]}[num_types]}{num_entries:u32}{entries:{entry_index:u32}{type_index:u16}[num_entries]}",
+        ),
+        (
+            Self::PROVENANCE,
+            "provenance",
+            "{num_entries:u32}{entries:{entry_index:u32}{path_len:u16}{path:u8[path_len]}{hash_len:u8}{hash:u8[hash_len]}{tool_len:u8}{tool:u8[tool_len]}[num_entries]}",
+        ),
+        (
+            Self::STORE_ONLY,
+            "store_only",
+            "{alignment:u32} (every file is stored as is, at an archive offset which is a multiple of alignment)",
+        ),
+        (
+            Self::EXTRA_DATA_COMPRESSION,
+            "extra_data_compression",
+            "u8, 1 for LZ4 with the uncompressed size prepended as a u32",
+        ),
+        (
+            Self::GENERATION,
+            "generation",
+            "{generation:u64}{timestamp:u64} (milliseconds since the unix epoch, 0 while being updated), always the first field",
+        ),
+        (
+            Self::KEY_IDS,
+            "key_ids",
+            "{num_keys:u16}{key_ids:u8[8][num_keys]}{num_entries:u32}{entries:{entry_index:u32}{key_index:u16}[num_entries]}",
+        ),
+        (
+            Self::SOLID_BLOCKS,
+            "solid_blocks",
+            "{num_blocks:u32}{blocks:{offset:u64}{size:u64}{flags:u8}[num_blocks]}{num_members:u32}{members:{entry_index:u32}{block:u32}{offset:u64}{size:u64}[num_members]}",
+        ),
+        (
+            Self::TRANSFORMS,
+            "transforms",
+            "{num_entries:u32}{entries:{entry_index:u32}{hash_len:u8}{input_hash:u8[hash_len]}{num_steps:u8}{steps:{len:u8}{step:u8[len]}[num_steps]}[num_entries]}",
+        ),
+        (
+            Self::ORIGINAL_SIZES,
+            "original_sizes",
+            "{num_entries:u32}{entries:{entry_index:u32}{size:u64}[num_entries]}",
+        ),
+        (
+            Self::CIPHERS,
+            "ciphers",
+            "{num_entries:u32}{entries:{entry_index:u32}{cipher:u8}[num_entries]} (0 = AES256-GCM, 1 = XChaCha20-Poly1305, AES256-GCM when absent)",
+        ),
+        (
+            Self::TRANSFORM_OUTPUTS,
+            "transform_outputs",
+            "{num_entries:u32}{entries:{entry_index:u32}{hash_len:u8}{output_hash:u8[hash_len]}[num_entries]}",
+        ),
+    ];
+
+    /// Records the content type of an entry, adding it to the registry if it is new
+    pub(crate) fn add_content_type(
+        &mut self,
+        index: u32,
+        content_type: &str,
+    ) -> Result<(), PfaError> {
+        let position = match self.content_types.iter().position(|x| x == content_type) {
+            Some(position) => position,
+            None => {
+                self.content_types.push(content_type.to_string());
+                self.content_types.len() - 1
+            }
+        };
+        let position = u16::try_from(position)
+            .map_err(|_| PfaError::CustomError("too many content types".into()))?;
+        self.entry_content_types.push((index, position));
+        Ok(())
+    }
+
+    pub(crate) fn entry_content_type(&self, index: usize) -> Option<&str> {
+        let index = u32::try_from(index).ok()?;
+        let position = self
+            .entry_content_types
+            .binary_search_by_key(&index, |x| x.0)
+            .ok()?;
+        let content_type = self.entry_content_types[position].1;
+        self.content_types
+            .get(usize::from(content_type))
+            .map(|x| &x[..])
+    }
+
+    /// Records the id of the key an entry is encrypted with, adding it to the list if it is new
+    pub(crate) fn add_key_id(&mut self, index: u32, key_id: KeyId) -> Result<(), PfaError> {
+        let position = match self.key_ids.iter().position(|x| *x == key_id) {
+            Some(position) => position,
+            None => {
+                self.key_ids.push(key_id);
+                self.key_ids.len() - 1
+            }
+        };
+        let position = u16::try_from(position)
+            .map_err(|_| PfaError::CustomError("too many encryption keys".into()))?;
+        self.entry_key_ids.push((index, position));
+        Ok(())
+    }
+
+    pub(crate) fn entry_key_id(&self, index: usize) -> Option<KeyId> {
+        let index = u32::try_from(index).ok()?;
+        let position = self
+            .entry_key_ids
+            .binary_search_by_key(&index, |x| x.0)
+            .ok()?;
+        let key_id = self.entry_key_ids[position].1;
+        self.key_ids.get(usize::from(key_id)).copied()
+    }
+
+    pub(crate) fn entry_provenance(&self, index: usize) -> Option<&Provenance> {
+        let index = u32::try_from(index).ok()?;
+        let position = self.provenance.binary_search_by_key(&index, |x| x.0).ok()?;
+        Some(&self.provenance[position].1)
+    }
+
+    /// Updates every field which refers to entries by their catalog index after entries moved,
+    /// `moved` mapping their old index to their new one, keeping the fields sorted
+    pub(crate) fn remap_entries(&mut self, moved: &HashMap<usize, usize>) -> Result<(), PfaError> {
+        let remap = |index: &mut u32| {
+            if let Some(new) = moved.get(&(*index as usize)) {
+                *index = u32::try_from(*new)
+                    .map_err(|_| PfaError::WriterOverflowError("moved entry index"))?;
+            }
+            Ok::<_, PfaError>(())
+        };
+        fn remap_keyed<V>(
+            entries: &mut [(u32, V)],
+            remap: impl Fn(&mut u32) -> Result<(), PfaError>,
+        ) -> Result<(), PfaError> {
+            for (index, _) in entries.iter_mut() {
+                remap(index)?;
+            }
+            entries.sort_by_key(|x| x.0);
+            Ok(())
+        }
+        for indices in [&mut self.compression_skipped, &mut self.sharded_directories] {
+            for index in indices.iter_mut() {
+                remap(index)?;
+            }
+            indices.sort();
+        }
+        remap_keyed(&mut self.tiers, remap)?;
+        remap_keyed(&mut self.entry_content_types, remap)?;
+        remap_keyed(&mut self.entry_key_ids, remap)?;
+        remap_keyed(&mut self.provenance, remap)?;
+        remap_keyed(&mut self.solid_members, remap)?;
+        remap_keyed(&mut self.transforms, remap)?;
+        remap_keyed(&mut self.original_sizes, remap)?;
+        remap_keyed(&mut self.ciphers, remap)?;
+        Ok(())
+    }
+
+    pub(crate) fn entry_transforms(&self, index: usize) -> Option<&TransformChain> {
+        let index = u32::try_from(index).ok()?;
+        let position = self.transforms.binary_search_by_key(&index, |x| x.0).ok()?;
+        Some(&self.transforms[position].1)
+    }
+
+    pub(crate) fn entry_original_size(&self, index: usize) -> Option<u64> {
+        let index = u32::try_from(index).ok()?;
+        let position = self
+            .original_sizes
+            .binary_search_by_key(&index, |x| x.0)
+            .ok()?;
+        Some(self.original_sizes[position].1)
+    }
+
+    pub(crate) fn entry_cipher(&self, index: usize) -> Cipher {
+        u32::try_from(index)
+            .ok()
+            .and_then(|index| self.ciphers.binary_search_by_key(&index, |x| x.0).ok())
+            .map_or(Cipher::Aes256Gcm, |position| self.ciphers[position].1)
+    }
+
+    /// The solid block holding the contents of an entry, and where they are within it
+    pub(crate) fn solid_member(&self, index: usize) -> Option<(SolidMember, SolidBlock)> {
+        let index = u32::try_from(index).ok()?;
+        let position = self
+            .solid_members
+            .binary_search_by_key(&index, |x| x.0)
+            .ok()?;
+        let member = self.solid_members[position].1;
+        Some((member, *self.solid_blocks.get(member.block as usize)?))
+    }
+
+    pub(crate) fn read(buf: &mut impl Read) -> Result<Self, PfaError> {
+        let mut fields = Self::default();
+        let mut transform_outputs = vec![];
+        let num_fields = buf.read_u16::<LittleEndian>()?;
+        let mut offset = 2;
+        for _ in 0..num_fields {
+            let tag = buf.read_u8()?;
+            let len = buf.read_u32::<LittleEndian>()?;
+            let mut data = vec![0; len as usize];
+            buf.read_exact(&mut data)?;
+            let data_offset = offset + 5;
+            offset = data_offset + u64::from(len);
+
+            match tag {
+                Self::ARCHIVE_ID => {
+                    let id: [u8; 16] = data.try_into().map_err(|_| {
+                        PfaError::CustomError("archive id header field has invalid size".into())
+                    })?;
+                    fields.archive_id = Some(Ulid::from_bytes(id));
+                }
+                Self::CATALOG_LAYOUT => {
+                    fields.catalog_layout = match data[..] {
+                        [0] => CatalogLayout::Fixed,
+                        [1] => CatalogLayout::Compact,
+                        _ => {
+                            return Err(PfaError::CustomError("unsupported catalog layout".into()))
+                        }
+                    };
+                }
+                Self::ENTRY_HASHES => match data.split_first() {
+                    Some((&Self::BLAKE3, hashes)) if hashes.len() % 32 == 0 => {
+                        fields.entry_hashes = Some(
+                            hashes
+                                .chunks_exact(32)
+                                .map(|x| x.try_into().unwrap())
+                                .collect(),
+                        );
+                    }
+                    // hashes from an unknown algorithm can't be used, but don't stop the archive
+                    // from being read
+                    _ => fields.unknown.push((tag, data)),
+                },
+                Self::VARIANTS => {
+                    let mut data = &data[..];
+                    let count = data.read_u16::<LittleEndian>()?;
+                    for _ in 0..count {
+                        let mut variant = vec![0; data.read_u8()?.into()];
+                        data.read_exact(&mut variant)?;
+                        fields.variants.push(String::from_utf8(variant)?);
+                    }
+                }
+                Self::TIERS => {
+                    let mut data = &data[..];
+                    let count = data.read_u32::<LittleEndian>()?;
+                    for _ in 0..count {
+                        let index = data.read_u32::<LittleEndian>()?;
+                        let tier = Tier::from_u8(data.read_u8()?)
+                            .ok_or(PfaError::CustomError("unknown tier".into()))?;
+                        fields.tiers.push((index, tier));
+                    }
+                    fields.tiers.sort_by_key(|x| x.0);
+                }
+                Self::RESERVED_SPACE => {
+                    let mut data = &data[..];
+                    fields.reserved_space = Some(ReservedSpace {
+                        slots_start: data.read_u64::<LittleEndian>()?,
+                        first_free_slot: data.read_u64::<LittleEndian>()?,
+                        free_slots: data.read_u64::<LittleEndian>()?,
+                        data_offset: data.read_u64::<LittleEndian>()?,
+                        free_data: data.read_u64::<LittleEndian>()?,
+                    });
+                }
+                Self::NAME_ENCODING => {
+                    fields.name_encoding = match data[..] {
+                        [0] => NameEncoding::Plain,
+                        [1] => NameEncoding::Hashed,
+                        _ => return Err(PfaError::CustomError("unsupported name encoding".into())),
+                    };
+                }
+                Self::COMPRESSION_SKIPPED => {
+                    let mut data = &data[..];
+                    let count = data.read_u32::<LittleEndian>()?;
+                    for _ in 0..count {
+                        fields
+                            .compression_skipped
+                            .push(data.read_u32::<LittleEndian>()?);
+                    }
+                    fields.compression_skipped.sort();
+                }
+                Self::SHARDED_DIRECTORIES => {
+                    let mut data = &data[..];
+                    let count = data.read_u32::<LittleEndian>()?;
+                    for _ in 0..count {
+                        fields
+                            .sharded_directories
+                            .push(data.read_u32::<LittleEndian>()?);
+                    }
+                    fields.sharded_directories.sort();
+                }
+                Self::CONTENT_TYPES => {
+                    let mut data = &data[..];
+                    let num_types = data.read_u16::<LittleEndian>()?;
+                    for _ in 0..num_types {
+                        let mut name = vec![0; data.read_u8()?.into()];
+                        data.read_exact(&mut name)?;
+                        fields.content_types.push(String::from_utf8(name)?);
+                    }
+                    let num_entries = data.read_u32::<LittleEndian>()?;
+                    for _ in 0..num_entries {
+                        let index = data.read_u32::<LittleEndian>()?;
+                        let content_type = data.read_u16::<LittleEndian>()?;
+                        if usize::from(content_type) >= fields.content_types.len() {
+                            return Err(PfaError::CustomError(
+                                "content type index out of bounds".into(),
+                            ));
+                        }
+                        fields.entry_content_types.push((index, content_type));
+                    }
+                    fields.entry_content_types.sort_by_key(|x| x.0);
+                }
+                Self::PROVENANCE => {
+                    let mut data = &data[..];
+                    let num_entries = data.read_u32::<LittleEndian>()?;
+                    for _ in 0..num_entries {
+                        let index = data.read_u32::<LittleEndian>()?;
+                        let mut path = vec![0; data.read_u16::<LittleEndian>()?.into()];
+                        data.read_exact(&mut path)?;
+                        let mut hash = vec![0; data.read_u8()?.into()];
+                        data.read_exact(&mut hash)?;
+                        let mut tool = vec![0; data.read_u8()?.into()];
+                        data.read_exact(&mut tool)?;
+                        let provenance = Provenance {
+                            source_path: Some(String::from_utf8(path)?).filter(|x| !x.is_empty()),
+                            // hashes of other sizes are from algorithms this version doesn't know
+                            source_hash: hash.try_into().ok(),
+                            tool: Some(String::from_utf8(tool)?).filter(|x| !x.is_empty()),
+                        };
+                        fields.provenance.push((index, provenance));
+                    }
+                    fields.provenance.sort_by_key(|x| x.0);
+                }
+                Self::STORE_ONLY => {
+                    let alignment = (&data[..]).read_u32::<LittleEndian>()?;
+                    if alignment == 0 {
+                        return Err(PfaError::CustomError("invalid data alignment".into()));
+                    }
+                    fields.data_alignment = Some(alignment);
+                }
+                Self::EXTRA_DATA_COMPRESSION => {
+                    let algorithm = (&data[..]).read_u8()?;
+                    if algorithm != Self::LZ4 {
+                        return Err(PfaError::UnsupportedFeature(format!(
+                            "extra data compression algorithm {algorithm}"
+                        )));
+                    }
+                    fields.extra_data_compressed = true;
+                }
+                Self::GENERATION => {
+                    fields.generation = Some(Generation::from_bytes(&data).ok_or(
+                        PfaError::CustomError("generation header field has invalid size".into()),
+                    )?);
+                    fields.generation_offset = Some(data_offset);
+                }
+                Self::KEY_IDS => {
+                    let mut data = &data[..];
+                    let num_keys = data.read_u16::<LittleEndian>()?;
+                    for _ in 0..num_keys {
+                        let mut key_id = KeyId::default();
+                        data.read_exact(&mut key_id)?;
+                        fields.key_ids.push(key_id);
+                    }
+                    let num_entries = data.read_u32::<LittleEndian>()?;
+                    for _ in 0..num_entries {
+                        let index = data.read_u32::<LittleEndian>()?;
+                        let key_id = data.read_u16::<LittleEndian>()?;
+                        if usize::from(key_id) >= fields.key_ids.len() {
+                            return Err(PfaError::CustomError("key id index out of bounds".into()));
+                        }
+                        fields.entry_key_ids.push((index, key_id));
+                    }
+                    fields.entry_key_ids.sort_by_key(|x| x.0);
+                }
+                Self::SOLID_BLOCKS => {
+                    let mut data = &data[..];
+                    let num_blocks = data.read_u32::<LittleEndian>()?;
+                    for _ in 0..num_blocks {
+                        fields.solid_blocks.push(SolidBlock {
+                            offset: data.read_u64::<LittleEndian>()?,
+                            size: data.read_u64::<LittleEndian>()?,
+                            flags: data.read_u8()?,
+                        });
+                    }
+                    let num_members = data.read_u32::<LittleEndian>()?;
+                    for _ in 0..num_members {
+                        let index = data.read_u32::<LittleEndian>()?;
+                        let member = SolidMember {
+                            block: data.read_u32::<LittleEndian>()?,
+                            offset: data.read_u64::<LittleEndian>()?,
+                            size: data.read_u64::<LittleEndian>()?,
+                        };
+                        if member.block as usize >= fields.solid_blocks.len() {
+                            return Err(PfaError::CustomError(
+                                "solid block index out of bounds".into(),
+                            ));
+                        }
+                        fields.solid_members.push((index, member));
+                    }
+                    fields.solid_members.sort_by_key(|x| x.0);
+                }
+                Self::TRANSFORMS => {
+                    let mut data = &data[..];
+                    let num_entries = data.read_u32::<LittleEndian>()?;
+                    for _ in 0..num_entries {
+                        let index = data.read_u32::<LittleEndian>()?;
+                        let mut hash = vec![0; data.read_u8()?.into()];
+                        data.read_exact(&mut hash)?;
+                        let mut chain = TransformChain {
+                            // hashes of other sizes are from algorithms this version doesn't know
+                            input_hash: hash.try_into().ok(),
+                            ..Default::default()
+                        };
+                        for _ in 0..data.read_u8()? {
+                            let mut step = vec![0; data.read_u8()?.into()];
+                            data.read_exact(&mut step)?;
+                            chain.steps.push(String::from_utf8(step)?);
+                        }
+                        fields.transforms.push((index, chain));
+                    }
+                    fields.transforms.sort_by_key(|x| x.0);
+                }
+                Self::ORIGINAL_SIZES => {
+                    let mut data = &data[..];
+                    let num_entries = data.read_u32::<LittleEndian>()?;
+                    for _ in 0..num_entries {
+                        let index = data.read_u32::<LittleEndian>()?;
+                        let size = data.read_u64::<LittleEndian>()?;
+                        fields.original_sizes.push((index, size));
+                    }
+                    fields.original_sizes.sort_by_key(|x| x.0);
+                }
+                Self::CIPHERS => {
+                    let mut data = &data[..];
+                    let num_entries = data.read_u32::<LittleEndian>()?;
+                    for _ in 0..num_entries {
+                        let index = data.read_u32::<LittleEndian>()?;
+                        let cipher = Cipher::from_u8(data.read_u8()?)
+                            .ok_or(PfaError::CustomError("unknown cipher".into()))?;
+                        fields.ciphers.push((index, cipher));
+                    }
+                    fields.ciphers.sort_by_key(|x| x.0);
+                }
+                Self::TRANSFORM_OUTPUTS => {
+                    let mut data = &data[..];
+                    let num_entries = data.read_u32::<LittleEndian>()?;
+                    for _ in 0..num_entries {
+                        let index = data.read_u32::<LittleEndian>()?;
+                        let mut hash = vec![0; data.read_u8()?.into()];
+                        data.read_exact(&mut hash)?;
+                        // hashes of other sizes are from algorithms this version doesn't know
+                        if let Ok(hash) = hash.try_into() {
+                            transform_outputs.push((index, hash));
+                        }
+                    }
+                }
+                _ => fields.unknown.push((tag, data)),
+            }
+        }
+        // the transforms field may come after this one
+        for (index, hash) in transform_outputs {
+            if let Ok(position) = fields.transforms.binary_search_by_key(&index, |x| x.0) {
+                fields.transforms[position].1.output_hash = Some(hash);
+            }
+        }
+
+        Ok(fields)
+    }
+
+    pub(crate) fn write(&self, buf: &mut impl Write) -> Result<(), PfaError> {
+        let mut fields = vec![];
+        if let Some(generation) = self.generation {
+            fields.push((Self::GENERATION, generation.to_bytes().to_vec()));
+        }
+        if let Some(id) = self.archive_id {
+            fields.push((Self::ARCHIVE_ID, id.to_bytes().to_vec()));
+        }
+        if self.catalog_layout == CatalogLayout::Compact {
+            fields.push((Self::CATALOG_LAYOUT, vec![1]));
+        }
+        if let Some(hashes) = &self.entry_hashes {
+            let mut data = Vec::with_capacity(1 + hashes.len() * 32);
+            data.push(Self::BLAKE3);
+            data.extend(hashes.iter().flatten());
+            fields.push((Self::ENTRY_HASHES, data));
+        }
+        if !self.variants.is_empty() {
+            let too_many = || PfaError::CustomError("too many variants".into());
+            let mut data = vec![];
+            data.write_u16::<LittleEndian>(
+                self.variants.len().try_into().map_err(|_| too_many())?,
+            )?;
+            for variant in &self.variants {
+                data.write_u8(
+                    variant
+                        .len()
+                        .try_into()
+                        .map_err(|_| PfaError::CustomError("variant name too long".into()))?,
+                )?;
+                data.write_all(variant.as_bytes())?;
+            }
+            fields.push((Self::VARIANTS, data));
+        }
+        if !self.tiers.is_empty() {
+            let mut data = vec![];
+            data.write_u32::<LittleEndian>(
+                self.tiers
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("too many tiered entries".into()))?,
+            )?;
+            for (index, tier) in &self.tiers {
+                data.write_u32::<LittleEndian>(*index)?;
+                data.write_u8(tier.to_u8())?;
+            }
+            fields.push((Self::TIERS, data));
+        }
+        if let Some(reserved) = &self.reserved_space {
+            let mut data = vec![];
+            data.write_u64::<LittleEndian>(reserved.slots_start)?;
+            data.write_u64::<LittleEndian>(reserved.first_free_slot)?;
+            data.write_u64::<LittleEndian>(reserved.free_slots)?;
+            data.write_u64::<LittleEndian>(reserved.data_offset)?;
+            data.write_u64::<LittleEndian>(reserved.free_data)?;
+            fields.push((Self::RESERVED_SPACE, data));
+        }
+        if self.name_encoding == NameEncoding::Hashed {
+            fields.push((Self::NAME_ENCODING, vec![1]));
+        }
+        if !self.compression_skipped.is_empty() {
+            let mut data = vec![];
+            data.write_u32::<LittleEndian>(
+                self.compression_skipped
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("too many skipped entries".into()))?,
+            )?;
+            for index in &self.compression_skipped {
+                data.write_u32::<LittleEndian>(*index)?;
+            }
+            fields.push((Self::COMPRESSION_SKIPPED, data));
+        }
+        if !self.sharded_directories.is_empty() {
+            let mut data = vec![];
+            data.write_u32::<LittleEndian>(
+                self.sharded_directories
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("too many sharded directories".into()))?,
+            )?;
+            for index in &self.sharded_directories {
+                data.write_u32::<LittleEndian>(*index)?;
+            }
+            fields.push((Self::SHARDED_DIRECTORIES, data));
+        }
+        if !self.entry_content_types.is_empty() {
+            let mut data = vec![];
+            data.write_u16::<LittleEndian>(
+                self.content_types
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("too many content types".into()))?,
+            )?;
+            for content_type in &self.content_types {
+                data.write_u8(content_type.len().try_into().map_err(|_| {
+                    PfaError::CustomError(format!("content type '{content_type}' is too long"))
+                })?)?;
+                data.write_all(content_type.as_bytes())?;
+            }
+            data.write_u32::<LittleEndian>(
+                self.entry_content_types
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("too many typed entries".into()))?,
+            )?;
+            for (index, content_type) in &self.entry_content_types {
+                data.write_u32::<LittleEndian>(*index)?;
+                data.write_u16::<LittleEndian>(*content_type)?;
+            }
+            fields.push((Self::CONTENT_TYPES, data));
+        }
+        if !self.entry_key_ids.is_empty() {
+            let mut data = vec![];
+            data.write_u16::<LittleEndian>(
+                self.key_ids
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("too many encryption keys".into()))?,
+            )?;
+            for key_id in &self.key_ids {
+                data.write_all(key_id)?;
+            }
+            data.write_u32::<LittleEndian>(
+                self.entry_key_ids
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("too many encrypted entries".into()))?,
+            )?;
+            for (index, key_id) in &self.entry_key_ids {
+                data.write_u32::<LittleEndian>(*index)?;
+                data.write_u16::<LittleEndian>(*key_id)?;
+            }
+            fields.push((Self::KEY_IDS, data));
+        }
+        if !self.provenance.is_empty() {
+            let mut data = vec![];
+            data.write_u32::<LittleEndian>(
+                self.provenance
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("too many provenance entries".into()))?,
+            )?;
+            for (index, provenance) in &self.provenance {
+                provenance.validate()?;
+                let path = provenance.source_path.as_deref().unwrap_or_default();
+                let hash = provenance.source_hash.as_ref().map_or(&[][..], |x| &x[..]);
+                let tool = provenance.tool.as_deref().unwrap_or_default();
+                data.write_u32::<LittleEndian>(*index)?;
+                data.write_u16::<LittleEndian>(path.len() as u16)?;
+                data.write_all(path.as_bytes())?;
+                data.write_u8(hash.len() as u8)?;
+                data.write_all(hash)?;
+                data.write_u8(tool.len() as u8)?;
+                data.write_all(tool.as_bytes())?;
+            }
+            fields.push((Self::PROVENANCE, data));
+        }
+        if let Some(alignment) = self.data_alignment {
+            fields.push((Self::STORE_ONLY, alignment.to_le_bytes().to_vec()));
+        }
+        if self.extra_data_compressed {
+            fields.push((Self::EXTRA_DATA_COMPRESSION, vec![Self::LZ4]));
+        }
+        if !self.solid_blocks.is_empty() {
+            let mut data = vec![];
+            data.write_u32::<LittleEndian>(
+                self.solid_blocks
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("too many solid blocks".into()))?,
+            )?;
+            for block in &self.solid_blocks {
+                data.write_u64::<LittleEndian>(block.offset)?;
+                data.write_u64::<LittleEndian>(block.size)?;
+                data.write_u8(block.flags)?;
+            }
+            data.write_u32::<LittleEndian>(
+                self.solid_members
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("too many solid members".into()))?,
+            )?;
+            for (index, member) in &self.solid_members {
+                data.write_u32::<LittleEndian>(*index)?;
+                data.write_u32::<LittleEndian>(member.block)?;
+                data.write_u64::<LittleEndian>(member.offset)?;
+                data.write_u64::<LittleEndian>(member.size)?;
+            }
+            fields.push((Self::SOLID_BLOCKS, data));
+        }
+        if !self.transforms.is_empty() {
+            let mut data = vec![];
+            data.write_u32::<LittleEndian>(
+                self.transforms
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("too many transformed entries".into()))?,
+            )?;
+            for (index, chain) in &self.transforms {
+                chain.validate()?;
+                let hash = chain.input_hash.as_ref().map_or(&[][..], |x| &x[..]);
+                data.write_u32::<LittleEndian>(*index)?;
+                data.write_u8(hash.len() as u8)?;
+                data.write_all(hash)?;
+                data.write_u8(chain.steps.len() as u8)?;
+                for step in &chain.steps {
+                    data.write_u8(step.len() as u8)?;
+                    data.write_all(step.as_bytes())?;
+                }
+            }
+            fields.push((Self::TRANSFORMS, data));
+        }
+        if !self.original_sizes.is_empty() {
+            let mut data = vec![];
+            data.write_u32::<LittleEndian>(
+                self.original_sizes
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("too many sized entries".into()))?,
+            )?;
+            for (index, size) in &self.original_sizes {
+                data.write_u32::<LittleEndian>(*index)?;
+                data.write_u64::<LittleEndian>(*size)?;
+            }
+            fields.push((Self::ORIGINAL_SIZES, data));
+        }
+        if !self.ciphers.is_empty() {
+            let mut data = vec![];
+            data.write_u32::<LittleEndian>(
+                self.ciphers
+                    .len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("too many enciphered entries".into()))?,
+            )?;
+            for (index, cipher) in &self.ciphers {
+                data.write_u32::<LittleEndian>(*index)?;
+                data.write_u8(cipher.to_u8())?;
+            }
+            fields.push((Self::CIPHERS, data));
+        }
+        let transform_outputs = self
+            .transforms
+            .iter()
+            .filter_map(|(index, chain)| Some((index, chain.output_hash?)))
+            .collect::<Vec<_>>();
+        if !transform_outputs.is_empty() {
+            let mut data = vec![];
+            data.write_u32::<LittleEndian>(transform_outputs.len() as u32)?;
+            for (index, hash) in transform_outputs {
+                data.write_u32::<LittleEndian>(*index)?;
+                data.write_u8(hash.len() as u8)?;
+                data.write_all(&hash)?;
+            }
+            fields.push((Self::TRANSFORM_OUTPUTS, data));
+        }
+        fields.extend(self.unknown.iter().cloned());
+
+        buf.write_u16::<LittleEndian>(
+            fields
+                .len()
+                .try_into()
+                .map_err(|_| PfaError::CustomError("too many header fields".into()))?,
+        )?;
+        for (tag, data) in fields {
+            buf.write_u8(tag)?;
+            buf.write_u32::<LittleEndian>(
+                data.len()
+                    .try_into()
+                    .map_err(|_| PfaError::CustomError("header field too large".into()))?,
+            )?;
+            buf.write_all(&data)?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,2 +1,46 @@
+pub mod catalog_layout;
+pub mod cipher;
+pub mod codec;
+pub mod compression_heuristics;
+pub mod content_type;
 pub mod data_flags;
+pub mod encryption_frames;
+pub mod entropy;
+pub mod entry_hash;
+pub mod format_spec;
+pub mod generation;
+pub(crate) mod header_fields;
+pub mod name_encoding;
+pub(crate) mod ordered_pipeline;
+pub mod provenance;
+pub mod reserved_space;
+#[cfg(feature = "serde")]
+pub(crate) mod serde_fields;
+pub(crate) mod sharding;
+pub(crate) mod solid_block;
+pub mod tier;
+pub mod transform_chain;
+pub use catalog_layout::*;
+pub use cipher::Cipher;
+pub use codec::{Codec, CodecRegistry, StoredCodec};
+pub use compression_heuristics::PRECOMPRESSED_EXTENSIONS;
+pub use content_type::{content_type_matches, sniff_content_type};
 pub use data_flags::*;
+pub use encryption_frames::EncryptionFrames;
+pub use entry_hash::EntryHash;
+pub use format_spec::format_spec;
+pub use generation::Generation;
+pub use name_encoding::NameEncoding;
+pub use provenance::Provenance;
+pub use reserved_space::ReservedSpace;
+pub use tier::Tier;
+pub use transform_chain::TransformChain;
+pub use ulid::Ulid;
+
+/// The newest archive format version which can be read and written. Version 3 has the layout of
+/// version 2, and is only written for archives with data compressed with a codec other than LZ4.
+pub const LATEST_VERSION: u8 = 3;
+/// Deepest directory nesting readers and writers accept unless configured otherwise
+pub const DEFAULT_DEPTH_LIMIT: usize = 1024;
+/// Bytes every archive starts with
+pub(crate) const WATERMARK: &[u8; 3] = b"pfa";
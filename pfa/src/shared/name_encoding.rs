@@ -0,0 +1,40 @@
+use ulid::Ulid;
+
+/// How entry names are stored in the catalog
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum NameEncoding {
+    #[default]
+    Plain,
+    /// Every name is replaced by a hash of the entry's full path, keyed by the archive id, so the
+    /// catalog doesn't reveal file names. Entries can only be found by their exact path and
+    /// directories can't be listed. File contents should still be encrypted, since they aren't
+    /// hidden by this.
+    Hashed,
+}
+
+impl NameEncoding {
+    /// Hex digits kept from the hash, leaving room for the '/' of directory names in a fixed
+    /// layout name field
+    pub(crate) const HASHED_NAME_LEN: usize = 30;
+    /// Context of the key derived from the archive id
+    pub(crate) const KEY_CONTEXT: &'static str = "pfa 2024 hashed catalog names";
+
+    /// Name stored in the catalog for the entry called `name` at `path`, where directory paths
+    /// end with '/'
+    pub(crate) fn encode(&self, archive_id: Option<Ulid>, path: &str, name: &str) -> String {
+        match self {
+            NameEncoding::Plain => name.to_string(),
+            NameEncoding::Hashed => {
+                let id = archive_id.map(|x| x.to_bytes()).unwrap_or_default();
+                let key = blake3::derive_key(Self::KEY_CONTEXT, &id);
+                let hash = blake3::keyed_hash(&key, path.as_bytes()).to_hex();
+                hash[..Self::HASHED_NAME_LEN].to_string()
+            }
+        }
+    }
+}
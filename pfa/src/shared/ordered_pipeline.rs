@@ -0,0 +1,149 @@
+use std::{
+    collections::BTreeMap,
+    sync::{mpsc, Arc, Mutex},
+};
+
+use crate::PfaError;
+
+/// What the producer of a pipeline made of one input, see [`run`]
+pub(crate) enum Produced<W, O> {
+    /// Work for the pool of workers
+    Work(W),
+    /// An output which needs no more work, such as a file which failed to decode
+    Done(O),
+    /// The final output, the producer stops after it, such as after an IO error
+    Last(O),
+}
+
+/// Pulls inputs from `produced` on one thread while a pool of `workers` threads runs `work` on
+/// them, handing every output to `consume` on this thread in the order it was produced, so the
+/// result doesn't depend on scheduling.
+///
+/// At most `queue` inputs wait for a worker, and every input which has been produced but not yet
+/// consumed holds one of `queue + workers` tokens, which bounds memory usage when a single input
+/// is slow to work on. Returning Err from `consume` stops the pipeline.
+pub(crate) fn run<W: Send, O: Send, E: From<PfaError>>(
+    mut produced: impl Iterator<Item = Produced<W, O>> + Send,
+    queue: usize,
+    workers: usize,
+    work: impl Fn(W) -> O + Sync,
+    mut consume: impl FnMut(O) -> Result<(), E>,
+) -> Result<(), E> {
+    std::thread::scope(|scope| {
+        let in_flight = queue + workers;
+        let (token_tx, token_rx) = mpsc::sync_channel::<()>(in_flight);
+        for _ in 0..in_flight {
+            let _ = token_tx.send(());
+        }
+
+        let (work_tx, work_rx) = mpsc::sync_channel::<(usize, W)>(queue);
+        let (done_tx, done_rx) = mpsc::channel::<(usize, O)>();
+
+        {
+            let done_tx = done_tx.clone();
+            scope.spawn(move || {
+                for seq in 0.. {
+                    // the next input is only produced once there's room for it
+                    if token_rx.recv().is_err() {
+                        return;
+                    }
+                    let sent = match produced.next() {
+                        Some(Produced::Work(work)) => work_tx.send((seq, work)).is_ok(),
+                        Some(Produced::Done(output)) => done_tx.send((seq, output)).is_ok(),
+                        Some(Produced::Last(output)) => {
+                            let _ = done_tx.send((seq, output));
+                            false
+                        }
+                        None => false,
+                    };
+                    if !sent {
+                        return;
+                    }
+                }
+            });
+        }
+
+        let work_rx = Arc::new(Mutex::new(work_rx));
+        for _ in 0..workers {
+            let work_rx = work_rx.clone();
+            let done_tx = done_tx.clone();
+            let work = &work;
+            scope.spawn(move || loop {
+                let input = {
+                    let Ok(work_rx) = work_rx.lock() else {
+                        return;
+                    };
+                    work_rx.recv()
+                };
+                let Ok((seq, input)) = input else {
+                    return;
+                };
+                if done_tx.send((seq, work(input))).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(done_tx);
+
+        // outputs arrive until the producer and every worker have stopped
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for (seq, output) in done_rx {
+            pending.insert(seq, output);
+            while let Some(output) = pending.remove(&next) {
+                next += 1;
+                consume(output)?;
+                let _ = token_tx.send(());
+            }
+        }
+        match pending.is_empty() {
+            true => Ok(()),
+            false => Err(PfaError::CustomError("pipeline stopped unexpectedly".into()).into()),
+        }
+    })
+}
+
+/// Like [`run`], but works on batches of `batch_size` inputs on a rayon thread pool, producing
+/// the next batch while the current one is worked on. Nothing blocks on the pool, so this works
+/// when called from a task running on the same pool.
+#[cfg(feature = "rayon")]
+pub(crate) fn run_pooled<W: Send, O: Send, E>(
+    pool: &rayon::ThreadPool,
+    produced: impl Iterator<Item = Produced<W, O>> + Send,
+    batch_size: usize,
+    work: impl Fn(W) -> O + Sync,
+    mut consume: impl FnMut(O) -> Result<(), E>,
+) -> Result<(), E> {
+    use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+    // nothing is produced after the last output
+    let mut produced = produced.scan(false, |stopped, produced| {
+        if *stopped {
+            return None;
+        }
+        *stopped = matches!(produced, Produced::Last(_));
+        Some(produced)
+    });
+    let mut batch = produced.by_ref().take(batch_size).collect::<Vec<_>>();
+    while !batch.is_empty() {
+        let (next, outputs) = pool.install(|| {
+            rayon::join(
+                || produced.by_ref().take(batch_size).collect::<Vec<_>>(),
+                || {
+                    batch
+                        .into_par_iter()
+                        .map(|produced| match produced {
+                            Produced::Work(input) => work(input),
+                            Produced::Done(output) | Produced::Last(output) => output,
+                        })
+                        .collect::<Vec<_>>()
+                },
+            )
+        });
+        for output in outputs {
+            consume(output)?;
+        }
+        batch = next;
+    }
+    Ok(())
+}
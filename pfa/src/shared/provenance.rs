@@ -0,0 +1,62 @@
+use crate::PfaError;
+
+/// Where a file came from when the archive was built, so a bad packed file can be traced back to
+/// its source. Recorded with
+/// [`PfaBuilder::set_provenance`](crate::builder::PfaBuilder::set_provenance) or
+/// [`PfaBuilder::set_record_provenance`](crate::builder::PfaBuilder::set_record_provenance), and
+/// read with [`PfaReader::get_provenance`](crate::reader::PfaReader::get_provenance).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Provenance {
+    /// Path of the file on the filesystem of the machine which built the archive
+    pub source_path: Option<String>,
+    /// BLAKE3 hash of the source file's contents
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::shared::serde_fields::option_hex")
+    )]
+    pub source_hash: Option<[u8; 32]>,
+    /// Tool which produced the file, such as `texture-compiler 1.2`
+    pub tool: Option<String>,
+}
+
+impl Provenance {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn source_path(mut self, path: &str) -> Self {
+        self.source_path = Some(path.to_string());
+        self
+    }
+
+    pub fn source_hash(mut self, hash: [u8; 32]) -> Self {
+        self.source_hash = Some(hash);
+        self
+    }
+
+    pub fn tool(mut self, tool: &str) -> Self {
+        self.tool = Some(tool.to_string());
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), PfaError> {
+        if self
+            .tool
+            .as_ref()
+            .is_some_and(|x| x.len() > u8::MAX as usize)
+        {
+            return Err(PfaError::CustomError("provenance tool is too long".into()));
+        }
+        if self
+            .source_path
+            .as_ref()
+            .is_some_and(|x| x.len() > u16::MAX as usize)
+        {
+            return Err(PfaError::CustomError(
+                "provenance source path is too long".into(),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,13 @@
+/// Room left in an archive so entries can be added in place after it was built. Reserved catalog
+/// slots are placed after every other entry and aren't part of any directory, while reserved data
+/// is zero padding at the end of the data section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReservedSpace {
+    /// Index of the first reserved catalog slot, used or not
+    pub slots_start: u64,
+    pub first_free_slot: u64,
+    pub free_slots: u64,
+    /// Offset of the free data, relative to the start of the data section
+    pub data_offset: u64,
+    pub free_data: u64,
+}
@@ -0,0 +1,31 @@
+use std::fmt::Display;
+
+use serde::Serializer;
+
+use crate::writer::build_cache::to_hex;
+
+/// Writes hashes and key ids as hex strings rather than arrays of numbers
+pub(crate) fn hex<S: Serializer>(bytes: &impl AsRef<[u8]>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&to_hex(bytes.as_ref()))
+}
+
+pub(crate) fn option_hex<S: Serializer>(
+    bytes: &Option<impl AsRef<[u8]>>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    match bytes {
+        Some(bytes) => hex(bytes, s),
+        None => s.serialize_none(),
+    }
+}
+
+/// Writes values such as archive ids in their usual text form
+pub(crate) fn option_display<S: Serializer>(
+    value: &Option<impl Display>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => s.collect_str(value),
+        None => s.serialize_none(),
+    }
+}
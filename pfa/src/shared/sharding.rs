@@ -0,0 +1,8 @@
+/// Bucket holding the entry with the stored name `name` (without the trailing '/') in a sharded
+/// directory with `buckets` buckets
+pub(crate) fn shard_bucket(name: &str, buckets: u64) -> u64 {
+    let hash = blake3::hash(name.as_bytes());
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&hash.as_bytes()[..8]);
+    u64::from_le_bytes(prefix) % buckets.max(1)
+}
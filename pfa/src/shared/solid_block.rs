@@ -0,0 +1,44 @@
+use crate::PfaError;
+
+/// Small files compressed together as one data slice, see
+/// [`PfaBuilder::set_solid_blocks`](crate::builder::PfaBuilder::set_solid_blocks)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SolidBlock {
+    /// Relative to the start of the data section
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+    pub(crate) flags: u8,
+}
+
+/// Where the contents of a file are within its decoded solid block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SolidMember {
+    /// Index of the block in the solid_blocks header field
+    pub(crate) block: u32,
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+}
+
+impl SolidMember {
+    /// The member's contents, given the decoded contents of its block
+    pub(crate) fn contents<'a>(&self, block: &'a [u8]) -> Result<&'a [u8], PfaError> {
+        let start = usize::try_from(self.offset).ok();
+        let end = start.and_then(|x| x.checked_add(usize::try_from(self.size).ok()?));
+        match (start, end) {
+            (Some(start), Some(end)) if end <= block.len() => Ok(&block[start..end]),
+            _ => Err(PfaError::CustomError(
+                "solid member out of bounds of its block".into(),
+            )),
+        }
+    }
+
+    /// Stands in for the hash of the stored data in the member's entry hash, since the entry
+    /// itself stores nothing. Covers the block's stored data and the member's range within it.
+    pub(crate) fn digest(&self, block_hash: &[u8; 32]) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(block_hash);
+        hasher.update(&self.offset.to_le_bytes());
+        hasher.update(&self.size.to_le_bytes());
+        *hasher.finalize().as_bytes()
+    }
+}
@@ -0,0 +1,59 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::PfaError;
+
+/// Quality level of a file, readers can be limited to a maximum tier and archives can be stripped
+/// of higher tiers for constrained platforms. Files without a tier are always included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Tier {
+    Low,
+    Medium,
+    High,
+}
+
+impl Tier {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            Tier::Low => 0,
+            Tier::Medium => 1,
+            Tier::High => 2,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Tier::Low),
+            1 => Some(Tier::Medium),
+            2 => Some(Tier::High),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Tier {
+    type Err = PfaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_ascii_lowercase()[..] {
+            "low" => Ok(Tier::Low),
+            "med" | "medium" => Ok(Tier::Medium),
+            "high" => Ok(Tier::High),
+            _ => Err(PfaError::CustomError(format!("unknown tier '{s}'"))),
+        }
+    }
+}
+
+impl Display for Tier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Tier::Low => write!(f, "low"),
+            Tier::Medium => write!(f, "med"),
+            Tier::High => write!(f, "high"),
+        }
+    }
+}
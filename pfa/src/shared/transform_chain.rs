@@ -0,0 +1,69 @@
+use crate::PfaError;
+
+/// Transformations a file's contents went through before they were added, such as minifying or
+/// converting textures, in the order they ran. Lets verification tell a file which differs from
+/// its source on purpose apart from a damaged one (see
+/// [`PfaReader::check_source`](crate::reader::PfaReader::check_source)), and records how to
+/// reproduce it. Recorded with
+/// [`PfaBuilder::set_transforms`](crate::builder::PfaBuilder::set_transforms), and read with
+/// [`PfaReader::get_transforms`](crate::reader::PfaReader::get_transforms).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TransformChain {
+    /// Identifiers of the steps, such as `minify-json@2`, each up to 255 bytes without `|`
+    pub steps: Vec<String>,
+    /// BLAKE3 hash of the contents passed to the first step
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::shared::serde_fields::option_hex")
+    )]
+    pub input_hash: Option<[u8; 32]>,
+    /// BLAKE3 hash of the contents the last step produced, which are the contents stored. Set by
+    /// [`PfaBuilder::set_transforms`](crate::builder::PfaBuilder::set_transforms) when the file's
+    /// contents are in memory.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::shared::serde_fields::option_hex")
+    )]
+    pub output_hash: Option<[u8; 32]>,
+}
+
+impl TransformChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn step(mut self, id: &str) -> Self {
+        self.steps.push(id.to_string());
+        self
+    }
+
+    pub fn input_hash(mut self, hash: [u8; 32]) -> Self {
+        self.input_hash = Some(hash);
+        self
+    }
+
+    pub fn output_hash(mut self, hash: [u8; 32]) -> Self {
+        self.output_hash = Some(hash);
+        self
+    }
+
+    /// Identifier of the whole chain, its steps joined with `|`
+    pub fn id(&self) -> String {
+        self.steps.join("|")
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), PfaError> {
+        if self.steps.len() > u8::MAX as usize {
+            return Err(PfaError::CustomError("too many transform steps".into()));
+        }
+        for step in &self.steps {
+            if step.is_empty() || step.len() > u8::MAX as usize || step.contains('|') {
+                return Err(PfaError::CustomError(format!(
+                    "invalid transform step '{step}'"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
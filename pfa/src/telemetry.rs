@@ -0,0 +1,122 @@
+//! Counters reported through the [`metrics`](https://docs.rs/metrics) facade when the `metrics`
+//! feature is enabled, so services embedding the reader can monitor archive health. Nothing is
+//! recorded without the feature, or until the application installs a recorder.
+
+/// Files whose stored data was read and decoded whole. Files borrowed with
+/// [`PfaReader::get_file_slice`](crate::reader::PfaReader::get_file_slice) or streamed with
+/// [`PfaReader::open_file`](crate::reader::PfaReader::open_file) aren't counted.
+pub const FILES_READ: &str = "pfa_files_read_total";
+/// Bytes of file contents produced by decoding
+pub const BYTES_DECODED: &str = "pfa_bytes_decoded_total";
+/// Files which couldn't be decoded, such as with the wrong key or damaged data
+pub const DECODE_FAILURES: &str = "pfa_decode_failures_total";
+/// Bytes repaired by error correction while decoding
+pub const ECC_CORRECTIONS: &str = "pfa_ecc_corrections_total";
+/// Files copied from the previous archive by
+/// [`PfaBuilder::include_directory_cached`](crate::builder::PfaBuilder::include_directory_cached)
+/// instead of being processed again
+pub const BUILD_CACHE_HITS: &str = "pfa_build_cache_hits_total";
+/// Files processed by
+/// [`PfaBuilder::include_directory_cached`](crate::builder::PfaBuilder::include_directory_cached)
+/// because they weren't in the build cache or had changed
+pub const BUILD_CACHE_MISSES: &str = "pfa_build_cache_misses_total";
+/// Files with automatic compression stored uncompressed without trying to compress them, because
+/// a sample of their contents looked incompressible
+pub const ENTROPY_SKIPS: &str = "pfa_entropy_skips_total";
+/// Files with automatic compression stored uncompressed without trying to compress them, because
+/// they were smaller than their [minimum compression
+/// size](crate::shared::DataFlags::min_compression_size)
+pub const SIZE_SKIPS: &str = "pfa_size_skips_total";
+/// Files with automatic compression stored uncompressed without trying to compress them, because
+/// their extension is one of a format which is compressed already
+pub const EXTENSION_SKIPS: &str = "pfa_extension_skips_total";
+/// Archives opened with a
+/// [catalog cache](crate::reader::PfaReaderOptions::catalog_cache) whose catalog was loaded from it
+pub const CATALOG_CACHE_HITS: &str = "pfa_catalog_cache_hits_total";
+/// Archives opened with a
+/// [catalog cache](crate::reader::PfaReaderOptions::catalog_cache) whose catalog was decoded,
+/// because the cache was missing or written for a different catalog
+pub const CATALOG_CACHE_MISSES: &str = "pfa_catalog_cache_misses_total";
+
+#[cfg(feature = "metrics")]
+pub(crate) fn increment(name: &'static str, value: u64) {
+    ::metrics::counter!(name).increment(value);
+}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn increment(_name: &'static str, _value: u64) {}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{atomic::AtomicU64, atomic::Ordering, Arc, Mutex},
+    };
+
+    use metrics::{
+        Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit,
+    };
+
+    use crate::{builder::PfaBuilder, reader::PfaReader, shared::DataFlags};
+
+    #[derive(Default)]
+    struct CountingRecorder {
+        counters: Mutex<HashMap<String, Arc<AtomicU64>>>,
+    }
+
+    impl CountingRecorder {
+        fn get(&self, name: &str) -> u64 {
+            let counters = self.counters.lock().unwrap();
+            counters.get(name).map_or(0, |x| x.load(Ordering::Relaxed))
+        }
+    }
+
+    impl Recorder for CountingRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            let mut counters = self.counters.lock().unwrap();
+            let counter = counters.entry(key.name().to_string()).or_default();
+            Counter::from_arc(counter.clone())
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    #[test]
+    fn reader_counters() {
+        let key = DataFlags::generate_key();
+        let mut builder = PfaBuilder::new("metrics");
+        builder
+            .add_file("/a.txt", vec![1; 100], DataFlags::forced_compression())
+            .unwrap();
+        let flags = DataFlags::no_compression()
+            .encryption(Some(key))
+            .error_correction(Some(0.1));
+        builder.add_file("/b.txt", vec![2; 50], flags).unwrap();
+        let mut bytes = builder.build().unwrap();
+        // damage one byte of the last file's data, which error correction repairs
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+
+        let recorder = CountingRecorder::default();
+        metrics::with_local_recorder(&recorder, || {
+            let mut reader = PfaReader::from_bytes(bytes).unwrap();
+            reader.get_file("/a.txt", None).unwrap().unwrap();
+            reader.get_file("/b.txt", Some(key)).unwrap().unwrap();
+            assert!(reader.get_file("/b.txt", None).is_err());
+        });
+        assert_eq!(recorder.get(super::FILES_READ), 2);
+        assert_eq!(recorder.get(super::BYTES_DECODED), 150);
+        assert_eq!(recorder.get(super::DECODE_FAILURES), 1);
+        assert_eq!(recorder.get(super::ECC_CORRECTIONS), 1);
+    }
+}
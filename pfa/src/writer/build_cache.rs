@@ -0,0 +1,174 @@
+use std::{
+    collections::HashMap,
+    fs::Metadata,
+    io::{BufRead, BufReader, Read, Write},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    shared::{parse_hex_key, Ulid},
+    PfaError,
+};
+
+/// What was known about a source file when it was last packed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildCacheEntry {
+    /// Nanoseconds since the unix epoch
+    pub mtime: u128,
+    pub size: u64,
+    /// BLAKE3 hash of the unprocessed contents
+    pub hash: [u8; 32],
+    /// Identifies the data flags the file was processed with
+    pub flags_fingerprint: [u8; 32],
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BuildCacheStats {
+    /// Files copied from the previous archive
+    pub reused: usize,
+    pub processed: usize,
+}
+
+/// Records the source files which went into an archive, so the next build can copy unchanged
+/// files from it instead of processing them again. Stored as a text file:
+///
+/// ```text
+/// pfa-build-cache 1
+/// archive_id {ulid}
+/// {mtime}\t{size}\t{hash}\t{flags_fingerprint}\t{path}
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct BuildCache {
+    archive_id: Option<Ulid>,
+    entries: HashMap<String, BuildCacheEntry>,
+}
+
+impl BuildCache {
+    const MAGIC: &'static str = "pfa-build-cache 1";
+
+    pub fn new(archive_id: Ulid) -> Self {
+        Self {
+            archive_id: Some(archive_id),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Id of the archive the cache describes
+    pub fn archive_id(&self) -> Option<Ulid> {
+        self.archive_id
+    }
+
+    pub fn get(&self, path: &str) -> Option<&BuildCacheEntry> {
+        self.entries.get(path)
+    }
+
+    pub fn insert(&mut self, path: String, entry: BuildCacheEntry) {
+        self.entries.insert(path, entry);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn mtime(metadata: &Metadata) -> u128 {
+        metadata
+            .modified()
+            .ok()
+            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            .map(|x| x.as_nanos())
+            .unwrap_or_default()
+    }
+
+    /// Loads a cache file, a missing file results in an empty cache
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PfaError> {
+        match std::fs::File::open(path) {
+            Ok(file) => Self::read(file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PfaError> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    pub fn read(input: impl Read) -> Result<Self, PfaError> {
+        let invalid = || PfaError::CustomError("invalid build cache".into());
+        let mut lines = BufReader::new(input).lines();
+        if lines.next().transpose()?.as_deref() != Some(Self::MAGIC) {
+            return Err(invalid());
+        }
+
+        let archive_id = lines
+            .next()
+            .transpose()?
+            .and_then(|x| x.strip_prefix("archive_id ").map(Ulid::from_string))
+            .ok_or_else(invalid)?
+            .map_err(|_| invalid())?;
+
+        let mut entries = HashMap::new();
+        for line in lines {
+            let line = line?;
+            let mut fields = line.splitn(5, '\t');
+            let mut next = || fields.next().ok_or_else(invalid);
+            let mtime = next()?.parse().map_err(|_| invalid())?;
+            let size = next()?.parse().map_err(|_| invalid())?;
+            let hash = parse_hex_key(next()?).ok_or_else(invalid)?;
+            let flags_fingerprint = parse_hex_key(next()?).ok_or_else(invalid)?;
+            let path = next()?.to_string();
+            entries.insert(
+                path,
+                BuildCacheEntry {
+                    mtime,
+                    size,
+                    hash,
+                    flags_fingerprint,
+                },
+            );
+        }
+
+        Ok(Self {
+            archive_id: Some(archive_id),
+            entries,
+        })
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> Result<(), PfaError> {
+        let archive_id = self.archive_id.ok_or(PfaError::CustomError(
+            "build cache has no archive id".into(),
+        ))?;
+        writeln!(out, "{}", Self::MAGIC)?;
+        writeln!(out, "archive_id {archive_id}")?;
+
+        let mut entries = self.entries.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|x| x.0);
+        for (path, entry) in entries {
+            if path.contains('\n') {
+                continue; // can't be represented, the file will just be processed every time
+            }
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}",
+                entry.mtime,
+                entry.size,
+                to_hex(&entry.hash),
+                to_hex(&entry.flags_fingerprint),
+                path
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
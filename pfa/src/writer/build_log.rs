@@ -0,0 +1,162 @@
+use std::{
+    fmt::Write as _,
+    io::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{shared::CatalogLayout, PfaError};
+
+/// Records the progress of a build as JSON lines, one event per line, flushed as soon as it is
+/// written. When a build crashes or is killed, the last events tell which files were being
+/// processed. Every event has an `event` name and a `time_ms` unix timestamp:
+///
+/// - `build_started`: `archive`, `archive_id`, `files`
+/// - `file_started`: `path`, the file's source is being read
+/// - `file_encoded`: `path`, `stored_size`, `flags`
+/// - `file_written`: `path`, `offset`, `stored_size`, appended to the data section
+/// - `warning`: `message`, such as a directory which exceeded the directory limits
+/// - `catalog_flushed`: `entries`, `layout`
+/// - `build_finished`: `size`
+/// - `build_failed`: `error`
+pub(crate) struct BuildLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl BuildLog {
+    pub(crate) fn new(out: Box<dyn Write + Send>) -> Self {
+        Self {
+            out: Mutex::new(out),
+        }
+    }
+
+    fn event(&self, event: &str, fields: &[(&str, Value)]) -> Result<(), PfaError> {
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis())
+            .unwrap_or_default();
+        let mut line = format!("{{\"event\":{},\"time_ms\":{time_ms}", json_string(event));
+        for (name, value) in fields {
+            let _ = match value {
+                Value::Str(value) => write!(line, ",\"{name}\":{}", json_string(value)),
+                Value::Num(value) => write!(line, ",\"{name}\":{value}"),
+            };
+        }
+        line.push_str("}\n");
+
+        let mut out = self
+            .out
+            .lock()
+            .map_err(|_| PfaError::CustomError("build log poisoned".into()))?;
+        out.write_all(line.as_bytes())?;
+        out.flush()?;
+        Ok(())
+    }
+
+    pub(crate) fn build_started(
+        &self,
+        archive: &str,
+        archive_id: &str,
+        files: usize,
+    ) -> Result<(), PfaError> {
+        self.event(
+            "build_started",
+            &[
+                ("archive", Value::Str(archive)),
+                ("archive_id", Value::Str(archive_id)),
+                ("files", Value::Num(files as u64)),
+            ],
+        )
+    }
+
+    pub(crate) fn file_started(&self, path: &str) -> Result<(), PfaError> {
+        self.event("file_started", &[("path", Value::Str(path))])
+    }
+
+    pub(crate) fn file_encoded(
+        &self,
+        path: &str,
+        stored_size: u64,
+        flags: u8,
+    ) -> Result<(), PfaError> {
+        self.event(
+            "file_encoded",
+            &[
+                ("path", Value::Str(path)),
+                ("stored_size", Value::Num(stored_size)),
+                ("flags", Value::Num(flags as u64)),
+            ],
+        )
+    }
+
+    pub(crate) fn file_written(
+        &self,
+        path: &str,
+        offset: u64,
+        stored_size: u64,
+    ) -> Result<(), PfaError> {
+        self.event(
+            "file_written",
+            &[
+                ("path", Value::Str(path)),
+                ("offset", Value::Num(offset)),
+                ("stored_size", Value::Num(stored_size)),
+            ],
+        )
+    }
+
+    pub(crate) fn warning(&self, message: &str) -> Result<(), PfaError> {
+        self.event("warning", &[("message", Value::Str(message))])
+    }
+
+    pub(crate) fn catalog_flushed(
+        &self,
+        entries: usize,
+        layout: CatalogLayout,
+    ) -> Result<(), PfaError> {
+        let layout = match layout {
+            CatalogLayout::Fixed => "fixed",
+            CatalogLayout::Compact => "compact",
+        };
+        self.event(
+            "catalog_flushed",
+            &[
+                ("entries", Value::Num(entries as u64)),
+                ("layout", Value::Str(layout)),
+            ],
+        )
+    }
+
+    pub(crate) fn build_finished(&self, size: u64) -> Result<(), PfaError> {
+        self.event("build_finished", &[("size", Value::Num(size))])
+    }
+
+    pub(crate) fn build_failed(&self, error: &PfaError) -> Result<(), PfaError> {
+        self.event("build_failed", &[("error", Value::Str(&error.to_string()))])
+    }
+}
+
+enum Value<'a> {
+    Str(&'a str),
+    Num(u64),
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
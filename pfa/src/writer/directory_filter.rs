@@ -0,0 +1,118 @@
+use std::{borrow::Cow, fmt::Debug, sync::Arc};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+use crate::PfaError;
+
+type Predicate = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// Chooses the files added by [`PfaBuilder::include_directory`], see
+/// [`PfaBuilder::set_directory_filter`].
+///
+/// Patterns are matched against archive paths such as `/maps/a.map`. Patterns without a '/',
+/// such as `*.tmp` or `.git`, match the name of a file or of any directory above it. Others,
+/// such as `/target/**`, match the whole path from the root, where `*` doesn't match '/' and `**`
+/// matches across directories.
+/// Excluded directories aren't walked at all.
+///
+/// [`PfaBuilder::include_directory`]: crate::builder::PfaBuilder::include_directory
+/// [`PfaBuilder::set_directory_filter`]: crate::builder::PfaBuilder::set_directory_filter
+#[derive(Clone, Default)]
+pub struct DirectoryFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    predicate: Option<Predicate>,
+}
+
+impl Debug for DirectoryFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DirectoryFilter")
+            .field("include", &self.include)
+            .field("exclude", &self.exclude)
+            .field("predicate", &self.predicate.is_some())
+            .finish()
+    }
+}
+
+impl DirectoryFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Once any include pattern is given, only files matching one of them are added
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.include.push(pattern.to_string());
+        self
+    }
+
+    /// Skips files matching the pattern, and directories whose name matches it
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(pattern.to_string());
+        self
+    }
+
+    /// Skips files for which `predicate` returns false when given their archive path, after the
+    /// patterns have been checked
+    pub fn predicate(mut self, predicate: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        self.predicate = Some(Arc::new(predicate));
+        self
+    }
+
+    pub(crate) fn compile(&self) -> Result<CompiledFilter, PfaError> {
+        Ok(CompiledFilter {
+            include: match self.include.is_empty() {
+                true => None,
+                false => Some(compile_patterns(&self.include)?),
+            },
+            exclude: compile_patterns(&self.exclude)?,
+            predicate: self.predicate.clone(),
+        })
+    }
+}
+
+pub(crate) struct CompiledFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    predicate: Option<Predicate>,
+}
+
+impl CompiledFilter {
+    /// Whether a directory, given by its archive path without the trailing '/', is walked
+    pub(crate) fn allows_directory(&self, path: &str) -> bool {
+        !self.exclude.is_match(rooted(path).as_ref())
+    }
+
+    /// Whether a file in a directory which is walked is added
+    pub(crate) fn allows_file(&self, path: &str) -> bool {
+        let path = rooted(path);
+        let path = path.as_ref();
+        !self.exclude.is_match(path)
+            && self.include.as_ref().is_none_or(|x| x.is_match(path))
+            && self.predicate.as_ref().is_none_or(|x| x(path))
+    }
+}
+
+/// Archive paths may be given without the leading '/'
+fn rooted(path: &str) -> Cow<'_, str> {
+    match path.starts_with('/') {
+        true => Cow::Borrowed(path),
+        false => Cow::Owned(format!("/{path}")),
+    }
+}
+
+fn compile_patterns(patterns: &[String]) -> Result<GlobSet, PfaError> {
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        let full = match pattern.contains('/') {
+            true => pattern.clone(),
+            false => format!("**/{pattern}"),
+        };
+        let glob = GlobBuilder::new(&full)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| PfaError::InvalidGlobError(format!("'{pattern}': {}", e.kind())))?;
+        set.add(glob);
+    }
+    set.build()
+        .map_err(|e| PfaError::InvalidGlobError(e.to_string()))
+}
@@ -0,0 +1,123 @@
+use std::fmt::Display;
+
+use crate::writer::pfa_writer::PfaPath;
+
+/// Limits on the shape of the directory tree. Directories with more entries than `max_entries`
+/// are split into hash buckets at build time, so lookups only scan a bucket's entries. Sharding is
+/// off unless `max_entries` is set, since readers which predate it would list the buckets as
+/// directories of their own. Directories nested deeper than `max_depth` are only reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectoryLimits {
+    pub(crate) max_entries: Option<usize>,
+    pub(crate) bucket_size: usize,
+    pub(crate) max_depth: Option<usize>,
+}
+
+impl Default for DirectoryLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: None,
+            bucket_size: 1024,
+            max_depth: Some(64),
+        }
+    }
+}
+
+impl DirectoryLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Directories with more entries are sharded, None (the default) never shards. Archives with
+    /// sharded directories are only read correctly by readers which support sharding.
+    pub fn max_entries(mut self, max_entries: Option<usize>) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Average number of entries per bucket of a sharded directory
+    pub fn bucket_size(mut self, bucket_size: usize) -> Self {
+        self.bucket_size = bucket_size.max(1);
+        self
+    }
+
+    /// Directories nested deeper than this are reported, the root has a depth of 0
+    pub fn max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Number of buckets a directory with `entries` entries is split into, None if it isn't
+    /// sharded
+    pub(crate) fn buckets(&self, entries: usize) -> Option<usize> {
+        let max_entries = self.max_entries?;
+        (entries > max_entries).then(|| entries.div_ceil(self.bucket_size).max(2))
+    }
+
+    /// Every limit exceeded by the tree
+    pub(crate) fn check(&self, tree: &PfaPath) -> Vec<LimitWarning> {
+        let mut warnings = vec![];
+        let PfaPath::Directory(root) = tree else {
+            return warnings;
+        };
+        let mut stack = vec![(root, "/".to_string(), 0)];
+        while let Some((dir, path, depth)) = stack.pop() {
+            if let Some(buckets) = self.buckets(dir.contents.len()) {
+                warnings.push(LimitWarning::Sharded {
+                    path: path.clone(),
+                    entries: dir.contents.len(),
+                    buckets,
+                });
+            }
+            if self.max_depth.is_some_and(|x| depth == x + 1) {
+                warnings.push(LimitWarning::TooDeep {
+                    path: path.clone(),
+                    depth,
+                });
+            }
+            for child in dir.contents.iter().rev() {
+                if let PfaPath::Directory(child) = child {
+                    stack.push((child, format!("{path}{}/", child.name), depth + 1));
+                }
+            }
+        }
+        warnings
+    }
+}
+
+/// A directory which exceeded one of the [`DirectoryLimits`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(tag = "kind", rename_all = "snake_case")
+)]
+pub enum LimitWarning {
+    /// The directory had more entries than allowed and was split into buckets
+    Sharded {
+        path: String,
+        entries: usize,
+        buckets: usize,
+    },
+    /// The directory is nested deeper than allowed, only reported for the first directory past
+    /// the limit on each branch
+    TooDeep { path: String, depth: usize },
+}
+
+impl Display for LimitWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitWarning::Sharded {
+                path,
+                entries,
+                buckets,
+            } => write!(
+                f,
+                "directory '{path}' has {entries} entries, split into {buckets} buckets"
+            ),
+            LimitWarning::TooDeep { path, depth } => {
+                write!(f, "directory '{path}' is nested {depth} levels deep")
+            }
+        }
+    }
+}
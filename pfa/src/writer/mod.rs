@@ -1,4 +1,11 @@
+pub mod build_cache;
+mod build_log;
+pub mod directory_filter;
+pub mod directory_limits;
+pub mod pack;
 pub mod pfa_builder;
+pub mod pfa_editor;
 mod pfa_writer;
+pub mod streaming_builder;
 
 pub use pfa_builder as builder;
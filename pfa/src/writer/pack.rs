@@ -0,0 +1,28 @@
+use std::path::{Path, PathBuf};
+
+use crate::{builder::PfaBuilder, shared::DataFlags, PfaError};
+
+/// Packs a directory into `{OUT_DIR}/{name}.pfa` from a build script, and has cargo rerun the
+/// script when anything in the directory changes. The archive can then be embedded with
+/// `include_pfa!(concat!(env!("OUT_DIR"), "/{name}.pfa"))`.
+pub fn pack_directory(dir: &str, name: &str, flags: DataFlags) -> Result<PathBuf, PfaError> {
+    let out_dir = std::env::var_os("OUT_DIR").ok_or(PfaError::CustomError(
+        "OUT_DIR is not set, pack_directory must be called from a build script".into(),
+    ))?;
+    let out = pack_directory_into(dir, name, flags, Path::new(&out_dir))?;
+    println!("cargo:rerun-if-changed={dir}");
+    Ok(out)
+}
+
+pub(crate) fn pack_directory_into(
+    dir: &str,
+    name: &str,
+    flags: DataFlags,
+    out_dir: &Path,
+) -> Result<PathBuf, PfaError> {
+    let mut builder = PfaBuilder::new(name);
+    builder.include_directory(dir, flags)?;
+    let out = out_dir.join(format!("{name}.pfa"));
+    std::fs::write(&out, builder.build()?)?;
+    Ok(out)
+}
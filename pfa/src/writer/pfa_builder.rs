@@ -1,6 +1,22 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+};
 
-use crate::shared::data_flags::DataFlags;
+use crate::reader::pfa_reader::PfaFileSlice;
+use crate::reader::{PfaEncryptedEntry, PfaReader};
+use crate::shared::{
+    compression_heuristics,
+    data_flags::{DataCompressionType, DataFlags},
+    CatalogLayout, Cipher, CodecRegistry, KeyId, NameEncoding, Provenance, Tier, TransformChain,
+    Ulid, DEFAULT_DEPTH_LIMIT, LATEST_VERSION, PRECOMPRESSED_EXTENSIONS,
+};
+use crate::telemetry;
+use crate::writer::build_cache::{BuildCache, BuildCacheEntry, BuildCacheStats};
+use crate::writer::build_log::BuildLog;
+use crate::writer::directory_filter::DirectoryFilter;
+use crate::writer::directory_limits::{DirectoryLimits, LimitWarning};
 use crate::writer::pfa_writer::*;
 
 use crate::PfaError;
@@ -11,7 +27,8 @@ enum PfaBuilderPath {
 }
 
 impl From<String> for PfaBuilderPath {
-    fn from(mut value: String) -> Self {
+    fn from(value: String) -> Self {
+        let mut value = normalize_path(&value);
         if !value.starts_with('/') {
             value = format!("/{value}");
         }
@@ -26,8 +43,67 @@ impl From<String> for PfaBuilderPath {
     }
 }
 
+/// Replaces the backslashes of Windows paths with forward slashes and collapses repeated
+/// separators, so paths name the same entry however they were written
+pub(crate) fn normalize_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for c in path.chars() {
+        let c = if c == '\\' { '/' } else { c };
+        if c != '/' || !out.ends_with('/') {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Returns the key of an encrypted file, if it's known, see [`PfaBuilder::from_reader`]
+pub type KeyProvider<'a> = &'a dyn Fn(&PfaEncryptedEntry) -> Option<[u8; 32]>;
+
+/// What happens when a file is added at a path which already has one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// Fail with [`PfaError::DuplicatePath`]
+    #[default]
+    Error,
+    /// Replace the earlier file, along with its tier and other metadata
+    Overwrite,
+    /// Keep the earlier file and ignore the new one
+    KeepFirst,
+}
+
 pub struct PfaBuilder {
     name: String,
+    version: Option<u8>,
+    archive_id: Ulid,
+    catalog_layout: Option<CatalogLayout>,
+    entry_hashes: bool,
+    name_encoding: NameEncoding,
+    threads: usize,
+    #[cfg(feature = "rayon")]
+    thread_pool: Option<std::sync::Arc<rayon::ThreadPool>>,
+    reserved_space: (usize, u64),
+    build_log: Option<BuildLog>,
+    directory_limits: DirectoryLimits,
+    default_flags: Option<DataFlags>,
+    /// Default flags of the files under each directory, by its path ending with `/`
+    directory_flags: BTreeMap<String, DataFlags>,
+    sniff_content_types: bool,
+    precompressed_extensions: Vec<String>,
+    min_compression_size: Option<u32>,
+    record_provenance: bool,
+    provenance_tool: Option<String>,
+    strip_provenance: bool,
+    depth_limit: usize,
+    store_only: Option<u32>,
+    extra_data: Vec<u8>,
+    compress_extra_data: bool,
+    solid_blocks: Option<(u64, u64)>,
+    encryption_key: Option<[u8; 32]>,
+    duplicate_policy: DuplicatePolicy,
+    directory_filter: DirectoryFilter,
+    generation: Option<u64>,
+    variants: BTreeSet<String>,
+    codecs: CodecRegistry,
     file_tree: PfaPath,
 }
 
@@ -36,13 +112,554 @@ impl PfaBuilder {
         let root = PfaPath::Directory(PfaDirectory::new("", vec![]));
         Self {
             name: name.to_string(),
+            version: None,
+            archive_id: Ulid::new(),
+            catalog_layout: None,
+            entry_hashes: false,
+            name_encoding: NameEncoding::Plain,
+            threads: std::thread::available_parallelism()
+                .map(|x| x.get())
+                .unwrap_or(1),
+            #[cfg(feature = "rayon")]
+            thread_pool: None,
+            reserved_space: (0, 0),
+            build_log: None,
+            directory_limits: DirectoryLimits::default(),
+            default_flags: None,
+            directory_flags: BTreeMap::new(),
+            sniff_content_types: false,
+            precompressed_extensions: PRECOMPRESSED_EXTENSIONS
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+            record_provenance: false,
+            provenance_tool: None,
+            strip_provenance: false,
+            depth_limit: DEFAULT_DEPTH_LIMIT,
+            store_only: None,
+            min_compression_size: None,
+            extra_data: vec![],
+            compress_extra_data: false,
+            solid_blocks: None,
+            encryption_key: None,
+            duplicate_policy: DuplicatePolicy::default(),
+            directory_filter: DirectoryFilter::default(),
+            generation: None,
+            variants: BTreeSet::new(),
+            codecs: CodecRegistry::default(),
             file_tree: root,
         }
     }
 
+    /// Builder holding every file of `reader` with its metadata, and the archive's name, id,
+    /// version, extra data and layout, so tools can change an archive without adding every file
+    /// again. The generation isn't copied, since the archive built is a different one, see
+    /// [`Self::set_generation`] for updaters replacing the archive in place. Files are copied as
+    /// they are stored, except encrypted files `keys` returns a key for, which are decoded and
+    /// encrypted whole again with it when the archive is built. Copied files can be replaced once
+    /// the [duplicate policy](Self::set_duplicate_policy) allows it.
+    pub fn from_reader<T: Read + Seek>(
+        reader: &mut PfaReader<T>,
+        keys: Option<KeyProvider>,
+    ) -> Result<Self, PfaError> {
+        let encrypted = match keys {
+            Some(_) => reader
+                .encrypted_entries()?
+                .into_iter()
+                .map(|x| (x.path.to_string(), x))
+                .collect::<HashMap<_, _>>(),
+            None => HashMap::new(),
+        };
+        let decode = |slice: &PfaFileSlice| {
+            let entry = encrypted.get(&slice.path.to_string())?;
+            keys.and_then(|keys| keys(entry))
+        };
+        let mut builder = reader.rebuild(reader.get_version(), decode, |_, contents| {
+            Ok(Some(contents))
+        })?;
+        reader.copy_layout(&mut builder)?;
+        builder.generation = None;
+        Ok(builder)
+    }
+
+    /// Format version the archive is written as, read with [`PfaReader::get_version`]. Unless set,
+    /// version 2 is written, or version 3 when files are compressed with a codec other than LZ4,
+    /// which readers of version 2 would decode as LZ4. Version 1 archives hold at most 255 bytes
+    /// of extra data, and building an archive which uses any feature newer than its version
+    /// fails with [`PfaError::UnsupportedByVersion`].
+    pub fn set_version(&mut self, version: u8) -> Result<(), PfaError> {
+        if version == 0 || version > LATEST_VERSION {
+            return Err(PfaError::UnsupportedFeature(format!(
+                "archive version {version}"
+            )));
+        }
+        self.version = Some(version);
+        Ok(())
+    }
+
+    /// Custom codecs files copied from another archive can be compressed with again, such as
+    /// the files of solid blocks, which are decompressed to be grouped into new blocks. Builders
+    /// made from a reader have its codecs.
+    pub fn set_codecs(&mut self, codecs: CodecRegistry) {
+        self.codecs = codecs;
+    }
+
+    pub fn codecs(&self) -> &CodecRegistry {
+        &self.codecs
+    }
+
+    /// Every builder is assigned a new unique archive id, this overrides it (for example, to give
+    /// a patched archive the id recorded in the diff which produced it)
+    pub fn set_archive_id(&mut self, archive_id: Ulid) {
+        self.archive_id = archive_id;
+    }
+
+    /// Stores a generation number in the header, along with the time the archive is built.
+    /// Updaters which replace an archive in place give the new one a higher generation, so
+    /// readers following generations (see
+    /// [`PfaReaderOptions::follow_generations`](crate::reader::PfaReaderOptions::follow_generations))
+    /// notice and reopen it. [`PfaEditor`](crate::writer::pfa_editor::PfaEditor) increments it.
+    pub fn set_generation(&mut self, generation: u64) {
+        self.generation = Some(generation);
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    pub fn get_archive_id(&self) -> Ulid {
+        self.archive_id
+    }
+
+    /// By default the layout resulting in the smaller catalog is used, the compact layout is
+    /// required for names longer than 32 bytes. Archives using the fixed layout don't record it,
+    /// so readers from before the compact layout existed can still read them.
+    pub fn set_catalog_layout(&mut self, layout: CatalogLayout) {
+        self.catalog_layout = Some(layout);
+    }
+
+    /// Stores a hash of every entry, where directory hashes are computed from their children, so
+    /// readers can tell whether two subtrees are identical without reading them
+    pub fn set_entry_hashes(&mut self, entry_hashes: bool) {
+        self.entry_hashes = entry_hashes;
+    }
+
+    /// With [`NameEncoding::Hashed`], the catalog only stores hashes of paths. Readers can then
+    /// only open entries by their exact path, and can't list directories.
+    pub fn set_name_encoding(&mut self, encoding: NameEncoding) {
+        self.name_encoding = encoding;
+    }
+
+    /// Number of threads used to process files while building, files are processed on the
+    /// calling thread when this is 1. Defaults to the available parallelism.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads;
+    }
+
+    /// Process files on a rayon thread pool, such as one shared with the rest of the
+    /// application, instead of spawning threads for the build. Takes precedence over
+    /// [`Self::set_threads`].
+    #[cfg(feature = "rayon")]
+    pub fn set_thread_pool(&mut self, pool: Option<std::sync::Arc<rayon::ThreadPool>>) {
+        self.thread_pool = pool;
+    }
+
+    /// Leaves empty catalog slots and data padding in the archive, so
+    /// [`PfaEditor`](crate::writer::pfa_editor::PfaEditor) can add files to it in place later.
+    /// Each slot holds one entry with a name of up to 32 bytes.
+    pub fn set_reserved_space(&mut self, catalog_slots: usize, data_size: u64) {
+        self.reserved_space = (catalog_slots, data_size);
+    }
+
+    /// Writes a JSON line for every step of the build to `log` (such as a file opened in append
+    /// mode), so a build which crashed or was killed can be diagnosed. Each line is flushed
+    /// before the build moves on.
+    pub fn set_build_log(&mut self, log: impl Write + Send + 'static) {
+        self.build_log = Some(BuildLog::new(Box::new(log)));
+    }
+
+    /// Flags used instead of [`DataFlags::auto`] by the files added afterwards, so an archive's
+    /// processing options can be chosen in one place
+    pub fn set_default_flags(&mut self, flags: DataFlags) {
+        self.default_flags = Some(flags);
+    }
+
+    /// Flags used instead of [`DataFlags::auto`] by the files added afterwards anywhere under the
+    /// directory at `path`, such as to encrypt or error correct a whole subtree. Files passing
+    /// other flags keep them, and the flags of the innermost directory which has them are used,
+    /// before those of [`Self::set_default_flags`].
+    pub fn set_directory_flags(&mut self, path: &str, flags: DataFlags) {
+        let mut path = normalize_path(&format!("/{path}"));
+        if !path.ends_with('/') {
+            path.push('/');
+        }
+        self.directory_flags.insert(path, flags);
+    }
+
+    /// Encrypts every file in the archive which isn't encrypted with a key of its own, including
+    /// files added before this was called. Building fails if a file copied from another archive
+    /// (such as with [`Self::add_stored_file`]) isn't encrypted, since its data can't be
+    /// processed again.
+    pub fn set_encryption_key(&mut self, key: [u8; 32]) {
+        self.encryption_key = Some(key);
+    }
+
+    /// Builds an archive meant to be mapped into memory: every file is stored as is, at an
+    /// archive offset which is a multiple of `alignment` (such as the page size), and entry hashes
+    /// are always written. Readers can then borrow the contents of any file without copying it
+    /// (see [`PfaReader::get_file_cow`]). Building fails if any file is encrypted, has error
+    /// correction, or was added already processed.
+    pub fn set_store_only(&mut self, alignment: u32) {
+        self.store_only = Some(alignment);
+    }
+
+    /// Archive level data stored in the header, such as a manifest, read with
+    /// [`PfaReader::get_extra_data`]
+    pub fn set_extra_data(&mut self, extra_data: Vec<u8>) {
+        self.extra_data = extra_data;
+    }
+
+    /// Stores the extra data compressed with LZ4 when that makes it smaller, readers decompress it
+    /// when the archive is opened. Readers from before extra data compression existed see the
+    /// compressed bytes.
+    pub fn set_compress_extra_data(&mut self, compress: bool) {
+        self.compress_extra_data = compress;
+    }
+
+    /// Compresses files of up to `max_file_size` bytes together in solid blocks of up to
+    /// `block_size` bytes before compression, which compresses many small similar files, such as
+    /// scripts or JSON, far better than compressing each on its own. Only files whose compression
+    /// isn't turned off, and which aren't encrypted or error corrected, are grouped, and files
+    /// share blocks with files processed with the same flags. Readers decompress a whole block to
+    /// read any file in it, keeping the blocks they decompressed last.
+    pub fn set_solid_blocks(&mut self, max_file_size: u64, block_size: u64) {
+        self.solid_blocks = Some((max_file_size, block_size));
+    }
+
+    /// How files added at a path which already has one are handled, fails by default
+    pub fn set_duplicate_policy(&mut self, policy: DuplicatePolicy) {
+        self.duplicate_policy = policy;
+    }
+
+    /// Files skipped by [`Self::include_directory`] and [`Self::include_directory_cached`], on
+    /// top of hidden files and those ignored by `.gitignore` files, which are always skipped
+    pub fn set_directory_filter(&mut self, filter: DirectoryFilter) {
+        self.directory_filter = filter;
+    }
+
+    /// Flags a file added at `path` with `flags` is processed with
+    pub(crate) fn resolve_flags(&self, path: &str, flags: DataFlags) -> DataFlags {
+        let flags = match self.inherited_flags(path) {
+            Some(default) if flags.is_auto() => default.clone(),
+            _ => flags,
+        };
+        let flags = match self.min_compression_size {
+            Some(bytes)
+                if flags.is_automatic_compression() && !flags.has_min_compression_size() =>
+            {
+                flags.min_compression_size(Some(bytes))
+            }
+            _ => flags,
+        };
+        let flags = match self.store_only {
+            Some(_) => flags.compression_type(DataCompressionType::Forced(false)),
+            None => flags,
+        };
+        match self.encryption_key {
+            Some(key) if !flags.has_encryption_key() => flags.encryption(Some(key)),
+            _ => flags,
+        }
+    }
+
+    /// Default flags of the innermost directory containing `path` which has them, otherwise the
+    /// archive's
+    fn inherited_flags(&self, path: &str) -> Option<&DataFlags> {
+        let path = normalize_path(&format!("/{path}"));
+        // ancestors sort before their subdirectories, so the last match is the innermost
+        self.directory_flags
+            .iter()
+            .rev()
+            .find(|(dir, _)| path.starts_with(dir.as_str()))
+            .map(|(_, flags)| flags)
+            .or(self.default_flags.as_ref())
+    }
+
+    /// Applies the archive's encryption key to every file
+    fn encrypt_all(root: &mut PfaPath, key: [u8; 32]) -> Result<(), PfaError> {
+        let mut stack = vec![(root, "/".to_string())];
+        while let Some((path, prefix)) = stack.pop() {
+            match path {
+                PfaPath::Directory(dir) => {
+                    for child in dir.contents.iter_mut().rev() {
+                        let prefix = match child {
+                            PfaPath::Directory(child) => format!("{prefix}{}/", child.name),
+                            PfaPath::File(_) => prefix.clone(),
+                        };
+                        stack.push((child, prefix));
+                    }
+                }
+                PfaPath::File(file) => match &file.contents {
+                    PfaFileSource::Stored { flags, .. } | PfaFileSource::Spilled { flags, .. }
+                        if !DataFlags::is_encrypted(*flags) =>
+                    {
+                        return Err(PfaError::CustomError(format!(
+                            "'{prefix}{}' is stored unencrypted but the archive requires encryption",
+                            file.name
+                        )));
+                    }
+                    PfaFileSource::Stored { .. } | PfaFileSource::Spilled { .. } => {}
+                    _ if !file.flags.has_encryption_key() => {
+                        file.flags = file.flags.clone().encryption(Some(key));
+                    }
+                    _ => {}
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Turns off compression for every file, and fails if any file can't be stored as is
+    fn store_all(root: &mut PfaPath) -> Result<(), PfaError> {
+        let mut stack = vec![(root, "/".to_string())];
+        while let Some((path, prefix)) = stack.pop() {
+            match path {
+                PfaPath::Directory(dir) => {
+                    for child in dir.contents.iter_mut().rev() {
+                        let prefix = match child {
+                            PfaPath::Directory(child) => format!("{prefix}{}/", child.name),
+                            PfaPath::File(_) => prefix.clone(),
+                        };
+                        stack.push((child, prefix));
+                    }
+                }
+                PfaPath::File(file) => {
+                    let stored = match &file.contents {
+                        PfaFileSource::Stored { flags, .. }
+                        | PfaFileSource::Spilled { flags, .. } => DataFlags::is_unprocessed(*flags),
+                        _ => {
+                            !file.flags.has_encryption_key()
+                                && !file.flags.has_error_correction_percentage()
+                        }
+                    };
+                    if !stored {
+                        return Err(PfaError::CustomError(format!(
+                            "'{prefix}{}' can't be stored as is, which store only archives require",
+                            file.name
+                        )));
+                    }
+                    file.flags = file
+                        .flags
+                        .clone()
+                        .compression_type(DataCompressionType::Forced(false));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a content type guessed from the contents or name of every file which wasn't given
+    /// one with [`Self::set_content_type`], see
+    /// [`sniff_content_type`](crate::shared::sniff_content_type)
+    pub fn set_sniff_content_types(&mut self, sniff: bool) {
+        self.sniff_content_types = sniff;
+    }
+
+    /// Files with automatic compression whose extension is one of `extensions` (ignoring case)
+    /// are stored uncompressed without trying to compress them, and recorded as such (see
+    /// [`Self::set_compression_skipped`]). Defaults to [`PRECOMPRESSED_EXTENSIONS`], an empty
+    /// list tries to compress every file.
+    pub fn set_precompressed_extensions(&mut self, extensions: &[&str]) {
+        self.precompressed_extensions = extensions.iter().map(|x| x.to_string()).collect();
+    }
+
+    /// Files with automatic compression smaller than `bytes` are stored uncompressed without
+    /// trying to compress them, unless their flags set their own
+    /// [threshold](DataFlags::min_compression_size). Files grouped into solid blocks are
+    /// compressed with their block regardless.
+    pub fn set_min_compression_size(&mut self, bytes: u32) {
+        self.min_compression_size = Some(bytes);
+    }
+
+    /// Whether a file with automatic compression at `path` is stored without trial compression
+    /// because of its extension
+    pub(crate) fn skips_compression(&self, path: &str, flags: &DataFlags) -> bool {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        flags.is_automatic_compression()
+            && compression_heuristics::has_extension(name, &self.precompressed_extensions)
+    }
+
+    /// Records the MIME type of a file which has already been added, so readers don't need to
+    /// sniff its contents
+    pub fn set_content_type(&mut self, path: &str, content_type: &str) -> Result<(), PfaError> {
+        if content_type.len() > u8::MAX as usize {
+            return Err(PfaError::CustomError(format!(
+                "content type '{content_type}' is too long"
+            )));
+        }
+        self.find_file_mut(path, "set_content_type")?.content_type = Some(content_type.to_string());
+        Ok(())
+    }
+
+    /// Forgets the MIME type recorded for a file which has already been added, such as one
+    /// copied along with its metadata. It's still sniffed if
+    /// [content type sniffing](Self::set_sniff_content_types) is on.
+    pub fn clear_content_type(&mut self, path: &str) -> Result<(), PfaError> {
+        self.find_file_mut(path, "clear_content_type")?.content_type = None;
+        Ok(())
+    }
+
+    /// Records the id of the key a file which has already been added is stored encrypted with,
+    /// for files whose data was encrypted elsewhere, such as with [`Self::add_stored_file`]. Files
+    /// encrypted while the archive is built record the id of their key already.
+    pub fn set_key_id(&mut self, path: &str, key_id: KeyId) -> Result<(), PfaError> {
+        self.find_file_mut(path, "set_key_id")?.key_id = Some(key_id);
+        Ok(())
+    }
+
+    /// Records the cipher a file which has already been added is stored encrypted with, for
+    /// files whose data was encrypted elsewhere, such as with [`Self::add_stored_file`]. Files
+    /// encrypted while the archive is built record the cipher of their flags already.
+    pub fn set_cipher(&mut self, path: &str, cipher: Cipher) -> Result<(), PfaError> {
+        self.find_file_mut(path, "set_cipher")?.cipher = Some(cipher);
+        Ok(())
+    }
+
+    /// Records where a file which has already been added came from, see [`Provenance`]
+    pub fn set_provenance(&mut self, path: &str, provenance: Provenance) -> Result<(), PfaError> {
+        provenance.validate()?;
+        self.find_file_mut(path, "set_provenance")?.provenance = Some(provenance);
+        Ok(())
+    }
+
+    /// Records the transformations the contents of a file which has already been added went
+    /// through, see [`TransformChain`]. Their output hash is recorded from the file's contents
+    /// when it isn't set and the contents were added as bytes.
+    pub fn set_transforms(
+        &mut self,
+        path: &str,
+        mut transforms: TransformChain,
+    ) -> Result<(), PfaError> {
+        transforms.validate()?;
+        let file = self.find_file_mut(path, "set_transforms")?;
+        if let (None, PfaFileSource::Bytes(contents)) = (transforms.output_hash, &file.contents) {
+            transforms.output_hash = Some(*blake3::hash(contents).as_bytes());
+        }
+        file.transforms = Some(Box::new(transforms));
+        Ok(())
+    }
+
+    /// Records the source path and hash of every file added by [`Self::include_directory`] and
+    /// [`Self::include_directory_cached`] afterwards, along with the tool set by
+    /// [`Self::set_provenance_tool`]
+    pub fn set_record_provenance(&mut self, record: bool) {
+        self.record_provenance = record;
+    }
+
+    pub fn set_provenance_tool(&mut self, tool: &str) {
+        self.provenance_tool = Some(tool.to_string());
+    }
+
+    /// Leaves every file's provenance out of the archive, for shipping builds which shouldn't
+    /// reveal paths on the machine that built them
+    pub fn set_strip_provenance(&mut self, strip: bool) {
+        self.strip_provenance = strip;
+    }
+
+    /// Provenance recorded for a file read from `path` by [`Self::include_directory`]
+    pub(crate) fn record_source(
+        &mut self,
+        fpath: &str,
+        path: &Path,
+        hash: [u8; 32],
+    ) -> Result<(), PfaError> {
+        if !self.record_provenance {
+            return Ok(());
+        }
+        let mut provenance = Provenance::new()
+            .source_path(&path.to_string_lossy())
+            .source_hash(hash);
+        provenance.tool = self.provenance_tool.clone();
+        self.set_provenance(fpath, provenance)
+    }
+
+    /// Directories with too many entries are split into buckets, see [`DirectoryLimits`]
+    pub fn set_directory_limits(&mut self, limits: DirectoryLimits) {
+        self.directory_limits = limits;
+    }
+
+    /// Deepest directory nesting allowed, defaults to [`DEFAULT_DEPTH_LIMIT`]. Adding a path
+    /// nested deeper fails with [`PfaError::DepthLimitError`], as do builds of a tree which was
+    /// nested deeper before the limit was lowered.
+    pub fn set_depth_limit(&mut self, depth_limit: usize) {
+        self.depth_limit = depth_limit;
+    }
+
+    /// Directories of the files added so far which exceed the directory limits. These are also
+    /// written to the build log.
+    pub fn limit_warnings(&self) -> Vec<LimitWarning> {
+        self.directory_limits.check(&self.file_tree)
+    }
+
+    /// Hash of a file read from `path` to record as its provenance, None when provenance isn't
+    /// recorded. The file is read in chunks.
+    pub(crate) fn source_hash(&self, path: &Path) -> Result<Option<[u8; 32]>, PfaError> {
+        if !self.record_provenance {
+            return Ok(None);
+        }
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(std::fs::File::open(path)?)?;
+        Ok(Some(*hasher.finalize().as_bytes()))
+    }
+
     pub fn build(self) -> Result<Vec<u8>, PfaError> {
-        let writer = PfaWriter::new(&self.name, self.file_tree);
-        writer.generate()
+        self.into_writer()?.generate()
+    }
+
+    /// Builds the archive straight into `out` from its current position, instead of returning
+    /// it, so the finished archive isn't held in memory as well as the processed file data. The
+    /// processed data of every file is still held in memory until the archive is built, use a
+    /// [`PfaStreamingBuilder`](crate::writer::streaming_builder::PfaStreamingBuilder) to spill it
+    /// as files are added instead. Returns the number of bytes written.
+    pub fn build_into(self, mut out: impl Write + Seek) -> Result<u64, PfaError> {
+        let start = out.stream_position()?;
+        self.into_writer()?.generate_into(&mut out)?;
+        out.flush()?;
+        Ok(out.stream_position()? - start)
+    }
+
+    pub(crate) fn into_writer(mut self) -> Result<PfaWriter, PfaError> {
+        if let Some(key) = self.encryption_key {
+            Self::encrypt_all(&mut self.file_tree, key)?;
+        }
+        if let Some(alignment) = self.store_only {
+            if alignment == 0 {
+                return Err(PfaError::CustomError(
+                    "store only alignment must be greater than 0".into(),
+                ));
+            }
+            Self::store_all(&mut self.file_tree)?;
+        }
+        let mut writer = PfaWriter::new(&self.name, self.archive_id, self.file_tree);
+        writer.set_version(self.version);
+        writer.set_catalog_layout(self.catalog_layout);
+        writer.set_entry_hashes(self.entry_hashes || self.store_only.is_some());
+        writer.set_data_alignment(self.store_only);
+        writer.set_extra_data(self.extra_data);
+        writer.set_compress_extra_data(self.compress_extra_data);
+        writer.set_solid_blocks(self.solid_blocks);
+        writer.set_name_encoding(self.name_encoding);
+        writer.set_threads(self.threads);
+        #[cfg(feature = "rayon")]
+        writer.set_thread_pool(self.thread_pool);
+        writer.set_reserved_space(self.reserved_space.0, self.reserved_space.1);
+        writer.set_variants(self.variants.into_iter().collect());
+        writer.set_build_log(self.build_log);
+        writer.set_directory_limits(self.directory_limits);
+        writer.set_sniff_content_types(self.sniff_content_types);
+        writer.set_strip_provenance(self.strip_provenance);
+        writer.set_depth_limit(self.depth_limit);
+        writer.set_generation(self.generation);
+        Ok(writer)
     }
 
     fn get_directory_index_by_name(name: &str, path: &PfaPath) -> Option<usize> {
@@ -71,9 +688,20 @@ impl PfaBuilder {
     fn create(
         &mut self,
         path: &PfaBuilderPath,
-        data: Option<Vec<u8>>,
+        data: Option<PfaFileSource>,
         flags: DataFlags,
     ) -> Result<(), PfaError> {
+        let flags = match (data.as_ref(), path) {
+            (
+                Some(PfaFileSource::Bytes(_) | PfaFileSource::Reader(_) | PfaFileSource::Path(_)),
+                PfaBuilderPath::File { parts, name },
+            ) => self.resolve_flags(&format!("{}/{name}", parts.join("/")), flags),
+            _ => flags,
+        };
+        let skip_compression = match path {
+            PfaBuilderPath::File { name, .. } => self.skips_compression(name, &flags),
+            PfaBuilderPath::Directory(_) => false,
+        };
         let mut parts = VecDeque::from(
             match path {
                 PfaBuilderPath::File { parts, .. } => parts,
@@ -83,7 +711,17 @@ impl PfaBuilder {
         );
 
         parts.pop_front(); // pop root
+        if parts.iter().filter(|x| !x.is_empty()).count() > self.depth_limit {
+            return Err(PfaError::DepthLimitError {
+                path: match path {
+                    PfaBuilderPath::Directory(parts) => parts.join("/"),
+                    PfaBuilderPath::File { parts, name } => format!("{}/{name}", parts.join("/")),
+                },
+                limit: self.depth_limit,
+            });
+        }
 
+        let duplicate_policy = self.duplicate_policy;
         let mut working_path = &mut self.file_tree;
         for part in parts.iter() {
             let index = Self::get_directory_index_by_name(part, working_path)
@@ -111,10 +749,27 @@ impl PfaBuilder {
             };
 
             if let PfaPath::Directory(dir) = working_path {
-                dir.contents.push(PfaPath::File(
-                    PfaFile::new(name.to_owned(), data, flags)
-                        .ok_or(PfaError::CustomError("file name too large".into()))?,
-                ));
+                let mut file = PfaFile::from_source(name.to_owned(), data, flags)
+                    .ok_or(PfaError::CustomError("file name too large".into()))?;
+                if skip_compression {
+                    telemetry::increment(telemetry::EXTENSION_SKIPS, 1);
+                    file.compression_skipped = true;
+                }
+                let existing = dir
+                    .contents
+                    .iter()
+                    .position(|x| matches!(x, PfaPath::File(x) if x.name == *name));
+                match (existing, duplicate_policy) {
+                    (None, _) => dir.contents.push(PfaPath::File(Box::new(file))),
+                    (Some(_), DuplicatePolicy::Error) => {
+                        let parts = parts.iter().map(|x| format!("/{x}")).collect::<String>();
+                        return Err(PfaError::DuplicatePath(format!("{parts}/{name}")));
+                    }
+                    (Some(index), DuplicatePolicy::Overwrite) => {
+                        dir.contents[index] = PfaPath::File(Box::new(file))
+                    }
+                    (Some(_), DuplicatePolicy::KeepFirst) => {}
+                }
             } else {
                 return Err(PfaError::CustomError(
                     "attempt to create file in non directory".into(),
@@ -150,7 +805,7 @@ impl PfaBuilder {
         let path = path.to_string();
         let path = path.into();
         if let PfaBuilderPath::File { .. } = path {
-            self.create(&path, Some(content), flags)?;
+            self.create(&path, Some(PfaFileSource::Bytes(content)), flags)?;
             return Ok(());
         }
 
@@ -159,37 +814,464 @@ impl PfaBuilder {
         ))
     }
 
-    pub fn include_directory(&mut self, path: &str, flags: DataFlags) -> Result<(), PfaError> {
-        let opath = path;
-        let path = std::path::Path::new(opath);
+    /// Adds a file whose contents are read from `reader` when the archive is built, such as a
+    /// socket, pipe or large file. Files which are stored uncompressed and are either unencrypted
+    /// or encrypted in frames are processed a chunk at a time as they're read, others are read
+    /// whole first since compression and whole file encryption need all of the contents.
+    pub fn add_file_from_reader(
+        &mut self,
+        path: &str,
+        reader: impl Read + Send + 'static,
+        flags: DataFlags,
+    ) -> Result<(), PfaError> {
+        let path = path.to_string().into();
+        let PfaBuilderPath::File { .. } = path else {
+            return Err(PfaError::CustomError(
+                "called add_file_from_reader but provided a directory".into(),
+            ));
+        };
+        self.create(&path, Some(PfaFileSource::Reader(Box::new(reader))), flags)
+    }
+
+    /// Adds the file at `fs_path` on disk as `archive_path`. The file is only read when the
+    /// archive is built, so it doesn't need to fit in memory along with every other file, and it
+    /// is processed as it's read whenever [`Self::add_file_from_reader`] would be.
+    pub fn add_file_from_path(
+        &mut self,
+        archive_path: &str,
+        fs_path: impl AsRef<Path>,
+        flags: DataFlags,
+    ) -> Result<(), PfaError> {
+        let path = archive_path.to_string().into();
+        let PfaBuilderPath::File { .. } = path else {
+            return Err(PfaError::CustomError(
+                "called add_file_from_path but provided a directory".into(),
+            ));
+        };
+        let source = PfaFileSource::Path(fs_path.as_ref().to_path_buf());
+        self.create(&path, Some(source), flags)
+    }
+
+    /// Adds a platform or quality specific variant of a file, stored as `{path}#{variant}`.
+    /// Readers pick a variant based on their preferences, falling back to the file added with
+    /// [`Self::add_file`] at the same path, so content shared between platforms is only stored
+    /// once.
+    pub fn add_file_variant(
+        &mut self,
+        path: &str,
+        variant: &str,
+        content: Vec<u8>,
+        flags: DataFlags,
+    ) -> Result<(), PfaError> {
+        if variant.is_empty() || variant.contains(['/', '#']) {
+            return Err(PfaError::CustomError(format!(
+                "invalid variant name '{variant}'"
+            )));
+        }
+        if path.ends_with('/') {
+            return Err(PfaError::CustomError(
+                "called add_file_variant but provided a directory".into(),
+            ));
+        }
+
+        self.add_file(&format!("{path}#{variant}"), content, flags)?;
+        self.variants.insert(variant.to_string());
+        Ok(())
+    }
+
+    /// Tags a file which has already been added with a quality tier
+    pub fn set_tier(&mut self, path: &str, tier: Tier) -> Result<(), PfaError> {
+        self.find_file_mut(path, "set_tier")?.tier = Some(tier);
+        Ok(())
+    }
+
+    /// Marks a file which has already been added as not benefiting from compression, which is
+    /// recorded in the archive (see
+    /// [`PfaReader::is_compression_skipped`](crate::reader::PfaReader::is_compression_skipped)).
+    /// Files with automatic compression are stored uncompressed without trying to compress them.
+    pub fn set_compression_skipped(&mut self, path: &str) -> Result<(), PfaError> {
+        self.find_file_mut(path, "set_compression_skipped")?
+            .compression_skipped = true;
+        Ok(())
+    }
+
+    /// Records the size of the contents of a file which was added already processed, such as
+    /// with [`Self::add_stored_file`], so readers know it without decoding the data (see
+    /// [`PfaReader::stats`](crate::reader::PfaReader::stats)). The sizes of other files are
+    /// recorded when they're processed.
+    pub fn set_original_size(&mut self, path: &str, size: u64) -> Result<(), PfaError> {
+        self.find_file_mut(path, "set_original_size")?.original_size = Some(size);
+        Ok(())
+    }
+
+    fn find_file_mut(&mut self, path: &str, method: &str) -> Result<&mut PfaFile, PfaError> {
+        let not_found = || PfaError::CustomError(format!("called {method} but '{path}' not found"));
+        let PfaBuilderPath::File { parts, name } = path.to_string().into() else {
+            return Err(PfaError::CustomError(format!(
+                "called {method} but provided a directory"
+            )));
+        };
+
+        let mut working_path = &mut self.file_tree;
+        for part in parts.iter().skip(1) {
+            let index =
+                Self::get_directory_index_by_name(part, working_path).ok_or_else(not_found)?;
+            working_path =
+                Self::get_directory_from_index(working_path, index).ok_or_else(not_found)?;
+        }
+        let PfaPath::Directory(dir) = working_path else {
+            return Err(not_found());
+        };
+        dir.contents
+            .iter_mut()
+            .find_map(|x| match x {
+                PfaPath::File(file) if file.name == name => Some(&mut **file),
+                _ => None,
+            })
+            .ok_or_else(not_found)
+    }
+
+    /// Removes a file which has already been added, or a directory and everything in it when
+    /// `path` ends with '/'
+    pub fn remove(&mut self, path: &str) -> Result<(), PfaError> {
+        let (parents, name, is_dir) = Self::split_path(path, "remove")?;
+        let (dir, index) = self.find_entry(&parents, &name, is_dir).ok_or_else(|| {
+            PfaError::CustomError(format!("called remove but '{path}' not found"))
+        })?;
+        dir.contents.remove(index);
+        Ok(())
+    }
+
+    /// Moves a file or directory which has already been added to `new`, creating its parent
+    /// directories. Files keep their contents, flags and metadata. Both paths must end with '/'
+    /// for directories.
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<(), PfaError> {
+        let (old_parents, old_name, is_dir) = Self::split_path(old, "rename")?;
+        let (new_parents, new_name, new_is_dir) = Self::split_path(new, "rename")?;
+        if is_dir != new_is_dir {
+            return Err(PfaError::CustomError(format!(
+                "called rename with '{old}' and '{new}', which aren't both files or directories"
+            )));
+        }
+        let into_itself = new_parents.starts_with(&old_parents)
+            && new_parents.get(old_parents.len()) == Some(&old_name);
+        if is_dir && into_itself {
+            return Err(PfaError::CustomError(format!(
+                "can't move '{old}' into itself"
+            )));
+        }
+        if self.find_entry(&new_parents, &new_name, is_dir).is_some() {
+            return Err(PfaError::DuplicatePath(new.to_string()));
+        }
+        if self.find_entry(&old_parents, &old_name, is_dir).is_none() {
+            return Err(PfaError::CustomError(format!(
+                "called rename but '{old}' not found"
+            )));
+        }
+        if !new_parents.is_empty() {
+            self.add_directory(&format!("/{}/", new_parents.join("/")))?;
+        }
+
+        let not_found = || PfaError::CustomError("could not get directory".into());
+        let (dir, index) = self
+            .find_entry(&old_parents, &old_name, is_dir)
+            .ok_or_else(not_found)?;
+        let mut entry = dir.contents.remove(index);
+        match &mut entry {
+            PfaPath::File(file) => file.name = new_name,
+            PfaPath::Directory(dir) => dir.name = new_name,
+        }
+        let PfaPath::Directory(dir) = self.directory_mut(&new_parents).ok_or_else(not_found)?
+        else {
+            return Err(not_found());
+        };
+        dir.contents.push(entry);
+        Ok(())
+    }
+
+    /// Parent directories and name of a path, and whether it's a directory
+    fn split_path(path: &str, method: &str) -> Result<(Vec<String>, String, bool), PfaError> {
+        let mut parts = normalize_path(path)
+            .split('/')
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        let name = parts.pop().ok_or_else(|| {
+            PfaError::CustomError(format!("called {method} on the root directory"))
+        })?;
+        Ok((parts, name, path.ends_with(['/', '\\'])))
+    }
+
+    fn directory_mut(&mut self, parts: &[String]) -> Option<&mut PfaPath> {
+        let mut working_path = &mut self.file_tree;
+        for part in parts {
+            let index = Self::get_directory_index_by_name(part, working_path)?;
+            working_path = Self::get_directory_from_index(working_path, index)?;
+        }
+        Some(working_path)
+    }
+
+    /// The directory holding an entry, and the entry's position in it
+    fn find_entry(
+        &mut self,
+        parents: &[String],
+        name: &str,
+        is_dir: bool,
+    ) -> Option<(&mut PfaDirectory, usize)> {
+        let PfaPath::Directory(dir) = self.directory_mut(parents)? else {
+            return None;
+        };
+        let index = dir.contents.iter().position(|x| match x {
+            PfaPath::File(file) => !is_dir && file.name == name,
+            PfaPath::Directory(dir) => is_dir && dir.name == name,
+        })?;
+        Some((dir, index))
+    }
+
+    /// Whether a file has been added at `path`
+    pub(crate) fn contains_file(&mut self, path: &str) -> bool {
+        self.find_file_mut(path, "contains_file").is_ok()
+    }
+
+    pub(crate) fn duplicate_policy(&self) -> DuplicatePolicy {
+        self.duplicate_policy
+    }
+
+    pub(crate) fn add_variant_names(&mut self, variants: impl IntoIterator<Item = String>) {
+        self.variants.extend(variants);
+    }
+
+    /// Adds files whose contents are read from the given readers when the archive is built, so
+    /// generated content doesn't need to be collected up front
+    pub fn add_entries(
+        &mut self,
+        entries: impl IntoIterator<Item = (String, Box<dyn Read + Send>, DataFlags)>,
+    ) -> Result<(), PfaError> {
+        for (path, reader, flags) in entries {
+            let path = path.into();
+            let PfaBuilderPath::File { .. } = path else {
+                return Err(PfaError::CustomError(
+                    "called add_entries but provided a directory".into(),
+                ));
+            };
+            self.create(&path, Some(PfaFileSource::Reader(reader)), flags)?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds a file whose processed data was written to the spilled part of the data section
+    pub(crate) fn add_spilled_file(
+        &mut self,
+        path: &str,
+        source: PfaFileSource,
+    ) -> Result<(), PfaError> {
+        debug_assert!(matches!(source, PfaFileSource::Spilled { .. }));
+        self.create(&path.to_string().into(), Some(source), DataFlags::default())
+    }
+
+    /// Adds a file whose data has already been processed, such as one copied from another
+    /// archive with [`PfaReader::get_stored_file`](crate::reader::PfaReader::get_stored_file)
+    pub fn add_stored_file(
+        &mut self,
+        path: &str,
+        contents: Vec<u8>,
+        flags: u8,
+    ) -> Result<(), PfaError> {
+        let path = path.to_string().into();
+        let PfaBuilderPath::File { .. } = path else {
+            return Err(PfaError::CustomError(
+                "called add_stored_file but provided a directory".into(),
+            ));
+        };
+        self.create(
+            &path,
+            Some(PfaFileSource::Stored { contents, flags }),
+            DataFlags::default(),
+        )
+    }
+
+    /// Path in the archive of a file or directory found in the directory at `root`, relative to
+    /// the root and separated by forward slashes on every platform. Names containing a separator
+    /// (such as a backslash in a Unix file name) are rejected, since they would be split into
+    /// directories.
+    fn archive_path(root: &Path, path: &Path) -> Result<String, PfaError> {
+        let relative = path.strip_prefix(root).map_err(|_| {
+            PfaError::CustomError(format!(
+                "'{}' is outside of '{}'",
+                path.display(),
+                root.display()
+            ))
+        })?;
+        let mut parts = vec![];
+        for component in relative.components() {
+            let name = component
+                .as_os_str()
+                .to_str()
+                .ok_or(PfaError::CustomError("Invalid file".into()))?;
+            if name.contains(['/', '\\']) {
+                return Err(PfaError::CustomError(format!(
+                    "file name '{name}' in '{}' contains a path separator",
+                    root.display()
+                )));
+            }
+            parts.push(name);
+        }
+        Ok(parts.join("/"))
+    }
+
+    /// Files in the directory which pass the [directory filter](Self::set_directory_filter),
+    /// with their archive paths
+    pub(crate) fn walk_directory(&self, path: &str) -> Result<Vec<(String, PathBuf)>, PfaError> {
+        let path = Path::new(path);
         if !path.is_dir() {
             return Err(PfaError::CustomError(
                 "called include_directory but provided a non-directory".into(),
             ));
         }
 
-        for f in ignore::Walk::new(path).flatten() {
+        let filter = std::sync::Arc::new(self.directory_filter.compile()?);
+        let walk_filter = filter.clone();
+        let walk_root = path.to_path_buf();
+        let walk = ignore::WalkBuilder::new(path)
+            .filter_entry(move |entry| {
+                entry.path() == walk_root
+                    || !entry.path().is_dir()
+                    // invalid names are reported when the files are added
+                    || Self::archive_path(&walk_root, entry.path())
+                        .map_or(true, |x| walk_filter.allows_directory(&x))
+            })
+            .build();
+
+        let mut files = vec![];
+        for f in walk.flatten() {
             if f.path().is_dir() {
                 continue;
             }
 
-            let mut fpath = f
-                .path()
-                .to_str()
-                .ok_or(PfaError::CustomError("Invalid file".into()))?
-                .to_string()
-                .replace('\\', "/")
-                .replace("//", "/");
-            if fpath.starts_with(opath) {
-                fpath = fpath.replacen(opath, "", 1);
+            let fpath = Self::archive_path(path, f.path())?;
+            if filter.allows_file(&fpath) {
+                files.push((fpath, f.into_path()));
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Adds every file in a directory, which are only read when the archive is built (see
+    /// [`Self::add_file_from_path`]). Files are also hashed when they're added if provenance is
+    /// recorded.
+    pub fn include_directory(&mut self, path: &str, flags: DataFlags) -> Result<(), PfaError> {
+        for (fpath, path) in self.walk_directory(path)? {
+            let hash = self.source_hash(&path)?;
+            self.add_file_from_path(&fpath, &path, flags.clone())?;
+            if let Some(hash) = hash {
+                self.record_source(&fpath, &path, hash)?;
             }
-            self.add_file(
-                &fpath,
-                std::fs::read(f.path()).map_err(PfaError::IOError)?,
-                flags.clone(),
-            )?;
         }
 
         Ok(())
     }
+
+    /// Like [`Self::include_directory`], but files which are unchanged since `previous` was
+    /// built are copied from it without being processed again, except for files in solid blocks
+    /// which are grouped again. `cache` must be the cache returned when `previous` was built,
+    /// otherwise it is ignored. Returns the cache for this archive, which is only valid if the
+    /// archive id isn't changed afterwards.
+    pub fn include_directory_cached(
+        &mut self,
+        path: &str,
+        flags: DataFlags,
+        mut previous: Option<&mut PfaReader<impl Read + Seek>>,
+        cache: &BuildCache,
+    ) -> Result<(BuildCache, BuildCacheStats), PfaError> {
+        let cache_valid = previous.as_ref().is_some_and(|x| {
+            x.get_archive_id().is_some() && x.get_archive_id() == cache.archive_id()
+        });
+
+        let mut new_cache = BuildCache::new(self.archive_id);
+        let mut stats = BuildCacheStats::default();
+        for (fpath, path) in self.walk_directory(path)? {
+            let flags = self.resolve_flags(&fpath, flags.clone());
+            let fingerprint = flags.fingerprint();
+            let key_id = flags.encryption_key_id();
+            let cipher = flags.encryption_cipher();
+            let metadata = std::fs::metadata(&path)?;
+            let mtime = BuildCache::mtime(&metadata);
+            let size = metadata.len();
+
+            let cached = cache
+                .get(&fpath)
+                .filter(|x| cache_valid && x.size == size && x.flags_fingerprint == fingerprint);
+            let (contents, hash) = match cached {
+                Some(entry) if entry.mtime == mtime => (None, entry.hash),
+                // the file may have been touched without its contents changing
+                _ => {
+                    let contents = std::fs::read(&path)?;
+                    let hash = *blake3::hash(&contents).as_bytes();
+                    (Some(contents), hash)
+                }
+            };
+
+            let unchanged = cached.is_some_and(|x| x.hash == hash);
+            let stored = match (unchanged, previous.as_mut()) {
+                (true, Some(previous)) if !previous.is_in_solid_block(&fpath[..])? => {
+                    match previous.get_stored_file(&fpath[..])? {
+                        Some(stored) => Some((
+                            stored,
+                            previous.is_compression_skipped(&fpath[..])?,
+                            previous.get_content_type(&fpath[..])?,
+                            previous
+                                .get_file_info(&fpath[..])?
+                                .and_then(|x| x.contents_size),
+                        )),
+                        None => None,
+                    }
+                }
+                _ => None,
+            };
+            if let Some((stored, compression_skipped, content_type, original_size)) = stored {
+                let flags = stored.get_flags();
+                self.add_stored_file(&fpath, stored.into_contents(), flags)?;
+                if compression_skipped {
+                    self.set_compression_skipped(&fpath)?;
+                }
+                if let Some(original_size) = original_size {
+                    self.set_original_size(&fpath, original_size)?;
+                }
+                if let Some(content_type) = content_type {
+                    self.set_content_type(&fpath, &content_type)?;
+                }
+                if let Some(key_id) = key_id {
+                    self.set_key_id(&fpath, key_id)?;
+                }
+                if let Some(cipher) = cipher {
+                    self.set_cipher(&fpath, cipher)?;
+                }
+                stats.reused += 1;
+                telemetry::increment(telemetry::BUILD_CACHE_HITS, 1);
+            } else {
+                let contents = match contents {
+                    Some(contents) => contents,
+                    None => std::fs::read(&path)?,
+                };
+                self.add_file(&fpath, contents, flags)?;
+                stats.processed += 1;
+                telemetry::increment(telemetry::BUILD_CACHE_MISSES, 1);
+            }
+            self.record_source(&fpath, &path, hash)?;
+
+            new_cache.insert(
+                fpath,
+                BuildCacheEntry {
+                    mtime,
+                    size,
+                    hash,
+                    flags_fingerprint: fingerprint,
+                },
+            );
+        }
+
+        Ok((new_cache, stats))
+    }
 }
@@ -0,0 +1,438 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::{
+    reader::PfaReader,
+    shared::{
+        data_flags::{DataCompressionType, DataFlags},
+        entry_hash,
+        header_fields::HeaderFields,
+        sharding, CatalogLayout, Generation, ReservedSpace,
+    },
+    writer::pfa_writer::{child_slots, to_u64, write_reserved_slot, PfaCatalogEntry},
+    PfaError,
+};
+
+/// Adds files to a built archive in place, using the catalog slots and data padding reserved with
+/// [`PfaBuilder::set_reserved_space`](crate::builder::PfaBuilder::set_reserved_space). Reserved
+/// data is used first, once it runs out file data is appended to the end of the archive.
+///
+/// Reserved slots come after the rest of the catalog, so when a directory's children don't end
+/// right before the free slots, its contents are copied into the reserved slots along with the new
+/// file.
+pub struct PfaEditor<T: Read + Write + Seek> {
+    inner: T,
+    version: u8,
+    layout: CatalogLayout,
+    fields: HeaderFields,
+    fields_idx: u64,
+    catalog_idx: u64,
+    data_idx: u64,
+    entries: Vec<PfaCatalogEntry>,
+}
+
+impl<T: Read + Write + Seek> PfaEditor<T> {
+    pub fn new(mut inner: T) -> Result<Self, PfaError> {
+        let reader = PfaReader::new(&mut inner)?;
+        if reader.get_version() < 2 {
+            return Err(PfaError::CustomError(
+                "archives older than version 2 can't be edited".into(),
+            ));
+        }
+        let version = reader.get_version();
+        let fields = reader.get_header_fields().clone();
+        let layout = reader.get_catalog_layout();
+        let catalog_idx = reader.catalog_idx as u64;
+        let data_idx = reader.data_idx as u64;
+        let entries = reader
+            .get_raw_entries()
+            .enumerate()
+            .map(|(i, entry)| {
+                let (name, flags, size, offset) = entry?;
+                Ok(PfaCatalogEntry {
+                    name,
+                    flags,
+                    size,
+                    offset,
+                    hash: fields
+                        .entry_hashes
+                        .as_ref()
+                        .map(|x| x[i])
+                        .unwrap_or_default(),
+                })
+            })
+            .collect::<Result<Vec<_>, PfaError>>()?;
+        drop(reader);
+
+        // the header is rewritten in place, which is only possible if writing the fields again
+        // results in the same bytes
+        let mut encoded = vec![];
+        fields.write(&mut encoded)?;
+        let fields_idx = catalog_idx
+            .checked_sub(encoded.len() as u64)
+            .ok_or(PfaError::CustomError("invalid header".into()))?;
+        let mut original = vec![0; encoded.len()];
+        inner.seek(SeekFrom::Start(fields_idx))?;
+        inner.read_exact(&mut original)?;
+        if original != encoded {
+            return Err(PfaError::CustomError(
+                "archive header can't be rewritten in place".into(),
+            ));
+        }
+
+        Ok(Self {
+            inner,
+            version,
+            layout,
+            fields,
+            fields_idx,
+            catalog_idx,
+            data_idx,
+            entries,
+        })
+    }
+
+    pub fn get_reserved_space(&self) -> Option<ReservedSpace> {
+        self.fields.reserved_space
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Adds a file to an existing directory. The name can be at most 32 bytes long, since it has
+    /// to fit in a reserved slot. Encrypted files can't be added, since the header can't grow to
+    /// record the id of their key. In store only archives the file is stored uncompressed and
+    /// aligned, and adding it fails if it would have error correction. Version 2
+    /// archives can't hold files compressed with codecs other than LZ4, see
+    /// [`PfaBuilder::set_version`](crate::builder::PfaBuilder::set_version).
+    ///
+    /// Archives with a generation move to the next one, which is marked as in progress until the
+    /// file has been added, so readers following generations don't read it half written.
+    pub fn add_file(
+        &mut self,
+        path: &str,
+        contents: Vec<u8>,
+        flags: DataFlags,
+    ) -> Result<(), PfaError> {
+        let Some(generation) = self.fields.generation else {
+            return self.add_file_in_place(path, contents, flags);
+        };
+        let number = generation
+            .number
+            .checked_add(1)
+            .ok_or(PfaError::WriterOverflowError("generation"))?;
+        self.write_generation(Generation {
+            number,
+            timestamp: 0,
+        })?;
+        self.fields.generation = Some(Generation::now(number));
+        let added = self.add_file_in_place(path, contents, flags);
+        if added.is_err() {
+            self.fields.generation = Some(generation);
+            self.write_generation(generation)?;
+        }
+        added
+    }
+
+    fn add_file_in_place(
+        &mut self,
+        path: &str,
+        contents: Vec<u8>,
+        flags: DataFlags,
+    ) -> Result<(), PfaError> {
+        let mut reserved = self.fields.reserved_space.ok_or(PfaError::CustomError(
+            "archive has no reserved space".into(),
+        ))?;
+
+        let mut parts = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+        let name = parts
+            .pop()
+            .filter(|x| !x.is_empty())
+            .ok_or(PfaError::MalformedPathError)?;
+        if !self.entries.first().is_some_and(|x| x.name.ends_with('/')) {
+            return Err(PfaError::CustomError(
+                "archive root is not a directory".into(),
+            ));
+        }
+
+        let encoding = self.fields.name_encoding;
+        let archive_id = self.fields.archive_id;
+        let mut ancestors = vec![0];
+        let mut parent = 0;
+        let mut prefix = String::from("/");
+        for part in parts {
+            prefix = format!("{prefix}{part}/");
+            let dir_name = encoding.encode(archive_id, &prefix, part);
+            let bucket = self.bucket_for(parent, &dir_name)?;
+            if bucket != parent {
+                ancestors.push(bucket);
+            }
+            parent = self
+                .find_child(bucket, &format!("{dir_name}/"))?
+                .ok_or_else(|| PfaError::CustomError(format!("directory '{part}' not found")))?;
+            ancestors.push(parent);
+        }
+
+        let name = encoding.encode(archive_id, &format!("{prefix}{name}"), name);
+        let name = &name[..];
+        let bucket = self.bucket_for(parent, name)?;
+        if bucket != parent {
+            ancestors.push(bucket);
+            parent = bucket;
+        }
+        if name.len() > CatalogLayout::FIXED_NAME_SIZE {
+            return Err(PfaError::CustomError(format!(
+                "name '{name}' does not fit in a reserved slot"
+            )));
+        }
+        if self.find_child(parent, name)?.is_some() {
+            return Err(PfaError::CustomError(format!("'{path}' already exists")));
+        }
+
+        // the header can't grow to record the id of its key, or its cipher
+        if flags.has_encryption_key() {
+            return Err(PfaError::CustomError(format!(
+                "'{path}' can't be encrypted when it's added in place"
+            )));
+        }
+        let alignment = self.fields.data_alignment;
+        let flags = match alignment {
+            Some(_) => flags.compression_type(DataCompressionType::Forced(false)),
+            None => flags,
+        };
+        let (data, stored_flags) = flags.process_content_and_generate_flags(&contents)?;
+        if self.version < 3 && DataFlags::needs_version_3(stored_flags) {
+            let codec = DataFlags::codec(stored_flags).map_or("", |x| x.name());
+            return Err(PfaError::UnsupportedByVersion {
+                version: self.version,
+                features: vec![format!("{codec} compression, used by '{path}'")],
+            });
+        }
+        if alignment.is_some() && stored_flags != 0 {
+            return Err(PfaError::CustomError(format!(
+                "'{path}' isn't stored as is, which store only archives require"
+            )));
+        }
+        let size = to_u64(data.len(), "data size")?;
+        let file = PfaCatalogEntry {
+            name: name.to_string(),
+            flags: stored_flags,
+            size,
+            offset: 0,
+            hash: entry_hash::file_hash(name, stored_flags, &data),
+        };
+
+        let first_free = usize::try_from(reserved.first_free_slot)
+            .map_err(|_| PfaError::CustomError("invalid reserved space".into()))?;
+        let mut slots = vec![];
+        let mut moved = vec![];
+        let children = child_slots(parent, &self.entries[parent])?;
+        let (parent_offset, file_slot) = if children.end == first_free {
+            slots.push(file);
+            (self.entries[parent].offset, first_free)
+        } else {
+            let start =
+                self.copy_children(parent, first_free, Some(file), &mut slots, &mut moved)?;
+            (
+                to_u64(start - parent, "catalog slice index")?,
+                start + children.len(),
+            )
+        };
+        if to_u64(slots.len(), "reserved catalog slots")? > reserved.free_slots
+            || first_free + slots.len() > self.entries.len()
+        {
+            return Err(PfaError::CustomError(
+                "not enough reserved catalog slots".into(),
+            ));
+        }
+
+        // offset of the first position at or after `offset` which is aligned within the archive
+        let align = |offset: u64| -> Result<u64, PfaError> {
+            let alignment = u64::from(alignment.unwrap_or(1));
+            Ok((self.data_idx + offset)
+                .checked_next_multiple_of(alignment)
+                .ok_or(PfaError::WriterOverflowError("aligned data"))?
+                - self.data_idx)
+        };
+        let aligned = align(reserved.data_offset)?;
+        let used_data = aligned - reserved.data_offset + size;
+        let offset = if used_data <= reserved.free_data {
+            reserved.data_offset += used_data;
+            reserved.free_data -= used_data;
+            aligned
+        } else {
+            let end = self.inner.seek(SeekFrom::End(0))?;
+            align(end - self.data_idx)?
+        };
+        slots[file_slot - first_free].offset = offset;
+        self.inner.seek(SeekFrom::Start(self.data_idx + offset))?;
+        self.inner.write_all(&data)?;
+
+        let used = first_free..first_free + slots.len();
+        for (slot, entry) in used.clone().zip(slots) {
+            self.entries[slot] = entry;
+        }
+        self.entries[parent].offset = parent_offset;
+        self.entries[parent].size += 1;
+        reserved.first_free_slot += to_u64(used.len(), "reserved catalog slots")?;
+        reserved.free_slots -= to_u64(used.len(), "reserved catalog slots")?;
+        self.fields.reserved_space = Some(reserved);
+
+        // the header can't grow, so the size of the added file isn't recorded
+        self.fields
+            .remap_entries(&moved.into_iter().collect::<HashMap<_, _>>())?;
+
+        if self.fields.entry_hashes.is_some() {
+            for &dir in ancestors.iter().rev() {
+                let children = child_slots(dir, &self.entries[dir])?;
+                let name = self.entries[dir].name.trim_end_matches('/');
+                let hashes = self.entries[children].iter().map(|x| &x.hash);
+                self.entries[dir].hash = entry_hash::directory_hash(name, hashes);
+            }
+            self.fields.entry_hashes = Some(self.entries.iter().map(|x| x.hash).collect());
+        }
+
+        for slot in used {
+            self.inner
+                .seek(SeekFrom::Start(self.entry_position(slot)?))?;
+            write_reserved_slot(&mut self.inner, &self.entries[slot], self.layout)?;
+        }
+        self.write_slice(parent)?;
+        self.write_fields()?;
+        self.inner.flush()?;
+
+        Ok(())
+    }
+
+    /// The bucket of a sharded directory which holds the entry stored as `name` (without the
+    /// trailing '/'), or the directory itself when it isn't sharded
+    fn bucket_for(&self, dir: usize, name: &str) -> Result<usize, PfaError> {
+        let sharded = u32::try_from(dir)
+            .is_ok_and(|x| self.fields.sharded_directories.binary_search(&x).is_ok());
+        if !sharded {
+            return Ok(dir);
+        }
+        let children = child_slots(dir, &self.entries[dir])?;
+        let bucket = sharding::shard_bucket(name, to_u64(children.len(), "directory size")?);
+        let bucket = children.start + bucket as usize;
+        if !self
+            .entries
+            .get(bucket)
+            .is_some_and(|x| x.name.ends_with('/'))
+        {
+            return Err(PfaError::CustomError("bucket is not a directory".into()));
+        }
+        Ok(bucket)
+    }
+
+    fn find_child(&self, dir: usize, name: &str) -> Result<Option<usize>, PfaError> {
+        let children = child_slots(dir, &self.entries[dir])?;
+        let entries = self
+            .entries
+            .get(children.clone())
+            .ok_or(PfaError::CustomError("catalog slice out of bounds".into()))?;
+        Ok(entries
+            .iter()
+            .zip(children)
+            .find(|(x, _)| x.name == name)
+            .map(|(_, index)| index))
+    }
+
+    /// Copies the children of a directory, and everything below them, to the end of `slots`
+    /// (which starts at entry index `base`). Returns the index of the first copied child.
+    fn copy_children(
+        &self,
+        dir: usize,
+        base: usize,
+        extra: Option<PfaCatalogEntry>,
+        slots: &mut Vec<PfaCatalogEntry>,
+        moved: &mut Vec<(usize, usize)>,
+    ) -> Result<usize, PfaError> {
+        // directories still to copy, with the index of their own copy
+        let mut stack: Vec<(usize, Option<usize>, _)> = vec![(dir, None, extra)];
+        let mut top = 0;
+        while let Some((dir, copy, extra)) = stack.pop() {
+            let children = child_slots(dir, &self.entries[dir])?;
+            let first = base + slots.len();
+            for (old, new) in children.clone().zip(first..) {
+                let entry = self
+                    .entries
+                    .get(old)
+                    .ok_or(PfaError::CustomError("catalog slice out of bounds".into()))?;
+                slots.push(entry.clone());
+                moved.push((old, new));
+            }
+            slots.extend(extra);
+
+            match copy {
+                Some(new) => slots[new - base].offset = to_u64(first - new, "catalog slice index")?,
+                None => top = first,
+            }
+            for (old, new) in children.clone().zip(first..first + children.len()).rev() {
+                if self.entries[old].name.ends_with('/') {
+                    stack.push((old, Some(new), None));
+                }
+            }
+        }
+
+        Ok(top)
+    }
+
+    /// Position of an entry in the archive. Entries in the reserved region are all the same size,
+    /// while the size of the others depends on their name.
+    fn entry_position(&self, index: usize) -> Result<u64, PfaError> {
+        let slots_start = self
+            .fields
+            .reserved_space
+            .and_then(|x| usize::try_from(x.slots_start).ok())
+            .unwrap_or(self.entries.len());
+        let before = self.entries[..index.min(slots_start)]
+            .iter()
+            .map(|x| self.layout.entry_size(&x.name))
+            .sum::<usize>()
+            + index.saturating_sub(slots_start) * self.layout.reserved_slot_size();
+        Ok(self.catalog_idx + 8 + to_u64(before, "catalog size")?)
+    }
+
+    /// Rewrites the flags, size and offset of an entry
+    fn write_slice(&mut self, index: usize) -> Result<(), PfaError> {
+        let end = self.entry_position(index + 1)?;
+        self.inner
+            .seek(SeekFrom::Start(end - CatalogLayout::SLICE_SIZE as u64))?;
+        let entry = &self.entries[index];
+        self.inner.write_u8(entry.flags)?;
+        self.inner.write_u64::<LittleEndian>(entry.size)?;
+        self.inner.write_u64::<LittleEndian>(entry.offset)?;
+        Ok(())
+    }
+
+    /// Rewrites only the generation in the header
+    fn write_generation(&mut self, generation: Generation) -> Result<(), PfaError> {
+        let offset = self
+            .fields
+            .generation_offset
+            .ok_or(PfaError::CustomError("archive has no generation".into()))?;
+        self.inner.seek(SeekFrom::Start(self.fields_idx + offset))?;
+        self.inner.write_all(&generation.to_bytes())?;
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    fn write_fields(&mut self) -> Result<(), PfaError> {
+        let mut encoded = vec![];
+        self.fields.write(&mut encoded)?;
+        if self.fields_idx + encoded.len() as u64 != self.catalog_idx {
+            return Err(PfaError::CustomError(
+                "archive header changed size while editing".into(),
+            ));
+        }
+        self.inner.seek(SeekFrom::Start(self.fields_idx))?;
+        self.inner.write_all(&encoded)?;
+        Ok(())
+    }
+}
@@ -1,22 +1,112 @@
-use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::{
+    fs::File,
+    io::{BufReader, Cursor, Read, Write},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use byteorder::{LittleEndian, WriteBytesExt};
 
-use crate::{shared::data_flags::DataFlags, PfaError};
+use crate::{
+    shared::{
+        data_flags::{DataCompressionType, DataFlags},
+        entry_hash,
+        header_fields::HeaderFields,
+        ordered_pipeline::{self, Produced},
+        sharding, sniff_content_type,
+        solid_block::{SolidBlock, SolidMember},
+        CatalogLayout, Cipher, EntryHash, Generation, KeyId, NameEncoding, Provenance,
+        ReservedSpace, Tier, TransformChain, Ulid, DEFAULT_DEPTH_LIMIT, LATEST_VERSION, WATERMARK,
+    },
+    writer::{build_log::BuildLog, directory_limits::DirectoryLimits},
+    PfaError,
+};
+
+pub enum PfaFileSource {
+    Bytes(Vec<u8>),
+    /// Read to the end when the archive is built
+    Reader(Box<dyn Read + Send>),
+    /// File on disk, only opened when the archive is built
+    Path(PathBuf),
+    /// Data which was already processed and written to the spilled part of the data section by a
+    /// [`PfaStreamingBuilder`](crate::writer::streaming_builder::PfaStreamingBuilder)
+    Spilled {
+        offset: u64,
+        size: u64,
+        flags: u8,
+        /// BLAKE3 hash of the stored data
+        contents_hash: [u8; 32],
+    },
+    /// Data which has already been processed, copied into the archive as is
+    Stored {
+        contents: Vec<u8>,
+        flags: u8,
+    },
+}
+
+impl PfaFileSource {
+    fn take(&mut self) -> Self {
+        std::mem::replace(self, PfaFileSource::Bytes(vec![]))
+    }
+}
+
+impl std::fmt::Debug for PfaFileSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PfaFileSource::Bytes(contents) => write!(f, "Bytes({}b)", contents.len()),
+            PfaFileSource::Reader(_) => write!(f, "Reader"),
+            PfaFileSource::Path(path) => write!(f, "Path({})", path.display()),
+            PfaFileSource::Spilled {
+                offset,
+                size,
+                flags,
+                ..
+            } => write!(f, "Spilled({size}b at {offset}, {flags:#010b})"),
+            PfaFileSource::Stored { contents, flags } => {
+                write!(f, "Stored({}b, {flags:#010b})", contents.len())
+            }
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct PfaFile {
     pub(super) name: String,
     pub(super) flags: DataFlags,
-    pub(super) contents: Vec<u8>,
+    pub(super) contents: PfaFileSource,
+    pub(super) tier: Option<Tier>,
+    /// Compression is known not to help, so it isn't tried again
+    pub(super) compression_skipped: bool,
+    pub(super) content_type: Option<String>,
+    pub(super) provenance: Option<Provenance>,
+    /// Boxed since few files have one
+    pub(super) transforms: Option<Box<TransformChain>>,
+    /// Id of the key stored data was encrypted with, when it isn't encrypted with `flags`
+    pub(super) key_id: Option<KeyId>,
+    /// Size of the contents of data added already processed, when it's known
+    pub(super) original_size: Option<u64>,
+    /// Cipher stored data was encrypted with, when it isn't encrypted with `flags`
+    pub(super) cipher: Option<Cipher>,
 }
 
 impl PfaFile {
     pub fn new(name: String, contents: Vec<u8>, flags: DataFlags) -> Option<Self> {
+        Self::from_source(name, PfaFileSource::Bytes(contents), flags)
+    }
+
+    pub fn from_source(name: String, contents: PfaFileSource, flags: DataFlags) -> Option<Self> {
         Some(Self {
             name,
             contents,
             flags,
+            tier: None,
+            compression_skipped: false,
+            content_type: None,
+            provenance: None,
+            transforms: None,
+            key_id: None,
+            original_size: None,
+            cipher: None,
         })
     }
 }
@@ -25,6 +115,10 @@ impl PfaFile {
 pub struct PfaDirectory {
     pub(super) name: String,
     pub(super) contents: Vec<PfaPath>,
+    /// Set when the contents were split into buckets
+    sharded: bool,
+    /// Buckets aren't part of the paths of their contents, and their names aren't encoded
+    bucket: bool,
 }
 
 impl PfaDirectory {
@@ -32,59 +126,558 @@ impl PfaDirectory {
         Self {
             name: name.to_string(),
             contents,
+            sharded: false,
+            bucket: false,
         }
     }
 }
 
 #[derive(Debug)]
 pub enum PfaPath {
-    File(PfaFile),
+    /// Boxed since a file holds far more than a directory
+    File(Box<PfaFile>),
     Directory(PfaDirectory),
 }
 
-impl PfaPath {
-    const MAX_SIZE: usize = 32;
+pub(super) fn to_u64(value: usize, what: &'static str) -> Result<u64, PfaError> {
+    u64::try_from(value).map_err(|_| PfaError::WriterOverflowError(what))
 }
 
+/// A catalog entry before it is encoded, directory names include their trailing '/'
 #[derive(Clone, Debug)]
-struct PfaDataSlice {
-    flags: u8,
-    offset: u64,
-    size: u64,
+pub(super) struct PfaCatalogEntry {
+    pub(super) name: String,
+    pub(super) flags: u8,
+    pub(super) size: u64,
+    /// Data offset for files, relative entry index for directories
+    pub(super) offset: u64,
+    pub(super) hash: EntryHash,
 }
 
-#[derive(Clone, Debug)]
-struct PfaCatalogSlice {
+impl PfaCatalogEntry {
+    fn directory(dir: &PfaDirectory, offset: u64) -> Result<Self, PfaError> {
+        Ok(Self {
+            name: format!("{}/", dir.name),
+            flags: 0,
+            size: to_u64(dir.contents.len(), "directory size")?,
+            offset,
+            hash: EntryHash::default(),
+        })
+    }
+
+    /// The slice is filled in once the file has been processed
+    fn file(file: &PfaFile) -> Self {
+        Self {
+            name: file.name.clone(),
+            flags: 0,
+            size: 0,
+            offset: 0,
+            hash: EntryHash::default(),
+        }
+    }
+
+    fn reserved() -> Self {
+        Self {
+            name: String::new(),
+            flags: 0,
+            size: 0,
+            offset: 0,
+            hash: entry_hash::file_hash("", 0, &[]),
+        }
+    }
+}
+
+/// Writes an entry with its name padded to the size of a reserved slot
+pub(super) fn write_reserved_slot(
+    out: &mut impl Write,
+    entry: &PfaCatalogEntry,
+    layout: CatalogLayout,
+) -> Result<(), PfaError> {
+    let name_size = CatalogLayout::FIXED_NAME_SIZE;
+    if entry.name.len() > name_size {
+        return Err(PfaError::StringTooLongError {
+            string: entry.name.clone(),
+            max: name_size,
+        });
+    }
+    if layout == CatalogLayout::Compact {
+        out.write_u8(name_size as u8)?;
+    }
+    out.write_all(entry.name.as_bytes())?;
+    out.write_all(&vec![0; name_size - entry.name.len()])?;
+    out.write_u8(entry.flags)?;
+    out.write_u64::<LittleEndian>(entry.size)?;
+    out.write_u64::<LittleEndian>(entry.offset)?;
+    Ok(())
+}
+
+pub(super) fn child_slots(
+    slot: usize,
+    entry: &PfaCatalogEntry,
+) -> Result<std::ops::Range<usize>, PfaError> {
+    let overflow = || PfaError::WriterOverflowError("catalog slice index");
+    let start = usize::try_from(entry.offset)
+        .ok()
+        .and_then(|x| slot.checked_add(x))
+        .ok_or_else(overflow)?;
+    let end = usize::try_from(entry.size)
+        .ok()
+        .and_then(|x| start.checked_add(x))
+        .ok_or_else(overflow)?;
+    Ok(start..end)
+}
+
+/// A file waiting to be written to the data section, whose source is an unread
+/// [`PfaFileSource`] until [`FileJob::read`] reads it into a [`ReadSource`]
+struct FileJob<S = PfaFileSource> {
+    slot: usize,
+    path: String,
+    tier: Option<Tier>,
+    name: String,
+    flags: DataFlags,
+    compression_skipped: bool,
+    content_type: Option<String>,
+    provenance: Option<Provenance>,
+    transforms: Option<TransformChain>,
+    key_id: Option<KeyId>,
+    original_size: Option<u64>,
+    cipher: Option<Cipher>,
+    /// Guess the content type when the file wasn't given one
+    sniff: bool,
+    source: S,
+}
+
+/// Source of a file which has been read, see [`FileJob::read`]
+enum ReadSource {
+    Bytes(Vec<u8>),
+    /// Processed a chunk at a time as it's read, with these flags
+    Streaming(Box<dyn Read + Send>, DataFlags),
+    Spilled {
+        offset: u64,
+        size: u64,
+        flags: u8,
+        contents_hash: [u8; 32],
+    },
+    Stored {
+        contents: Vec<u8>,
+        flags: u8,
+    },
+}
+
+/// Source of a file stored in a solid block, see [`PfaWriter::take_solid_groups`]
+enum SolidSource {
+    Bytes(Vec<u8>),
+    Path(PathBuf),
+}
+
+impl<S> FileJob<S> {
+    /// Swaps the job's source for `source`, returning the old one
+    fn replace_source<T>(self, source: T) -> (FileJob<T>, S) {
+        let job = FileJob {
+            slot: self.slot,
+            path: self.path,
+            tier: self.tier,
+            name: self.name,
+            flags: self.flags,
+            compression_skipped: self.compression_skipped,
+            content_type: self.content_type,
+            provenance: self.provenance,
+            transforms: self.transforms,
+            key_id: self.key_id,
+            original_size: self.original_size,
+            cipher: self.cipher,
+            sniff: self.sniff,
+            source,
+        };
+        (job, self.source)
+    }
+}
+
+impl FileJob<SolidSource> {
+    /// Reads the source into memory
+    fn read(self, log: Option<&BuildLog>) -> Result<FileJob<Vec<u8>>, PfaError> {
+        if let Some(log) = log {
+            log.file_started(&self.path)?;
+        }
+        let (job, source) = self.replace_source(());
+        let contents = match source {
+            SolidSource::Bytes(contents) => contents,
+            SolidSource::Path(path) => std::fs::read(&path)
+                .map_err(|e| PfaError::CustomError(format!("read '{}': {e}", path.display())))?,
+        };
+        Ok(job.replace_source(contents).0)
+    }
+}
+
+impl FileJob {
+    /// `name` is the name stored in the catalog, which differs from the file's name when names
+    /// are hashed
+    fn new(slot: usize, path: String, name: String, file: &mut PfaFile) -> Self {
+        Self {
+            slot,
+            path,
+            tier: file.tier,
+            name,
+            flags: file.flags.clone(),
+            compression_skipped: file.compression_skipped,
+            content_type: file.content_type.take(),
+            provenance: file.provenance.take(),
+            transforms: file.transforms.take().map(|x| *x),
+            key_id: file.key_id.or_else(|| file.flags.encryption_key_id()),
+            original_size: file.original_size,
+            cipher: file.flags.encryption_cipher().or(file.cipher),
+            sniff: false,
+            source: file.contents.take(),
+        }
+    }
+
+    /// Reads reader and path sources into memory, unless they can be processed as they are read
+    fn read(self, log: Option<&BuildLog>) -> Result<FileJob<ReadSource>, PfaError> {
+        if let Some(log) = log {
+            log.file_started(&self.path)?;
+        }
+        let streaming = self.streaming_flags();
+        let (job, source) = self.replace_source(());
+        let mut reader: Box<dyn Read + Send> = match source {
+            PfaFileSource::Bytes(contents) => {
+                return Ok(job.replace_source(ReadSource::Bytes(contents)).0)
+            }
+            PfaFileSource::Spilled {
+                offset,
+                size,
+                flags,
+                contents_hash,
+            } => {
+                let source = ReadSource::Spilled {
+                    offset,
+                    size,
+                    flags,
+                    contents_hash,
+                };
+                return Ok(job.replace_source(source).0);
+            }
+            PfaFileSource::Stored { contents, flags } => {
+                return Ok(job.replace_source(ReadSource::Stored { contents, flags }).0)
+            }
+            PfaFileSource::Reader(reader) => reader,
+            PfaFileSource::Path(path) => {
+                let file = File::open(&path).map_err(|e| {
+                    PfaError::CustomError(format!("open '{}': {e}", path.display()))
+                })?;
+                Box::new(BufReader::new(file))
+            }
+        };
+        let source = match streaming {
+            Some(flags) => ReadSource::Streaming(reader, flags),
+            None => {
+                let mut contents = vec![];
+                reader.read_to_end(&mut contents)?;
+                ReadSource::Bytes(contents)
+            }
+        };
+        Ok(job.replace_source(source).0)
+    }
+
+    /// Flags a reader or path source is processed with a chunk at a time as it's read, None when
+    /// it has to be read whole first, see [`DataFlags::is_streamable`]
+    fn streaming_flags(&self) -> Option<DataFlags> {
+        let flags = match self.flags.is_automatic_compression() && self.compression_skipped {
+            true => self
+                .flags
+                .clone()
+                .compression_type(DataCompressionType::Forced(false)),
+            false => self.flags.clone(),
+        };
+        flags.is_streamable().then_some(flags)
+    }
+}
+
+impl FileJob<ReadSource> {
+    fn process(self) -> Result<ProcessedFile, PfaError> {
+        let sniff = |contents: &[u8]| {
+            self.sniff
+                .then(|| sniff_content_type(&self.path, contents))
+                .flatten()
+                .map(|x| x.to_string())
+        };
+        let mut content_type = self.content_type.clone().or_else(|| {
+            let contents = match &self.source {
+                ReadSource::Bytes(contents) => contents,
+                ReadSource::Stored { contents, flags } if DataFlags::is_unprocessed(*flags) => {
+                    contents
+                }
+                _ => &[][..],
+            };
+            sniff(contents)
+        });
+        let automatic = self.flags.is_automatic_compression();
+        let (data, flags, tried, original_size) = match self.source {
+            ReadSource::Stored { contents, flags } => (contents, flags, false, self.original_size),
+            ReadSource::Bytes(contents) if automatic && self.compression_skipped => {
+                let flags = self
+                    .flags
+                    .compression_type(DataCompressionType::Forced(false));
+                let (data, flags) = flags.process_content_and_generate_flags(&contents)?;
+                (data, flags, false, Some(contents.len() as u64))
+            }
+            ReadSource::Bytes(contents) => {
+                let (data, flags) = self.flags.process_content_and_generate_flags(&contents)?;
+                (data, flags, automatic, Some(contents.len() as u64))
+            }
+            ReadSource::Streaming(mut reader, streaming) => {
+                let mut data = vec![];
+                let (flags, size) = streaming.process_reader(&mut reader, &mut data)?;
+                // the contents are only known once they're processed
+                if self.content_type.is_none() && DataFlags::is_unprocessed(flags) {
+                    content_type = sniff(&data);
+                }
+                (data, flags, false, Some(size))
+            }
+            ReadSource::Spilled {
+                offset,
+                size,
+                flags,
+                contents_hash,
+            } => {
+                return Ok(ProcessedFile {
+                    slot: self.slot,
+                    path: self.path,
+                    data: vec![],
+                    spilled: Some((offset, size)),
+                    flags,
+                    hash: entry_hash::file_hash_of_digest(&self.name, flags, &contents_hash),
+                    compression_skipped: !DataFlags::is_compressed(flags)
+                        && self.compression_skipped,
+                    content_type,
+                    key_id: self.key_id.filter(|_| DataFlags::is_encrypted(flags)),
+                    original_size: self
+                        .original_size
+                        .filter(|_| !DataFlags::is_unprocessed(flags)),
+                    cipher: self.cipher.filter(|_| DataFlags::is_encrypted(flags)),
+                });
+            }
+        };
+        Ok(ProcessedFile {
+            slot: self.slot,
+            path: self.path,
+            spilled: None,
+            hash: entry_hash::file_hash(&self.name, flags, &data),
+            compression_skipped: !DataFlags::is_compressed(flags)
+                && (tried || self.compression_skipped),
+            content_type,
+            key_id: self.key_id.filter(|_| DataFlags::is_encrypted(flags)),
+            original_size: original_size.filter(|_| !DataFlags::is_unprocessed(flags)),
+            cipher: self.cipher.filter(|_| DataFlags::is_encrypted(flags)),
+            data,
+            flags,
+        })
+    }
+}
+
+struct ProcessedFile {
+    slot: usize,
+    path: String,
+    data: Vec<u8>,
+    /// Offset and size of spilled data, which is already in the data section
+    spilled: Option<(u64, u64)>,
     flags: u8,
-    index: u64,
-    size: u64,
+    hash: EntryHash,
+    compression_skipped: bool,
+    content_type: Option<String>,
+    key_id: Option<KeyId>,
+    /// Size of the contents when the data isn't them as is
+    original_size: Option<u64>,
+    cipher: Option<Cipher>,
 }
 
 pub struct PfaWriter {
     name: String,
-    version: u8,
+    /// None writes the oldest version 2 or later which can hold the data
+    version: Option<u8>,
+    header_fields: HeaderFields,
+    /// None picks whichever layout results in the smaller catalog
+    catalog_layout: Option<CatalogLayout>,
+    entry_hashes: bool,
+    threads: usize,
+    #[cfg(feature = "rayon")]
+    thread_pool: Option<Arc<rayon::ThreadPool>>,
+    reserved_slots: usize,
+    reserved_data: u64,
+    build_log: Option<Arc<BuildLog>>,
+    directory_limits: DirectoryLimits,
+    sniff_content_types: bool,
+    strip_provenance: bool,
+    depth_limit: usize,
+    extra_data: Vec<u8>,
+    compress_extra_data: bool,
+    /// Largest file stored in a solid block, and the size of the blocks before compression
+    solid_blocks: Option<(u64, u64)>,
     files: PfaPath,
     buf: Cursor<Vec<u8>>,
+    /// Start of the data section which was written before the build, followed by `data`
+    spilled: Option<(Box<dyn Read + Send>, u64)>,
     data: Vec<u8>,
 }
 
 impl PfaWriter {
-    pub fn new(name: &str, files: PfaPath) -> Self {
+    pub fn new(name: &str, archive_id: Ulid, files: PfaPath) -> Self {
         Self {
             buf: Cursor::new(vec![]),
+            spilled: None,
             data: vec![],
             files,
             name: name.to_string(),
-            version: 1,
+            version: None,
+            header_fields: HeaderFields {
+                archive_id: Some(archive_id),
+                ..Default::default()
+            },
+            catalog_layout: None,
+            entry_hashes: false,
+            threads: 1,
+            #[cfg(feature = "rayon")]
+            thread_pool: None,
+            reserved_slots: 0,
+            reserved_data: 0,
+            build_log: None,
+            directory_limits: DirectoryLimits::default(),
+            sniff_content_types: false,
+            strip_provenance: false,
+            depth_limit: DEFAULT_DEPTH_LIMIT,
+            extra_data: vec![],
+            compress_extra_data: false,
+            solid_blocks: None,
         }
     }
 
+    pub fn set_catalog_layout(&mut self, layout: Option<CatalogLayout>) {
+        self.catalog_layout = layout;
+    }
+
+    pub fn set_entry_hashes(&mut self, entry_hashes: bool) {
+        self.entry_hashes = entry_hashes;
+    }
+
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads;
+    }
+
+    /// Process files on a rayon thread pool instead of threads owned by the writer, which takes
+    /// precedence over [`Self::set_threads`]
+    #[cfg(feature = "rayon")]
+    pub fn set_thread_pool(&mut self, pool: Option<Arc<rayon::ThreadPool>>) {
+        self.thread_pool = pool;
+    }
+
+    pub fn set_reserved_space(&mut self, catalog_slots: usize, data_size: u64) {
+        self.reserved_slots = catalog_slots;
+        self.reserved_data = data_size;
+    }
+
+    pub fn set_name_encoding(&mut self, encoding: NameEncoding) {
+        self.header_fields.name_encoding = encoding;
+    }
+
+    pub fn set_variants(&mut self, variants: Vec<String>) {
+        self.header_fields.variants = variants;
+    }
+
+    pub fn set_build_log(&mut self, log: Option<BuildLog>) {
+        self.build_log = log.map(Arc::new);
+    }
+
+    pub fn set_directory_limits(&mut self, limits: DirectoryLimits) {
+        self.directory_limits = limits;
+    }
+
+    pub fn set_sniff_content_types(&mut self, sniff: bool) {
+        self.sniff_content_types = sniff;
+    }
+
+    pub fn set_strip_provenance(&mut self, strip: bool) {
+        self.strip_provenance = strip;
+    }
+
+    pub fn set_depth_limit(&mut self, depth_limit: usize) {
+        self.depth_limit = depth_limit;
+    }
+
+    pub fn set_extra_data(&mut self, extra_data: Vec<u8>) {
+        self.extra_data = extra_data;
+    }
+
+    pub fn set_compress_extra_data(&mut self, compress: bool) {
+        self.compress_extra_data = compress;
+    }
+
+    /// Format version to write, version 1 archives can't use any feature stored in a header field
+    /// and version 2 archives can't use codecs other than LZ4. When unset, version 2 is written
+    /// unless the data needs version 3.
+    pub fn set_version(&mut self, version: Option<u8>) {
+        self.version = version;
+    }
+
+    /// Generation number written in the header, timestamped when the archive is built
+    pub fn set_generation(&mut self, generation: Option<u64>) {
+        self.header_fields.generation = generation.map(Generation::now);
+    }
+
+    pub fn set_solid_blocks(&mut self, solid_blocks: Option<(u64, u64)>) {
+        self.solid_blocks = solid_blocks;
+    }
+
+    /// Data written before the build, which starts the data section. Files with
+    /// [`PfaFileSource::Spilled`] sources refer to it.
+    pub fn set_spilled_data(&mut self, data: Box<dyn Read + Send>, len: u64) {
+        self.spilled = Some((data, len));
+    }
+
+    /// Size of the data section so far
+    fn data_len(&self) -> Result<u64, PfaError> {
+        let spilled = self.spilled.as_ref().map_or(0, |x| x.1);
+        spilled
+            .checked_add(to_u64(self.data.len(), "data section")?)
+            .ok_or(PfaError::WriterOverflowError("data section"))
+    }
+
+    /// Places the data of every file at an archive offset which is a multiple of `alignment`, and
+    /// marks the archive as store only. Files must be stored as is.
+    pub fn set_data_alignment(&mut self, alignment: Option<u32>) {
+        self.header_fields.data_alignment = alignment;
+    }
+
     pub fn generate(self) -> Result<Vec<u8>, PfaError> {
-        self.write_pfa()
+        let mut out = vec![];
+        self.generate_into(&mut out)?;
+        Ok(out)
+    }
+
+    /// Writes the archive to `out`, returning its size. The processed data of every file is still
+    /// held in memory until then, as the header and catalog written before it can only be built
+    /// once every file has been processed. Data spilled by a
+    /// [`PfaStreamingBuilder`](crate::writer::streaming_builder::PfaStreamingBuilder) is copied
+    /// from its spill instead.
+    pub fn generate_into(self, out: &mut impl Write) -> Result<u64, PfaError> {
+        let log = self.build_log.clone();
+        let result = self.write_pfa(out);
+        if let Some(log) = log {
+            match &result {
+                Ok(size) => log.build_finished(*size)?,
+                // the original error is more useful than a failure to log it
+                Err(e) => {
+                    let _ = log.build_failed(e);
+                }
+            }
+        }
+        result
     }
 
     fn write_u8_sized_string(&mut self, string: &str) -> Result<(), PfaError> {
-        self.buf.write_u8(string.len() as u8)?;
+        let len = u8::try_from(string.len()).map_err(|_| PfaError::StringTooLongError {
+            string: string.to_string(),
+            max: u8::MAX.into(),
+        })?;
+        self.buf.write_u8(len)?;
         self.buf.write_all(string.as_bytes())?;
 
         Ok(())
@@ -96,12 +689,10 @@ impl PfaWriter {
         size: usize,
     ) -> Result<(), PfaError> {
         if string.len() > size {
-            return Err(PfaError::CustomError(format!(
-                "string '{}' of length {} is larger than max string size of {}",
-                string,
-                string.len(),
-                size
-            )));
+            return Err(PfaError::StringTooLongError {
+                string: string.to_string(),
+                max: size,
+            });
         }
 
         self.buf.write_all(string.as_bytes())?;
@@ -112,147 +703,919 @@ impl PfaWriter {
         Ok(())
     }
 
-    fn write_pfa(mut self) -> Result<Vec<u8>, PfaError> {
-        self.buf.write_all(b"pfa")?; // watermark
-        self.write_header()?;
-        self.write_catalog()?;
-        self.write_data()?;
-        Ok(self.buf.into_inner())
+    fn write_pfa(mut self, out: &mut impl Write) -> Result<u64, PfaError> {
+        if let Some(version) = self.version.filter(|x| *x == 0 || *x > LATEST_VERSION) {
+            return Err(PfaError::UnsupportedFeature(format!(
+                "archive version {version}"
+            )));
+        }
+        let mut entries = self.build_catalog()?;
+        let newer_codecs = self.newer_codecs(&entries);
+        let version = match self.version {
+            Some(version) => version,
+            None if newer_codecs.is_empty() => 2,
+            None => 3,
+        };
+        if version == 1 {
+            self.check_version_1(&entries)?;
+        } else if version == 2 && !newer_codecs.is_empty() {
+            return Err(PfaError::UnsupportedByVersion {
+                version,
+                features: newer_codecs,
+            });
+        }
+        if self.compress_extra_data && version > 1 {
+            let compressed = lz4_flex::compress_prepend_size(&self.extra_data);
+            if compressed.len() < self.extra_data.len() {
+                self.extra_data = compressed;
+                self.header_fields.extra_data_compressed = true;
+            }
+        }
+        let layout = self.choose_catalog_layout(&entries)?;
+        self.header_fields.catalog_layout = layout;
+        let slots_start = entries.len();
+        if self.reserved_slots > 0 || self.reserved_data > 0 {
+            let start = to_u64(slots_start, "reserved catalog slots")?;
+            self.header_fields.reserved_space = Some(ReservedSpace {
+                slots_start: start,
+                first_free_slot: start,
+                free_slots: to_u64(self.reserved_slots, "reserved catalog slots")?,
+                data_offset: self.data_len()?,
+                free_data: self.reserved_data,
+            });
+            entries.resize(
+                slots_start + self.reserved_slots,
+                PfaCatalogEntry::reserved(),
+            );
+            let reserved_data = usize::try_from(self.reserved_data)
+                .map_err(|_| PfaError::WriterOverflowError("reserved data"))?;
+            self.data.resize(self.data.len() + reserved_data, 0);
+        }
+        if self.entry_hashes {
+            self.header_fields.entry_hashes = Some(entries.iter().map(|x| x.hash).collect());
+        }
+
+        self.buf.write_all(WATERMARK)?;
+        self.write_header(version)?;
+        self.write_catalog(&entries, slots_start, layout)?;
+        let data_start = self.buf.position();
+        if let Some(alignment) = self.header_fields.data_alignment {
+            // offsets are fixed size, so moving the data doesn't move the start of the data section
+            self.align_data(&mut entries[..slots_start], data_start, alignment)?;
+            self.buf = Cursor::new(vec![]);
+            self.buf.write_all(WATERMARK)?;
+            self.write_header(version)?;
+            self.write_catalog(&entries, slots_start, layout)?;
+            debug_assert_eq!(self.buf.position(), data_start);
+        }
+        if let Some(log) = &self.build_log {
+            log.catalog_flushed(entries.len(), layout)?;
+        }
+        let data_len = self.data_len()?;
+        out.write_all(self.buf.get_ref())?;
+        if let Some((spilled, len)) = &mut self.spilled {
+            let copied = std::io::copy(&mut spilled.take(*len), out)?;
+            if copied != *len {
+                return Err(PfaError::CustomError("spilled data is incomplete".into()));
+            }
+        }
+        out.write_all(&self.data)?;
+        data_start
+            .checked_add(data_len)
+            .ok_or(PfaError::WriterOverflowError("archive size"))
+    }
+
+    /// Moves the data of every file so it starts at a multiple of `alignment` from the start of
+    /// the archive, padding with zeros. Reserved data is kept at the end, starting aligned.
+    fn align_data(
+        &mut self,
+        entries: &mut [PfaCatalogEntry],
+        data_start: u64,
+        alignment: u32,
+    ) -> Result<(), PfaError> {
+        if self.spilled.is_some() {
+            return Err(PfaError::CustomError(
+                "store only archives can't be built from spilled data".into(),
+            ));
+        }
+        let alignment = u64::from(alignment);
+        let mut files = entries
+            .iter_mut()
+            .filter(|x| !x.name.ends_with('/') && x.size > 0)
+            .collect::<Vec<_>>();
+        if let Some(entry) = files.iter().find(|x| x.flags != 0) {
+            return Err(PfaError::CustomError(format!(
+                "'{}' isn't stored as is, which store only archives require",
+                entry.name
+            )));
+        }
+        files.sort_by_key(|x| x.offset);
+
+        let overflow = || PfaError::WriterOverflowError("aligned data");
+        let pad = |data: &mut Vec<u8>| {
+            let position = data_start + to_u64(data.len(), "aligned data")?;
+            let aligned = position
+                .checked_next_multiple_of(alignment)
+                .ok_or_else(overflow)?;
+            data.resize(data.len() + (aligned - position) as usize, 0);
+            Ok::<_, PfaError>(())
+        };
+        let mut data = Vec::with_capacity(self.data.len());
+        for entry in files {
+            pad(&mut data)?;
+            let start = usize::try_from(entry.offset).map_err(|_| overflow())?;
+            let end = usize::try_from(entry.offset + entry.size).map_err(|_| overflow())?;
+            entry.offset = to_u64(data.len(), "data offset")?;
+            data.extend_from_slice(&self.data[start..end]);
+        }
+
+        if let Some(reserved) = &mut self.header_fields.reserved_space {
+            pad(&mut data)?;
+            reserved.data_offset = to_u64(data.len(), "reserved data")?;
+            let free_data = usize::try_from(reserved.free_data).map_err(|_| overflow())?;
+            data.resize(data.len() + free_data, 0);
+        }
+        self.data = data;
+        Ok(())
+    }
+
+    /// Codecs other than LZ4 used by files or solid blocks, which version 2 readers that predate
+    /// them would decode as LZ4
+    fn newer_codecs(&self, entries: &[PfaCatalogEntry]) -> Vec<String> {
+        let files = entries
+            .iter()
+            .filter(|x| DataFlags::needs_version_3(x.flags))
+            .map(|x| (x.flags, format!("'{}'", x.name)));
+        let blocks = self
+            .header_fields
+            .solid_blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| DataFlags::needs_version_3(x.flags))
+            .map(|(i, x)| (x.flags, format!("solid block {i}")));
+        files
+            .chain(blocks)
+            .map(|(flags, user)| {
+                let codec = DataFlags::codec(flags).map_or("", |x| x.name());
+                format!("{codec} compression, used by {user}")
+            })
+            .collect()
+    }
+
+    /// Version 1 headers have no fields, so nothing stored in one can be written. Every feature
+    /// which would be lost is reported at once. Compression skipped markers and key ids are only
+    /// hints, so they are dropped.
+    fn check_version_1(&mut self, entries: &[PfaCatalogEntry]) -> Result<(), PfaError> {
+        let fields = &self.header_fields;
+        let mut features = vec![];
+        if self.catalog_layout == Some(CatalogLayout::Compact) {
+            features.push("the compact catalog layout".to_string());
+        }
+        let max_name_size = CatalogLayout::Fixed.max_name_size();
+        for entry in entries.iter().filter(|x| x.name.len() > max_name_size) {
+            features.push(format!(
+                "name '{}', longer than {max_name_size} bytes",
+                entry.name
+            ));
+        }
+        for entry in entries
+            .iter()
+            .filter(|x| DataFlags::has_encryption_frames(x.flags))
+        {
+            features.push(format!("encryption frames, used by '{}'", entry.name));
+        }
+        for entry in entries
+            .iter()
+            .filter(|x| DataFlags::has_size_padding(x.flags))
+        {
+            features.push(format!("size padding, used by '{}'", entry.name));
+        }
+        for entry in entries
+            .iter()
+            .filter(|x| DataFlags::needs_version_3(x.flags))
+        {
+            let codec = DataFlags::codec(entry.flags).map_or("", |x| x.name());
+            features.push(format!("{codec} compression, used by '{}'", entry.name));
+        }
+        if self.extra_data.len() > u8::MAX as usize {
+            features.push(format!(
+                "{} bytes of extra data, more than {}",
+                self.extra_data.len(),
+                u8::MAX
+            ));
+        }
+        let unsupported = [
+            (self.entry_hashes, "entry hashes"),
+            (!fields.variants.is_empty(), "variants"),
+            (!fields.tiers.is_empty(), "tiers"),
+            (
+                self.reserved_slots > 0 || self.reserved_data > 0,
+                "reserved space",
+            ),
+            (fields.name_encoding != NameEncoding::Plain, "hashed names"),
+            (
+                !fields.sharded_directories.is_empty(),
+                "sharded directories",
+            ),
+            (!fields.entry_content_types.is_empty(), "content types"),
+            (!fields.provenance.is_empty(), "provenance"),
+            (!fields.transforms.is_empty(), "transforms"),
+            (fields.data_alignment.is_some(), "store only data alignment"),
+            (fields.generation.is_some(), "generations"),
+            (!fields.solid_blocks.is_empty(), "solid blocks"),
+            (!fields.ciphers.is_empty(), "XChaCha20-Poly1305 encryption"),
+        ];
+        features.extend(
+            unsupported
+                .into_iter()
+                .filter(|x| x.0)
+                .map(|x| x.1.to_string()),
+        );
+        if !features.is_empty() {
+            return Err(PfaError::UnsupportedByVersion {
+                version: 1,
+                features,
+            });
+        }
+
+        self.catalog_layout = Some(CatalogLayout::Fixed);
+        self.header_fields.compression_skipped.clear();
+        self.header_fields.original_sizes.clear();
+        self.header_fields.key_ids.clear();
+        self.header_fields.entry_key_ids.clear();
+        Ok(())
     }
 
-    fn write_header(&mut self) -> Result<(), PfaError> {
-        self.buf.write_u8(self.version)?; // version
+    fn write_header(&mut self, version: u8) -> Result<(), PfaError> {
+        self.buf.write_u8(version)?; // version
         self.write_u8_sized_string(&self.name.clone())?; // name
-        self.buf.write_u8(0)?; // size of extra data
+        if version == 1 {
+            let len = u8::try_from(self.extra_data.len())
+                .map_err(|_| PfaError::WriterOverflowError("extra data size"))?;
+            self.buf.write_u8(len)?;
+            self.buf.write_all(&self.extra_data)?;
+            return Ok(());
+        }
+        self.buf
+            .write_u64::<LittleEndian>(to_u64(self.extra_data.len(), "extra data size")?)?;
+        self.buf.write_all(&self.extra_data)?;
+        self.header_fields.write(&mut self.buf)?;
 
         Ok(())
     }
 
-    fn write_catalog(&mut self) -> Result<(), PfaError> {
-        struct CatalogState<'a> {
-            writer: &'a mut PfaWriter,
-            catalog_len: u64,
+    /// The fixed layout is kept whenever it isn't larger, since its entries can be located
+    /// without reading the names before them
+    fn choose_catalog_layout(
+        &self,
+        entries: &[PfaCatalogEntry],
+    ) -> Result<CatalogLayout, PfaError> {
+        let catalog_size = |layout: CatalogLayout| {
+            entries
+                .iter()
+                .map(|x| layout.entry_size(&x.name))
+                .sum::<usize>()
+        };
+        let fits = |layout: CatalogLayout| {
+            entries
+                .iter()
+                .all(|x| x.name.len() <= layout.max_name_size())
+        };
+
+        let layout = self.catalog_layout.unwrap_or_else(|| {
+            if fits(CatalogLayout::Fixed)
+                && catalog_size(CatalogLayout::Fixed) <= catalog_size(CatalogLayout::Compact)
+            {
+                CatalogLayout::Fixed
+            } else {
+                CatalogLayout::Compact
+            }
+        });
+
+        if let Some(entry) = entries
+            .iter()
+            .find(|x| x.name.len() > layout.max_name_size())
+        {
+            return Err(PfaError::StringTooLongError {
+                string: entry.name.clone(),
+                max: layout.max_name_size(),
+            });
         }
 
-        let mut file = PfaPath::File(
+        Ok(layout)
+    }
+
+    /// Lays out the catalog and processes every file into the data section. The children of a
+    /// directory are stored next to each other, with sub-directory contents appended after them.
+    fn build_catalog(&mut self) -> Result<Vec<PfaCatalogEntry>, PfaError> {
+        let mut file = PfaPath::File(Box::new(
             PfaFile::new("".to_string(), vec![], DataFlags::default()).ok_or(
                 PfaError::CustomError("unable to make empty file for swap".to_string()),
             )?,
-        );
+        ));
         std::mem::swap(&mut file, &mut self.files);
 
-        let catalog_len_idx = self.buf.position();
-        self.buf.write_u64::<LittleEndian>(0)?;
-
-        let mut catalog_len = 0;
-        if let PfaPath::Directory(dir) = &file {
-            let name = dir.name.clone();
-            let size = dir.contents.len() as u64;
-            self.write_catalog_entry(
-                &name,
-                &PfaCatalogSlice {
-                    index: 1,
-                    size,
-                    flags: 0,
-                },
-            )?;
-            catalog_len += 1;
-        }
-        const ENTRY_SIZE: usize = 49;
-        fn write_catalog_inner(state: &mut CatalogState, path: &PfaPath) -> Result<(), PfaError> {
-            match path {
-                PfaPath::Directory(dir) => {
-                    let mut catalog_idx = vec![];
-                    for _ in &dir.contents {
-                        catalog_idx.push(state.writer.buf.position());
-                        state.writer.buf.write_all(&[0; ENTRY_SIZE])?; // pre allocate catalog
+        let warnings = self.directory_limits.check(&file);
+        if let Some(log) = &self.build_log {
+            for warning in &warnings {
+                log.warning(&warning.to_string())?;
+            }
+        }
+
+        let mut entries = vec![];
+        let mut jobs = vec![];
+        let mut sharded = vec![];
+        match &mut file {
+            PfaPath::Directory(dir) => {
+                self.shard_directory(dir, "/");
+                if dir.sharded {
+                    sharded.push(0);
+                }
+                entries.push(PfaCatalogEntry::directory(dir, 1)?);
+                let fields = &self.header_fields;
+                let limit = self.depth_limit;
+                Self::layout_directory(dir, fields, limit, &mut entries, &mut jobs, &mut sharded)?;
+            }
+            PfaPath::File(file) => {
+                entries.push(PfaCatalogEntry::file(file));
+                jobs.push(FileJob::new(0, "/".to_string(), file.name.clone(), file));
+            }
+        }
+        if let Some(log) = &self.build_log {
+            let archive_id = self
+                .header_fields
+                .archive_id
+                .map(|x| x.to_string())
+                .unwrap_or_default();
+            log.build_started(&self.name, &archive_id, jobs.len())?;
+        }
+
+        self.header_fields.tiers = jobs
+            .iter()
+            .filter_map(|job| Some((job.slot, job.tier?)))
+            .map(|(slot, tier)| {
+                u32::try_from(slot)
+                    .map(|slot| (slot, tier))
+                    .map_err(|_| PfaError::WriterOverflowError("tiered entry index"))
+            })
+            .collect::<Result<_, _>>()?;
+        self.header_fields.tiers.sort_by_key(|x| x.0);
+        if !self.strip_provenance {
+            self.header_fields.provenance = jobs
+                .iter_mut()
+                .filter_map(|job| Some((job.slot, job.provenance.take()?)))
+                .map(|(slot, provenance)| {
+                    u32::try_from(slot)
+                        .map(|slot| (slot, provenance))
+                        .map_err(|_| PfaError::WriterOverflowError("provenance entry index"))
+                })
+                .collect::<Result<_, _>>()?;
+            self.header_fields.provenance.sort_by_key(|x| x.0);
+        }
+        self.header_fields.transforms = jobs
+            .iter_mut()
+            .filter_map(|job| Some((job.slot, job.transforms.take()?)))
+            .map(|(slot, chain)| {
+                u32::try_from(slot)
+                    .map(|slot| (slot, chain))
+                    .map_err(|_| PfaError::WriterOverflowError("transformed entry index"))
+            })
+            .collect::<Result<_, _>>()?;
+        self.header_fields.transforms.sort_by_key(|x| x.0);
+        sharded.sort();
+        self.header_fields.sharded_directories = sharded;
+
+        for job in &mut jobs {
+            job.sniff = self.sniff_content_types;
+        }
+
+        let solid = self.take_solid_groups(&mut jobs)?;
+        self.write_file_data(jobs, &mut entries)?;
+        for group in solid {
+            self.write_solid_block(group, &mut entries)?;
+        }
+
+        self.header_fields.compression_skipped.sort();
+        self.header_fields.original_sizes.sort_by_key(|x| x.0);
+        self.header_fields.entry_content_types.sort_by_key(|x| x.0);
+        self.header_fields.entry_key_ids.sort_by_key(|x| x.0);
+        self.header_fields.solid_members.sort_by_key(|x| x.0);
+        self.header_fields.ciphers.sort_by_key(|x| x.0);
+
+        // children always come after their directory, so they are hashed first
+        for slot in (0..entries.len()).rev() {
+            let Some(name) = entries[slot].name.strip_suffix('/') else {
+                continue;
+            };
+            let children = child_slots(slot, &entries[slot])?;
+            let hashes = entries
+                .get(children)
+                .ok_or(PfaError::WriterOverflowError("catalog slice index"))?
+                .iter()
+                .map(|x| &x.hash);
+            entries[slot].hash = entry_hash::directory_hash(name, hashes);
+        }
+
+        Ok(entries)
+    }
+
+    /// Name stored in the catalog for an entry of the directory at `prefix`, without the trailing
+    /// '/' of directories
+    fn stored_name(fields: &HeaderFields, prefix: &str, path: &PfaPath) -> String {
+        let encoding = fields.name_encoding;
+        match path {
+            PfaPath::Directory(dir) if dir.bucket => dir.name.clone(),
+            PfaPath::Directory(dir) => {
+                let path = format!("{prefix}{}/", dir.name);
+                encoding.encode(fields.archive_id, &path, &dir.name)
+            }
+            PfaPath::File(file) => {
+                let path = format!("{prefix}{}", file.name);
+                encoding.encode(fields.archive_id, &path, &file.name)
+            }
+        }
+    }
+
+    /// Splits directories with too many entries into buckets, by the hash of each entry's stored
+    /// name
+    fn shard_directory(&self, root: &mut PfaDirectory, prefix: &str) {
+        let mut stack = vec![(root, prefix.to_string())];
+        while let Some((dir, prefix)) = stack.pop() {
+            self.shard_entries(dir, &prefix);
+            // the entries of a bucket are still named relative to its directory
+            for path in &mut dir.contents {
+                let PfaPath::Directory(child) = path else {
+                    continue;
+                };
+                if !child.bucket {
+                    let prefix = format!("{prefix}{}/", child.name);
+                    stack.push((child, prefix));
+                    continue;
+                }
+                for path in &mut child.contents {
+                    if let PfaPath::Directory(child) = path {
+                        let prefix = format!("{prefix}{}/", child.name);
+                        stack.push((child, prefix));
                     }
-                    for (idx, path) in catalog_idx.iter().zip(dir.contents.iter()) {
-                        match path {
-                            PfaPath::Directory(dir) => {
-                                let idx = *idx;
-                                state.writer.buf.seek(SeekFrom::End(0))?;
-                                let end_pos =
-                                    (state.writer.buf.position() - idx) / ENTRY_SIZE as u64;
-                                write_catalog_inner(state, path)?;
-                                state.writer.buf.set_position(idx);
-                                state.writer.write_catalog_entry(
-                                    &dir.name,
-                                    &PfaCatalogSlice {
-                                        index: end_pos,
-                                        size: dir.contents.len() as u64,
-                                        flags: 0,
-                                    },
-                                )?;
-                                state.catalog_len += 1;
-                                state.writer.buf.seek(SeekFrom::End(0))?;
-                            }
-                            PfaPath::File(_) => {
-                                state.writer.buf.set_position(*idx);
-                                write_catalog_inner(state, path)?;
-                            }
-                        }
+                }
+            }
+        }
+    }
+
+    fn shard_entries(&self, dir: &mut PfaDirectory, prefix: &str) {
+        let Some(count) = self.directory_limits.buckets(dir.contents.len()) else {
+            return;
+        };
+
+        let mut buckets: Vec<_> = (0..count)
+            .map(|i| {
+                let mut bucket = PfaDirectory::new(&i.to_string(), vec![]);
+                bucket.bucket = true;
+                bucket
+            })
+            .collect();
+        for path in std::mem::take(&mut dir.contents) {
+            let name = Self::stored_name(&self.header_fields, prefix, &path);
+            let bucket = sharding::shard_bucket(&name, count as u64) as usize;
+            buckets[bucket].contents.push(path);
+        }
+        dir.contents = buckets.into_iter().map(PfaPath::Directory).collect();
+        dir.sharded = true;
+    }
+
+    /// Lays out the entries within `root`, whose catalog entry is at slot 0. Directories are laid
+    /// out in the order a recursive depth first walk would reach them.
+    fn layout_directory(
+        root: &mut PfaDirectory,
+        fields: &HeaderFields,
+        depth_limit: usize,
+        entries: &mut Vec<PfaCatalogEntry>,
+        jobs: &mut Vec<FileJob>,
+        sharded: &mut Vec<u32>,
+    ) -> Result<(), PfaError> {
+        enum Item<'a> {
+            Directory {
+                dir: &'a mut PfaDirectory,
+                prefix: String,
+                slot: usize,
+                depth: usize,
+            },
+            File {
+                file: &'a mut PfaFile,
+                path: String,
+                slot: usize,
+            },
+        }
+
+        let mut stack = vec![Item::Directory {
+            dir: root,
+            prefix: "/".to_string(),
+            slot: 0,
+            depth: 0,
+        }];
+        while let Some(item) = stack.pop() {
+            let (dir, prefix, slot, depth) = match item {
+                Item::File { file, path, slot } => {
+                    let name = entries[slot].name.clone();
+                    jobs.push(FileJob::new(slot, path, name, file));
+                    continue;
+                }
+                Item::Directory {
+                    dir,
+                    prefix,
+                    slot,
+                    depth,
+                } => (dir, prefix, slot, depth),
+            };
+            if depth > depth_limit {
+                return Err(PfaError::DepthLimitError {
+                    path: prefix,
+                    limit: depth_limit,
+                });
+            }
+            let offset = entries
+                .len()
+                .checked_sub(slot)
+                .ok_or(PfaError::WriterOverflowError("catalog slice index"))?;
+            entries[slot].offset = to_u64(offset, "catalog slice index")?;
+            if slot != 0 && dir.sharded {
+                sharded.push(
+                    u32::try_from(slot)
+                        .map_err(|_| PfaError::WriterOverflowError("sharded entry index"))?,
+                );
+            }
+
+            let first = entries.len();
+            for path in &dir.contents {
+                let mut entry = match path {
+                    PfaPath::Directory(dir) => PfaCatalogEntry::directory(dir, 0)?,
+                    PfaPath::File(file) => PfaCatalogEntry::file(file),
+                }; // pre allocate catalog
+                if fields.name_encoding != NameEncoding::Plain {
+                    entry.name = Self::stored_name(fields, &prefix, path);
+                    if let PfaPath::Directory(_) = path {
+                        entry.name.push('/');
                     }
                 }
-                PfaPath::File(file) => {
-                    let data_idx = state.writer.data.len();
-
-                    let (buf, flags) = file
-                        .flags
-                        .clone()
-                        .process_content_and_generate_flags(&file.contents);
-
-                    state.writer.data.append(&mut buf.clone());
-                    state.writer.write_data_entry(
-                        &file.name,
-                        &PfaDataSlice {
-                            offset: data_idx as u64,
-                            size: buf.len() as u64,
-                            flags,
+                entries.push(entry);
+            }
+
+            let children = (first..entries.len()).zip(dir.contents.iter_mut()).rev();
+            for (slot, path) in children {
+                stack.push(match path {
+                    // buckets don't change the paths of their entries
+                    PfaPath::Directory(dir) => match dir.bucket {
+                        true => Item::Directory {
+                            dir,
+                            prefix: prefix.clone(),
+                            slot,
+                            depth,
+                        },
+                        false => Item::Directory {
+                            prefix: format!("{prefix}{}/", dir.name),
+                            dir,
+                            slot,
+                            depth: depth + 1,
                         },
-                    )?;
-                    state.catalog_len += 1;
+                    },
+                    PfaPath::File(file) => Item::File {
+                        path: format!("{prefix}{}", file.name),
+                        file,
+                        slot,
+                    },
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_file_data(
+        &mut self,
+        jobs: Vec<FileJob>,
+        entries: &mut [PfaCatalogEntry],
+    ) -> Result<(), PfaError> {
+        #[cfg(feature = "rayon")]
+        if let Some(pool) = self.thread_pool.clone().filter(|_| jobs.len() > 1) {
+            return self.write_file_data_pooled(&pool, jobs, entries);
+        }
+        if self.threads > 1 && jobs.len() > 1 {
+            return self.write_file_data_threaded(jobs, entries);
+        }
+        let log = self.build_log.clone();
+        for job in jobs {
+            let processed = job.read(log.as_deref())?.process();
+            self.append_processed(entries, processed)?;
+        }
+        Ok(())
+    }
+
+    /// Sources are read on one thread and processed on a pool of threads, while the results are
+    /// appended to the data section in the original order so the output doesn't depend on
+    /// scheduling. Readers which can be processed as they're read are read by the pool instead.
+    fn write_file_data_threaded(
+        &mut self,
+        jobs: Vec<FileJob>,
+        entries: &mut [PfaCatalogEntry],
+    ) -> Result<(), PfaError> {
+        let threads = self.threads;
+        let log = self.build_log.clone();
+        ordered_pipeline::run(
+            Self::read_jobs(jobs, log.as_deref()),
+            threads,
+            threads,
+            FileJob::process,
+            |processed| self.append_processed(entries, processed),
+        )
+    }
+
+    /// Processes files in batches on the thread pool, reading the next batch while the current
+    /// one is processed. Unlike [`Self::write_file_data_threaded`] nothing blocks on the pool, so
+    /// this works when called from a task running on the same pool.
+    #[cfg(feature = "rayon")]
+    fn write_file_data_pooled(
+        &mut self,
+        pool: &rayon::ThreadPool,
+        jobs: Vec<FileJob>,
+        entries: &mut [PfaCatalogEntry],
+    ) -> Result<(), PfaError> {
+        let log = self.build_log.clone();
+        ordered_pipeline::run_pooled(
+            pool,
+            Self::read_jobs(jobs, log.as_deref()),
+            pool.current_num_threads() * 2,
+            FileJob::process,
+            |processed| self.append_processed(entries, processed),
+        )
+    }
+
+    /// Reads the sources of `jobs` as they're pulled, stopping at the first which fails
+    fn read_jobs<'a>(
+        jobs: Vec<FileJob>,
+        log: Option<&'a BuildLog>,
+    ) -> impl Iterator<Item = Produced<FileJob<ReadSource>, Result<ProcessedFile, PfaError>>> + Send + 'a
+    {
+        jobs.into_iter().map(move |job| match job.read(log) {
+            Ok(job) => Produced::Work(job),
+            Err(e) => Produced::Last(Err(e)),
+        })
+    }
+
+    fn append_processed(
+        &mut self,
+        entries: &mut [PfaCatalogEntry],
+        processed: Result<ProcessedFile, PfaError>,
+    ) -> Result<(), PfaError> {
+        let processed = processed?;
+        self.append_file_data(&mut entries[processed.slot], processed)
+    }
+
+    /// Takes the files which can be stored in solid blocks out of `jobs`, grouped into blocks of
+    /// files processed with the same flags, in their original order. Files known not to compress
+    /// are left out, along with reader sources since their size isn't known up front.
+    fn take_solid_groups(
+        &self,
+        jobs: &mut Vec<FileJob>,
+    ) -> Result<Vec<Vec<FileJob<SolidSource>>>, PfaError> {
+        let Some((max_file_size, block_size)) = self.solid_blocks else {
+            return Ok(vec![]);
+        };
+        let mut solid = vec![];
+        for job in std::mem::take(jobs) {
+            let size = match &job.source {
+                _ if job.compression_skipped || !job.flags.allows_solid() => None,
+                PfaFileSource::Bytes(contents) => Some(to_u64(contents.len(), "file size")?),
+                PfaFileSource::Path(path) => Some(std::fs::metadata(path)?.len()),
+                _ => None,
+            };
+            let Some(size) = size.filter(|x| *x <= max_file_size) else {
+                jobs.push(job);
+                continue;
+            };
+            let (job, source) = job.replace_source(());
+            let source = match source {
+                PfaFileSource::Bytes(contents) => SolidSource::Bytes(contents),
+                PfaFileSource::Path(path) => SolidSource::Path(path),
+                source => {
+                    jobs.push(job.replace_source(source).0);
+                    continue;
                 }
             };
-            Ok(())
+            solid.push((size, job.replace_source(source).0));
         }
 
-        let mut state = CatalogState {
-            writer: self,
-            catalog_len,
+        // fingerprint of the flags, size so far and files of the block being filled for each
+        let mut open: Vec<([u8; 32], u64, Vec<FileJob<SolidSource>>)> = vec![];
+        let mut groups = vec![];
+        for (size, job) in solid {
+            let fingerprint = job.flags.fingerprint();
+            match open.iter_mut().find(|x| x.0 == fingerprint) {
+                Some(block) if block.1.saturating_add(size) <= block_size => {
+                    block.1 += size;
+                    block.2.push(job);
+                }
+                Some(block) => {
+                    groups.push(std::mem::replace(block, (fingerprint, size, vec![job])).2)
+                }
+                None => open.push((fingerprint, size, vec![job])),
+            }
+        }
+        groups.extend(open.into_iter().map(|x| x.2));
+        Ok(groups)
+    }
+
+    /// Compresses the files of `jobs` together as one solid block. Each file gets an entry with
+    /// an empty data slice at the block's offset, and its range of the block is recorded in the
+    /// header. The files are stored on their own instead when there is only one, or compressing
+    /// them together doesn't make them smaller.
+    fn write_solid_block(
+        &mut self,
+        jobs: Vec<FileJob<SolidSource>>,
+        entries: &mut [PfaCatalogEntry],
+    ) -> Result<(), PfaError> {
+        let log = self.build_log.clone();
+        let jobs = jobs
+            .into_iter()
+            .map(|job| job.read(log.as_deref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let raw_size = jobs.iter().map(|job| job.source.len()).sum::<usize>();
+        let compressed = match &jobs[..] {
+            [first, _, ..] => {
+                let mut raw = Vec::with_capacity(raw_size);
+                for job in &jobs {
+                    raw.extend_from_slice(&job.source);
+                }
+                let flags = first
+                    .flags
+                    .clone()
+                    .compression_type(DataCompressionType::Forced(true));
+                Some(flags.process_content_and_generate_flags(&raw)?)
+                    .filter(|(data, _)| data.len() < raw_size)
+            }
+            _ => None,
+        };
+        let Some((data, flags)) = compressed else {
+            for job in jobs {
+                let (job, contents) = job.replace_source(());
+                let processed = job.replace_source(ReadSource::Bytes(contents)).0.process();
+                self.append_processed(entries, processed)?;
+            }
+            return Ok(());
         };
 
-        write_catalog_inner(&mut state, &file)?;
-        let catalog_len = state.catalog_len;
-        self.buf.set_position(catalog_len_idx);
-        self.buf.write_u64::<LittleEndian>(catalog_len)?;
-        self.buf.seek(SeekFrom::End(0))?;
+        let block = u32::try_from(self.header_fields.solid_blocks.len())
+            .map_err(|_| PfaError::WriterOverflowError("solid block index"))?;
+        let offset = self.data_len()?;
+        let size = to_u64(data.len(), "data size")?;
+        offset
+            .checked_add(size)
+            .ok_or(PfaError::WriterOverflowError("data section"))?;
+        let block_hash = blake3::hash(&data);
+        let mut member_offset = 0;
+        for job in jobs {
+            let contents = &job.source;
+            let member = SolidMember {
+                block,
+                offset: member_offset,
+                size: to_u64(contents.len(), "data size")?,
+            };
+            member_offset += member.size;
 
+            let entry = &mut entries[job.slot];
+            entry.flags = flags;
+            entry.offset = offset;
+            entry.size = 0;
+            entry.hash = entry_hash::file_hash_of_digest(
+                &job.name,
+                flags,
+                &member.digest(block_hash.as_bytes()),
+            );
+            let slot = u32::try_from(job.slot)
+                .map_err(|_| PfaError::WriterOverflowError("solid member index"))?;
+            let content_type = job.content_type.clone().or_else(|| {
+                job.sniff
+                    .then(|| sniff_content_type(&job.path, contents))
+                    .flatten()
+                    .map(|x| x.to_string())
+            });
+            if let Some(content_type) = content_type {
+                self.header_fields.add_content_type(slot, &content_type)?;
+            }
+            self.header_fields.solid_members.push((slot, member));
+            if let Some(log) = &self.build_log {
+                log.file_encoded(&job.path, 0, flags)?;
+                log.file_written(&job.path, offset, 0)?;
+            }
+        }
+        self.header_fields.solid_blocks.push(SolidBlock {
+            offset,
+            size,
+            flags,
+        });
+        self.data.extend_from_slice(&data);
         Ok(())
     }
 
-    fn write_data_entry(&mut self, filename: &str, slice: &PfaDataSlice) -> Result<(), PfaError> {
-        self.write_nulled_fixed_size_string(filename, PfaPath::MAX_SIZE)?;
-        self.buf.write_u8(slice.flags)?;
-        self.buf.write_u64::<LittleEndian>(slice.size)?;
-        self.buf.write_u64::<LittleEndian>(slice.offset)?;
+    fn append_file_data(
+        &mut self,
+        entry: &mut PfaCatalogEntry,
+        processed: ProcessedFile,
+    ) -> Result<(), PfaError> {
+        entry.flags = processed.flags;
+        (entry.offset, entry.size) = match processed.spilled {
+            Some(location) => location,
+            None => (self.data_len()?, to_u64(processed.data.len(), "data size")?),
+        };
+        entry.hash = processed.hash;
+        entry
+            .offset
+            .checked_add(entry.size)
+            .ok_or(PfaError::WriterOverflowError("data section"))?;
+        if processed.compression_skipped {
+            self.header_fields.compression_skipped.push(
+                u32::try_from(processed.slot)
+                    .map_err(|_| PfaError::WriterOverflowError("skipped entry index"))?,
+            );
+        }
+        if let Some(content_type) = &processed.content_type {
+            let slot = u32::try_from(processed.slot)
+                .map_err(|_| PfaError::WriterOverflowError("typed entry index"))?;
+            self.header_fields.add_content_type(slot, content_type)?;
+        }
+        if let Some(key_id) = processed.key_id {
+            let slot = u32::try_from(processed.slot)
+                .map_err(|_| PfaError::WriterOverflowError("encrypted entry index"))?;
+            self.header_fields.add_key_id(slot, key_id)?;
+        }
+        if let Some(cipher) = processed.cipher.filter(|x| *x != Cipher::Aes256Gcm) {
+            let slot = u32::try_from(processed.slot)
+                .map_err(|_| PfaError::WriterOverflowError("enciphered entry index"))?;
+            self.header_fields.ciphers.push((slot, cipher));
+        }
+        if let Some(size) = processed.original_size {
+            let slot = u32::try_from(processed.slot)
+                .map_err(|_| PfaError::WriterOverflowError("sized entry index"))?;
+            self.header_fields.original_sizes.push((slot, size));
+        }
+        if let Some(log) = &self.build_log {
+            log.file_encoded(&processed.path, entry.size, entry.flags)?;
+        }
+        self.data.extend_from_slice(&processed.data);
+        if let Some(log) = &self.build_log {
+            log.file_written(&processed.path, entry.offset, entry.size)?;
+        }
+
         Ok(())
     }
 
-    fn write_catalog_entry(
+    fn write_catalog(
         &mut self,
-        filename: &str,
-        slice: &PfaCatalogSlice,
+        entries: &[PfaCatalogEntry],
+        slots_start: usize,
+        layout: CatalogLayout,
     ) -> Result<(), PfaError> {
-        self.write_nulled_fixed_size_string(&format!("{}/", filename), PfaPath::MAX_SIZE)?;
-        self.buf.write_u8(slice.flags)?;
-        self.buf.write_u64::<LittleEndian>(slice.size)?;
-        self.buf.write_u64::<LittleEndian>(slice.index)?;
+        let catalog_start = self.buf.position();
+        self.buf
+            .write_u64::<LittleEndian>(to_u64(entries.len(), "catalog length")?)?;
+        for entry in &entries[..slots_start] {
+            self.write_catalog_entry(entry, layout)?;
+        }
+        for entry in &entries[slots_start..] {
+            write_reserved_slot(&mut self.buf, entry, layout)?;
+        }
+
+        debug_assert_eq!(
+            self.buf.position() - catalog_start,
+            8 + entries[..slots_start]
+                .iter()
+                .map(|x| layout.entry_size(&x.name) as u64)
+                .sum::<u64>()
+                + ((entries.len() - slots_start) * layout.reserved_slot_size()) as u64,
+            "catalog size does not match the entries written"
+        );
+
         Ok(())
     }
 
-    fn write_data(&mut self) -> Result<(), PfaError> {
-        self.buf.write_all(&self.data)?;
+    fn write_catalog_entry(
+        &mut self,
+        entry: &PfaCatalogEntry,
+        layout: CatalogLayout,
+    ) -> Result<(), PfaError> {
+        let start = self.buf.position();
+        match layout {
+            CatalogLayout::Fixed => {
+                self.write_nulled_fixed_size_string(&entry.name, CatalogLayout::FIXED_NAME_SIZE)?
+            }
+            CatalogLayout::Compact => self.write_u8_sized_string(&entry.name)?,
+        }
+        self.buf.write_u8(entry.flags)?;
+        self.buf.write_u64::<LittleEndian>(entry.size)?;
+        self.buf.write_u64::<LittleEndian>(entry.offset)?;
+        debug_assert_eq!(
+            self.buf.position() - start,
+            layout.entry_size(&entry.name) as u64
+        );
         Ok(())
     }
 }
@@ -0,0 +1,524 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    builder::{normalize_path, DuplicatePolicy, PfaBuilder},
+    shared::{Cipher, DataCompressionType, DataFlags, KeyId},
+    telemetry,
+    writer::{build_cache::BuildCache, pfa_writer::PfaFileSource},
+    PfaError,
+};
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"pfackpt\0";
+const CHECKPOINT_VERSION: u8 = 1;
+
+/// A file whose processed data is in the spill, as recorded in a checkpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SpilledFile {
+    offset: u64,
+    size: u64,
+    flags: u8,
+    contents_hash: [u8; 32],
+    key_id: Option<KeyId>,
+    cipher: Option<Cipher>,
+    compression_skipped: bool,
+    /// Size of the contents
+    original_size: u64,
+    /// Identifies the data flags the file was processed with
+    flags_fingerprint: [u8; 32],
+    /// The file on disk it was read from
+    source: Option<SpilledSource>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SpilledSource {
+    /// Modification time, see [`BuildCache`]
+    mtime: u128,
+    size: u64,
+    /// BLAKE3 hash of the contents
+    hash: [u8; 32],
+}
+
+impl SpilledFile {
+    fn write_to(&self, path: &str, out: &mut impl Write) -> Result<(), PfaError> {
+        let path_len = u32::try_from(path.len())
+            .map_err(|_| PfaError::WriterOverflowError("checkpoint path"))?;
+        out.write_u32::<LittleEndian>(path_len)?;
+        out.write_all(path.as_bytes())?;
+        out.write_u64::<LittleEndian>(self.offset)?;
+        out.write_u64::<LittleEndian>(self.size)?;
+        out.write_u8(self.flags)?;
+        out.write_all(&self.contents_hash)?;
+        match self.key_id {
+            Some(key_id) => {
+                out.write_u8(1)?;
+                out.write_all(&key_id)?;
+            }
+            None => out.write_u8(0)?,
+        }
+        out.write_u8(self.cipher.map_or(u8::MAX, Cipher::to_u8))?;
+        out.write_u8(u8::from(self.compression_skipped))?;
+        out.write_u64::<LittleEndian>(self.original_size)?;
+        out.write_all(&self.flags_fingerprint)?;
+        match self.source {
+            Some(source) => {
+                out.write_u8(1)?;
+                out.write_u128::<LittleEndian>(source.mtime)?;
+                out.write_u64::<LittleEndian>(source.size)?;
+                out.write_all(&source.hash)?;
+            }
+            None => out.write_u8(0)?,
+        }
+        Ok(())
+    }
+
+    fn read_from(buf: &mut impl Read) -> Result<(String, Self), PfaError> {
+        let invalid = || PfaError::CustomError("invalid build checkpoint".into());
+        let read_hash = |buf: &mut dyn Read| -> Result<[u8; 32], PfaError> {
+            let mut hash = [0; 32];
+            buf.read_exact(&mut hash)?;
+            Ok(hash)
+        };
+        let path_len = buf.read_u32::<LittleEndian>()? as usize;
+        let mut path = vec![];
+        buf.take(path_len as u64).read_to_end(&mut path)?;
+        if path.len() != path_len {
+            return Err(invalid());
+        }
+        let path = String::from_utf8(path).map_err(|_| invalid())?;
+        let offset = buf.read_u64::<LittleEndian>()?;
+        let size = buf.read_u64::<LittleEndian>()?;
+        let flags = buf.read_u8()?;
+        let contents_hash = read_hash(buf)?;
+        let key_id = match buf.read_u8()? {
+            0 => None,
+            _ => {
+                let mut key_id = KeyId::default();
+                buf.read_exact(&mut key_id)?;
+                Some(key_id)
+            }
+        };
+        let cipher = match buf.read_u8()? {
+            u8::MAX => None,
+            value => Some(Cipher::from_u8(value).ok_or_else(invalid)?),
+        };
+        let compression_skipped = buf.read_u8()? != 0;
+        let original_size = buf.read_u64::<LittleEndian>()?;
+        let flags_fingerprint = read_hash(buf)?;
+        let source = match buf.read_u8()? {
+            0 => None,
+            _ => Some(SpilledSource {
+                mtime: buf.read_u128::<LittleEndian>()?,
+                size: buf.read_u64::<LittleEndian>()?,
+                hash: read_hash(buf)?,
+            }),
+        };
+        let file = SpilledFile {
+            offset,
+            size,
+            flags,
+            contents_hash,
+            key_id,
+            cipher,
+            compression_skipped,
+            original_size,
+            flags_fingerprint,
+            source,
+        };
+        Ok((path, file))
+    }
+}
+
+/// Builds an archive whose files are processed and written to `spill` (such as a temporary file)
+/// as they are added, instead of being kept in memory until the archive is built. Only the
+/// catalog is kept in memory, so memory use grows with the number of files rather than their
+/// size. Building writes the header and catalog, then copies the spill as the data section.
+///
+/// Options and metadata are set on the underlying builder, see [`Self::builder`]. Options which
+/// change how files are processed, such as default flags or the encryption key, only apply to
+/// files added after they are set. Store only archives can't be built this way.
+///
+/// Long builds can write checkpoints (see [`Self::checkpoint`]), recording the files which have
+/// been spilled so far, so an interrupted build can [resume](Self::resume) with the same spill.
+pub struct PfaStreamingBuilder<S: Read + Write + Seek + Send + 'static> {
+    builder: PfaBuilder,
+    spill: S,
+    /// Position of the spilled data in `spill`
+    start: u64,
+    spilled: u64,
+    files: HashMap<String, SpilledFile>,
+    /// Files restored from a checkpoint which were read from disk, and haven't been added again
+    /// since, see [`Self::resume`]
+    restored: HashSet<String>,
+    /// Path checkpoints are written to, and the number of spilled bytes between them
+    checkpoint: Option<(PathBuf, u64)>,
+    /// Number of spilled bytes when the last checkpoint was written
+    checkpointed: u64,
+}
+
+impl<S: Read + Write + Seek + Send + 'static> PfaStreamingBuilder<S> {
+    /// Spilled data is written from the current position of `spill`
+    pub fn new(name: &str, mut spill: S) -> Result<Self, PfaError> {
+        let start = spill.stream_position()?;
+        Ok(Self {
+            builder: PfaBuilder::new(name),
+            spill,
+            start,
+            spilled: 0,
+            files: HashMap::new(),
+            restored: HashSet::new(),
+            checkpoint: None,
+            checkpointed: 0,
+        })
+    }
+
+    /// Continues a build from a checkpoint written by [`Self::checkpoint`], with the spill it was
+    /// written for positioned where the spilled data starts. Files spilled after the checkpoint
+    /// are overwritten. Options and metadata aren't part of checkpoints, so they must be set
+    /// on the builder again. Restored files which were read from disk are left out of the
+    /// archive unless they're added again (see [`Self::add_file_from_path`]), so files deleted
+    /// since aren't built into it. Those spilled from readers stay in it.
+    pub fn resume(name: &str, spill: S, checkpoint: impl Read) -> Result<Self, PfaError> {
+        let invalid = || PfaError::CustomError("invalid build checkpoint".into());
+        let mut checkpoint = BufReader::new(checkpoint);
+        let mut magic = [0; CHECKPOINT_MAGIC.len()];
+        checkpoint.read_exact(&mut magic).map_err(|_| invalid())?;
+        if magic != *CHECKPOINT_MAGIC || checkpoint.read_u8().ok() != Some(CHECKPOINT_VERSION) {
+            return Err(invalid());
+        }
+        let spilled = checkpoint
+            .read_u64::<LittleEndian>()
+            .map_err(|_| invalid())?;
+
+        let mut streaming = Self::new(name, spill)?;
+        let available = streaming.spill.seek(SeekFrom::End(0))? - streaming.start;
+        if available < spilled {
+            return Err(PfaError::CustomError(format!(
+                "spill holds {available} bytes, but the checkpoint needs {spilled}"
+            )));
+        }
+        let num_files = checkpoint
+            .read_u64::<LittleEndian>()
+            .map_err(|_| invalid())?;
+        for _ in 0..num_files {
+            let (path, file) = SpilledFile::read_from(&mut checkpoint).map_err(|_| invalid())?;
+            if file
+                .offset
+                .checked_add(file.size)
+                .is_none_or(|x| x > spilled)
+            {
+                return Err(invalid());
+            }
+            if file.source.is_some() {
+                streaming.restored.insert(path.clone());
+            }
+            streaming.add_spilled(&path, file)?;
+        }
+        streaming.spilled = spilled;
+        streaming.checkpointed = spilled;
+        Ok(streaming)
+    }
+
+    /// The builder holding the catalog. Files added to it directly are kept in memory until the
+    /// archive is built, like with [`PfaBuilder`].
+    pub fn builder(&mut self) -> &mut PfaBuilder {
+        &mut self.builder
+    }
+
+    /// Number of bytes written to the spill so far
+    pub fn spilled(&self) -> u64 {
+        self.spilled
+    }
+
+    /// Writes a checkpoint to `path` whenever `interval` more bytes have been spilled since the
+    /// last one, replacing the previous checkpoint
+    pub fn set_checkpoint(&mut self, path: impl Into<PathBuf>, interval: u64) {
+        self.checkpoint = Some((path.into(), interval));
+    }
+
+    /// Records every file spilled so far:
+    ///
+    /// ```text
+    /// {magic:u8[8]="pfackpt\0"}{version:u8=1}{spilled:u64}{num_files:u64}
+    /// {files:{path_len:u32}{path:u8[path_len]}{offset:u64}{size:u64}{flags:u8}
+    ///     {contents_hash:u8[32]}{has_key_id:u8}{key_id:u8[8]?}{cipher:u8=0xFF for none}
+    ///     {compression_skipped:u8}{original_size:u64}{flags_fingerprint:u8[32]}
+    ///     {has_source:u8}{source_mtime:u128?}{source_size:u64?}{source_hash:u8[32]?}[num_files]}
+    /// ```
+    ///
+    /// Integers are little endian. The spill is flushed first, so the checkpoint never refers
+    /// to data which wasn't written. Files added to [`Self::builder`] directly aren't recorded.
+    pub fn checkpoint(&mut self, out: &mut impl Write) -> Result<(), PfaError> {
+        self.spill.flush()?;
+        out.write_all(CHECKPOINT_MAGIC)?;
+        out.write_u8(CHECKPOINT_VERSION)?;
+        out.write_u64::<LittleEndian>(self.spilled)?;
+        out.write_u64::<LittleEndian>(self.files.len() as u64)?;
+
+        let mut files = self.files.iter().collect::<Vec<_>>();
+        files.sort_by_key(|(_, file)| file.offset);
+        for (path, file) in files {
+            file.write_to(path, out)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a checkpoint to `path`, replacing the previous one only once the new one is
+    /// complete
+    pub fn checkpoint_to(&mut self, path: impl AsRef<Path>) -> Result<(), PfaError> {
+        let path = path.as_ref();
+        let mut partial = path.as_os_str().to_os_string();
+        partial.push(".partial");
+        let mut out = std::io::BufWriter::new(File::create(&partial)?);
+        self.checkpoint(&mut out)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&partial, path)?;
+        self.checkpointed = self.spilled;
+        Ok(())
+    }
+
+    /// Processes the contents of `reader` and writes them to the spill. Contents are processed a
+    /// chunk at a time where [`PfaBuilder::add_file_from_reader`] would, others are read whole
+    /// first. Duplicate paths are handled before anything is read, but the spilled data of a
+    /// file which is overwritten stays in the archive, unreferenced, until it's compacted (see
+    /// [`PfaReader::compact`](crate::reader::PfaReader::compact)).
+    pub fn add_file_from_reader(
+        &mut self,
+        path: &str,
+        reader: impl Read,
+        flags: DataFlags,
+    ) -> Result<(), PfaError> {
+        self.spill_file(path, reader, flags, None)
+    }
+
+    /// `source` is the modification time and size of the file on disk `reader` reads
+    fn spill_file(
+        &mut self,
+        path: &str,
+        reader: impl Read,
+        flags: DataFlags,
+        source: Option<(u128, u64)>,
+    ) -> Result<(), PfaError> {
+        let path = &normalize_path(path);
+        if path.ends_with('/') {
+            return Err(PfaError::CustomError(
+                "called add_file_from_reader but provided a directory".into(),
+            ));
+        }
+        // a restored file is replaced rather than treated as a duplicate
+        if self.restored.remove(path) {
+            self.builder.remove(path)?;
+            self.files.remove(path);
+        }
+        if self.builder.contains_file(path) {
+            match self.builder.duplicate_policy() {
+                DuplicatePolicy::Error => return Err(PfaError::DuplicatePath(path.to_string())),
+                DuplicatePolicy::KeepFirst => return Ok(()),
+                DuplicatePolicy::Overwrite => {}
+            }
+        }
+        let flags = self.builder.resolve_flags(path, flags);
+        let key_id = flags.encryption_key_id();
+        let cipher = flags.encryption_cipher();
+        let flags_fingerprint = flags.fingerprint();
+        let skip_compression = self.builder.skips_compression(path, &flags);
+        let flags = match skip_compression {
+            true => {
+                telemetry::increment(telemetry::EXTENSION_SKIPS, 1);
+                flags.compression_type(DataCompressionType::Forced(false))
+            }
+            false => flags,
+        };
+        let offset = self.spilled;
+        self.spill.seek(SeekFrom::Start(self.start + offset))?;
+
+        let mut reader = Hashing {
+            inner: reader,
+            hasher: blake3::Hasher::new(),
+            len: 0,
+        };
+        let mut sink = Hashing {
+            inner: &mut self.spill,
+            hasher: blake3::Hasher::new(),
+            len: 0,
+        };
+        let processed = match flags.is_streamable() {
+            true => flags
+                .process_reader(&mut reader, &mut sink)
+                .map(|(bits, original_size)| (bits, false, original_size)),
+            false => Self::process_whole(flags, &mut reader, &mut sink),
+        };
+        let (size, contents_hash) = (sink.len, *sink.hasher.finalize().as_bytes());
+        // anything written for a file which wasn't added is overwritten by the next one
+        let (bits, tried, original_size) = processed?;
+
+        let file = SpilledFile {
+            offset,
+            size,
+            flags: bits,
+            contents_hash,
+            key_id: key_id.filter(|_| DataFlags::is_encrypted(bits)),
+            cipher: cipher.filter(|_| DataFlags::is_encrypted(bits)),
+            compression_skipped: (tried || skip_compression) && !DataFlags::is_compressed(bits),
+            original_size,
+            flags_fingerprint,
+            source: source.map(|(mtime, size)| SpilledSource {
+                mtime,
+                size,
+                hash: *reader.hasher.finalize().as_bytes(),
+            }),
+        };
+        self.add_spilled(path, file)?;
+        self.spilled = offset
+            .checked_add(size)
+            .ok_or(PfaError::WriterOverflowError("spilled data"))?;
+
+        if let Some((checkpoint, interval)) = &self.checkpoint {
+            if self.spilled - self.checkpointed >= *interval {
+                let checkpoint = checkpoint.clone();
+                self.checkpoint_to(checkpoint)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a file whose data is already in the spill to the catalog
+    fn add_spilled(&mut self, path: &str, file: SpilledFile) -> Result<(), PfaError> {
+        let source = PfaFileSource::Spilled {
+            offset: file.offset,
+            size: file.size,
+            flags: file.flags,
+            contents_hash: file.contents_hash,
+        };
+        self.builder.add_spilled_file(path, source)?;
+        if let Some(key_id) = file.key_id {
+            self.builder.set_key_id(path, key_id)?;
+        }
+        if let Some(cipher) = file.cipher {
+            self.builder.set_cipher(path, cipher)?;
+        }
+        if file.compression_skipped {
+            self.builder.set_compression_skipped(path)?;
+        }
+        if !DataFlags::is_unprocessed(file.flags) {
+            self.builder.set_original_size(path, file.original_size)?;
+        }
+        self.files.insert(path.to_string(), file);
+        Ok(())
+    }
+
+    /// Returns the flags of the processed data, whether compression was tried, and the size of
+    /// the contents
+    fn process_whole(
+        flags: DataFlags,
+        reader: &mut impl Read,
+        sink: &mut impl Write,
+    ) -> Result<(u8, bool, u64), PfaError> {
+        let mut contents = vec![];
+        reader.read_to_end(&mut contents)?;
+        let tried = flags.is_automatic_compression();
+        let (data, bits) = flags.process_content_and_generate_flags(&contents)?;
+        sink.write_all(&data)?;
+        Ok((bits, tried, contents.len() as u64))
+    }
+
+    /// Reads and spills the file at `fs_path`. When resuming from a checkpoint, a file which was
+    /// spilled from the same path with the same flags, and hasn't been modified since, isn't read
+    /// again.
+    pub fn add_file_from_path(
+        &mut self,
+        archive_path: &str,
+        fs_path: impl AsRef<Path>,
+        flags: DataFlags,
+    ) -> Result<(), PfaError> {
+        let fs_path = fs_path.as_ref();
+        let file = File::open(fs_path)
+            .map_err(|e| PfaError::CustomError(format!("open '{}': {e}", fs_path.display())))?;
+        let metadata = file.metadata()?;
+        let source = (BuildCache::mtime(&metadata), metadata.len());
+
+        let path = normalize_path(archive_path);
+        if self.restored.contains(&path) {
+            let fingerprint = self
+                .builder
+                .resolve_flags(&path, flags.clone())
+                .fingerprint();
+            let spilled = &self.files[&path];
+            let unchanged = spilled.source.is_some_and(|x| (x.mtime, x.size) == source);
+            if unchanged && spilled.flags_fingerprint == fingerprint {
+                self.restored.remove(&path);
+                return Ok(());
+            }
+        }
+        self.spill_file(&path, BufReader::new(file), flags, Some(source))
+    }
+
+    /// Adds every file in a directory, reading each of them as it's added, see
+    /// [`Self::add_file_from_path`] for files restored from a checkpoint. Files are hashed as
+    /// they're read, so recording provenance doesn't read them again.
+    pub fn include_directory(&mut self, path: &str, flags: DataFlags) -> Result<(), PfaError> {
+        for (fpath, path) in self.builder.walk_directory(path)? {
+            self.add_file_from_path(&fpath, &path, flags.clone())?;
+            let hash = self
+                .files
+                .get(&fpath)
+                .and_then(|x| x.source)
+                .map(|x| x.hash);
+            if let Some(hash) = hash {
+                self.builder.record_source(&fpath, &path, hash)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the archive to `out` from its current position, copying the spilled data after the
+    /// catalog. Returns the number of bytes written.
+    pub fn build_into(mut self, mut out: impl Write + Seek) -> Result<u64, PfaError> {
+        for path in std::mem::take(&mut self.restored) {
+            self.builder.remove(&path)?;
+        }
+        self.spill.seek(SeekFrom::Start(self.start))?;
+        let mut writer = self.builder.into_writer()?;
+        writer.set_spilled_data(Box::new(self.spill), self.spilled);
+
+        let start = out.stream_position()?;
+        writer.generate_into(&mut out)?;
+        out.flush()?;
+        Ok(out.stream_position()? - start)
+    }
+}
+
+/// Hashes and counts everything read or written through it
+struct Hashing<T> {
+    inner: T,
+    hasher: blake3::Hasher,
+    len: u64,
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.len += read as u64;
+        Ok(read)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,105 @@
+//! Ships an update to an installed archive as a diff, and rolls it back by applying the diff
+//! computed in the other direction. Patching keeps a backup until the patched archive validates,
+//! so a failed patch leaves the install as it was.
+//!
+//! cargo run -p pfadiff --example patch_and_rollback
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use pfa::{
+    builder::PfaBuilder,
+    reader::PfaReader,
+    shared::{DataFlags, Ulid},
+};
+use pfadiff_lib::{apply_diff_to_file, ApplyOptions, PfaDiff};
+
+fn write_archive(path: &Path, files: &[(&str, &str)]) -> Result<()> {
+    let mut builder = PfaBuilder::new("game");
+    for (path, contents) in files {
+        builder.add_file(path, contents.as_bytes().to_vec(), DataFlags::auto())?;
+    }
+    std::fs::write(path, builder.build()?).context("write archive")
+}
+
+/// Writes the diff from the archive at `from` to the archive at `to`
+fn write_diff(from: &Path, to: &Path, out: &Path) -> Result<()> {
+    let diff = PfaDiff::compute(&mut PfaReader::open(from)?, &mut PfaReader::open(to)?)?;
+    println!(
+        "{} -> {}: {} added, {} removed, {} changed",
+        from.file_name().unwrap().to_string_lossy(),
+        to.file_name().unwrap().to_string_lossy(),
+        diff.added().count(),
+        diff.removed().count(),
+        diff.changed().count()
+    );
+    std::fs::write(out, diff.to_archive("game_patch")?).context("write diff")
+}
+
+fn read(path: &Path, file: &str) -> Result<Option<String>> {
+    let mut reader = PfaReader::open(path)?;
+    Ok(reader
+        .get_file(file, None)?
+        .map(|x| String::from_utf8_lossy(x.get_contents()).into_owned()))
+}
+
+fn main() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("pfadiff_rollback_{}", Ulid::new()));
+    std::fs::create_dir_all(&dir)?;
+    let (v1, v2, installed) = (dir.join("v1.pfa"), dir.join("v2.pfa"), dir.join("game.pfa"));
+    write_archive(
+        &v1,
+        &[
+            ("/version.txt", "1.0"),
+            ("/maps/forest.txt", "trees\n".repeat(100).as_str()),
+            ("/maps/beta.txt", "unfinished"),
+        ],
+    )?;
+    write_archive(
+        &v2,
+        &[
+            ("/version.txt", "1.1"),
+            (
+                "/maps/forest.txt",
+                &format!("{}river\n", "trees\n".repeat(100)),
+            ),
+            ("/maps/desert.txt", "sand"),
+        ],
+    )?;
+    std::fs::copy(&v1, &installed)?;
+
+    // the update and its rollback are built ahead of time, next to the release
+    let (update, rollback) = (dir.join("update.pfa"), dir.join("rollback.pfa"));
+    write_diff(&v1, &v2, &update)?;
+    write_diff(&v2, &v1, &rollback)?;
+
+    let options = ApplyOptions::new().backup(true);
+    apply_diff_to_file(&installed, &update, &installed, &options)?;
+    println!("patched to {}", read(&installed, "/version.txt")?.unwrap());
+    assert_eq!(
+        read(&installed, "/maps/desert.txt")?.as_deref(),
+        Some("sand")
+    );
+    assert!(read(&installed, "/maps/beta.txt")?.is_none());
+
+    // applying the update again fails, since the install no longer matches v1
+    assert!(apply_diff_to_file(&installed, &update, &installed, &options).is_err());
+    assert_eq!(read(&installed, "/version.txt")?.as_deref(), Some("1.1"));
+
+    apply_diff_to_file(&installed, &rollback, &installed, &options)?;
+    println!(
+        "rolled back to {}",
+        read(&installed, "/version.txt")?.unwrap()
+    );
+    for file in [
+        "/version.txt",
+        "/maps/forest.txt",
+        "/maps/beta.txt",
+        "/maps/desert.txt",
+    ] {
+        assert_eq!(read(&installed, file)?, read(&v1, file)?);
+    }
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use pfa::reader::{pfa_reader::PfaPath, PfaReader};
+use serde::Serialize;
+
+use crate::{
+    filter::PathFilter,
+    model::PfaDiff,
+    outcome::{ApplyFailure, DiffCounts},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    filter: PathFilter,
+    backup: bool,
+}
+
+impl ApplyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Entries whose paths don't pass the filter aren't applied, leaving those files as they are
+    /// in the old archive
+    pub fn filter(mut self, filter: PathFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Copy an existing output file to `{output}.bak` before overwriting it, and put it back if
+    /// the new archive can't be written or doesn't validate. The copy is removed once the new
+    /// archive validates.
+    pub fn backup(mut self, backup: bool) -> Self {
+        self.backup = backup;
+        self
+    }
+}
+
+/// What [`apply_diff_to_file`] changed
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ApplyReport {
+    /// Entries of the diff which were applied, after filtering
+    pub counts: DiffCounts,
+    /// Text files whose patches didn't match the old contents exactly, see
+    /// [`PfaDiff::apply_with_corrections`]
+    pub corrected: Vec<String>,
+}
+
+/// Path the output file is copied to by [`ApplyOptions::backup`]
+pub fn backup_path(output: &Path) -> PathBuf {
+    let mut name = output.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".bak");
+    output.with_file_name(name)
+}
+
+/// Applies the diff at `diff` to the archive at `old`, writing the result to `output`, which may
+/// be the same file as `old`. The new archive is built completely before `output` is touched.
+/// Errors are tagged with an [`ApplyFailure`] where they fit one.
+pub fn apply_diff_to_file(
+    old: &Path,
+    diff: &Path,
+    output: &Path,
+    options: &ApplyOptions,
+) -> Result<ApplyReport> {
+    let mut diff = PfaReader::open(diff).context("open diff file")?;
+    let mut diff = PfaDiff::from_archive(&mut diff).context("read diff pfa")?;
+    diff.retain_paths(&options.filter);
+    let mut old = PfaReader::open(old).context("open old file")?;
+    let (builder, corrected) = diff.apply_with_corrections(&mut old)?;
+    let bytes = builder.build().context("build newly patched pfa")?;
+
+    if options.backup {
+        write_all_with_backup(&[(output, &bytes)], |_, path| validate(path, &diff))?;
+    } else {
+        write_file(output, &bytes).context(ApplyFailure::Write)?;
+    }
+    Ok(ApplyReport {
+        counts: DiffCounts::of(&diff),
+        corrected,
+    })
+}
+
+/// Applies a diff to the archive at `old`, returning the new archive
+pub(crate) fn build_patched(old: &Path, diff: &PfaDiff) -> Result<Vec<u8>> {
+    let mut old = PfaReader::open(old).context("open old file")?;
+    diff.apply(&mut old)?
+        .build()
+        .context("build newly patched pfa")
+}
+
+/// Checks that a patched archive opens, every file in it can be read, and it has the id and the
+/// added and removed paths the diff calls for
+pub(crate) fn validate(path: &Path, diff: &PfaDiff) -> Result<()> {
+    let mut reader = PfaReader::open(path)?;
+    if let Some(to_id) = diff.to_id {
+        if reader.get_archive_id() != Some(to_id) {
+            return Err(anyhow!("patched archive doesn't have the id {to_id}"));
+        }
+    }
+    let mut paths = HashSet::new();
+    let mut directories = vec![PfaPath::from("/")];
+    while let Some(directory) = directories.pop() {
+        let contents = reader
+            .get_directory(directory.clone(), None)?
+            .with_context(|| format!("directory {directory} is missing"))?;
+        for path in contents.get_contents() {
+            if path.is_directory() {
+                directories.push(path.clone());
+                continue;
+            }
+            reader
+                .get_file(path.clone(), None)
+                .with_context(|| format!("read file {path}"))?
+                .with_context(|| format!("file {path} is missing"))?;
+            paths.insert(path.to_string());
+        }
+    }
+    if let Some(add) = diff.added().find(|x| !paths.contains(&x.path)) {
+        return Err(anyhow!("added file {} is missing", add.path));
+    }
+    if let Some(remove) = diff.removed().find(|x| paths.contains(&x.path)) {
+        return Err(anyhow!("removed file {} is still there", remove.path));
+    }
+    Ok(())
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut file = File::create(path).context("create output file")?;
+    file.write_all(bytes).context("write newly patched pfa")?;
+    file.sync_all().context("flush newly patched pfa")?;
+    Ok(())
+}
+
+/// Writes every file and validates it with its index. When any write or validation fails, all
+/// of the previous files are restored (and files which didn't exist before are removed).
+pub(crate) fn write_all_with_backup(
+    files: &[(&Path, &[u8])],
+    validate: impl Fn(usize, &Path) -> Result<()>,
+) -> Result<()> {
+    // whether each file existed, and so has a backup
+    let mut existed = Vec::with_capacity(files.len());
+    let mut result = Ok(());
+    for (output, _) in files {
+        let exists = output.exists();
+        if exists {
+            if let Err(e) = std::fs::copy(output, backup_path(output)) {
+                result = Err(anyhow::Error::new(e)
+                    .context(format!("back up {}", output.display()))
+                    .context(ApplyFailure::Write));
+                break;
+            }
+        }
+        existed.push(exists);
+    }
+    if result.is_ok() {
+        result = files
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, (output, bytes))| {
+                write_file(output, bytes)
+                    .context(ApplyFailure::Write)
+                    .and_then(|_| validate(i, output).context(ApplyFailure::Invalid))
+                    .with_context(|| format!("write {}", output.display()))
+            });
+    }
+
+    let mut restore_failed = vec![];
+    for ((output, _), existed) in files.iter().zip(existed) {
+        let backup = backup_path(output);
+        match (&result, existed) {
+            (Ok(()), true) => std::fs::remove_file(&backup).context("remove backup")?,
+            (Ok(()), false) => {}
+            (Err(_), true) => {
+                if std::fs::rename(&backup, output).is_err() {
+                    restore_failed.push(backup.display().to_string());
+                }
+            }
+            (Err(_), false) => {
+                let _ = std::fs::remove_file(output);
+            }
+        }
+    }
+    match result {
+        Err(e) if !restore_failed.is_empty() => Err(e.context(format!(
+            "restoring backups failed, they are kept at {}",
+            restore_failed.join(", ")
+        ))),
+        Err(e) => Err(e.context("restored the backups")),
+        Ok(()) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use anyhow::anyhow;
+    use pfa::{
+        builder::PfaBuilder,
+        reader::PfaReader,
+        shared::{DataFlags, Ulid},
+    };
+
+    use super::{apply_diff_to_file, backup_path, write_all_with_backup, ApplyOptions};
+    use crate::model::PfaDiff;
+
+    #[test]
+    fn backup() {
+        let dir = std::env::temp_dir().join(format!("pfadiff_backup_{}", Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let archive = |contents: &[u8]| {
+            let mut builder = PfaBuilder::new("game");
+            builder
+                .add_file("/a.txt", contents.to_vec(), DataFlags::auto())
+                .unwrap();
+            builder.build().unwrap()
+        };
+        let old = dir.join("game.pfa");
+        let new = archive(b"new");
+        std::fs::write(&old, archive(b"old")).unwrap();
+
+        let old_bytes = std::fs::read(&old).unwrap();
+        let mut old_reader = PfaReader::new(Cursor::new(old_bytes.clone())).unwrap();
+        let mut new_reader = PfaReader::new(Cursor::new(new)).unwrap();
+        let diff = PfaDiff::compute(&mut old_reader, &mut new_reader).unwrap();
+        let diff_path = dir.join("update.pfa");
+        std::fs::write(&diff_path, diff.to_archive("update").unwrap()).unwrap();
+
+        let failed = write_all_with_backup(&[(&old, b"broken")], |_, _| Err(anyhow!("invalid")));
+        assert!(format!("{:#}", failed.unwrap_err()).contains("invalid"));
+        assert_eq!(std::fs::read(&old).unwrap(), old_bytes);
+        assert!(!backup_path(&old).exists());
+
+        let options = ApplyOptions::new().backup(true);
+        apply_diff_to_file(&old, &diff_path, &old, &options).unwrap();
+        assert!(!backup_path(&old).exists());
+        let mut patched = PfaReader::new(Cursor::new(std::fs::read(&old).unwrap())).unwrap();
+        let file = patched.get_file("/a.txt", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), b"new");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
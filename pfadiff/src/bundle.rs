@@ -0,0 +1,225 @@
+use std::{
+    io::{Read, Seek},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Result};
+use pfa::{builder::PfaBuilder, reader::PfaReader, shared::DataFlags};
+
+use crate::{
+    apply::{build_patched, validate, write_all_with_backup},
+    filter::PathFilter,
+    model::{DiffOptions, PfaDiff},
+};
+
+/// The diff for one archive of a [`PatchBundle`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleTarget {
+    /// Path of the archive, relative to the directory the bundle is applied to
+    pub name: String,
+    pub diff: PfaDiff,
+}
+
+/// Diffs for several archives which are applied together, either all of them or none.
+///
+/// Stored as a PFA archive with a `/manifest` listing the target names in order, after a
+/// `pfa-bundle 1` line, and the diff archive of each target in `/targets/`, named after the
+/// target with `/` replaced by `%`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatchBundle {
+    pub targets: Vec<BundleTarget>,
+}
+
+impl PatchBundle {
+    const MAGIC: &'static str = "pfa-bundle 1";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, name: &str, diff: PfaDiff) -> Result<()> {
+        // names are relative paths which stay inside the directory the bundle is applied to
+        if name.contains(['%', '\\'])
+            || name
+                .split('/')
+                .any(|x| x.is_empty() || x == "." || x == "..")
+        {
+            return Err(anyhow!("invalid bundle target name: {name}"));
+        }
+        if self.targets.iter().any(|x| x.name == name) {
+            return Err(anyhow!("bundle target added twice: {name}"));
+        }
+        self.targets.push(BundleTarget {
+            name: name.to_string(),
+            diff,
+        });
+        Ok(())
+    }
+
+    /// Diffs each of the archives `names` between the directories `old` and `new`
+    pub fn compute(
+        old: &Path,
+        new: &Path,
+        names: &[String],
+        options: &DiffOptions,
+    ) -> Result<Self> {
+        let open = |dir: &Path, name: &str| {
+            PfaReader::open(dir.join(name)).with_context(|| format!("open {name}"))
+        };
+        let mut bundle = Self::new();
+        for name in names {
+            let diff = PfaDiff::compute_with_options(
+                &mut open(old, name)?,
+                &mut open(new, name)?,
+                options,
+            )
+            .with_context(|| format!("diff {name}"))?;
+            bundle.add(name, diff)?;
+        }
+        Ok(bundle)
+    }
+
+    /// Applies every diff to the archive it targets in `dir`. The new archives are all built
+    /// before any of them are written, and if writing or validating any of them fails every
+    /// archive is restored from a backup. Entries whose paths don't pass the filter aren't
+    /// applied.
+    pub fn apply(&self, dir: &Path, filter: &PathFilter) -> Result<()> {
+        let mut diffs = Vec::with_capacity(self.targets.len());
+        let mut patched = Vec::with_capacity(self.targets.len());
+        for target in &self.targets {
+            let mut diff = target.diff.clone();
+            diff.retain_paths(filter);
+            let bytes = build_patched(&dir.join(&target.name), &diff)
+                .with_context(|| format!("apply diff to {}", target.name))?;
+            diffs.push(diff);
+            patched.push((dir.join(&target.name), bytes));
+        }
+
+        let files = patched
+            .iter()
+            .map(|(path, bytes)| (path.as_path(), &bytes[..]))
+            .collect::<Vec<_>>();
+        write_all_with_backup(&files, |i, path| validate(path, &diffs[i]))
+    }
+
+    pub fn to_archive(&self, name: &str) -> Result<Vec<u8>> {
+        let mut builder = PfaBuilder::new(name);
+        let mut manifest = format!("{}\n", Self::MAGIC);
+        for target in &self.targets {
+            manifest.push_str(&target.name);
+            manifest.push('\n');
+            let diff = target
+                .diff
+                .to_archive(&format!("{}_patch", target.name))
+                .with_context(|| format!("build diff for {}", target.name))?;
+            builder
+                .add_file(
+                    &format!("/targets/{}", Self::encode_name(&target.name)),
+                    diff,
+                    DataFlags::no_compression(),
+                )
+                .with_context(|| format!("add diff for {}", target.name))?;
+        }
+        builder
+            .add_file("/manifest", manifest.into_bytes(), DataFlags::auto())
+            .context("add manifest")?;
+        builder.build().context("build bundle pfa")
+    }
+
+    pub fn from_archive(bundle: &mut PfaReader<impl Read + Seek>) -> Result<Self> {
+        let manifest = bundle
+            .get_file("/manifest", None)?
+            .ok_or(anyhow!("bundle has no manifest"))?;
+        let manifest = String::from_utf8(manifest.into_contents()).context("parse manifest")?;
+        let mut lines = manifest.lines();
+        if lines.next() != Some(Self::MAGIC) {
+            return Err(anyhow!("unsupported bundle manifest"));
+        }
+
+        let mut out = Self::new();
+        for name in lines {
+            let diff = bundle
+                .get_file(&format!("/targets/{}", Self::encode_name(name))[..], None)?
+                .with_context(|| format!("bundle has no diff for {name}"))?;
+            let mut diff = PfaReader::from_bytes(diff.into_contents())
+                .with_context(|| format!("parse diff pfa for {name}"))?;
+            let diff = PfaDiff::from_archive(&mut diff)
+                .with_context(|| format!("read diff for {name}"))?;
+            out.add(name, diff)?;
+        }
+        Ok(out)
+    }
+
+    fn encode_name(name: &str) -> String {
+        name.replace('/', "%")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, path::Path};
+
+    use pfa::{
+        builder::PfaBuilder,
+        reader::PfaReader,
+        shared::{DataFlags, Ulid},
+    };
+
+    use super::PatchBundle;
+    use crate::{apply::backup_path, filter::PathFilter, model::DiffOptions};
+
+    fn write_archive(path: &Path, files: &[(&str, &[u8])]) {
+        let mut builder = PfaBuilder::new("test");
+        for (path, contents) in files {
+            builder
+                .add_file(path, contents.to_vec(), DataFlags::auto())
+                .unwrap();
+        }
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, builder.build().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn bundle_round_trip() {
+        let dir = std::env::temp_dir().join(format!("pfadiff_bundle_{}", Ulid::new()));
+        let (old, new, install) = (dir.join("old"), dir.join("new"), dir.join("install"));
+        for (root, version) in [(&old, &b"1"[..]), (&new, b"2")] {
+            write_archive(&root.join("core.pfa"), &[("/version.txt", version)]);
+            write_archive(&root.join("dlc/maps.pfa"), &[("/map.txt", version)]);
+        }
+        let names = ["core.pfa".to_string(), "dlc/maps.pfa".to_string()];
+        let bundle = PatchBundle::compute(&old, &new, &names, &DiffOptions::new()).unwrap();
+        assert!(bundle
+            .clone()
+            .add("../escape.pfa", Default::default())
+            .is_err());
+
+        let bytes = bundle.to_archive("update").unwrap();
+        let read = PatchBundle::from_archive(&mut PfaReader::new(Cursor::new(bytes)).unwrap());
+        assert_eq!(read.unwrap(), bundle);
+
+        let read_file = |path: &Path, file: &str| {
+            let mut reader = PfaReader::new(Cursor::new(std::fs::read(path).unwrap())).unwrap();
+            reader
+                .get_file(file, None)
+                .unwrap()
+                .unwrap()
+                .into_contents()
+        };
+
+        // the second target doesn't match, so neither is applied
+        std::fs::create_dir_all(install.join("dlc")).unwrap();
+        std::fs::copy(old.join("core.pfa"), install.join("core.pfa")).unwrap();
+        std::fs::copy(new.join("dlc/maps.pfa"), install.join("dlc/maps.pfa")).unwrap();
+        assert!(bundle.apply(&install, &PathFilter::new()).is_err());
+        assert_eq!(read_file(&install.join("core.pfa"), "/version.txt"), b"1");
+
+        std::fs::copy(old.join("dlc/maps.pfa"), install.join("dlc/maps.pfa")).unwrap();
+        bundle.apply(&install, &PathFilter::new()).unwrap();
+        assert_eq!(read_file(&install.join("core.pfa"), "/version.txt"), b"2");
+        assert_eq!(read_file(&install.join("dlc/maps.pfa"), "/map.txt"), b"2");
+        assert!(!backup_path(&install.join("core.pfa")).exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
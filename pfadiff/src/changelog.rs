@@ -0,0 +1,362 @@
+use std::{
+    fmt::Display,
+    io::{Read, Seek},
+};
+
+use anyhow::{anyhow, Context, Result};
+use pfa::reader::PfaReader;
+use serde::Serialize;
+
+use crate::model::{DiffEntry, PatchKind, PfaDiff};
+
+#[derive(Debug, Clone)]
+pub struct ChangelogOptions {
+    preview: bool,
+    context: usize,
+    max_preview_lines: usize,
+}
+
+impl ChangelogOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Include a unified diff preview for changed text files, this requires the old archive
+    pub fn preview(mut self, preview: bool) -> Self {
+        self.preview = preview;
+        self
+    }
+
+    /// Number of unchanged lines shown around each change in a preview
+    pub fn context(mut self, context: usize) -> Self {
+        self.context = context;
+        self
+    }
+
+    pub fn max_preview_lines(mut self, max_preview_lines: usize) -> Self {
+        self.max_preview_lines = max_preview_lines;
+        self
+    }
+}
+
+impl Default for ChangelogOptions {
+    fn default() -> Self {
+        Self {
+            preview: false,
+            context: 3,
+            max_preview_lines: 40,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Changed,
+    /// Same contents, stored with different flags or content type
+    Metadata,
+    Removed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangelogEntry {
+    pub kind: ChangeKind,
+    pub path: String,
+    /// Size of the file before the change, only known when the old archive was provided
+    pub old_size: Option<usize>,
+    /// Size of the file after the change, None for text patches when the old archive is unknown
+    pub new_size: Option<usize>,
+    pub preview: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Changelog {
+    pub entries: Vec<ChangelogEntry>,
+}
+
+impl Changelog {
+    /// Builds a changelog from the diff alone, sizes which depend on the old archive are omitted
+    pub fn from_diff(diff: &PfaDiff) -> Self {
+        let mut entries = diff
+            .entries
+            .iter()
+            .map(|entry| match entry {
+                DiffEntry::Add(add) => ChangelogEntry {
+                    kind: ChangeKind::Added,
+                    path: add.path.clone(),
+                    old_size: None,
+                    new_size: Some(add.contents.len()),
+                    preview: None,
+                },
+                DiffEntry::Remove(remove) => ChangelogEntry {
+                    kind: ChangeKind::Removed,
+                    path: remove.path.clone(),
+                    old_size: None,
+                    new_size: None,
+                    preview: None,
+                },
+                DiffEntry::Change(change) => ChangelogEntry {
+                    kind: ChangeKind::Changed,
+                    path: change.path.clone(),
+                    old_size: None,
+                    new_size: match &change.patch {
+                        PatchKind::Text(_) => None,
+                        PatchKind::Replace(contents) => Some(contents.len()),
+                    },
+                    preview: None,
+                },
+                DiffEntry::Metadata(metadata) => ChangelogEntry {
+                    kind: ChangeKind::Metadata,
+                    path: metadata.path.clone(),
+                    old_size: None,
+                    new_size: None,
+                    preview: None,
+                },
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| (a.kind, &a.path).cmp(&(b.kind, &b.path)));
+
+        Self { entries }
+    }
+
+    /// Builds a changelog using the old archive to fill in sizes and text previews
+    pub fn from_diff_with_old(
+        diff: &PfaDiff,
+        old: &mut PfaReader<impl Read + Seek>,
+        options: &ChangelogOptions,
+    ) -> Result<Self> {
+        let mut changelog = Self::from_diff(diff);
+        for entry in &mut changelog.entries {
+            if entry.kind == ChangeKind::Added {
+                continue;
+            }
+
+            let old_file = old
+                .get_file(&entry.path[..], None)
+                .context(format!("read old file: {}", entry.path))?
+                .ok_or(anyhow!("file missing from old archive: {}", entry.path))?;
+            let old_contents = old_file.get_contents();
+            entry.old_size = Some(old_contents.len());
+            if entry.kind == ChangeKind::Metadata {
+                entry.new_size = entry.old_size;
+                continue;
+            }
+
+            let Some(DiffEntry::Change(change)) =
+                diff.entries.iter().find(|x| x.path() == entry.path)
+            else {
+                continue;
+            };
+            let new_contents = match &change.patch {
+                PatchKind::Text(patch) => Some(
+                    apply_text_patch(old_contents, patch)
+                        .context(format!("apply patch for file: {}", entry.path))?,
+                ),
+                PatchKind::Replace(contents) => String::from_utf8(contents.to_vec()).ok(),
+            };
+
+            if let Some(new_contents) = new_contents {
+                entry.new_size = Some(new_contents.len());
+                if options.preview {
+                    if let Ok(old_text) = std::str::from_utf8(old_contents) {
+                        entry.preview = Some(unified_preview(
+                            old_text,
+                            &new_contents,
+                            options.context,
+                            options.max_preview_lines,
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(changelog)
+    }
+}
+
+impl Display for Changelog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "# Changelog")?;
+        for (kind, title) in [
+            (ChangeKind::Added, "Added"),
+            (ChangeKind::Changed, "Changed"),
+            (ChangeKind::Metadata, "Metadata changed"),
+            (ChangeKind::Removed, "Removed"),
+        ] {
+            let entries = self
+                .entries
+                .iter()
+                .filter(|x| x.kind == kind)
+                .collect::<Vec<_>>();
+            if entries.is_empty() {
+                continue;
+            }
+
+            writeln!(f)?;
+            writeln!(f, "## {} ({})", title, entries.len())?;
+            for entry in entries {
+                let size = match (entry.old_size, entry.new_size) {
+                    (Some(old), Some(new)) => {
+                        format!(" ({} -> {})", format_size(old), format_size(new))
+                    }
+                    (Some(size), None) | (None, Some(size)) => format!(" ({})", format_size(size)),
+                    (None, None) => String::new(),
+                };
+                writeln!(f, "- `{}`{}", entry.path, size)?;
+                if let Some(preview) = &entry.preview {
+                    writeln!(f, "  ```diff")?;
+                    for line in preview.lines() {
+                        writeln!(f, "  {}", line)?;
+                    }
+                    writeln!(f, "  ```")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn apply_text_patch(old_contents: &[u8], patch: &str) -> Result<String> {
+    let old_contents =
+        std::str::from_utf8(old_contents).context("extracting file contents as utf-8 string")?;
+    let dmp = dmp::Dmp::new();
+    let patches = dmp
+        .patch_from_text(patch.to_string())
+        .map_err(|e| anyhow!("error while deserializing patch: {e:?}"))?;
+    let (new, applied) = dmp
+        .patch_apply(&patches, old_contents)
+        .map_err(|e| anyhow!("error while applying patch: {e:?}"))?;
+    if applied.contains(&false) {
+        return Err(anyhow!("at least 1 patch failed to apply"));
+    }
+    Ok(new.iter().collect())
+}
+
+fn format_size(size: usize) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+
+    let mut size = size as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    format!("{size:.1} {unit}")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineOp {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Renders a unified diff between two texts, limited to `max_lines` lines of output
+fn unified_preview(old: &str, new: &str, context: usize, max_lines: usize) -> String {
+    // the lcs table is quadratic, so very large files don't get a preview
+    const MAX_CELLS: usize = 4_000_000;
+
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    if (old.len() + 1).saturating_mul(new.len() + 1) > MAX_CELLS {
+        return "(preview skipped, file is too large)".to_string();
+    }
+
+    let width = new.len() + 1;
+    let mut lcs = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i * width + j] = if old[i] == new[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut ops = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push((LineOp::Equal, i, j));
+            i += 1;
+            j += 1;
+        } else if i < old.len()
+            && (j == new.len() || lcs[(i + 1) * width + j] >= lcs[i * width + j + 1])
+        {
+            ops.push((LineOp::Delete, i, j));
+            i += 1;
+        } else {
+            ops.push((LineOp::Insert, i, j));
+            j += 1;
+        }
+    }
+
+    // group changes into hunks which share context
+    let changes = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| op.0 != LineOp::Equal)
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for idx in changes {
+        let start = idx.saturating_sub(context);
+        let end = (idx + context + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = vec![];
+    for (start, end) in hunks {
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|x| x.0 != LineOp::Insert).count();
+        let new_len = hunk.iter().filter(|x| x.0 != LineOp::Delete).count();
+        out.push(format!(
+            "@@ -{},{} +{},{} @@",
+            hunk[0].1 + 1,
+            old_len,
+            hunk[0].2 + 1,
+            new_len
+        ));
+        for (op, i, j) in hunk {
+            out.push(match op {
+                LineOp::Equal => format!(" {}", old[*i]),
+                LineOp::Delete => format!("-{}", old[*i]),
+                LineOp::Insert => format!("+{}", new[*j]),
+            });
+        }
+    }
+
+    if out.len() > max_lines {
+        let remaining = out.len() - max_lines;
+        out.truncate(max_lines);
+        out.push(format!("... ({remaining} more lines)"));
+    }
+    out.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unified_preview;
+
+    #[test]
+    fn preview_shows_changed_lines_with_context() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        let new = "a\nb\nc\nD\ne\nf\ng\nh\ni\n";
+        let preview = unified_preview(old, new, 1, 100);
+        assert_eq!(
+            preview,
+            "@@ -3,3 +3,3 @@\n c\n-d\n+D\n e\n@@ -8,1 +8,2 @@\n h\n+i"
+        );
+    }
+}
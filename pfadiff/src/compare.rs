@@ -0,0 +1,358 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    io::{Read, Seek},
+};
+
+use anyhow::{Context, Result};
+use pfa::{reader::PfaReader, shared::DataFlags};
+use serde::Serialize;
+
+/// How an entry is stored, apart from its contents
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EntryMetadata {
+    pub is_directory: bool,
+    /// Flags of the stored data, zero for directories
+    pub flags: u8,
+    pub content_type: Option<String>,
+    pub tier: Option<String>,
+    /// Stored uncompressed because compressing it didn't reduce its size
+    pub compression_skipped: bool,
+    pub solid: bool,
+}
+
+impl EntryMetadata {
+    /// Field names and values, in the order they are compared
+    fn fields(&self) -> [(&'static str, String); 6] {
+        let kind = match self.is_directory {
+            true => "directory",
+            false => "file",
+        };
+        [
+            ("kind", kind.to_string()),
+            ("flags", describe_flags(self.flags)),
+            ("content type", optional(&self.content_type)),
+            ("tier", optional(&self.tier)),
+            ("compression skipped", self.compression_skipped.to_string()),
+            ("solid", self.solid.to_string()),
+        ]
+    }
+}
+
+/// The header fields of an archive and the metadata of every entry, leaving out file contents
+/// and the ids which differ every build
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ArchiveMetadata {
+    pub header: Vec<(&'static str, String)>,
+    pub entries: BTreeMap<String, EntryMetadata>,
+}
+
+impl ArchiveMetadata {
+    /// Reads the metadata from the catalog and header, without reading any file data. Fails for
+    /// archives with hashed names, whose entries can't be listed.
+    pub fn read(reader: &PfaReader<impl Read + Seek>) -> Result<Self> {
+        let header = vec![
+            ("name", reader.get_name().to_string()),
+            ("version", reader.get_version().to_string()),
+            ("extra data", format_bytes(reader.get_extra_data())),
+            ("catalog layout", reader.get_catalog_layout().to_string()),
+            ("name encoding", format!("{:?}", reader.get_name_encoding())),
+            ("entry hashes", reader.has_entry_hashes().to_string()),
+            (
+                "data alignment",
+                optional(&reader.get_data_alignment().map(|x| x.to_string())),
+            ),
+            ("variants", reader.get_variants().join(", ")),
+        ];
+
+        let mut entries = BTreeMap::new();
+        for entry in reader.entries().context("list entries")? {
+            let entry = entry.context("list entries")?;
+            let path = entry.path.to_string();
+            let metadata = EntryMetadata {
+                is_directory: entry.is_directory,
+                flags: entry.flags,
+                content_type: reader.get_content_type(&path[..])?,
+                tier: reader.get_tier(&path[..])?.map(|x| x.to_string()),
+                compression_skipped: !entry.is_directory
+                    && reader.is_compression_skipped(&path[..])?,
+                solid: !entry.is_directory && reader.is_in_solid_block(&path[..])?,
+            };
+            entries.insert(path, metadata);
+        }
+        Ok(Self { header, entries })
+    }
+
+    /// Number of files with each flag set, see [`describe_flags`]
+    pub fn flag_counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for entry in self.entries.values().filter(|x| !x.is_directory) {
+            for name in flag_names(entry.flags) {
+                *counts.entry(name).or_default() += 1;
+            }
+        }
+        counts
+    }
+}
+
+/// A value which differs between the two archives
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+/// Number of files with a flag set, which differs between the two archives
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FlagCountChange {
+    pub flag: &'static str,
+    pub old: usize,
+    pub new: usize,
+}
+
+/// An entry which exists in both archives, but is stored differently
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EntryChange {
+    pub path: String,
+    pub changes: Vec<FieldChange>,
+}
+
+/// Differences between the headers and entry metadata of two archives, ignoring file contents.
+/// Catches packaging regressions, such as files losing their error correction, which a content
+/// diff doesn't show.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MetadataComparison {
+    pub header: Vec<FieldChange>,
+    /// Number of files with each flag set, for flags whose count differs
+    pub flag_counts: Vec<FlagCountChange>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<EntryChange>,
+}
+
+impl MetadataComparison {
+    pub fn compute(
+        old: &PfaReader<impl Read + Seek>,
+        new: &PfaReader<impl Read + Seek>,
+    ) -> Result<Self> {
+        let old = ArchiveMetadata::read(old).context("read old archive metadata")?;
+        let new = ArchiveMetadata::read(new).context("read new archive metadata")?;
+        Ok(Self::between(&old, &new))
+    }
+
+    pub fn between(old: &ArchiveMetadata, new: &ArchiveMetadata) -> Self {
+        let header = old
+            .header
+            .iter()
+            .zip(&new.header)
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((field, old), (_, new))| FieldChange {
+                field,
+                old: old.clone(),
+                new: new.clone(),
+            })
+            .collect();
+
+        let old_counts = old.flag_counts();
+        let new_counts = new.flag_counts();
+        let flag_counts = old_counts
+            .keys()
+            .chain(new_counts.keys())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|flag| {
+                let count = |counts: &BTreeMap<&str, usize>| counts.get(flag).copied();
+                FlagCountChange {
+                    flag,
+                    old: count(&old_counts).unwrap_or(0),
+                    new: count(&new_counts).unwrap_or(0),
+                }
+            })
+            .filter(|x| x.old != x.new)
+            .collect();
+
+        let mut comparison = Self {
+            header,
+            flag_counts,
+            ..Default::default()
+        };
+        for (path, old_entry) in &old.entries {
+            let Some(new_entry) = new.entries.get(path) else {
+                comparison.removed.push(path.clone());
+                continue;
+            };
+            let changes = old_entry
+                .fields()
+                .into_iter()
+                .zip(new_entry.fields())
+                .filter(|((_, old), (_, new))| old != new)
+                .map(|((field, old), (_, new))| FieldChange { field, old, new })
+                .collect::<Vec<_>>();
+            if !changes.is_empty() {
+                comparison.changed.push(EntryChange {
+                    path: path.clone(),
+                    changes,
+                });
+            }
+        }
+        comparison.added = new
+            .entries
+            .keys()
+            .filter(|x| !old.entries.contains_key(*x))
+            .cloned()
+            .collect();
+        comparison
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.header.is_empty()
+            && self.flag_counts.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+impl Display for MetadataComparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "The archives' metadata matches");
+        }
+        writeln!(f, "# Metadata comparison")?;
+        if !self.header.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "## Header ({})", self.header.len())?;
+            for change in &self.header {
+                writeln!(f, "- {}: {} -> {}", change.field, change.old, change.new)?;
+            }
+        }
+        if !self.flag_counts.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "## Files with each flag ({})", self.flag_counts.len())?;
+            for count in &self.flag_counts {
+                writeln!(f, "- {}: {} -> {}", count.flag, count.old, count.new)?;
+            }
+        }
+        for (title, paths) in [("Added", &self.added), ("Removed", &self.removed)] {
+            if paths.is_empty() {
+                continue;
+            }
+            writeln!(f)?;
+            writeln!(f, "## {} ({})", title, paths.len())?;
+            for path in paths {
+                writeln!(f, "- `{path}`")?;
+            }
+        }
+        if !self.changed.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "## Changed ({})", self.changed.len())?;
+            for entry in &self.changed {
+                writeln!(f, "- `{}`", entry.path)?;
+                for change in &entry.changes {
+                    writeln!(f, "  - {}: {} -> {}", change.field, change.old, change.new)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Names of the flags set in a data slice's flag bitfield, compression named by its codec
+pub fn flag_names(flags: u8) -> Vec<&'static str> {
+    let mut names = vec![];
+    if DataFlags::is_compressed(flags) {
+        names.push(DataFlags::codec(flags).map_or("compression", |x| x.name()));
+    }
+    if DataFlags::is_encrypted(flags) {
+        names.push("encryption");
+    }
+    if DataFlags::has_encryption_frames(flags) {
+        names.push("encryption frames");
+    }
+    if DataFlags::has_size_padding(flags) {
+        names.push("size padding");
+    }
+    if DataFlags::has_error_correction(flags) {
+        names.push("error correction");
+    }
+    if DataFlags::unknown_bits(flags) != 0 {
+        names.push("unknown bits");
+    }
+    names
+}
+
+/// The flag names joined with commas, `none` without any
+pub fn describe_flags(flags: u8) -> String {
+    let names = flag_names(flags);
+    match names.is_empty() {
+        true => "none".into(),
+        false => names.join(", "),
+    }
+}
+
+fn optional(value: &Option<String>) -> String {
+    value.clone().unwrap_or_else(|| "none".into())
+}
+
+fn format_bytes(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "none".into();
+    }
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use pfa::{builder::PfaBuilder, reader::PfaReader, shared::DataFlags};
+
+    use super::{FieldChange, FlagCountChange, MetadataComparison};
+
+    fn archive(extra_data: &[u8], flags: DataFlags) -> PfaReader<Cursor<Vec<u8>>> {
+        let mut builder = PfaBuilder::new("test");
+        builder.set_extra_data(extra_data.to_vec());
+        for path in ["/a.txt", "/dir/b.txt"] {
+            builder.add_file(path, vec![7; 300], flags.clone()).unwrap();
+        }
+        PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap()
+    }
+
+    #[test]
+    fn metadata_comparison_ignores_contents() {
+        let flags = DataFlags::no_compression().error_correction(Some(0.2));
+        let old = archive(b"v1", flags.clone());
+        let same = archive(b"v1", flags);
+        assert!(MetadataComparison::compute(&old, &same).unwrap().is_empty());
+
+        let new = archive(b"v2", DataFlags::no_compression());
+        let comparison = MetadataComparison::compute(&old, &new).unwrap();
+        assert_eq!(
+            comparison.header,
+            vec![FieldChange {
+                field: "extra data",
+                old: "7631".into(),
+                new: "7632".into(),
+            }]
+        );
+        assert_eq!(
+            comparison.flag_counts,
+            vec![FlagCountChange {
+                flag: "error correction",
+                old: 2,
+                new: 0,
+            }]
+        );
+        assert!(comparison.added.is_empty() && comparison.removed.is_empty());
+        let changed = comparison
+            .changed
+            .iter()
+            .map(|x| (x.path.as_str(), x.changes[0].new.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(changed, vec![("/a.txt", "none"), ("/dir/b.txt", "none")]);
+        assert!(comparison
+            .to_string()
+            .contains("- error correction: 2 -> 0"));
+    }
+}
@@ -0,0 +1,79 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobBuilder, GlobMatcher};
+
+/// Include and exclude globs for the paths of diff entries. A path passes the filter if it
+/// matches any include glob (or there are none) and no exclude glob.
+///
+/// Patterns starting with '/' are matched from the archive root, others at any depth, so `*.log`
+/// matches `/logs/a.log`. A pattern ending with '/' matches everything in that directory. `*`
+/// doesn't match '/', use `**` to match across directories.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    include: Vec<GlobMatcher>,
+    exclude: Vec<GlobMatcher>,
+}
+
+impl PathFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn include(mut self, pattern: &str) -> Result<Self> {
+        self.include.push(Self::compile(pattern)?);
+        Ok(self)
+    }
+
+    pub fn exclude(mut self, pattern: &str) -> Result<Self> {
+        self.exclude.push(Self::compile(pattern)?);
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|x| x.is_match(path)))
+            && !self.exclude.iter().any(|x| x.is_match(path))
+    }
+
+    fn compile(pattern: &str) -> Result<GlobMatcher> {
+        let mut glob = match pattern.strip_prefix('/') {
+            Some(anchored) => format!("/{anchored}"),
+            None => format!("/**/{pattern}"),
+        };
+        if glob.ends_with('/') {
+            glob.push_str("**");
+        }
+        let glob = GlobBuilder::new(&glob)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("invalid glob '{pattern}'"))?;
+        Ok(Glob::compile_matcher(&glob))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PathFilter;
+
+    #[test]
+    fn filter_globs() {
+        let filter = PathFilter::new()
+            .exclude("*.log")
+            .unwrap()
+            .exclude("/cache/")
+            .unwrap();
+        assert!(filter.matches("/a.txt"));
+        assert!(!filter.matches("/a.log"));
+        assert!(!filter.matches("/deep/dir/a.log"));
+        assert!(!filter.matches("/cache/nested/a.txt"));
+        assert!(filter.matches("/src/cache/a.txt"));
+
+        let filter = PathFilter::new().include("/src/*.rs").unwrap();
+        assert!(filter.matches("/src/main.rs"));
+        assert!(!filter.matches("/src/nested/main.rs"));
+        assert!(!filter.matches("/main.rs"));
+        assert!(PathFilter::new().include("[").is_err());
+    }
+}
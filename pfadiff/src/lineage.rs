@@ -0,0 +1,161 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
+};
+
+use anyhow::{anyhow, Result};
+use pfa::shared::Ulid;
+
+use crate::model::PfaDiff;
+
+/// A single update step, recorded by a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LineageLink {
+    pub from_id: Ulid,
+    pub to_id: Ulid,
+}
+
+impl LineageLink {
+    pub fn from_diff(diff: &PfaDiff) -> Result<Self> {
+        match (diff.from_id, diff.to_id) {
+            (Some(from_id), Some(to_id)) => Ok(Self { from_id, to_id }),
+            _ => Err(anyhow!("diff does not record archive lineage")),
+        }
+    }
+}
+
+impl Display for LineageLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.from_id, self.to_id)
+    }
+}
+
+/// Checks that a sequence of diffs forms an unbroken update chain starting at `base`, returning
+/// the id of the archive produced by applying all of them
+pub fn validate_chain<'a>(
+    base: Ulid,
+    diffs: impl IntoIterator<Item = &'a PfaDiff>,
+) -> Result<Ulid> {
+    let mut current = base;
+    let mut seen = HashSet::from([base]);
+    for (i, diff) in diffs.into_iter().enumerate() {
+        let link = LineageLink::from_diff(diff).map_err(|e| anyhow!("diff {i}: {e}"))?;
+        if link.from_id != current {
+            return Err(anyhow!(
+                "diff {i} applies to archive {}, but the chain is at archive {current}",
+                link.from_id
+            ));
+        }
+        if !seen.insert(link.to_id) {
+            return Err(anyhow!(
+                "diff {i} creates a cycle at archive {}",
+                link.to_id
+            ));
+        }
+        current = link.to_id;
+    }
+
+    Ok(current)
+}
+
+/// A graph of update steps between archive builds
+#[derive(Debug, Clone, Default)]
+pub struct Lineage {
+    links: Vec<LineageLink>,
+}
+
+impl Lineage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_diffs<'a>(diffs: impl IntoIterator<Item = &'a PfaDiff>) -> Result<Self> {
+        let links = diffs
+            .into_iter()
+            .map(LineageLink::from_diff)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { links })
+    }
+
+    pub fn add_link(&mut self, link: LineageLink) {
+        self.links.push(link);
+    }
+
+    pub fn links(&self) -> &[LineageLink] {
+        &self.links
+    }
+
+    /// Finds the shortest sequence of update steps which turns `from` into `to`
+    pub fn find_chain(&self, from: Ulid, to: Ulid) -> Option<Vec<LineageLink>> {
+        let mut previous: HashMap<Ulid, LineageLink> = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+        while let Some(id) = queue.pop_front() {
+            if id == to {
+                let mut chain = vec![];
+                let mut id = to;
+                while id != from {
+                    let link = previous[&id];
+                    chain.push(link);
+                    id = link.from_id;
+                }
+                chain.reverse();
+                return Some(chain);
+            }
+
+            for link in self.links.iter().filter(|x| x.from_id == id) {
+                if link.to_id != from && !previous.contains_key(&link.to_id) {
+                    previous.insert(link.to_id, *link);
+                    queue.push_back(link.to_id);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl Display for Lineage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for link in &self.links {
+            writeln!(f, "{link}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pfa::shared::Ulid;
+
+    use super::{validate_chain, Lineage};
+    use crate::model::PfaDiff;
+
+    fn diff(from: Ulid, to: Ulid) -> PfaDiff {
+        PfaDiff {
+            from_id: Some(from),
+            to_id: Some(to),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn chains() {
+        let ids = (0..4).map(|_| Ulid::new()).collect::<Vec<_>>();
+        let diffs = [
+            diff(ids[0], ids[1]),
+            diff(ids[1], ids[2]),
+            diff(ids[2], ids[3]),
+        ];
+
+        assert_eq!(validate_chain(ids[0], &diffs).unwrap(), ids[3]);
+        assert!(validate_chain(ids[1], &diffs).is_err());
+        assert!(validate_chain(ids[0], [&diffs[0], &diffs[2]]).is_err());
+
+        let lineage = Lineage::from_diffs(diffs.iter().rev()).unwrap();
+        let chain = lineage.find_chain(ids[0], ids[3]).unwrap();
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain[0].from_id, ids[0]);
+        assert_eq!(chain[2].to_id, ids[3]);
+        assert!(lineage.find_chain(ids[3], ids[0]).is_none());
+    }
+}
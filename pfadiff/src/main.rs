@@ -1,16 +1,29 @@
-use std::{
-    fs::File,
-    io::{BufReader, BufWriter},
-    path::PathBuf,
-};
+use std::{fs::File, io::BufWriter, path::PathBuf};
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use pfa::reader::PfaReader;
-use pfadiff_lib::{apply_diff, create_diff};
+use pfadiff_lib::{
+    apply_diff_to_file, create_diff_with_options, ApplyOptions, Changelog, ChangelogOptions,
+    DiffCounts, DiffOptions, MetadataComparison, Outcome, PatchBundle, PathFilter, PfaDiff,
+    Summary,
+};
+
+const EXIT_CODES: &str = "\
+Exit codes:
+  0   diff created / applied, compare: the metadata matches
+  1   error
+  2   invalid arguments
+  3   create: the archives have the same contents
+  4   apply: applied, but some text patches only matched approximately
+  5   compare: the headers or entry metadata differ
+  10  apply: the diff is for a different archive
+  11  apply: a patch conflicts with the old archive
+  12  apply: the patched archive didn't validate, backups were restored
+  13  apply: the patched archive couldn't be written";
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = EXIT_CODES)]
 struct Args {
     #[command(subcommand)]
     cmd: Commands,
@@ -25,6 +38,24 @@ enum Commands {
         new: PathBuf,
         #[clap(short, long)]
         diff_output: PathBuf,
+        /// Only diff paths matching this glob, can be given multiple times
+        #[clap(long = "include", value_name = "GLOB")]
+        include: Vec<String>,
+        /// Leave paths matching this glob out of the diff, can be given multiple times
+        #[clap(long = "exclude", value_name = "GLOB")]
+        exclude: Vec<String>,
+        /// Number of unchanged characters kept around each change in text patches
+        #[clap(long, default_value_t = 4)]
+        context: usize,
+        /// Store changed text files whole when a patch hunk spans more characters than this
+        #[clap(long)]
+        max_hunk_size: Option<usize>,
+        /// Store changed text files whole when their patch isn't smaller than the new file
+        #[clap(long)]
+        replace_larger_patches: bool,
+        /// Print a JSON summary of the result to stdout
+        #[clap(long)]
+        json: bool,
     },
     Apply {
         #[clap(short, long)]
@@ -33,45 +64,216 @@ enum Commands {
         diff: PathBuf,
         #[clap(short, long)]
         new_output: PathBuf,
+        /// Don't apply entries for paths matching this glob, keeping the old files, can be given
+        /// multiple times
+        #[clap(long = "skip", value_name = "GLOB")]
+        skip: Vec<String>,
+        /// Copy the existing output file before overwriting it, and restore it if the new
+        /// archive can't be written or doesn't validate
+        #[clap(long)]
+        backup: bool,
+        /// Print a JSON summary of the result to stdout
+        #[clap(long)]
+        json: bool,
+    },
+    /// Diff several archives into one bundle, which is applied to all of them at once
+    CreateBundle {
+        /// Directory containing the old archives
+        #[clap(short, long)]
+        old: PathBuf,
+        /// Directory containing the new archives
+        #[clap(short, long)]
+        new: PathBuf,
+        #[clap(short, long)]
+        bundle_output: PathBuf,
+        /// Path of an archive to diff, relative to the directories, can be given multiple times.
+        /// Defaults to every .pfa file in the new directory.
+        #[clap(long = "target", value_name = "PATH")]
+        targets: Vec<String>,
+    },
+    /// Apply a bundle to the archives in a directory, restoring all of them if any fails
+    ApplyBundle {
+        /// Directory containing the archives to update
+        #[clap(short, long)]
+        dir: PathBuf,
+        #[clap(short, long)]
+        bundle: PathBuf,
+        /// Don't apply entries for paths matching this glob, keeping the old files, can be given
+        /// multiple times
+        #[clap(long = "skip", value_name = "GLOB")]
+        skip: Vec<String>,
+    },
+    /// Render a human-readable changelog from a diff archive
+    Changelog {
+        #[clap(short, long)]
+        diff: PathBuf,
+        /// The archive the diff applies to, used for file sizes and previews
+        #[clap(short, long)]
+        old: Option<PathBuf>,
+        /// Include unified diff previews of changed text files (requires --old)
+        #[clap(short, long, requires = "old")]
+        preview: bool,
+    },
+    /// Compare the headers, flags and entry metadata of two archives, ignoring file contents
+    Compare {
+        #[clap(short, long)]
+        old: PathBuf,
+        #[clap(short, long)]
+        new: PathBuf,
+        /// Print a JSON summary of the result to stdout instead of the comparison
+        #[clap(long)]
+        json: bool,
     },
 }
 
-fn run() -> Result<()> {
-    let args = Args::parse();
-    match args.cmd {
+/// Runs the command, returning the summary of create, apply and compare
+fn run(cmd: Commands) -> Result<Option<Summary>> {
+    match cmd {
         Commands::Create {
             old,
             new,
             diff_output,
+            include,
+            exclude,
+            context,
+            max_hunk_size,
+            replace_larger_patches,
+            json: _,
         } => {
-            let old = PfaReader::new(BufReader::new(File::open(old).context("open old file")?))
-                .context("parse old file pfa")?;
-            let new = PfaReader::new(BufReader::new(File::open(new).context("open new file")?))
-                .context("open new file pfa")?;
+            let mut filter = PathFilter::new();
+            for pattern in include {
+                filter = filter.include(&pattern)?;
+            }
+            for pattern in exclude {
+                filter = filter.exclude(&pattern)?;
+            }
+            let old = PfaReader::open(old).context("open old file")?;
+            let new = PfaReader::open(new).context("open new file")?;
             let out = BufWriter::new(File::create(diff_output).context("create output file")?);
-            create_diff(old, new, out).context("create diff")?
+            let options = DiffOptions::new()
+                .filter(filter)
+                .context(context)
+                .max_hunk_size(max_hunk_size)
+                .replace_larger_patches(replace_larger_patches);
+            let diff = create_diff_with_options(old, new, out, &options).context("create diff")?;
+            let outcome = match diff.is_empty() {
+                true => Outcome::NoChanges,
+                false => Outcome::Created,
+            };
+            return Ok(Some(Summary {
+                counts: Some(DiffCounts::of(&diff)),
+                ..Summary::new("create", outcome)
+            }));
         }
         Commands::Apply {
             old,
             diff,
             new_output,
+            skip,
+            backup,
+            json: _,
         } => {
-            let old = PfaReader::new(BufReader::new(File::open(old).context("open old file")?))
-                .context("parse old file pfa")?;
-            let diff = PfaReader::new(BufReader::new(File::open(diff).context("open diff file")?))
-                .context("open new file pfa")?;
-            let out = BufWriter::new(File::create(new_output).context("create output file")?);
-            apply_diff(old, diff, out).context("create diff")?
+            let mut filter = PathFilter::new();
+            for pattern in skip {
+                filter = filter.exclude(&pattern)?;
+            }
+            let options = ApplyOptions::new().filter(filter).backup(backup);
+            let report =
+                apply_diff_to_file(&old, &diff, &new_output, &options).context("apply diff")?;
+            let outcome = match report.corrected.is_empty() {
+                true => Outcome::Applied,
+                false => Outcome::Corrected,
+            };
+            return Ok(Some(Summary {
+                counts: Some(report.counts),
+                corrected: Some(report.corrected),
+                ..Summary::new("apply", outcome)
+            }));
+        }
+        Commands::CreateBundle {
+            old,
+            new,
+            bundle_output,
+            mut targets,
+        } => {
+            if targets.is_empty() {
+                for entry in std::fs::read_dir(&new).context("read new directory")? {
+                    let path = entry?.path();
+                    if path.is_file() && path.extension().is_some_and(|x| x == "pfa") {
+                        let name = path.file_name().context("archive file name")?;
+                        targets.push(name.to_string_lossy().into_owned());
+                    }
+                }
+                targets.sort();
+            }
+            let bundle = PatchBundle::compute(&old, &new, &targets, &DiffOptions::new())
+                .context("create bundle")?;
+            let bytes = bundle.to_archive("bundle").context("build bundle")?;
+            std::fs::write(bundle_output, bytes).context("write bundle file")?;
+        }
+        Commands::ApplyBundle { dir, bundle, skip } => {
+            let mut filter = PathFilter::new();
+            for pattern in skip {
+                filter = filter.exclude(&pattern)?;
+            }
+            let mut bundle = PfaReader::open(bundle).context("open bundle file")?;
+            let bundle = PatchBundle::from_archive(&mut bundle).context("read bundle pfa")?;
+            bundle.apply(&dir, &filter).context("apply bundle")?
+        }
+        Commands::Changelog { diff, old, preview } => {
+            let mut diff = PfaReader::open(diff).context("open diff file")?;
+            let diff = PfaDiff::from_archive(&mut diff).context("read diff pfa")?;
+            let changelog = match old {
+                Some(old) => {
+                    let mut old = PfaReader::open(old).context("open old file")?;
+                    let options = ChangelogOptions::new().preview(preview);
+                    Changelog::from_diff_with_old(&diff, &mut old, &options)
+                        .context("create changelog")?
+                }
+                None => Changelog::from_diff(&diff),
+            };
+            print!("{changelog}");
+        }
+        Commands::Compare { old, new, json } => {
+            let old = PfaReader::open(old).context("open old file")?;
+            let new = PfaReader::open(new).context("open new file")?;
+            let comparison = MetadataComparison::compute(&old, &new).context("compare archives")?;
+            if !json {
+                print!("{comparison}");
+            }
+            let outcome = match comparison.is_empty() {
+                true => Outcome::Matched,
+                false => Outcome::MetadataDiffers,
+            };
+            return Ok(Some(Summary::new("compare", outcome)));
         }
     }
-    Ok(())
+    Ok(None)
 }
 
 fn main() {
-    if let Err(e) = run() {
-        eprintln!("ERROR: {}", e);
-        e.chain()
-            .skip(1)
-            .for_each(|c| eprintln!("\tCaused by: {c}"))
+    let args = Args::parse();
+    let (command, json) = match args.cmd {
+        Commands::Create { json, .. } => ("create", json),
+        Commands::Apply { json, .. } => ("apply", json),
+        Commands::Compare { json, .. } => ("compare", json),
+        _ => ("", false),
+    };
+    let summary = match run(args.cmd) {
+        Ok(summary) => summary,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            e.chain()
+                .skip(1)
+                .for_each(|c| eprintln!("\tCaused by: {c}"));
+            Some(Summary::failed(command, &e))
+        }
+    };
+    let Some(summary) = summary else {
+        return;
+    };
+    if json {
+        println!("{}", summary.to_json());
     }
+    std::process::exit(summary.outcome.exit_code());
 }
@@ -0,0 +1,1198 @@
+use std::{
+    io::{Cursor, Read, Seek},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use pfa::{
+    builder::{DuplicatePolicy, PfaBuilder},
+    reader::{pfa_reader::PfaPath, PfaReader},
+    shared::{DataCompressionType, DataFlags, Ulid},
+};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{filter::PathFilter, outcome::ApplyFailure};
+
+/// Controls how diffs are computed
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    filter: PathFilter,
+    context: usize,
+    max_hunk_size: Option<usize>,
+    replace_larger_patches: bool,
+}
+
+impl DiffOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only paths passing the filter are compared
+    pub fn filter(mut self, filter: PathFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Number of unchanged characters kept around each change in text patches
+    pub fn context(mut self, context: usize) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// Files with a patch hunk spanning more characters than this are stored whole instead
+    pub fn max_hunk_size(mut self, max_hunk_size: Option<usize>) -> Self {
+        self.max_hunk_size = max_hunk_size;
+        self
+    }
+
+    /// Store files whole when their text patch isn't smaller than the new contents, which
+    /// happens when a file was heavily reordered
+    pub fn replace_larger_patches(mut self, replace_larger_patches: bool) -> Self {
+        self.replace_larger_patches = replace_larger_patches;
+        self
+    }
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            filter: PathFilter::new(),
+            context: 4,
+            max_hunk_size: None,
+            replace_larger_patches: false,
+        }
+    }
+}
+
+/// A file which exists in the new archive but not the old one, stored with its full contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddEntry {
+    pub path: String,
+    pub contents: Vec<u8>,
+}
+
+/// A file which exists in the old archive but not the new one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoveEntry {
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchKind {
+    /// A diff-match-patch patch in its text representation, used when both versions are utf-8
+    Text(String),
+    /// The full new contents of the file, used for binary files
+    Replace(Vec<u8>),
+}
+
+/// A file which exists in both archives with different contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEntry {
+    pub path: String,
+    pub patch: PatchKind,
+}
+
+/// How a file is stored, apart from its contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub compression: bool,
+    /// Error correction bytes per block, None without error correction
+    pub error_correction: Option<u8>,
+    pub content_type: Option<String>,
+}
+
+impl FileMetadata {
+    /// Reads the metadata of a file, None if it doesn't exist or is encrypted
+    pub fn read(reader: &mut PfaReader<impl Read + Seek>, path: &str) -> Result<Option<Self>> {
+        let Some(stat) = reader
+            .stat(path)?
+            .filter(|x| !x.is_directory && !DataFlags::is_encrypted(x.flags))
+        else {
+            return Ok(None);
+        };
+        let mut error_correction = None;
+        if DataFlags::has_error_correction(stat.flags) {
+            let raw = reader
+                .get_file_raw(path)?
+                .ok_or(anyhow!("missing file: {path}"))?;
+            error_correction = raw
+                .get_stage_headers()
+                .ecc_size
+                .and_then(|x| u8::try_from(x).ok());
+        }
+        Ok(Some(Self {
+            compression: DataFlags::is_compressed(stat.flags),
+            error_correction,
+            content_type: stat.content_type,
+        }))
+    }
+
+    /// Flags which store a file the same way
+    pub fn data_flags(&self) -> DataFlags {
+        // DataFlags rounds the percentage down to whole bytes
+        let error_correction = self.error_correction.map(|x| (f32::from(x) + 0.5) / 255.0);
+        DataFlags::new(
+            error_correction,
+            None,
+            DataCompressionType::Forced(self.compression),
+        )
+    }
+
+    /// {compression:u8}{error_correction:u16, u16::MAX for none}{content_type_len:u8}{content_type}
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = vec![u8::from(self.compression)];
+        out.write_u16::<LittleEndian>(self.error_correction.map_or(u16::MAX, u16::from))?;
+        let content_type = self.content_type.as_deref().unwrap_or_default();
+        out.write_u8(
+            content_type
+                .len()
+                .try_into()
+                .map_err(|_| anyhow!("content type too long: {content_type}"))?,
+        )?;
+        out.extend_from_slice(content_type.as_bytes());
+        Ok(out)
+    }
+
+    fn decode(mut data: &[u8]) -> Result<Self> {
+        let compression = data.read_u8()? != 0;
+        let error_correction = match data.read_u16::<LittleEndian>()? {
+            u16::MAX => None,
+            x => Some(u8::try_from(x).context("invalid error correction size")?),
+        };
+        let mut content_type = vec![0; data.read_u8()?.into()];
+        data.read_exact(&mut content_type)?;
+        let content_type = String::from_utf8(content_type).context("parse content type")?;
+        Ok(Self {
+            compression,
+            error_correction,
+            content_type: Some(content_type).filter(|x| !x.is_empty()),
+        })
+    }
+}
+
+/// A file which exists in both archives with the same contents, but is stored differently
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataEntry {
+    pub path: String,
+    pub metadata: FileMetadata,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffEntry {
+    Add(AddEntry),
+    Remove(RemoveEntry),
+    Change(ChangeEntry),
+    Metadata(MetadataEntry),
+}
+
+impl DiffEntry {
+    pub fn path(&self) -> &str {
+        match self {
+            DiffEntry::Add(AddEntry { path, .. })
+            | DiffEntry::Remove(RemoveEntry { path })
+            | DiffEntry::Change(ChangeEntry { path, .. })
+            | DiffEntry::Metadata(MetadataEntry { path, .. }) => path,
+        }
+    }
+}
+
+/// The set of changes which turns an old archive into a new one.
+///
+/// A diff is stored as a PFA archive with an `/index` listing every entry once, as
+/// {version:u8}{num_entries:u32}{entries:{operation:u8}{path_len:u16}{path:u8\[path_len\]}\[num_entries\]}
+/// with little endian numbers. The contents (or patch) of the entry at position `i` are stored in
+/// `/data/{i}`, removals have none. The ids of the archives the diff was created from are stored
+/// in `/lineage`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PfaDiff {
+    pub entries: Vec<DiffEntry>,
+    /// Id of the archive this diff applies to
+    pub from_id: Option<Ulid>,
+    /// Id given to the archive produced by applying this diff
+    pub to_id: Option<Ulid>,
+}
+
+impl PfaDiff {
+    const INDEX_VERSION: u8 = 1;
+    const ADD: u8 = 0;
+    const REMOVE: u8 = 1;
+    const CHANGE: u8 = 2;
+    const REPLACE: u8 = 3;
+    const METADATA: u8 = 4;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn added(&self) -> impl Iterator<Item = &AddEntry> {
+        self.entries.iter().filter_map(|x| match x {
+            DiffEntry::Add(x) => Some(x),
+            _ => None,
+        })
+    }
+
+    pub fn removed(&self) -> impl Iterator<Item = &RemoveEntry> {
+        self.entries.iter().filter_map(|x| match x {
+            DiffEntry::Remove(x) => Some(x),
+            _ => None,
+        })
+    }
+
+    pub fn changed(&self) -> impl Iterator<Item = &ChangeEntry> {
+        self.entries.iter().filter_map(|x| match x {
+            DiffEntry::Change(x) => Some(x),
+            _ => None,
+        })
+    }
+
+    pub fn metadata(&self) -> impl Iterator<Item = &MetadataEntry> {
+        self.entries.iter().filter_map(|x| match x {
+            DiffEntry::Metadata(x) => Some(x),
+            _ => None,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Computes the changes between two archives. Only non-encrypted files are compared.
+    pub fn compute(
+        old: &mut PfaReader<impl Read + Seek>,
+        new: &mut PfaReader<impl Read + Seek>,
+    ) -> Result<Self> {
+        Self::compute_filtered(old, new, &PathFilter::new())
+    }
+
+    /// Like [`Self::compute`], but only paths passing the filter are compared, so volatile files
+    /// can be left out of the diff. If the filter leaves any file out, applying the diff no longer
+    /// produces the new archive, so the diff has no target id.
+    pub fn compute_filtered(
+        old: &mut PfaReader<impl Read + Seek>,
+        new: &mut PfaReader<impl Read + Seek>,
+        filter: &PathFilter,
+    ) -> Result<Self> {
+        Self::compute_with_options(old, new, &DiffOptions::new().filter(filter.clone()))
+    }
+
+    pub fn compute_with_options(
+        old: &mut PfaReader<impl Read + Seek>,
+        new: &mut PfaReader<impl Read + Seek>,
+        options: &DiffOptions,
+    ) -> Result<Self> {
+        let mut diff = Self {
+            from_id: old.get_archive_id(),
+            to_id: new.get_archive_id(),
+            ..Default::default()
+        };
+
+        // Firstly, look through the old PFA to see if there are any paths which don't exist in the new PFA. These are deleted.
+        for root in Self::differing_paths(old, new, "/".into())? {
+            Self::scan_old(old, new, root, options, &mut diff)?;
+        }
+
+        // Next, traverse new PFA to find files that don't exist in old PFA. These are created and don't need a diff (full content stored)
+        for root in Self::differing_paths(new, old, "/".into())? {
+            Self::scan_new(old, new, root, &options.filter, &mut diff)?;
+        }
+
+        Ok(diff)
+    }
+
+    /// Paths within `path` in `a` which may differ from `b`. Subtrees with matching entry hashes
+    /// are skipped, and archives without hashes have to be scanned in full.
+    fn differing_paths(
+        a: &mut PfaReader<impl Read + Seek>,
+        b: &mut PfaReader<impl Read + Seek>,
+        path: PfaPath,
+    ) -> Result<Vec<PfaPath>> {
+        let mut paths = vec![];
+        let mut stack = vec![path];
+        while let Some(path) = stack.pop() {
+            let (Some(a_hash), Some(b_hash)) = (
+                a.get_entry_hash(path.clone())?,
+                b.get_entry_hash(path.clone())?,
+            ) else {
+                paths.push(path);
+                continue;
+            };
+            if a_hash == b_hash {
+                continue;
+            }
+            if path.is_file() {
+                paths.push(path);
+                continue;
+            }
+
+            if let Some(dir) = a.get_directory(path, None)? {
+                stack.extend(dir.get_contents().iter().rev().cloned());
+            }
+        }
+        Ok(paths)
+    }
+
+    fn scan_old(
+        old: &mut PfaReader<impl Read + Seek>,
+        new: &mut PfaReader<impl Read + Seek>,
+        root: PfaPath,
+        options: &DiffOptions,
+        diff: &mut PfaDiff,
+    ) -> Result<()> {
+        let mut unchanged = vec![];
+        old.traverse_files_cancelable(root, |file| {
+            {
+                let path = file.get_path().to_string();
+                if !options.filter.matches(&path) {
+                    // applying the diff won't produce the new archive, see `retain_paths`
+                    diff.to_id = None;
+                    return anyhow::Ok(());
+                }
+                let in_new = new.get_file(&path[..], None)?;
+                if let Some(new_file) = in_new {
+                    if file.get_contents() != new_file.get_contents() {
+                        // Files with the same path but different content, time to make a patch
+                        let patch =
+                            Self::make_patch(file.get_contents(), new_file.get_contents(), options);
+                        diff.entries
+                            .push(DiffEntry::Change(ChangeEntry { path, patch }));
+                    } else {
+                        unchanged.push(path);
+                    }
+                } else {
+                    diff.entries.push(DiffEntry::Remove(RemoveEntry { path }));
+                }
+                anyhow::Ok(())
+            }
+            .context(format!("scanning file: {}", file.get_path()))
+        })
+        .context("scanning deleted files")?;
+
+        // Files with the same contents may still be stored differently
+        for path in unchanged {
+            let old_metadata = FileMetadata::read(old, &path)?;
+            let new_metadata = FileMetadata::read(new, &path)?;
+            if let (Some(old_metadata), Some(metadata)) = (old_metadata, new_metadata) {
+                if old_metadata != metadata {
+                    diff.entries
+                        .push(DiffEntry::Metadata(MetadataEntry { path, metadata }));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_new(
+        old: &mut PfaReader<impl Read + Seek>,
+        new: &mut PfaReader<impl Read + Seek>,
+        root: PfaPath,
+        filter: &PathFilter,
+        diff: &mut PfaDiff,
+    ) -> Result<()> {
+        new.traverse_files_cancelable(root, |file| {
+            {
+                let path = file.get_path().to_string();
+                if !filter.matches(&path) {
+                    diff.to_id = None;
+                    return anyhow::Ok(());
+                }
+                if old.get_path(&path[..], None)?.is_none() {
+                    diff.entries.push(DiffEntry::Add(AddEntry {
+                        path,
+                        contents: file.get_contents().to_vec(),
+                    }));
+                }
+                anyhow::Ok(())
+            }
+            .context(format!("scanning file: {}", file.get_path()))
+        })
+        .context("scanning created files")
+    }
+
+    fn make_patch(old: &[u8], new: &[u8], options: &DiffOptions) -> PatchKind {
+        let (Ok(old_text), Ok(new_text)) = (std::str::from_utf8(old), std::str::from_utf8(new))
+        else {
+            return PatchKind::Replace(new.to_vec());
+        };
+
+        let mut dmp = dmp::Dmp::new();
+        dmp.patch_margin = options.context.try_into().unwrap_or(i32::MAX);
+        let patches = dmp.patch_make1(old_text, new_text);
+        let hunk_too_large = options.max_hunk_size.is_some_and(|max| {
+            patches
+                .iter()
+                .any(|x| x.length1.max(x.length2).max(0) as usize > max)
+        });
+        let patch = dmp.patch_to_text(&patches);
+        if hunk_too_large || (options.replace_larger_patches && patch.len() >= new.len()) {
+            PatchKind::Replace(new.to_vec())
+        } else {
+            PatchKind::Text(patch)
+        }
+    }
+
+    /// Drops the entries whose paths don't pass the filter, so applying the diff leaves those
+    /// files as they are in the old archive. If any entry is dropped, applying the diff no longer
+    /// produces the archive it was created for, so its target id is cleared.
+    pub fn retain_paths(&mut self, filter: &PathFilter) {
+        let len = self.entries.len();
+        self.entries.retain(|x| filter.matches(x.path()));
+        if self.entries.len() != len {
+            self.to_id = None;
+        }
+    }
+
+    /// Applies the changes to an old archive, returning a builder containing the new archive
+    pub fn apply(&self, old: &mut PfaReader<impl Read + Seek>) -> Result<PfaBuilder> {
+        self.apply_with_corrections(old).map(|(builder, _)| builder)
+    }
+
+    /// Like [`apply`](Self::apply), also returning the paths of the text files whose patches
+    /// didn't match the old contents exactly, and were applied by fuzzy matching
+    pub fn apply_with_corrections(
+        &self,
+        old: &mut PfaReader<impl Read + Seek>,
+    ) -> Result<(PfaBuilder, Vec<String>)> {
+        if let (Some(expected), Some(actual)) = (self.from_id, old.get_archive_id()) {
+            if expected != actual {
+                return Err(anyhow!(
+                    "diff applies to archive {expected}, but was given archive {actual}"
+                )
+                .context(ApplyFailure::WrongArchive));
+            }
+        }
+
+        struct ApplyPatchTask<'a> {
+            patch: &'a str,
+            file_contents: String,
+            path: &'a str,
+        }
+        let mut patch_tasks = vec![];
+
+        let mut builder = PfaBuilder::from_reader(old, None).context("cloning old pfa")?;
+        builder.set_name(&format!("{}_patched", old.get_name()));
+        // the copy has the old archive's id, which the patched archive must not share
+        builder.set_archive_id(match self.to_id {
+            Some(to_id) => to_id,
+            None => Ulid::new(),
+        });
+        builder.set_duplicate_policy(DuplicatePolicy::Overwrite);
+        for remove in self.removed() {
+            builder
+                .remove(&remove.path)
+                .with_context(|| format!("removing file: {}", remove.path))
+                .context(ApplyFailure::Conflict)?;
+        }
+        for change in self.changed() {
+            let path = &change.path[..];
+            match &change.patch {
+                PatchKind::Text(patch) => {
+                    let Some(file) = old.get_file(path, None)? else {
+                        return Err(anyhow!("patched file is missing: {path}")
+                            .context(ApplyFailure::Conflict));
+                    };
+                    let task = ApplyPatchTask {
+                        patch,
+                        file_contents: String::from_utf8(file.into_contents())
+                            .context("extracting file contents as utf-8 string")
+                            .with_context(|| format!("analyzing file: {path}"))?,
+                        path,
+                    };
+                    patch_tasks.push(task);
+                }
+                PatchKind::Replace(contents) => {
+                    builder.add_file(path, contents.to_vec(), DataFlags::auto())?;
+                }
+            }
+        }
+        let builder = Mutex::new(builder);
+
+        let corrected = patch_tasks
+            .par_iter()
+            .map(|task| {
+                {
+                    let ApplyPatchTask {
+                        patch,
+                        file_contents,
+                        path,
+                    } = task;
+
+                    let dmp = dmp::Dmp::new();
+                    //dmp.patch_margin = 64;
+                    let patches = dmp
+                        .patch_from_text(patch.to_string())
+                        .map_err(|e| anyhow!("error while deserializing patch: {e:?}"))?;
+                    let new = dmp
+                        .patch_apply(&patches, file_contents)
+                        .map_err(|e| anyhow!("error while applying patch: {e:?}"))?;
+                    if new.1.contains(&false) {
+                        return Err(anyhow::Error::msg(ApplyFailure::Conflict));
+                    }
+                    let exact = Self::patches_match_exactly(&patches, file_contents);
+
+                    builder.lock().map_err(|_| anyhow!("get lock"))?.add_file(
+                        path,
+                        new.0.iter().collect::<String>().as_bytes().to_vec(),
+                        DataFlags::auto(),
+                    )?;
+                    anyhow::Ok((!exact).then(|| path.to_string()))
+                }
+                .context(format!("apply patch for file: {}", task.path))
+            })
+            .collect::<Result<Vec<_>>>()
+            .context("batch apply change patches")?;
+        let mut corrected = corrected.into_iter().flatten().collect::<Vec<_>>();
+        corrected.sort();
+
+        let mut builder = builder.into_inner().map_err(|_| anyhow!("get lock"))?;
+
+        for entry in self.metadata() {
+            Self::apply_metadata(old, &mut builder, entry)
+                .context(format!("apply metadata for file: {}", entry.path))?;
+        }
+
+        for add in self.added() {
+            builder
+                .add_file(&add.path, add.contents.to_vec(), DataFlags::auto())
+                .context(format!("add added file: {}", add.path))?;
+        }
+
+        Ok((builder, corrected))
+    }
+
+    /// Whether each patch finds the text it was made from at the position it was made for, in
+    /// which case applying them doesn't need fuzzy matching
+    fn patches_match_exactly(patches: &[dmp::Patch], text: &str) -> bool {
+        let mut text = text.chars().collect::<Vec<_>>();
+        for patch in patches {
+            let (mut before, mut after) = (vec![], vec![]);
+            for diff in &patch.diffs {
+                if diff.operation <= 0 {
+                    before.extend(diff.text.chars());
+                }
+                if diff.operation >= 0 {
+                    after.extend(diff.text.chars());
+                }
+            }
+            // empty ranges are parsed one before their start
+            let start = usize::try_from(patch.start2.max(0)).unwrap_or_default();
+            let end = start + before.len();
+            if text.get(start..end) != Some(&before[..]) {
+                return false;
+            }
+            text.splice(start..end, after);
+        }
+        true
+    }
+
+    /// Copies a file from the old archive with new metadata. The stored data is reused when only
+    /// the content type changes, otherwise the contents are encoded again. Encrypted files can't
+    /// be encoded again without their key, so only their content type changes. The rest of the
+    /// file's metadata, such as its tier, cipher and contents size, is copied from the old
+    /// archive.
+    fn apply_metadata(
+        old: &mut PfaReader<impl Read + Seek>,
+        builder: &mut PfaBuilder,
+        entry: &MetadataEntry,
+    ) -> Result<()> {
+        let path = &entry.path[..];
+        let metadata = &entry.metadata;
+        let missing = || anyhow!("file missing from old archive: {path}");
+        let stat = old
+            .stat(path)?
+            .filter(|x| !x.is_directory)
+            .ok_or_else(missing)?;
+        let reuse = DataFlags::is_encrypted(stat.flags)
+            || FileMetadata::read(old, path)?.is_some_and(|x| {
+                x.compression == metadata.compression
+                    && x.error_correction == metadata.error_correction
+            });
+        if reuse {
+            let stored = old.get_stored_file(path)?.ok_or_else(missing)?;
+            let flags = stored.get_flags();
+            builder.add_stored_file(path, stored.into_contents(), flags)?;
+        } else {
+            let contents = old
+                .get_file(path, None)?
+                .ok_or_else(missing)?
+                .into_contents();
+            builder.add_file(path, contents, metadata.data_flags())?;
+        }
+        old.copy_file_metadata(path, builder)?;
+        match &metadata.content_type {
+            Some(content_type) => builder.set_content_type(path, content_type)?,
+            None => builder.clear_content_type(path)?,
+        }
+        Ok(())
+    }
+
+    /// Serializes the diff into a diff archive
+    pub fn to_archive(&self, name: &str) -> Result<Vec<u8>> {
+        let mut builder = PfaBuilder::new(name);
+        if self.from_id.is_some() || self.to_id.is_some() {
+            let format_id = |id: Option<Ulid>| id.map(|x| x.to_string()).unwrap_or("-".into());
+            let lineage = format!("{}\n{}\n", format_id(self.from_id), format_id(self.to_id));
+            builder
+                .add_file("/lineage", lineage.into_bytes(), DataFlags::auto())
+                .context("add lineage")?;
+        }
+        let mut index = vec![Self::INDEX_VERSION];
+        index.write_u32::<LittleEndian>(
+            self.entries
+                .len()
+                .try_into()
+                .map_err(|_| anyhow!("too many diff entries"))?,
+        )?;
+        for (i, entry) in self.entries.iter().enumerate() {
+            let metadata;
+            let (op, contents) = match entry {
+                DiffEntry::Remove(_) => (Self::REMOVE, None),
+                DiffEntry::Add(AddEntry { contents, .. }) => (Self::ADD, Some(&contents[..])),
+                DiffEntry::Change(ChangeEntry {
+                    patch: PatchKind::Text(patch),
+                    ..
+                }) => (Self::CHANGE, Some(patch.as_bytes())),
+                DiffEntry::Change(ChangeEntry {
+                    patch: PatchKind::Replace(contents),
+                    ..
+                }) => (Self::REPLACE, Some(&contents[..])),
+                DiffEntry::Metadata(entry) => {
+                    metadata = entry.metadata.encode()?;
+                    (Self::METADATA, Some(&metadata[..]))
+                }
+            };
+            let path = entry.path().as_bytes();
+            index.write_u8(op)?;
+            index.write_u16::<LittleEndian>(
+                path.len()
+                    .try_into()
+                    .map_err(|_| anyhow!("path too long: {}", entry.path()))?,
+            )?;
+            index.extend_from_slice(path);
+            if let Some(contents) = contents {
+                builder
+                    .add_file(&format!("/data/{i}"), contents.to_vec(), DataFlags::auto())
+                    .context(format!("add patch entry: {}", entry.path()))?;
+            }
+        }
+        builder
+            .add_file("/index", index, DataFlags::auto())
+            .context("add diff index")?;
+
+        builder.build().context("build diff pfa")
+    }
+
+    /// Deserializes a diff from a diff archive
+    pub fn from_archive(diff: &mut PfaReader<impl Read + Seek>) -> Result<Self> {
+        let (from_id, to_id) = match diff.get_file("/lineage", None)? {
+            Some(lineage) => {
+                let lineage = String::from_utf8(lineage.get_contents().to_vec())
+                    .context("parsing lineage as string")?;
+                let mut ids = lineage.lines().map(|x| match x {
+                    "-" => Ok(None),
+                    x => Ulid::from_string(x)
+                        .map(Some)
+                        .map_err(|e| anyhow!("invalid archive id in lineage: {e}")),
+                });
+                (
+                    ids.next().transpose()?.flatten(),
+                    ids.next().transpose()?.flatten(),
+                )
+            }
+            None => (None, None),
+        };
+
+        let entries = match diff.get_file("/index", None)? {
+            Some(index) => Self::read_index(diff, index.get_contents())?,
+            None => Self::read_legacy_entries(diff)?,
+        };
+
+        Ok(Self {
+            entries,
+            from_id,
+            to_id,
+        })
+    }
+
+    fn read_index(diff: &mut PfaReader<impl Read + Seek>, index: &[u8]) -> Result<Vec<DiffEntry>> {
+        let mut index = Cursor::new(index);
+        let version = index.read_u8().context("read diff index version")?;
+        if version != Self::INDEX_VERSION {
+            return Err(anyhow!("unsupported diff index version: {version}"));
+        }
+        let num_entries = index.read_u32::<LittleEndian>()?;
+        let mut entries = Vec::with_capacity(num_entries.min(u16::MAX.into()) as usize);
+        for i in 0..num_entries {
+            let op = index.read_u8()?;
+            let mut path = vec![0; index.read_u16::<LittleEndian>()?.into()];
+            index
+                .read_exact(&mut path)
+                .context("read diff index path")?;
+            let path = String::from_utf8(path).context("parse diff index path")?;
+            let mut contents = || -> Result<Vec<u8>> {
+                Ok(diff
+                    .get_file(&format!("/data/{i}")[..], None)?
+                    .ok_or(anyhow!("missing contents for diff entry: {path}"))?
+                    .into_contents())
+            };
+            entries.push(match op {
+                Self::ADD => DiffEntry::Add(AddEntry {
+                    contents: contents()?,
+                    path,
+                }),
+                Self::REMOVE => DiffEntry::Remove(RemoveEntry { path }),
+                Self::CHANGE => DiffEntry::Change(ChangeEntry {
+                    patch: PatchKind::Text(
+                        String::from_utf8(contents()?)
+                            .context("parsing change patch contents as string")?,
+                    ),
+                    path,
+                }),
+                Self::REPLACE => DiffEntry::Change(ChangeEntry {
+                    patch: PatchKind::Replace(contents()?),
+                    path,
+                }),
+                Self::METADATA => DiffEntry::Metadata(MetadataEntry {
+                    metadata: FileMetadata::decode(&contents()?)
+                        .with_context(|| format!("parse metadata for {path}"))?,
+                    path,
+                }),
+                _ => return Err(anyhow!("unknown diff operation {op} for {path}")),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Reads diffs from before the index, which stored each entry under a directory for its
+    /// operation, named after its path with `/` replaced by `%`
+    fn read_legacy_entries(diff: &mut PfaReader<impl Read + Seek>) -> Result<Vec<DiffEntry>> {
+        let mut entries = vec![];
+        diff.traverse_files("/add/", |file| {
+            entries.push(DiffEntry::Add(AddEntry {
+                path: Self::decode_path(&file.get_name()),
+                contents: file.get_contents().to_vec(),
+            }));
+        });
+        diff.traverse_files("/remove/", |file| {
+            entries.push(DiffEntry::Remove(RemoveEntry {
+                path: Self::decode_path(&file.get_name()),
+            }));
+        });
+        diff.traverse_files_cancelable("/change/", |file| {
+            entries.push(DiffEntry::Change(ChangeEntry {
+                path: Self::decode_path(&file.get_name()),
+                patch: PatchKind::Text(
+                    String::from_utf8(file.get_contents().to_vec())
+                        .context("parsing change patch contents as string")?,
+                ),
+            }));
+            anyhow::Ok(())
+        })?;
+        diff.traverse_files("/replace/", |file| {
+            entries.push(DiffEntry::Change(ChangeEntry {
+                path: Self::decode_path(&file.get_name()),
+                patch: PatchKind::Replace(file.get_contents().to_vec()),
+            }));
+        });
+        Ok(entries)
+    }
+
+    fn decode_path(name: &str) -> String {
+        name.replace('%', "/")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use pfa::{
+        builder::PfaBuilder,
+        reader::PfaReader,
+        shared::{Cipher, DataCompressionType, DataFlags, Tier},
+    };
+
+    use super::{DiffEntry, DiffOptions, FileMetadata, MetadataEntry, PatchKind, PfaDiff};
+    use crate::{filter::PathFilter, outcome::ApplyFailure};
+
+    fn archive(files: &[(&str, &[u8])]) -> PfaReader<Cursor<Vec<u8>>> {
+        archive_with_hashes(files, false)
+    }
+
+    fn archive_with_hashes(
+        files: &[(&str, &[u8])],
+        entry_hashes: bool,
+    ) -> PfaReader<Cursor<Vec<u8>>> {
+        let mut builder = PfaBuilder::new("test");
+        builder.set_entry_hashes(entry_hashes);
+        for (path, contents) in files {
+            builder
+                .add_file(path, contents.to_vec(), DataFlags::auto())
+                .unwrap();
+        }
+        PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap()
+    }
+
+    #[test]
+    fn diff_round_trip() {
+        let mut old = archive(&[
+            ("/a.txt", b"hello world"),
+            ("/b.bin", &[0xff, 0x00, 0x01]),
+            ("/gone.txt", b"bye"),
+        ]);
+        let mut new = archive(&[
+            ("/a.txt", b"hello there world"),
+            ("/b.bin", &[0xff, 0x00, 0x02]),
+            ("/new.txt", b"hi"),
+        ]);
+
+        let diff = PfaDiff::compute(&mut old, &mut new).unwrap();
+        assert_eq!(diff.added().count(), 1);
+        assert_eq!(diff.removed().count(), 1);
+        assert_eq!(diff.changed().count(), 2);
+        assert!(diff.entries.iter().any(|x| matches!(
+            x,
+            DiffEntry::Change(c) if c.path == "/b.bin" && c.patch == PatchKind::Replace(vec![0xff, 0x00, 0x02])
+        )));
+
+        let bytes = diff.to_archive("test_patch").unwrap();
+        let parsed =
+            PfaDiff::from_archive(&mut PfaReader::new(Cursor::new(bytes)).unwrap()).unwrap();
+        assert_eq!(parsed.entries.len(), diff.entries.len());
+        for entry in &diff.entries {
+            assert!(parsed.entries.contains(entry));
+        }
+
+        assert_eq!(parsed.from_id, old.get_archive_id());
+        assert_eq!(parsed.to_id, new.get_archive_id());
+        assert!(parsed.apply(&mut new).is_err());
+
+        let patched = parsed.apply(&mut old).unwrap().build().unwrap();
+        let mut patched = PfaReader::new(Cursor::new(patched)).unwrap();
+        assert_eq!(patched.get_archive_id(), new.get_archive_id());
+        assert_eq!(patched.get_name(), "test_patched");
+        for (path, contents) in [
+            ("/a.txt", &b"hello there world"[..]),
+            ("/b.bin", &[0xff, 0x00, 0x02]),
+            ("/new.txt", b"hi"),
+        ] {
+            let file = patched.get_file(path, None).unwrap().unwrap();
+            assert_eq!(file.get_contents(), contents);
+        }
+        assert!(patched.get_file("/gone.txt", None).unwrap().is_none());
+
+        // a removed file missing from the old archive is a conflict
+        let mut unchecked = parsed.clone();
+        unchecked.from_id = None;
+        let error = unchecked
+            .apply(&mut archive(&[("/a.txt", b"hello world")]))
+            .err()
+            .unwrap();
+        assert_eq!(ApplyFailure::of(&error), Some(ApplyFailure::Conflict));
+    }
+
+    #[test]
+    fn apply_corrections() {
+        let text = "line one\nline two\nline three\nline four\nline five\n";
+        let changed = "line 1\nline two\nline three\nline 4\nline five, and more\n";
+        let mut old = archive(&[("/a.txt", text.as_bytes())]);
+        let mut new = archive(&[("/a.txt", changed.as_bytes())]);
+        let mut diff = PfaDiff::compute(&mut old, &mut new).unwrap();
+
+        let (_, corrected) = diff.apply_with_corrections(&mut old).unwrap();
+        assert!(corrected.is_empty());
+        let error = diff.apply(&mut new).err().unwrap();
+        assert_eq!(ApplyFailure::of(&error), Some(ApplyFailure::WrongArchive));
+
+        diff.from_id = None;
+        let shifted = format!("a new first line\n{text}");
+        let mut shifted = archive(&[("/a.txt", shifted.as_bytes())]);
+        let (builder, corrected) = diff.apply_with_corrections(&mut shifted).unwrap();
+        assert_eq!(corrected, ["/a.txt"]);
+        let mut patched = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        let file = patched.get_file("/a.txt", None).unwrap().unwrap();
+        assert_eq!(
+            file.get_contents(),
+            format!("a new first line\n{changed}").as_bytes()
+        );
+
+        let mut unrelated = archive(&[("/a.txt", b"something else entirely")]);
+        let error = diff.apply(&mut unrelated).err().unwrap();
+        assert_eq!(ApplyFailure::of(&error), Some(ApplyFailure::Conflict));
+
+        // so is a patched file missing from the old archive
+        let mut missing = archive(&[("/b.txt", text.as_bytes())]);
+        let error = diff.apply(&mut missing).err().unwrap();
+        assert_eq!(ApplyFailure::of(&error), Some(ApplyFailure::Conflict));
+        assert!(format!("{error:#}").contains("/a.txt"));
+    }
+
+    #[test]
+    fn diff_index_layout() {
+        let mut old = archive(&[("/dir/a.txt", b"a"), ("/dir/gone.txt", b"gone")]);
+        let mut new = archive(&[("/dir/a.txt", b"aa"), ("/dir/new.bin", &[0xff])]);
+        let diff = PfaDiff::compute(&mut old, &mut new).unwrap();
+
+        let mut archive = PfaReader::new(Cursor::new(diff.to_archive("patch").unwrap())).unwrap();
+        let data = archive.get_directory("/data/", None).unwrap().unwrap();
+        // removals don't store anything besides their index entry
+        assert_eq!(data.get_contents().len(), 2);
+        assert!(archive.get_directory("/add/", None).unwrap().is_none());
+        assert_eq!(PfaDiff::from_archive(&mut archive).unwrap(), diff);
+
+        // diffs from before the index are still read
+        let mut builder = PfaBuilder::new("legacy_patch");
+        for (path, contents) in [
+            ("/add/%dir%new.bin", &[0xff][..]),
+            ("/remove/%dir%gone.txt", b""),
+            ("/replace/%dir%a.txt", b"aa"),
+        ] {
+            builder
+                .add_file(path, contents.to_vec(), DataFlags::auto())
+                .unwrap();
+        }
+        let mut legacy = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+        let legacy = PfaDiff::from_archive(&mut legacy).unwrap();
+        assert_eq!(legacy.added().next().unwrap().path, "/dir/new.bin");
+        assert_eq!(legacy.removed().next().unwrap().path, "/dir/gone.txt");
+        assert_eq!(
+            legacy.changed().next().unwrap().patch,
+            PatchKind::Replace(b"aa".to_vec())
+        );
+    }
+
+    #[test]
+    fn metadata_only_changes() {
+        let contents = b"the same contents in both archives".to_vec();
+        let mut old = archive(&[("/a.txt", &contents), ("/b.txt", &contents)]);
+        let mut builder = PfaBuilder::new("test");
+        builder
+            .add_file(
+                "/a.txt",
+                contents.clone(),
+                DataFlags::no_compression().error_correction(Some(0.1)),
+            )
+            .unwrap();
+        builder
+            .add_file("/b.txt", contents.clone(), DataFlags::auto())
+            .unwrap();
+        builder.set_content_type("/b.txt", "text/plain").unwrap();
+        let mut new = PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap();
+
+        let diff = PfaDiff::compute(&mut old, &mut new).unwrap();
+        assert_eq!(diff.metadata().count(), 2);
+        assert_eq!(diff.entries.len(), 2);
+
+        let mut archive = PfaReader::new(Cursor::new(diff.to_archive("patch").unwrap())).unwrap();
+        let diff = PfaDiff::from_archive(&mut archive).unwrap();
+        assert_eq!(diff.metadata().count(), 2);
+
+        let mut patched =
+            PfaReader::new(Cursor::new(diff.apply(&mut old).unwrap().build().unwrap())).unwrap();
+        for path in ["/a.txt", "/b.txt"] {
+            let file = patched.get_file(path, None).unwrap().unwrap();
+            assert_eq!(file.get_contents(), contents);
+            assert_eq!(
+                FileMetadata::read(&mut patched, path).unwrap(),
+                FileMetadata::read(&mut new, path).unwrap()
+            );
+        }
+        assert!(PfaDiff::compute(&mut patched, &mut new).unwrap().is_empty());
+    }
+
+    #[test]
+    fn metadata_changes_keep_entry_metadata() {
+        let key = DataFlags::generate_key();
+        let secret = b"encrypted with xchacha".repeat(20);
+        let text = b"the same text in both archives".repeat(20);
+        let build = |compression| {
+            let mut builder = PfaBuilder::new("test");
+            builder
+                .add_file(
+                    "/secret.bin",
+                    secret.clone(),
+                    DataFlags::auto()
+                        .encryption(Some(key))
+                        .cipher(Cipher::XChaCha20Poly1305),
+                )
+                .unwrap();
+            builder
+                .add_file(
+                    "/a.txt",
+                    text.clone(),
+                    DataFlags::new(None, None, DataCompressionType::Forced(compression)),
+                )
+                .unwrap();
+            builder.set_tier("/a.txt", Tier::Low).unwrap();
+            PfaReader::new(Cursor::new(builder.build().unwrap())).unwrap()
+        };
+        let mut old = build(true);
+        let mut new = build(false);
+
+        let mut diff = PfaDiff::compute(&mut old, &mut new).unwrap();
+        assert_eq!(diff.metadata().count(), 1);
+        // encrypted files aren't compared, but their content type can still be changed
+        diff.entries.push(DiffEntry::Metadata(MetadataEntry {
+            path: "/secret.bin".into(),
+            metadata: FileMetadata {
+                compression: false,
+                error_correction: None,
+                content_type: Some("application/octet-stream".into()),
+            },
+        }));
+        let mut archive = PfaReader::new(Cursor::new(diff.to_archive("patch").unwrap())).unwrap();
+        let diff = PfaDiff::from_archive(&mut archive).unwrap();
+
+        let mut patched =
+            PfaReader::new(Cursor::new(diff.apply(&mut old).unwrap().build().unwrap())).unwrap();
+        assert_eq!(
+            patched.get_cipher("/secret.bin").unwrap(),
+            Some(Cipher::XChaCha20Poly1305)
+        );
+        let file = patched.get_file("/secret.bin", Some(key)).unwrap().unwrap();
+        assert_eq!(file.get_contents(), secret);
+        let stat = patched.stat("/secret.bin").unwrap().unwrap();
+        assert_eq!(
+            stat.content_type.as_deref(),
+            Some("application/octet-stream")
+        );
+        let info = patched.get_file_info("/secret.bin").unwrap().unwrap();
+        assert_eq!(info.contents_size, Some(secret.len() as u64));
+
+        assert_eq!(patched.get_tier("/a.txt").unwrap(), Some(Tier::Low));
+        assert_eq!(
+            FileMetadata::read(&mut patched, "/a.txt").unwrap(),
+            FileMetadata::read(&mut new, "/a.txt").unwrap()
+        );
+    }
+
+    #[test]
+    fn diff_skips_identical_subtrees() {
+        let old_files: &[(&str, &[u8])] = &[
+            ("/same/a.txt", b"a"),
+            ("/same/nested/b.txt", b"b"),
+            ("/changed/c.txt", b"c"),
+            ("/changed/gone.txt", b"gone"),
+        ];
+        let new_files: &[(&str, &[u8])] = &[
+            ("/same/a.txt", b"a"),
+            ("/same/nested/b.txt", b"b"),
+            ("/changed/c.txt", b"cc"),
+            ("/added/d.txt", b"d"),
+        ];
+
+        let mut expected = PfaDiff::compute(&mut archive(old_files), &mut archive(new_files))
+            .unwrap()
+            .entries;
+        let mut old = archive_with_hashes(old_files, true);
+        let mut new = archive_with_hashes(new_files, true);
+        assert_eq!(
+            old.get_entry_hash("/same/").unwrap(),
+            new.get_entry_hash("/same/").unwrap()
+        );
+        assert_ne!(old.get_root_hash(), new.get_root_hash());
+
+        let mut entries = PfaDiff::compute(&mut old, &mut new).unwrap().entries;
+        expected.sort_by(|a, b| a.path().cmp(b.path()));
+        entries.sort_by(|a, b| a.path().cmp(b.path()));
+        assert_eq!(entries, expected);
+        assert_eq!(entries.len(), 3);
+
+        let mut copy = archive_with_hashes(old_files, true);
+        assert!(PfaDiff::compute(&mut old, &mut copy).unwrap().is_empty());
+    }
+
+    #[test]
+    fn diff_path_filters() {
+        let mut old = archive(&[
+            ("/a.txt", b"a"),
+            ("/logs/build.log", b"old log"),
+            ("/cache/x", b"x"),
+        ]);
+        let mut new = archive(&[
+            ("/a.txt", b"aa"),
+            ("/logs/build.log", b"new log"),
+            ("/cache/y", b"y"),
+        ]);
+
+        let filter = PathFilter::new()
+            .exclude("*.log")
+            .unwrap()
+            .exclude("/cache/")
+            .unwrap();
+        let diff = PfaDiff::compute_filtered(&mut old, &mut new, &filter).unwrap();
+        assert_eq!(diff.entries.len(), 1);
+        assert_eq!(diff.entries[0].path(), "/a.txt");
+        assert_eq!(diff.to_id, None);
+        let patched = diff.apply(&mut old).unwrap().build().unwrap();
+        let mut patched = PfaReader::new(Cursor::new(patched)).unwrap();
+        let log = patched.get_file("/logs/build.log", None).unwrap().unwrap();
+        assert_eq!(log.get_contents(), b"old log");
+        assert_ne!(patched.get_archive_id(), new.get_archive_id());
+        assert_ne!(patched.get_archive_id(), old.get_archive_id());
+
+        let unfiltered = PathFilter::new().exclude("/missing/").unwrap();
+        let diff = PfaDiff::compute_filtered(&mut old, &mut new, &unfiltered).unwrap();
+        assert_eq!(diff.to_id, new.get_archive_id());
+
+        let mut diff = PfaDiff::compute(&mut old, &mut new).unwrap();
+        assert_eq!(diff.entries.len(), 4);
+        diff.retain_paths(&PathFilter::new().exclude("/logs/").unwrap());
+        assert_eq!(diff.to_id, None);
+        let patched = diff.apply(&mut old).unwrap().build().unwrap();
+        let mut patched = PfaReader::new(Cursor::new(patched)).unwrap();
+        let log = patched.get_file("/logs/build.log", None).unwrap().unwrap();
+        assert_eq!(log.get_contents(), b"old log");
+        assert!(patched.get_file("/cache/x", None).unwrap().is_none());
+        assert_ne!(patched.get_archive_id(), new.get_archive_id());
+    }
+
+    #[test]
+    fn diff_patch_limits() {
+        let config = (0..50)
+            .map(|x| format!("key{x} = {x}\n"))
+            .collect::<String>();
+        let edited = config.replace("key10 = 10", "key10 = 11");
+        let shuffled = config.lines().rev().collect::<Vec<_>>().join("\n");
+        let mut old = archive(&[("/a.cfg", config.as_bytes()), ("/b.cfg", config.as_bytes())]);
+        let mut new = archive(&[
+            ("/a.cfg", edited.as_bytes()),
+            ("/b.cfg", shuffled.as_bytes()),
+        ]);
+
+        let patch_for = |diff: &PfaDiff, path: &str| {
+            diff.changed()
+                .find(|x| x.path == path)
+                .map(|x| x.patch.clone())
+                .unwrap()
+        };
+
+        let options = DiffOptions::new().context(32);
+        let diff = PfaDiff::compute_with_options(&mut old, &mut new, &options).unwrap();
+        let PatchKind::Text(wide) = patch_for(&diff, "/a.cfg") else {
+            panic!("expected a text patch");
+        };
+        let diff = PfaDiff::compute(&mut old, &mut new).unwrap();
+        let PatchKind::Text(narrow) = patch_for(&diff, "/a.cfg") else {
+            panic!("expected a text patch");
+        };
+        assert!(wide.len() > narrow.len());
+        assert!(matches!(patch_for(&diff, "/b.cfg"), PatchKind::Text(_)));
+
+        let options = DiffOptions::new().context(32).replace_larger_patches(true);
+        let diff = PfaDiff::compute_with_options(&mut old, &mut new, &options).unwrap();
+        assert!(matches!(patch_for(&diff, "/a.cfg"), PatchKind::Text(_)));
+        assert_eq!(
+            patch_for(&diff, "/b.cfg"),
+            PatchKind::Replace(shuffled.as_bytes().to_vec())
+        );
+        let patched = diff.apply(&mut old).unwrap().build().unwrap();
+        let mut patched = PfaReader::new(Cursor::new(patched)).unwrap();
+        let file = patched.get_file("/a.cfg", None).unwrap().unwrap();
+        assert_eq!(file.get_contents(), edited.as_bytes());
+
+        let options = DiffOptions::new().max_hunk_size(Some(4));
+        let diff = PfaDiff::compute_with_options(&mut old, &mut new, &options).unwrap();
+        assert!(matches!(patch_for(&diff, "/a.cfg"), PatchKind::Replace(_)));
+    }
+}
@@ -0,0 +1,195 @@
+use std::fmt;
+
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+use crate::model::PfaDiff;
+
+/// Why applying a diff failed. Errors are tagged with it (as their message or context), so it
+/// can be found with [`ApplyFailure::of`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyFailure {
+    /// The diff was made for a different archive
+    WrongArchive,
+    /// A text patch couldn't be matched against the old file, or a file the diff changes or
+    /// removes is missing from it
+    Conflict,
+    /// The patched archive was written but doesn't validate
+    Invalid,
+    /// The patched archive, or a backup, couldn't be written
+    Write,
+}
+
+impl ApplyFailure {
+    pub fn of(error: &anyhow::Error) -> Option<Self> {
+        error.downcast_ref::<Self>().copied()
+    }
+}
+
+impl fmt::Display for ApplyFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ApplyFailure::WrongArchive => "diff is for a different archive",
+            ApplyFailure::Conflict => "at least 1 patch failed to apply",
+            ApplyFailure::Invalid => "validate newly patched pfa",
+            ApplyFailure::Write => "write patched archive",
+        })
+    }
+}
+
+/// Number of entries of each kind in a diff
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DiffCounts {
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+    pub metadata: usize,
+}
+
+impl DiffCounts {
+    pub fn of(diff: &PfaDiff) -> Self {
+        Self {
+            added: diff.added().count(),
+            removed: diff.removed().count(),
+            changed: diff.changed().count(),
+            metadata: diff.metadata().count(),
+        }
+    }
+}
+
+/// How a `create`, `apply` or `compare` run ended, which decides the exit code of `pfadiff`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// A diff was created, and the archives differ
+    Created,
+    /// A diff was created, but the archives have the same contents
+    NoChanges,
+    /// Every patch matched the old archive exactly
+    Applied,
+    /// The diff was applied, but some text patches only matched the old files approximately
+    Corrected,
+    /// The compared archives are stored the same way
+    Matched,
+    /// The compared archives differ in their headers or entry metadata
+    MetadataDiffers,
+    /// Failed for a reason other than an [`ApplyFailure`], such as a missing input file
+    Error,
+    Failed(ApplyFailure),
+}
+
+impl Outcome {
+    pub fn of_error(error: &anyhow::Error) -> Self {
+        ApplyFailure::of(error).map_or(Outcome::Error, Outcome::Failed)
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Created | Outcome::Applied | Outcome::Matched => 0,
+            Outcome::Error => 1,
+            Outcome::NoChanges => 3,
+            Outcome::Corrected => 4,
+            Outcome::MetadataDiffers => 5,
+            Outcome::Failed(ApplyFailure::WrongArchive) => 10,
+            Outcome::Failed(ApplyFailure::Conflict) => 11,
+            Outcome::Failed(ApplyFailure::Invalid) => 12,
+            Outcome::Failed(ApplyFailure::Write) => 13,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Created => "created",
+            Outcome::NoChanges => "no_changes",
+            Outcome::Applied => "applied",
+            Outcome::Corrected => "applied_with_corrections",
+            Outcome::Matched => "matched",
+            Outcome::MetadataDiffers => "metadata_differs",
+            Outcome::Error => "error",
+            Outcome::Failed(ApplyFailure::WrongArchive) => "wrong_archive",
+            Outcome::Failed(ApplyFailure::Conflict) => "conflict",
+            Outcome::Failed(ApplyFailure::Invalid) => "invalid",
+            Outcome::Failed(ApplyFailure::Write) => "write_failed",
+        }
+    }
+}
+
+/// Written as the fields `outcome`, its [`name`](Outcome::name), and `exit_code`
+impl Serialize for Outcome {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut fields = serializer.serialize_struct("Outcome", 2)?;
+        fields.serialize_field("outcome", self.name())?;
+        fields.serialize_field("exit_code", &self.exit_code())?;
+        fields.end()
+    }
+}
+
+/// The result of a run, serialized as a single JSON object and printed by `pfadiff --json`:
+/// `command`, `outcome` (see [`Outcome::name`]), `exit_code`, then the `added`, `removed`,
+/// `changed` and `metadata` entry counts when the run got that far, the `corrected` paths of an
+/// apply, and the `error` when it failed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Summary {
+    pub command: &'static str,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+    #[serde(flatten)]
+    pub counts: Option<DiffCounts>,
+    /// Paths whose patches only matched approximately, None unless the diff was applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub corrected: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Summary {
+    pub fn new(command: &'static str, outcome: Outcome) -> Self {
+        Self {
+            command,
+            outcome,
+            counts: None,
+            corrected: None,
+            error: None,
+        }
+    }
+
+    pub fn failed(command: &'static str, error: &anyhow::Error) -> Self {
+        Self {
+            error: Some(format!("{error:#}")),
+            ..Self::new(command, Outcome::of_error(error))
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("summaries always serialize")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DiffCounts, Outcome, Summary};
+
+    #[test]
+    fn summary_json_schema() {
+        let summary = Summary {
+            counts: Some(DiffCounts {
+                added: 1,
+                removed: 2,
+                changed: 3,
+                metadata: 0,
+            }),
+            corrected: Some(vec!["/a \"b\".txt".into()]),
+            ..Summary::new("apply", Outcome::Corrected)
+        };
+        assert_eq!(
+            summary.to_json(),
+            "{\"command\":\"apply\",\"outcome\":\"applied_with_corrections\",\"exit_code\":4,\
+             \"added\":1,\"removed\":2,\"changed\":3,\"metadata\":0,\
+             \"corrected\":[\"/a \\\"b\\\".txt\"]}"
+        );
+
+        let error = anyhow::anyhow!("missing\nfile");
+        assert_eq!(
+            Summary::failed("create", &error).to_json(),
+            "{\"command\":\"create\",\"outcome\":\"error\",\"exit_code\":1,\"error\":\"missing\\nfile\"}"
+        );
+    }
+}